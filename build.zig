@@ -1,15 +1,55 @@
 const std = @import("std");
 
+const JsonCodec = enum { builtin, typed };
+
 pub fn build(b: *std.Build) void {
     const target = b.standardTargetOptions(.{});
     const optimize = b.standardOptimizeOption(.{});
 
+    // Test-only C API (mock clock); never enable in release builds
+    const ffi_test_util = b.option(bool, "ffi-test-util", "Export test-only FFI functions such as the mock clock") orelse false;
+
+    // JSON implementation used to persist tokens (see src/codec.zig)
+    const json_codec = b.option(JsonCodec, "json-codec", "JSON codec used by storage backends") orelse .builtin;
+
+    // Grants deprecated by OAuth 2.1, for migrating off legacy identity providers
+    const legacy_grants = b.option(bool, "legacy-grants", "Enable the resource owner password grant") orelse false;
+
+    // Secret Service storage backend (needs libsecret at build and run time)
+    const libsecret = b.option(bool, "libsecret", "Build SecretServiceStorage against libsecret") orelse false;
+
+    // SQLite storage backend (links the system libsqlite3)
+    const sqlite = b.option(bool, "sqlite", "Build SqliteStorage against the system SQLite") orelse false;
+
+    // AWS Secrets Manager storage backend (pure Zig, SigV4-signed HTTP)
+    const aws_secrets = b.option(bool, "aws-secrets", "Enable AwsSecretsStorage") orelse false;
+
+    const build_options = b.addOptions();
+    build_options.addOption(bool, "ffi_test_util", ffi_test_util);
+    build_options.addOption(JsonCodec, "json_codec", json_codec);
+    build_options.addOption(bool, "legacy_grants", legacy_grants);
+    build_options.addOption(bool, "libsecret", libsecret);
+    build_options.addOption(bool, "sqlite", sqlite);
+    build_options.addOption(bool, "aws_secrets", aws_secrets);
+
+    const test_build_options = b.addOptions();
+    test_build_options.addOption(bool, "ffi_test_util", true);
+    test_build_options.addOption(JsonCodec, "json_codec", json_codec);
+    test_build_options.addOption(bool, "legacy_grants", true);
+    test_build_options.addOption(bool, "libsecret", libsecret);
+    test_build_options.addOption(bool, "sqlite", sqlite);
+    test_build_options.addOption(bool, "aws_secrets", true);
+
     // Main library module
     const lib_mod = b.addModule("schlussel", .{
         .root_source_file = b.path("src/lib.zig"),
         .target = target,
         .optimize = optimize,
     });
+    lib_mod.addOptions("build_options", build_options);
+    linkSecurityFramework(lib_mod);
+    if (libsecret) linkLibsecret(lib_mod);
+    if (sqlite) linkSqlite(lib_mod);
 
     // Static library for C FFI
     const lib = b.addLibrary(.{
@@ -22,6 +62,11 @@ pub fn build(b: *std.Build) void {
         }),
     });
 
+    lib.root_module.addOptions("build_options", build_options);
+    linkSecurityFramework(lib.root_module);
+    if (libsecret) linkLibsecret(lib.root_module);
+    if (sqlite) linkSqlite(lib.root_module);
+
     // Link libc for FFI
     lib.linkLibC();
 
@@ -40,6 +85,11 @@ pub fn build(b: *std.Build) void {
         }),
     });
 
+    lib_unit_tests.root_module.addOptions("build_options", test_build_options);
+    linkSecurityFramework(lib_unit_tests.root_module);
+    if (libsecret) linkLibsecret(lib_unit_tests.root_module);
+    if (sqlite) linkSqlite(lib_unit_tests.root_module);
+
     const run_lib_unit_tests = b.addRunArtifact(lib_unit_tests);
 
     const test_step = b.step("test", "Run unit tests");
@@ -115,6 +165,7 @@ pub fn build(b: *std.Build) void {
         .target = target,
         .optimize = .Debug,
     });
+    docs_mod.addOptions("build_options", build_options);
 
     const docs = b.addLibrary(.{
         .linkage = .static,
@@ -131,3 +182,23 @@ pub fn build(b: *std.Build) void {
     const docs_step = b.step("docs", "Generate documentation");
     docs_step.dependOn(&install_docs.step);
 }
+
+/// KeychainStorage calls the Security framework on macOS
+fn linkSecurityFramework(module: *std.Build.Module) void {
+    const target = module.resolved_target orelse return;
+    if (target.result.os.tag != .macos) return;
+    module.linkFramework("Security", .{});
+    module.linkFramework("CoreFoundation", .{});
+}
+
+/// SecretServiceStorage calls libsecret with -Dlibsecret=true
+fn linkLibsecret(module: *std.Build.Module) void {
+    module.link_libc = true;
+    module.linkSystemLibrary("libsecret-1", .{});
+}
+
+/// SqliteStorage calls the system SQLite with -Dsqlite=true
+fn linkSqlite(module: *std.Build.Module) void {
+    module.link_libc = true;
+    module.linkSystemLibrary("sqlite3", .{});
+}
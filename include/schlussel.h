@@ -70,6 +70,32 @@ typedef enum {
     SCHLUSSEL_ERROR_TIMEOUT = 18,
     SCHLUSSEL_ERROR_AUTHORIZATION_PENDING = 19,
     SCHLUSSEL_ERROR_SLOW_DOWN = 20,
+    SCHLUSSEL_ERROR_DISALLOWED_ALGORITHM = 21,
+    SCHLUSSEL_ERROR_CANCELLED = 22,
+    SCHLUSSEL_ERROR_INVALID_GRANT = 23,
+    SCHLUSSEL_ERROR_CIRCUIT_OPEN = 24,
+    SCHLUSSEL_ERROR_UNREGISTERED_REDIRECT_URI = 25,
+    SCHLUSSEL_ERROR_INVALID_NONCE = 26,
+    SCHLUSSEL_ERROR_DECRYPTION_FAILED = 27,
+    SCHLUSSEL_ERROR_TOKEN_NOT_FOUND = 28,
+    SCHLUSSEL_ERROR_ISSUER_MISMATCH = 29,
+    SCHLUSSEL_ERROR_INVALID_SIGNATURE = 30,
+    SCHLUSSEL_ERROR_INVALID_ID_TOKEN = 31,
+    SCHLUSSEL_ERROR_AUTH_REQUEST_EXPIRED = 32,
+    SCHLUSSEL_ERROR_INVALID_AUTHORIZATION_RESPONSE = 33,
+    SCHLUSSEL_ERROR_REFRESH_TOKEN_REUSED = 34,
+    SCHLUSSEL_ERROR_INVALID_REQUEST = 35,
+    SCHLUSSEL_ERROR_INVALID_CLIENT = 36,
+    SCHLUSSEL_ERROR_UNAUTHORIZED_CLIENT = 37,
+    SCHLUSSEL_ERROR_INVALID_SCOPE = 38,
+    SCHLUSSEL_ERROR_TEMPORARILY_UNAVAILABLE = 39,
+    SCHLUSSEL_ERROR_STALE_AUTHENTICATION = 40,
+    SCHLUSSEL_ERROR_INSUFFICIENT_AUTHENTICATION = 41,
+    SCHLUSSEL_ERROR_INVALID_LOGOUT_TOKEN = 42,
+    SCHLUSSEL_ERROR_SECRET_SERVICE_UNAVAILABLE = 43,
+    SCHLUSSEL_ERROR_INSECURE_PERMISSIONS = 44,
+    SCHLUSSEL_ERROR_UNSUPPORTED_SCHEMA_VERSION = 45,
+    SCHLUSSEL_ERROR_CERTIFICATE_MISMATCH = 46,
     SCHLUSSEL_ERROR_UNKNOWN = 99,
 } SchlusselError;
 
@@ -89,6 +115,22 @@ typedef struct SchlusselClient SchlusselClient;
  */
 typedef struct SchlusselToken SchlusselToken;
 
+/**
+ * Opaque token refresher handle
+ *
+ * Created by schlussel_token_refresher_new().
+ * Must be freed with schlussel_token_refresher_free() before its client.
+ */
+typedef struct SchlusselTokenRefresher SchlusselTokenRefresher;
+
+/**
+ * Opaque authorization flow handle
+ *
+ * Created by schlussel_start_flow_parts().
+ * Must be freed with schlussel_auth_flow_free().
+ */
+typedef struct SchlusselAuthFlow SchlusselAuthFlow;
+
 /**
  * Opaque dynamic registration client handle
  *
@@ -191,6 +233,74 @@ SchlusselToken* schlussel_authorize_device(SchlusselClient* client);
  */
 SchlusselToken* schlussel_authorize(SchlusselClient* client);
 
+/**
+ * Start an Authorization Code flow for a native authorization session
+ *
+ * Builds the authorization URL for the client's redirect URI without
+ * starting a callback server or opening a browser. The caller takes over
+ * the security responsibilities the library would otherwise handle:
+ *
+ * - Keep the code verifier and nonce secret and in memory only; never log
+ *   them or send them anywhere except the code exchange
+ * - Reject a callback whose state does not match exactly
+ * - Use the flow once; discard it after the exchange or on failure
+ *
+ * @param client    The OAuth client
+ * @return          Flow pointer on success, NULL on error
+ */
+SchlusselAuthFlow* schlussel_start_flow_parts(SchlusselClient* client);
+
+/**
+ * Exchange an authorization code without any stored flow state
+ *
+ * For callers that hold the code verifier and redirect URI themselves
+ * (see schlussel_start_flow_parts). The token is not saved to storage.
+ * The JSON never carries the DPoP private key of a DPoP-bound token.
+ *
+ * @param client            The OAuth client
+ * @param code              Authorization code from the callback
+ * @param code_verifier     PKCE verifier of the flow
+ * @param redirect_uri      Redirect URI used in the authorization request
+ * @param out_token_json    Receives the token as JSON on success, NULL on
+ *                          error; free with schlussel_string_free()
+ * @return                  SCHLUSSEL_OK on success, error code on failure
+ */
+SchlusselError schlussel_exchange_code_stateless(
+    SchlusselClient* client,
+    const char* code,
+    const char* code_verifier,
+    const char* redirect_uri,
+    char** out_token_json
+);
+
+/**
+ * Get the authorization URL (caller must free with schlussel_string_free)
+ */
+char* schlussel_auth_flow_get_url(SchlusselAuthFlow* flow);
+
+/**
+ * Get the CSRF state (caller must free with schlussel_string_free)
+ */
+char* schlussel_auth_flow_get_state(SchlusselAuthFlow* flow);
+
+/**
+ * Get the PKCE code verifier (caller must free with schlussel_string_free)
+ */
+char* schlussel_auth_flow_get_code_verifier(SchlusselAuthFlow* flow);
+
+/**
+ * Get the OIDC nonce, or NULL if the scope did not include "openid"
+ * (caller must free with schlussel_string_free)
+ */
+char* schlussel_auth_flow_get_nonce(SchlusselAuthFlow* flow);
+
+/**
+ * Free an authorization flow
+ *
+ * @param flow      The flow to free (may be NULL)
+ */
+void schlussel_auth_flow_free(SchlusselAuthFlow* flow);
+
 /* ============================================================================
  * Token storage operations
  * ============================================================================ */
@@ -215,12 +325,65 @@ SchlusselError schlussel_save_token(
  * @param client    The OAuth client
  * @param key       Storage key (null-terminated)
  * @return          Token pointer on success, NULL if not found or on error
+ *                  (SCHLUSSEL_ERROR_TOKEN_NOT_FOUND when the key has no token)
  */
 SchlusselToken* schlussel_get_token(
     SchlusselClient* client,
     const char* key
 );
 
+/**
+ * Get a valid token from storage, refreshing it first if needed
+ *
+ * All calls for one client share a single refresher, so concurrent
+ * callers wait for one in-flight refresh.
+ *
+ * @param client    The OAuth client
+ * @param key       Storage key (null-terminated)
+ * @param threshold Fraction of remaining lifetime at which to refresh
+ *                  (0.0-1.0), or a negative value for the default
+ * @return          Token pointer on success, NULL on error
+ *                  (SCHLUSSEL_ERROR_TOKEN_NOT_FOUND when the key has no token)
+ */
+SchlusselToken* schlussel_get_valid_token(
+    SchlusselClient* client,
+    const char* key,
+    double threshold
+);
+
+/**
+ * Create a token refresher for a client
+ *
+ * The refresher keeps its single-flight and failure state across calls.
+ *
+ * @param client    The OAuth client
+ * @return          Refresher handle on success, NULL on error
+ */
+SchlusselTokenRefresher* schlussel_token_refresher_new(SchlusselClient* client);
+
+/**
+ * Get a valid token through a refresher, refreshing it first if needed
+ *
+ * @param refresher The token refresher
+ * @param key       Storage key (null-terminated)
+ * @param threshold Fraction of remaining lifetime at which to refresh
+ *                  (0.0-1.0), or a negative value for the default
+ * @return          Token pointer on success, NULL on error
+ *                  (SCHLUSSEL_ERROR_TOKEN_NOT_FOUND when the key has no token)
+ */
+SchlusselToken* schlussel_token_refresher_get_valid_token(
+    SchlusselTokenRefresher* refresher,
+    const char* key,
+    double threshold
+);
+
+/**
+ * Free a token refresher
+ *
+ * @param refresher The refresher to free (may be NULL)
+ */
+void schlussel_token_refresher_free(SchlusselTokenRefresher* refresher);
+
 /**
  * Delete a token from storage
  *
@@ -548,6 +711,34 @@ char* schlussel_registration_response_get_registration_client_uri(
     SchlusselRegistrationResponse* response
 );
 
+/* ============================================================================
+ * Test utilities
+ * ============================================================================ */
+
+/*
+ * Only exported when the library is built with -Dffi-test-util=true.
+ * Define SCHLUSSEL_TEST_UTIL before including this header to declare them.
+ * Never use these in production code.
+ */
+#ifdef SCHLUSSEL_TEST_UTIL
+
+/**
+ * Pin the clock used for token expiry and refresh decisions
+ *
+ * The mock time is process-wide: it affects every client, token and thread
+ * until schlussel_clear_mock_time() is called.
+ *
+ * @param unix_secs Unix timestamp (seconds) to report as the current time
+ */
+void schlussel_set_mock_time(uint64_t unix_secs);
+
+/**
+ * Return to the system clock
+ */
+void schlussel_clear_mock_time(void);
+
+#endif /* SCHLUSSEL_TEST_UTIL */
+
 #ifdef __cplusplus
 }
 #endif
@@ -0,0 +1,295 @@
+//! Completion-based token storage
+//!
+//! `AsyncSessionStorage` is the non-blocking counterpart of SessionStorage:
+//! every operation returns immediately and reports its result through a
+//! completion callback, so network backends (Redis, Vault, cloud secret
+//! stores) do not have to hold the caller's thread.
+//!
+//! Two adapters connect it to the blocking interface:
+//!
+//! - `PooledStorage` runs any SessionStorage on a `std.Thread.Pool`
+//! - `AsyncSessionStorage.blocking()` waits for each completion, for code
+//!   that takes a SessionStorage (such as OAuthClient)
+//!
+//! ## Example
+//!
+//! ```zig
+//! var pooled = PooledStorage.init(allocator, &pool, vault.storage());
+//! const store = pooled.asyncStorage();
+//!
+//! store.load(allocator, "github", .{ .context = &request, .callback = Request.onToken });
+//! ```
+
+const std = @import("std");
+const Allocator = std.mem.Allocator;
+
+const session = @import("session.zig");
+
+const Token = session.Token;
+const SessionStorage = session.SessionStorage;
+const StorageError = session.StorageError;
+
+/// Callback receiving the result of one asynchronous operation
+pub fn Completion(comptime T: type) type {
+    return struct {
+        context: *anyopaque,
+        callback: *const fn (context: *anyopaque, result: T) void,
+
+        pub fn complete(self: @This(), result: T) void {
+            self.callback(self.context, result);
+        }
+    };
+}
+
+pub const SaveCompletion = Completion(StorageError!void);
+pub const LoadCompletion = Completion(StorageError!?Token);
+pub const ExistsCompletion = Completion(bool);
+
+/// Storage interface whose operations complete through callbacks
+///
+/// Implementations call each completion exactly once, from any thread and
+/// possibly before the operation returns. `key` and `token` are only valid
+/// during the call, so implementations copy what they keep. A loaded token
+/// belongs to the completion.
+pub const AsyncSessionStorage = struct {
+    ptr: *anyopaque,
+    vtable: *const VTable,
+
+    pub const VTable = struct {
+        save: *const fn (ptr: *anyopaque, key: []const u8, token: Token, done: SaveCompletion) void,
+        load: *const fn (ptr: *anyopaque, allocator: Allocator, key: []const u8, done: LoadCompletion) void,
+        delete: *const fn (ptr: *anyopaque, key: []const u8, done: SaveCompletion) void,
+        exists: *const fn (ptr: *anyopaque, key: []const u8, done: ExistsCompletion) void,
+    };
+
+    pub fn save(self: AsyncSessionStorage, key: []const u8, token: Token, done: SaveCompletion) void {
+        self.vtable.save(self.ptr, key, token, done);
+    }
+
+    pub fn load(self: AsyncSessionStorage, allocator: Allocator, key: []const u8, done: LoadCompletion) void {
+        self.vtable.load(self.ptr, allocator, key, done);
+    }
+
+    pub fn delete(self: AsyncSessionStorage, key: []const u8, done: SaveCompletion) void {
+        self.vtable.delete(self.ptr, key, done);
+    }
+
+    pub fn exists(self: AsyncSessionStorage, key: []const u8, done: ExistsCompletion) void {
+        self.vtable.exists(self.ptr, key, done);
+    }
+
+    /// Blocking SessionStorage that waits for each operation to complete
+    ///
+    /// `self` must outlive the returned storage.
+    pub fn blocking(self: *AsyncSessionStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = Blocking.save,
+                .load = Blocking.load,
+                .delete = Blocking.delete,
+                .exists = Blocking.exists,
+            }),
+        };
+    }
+};
+
+/// SessionStorage functions behind AsyncSessionStorage.blocking()
+const Blocking = struct {
+    fn Waiter(comptime T: type) type {
+        return struct {
+            event: std.Thread.ResetEvent = .{},
+            result: T = undefined,
+
+            fn completion(self: *@This()) Completion(T) {
+                return .{ .context = self, .callback = done };
+            }
+
+            fn done(context: *anyopaque, result: T) void {
+                const self: *@This() = @ptrCast(@alignCast(context));
+                self.result = result;
+                self.event.set();
+            }
+
+            fn wait(self: *@This()) T {
+                self.event.wait();
+                return self.result;
+            }
+        };
+    }
+
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        const self: *AsyncSessionStorage = @ptrCast(@alignCast(ptr));
+        var waiter: Waiter(StorageError!void) = .{};
+        self.save(key, token, waiter.completion());
+        return waiter.wait();
+    }
+
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *AsyncSessionStorage = @ptrCast(@alignCast(ptr));
+        var waiter: Waiter(StorageError!?Token) = .{};
+        self.load(allocator, key, waiter.completion());
+        return waiter.wait();
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        const self: *AsyncSessionStorage = @ptrCast(@alignCast(ptr));
+        var waiter: Waiter(StorageError!void) = .{};
+        self.delete(key, waiter.completion());
+        return waiter.wait();
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        const self: *AsyncSessionStorage = @ptrCast(@alignCast(ptr));
+        var waiter: Waiter(bool) = .{};
+        self.exists(key, waiter.completion());
+        return waiter.wait();
+    }
+};
+
+/// Runs a blocking SessionStorage on a thread pool
+///
+/// Each operation copies its key (and token) into a job for the pool; the
+/// completion is called on a pool thread. If the job cannot be queued the
+/// completion is called right away with the error (or false).
+pub const PooledStorage = struct {
+    allocator: Allocator,
+    pool: *std.Thread.Pool,
+    backing: SessionStorage,
+
+    pub fn init(allocator: Allocator, pool: *std.Thread.Pool, backing: SessionStorage) PooledStorage {
+        return .{ .allocator = allocator, .pool = pool, .backing = backing };
+    }
+
+    pub fn asyncStorage(self: *PooledStorage) AsyncSessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = &.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+            },
+        };
+    }
+
+    const Job = struct {
+        storage: *PooledStorage,
+        key: []u8,
+        op: union(enum) {
+            save: struct { token: Token, done: SaveCompletion },
+            load: struct { allocator: Allocator, done: LoadCompletion },
+            delete: SaveCompletion,
+            exists: ExistsCompletion,
+        },
+
+        fn run(job: *Job) void {
+            const backing = job.storage.backing;
+            switch (job.op) {
+                .save => |*op| {
+                    op.done.complete(backing.save(job.key, op.token));
+                    op.token.deinit();
+                },
+                .load => |op| op.done.complete(backing.load(op.allocator, job.key)),
+                .delete => |done| done.complete(backing.delete(job.key)),
+                .exists => |done| done.complete(backing.exists(job.key)),
+            }
+            job.destroy();
+        }
+
+        fn destroy(job: *Job) void {
+            const allocator = job.storage.allocator;
+            allocator.free(job.key);
+            allocator.destroy(job);
+        }
+    };
+
+    fn createJob(self: *PooledStorage, key: []const u8) !*Job {
+        const job = try self.allocator.create(Job);
+        errdefer self.allocator.destroy(job);
+        job.* = .{ .storage = self, .key = try self.allocator.dupe(u8, key), .op = undefined };
+        return job;
+    }
+
+    fn submit(self: *PooledStorage, job: *Job) StorageError!void {
+        self.pool.spawn(Job.run, .{job}) catch |err| return session.mapStorageError(err);
+    }
+
+    fn save(ptr: *anyopaque, key: []const u8, token: Token, done: SaveCompletion) void {
+        const self: *PooledStorage = @ptrCast(@alignCast(ptr));
+
+        const job = self.createJob(key) catch |err| return done.complete(err);
+        var copy = token.clone(self.allocator) catch |err| {
+            job.destroy();
+            return done.complete(err);
+        };
+        job.op = .{ .save = .{ .token = copy, .done = done } };
+        self.submit(job) catch |err| {
+            copy.deinit();
+            job.destroy();
+            done.complete(err);
+        };
+    }
+
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8, done: LoadCompletion) void {
+        const self: *PooledStorage = @ptrCast(@alignCast(ptr));
+
+        const job = self.createJob(key) catch |err| return done.complete(err);
+        job.op = .{ .load = .{ .allocator = allocator, .done = done } };
+        self.submit(job) catch |err| {
+            job.destroy();
+            done.complete(err);
+        };
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8, done: SaveCompletion) void {
+        const self: *PooledStorage = @ptrCast(@alignCast(ptr));
+
+        const job = self.createJob(key) catch |err| return done.complete(err);
+        job.op = .{ .delete = done };
+        self.submit(job) catch |err| {
+            job.destroy();
+            done.complete(err);
+        };
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8, done: ExistsCompletion) void {
+        const self: *PooledStorage = @ptrCast(@alignCast(ptr));
+
+        const job = self.createJob(key) catch return done.complete(false);
+        job.op = .{ .exists = done };
+        self.submit(job) catch {
+            job.destroy();
+            done.complete(false);
+        };
+    }
+};
+
+test "PooledStorage runs a blocking backend on the pool and blocking() waits for it" {
+    const allocator = std.testing.allocator;
+
+    var pool: std.Thread.Pool = undefined;
+    try pool.init(.{ .allocator = allocator, .n_jobs = 2 });
+    defer pool.deinit();
+
+    var memory = session.MemoryStorage.init(allocator);
+    defer memory.deinit();
+
+    var pooled = PooledStorage.init(allocator, &pool, memory.storage());
+    var async_store = pooled.asyncStorage();
+    const store = async_store.blocking();
+
+    var token = try Token.init(allocator, "pooled", "Bearer");
+    try store.save("user", token);
+    // The job saved its own copy
+    token.deinit();
+
+    try std.testing.expect(store.exists("user"));
+    var loaded = (try store.load(allocator, "user")).?;
+    defer loaded.deinit();
+    try std.testing.expectEqualStrings("pooled", loaded.access_token);
+
+    try store.delete("user");
+    try std.testing.expect(!memory.storage().exists("user"));
+    try std.testing.expect((try store.load(allocator, "user")) == null);
+}
@@ -0,0 +1,516 @@
+//! AWS Secrets Manager token storage
+//!
+//! `AwsSecretsStorage` keeps every token as a Secrets Manager secret named
+//! `<prefix><key>`, so serverless tools (Lambda, Fargate) can persist
+//! refresh tokens without a local disk. Requests are signed with AWS
+//! Signature Version 4 and go through an `HttpTransport`, like the OAuth
+//! client's. Build with `-Daws-secrets=true`; other builds get
+//! `error.UnsupportedOperation` from init().
+//!
+//! Lambda exposes the function role's credentials in the environment,
+//! which `Credentials.fromEnvironment()` reads. Deleting a key deletes the
+//! secret without a recovery window.
+//!
+//! ## Example
+//!
+//! ```zig
+//! const credentials = try Credentials.fromEnvironment(allocator);
+//! defer credentials.free(allocator);
+//!
+//! var secrets = try AwsSecretsStorage.init(allocator, .{
+//!     .region = "eu-west-1",
+//!     .credentials = credentials,
+//! });
+//! defer secrets.deinit();
+//!
+//! var client = OAuthClient.init(allocator, config, secrets.storage());
+//! ```
+
+const std = @import("std");
+const build_options = @import("build_options");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+const HmacSha256 = std.crypto.auth.hmac.sha2.HmacSha256;
+const Sha256 = std.crypto.hash.sha2.Sha256;
+
+const clock = @import("clock.zig");
+const codec = @import("codec.zig");
+const session = @import("session.zig");
+const transport = @import("transport.zig");
+
+const Token = session.Token;
+const SessionStorage = session.SessionStorage;
+const StorageCapabilities = session.StorageCapabilities;
+const HttpTransport = transport.HttpTransport;
+const Header = transport.Header;
+
+/// Whether AwsSecretsStorage is enabled in this build
+pub const supported = build_options.aws_secrets;
+
+/// AWS access keys
+pub const Credentials = struct {
+    access_key_id: []const u8,
+    secret_access_key: []const u8,
+    /// Present for temporary credentials (roles, Lambda)
+    session_token: ?[]const u8 = null,
+
+    /// Read `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`
+    ///
+    /// Returns `error.ConfigurationError` if the keys are not set; release
+    /// the result with free().
+    pub fn fromEnvironment(allocator: Allocator) !Credentials {
+        const access_key_id = std.process.getEnvVarOwned(allocator, "AWS_ACCESS_KEY_ID") catch |err| switch (err) {
+            error.EnvironmentVariableNotFound => return error.ConfigurationError,
+            else => return err,
+        };
+        errdefer allocator.free(access_key_id);
+        const secret_access_key = std.process.getEnvVarOwned(allocator, "AWS_SECRET_ACCESS_KEY") catch |err| switch (err) {
+            error.EnvironmentVariableNotFound => return error.ConfigurationError,
+            else => return err,
+        };
+        errdefer allocator.free(secret_access_key);
+        const session_token = std.process.getEnvVarOwned(allocator, "AWS_SESSION_TOKEN") catch |err| switch (err) {
+            error.EnvironmentVariableNotFound => null,
+            else => return err,
+        };
+        return .{
+            .access_key_id = access_key_id,
+            .secret_access_key = secret_access_key,
+            .session_token = session_token,
+        };
+    }
+
+    /// Free credentials returned by fromEnvironment()
+    pub fn free(self: Credentials, allocator: Allocator) void {
+        allocator.free(self.access_key_id);
+        std.crypto.secureZero(u8, @constCast(self.secret_access_key));
+        allocator.free(self.secret_access_key);
+        if (self.session_token) |session_token| {
+            std.crypto.secureZero(u8, @constCast(session_token));
+            allocator.free(session_token);
+        }
+    }
+};
+
+/// Token storage in AWS Secrets Manager
+pub const AwsSecretsStorage = struct {
+    allocator: Allocator,
+    options: Options,
+    /// `https://` URL requests are sent to
+    endpoint: []const u8,
+    /// HTTP transport override (defaults to std.http when null)
+    http_transport: ?HttpTransport = null,
+
+    /// Storage settings; the strings are borrowed and must outlive the storage
+    pub const Options = struct {
+        region: []const u8,
+        credentials: Credentials,
+        /// Prepended to every storage key to form the secret name
+        name_prefix: []const u8 = "schlussel/",
+        /// KMS key for new secrets; the account's default key when null
+        kms_key_id: ?[]const u8 = null,
+        /// Endpoint override (VPC endpoints, LocalStack)
+        endpoint: ?[]const u8 = null,
+    };
+
+    /// Returns `error.UnsupportedOperation` in builds without
+    /// `-Daws-secrets=true` and `error.ConfigurationError` without a region
+    /// or access key.
+    pub fn init(allocator: Allocator, options: Options) !AwsSecretsStorage {
+        if (!supported) return error.UnsupportedOperation;
+        if (options.region.len == 0 or options.credentials.access_key_id.len == 0) return error.ConfigurationError;
+
+        const endpoint = if (options.endpoint) |endpoint|
+            try allocator.dupe(u8, std.mem.trimRight(u8, endpoint, "/"))
+        else
+            try std.fmt.allocPrint(allocator, "https://secretsmanager.{s}.amazonaws.com", .{options.region});
+        return .{ .allocator = allocator, .options = options, .endpoint = endpoint };
+    }
+
+    pub fn deinit(self: *AwsSecretsStorage) void {
+        self.allocator.free(self.endpoint);
+    }
+
+    pub fn storage(self: *AwsSecretsStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+                .capabilities = capabilities,
+                .list_keys = listKeys,
+            }),
+        };
+    }
+
+    /// Get the transport used for requests to Secrets Manager
+    pub fn httpTransport(self: *const AwsSecretsStorage) HttpTransport {
+        return self.http_transport orelse transport.defaultTransport();
+    }
+
+    fn capabilities(_: *anyopaque) StorageCapabilities {
+        return .{ .list_keys = true, .atomic_swap = true };
+    }
+
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        const self: *AwsSecretsStorage = @ptrCast(@alignCast(ptr));
+
+        const name = try self.secretName(key);
+        defer self.allocator.free(name);
+        const data = try codec.default.encode(self.allocator, &token);
+        defer {
+            std.crypto.secureZero(u8, data);
+            self.allocator.free(data);
+        }
+
+        // New values go to an existing secret; the first save creates it
+        var put = try self.call("PutSecretValue", .{ .SecretId = name, .SecretString = data });
+        defer put.deinit();
+        if (put.status == 200) return;
+        if (!isNotFound(&put)) return serviceError(&put);
+
+        var create = try self.call("CreateSecret", .{ .Name = name, .SecretString = data, .KmsKeyId = self.options.kms_key_id });
+        defer create.deinit();
+        if (create.status != 200) return serviceError(&create);
+    }
+
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *AwsSecretsStorage = @ptrCast(@alignCast(ptr));
+
+        const name = try self.secretName(key);
+        defer self.allocator.free(name);
+
+        var response = try self.call("GetSecretValue", .{ .SecretId = name });
+        defer {
+            std.crypto.secureZero(u8, @constCast(response.body));
+            response.deinit();
+        }
+        if (isNotFound(&response)) return null;
+        if (response.status != 200) return serviceError(&response);
+
+        const Secret = struct { SecretString: ?[]const u8 = null };
+        const parsed = json.parseFromSlice(Secret, self.allocator, response.body, .{ .ignore_unknown_fields = true }) catch return error.ServerError;
+        defer parsed.deinit();
+
+        const data = parsed.value.SecretString orelse return error.ServerError;
+        return try codec.default.decode(allocator, data);
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        const self: *AwsSecretsStorage = @ptrCast(@alignCast(ptr));
+
+        const name = try self.secretName(key);
+        defer self.allocator.free(name);
+
+        var response = try self.call("DeleteSecret", .{ .SecretId = name, .ForceDeleteWithoutRecovery = true });
+        defer response.deinit();
+        if (response.status != 200 and !isNotFound(&response)) return serviceError(&response);
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        const self: *AwsSecretsStorage = @ptrCast(@alignCast(ptr));
+        var token = (load(self, self.allocator, key) catch return false) orelse return false;
+        token.deinit();
+        return true;
+    }
+
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) ![][]const u8 {
+        const self: *AwsSecretsStorage = @ptrCast(@alignCast(ptr));
+
+        var keys: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
+
+        const Page = struct {
+            SecretList: []const struct { Name: []const u8 } = &.{},
+            NextToken: ?[]const u8 = null,
+        };
+        const Filter = struct { Key: []const u8, Values: []const []const u8 };
+        const filters = [_]Filter{.{ .Key = "name", .Values = &.{self.options.name_prefix} }};
+
+        var next_token: ?[]u8 = null;
+        defer if (next_token) |t| self.allocator.free(t);
+        while (true) {
+            var response = try self.call("ListSecrets", .{ .Filters = &filters, .MaxResults = 100, .NextToken = next_token });
+            defer response.deinit();
+            if (response.status != 200) return serviceError(&response);
+
+            const parsed = json.parseFromSlice(Page, self.allocator, response.body, .{ .ignore_unknown_fields = true }) catch return error.ServerError;
+            defer parsed.deinit();
+
+            for (parsed.value.SecretList) |secret| {
+                // The name filter matches prefixes of words, not only of the name
+                if (!std.mem.startsWith(u8, secret.Name, self.options.name_prefix)) continue;
+                const copy = try allocator.dupe(u8, secret.Name[self.options.name_prefix.len..]);
+                errdefer allocator.free(copy);
+                try keys.append(allocator, copy);
+            }
+
+            if (next_token) |t| self.allocator.free(t);
+            next_token = null;
+            next_token = if (parsed.value.NextToken) |t| try self.allocator.dupe(u8, t) else break;
+        }
+        return keys.toOwnedSlice(allocator);
+    }
+
+    fn secretName(self: *AwsSecretsStorage, key: []const u8) ![]u8 {
+        if (key.len == 0) return error.InvalidParameter;
+        return std.mem.concat(self.allocator, u8, &.{ self.options.name_prefix, key });
+    }
+
+    /// Send a signed Secrets Manager JSON request
+    fn call(self: *AwsSecretsStorage, comptime operation: []const u8, params: anytype) !transport.Response {
+        const body = try json.Stringify.valueAlloc(self.allocator, params, .{ .emit_null_optional_fields = false });
+        defer {
+            std.crypto.secureZero(u8, body);
+            self.allocator.free(body);
+        }
+
+        const url = try std.fmt.allocPrint(self.allocator, "{s}/", .{self.endpoint});
+        defer self.allocator.free(url);
+
+        const amz_date = formatAmzDate(clock.now());
+        const content_type = "application/x-amz-json-1.1";
+        const target = "secretsmanager." ++ operation;
+        const credentials = self.options.credentials;
+
+        var signed: [5]Header = undefined;
+        var count: usize = 0;
+        signed[count] = .{ .name = "content-type", .value = content_type };
+        count += 1;
+        signed[count] = .{ .name = "host", .value = hostOf(self.endpoint) };
+        count += 1;
+        signed[count] = .{ .name = "x-amz-date", .value = &amz_date };
+        count += 1;
+        if (credentials.session_token) |session_token| {
+            signed[count] = .{ .name = "x-amz-security-token", .value = session_token };
+            count += 1;
+        }
+        signed[count] = .{ .name = "x-amz-target", .value = target };
+        count += 1;
+
+        const authorization = try signV4(self.allocator, .{
+            .credentials = credentials,
+            .region = self.options.region,
+            .service = "secretsmanager",
+            .amz_date = amz_date,
+        }, "POST", "/", signed[0..count], body);
+        defer self.allocator.free(authorization);
+
+        // Host comes from the URL
+        var headers: [5]Header = undefined;
+        var header_count: usize = 0;
+        for (signed[0..count]) |h| {
+            if (std.mem.eql(u8, h.name, "host")) continue;
+            headers[header_count] = h;
+            header_count += 1;
+        }
+        headers[header_count] = .{ .name = "authorization", .value = authorization };
+        header_count += 1;
+
+        return self.httpTransport().send(self.allocator, .{
+            .url = url,
+            .headers = headers[0..header_count],
+            .body = body,
+        });
+    }
+};
+
+fn isNotFound(response: *const transport.Response) bool {
+    if (response.status != 400) return false;
+    const Failure = struct { __type: []const u8 = "" };
+    const parsed = json.parseFromSlice(Failure, response.allocator, response.body, .{ .ignore_unknown_fields = true }) catch return false;
+    defer parsed.deinit();
+    // Types may carry a namespace (`com.amazonaws...#ResourceNotFoundException`)
+    return std.mem.endsWith(u8, parsed.value.__type, "ResourceNotFoundException");
+}
+
+fn serviceError(response: *const transport.Response) error{StorageError} {
+    std.log.warn("Secrets Manager request failed with HTTP {d}: {s}", .{ response.status, response.body });
+    return error.StorageError;
+}
+
+/// Host (and port) of an `https://host[:port][/path]` URL
+fn hostOf(url: []const u8) []const u8 {
+    const start = if (std.mem.indexOf(u8, url, "://")) |i| i + 3 else 0;
+    const end = std.mem.indexOfScalarPos(u8, url, start, '/') orelse url.len;
+    return url[start..end];
+}
+
+/// `YYYYMMDD'T'HHMMSS'Z'`
+fn formatAmzDate(timestamp: u64) [16]u8 {
+    const epoch_seconds = std.time.epoch.EpochSeconds{ .secs = timestamp };
+    const year_day = epoch_seconds.getEpochDay().calculateYearDay();
+    const month_day = year_day.calculateMonthDay();
+    const day_seconds = epoch_seconds.getDaySeconds();
+
+    var out: [16]u8 = undefined;
+    _ = std.fmt.bufPrint(&out, "{d:0>4}{d:0>2}{d:0>2}T{d:0>2}{d:0>2}{d:0>2}Z", .{
+        year_day.year,
+        month_day.month.numeric(),
+        month_day.day_index + 1,
+        day_seconds.getHoursIntoDay(),
+        day_seconds.getMinutesIntoHour(),
+        day_seconds.getSecondsIntoMinute(),
+    }) catch unreachable;
+    return out;
+}
+
+const SigningScope = struct {
+    credentials: Credentials,
+    region: []const u8,
+    service: []const u8,
+    amz_date: [16]u8,
+};
+
+/// `Authorization` header value for a request (Signature Version 4)
+///
+/// `headers` are the signed headers with lowercase names, sorted by name;
+/// the path must already be URI-encoded and the request has no query.
+fn signV4(
+    allocator: Allocator,
+    scope: SigningScope,
+    method: []const u8,
+    path: []const u8,
+    headers: []const Header,
+    body: []const u8,
+) ![]u8 {
+    const date = scope.amz_date[0..8];
+
+    var body_hash: [Sha256.digest_length]u8 = undefined;
+    Sha256.hash(body, &body_hash, .{});
+
+    var signed_headers: std.ArrayListUnmanaged(u8) = .{};
+    defer signed_headers.deinit(allocator);
+    var canonical: std.ArrayListUnmanaged(u8) = .{};
+    defer canonical.deinit(allocator);
+
+    const writer = canonical.writer(allocator);
+    try writer.print("{s}\n{s}\n\n", .{ method, path });
+    for (headers, 0..) |h, i| {
+        try writer.print("{s}:{s}\n", .{ h.name, std.mem.trim(u8, h.value, " ") });
+        if (i > 0) try signed_headers.append(allocator, ';');
+        try signed_headers.appendSlice(allocator, h.name);
+    }
+    try writer.print("\n{s}\n{s}", .{ signed_headers.items, &std.fmt.bytesToHex(body_hash, .lower) });
+
+    var canonical_hash: [Sha256.digest_length]u8 = undefined;
+    Sha256.hash(canonical.items, &canonical_hash, .{});
+
+    const string_to_sign = try std.fmt.allocPrint(allocator, "AWS4-HMAC-SHA256\n{s}\n{s}/{s}/{s}/aws4_request\n{s}", .{
+        scope.amz_date, date, scope.region, scope.service, &std.fmt.bytesToHex(canonical_hash, .lower),
+    });
+    defer allocator.free(string_to_sign);
+
+    const secret = try std.mem.concat(allocator, u8, &.{ "AWS4", scope.credentials.secret_access_key });
+    defer {
+        std.crypto.secureZero(u8, secret);
+        allocator.free(secret);
+    }
+    var key: [HmacSha256.mac_length]u8 = undefined;
+    HmacSha256.create(&key, date, secret);
+    HmacSha256.create(&key, scope.region, &key);
+    HmacSha256.create(&key, scope.service, &key);
+    HmacSha256.create(&key, "aws4_request", &key);
+    defer std.crypto.secureZero(u8, &key);
+
+    var signature: [HmacSha256.mac_length]u8 = undefined;
+    HmacSha256.create(&signature, string_to_sign, &key);
+
+    return std.fmt.allocPrint(allocator, "AWS4-HMAC-SHA256 Credential={s}/{s}/{s}/{s}/aws4_request, SignedHeaders={s}, Signature={s}", .{
+        scope.credentials.access_key_id, date, scope.region, scope.service, signed_headers.items, &std.fmt.bytesToHex(signature, .lower),
+    });
+}
+
+test "signV4 matches the AWS get-vanilla test vector" {
+    const allocator = std.testing.allocator;
+
+    const amz_date = formatAmzDate(1_440_938_160);
+    try std.testing.expectEqualStrings("20150830T123600Z", &amz_date);
+
+    const authorization = try signV4(allocator, .{
+        .credentials = .{
+            .access_key_id = "AKIDEXAMPLE",
+            .secret_access_key = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+        },
+        .region = "us-east-1",
+        .service = "service",
+        .amz_date = amz_date,
+    }, "GET", "/", &.{
+        .{ .name = "host", .value = "example.amazonaws.com" },
+        .{ .name = "x-amz-date", .value = &amz_date },
+    }, "");
+    defer allocator.free(authorization);
+
+    try std.testing.expectEqualStrings(
+        "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, " ++
+            "SignedHeaders=host;x-amz-date, " ++
+            "Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+        authorization,
+    );
+}
+
+test "AwsSecretsStorage creates the secret on first save" {
+    const allocator = std.testing.allocator;
+
+    const options: AwsSecretsStorage.Options = .{
+        .region = "eu-west-1",
+        .credentials = .{ .access_key_id = "AKIDEXAMPLE", .secret_access_key = "secret", .session_token = "session" },
+    };
+    if (!supported) {
+        try std.testing.expectError(error.UnsupportedOperation, AwsSecretsStorage.init(allocator, options));
+        return;
+    }
+
+    clock.setMockTime(1_440_938_160);
+    defer clock.clearMockTime();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var secrets = try AwsSecretsStorage.init(allocator, options);
+    defer secrets.deinit();
+    secrets.http_transport = mock.transport();
+    const store = secrets.storage();
+
+    var token = try Token.initFull(allocator, "aws-access", "Bearer", "aws-refresh", 3600, null, null);
+    defer token.deinit();
+
+    try mock.enqueue(.{ .status = 400, .body = "{\"__type\":\"ResourceNotFoundException\",\"Message\":\"not found\"}" });
+    try mock.enqueue(.{ .body = "{\"ARN\":\"arn:aws:secretsmanager:eu-west-1:1:secret:schlussel/lambda\"}" });
+    try store.save("lambda", token);
+
+    try std.testing.expectEqual(@as(usize, 2), mock.requestCount());
+    const put = mock.request(0).?;
+    try std.testing.expectEqualStrings("https://secretsmanager.eu-west-1.amazonaws.com/", put.url);
+    try std.testing.expectEqualStrings("secretsmanager.PutSecretValue", put.header("x-amz-target").?);
+    try std.testing.expectEqualStrings("session", put.header("x-amz-security-token").?);
+    try std.testing.expect(std.mem.startsWith(u8, put.header("authorization").?, "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/eu-west-1/secretsmanager/aws4_request, " ++
+        "SignedHeaders=content-type;host;x-amz-date;x-amz-security-token;x-amz-target, Signature="));
+    const create = mock.request(1).?;
+    try std.testing.expectEqualStrings("secretsmanager.CreateSecret", create.header("x-amz-target").?);
+    try std.testing.expect(std.mem.indexOf(u8, create.body.?, "\"Name\":\"schlussel/lambda\"") != null);
+    try std.testing.expect(std.mem.indexOf(u8, create.body.?, "KmsKeyId") == null);
+
+    const data = try codec.default.encode(allocator, &token);
+    defer allocator.free(data);
+    const secret = try json.Stringify.valueAlloc(allocator, .{ .SecretString = data }, .{});
+    defer allocator.free(secret);
+    try mock.enqueue(.{ .body = secret });
+    var loaded = (try store.load(allocator, "lambda")).?;
+    defer loaded.deinit();
+    try std.testing.expectEqualStrings("aws-refresh", loaded.refresh_token.?);
+
+    try mock.enqueue(.{ .status = 400, .body = "{\"__type\":\"ResourceNotFoundException\"}" });
+    try std.testing.expect((try store.load(allocator, "missing")) == null);
+
+    try mock.enqueue(.{ .body = "{\"SecretList\":[{\"Name\":\"schlussel/lambda\"},{\"Name\":\"other/schlussel/x\"}]}" });
+    const keys = try store.listKeys(allocator);
+    defer SessionStorage.freeKeys(allocator, keys);
+    try std.testing.expectEqual(@as(usize, 1), keys.len);
+    try std.testing.expectEqualStrings("lambda", keys[0]);
+}
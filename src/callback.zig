@@ -2,7 +2,8 @@
 //!
 //! This module provides a simple HTTP server that listens for OAuth
 //! authorization code callbacks. It handles the redirect from the
-//! authorization server and extracts the authorization code.
+//! authorization server and extracts the authorization code, whether it
+//! arrives as query parameters or as a `response_mode=form_post` body.
 //!
 //! ## Example
 //!
@@ -26,6 +27,8 @@ const net = std.net;
 const http = std.http;
 const Allocator = std.mem.Allocator;
 
+const error_types = @import("error.zig");
+
 /// Cross-platform helper to check if an environment variable is set
 fn hasEnvVar(name: []const u8) bool {
     if (std.process.getEnvVarOwned(std.heap.page_allocator, name)) |val| {
@@ -47,6 +50,11 @@ pub const CallbackResult = struct {
     error_code: ?[]const u8,
     /// Error description
     error_description: ?[]const u8,
+    /// Link to documentation about the error
+    error_uri: ?[]const u8 = null,
+    /// JWT-secured response (JARM) still to be unwrapped with
+    /// OAuthClient.unwrapJarmResponse()
+    response: ?[]const u8 = null,
 
     pub fn init(allocator: Allocator) CallbackResult {
         return .{
@@ -63,17 +71,29 @@ pub const CallbackResult = struct {
         if (self.state) |s| self.allocator.free(s);
         if (self.error_code) |e| self.allocator.free(e);
         if (self.error_description) |d| self.allocator.free(d);
+        if (self.error_uri) |u| self.allocator.free(u);
+        if (self.response) |r| self.allocator.free(r);
     }
 
     /// Check if the callback was successful
     pub fn isSuccess(self: *const CallbackResult) bool {
-        return self.code != null and self.error_code == null;
+        return (self.code != null or self.response != null) and self.error_code == null;
     }
 
     /// Check if there was an error
     pub fn isError(self: *const CallbackResult) bool {
         return self.error_code != null;
     }
+
+    /// The OAuthError for the `error` parameter, or null without one
+    ///
+    /// Codes outside the specs map to `error.AuthorizationDenied`; the raw
+    /// code stays available in `error_code`.
+    pub fn oauthError(self: *const CallbackResult) ?error_types.OAuthError {
+        const code = self.error_code orelse return null;
+        const known = error_types.ErrorCode.fromString(code) orelse return error.AuthorizationDenied;
+        return known.toError();
+    }
 };
 
 /// Local HTTP server for OAuth callbacks
@@ -195,23 +215,10 @@ pub const CallbackServer = struct {
         };
         defer connection.stream.close();
 
-        // Read the HTTP request
-        var buf: [4096]u8 = undefined;
-        const bytes_read = try connection.stream.read(&buf);
-        if (bytes_read == 0) {
-            return error.ConnectionClosed;
-        }
-
-        const request = buf[0..bytes_read];
-
-        // Parse the request line to get the path
-        const path = try parsePath(request);
-
-        // Parse query parameters
-        if (std.mem.indexOf(u8, path, "?")) |query_start| {
-            const query_string = path[query_start + 1 ..];
-            try parseQueryParams(self.allocator, query_string, &result);
-        }
+        // Read the HTTP request, including a form_post body
+        var buf: [max_request_len]u8 = undefined;
+        const request = try readRequest(connection.stream, &buf);
+        try parseRequest(self.allocator, request, &result);
 
         // Send appropriate response
         const response_html = if (result.isSuccess()) success_html else error_html;
@@ -232,6 +239,74 @@ pub const CallbackServer = struct {
         return result;
     }
 
+    /// Largest callback request accepted, headers and body included
+    const max_request_len = 16 * 1024;
+
+    /// Read from `stream` until the headers and any `Content-Length` body
+    /// have arrived
+    fn readRequest(stream: net.Stream, buf: []u8) ![]const u8 {
+        var len: usize = 0;
+        while (true) {
+            if (len == buf.len) return error.InvalidRequest;
+            const bytes_read = try stream.read(buf[len..]);
+            if (bytes_read == 0) {
+                if (len == 0) return error.ConnectionClosed;
+                return buf[0..len];
+            }
+            len += bytes_read;
+
+            const head_end = std.mem.indexOf(u8, buf[0..len], "\r\n\r\n") orelse continue;
+            const body_len = try contentLength(buf[0..head_end]);
+            if (head_end + 4 + body_len > buf.len) return error.InvalidRequest;
+            if (len >= head_end + 4 + body_len) return buf[0..len];
+        }
+    }
+
+    /// Extract the authorization response from a raw HTTP request
+    ///
+    /// Reads the query parameters of a redirect and, for
+    /// `response_mode=form_post`, the `application/x-www-form-urlencoded`
+    /// body of a POST. Other POST bodies yield `error.InvalidRequest`.
+    pub fn parseRequest(allocator: Allocator, request: []const u8, result: *CallbackResult) !void {
+        const path = try parsePath(request);
+        if (std.mem.indexOf(u8, path, "?")) |query_start| {
+            try parseQueryParams(allocator, path[query_start + 1 ..], result);
+        }
+
+        if (!std.mem.startsWith(u8, request, "POST ")) return;
+
+        const head_end = std.mem.indexOf(u8, request, "\r\n\r\n") orelse return error.InvalidRequest;
+        const head = request[0..head_end];
+        const content_type = headerValue(head, "Content-Type") orelse return error.InvalidRequest;
+        if (!std.ascii.startsWithIgnoreCase(content_type, "application/x-www-form-urlencoded")) return error.InvalidRequest;
+
+        const body = request[head_end + 4 ..];
+        try parseFormBody(allocator, body[0..@min(body.len, try contentLength(head))], result);
+    }
+
+    /// Parse a form_post body (`code=...&state=...`) into `result`
+    pub fn parseFormBody(allocator: Allocator, body: []const u8, result: *CallbackResult) !void {
+        try parseQueryParams(allocator, std.mem.trimRight(u8, body, "\r\n"), result);
+    }
+
+    fn contentLength(head: []const u8) !usize {
+        const value = headerValue(head, "Content-Length") orelse return 0;
+        return std.fmt.parseInt(usize, value, 10) catch error.InvalidRequest;
+    }
+
+    /// Value of header `name` in the request head, without surrounding spaces
+    fn headerValue(head: []const u8, name: []const u8) ?[]const u8 {
+        var lines = std.mem.splitSequence(u8, head, "\r\n");
+        _ = lines.next(); // Skip the request line
+        while (lines.next()) |line| {
+            const colon = std.mem.indexOfScalar(u8, line, ':') orelse continue;
+            if (std.ascii.eqlIgnoreCase(line[0..colon], name)) {
+                return std.mem.trim(u8, line[colon + 1 ..], " \t");
+            }
+        }
+        return null;
+    }
+
     fn parsePath(request: []const u8) ![]const u8 {
         // Find the first line (request line)
         const line_end = std.mem.indexOf(u8, request, "\r\n") orelse request.len;
@@ -254,14 +329,25 @@ pub const CallbackServer = struct {
 
                 const decoded = try urlDecode(allocator, value);
 
-                if (std.mem.eql(u8, key, "code")) {
-                    result.code = decoded;
-                } else if (std.mem.eql(u8, key, "state")) {
-                    result.state = decoded;
-                } else if (std.mem.eql(u8, key, "error")) {
-                    result.error_code = decoded;
-                } else if (std.mem.eql(u8, key, "error_description")) {
-                    result.error_description = decoded;
+                const field: ?*?[]const u8 = if (std.mem.eql(u8, key, "code"))
+                    &result.code
+                else if (std.mem.eql(u8, key, "state"))
+                    &result.state
+                else if (std.mem.eql(u8, key, "error"))
+                    &result.error_code
+                else if (std.mem.eql(u8, key, "error_description"))
+                    &result.error_description
+                else if (std.mem.eql(u8, key, "error_uri"))
+                    &result.error_uri
+                else if (std.mem.eql(u8, key, "response"))
+                    &result.response
+                else
+                    null;
+
+                // A repeated parameter replaces the earlier value
+                if (field) |slot| {
+                    if (slot.*) |previous| allocator.free(previous);
+                    slot.* = decoded;
                 } else {
                     allocator.free(decoded);
                 }
@@ -269,7 +355,8 @@ pub const CallbackServer = struct {
         }
     }
 
-    fn urlDecode(allocator: Allocator, input: []const u8) ![]const u8 {
+    /// Decode a percent-encoded form value (`+` decodes to a space)
+    pub fn urlDecode(allocator: Allocator, input: []const u8) ![]const u8 {
         var output: std.ArrayListUnmanaged(u8) = .{};
         errdefer output.deinit(allocator);
 
@@ -306,6 +393,7 @@ pub fn buildAuthorizationUrl(
     scope: ?[]const u8,
     state: []const u8,
     code_challenge: []const u8,
+    code_challenge_method: []const u8,
 ) ![]const u8 {
     var buf: std.ArrayListUnmanaged(u8) = .{};
     errdefer buf.deinit(allocator);
@@ -321,7 +409,8 @@ pub fn buildAuthorizationUrl(
     try appendUrlEncoded(allocator, &buf, state);
     try buf.appendSlice(allocator, "&code_challenge=");
     try appendUrlEncoded(allocator, &buf, code_challenge);
-    try buf.appendSlice(allocator, "&code_challenge_method=S256");
+    try buf.appendSlice(allocator, "&code_challenge_method=");
+    try buf.appendSlice(allocator, code_challenge_method);
 
     if (scope) |s| {
         try buf.appendSlice(allocator, "&scope=");
@@ -331,6 +420,76 @@ pub fn buildAuthorizationUrl(
     return buf.toOwnedSlice(allocator);
 }
 
+/// Authorization URL split into its endpoint and decoded query parameters
+pub const AuthorizationRequest = struct {
+    allocator: Allocator,
+    /// Endpoint URL without the query string
+    base: []const u8,
+    /// Decoded query parameters in the order they appear in the URL
+    query_params: []const QueryParam,
+
+    pub const QueryParam = struct {
+        name: []const u8,
+        value: []const u8,
+    };
+
+    /// Parse an authorization URL such as one built by buildAuthorizationUrl()
+    pub fn parse(allocator: Allocator, url: []const u8) !AuthorizationRequest {
+        const query_start = std.mem.indexOfScalar(u8, url, '?');
+        const end = std.mem.indexOfScalar(u8, url, '#') orelse url.len;
+        const base_end = query_start orelse end;
+
+        const base = try allocator.dupe(u8, url[0..base_end]);
+        errdefer allocator.free(base);
+
+        var params: std.ArrayListUnmanaged(QueryParam) = .{};
+        errdefer {
+            for (params.items) |param| freeParam(allocator, param);
+            params.deinit(allocator);
+        }
+
+        if (query_start) |start| {
+            var pairs = std.mem.splitScalar(u8, url[start + 1 .. end], '&');
+            while (pairs.next()) |pair| {
+                if (pair.len == 0) continue;
+                const eq_pos = std.mem.indexOfScalar(u8, pair, '=') orelse pair.len;
+
+                const name = try CallbackServer.urlDecode(allocator, pair[0..eq_pos]);
+                errdefer allocator.free(name);
+                const value = try CallbackServer.urlDecode(allocator, if (eq_pos < pair.len) pair[eq_pos + 1 ..] else "");
+                errdefer allocator.free(value);
+
+                try params.append(allocator, .{ .name = name, .value = value });
+            }
+        }
+
+        return .{
+            .allocator = allocator,
+            .base = base,
+            .query_params = try params.toOwnedSlice(allocator),
+        };
+    }
+
+    pub fn deinit(self: *AuthorizationRequest) void {
+        self.allocator.free(self.base);
+        for (self.query_params) |param| freeParam(self.allocator, param);
+        self.allocator.free(self.query_params);
+    }
+
+    /// Get the first value of query parameter `name`
+    pub fn get(self: *const AuthorizationRequest, name: []const u8) ?[]const u8 {
+        for (self.query_params) |param| {
+            if (std.mem.eql(u8, param.name, name)) return param.value;
+        }
+        return null;
+    }
+
+    fn freeParam(allocator: Allocator, param: QueryParam) void {
+        allocator.free(param.name);
+        allocator.free(param.value);
+    }
+};
+
 /// Append a URL-encoded string to the buffer (RFC 3986 unreserved characters)
 ///
 /// This function encodes all characters except unreserved characters:
@@ -434,6 +593,48 @@ test "CallbackResult error check" {
     try std.testing.expect(result.isError());
 }
 
+test "CallbackServer.parseRequest reads query parameters and form_post bodies" {
+    const allocator = std.testing.allocator;
+
+    var redirect = CallbackResult.init(allocator);
+    defer redirect.deinit();
+    try CallbackServer.parseRequest(allocator, "GET /callback?code=abc&state=xyz HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", &redirect);
+    try std.testing.expectEqualStrings("abc", redirect.code.?);
+    try std.testing.expectEqualStrings("xyz", redirect.state.?);
+
+    var posted = CallbackResult.init(allocator);
+    defer posted.deinit();
+    try CallbackServer.parseRequest(allocator, "POST /callback HTTP/1.1\r\n" ++
+        "Content-Type: application/x-www-form-urlencoded\r\n" ++
+        "Content-Length: 28\r\n\r\n" ++
+        "code=a%2Fb&state=xyz&extra=1", &posted);
+    try std.testing.expectEqualStrings("a/b", posted.code.?);
+    try std.testing.expectEqualStrings("xyz", posted.state.?);
+
+    var unsupported = CallbackResult.init(allocator);
+    defer unsupported.deinit();
+    try std.testing.expectError(error.InvalidRequest, CallbackServer.parseRequest(
+        allocator,
+        "POST /callback HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"code\":\"abc\"}",
+        &unsupported,
+    ));
+}
+
+test "CallbackResult.oauthError maps the error parameter" {
+    const allocator = std.testing.allocator;
+
+    var result = CallbackResult.init(allocator);
+    defer result.deinit();
+    try std.testing.expect(result.oauthError() == null);
+
+    result.error_code = try allocator.dupe(u8, "invalid_scope");
+    try std.testing.expectEqual(@as(?error_types.OAuthError, error.InvalidScope), result.oauthError());
+
+    allocator.free(result.error_code.?);
+    result.error_code = try allocator.dupe(u8, "login_required");
+    try std.testing.expectEqual(@as(?error_types.OAuthError, error.AuthorizationDenied), result.oauthError());
+}
+
 test "buildAuthorizationUrl" {
     const allocator = std.testing.allocator;
 
@@ -445,6 +646,7 @@ test "buildAuthorizationUrl" {
         "read write",
         "state123",
         "challenge123",
+        "S256",
     );
     defer allocator.free(url);
 
@@ -455,6 +657,24 @@ test "buildAuthorizationUrl" {
     try std.testing.expect(std.mem.indexOf(u8, url, "code_challenge_method=S256") != null);
 }
 
+test "AuthorizationRequest.parse decodes query parameters" {
+    const allocator = std.testing.allocator;
+
+    var request = try AuthorizationRequest.parse(
+        allocator,
+        "https://example.com/authorize?response_type=code&scope=read%20write&empty=&flag",
+    );
+    defer request.deinit();
+
+    try std.testing.expectEqualStrings("https://example.com/authorize", request.base);
+    try std.testing.expectEqual(@as(usize, 4), request.query_params.len);
+    try std.testing.expectEqualStrings("code", request.get("response_type").?);
+    try std.testing.expectEqualStrings("read write", request.get("scope").?);
+    try std.testing.expectEqualStrings("", request.get("empty").?);
+    try std.testing.expectEqualStrings("", request.get("flag").?);
+    try std.testing.expect(request.get("missing") == null);
+}
+
 test "URL encoding special characters" {
     const allocator = std.testing.allocator;
 
@@ -0,0 +1,165 @@
+//! Client-Initiated Backchannel Authentication (OpenID Connect CIBA Core 1.0)
+//!
+//! Authenticates a user on their own device while the client waits: the
+//! client posts a hint identifying the user to the backchannel endpoint,
+//! the provider asks the user to approve on their phone, and the client
+//! collects the token from the token endpoint. In poll mode the client
+//! asks periodically; in ping mode the provider first calls the client's
+//! notification endpoint and the client then fetches the token once.
+//!
+//! `OAuthClient.startBackchannelAuth()` runs the flow; tokens are stored
+//! and refreshed like any other.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var flow = try client.startBackchannelAuth(.{
+//!     .login_hint = "alice@example.com",
+//!     .binding_message = "W4SCT",
+//! });
+//! defer flow.deinit();
+//!
+//! var token = try flow.wait("alice");
+//! defer token.deinit();
+//! ```
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+
+const constantTimeEql = @import("id_token.zig").constantTimeEql;
+
+/// Grant type for collecting CIBA tokens (CIBA Core Section 10.1)
+pub const grant_type = "urn:openid:params:grant-type:ciba";
+
+/// How the client learns that the user has responded
+pub const DeliveryMode = enum {
+    /// The client polls the token endpoint
+    poll,
+    /// The provider notifies the client's registered endpoint, then the
+    /// client fetches the token
+    ping,
+};
+
+/// Parameters of a backchannel authentication request (CIBA Core Section 7.1)
+pub const BackchannelRequest = struct {
+    /// Scopes to request; `openid` is added when missing. Defaults to the
+    /// configured scope.
+    scope: ?[]const u8 = null,
+    /// Exactly one of `login_hint`, `login_hint_token` and `id_token_hint`
+    /// identifies the user
+    login_hint: ?[]const u8 = null,
+    login_hint_token: ?[]const u8 = null,
+    id_token_hint: ?[]const u8 = null,
+    /// Short text shown on both devices so the user can match them
+    binding_message: ?[]const u8 = null,
+    /// Secret the user knows, for providers that require one
+    user_code: ?[]const u8 = null,
+    /// Requested lifetime of the request, in seconds
+    requested_expiry: ?u64 = null,
+    delivery_mode: DeliveryMode = .poll,
+
+    /// Check that exactly one user hint is set
+    pub fn validate(self: *const BackchannelRequest) !void {
+        var hints: usize = 0;
+        inline for (.{ self.login_hint, self.login_hint_token, self.id_token_hint }) |hint| {
+            if (hint) |value| {
+                if (value.len == 0) return error.InvalidParameter;
+                hints += 1;
+            }
+        }
+        if (hints != 1) return error.InvalidParameter;
+    }
+};
+
+/// Successful backchannel authentication response (CIBA Core Section 7.3)
+pub const BackchannelAuthResponse = struct {
+    allocator: Allocator,
+    /// Identifies the request when collecting the token
+    auth_req_id: []const u8,
+    /// Lifetime of `auth_req_id` in seconds
+    expires_in: u64,
+    /// Minimum polling interval in seconds
+    interval: u64 = default_interval,
+
+    /// Polling interval when the provider does not send one
+    pub const default_interval: u64 = 5;
+
+    pub fn parse(allocator: Allocator, body: []const u8) !BackchannelAuthResponse {
+        const parsed = json.parseFromSlice(json.Value, allocator, body, .{}) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.ServerError,
+        };
+        defer parsed.deinit();
+
+        if (parsed.value != .object) return error.ServerError;
+        const obj = parsed.value.object;
+
+        const auth_req_id = obj.get("auth_req_id") orelse return error.ServerError;
+        if (auth_req_id != .string or auth_req_id.string.len == 0) return error.ServerError;
+        const expires_in = obj.get("expires_in") orelse return error.ServerError;
+        if (expires_in != .integer or expires_in.integer <= 0) return error.ServerError;
+
+        var interval = default_interval;
+        if (obj.get("interval")) |value| {
+            if (value == .integer and value.integer >= 0) interval = @intCast(value.integer);
+        }
+
+        return .{
+            .allocator = allocator,
+            .auth_req_id = try allocator.dupe(u8, auth_req_id.string),
+            .expires_in = @intCast(expires_in.integer),
+            .interval = interval,
+        };
+    }
+
+    pub fn deinit(self: *BackchannelAuthResponse) void {
+        self.allocator.free(self.auth_req_id);
+    }
+};
+
+/// Check a ping callback from the provider (CIBA Core Section 10.2)
+///
+/// `authorization` is the request's `Authorization` header, which must be
+/// `Bearer` followed by the `client_notification_token` sent with the
+/// request, and `body` is the JSON document naming the `auth_req_id`.
+/// Returns `error.InvalidParameter` if the token or request ID do not match.
+pub fn verifyPing(
+    allocator: Allocator,
+    authorization: ?[]const u8,
+    body: []const u8,
+    client_notification_token: []const u8,
+    auth_req_id: []const u8,
+) !void {
+    const header = authorization orelse return error.InvalidParameter;
+    const prefix = "Bearer ";
+    if (header.len < prefix.len or !std.ascii.eqlIgnoreCase(header[0..prefix.len], prefix)) return error.InvalidParameter;
+    if (!constantTimeEql(header[prefix.len..], client_notification_token)) return error.InvalidParameter;
+
+    const parsed = json.parseFromSlice(json.Value, allocator, body, .{}) catch |err| switch (err) {
+        error.OutOfMemory => return err,
+        else => return error.InvalidParameter,
+    };
+    defer parsed.deinit();
+
+    if (parsed.value != .object) return error.InvalidParameter;
+    const id = parsed.value.object.get("auth_req_id") orelse return error.InvalidParameter;
+    if (id != .string or !std.mem.eql(u8, id.string, auth_req_id)) return error.InvalidParameter;
+}
+
+test "BackchannelRequest.validate requires exactly one hint" {
+    try (BackchannelRequest{ .login_hint = "alice" }).validate();
+    try std.testing.expectError(error.InvalidParameter, (BackchannelRequest{}).validate());
+    try std.testing.expectError(error.InvalidParameter, (BackchannelRequest{ .login_hint = "alice", .id_token_hint = "a.b.c" }).validate());
+    try std.testing.expectError(error.InvalidParameter, (BackchannelRequest{ .login_hint = "" }).validate());
+}
+
+test "verifyPing checks the notification token and request ID" {
+    const allocator = std.testing.allocator;
+    const body = "{\"auth_req_id\":\"req-1\"}";
+
+    try verifyPing(allocator, "Bearer token-1", body, "token-1", "req-1");
+    try std.testing.expectError(error.InvalidParameter, verifyPing(allocator, "Bearer token-2", body, "token-1", "req-1"));
+    try std.testing.expectError(error.InvalidParameter, verifyPing(allocator, null, body, "token-1", "req-1"));
+    try std.testing.expectError(error.InvalidParameter, verifyPing(allocator, "Bearer token-1", body, "token-1", "req-2"));
+}
@@ -302,6 +302,7 @@ fn resolveScriptSteps(
             config.scope,
             &state,
             pair.getChallenge(),
+            pair.getChallengeMethod(),
         );
         const authorize_dup = try allocator.dupe(u8, authorize_url);
         allocator.free(authorize_url);
@@ -965,6 +966,7 @@ fn cmdRun(allocator: Allocator, args: []const []const u8, stdout: anytype, stder
 
         var result = try server.waitForCallback(120);
         defer result.deinit();
+        try client.unwrapJarmResponse(&result);
 
         if (result.state) |callback_state| {
             if (!std.mem.eql(u8, callback_state, state)) {
@@ -972,9 +974,7 @@ fn cmdRun(allocator: Allocator, args: []const []const u8, stdout: anytype, stder
             }
         }
 
-        if (result.error_code != null) {
-            return error.AuthorizationDenied;
-        }
+        if (result.oauthError()) |err| return err;
 
         const code = result.code orelse return error.ServerError;
         token = client.exchangeCode(code, pkce_verifier, callback_uri) catch |err| {
@@ -0,0 +1,120 @@
+//! Clock source for token expiry decisions
+//!
+//! Every expiry and refresh check reads the current time through `now()`.
+//! Tests can pin the clock with `setMockTime()` to exercise expiry logic
+//! without sleeping. The mock is process-wide and affects every thread.
+//!
+//! ## Example
+//!
+//! ```zig
+//! clock.setMockTime(token.expires_at.? + 1);
+//! defer clock.clearMockTime();
+//! try std.testing.expect(token.isExpired());
+//! ```
+
+const std = @import("std");
+
+/// Sentinel meaning no mock time is installed
+const no_mock = std.math.maxInt(u64);
+
+var mock_time = std.atomic.Value(u64).init(no_mock);
+
+/// Current Unix time in seconds (the mock time, if one is installed)
+pub fn now() u64 {
+    const mocked = mock_time.load(.acquire);
+    if (mocked != no_mock) return mocked;
+    return @intCast(std.time.timestamp());
+}
+
+/// Current Unix time in milliseconds (the mock time, if one is installed)
+pub fn nowMillis() i64 {
+    const mocked = mock_time.load(.acquire);
+    if (mocked != no_mock) return @intCast(mocked * std.time.ms_per_s);
+    return std.time.milliTimestamp();
+}
+
+/// Pin `now()` to `unix_secs` for the whole process (test only)
+pub fn setMockTime(unix_secs: u64) void {
+    mock_time.store(unix_secs, .release);
+}
+
+/// Return to the system clock
+pub fn clearMockTime() void {
+    mock_time.store(no_mock, .release);
+}
+
+/// Whether a mock time is currently installed
+pub fn isMocked() bool {
+    return mock_time.load(.acquire) != no_mock;
+}
+
+/// Current time shifted by `offset` seconds (clamped at the epoch)
+pub fn nowWithOffset(offset: i64) u64 {
+    const shifted = @as(i64, @intCast(now())) +| offset;
+    return @intCast(@max(shifted, 0));
+}
+
+/// Parse an HTTP `Date` header in IMF-fixdate form (RFC 9110 Section 5.6.7)
+///
+/// For example `Sun, 06 Nov 1994 08:49:37 GMT`. Returns Unix seconds, or
+/// null if the value is not a valid IMF-fixdate.
+pub fn parseHttpDate(value: []const u8) ?u64 {
+    // "Sun, 06 Nov 1994 08:49:37 GMT"
+    if (value.len != 29) return null;
+    if (value[3] != ',' or value[4] != ' ' or value[7] != ' ' or value[11] != ' ' or
+        value[16] != ' ' or value[19] != ':' or value[22] != ':' or value[25] != ' ')
+    {
+        return null;
+    }
+    if (!std.mem.eql(u8, value[26..29], "GMT")) return null;
+
+    const day = std.fmt.parseInt(u8, value[5..7], 10) catch return null;
+    const year = std.fmt.parseInt(u16, value[12..16], 10) catch return null;
+    const hour = std.fmt.parseInt(u8, value[17..19], 10) catch return null;
+    const minute = std.fmt.parseInt(u8, value[20..22], 10) catch return null;
+    const second = std.fmt.parseInt(u8, value[23..25], 10) catch return null;
+
+    const months = [_][]const u8{ "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec" };
+    const month: u8 = for (months, 1..) |name, number| {
+        if (std.mem.eql(u8, value[8..11], name)) break @intCast(number);
+    } else return null;
+
+    if (year < 1970 or day < 1 or hour > 23 or minute > 59 or second > 60) return null;
+    const month_days = std.time.epoch.getDaysInMonth(year, @enumFromInt(month));
+    if (day > month_days) return null;
+
+    // Days since the epoch
+    var days: u64 = 0;
+    var y: u16 = 1970;
+    while (y < year) : (y += 1) days += std.time.epoch.getDaysInYear(y);
+    var m: u8 = 1;
+    while (m < month) : (m += 1) days += std.time.epoch.getDaysInMonth(year, @enumFromInt(m));
+    days += day - 1;
+
+    return days * std.time.s_per_day + @as(u64, hour) * 3600 + @as(u64, minute) * 60 + second;
+}
+
+test "parseHttpDate reads IMF-fixdate" {
+    try std.testing.expectEqual(@as(?u64, 784111777), parseHttpDate("Sun, 06 Nov 1994 08:49:37 GMT"));
+    try std.testing.expectEqual(@as(?u64, 1_699_992_800), parseHttpDate("Tue, 14 Nov 2023 20:13:20 GMT"));
+    try std.testing.expectEqual(@as(?u64, 951_782_400), parseHttpDate("Tue, 29 Feb 2000 00:00:00 GMT"));
+
+    try std.testing.expect(parseHttpDate("Sunday, 06-Nov-94 08:49:37 GMT") == null);
+    try std.testing.expect(parseHttpDate("Sun, 06 Foo 1994 08:49:37 GMT") == null);
+    try std.testing.expect(parseHttpDate("Thu, 30 Feb 2023 00:00:00 GMT") == null);
+    try std.testing.expect(parseHttpDate("Sun, 06 Nov 1994 08:49:37 UTC") == null);
+}
+
+test "mock time overrides and restores the system clock" {
+    try std.testing.expect(!isMocked());
+
+    setMockTime(1_000);
+    defer clearMockTime();
+    try std.testing.expectEqual(@as(u64, 1_000), now());
+    try std.testing.expectEqual(@as(i64, 1_000_000), nowMillis());
+    try std.testing.expect(isMocked());
+
+    clearMockTime();
+    try std.testing.expect(!isMocked());
+    try std.testing.expect(now() > 1_000);
+}
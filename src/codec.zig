@@ -0,0 +1,317 @@
+//! JSON codecs for persisting tokens
+//!
+//! Storage backends serialize tokens through `default` instead of calling
+//! `Token.toJson` directly, so the JSON implementation can be swapped at
+//! build time (`-Djson-codec=...`) without touching any backend:
+//!
+//! - `builtin`: the hand-written `Token.toJson` writer and `std.json.Value` parser
+//! - `typed`: `std.json.Stringify` and typed `std.json` parsing of a wire struct
+//!
+//! Both produce and accept the same document, so tokens written with one
+//! codec can be read with the other.
+//!
+//! Documents carry a `schema_version`. Decoding runs the `migrations` that
+//! bring an older document up to date before reading it, and refuses one
+//! written by a newer schema with `error.UnsupportedSchemaVersion` rather
+//! than dropping the fields it does not know. Unversioned documents from
+//! before the stamp are version 0.
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+
+const build_options = @import("build_options");
+const session = @import("session.zig");
+const clock = @import("clock.zig");
+
+const Token = session.Token;
+
+/// Encodes and decodes tokens as JSON documents
+pub const JsonCodec = struct {
+    ptr: ?*anyopaque = null,
+    vtable: *const VTable,
+
+    pub const VTable = struct {
+        encode: *const fn (ptr: ?*anyopaque, allocator: Allocator, token: *const Token) anyerror![]u8,
+        decode: *const fn (ptr: ?*anyopaque, allocator: Allocator, data: []const u8) anyerror!Token,
+    };
+
+    /// Serialize `token`; caller owns the returned bytes
+    pub fn encode(self: JsonCodec, allocator: Allocator, token: *const Token) ![]u8 {
+        return self.vtable.encode(self.ptr, allocator, token);
+    }
+
+    /// Deserialize a token allocated with `allocator`
+    pub fn decode(self: JsonCodec, allocator: Allocator, data: []const u8) !Token {
+        return self.vtable.decode(self.ptr, allocator, data);
+    }
+};
+
+/// Codec backed by `Token.toJson` / `Token.fromJson`
+pub const builtin: JsonCodec = .{ .vtable = &.{
+    .encode = builtinEncode,
+    .decode = builtinDecode,
+} };
+
+/// Codec backed by `std.json.Stringify` and typed `std.json` parsing
+pub const typed: JsonCodec = .{ .vtable = &.{
+    .encode = typedEncode,
+    .decode = typedDecode,
+} };
+
+/// Codec selected at build time with `-Djson-codec`
+pub const default: JsonCodec = switch (build_options.json_codec) {
+    .builtin => builtin,
+    .typed => typed,
+};
+
+/// Schema version stamped on every encoded document
+///
+/// Bump it together with a new entry in `migrations` whenever the stored
+/// layout changes in a way older readers would get wrong.
+pub const schema_version: u32 = 1;
+
+/// Step upgrading a document from version `from` to `from + 1`
+pub const Migration = struct {
+    from: u32,
+    /// Rewrite `doc` in place; `allocator` owns the document
+    apply: *const fn (allocator: Allocator, doc: *json.ObjectMap) anyerror!void,
+};
+
+/// Every migration, in order
+pub const migrations = [_]Migration{
+    // Unversioned documents already use the version 1 layout
+    .{ .from = 0, .apply = noChanges },
+};
+
+comptime {
+    if (migrations.len != schema_version) @compileError("every schema version needs a migration");
+    for (migrations, 0..) |migration, i| {
+        if (migration.from != i) @compileError("migrations must be ordered by version");
+    }
+}
+
+fn noChanges(_: Allocator, _: *json.ObjectMap) anyerror!void {}
+
+/// Schema version of a parsed document (0 if it has none)
+pub fn documentVersion(doc: json.ObjectMap) !u32 {
+    const value = doc.get("schema_version") orelse return 0;
+    if (value != .integer) return error.InvalidParameter;
+    return std.math.cast(u32, value.integer) orelse error.InvalidParameter;
+}
+
+/// Bring a parsed document up to schema_version
+///
+/// `allocator` must own `doc` (usually the arena of its `json.Parsed`).
+pub fn upgrade(allocator: Allocator, doc: *json.ObjectMap) !void {
+    const version = try documentVersion(doc.*);
+    if (version > schema_version) return error.UnsupportedSchemaVersion;
+
+    for (migrations[version..]) |migration| try migration.apply(allocator, doc);
+    try doc.put("schema_version", .{ .integer = schema_version });
+}
+
+/// Parse `data` and upgrade it; caller deinits the result
+fn parseCurrent(allocator: Allocator, data: []const u8) !json.Parsed(json.Value) {
+    var parsed = try json.parseFromSlice(json.Value, allocator, data, .{});
+    errdefer parsed.deinit();
+    if (parsed.value != .object) return error.InvalidParameter;
+
+    try upgrade(parsed.arena.allocator(), &parsed.value.object);
+    return parsed;
+}
+
+fn builtinEncode(_: ?*anyopaque, allocator: Allocator, token: *const Token) anyerror![]u8 {
+    const data = try token.toJson(allocator);
+    defer {
+        std.crypto.secureZero(u8, data);
+        allocator.free(data);
+    }
+
+    // toJson always opens with `{"access_token"`, so the stamp goes first
+    var buf: std.ArrayListUnmanaged(u8) = .{};
+    errdefer buf.deinit(allocator);
+    try buf.writer(allocator).print("{{\"schema_version\":{d},", .{schema_version});
+    try buf.appendSlice(allocator, data[1..]);
+    return buf.toOwnedSlice(allocator);
+}
+
+fn builtinDecode(_: ?*anyopaque, allocator: Allocator, data: []const u8) anyerror!Token {
+    const parsed = try parseCurrent(allocator, data);
+    defer parsed.deinit();
+    return Token.fromJsonValue(allocator, parsed.value);
+}
+
+/// On-disk token document
+const Wire = struct {
+    schema_version: u32 = schema_version,
+    access_token: []const u8,
+    token_type: []const u8,
+    refresh_token: ?[]const u8 = null,
+    expires_in: ?u64 = null,
+    expires_at: ?u64 = null,
+    clock_offset: ?i64 = null,
+    not_before: ?u64 = null,
+    issued_at: ?u64 = null,
+    scope: ?[]const u8 = null,
+    id_token: ?[]const u8 = null,
+    metadata: ?[]const u8 = null,
+    request_audit: ?[]const u8 = null,
+    dpop_key: ?[]const u8 = null,
+    authorization_details: ?[]const u8 = null,
+    resource: ?[]const u8 = null,
+    audience: ?[]const u8 = null,
+};
+
+fn typedEncode(_: ?*anyopaque, allocator: Allocator, token: *const Token) anyerror![]u8 {
+    const wire = Wire{
+        .access_token = token.access_token,
+        .token_type = token.token_type,
+        .refresh_token = token.refresh_token,
+        .expires_in = token.expires_in,
+        .expires_at = token.expires_at,
+        .clock_offset = if (token.clock_offset != 0) token.clock_offset else null,
+        .not_before = token.not_before,
+        .issued_at = token.issued_at,
+        .scope = token.scope,
+        .id_token = token.id_token,
+        .metadata = token.metadata,
+        .request_audit = token.request_audit,
+        .dpop_key = token.dpop_key,
+        .authorization_details = token.authorization_details,
+        .resource = token.resource,
+        .audience = token.audience,
+    };
+    return json.Stringify.valueAlloc(allocator, wire, .{ .emit_null_optional_fields = false });
+}
+
+fn typedDecode(_: ?*anyopaque, allocator: Allocator, data: []const u8) anyerror!Token {
+    const document = parseCurrent(allocator, data) catch |err| switch (err) {
+        error.OutOfMemory, error.InvalidParameter, error.UnsupportedSchemaVersion => return err,
+        else => return error.JsonError,
+    };
+    defer document.deinit();
+
+    const parsed = json.parseFromValue(Wire, allocator, document.value, .{ .ignore_unknown_fields = true }) catch |err| switch (err) {
+        error.OutOfMemory => return err,
+        error.MissingField, error.UnexpectedToken => return error.InvalidParameter,
+        else => return error.JsonError,
+    };
+    defer parsed.deinit();
+    const wire = parsed.value;
+
+    var token = try Token.init(allocator, wire.access_token, wire.token_type);
+    errdefer token.deinit();
+
+    if (wire.refresh_token) |rt| token.refresh_token = try allocator.dupe(u8, rt);
+    if (wire.scope) |s| token.scope = try allocator.dupe(u8, s);
+    if (wire.id_token) |id| token.id_token = try allocator.dupe(u8, id);
+    if (wire.metadata) |m| token.metadata = try allocator.dupe(u8, m);
+    if (wire.request_audit) |a| token.request_audit = try allocator.dupe(u8, a);
+    if (wire.dpop_key) |k| token.dpop_key = try allocator.dupe(u8, k);
+    if (wire.authorization_details) |d| token.authorization_details = try allocator.dupe(u8, d);
+    if (wire.resource) |r| token.resource = try allocator.dupe(u8, r);
+    if (wire.audience) |aud| token.audience = try allocator.dupe(u8, aud);
+    token.expires_in = wire.expires_in;
+    token.expires_at = wire.expires_at;
+    token.clock_offset = wire.clock_offset orelse 0;
+    token.not_before = wire.not_before;
+    token.issued_at = wire.issued_at;
+
+    if (token.expires_at == null and token.expires_in != null) {
+        token.expires_at = clock.now() + token.expires_in.?;
+    }
+
+    return token;
+}
+
+fn expectTokensEqual(expected: *const Token, actual: *const Token) !void {
+    try std.testing.expectEqualStrings(expected.access_token, actual.access_token);
+    try std.testing.expectEqualStrings(expected.token_type, actual.token_type);
+    try std.testing.expectEqualDeep(expected.refresh_token, actual.refresh_token);
+    try std.testing.expectEqual(expected.expires_in, actual.expires_in);
+    try std.testing.expectEqual(expected.expires_at, actual.expires_at);
+    try std.testing.expectEqual(expected.clock_offset, actual.clock_offset);
+    try std.testing.expectEqual(expected.not_before, actual.not_before);
+    try std.testing.expectEqual(expected.issued_at, actual.issued_at);
+    try std.testing.expectEqualDeep(expected.scope, actual.scope);
+    try std.testing.expectEqualDeep(expected.id_token, actual.id_token);
+    try std.testing.expectEqualDeep(expected.metadata, actual.metadata);
+    try std.testing.expectEqualDeep(expected.request_audit, actual.request_audit);
+    try std.testing.expectEqualDeep(expected.dpop_key, actual.dpop_key);
+    try std.testing.expectEqualDeep(expected.authorization_details, actual.authorization_details);
+    try std.testing.expectEqualDeep(expected.resource, actual.resource);
+    try std.testing.expectEqualDeep(expected.audience, actual.audience);
+}
+
+test "token round-trips identically through every codec" {
+    const allocator = std.testing.allocator;
+
+    var full = try Token.initFull(allocator, "access \"quoted\"\n", "Bearer", "refresh", 3600, "read write", "id.token.sig");
+    defer full.deinit();
+    full.metadata = try allocator.dupe(u8, "tenant=acme");
+    full.clock_offset = -42;
+    full.not_before = 1_700_000_000;
+    full.issued_at = 1_699_999_990;
+    full.request_audit = try allocator.dupe(u8, "{\"grant_type\":\"refresh_token\"}");
+    full.dpop_key = try allocator.dupe(u8, "dpop-key");
+    full.authorization_details = try allocator.dupe(u8, "[{\"type\":\"account_information\"}]");
+    full.resource = try allocator.dupe(u8, "https://api.example.com");
+    full.audience = try allocator.dupe(u8, "https://api.example.com/");
+
+    var minimal = try Token.init(allocator, "access", "Bearer");
+    defer minimal.deinit();
+
+    const codecs = [_]JsonCodec{ builtin, typed };
+    for ([_]*const Token{ &full, &minimal }) |original| {
+        for (codecs) |writer| {
+            const data = try writer.encode(allocator, original);
+            defer allocator.free(data);
+
+            // Every codec reads what any codec wrote
+            for (codecs) |reader| {
+                var restored = try reader.decode(allocator, data);
+                defer restored.deinit();
+                try expectTokensEqual(original, &restored);
+            }
+        }
+    }
+}
+
+test "typed codec rejects documents without required fields" {
+    const allocator = std.testing.allocator;
+    try std.testing.expectError(error.InvalidParameter, typed.decode(allocator, "{\"token_type\":\"Bearer\"}"));
+    try std.testing.expectError(error.JsonError, typed.decode(allocator, "not json"));
+}
+
+test "documents are stamped with the schema version and older ones are upgraded" {
+    const allocator = std.testing.allocator;
+
+    var token = try Token.init(allocator, "access", "Bearer");
+    defer token.deinit();
+
+    const codecs = [_]JsonCodec{ builtin, typed };
+    for (codecs) |codec| {
+        const data = try codec.encode(allocator, &token);
+        defer allocator.free(data);
+
+        const parsed = try json.parseFromSlice(json.Value, allocator, data, .{});
+        defer parsed.deinit();
+        try std.testing.expectEqual(schema_version, try documentVersion(parsed.value.object));
+
+        // Written before documents were versioned
+        var legacy = try codec.decode(allocator, "{\"access_token\":\"old\",\"token_type\":\"Bearer\"}");
+        defer legacy.deinit();
+        try std.testing.expectEqualStrings("old", legacy.access_token);
+
+        // A newer schema is refused instead of being read lossily
+        try std.testing.expectError(
+            error.UnsupportedSchemaVersion,
+            codec.decode(allocator, "{\"schema_version\":99,\"access_token\":\"new\",\"token_type\":\"Bearer\"}"),
+        );
+        try std.testing.expectError(
+            error.InvalidParameter,
+            codec.decode(allocator, "{\"schema_version\":\"1\",\"access_token\":\"a\",\"token_type\":\"Bearer\"}"),
+        );
+    }
+}
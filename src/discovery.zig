@@ -0,0 +1,283 @@
+//! Authorization server metadata discovery (RFC 8414)
+//!
+//! Fetches `/.well-known/oauth-authorization-server` for an issuer and
+//! turns it into an `OAuthConfig`, so applications only need to know the
+//! issuer URL instead of every endpoint.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var config = try OAuthConfig.discover(allocator, "https://auth.example.com", "client-id", "http://127.0.0.1:8080/callback", "read");
+//! defer config.deinit();
+//!
+//! var client = OAuthClient.init(allocator, config.toConfig(), storage.storage());
+//! ```
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+
+const oauth = @import("oauth.zig");
+const pkce = @import("pkce.zig");
+const transport = @import("transport.zig");
+
+const HttpTransport = transport.HttpTransport;
+
+/// Well-known suffix for OAuth 2.0 authorization server metadata
+pub const oauth_well_known = "oauth-authorization-server";
+
+/// Authorization server metadata document (RFC 8414 Section 2)
+pub const ServerMetadata = struct {
+    allocator: Allocator,
+    issuer: []const u8,
+    authorization_endpoint: ?[]const u8 = null,
+    token_endpoint: ?[]const u8 = null,
+    device_authorization_endpoint: ?[]const u8 = null,
+    revocation_endpoint: ?[]const u8 = null,
+    introspection_endpoint: ?[]const u8 = null,
+    registration_endpoint: ?[]const u8 = null,
+    pushed_authorization_request_endpoint: ?[]const u8 = null,
+    backchannel_authentication_endpoint: ?[]const u8 = null,
+    jwks_uri: ?[]const u8 = null,
+    /// PKCE methods the server accepts; empty when it does not say
+    code_challenge_methods_supported: []const []const u8 = &.{},
+
+    /// Fields read from the document, in declaration order
+    const Document = struct {
+        issuer: []const u8,
+        authorization_endpoint: ?[]const u8 = null,
+        token_endpoint: ?[]const u8 = null,
+        device_authorization_endpoint: ?[]const u8 = null,
+        revocation_endpoint: ?[]const u8 = null,
+        introspection_endpoint: ?[]const u8 = null,
+        registration_endpoint: ?[]const u8 = null,
+        pushed_authorization_request_endpoint: ?[]const u8 = null,
+        backchannel_authentication_endpoint: ?[]const u8 = null,
+        jwks_uri: ?[]const u8 = null,
+        code_challenge_methods_supported: ?[]const []const u8 = null,
+    };
+
+    /// Parse a metadata document published for `expected_issuer`
+    ///
+    /// The document's `issuer` must equal `expected_issuer` (RFC 8414
+    /// Section 3.3), otherwise `error.IssuerMismatch` is returned. A single
+    /// trailing `/` on either side is ignored, since issuers are configured
+    /// both ways and the well-known URL is built without it.
+    pub fn parse(allocator: Allocator, expected_issuer: []const u8, body: []const u8) !ServerMetadata {
+        const parsed = json.parseFromSlice(Document, allocator, body, .{
+            .ignore_unknown_fields = true,
+        }) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.JsonError,
+        };
+        defer parsed.deinit();
+        const doc = parsed.value;
+
+        if (!issuersMatch(doc.issuer, expected_issuer)) return error.IssuerMismatch;
+
+        var metadata = ServerMetadata{ .allocator = allocator, .issuer = try allocator.dupe(u8, doc.issuer) };
+        errdefer metadata.deinit();
+
+        inline for (std.meta.fields(Document)) |field| {
+            if (field.type == ?[]const u8) {
+                if (@field(doc, field.name)) |value| {
+                    @field(metadata, field.name) = try allocator.dupe(u8, value);
+                }
+            }
+        }
+        if (doc.code_challenge_methods_supported) |methods| {
+            metadata.code_challenge_methods_supported = try dupeStrings(allocator, methods);
+        }
+        return metadata;
+    }
+
+    pub fn deinit(self: *ServerMetadata) void {
+        self.allocator.free(self.issuer);
+        inline for (std.meta.fields(Document)) |field| {
+            if (field.type == ?[]const u8) {
+                if (@field(self, field.name)) |value| self.allocator.free(value);
+            }
+        }
+        for (self.code_challenge_methods_supported) |method| self.allocator.free(method);
+        self.allocator.free(self.code_challenge_methods_supported);
+    }
+
+    /// Build a client configuration from the discovered endpoints
+    ///
+    /// Servers that only support the device flow have no authorization
+    /// endpoint; the device endpoint stands in for it, as with formulas.
+    /// The PKCE method is negotiated from `code_challenge_methods_supported`
+    /// (see pkce.negotiateMethod()).
+    pub fn toConfig(
+        self: *const ServerMetadata,
+        allocator: Allocator,
+        client_id: []const u8,
+        redirect_uri: []const u8,
+        scope: ?[]const u8,
+    ) !oauth.OAuthConfigOwned {
+        const token_endpoint = self.token_endpoint orelse return error.MissingEndpoint;
+        const authorization_endpoint = self.authorization_endpoint orelse
+            self.device_authorization_endpoint orelse return error.MissingEndpoint;
+
+        var owned = try oauth.OAuthConfig.custom(
+            allocator,
+            client_id,
+            authorization_endpoint,
+            token_endpoint,
+            redirect_uri,
+            scope,
+            self.device_authorization_endpoint,
+        );
+        errdefer owned.deinit();
+
+        const optional_endpoints = .{
+            .{ "revocation_endpoint", self.revocation_endpoint },
+            .{ "introspection_endpoint", self.introspection_endpoint },
+            .{ "pushed_authorization_request_endpoint", self.pushed_authorization_request_endpoint },
+            .{ "backchannel_authentication_endpoint", self.backchannel_authentication_endpoint },
+            .{ "jwks_uri", self.jwks_uri },
+        };
+        inline for (optional_endpoints) |endpoint| {
+            if (endpoint[1]) |url| {
+                try oauth.validateEndpointSecurity(url);
+                @field(owned, endpoint[0]) = try allocator.dupe(u8, url);
+            }
+        }
+        owned.issuer = try allocator.dupe(u8, self.issuer);
+        owned.pkce.method = try pkce.negotiateMethod(self.code_challenge_methods_supported);
+        return owned;
+    }
+};
+
+/// Well-known metadata URL for `issuer` (RFC 8414 Section 3.1)
+///
+/// The well-known segment goes between the host and any issuer path:
+/// `https://example.com/tenant` becomes
+/// `https://example.com/.well-known/oauth-authorization-server/tenant`.
+pub fn wellKnownUrl(allocator: Allocator, issuer: []const u8, suffix: []const u8) ![]u8 {
+    const scheme_end = (std.mem.indexOf(u8, issuer, "://") orelse return error.InvalidParameter) + 3;
+    if (std.mem.indexOfAny(u8, issuer, "?#") != null) return error.InvalidParameter;
+
+    const trimmed = std.mem.trimRight(u8, issuer, "/");
+    const path_start = std.mem.indexOfScalarPos(u8, trimmed, scheme_end, '/') orelse trimmed.len;
+    if (path_start == scheme_end) return error.InvalidParameter;
+
+    return std.fmt.allocPrint(allocator, "{s}/.well-known/{s}{s}", .{
+        trimmed[0..path_start],
+        suffix,
+        trimmed[path_start..],
+    });
+}
+
+/// Fetch and validate the metadata of `issuer` through `http_transport`
+pub fn discover(allocator: Allocator, http_transport: HttpTransport, issuer: []const u8) !ServerMetadata {
+    try oauth.validateEndpointSecurity(issuer);
+
+    const url = try wellKnownUrl(allocator, issuer, oauth_well_known);
+    defer allocator.free(url);
+
+    const body = try fetchDocument(allocator, http_transport, url);
+    defer allocator.free(body);
+
+    return ServerMetadata.parse(allocator, issuer, body);
+}
+
+fn dupeStrings(allocator: Allocator, values: []const []const u8) ![]const []const u8 {
+    const copies = try allocator.alloc([]const u8, values.len);
+    var copied: usize = 0;
+    errdefer {
+        for (copies[0..copied]) |copy| allocator.free(copy);
+        allocator.free(copies);
+    }
+    for (values) |value| {
+        copies[copied] = try allocator.dupe(u8, value);
+        copied += 1;
+    }
+    return copies;
+}
+
+/// Compare issuer identifiers, ignoring one trailing `/`
+fn issuersMatch(a: []const u8, b: []const u8) bool {
+    return std.mem.eql(u8, trimSlash(a), trimSlash(b));
+}
+
+fn trimSlash(issuer: []const u8) []const u8 {
+    return if (std.mem.endsWith(u8, issuer, "/")) issuer[0 .. issuer.len - 1] else issuer;
+}
+
+/// GET a metadata document; caller owns the returned body
+pub fn fetchDocument(allocator: Allocator, http_transport: HttpTransport, url: []const u8) ![]u8 {
+    var response = try http_transport.send(allocator, .{
+        .method = .GET,
+        .url = url,
+        .headers = &.{.{ .name = "Accept", .value = "application/json" }},
+    });
+    defer response.deinit();
+
+    if (response.status != 200) return error.ServerError;
+    return allocator.dupe(u8, response.body);
+}
+
+test "wellKnownUrl inserts the well-known segment before the issuer path" {
+    const allocator = std.testing.allocator;
+
+    const root = try wellKnownUrl(allocator, "https://example.com/", oauth_well_known);
+    defer allocator.free(root);
+    try std.testing.expectEqualStrings("https://example.com/.well-known/oauth-authorization-server", root);
+
+    const tenant = try wellKnownUrl(allocator, "https://example.com/tenant/a", oauth_well_known);
+    defer allocator.free(tenant);
+    try std.testing.expectEqualStrings("https://example.com/.well-known/oauth-authorization-server/tenant/a", tenant);
+
+    try std.testing.expectError(error.InvalidParameter, wellKnownUrl(allocator, "example.com", oauth_well_known));
+    try std.testing.expectError(error.InvalidParameter, wellKnownUrl(allocator, "https://example.com?x=1", oauth_well_known));
+}
+
+test "discover populates the endpoints and validates the issuer" {
+    const allocator = std.testing.allocator;
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body =
+        \\{"issuer":"https://auth.example.com",
+        \\ "authorization_endpoint":"https://auth.example.com/authorize",
+        \\ "token_endpoint":"https://auth.example.com/token",
+        \\ "device_authorization_endpoint":"https://auth.example.com/device",
+        \\ "revocation_endpoint":"https://auth.example.com/revoke",
+        \\ "introspection_endpoint":"https://auth.example.com/introspect",
+        \\ "response_types_supported":["code"]}
+    });
+    try mock.enqueue(.{ .body = "{\"issuer\":\"https://evil.example.com\",\"token_endpoint\":\"https://evil.example.com/token\"}" });
+
+    var metadata = try discover(allocator, mock.transport(), "https://auth.example.com");
+    defer metadata.deinit();
+    try std.testing.expectEqualStrings("https://auth.example.com/.well-known/oauth-authorization-server", mock.lastRequest().?.url);
+
+    var config = try metadata.toConfig(allocator, "client", "http://127.0.0.1/callback", "read");
+    defer config.deinit();
+    try std.testing.expectEqualStrings("https://auth.example.com/authorize", config.authorization_endpoint);
+    try std.testing.expectEqualStrings("https://auth.example.com/token", config.token_endpoint);
+    try std.testing.expectEqualStrings("https://auth.example.com/device", config.device_authorization_endpoint.?);
+    try std.testing.expectEqualStrings("https://auth.example.com/revoke", config.revocation_endpoint.?);
+    try std.testing.expectEqualStrings("https://auth.example.com/introspect", config.introspection_endpoint.?);
+
+    // A document for another issuer is rejected
+    try std.testing.expectError(error.IssuerMismatch, discover(allocator, mock.transport(), "https://auth.example.com"));
+}
+
+test "toConfig negotiates the PKCE method" {
+    const allocator = std.testing.allocator;
+
+    var metadata = try ServerMetadata.parse(allocator, "https://auth.example.com",
+        \\{"issuer":"https://auth.example.com",
+        \\ "authorization_endpoint":"https://auth.example.com/authorize",
+        \\ "token_endpoint":"https://auth.example.com/token",
+        \\ "code_challenge_methods_supported":["plain"]}
+    );
+    defer metadata.deinit();
+    try std.testing.expectEqualStrings("plain", metadata.code_challenge_methods_supported[0]);
+
+    var config = try metadata.toConfig(allocator, "client", "http://127.0.0.1/callback", null);
+    defer config.deinit();
+    try std.testing.expectEqual(pkce.Method.plain, config.pkce.method);
+}
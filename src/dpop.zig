@@ -0,0 +1,239 @@
+//! DPoP sender-constrained tokens (RFC 9449)
+//!
+//! A `DpopKey` is a P-256 key pair whose public half is presented in every
+//! proof. With `OAuthClient.withDpop()` each token endpoint request carries a
+//! proof, and tokens the server binds to the key (`token_type: DPoP`) keep
+//! the private key in `Token.dpop_key`, so the refresher and resource
+//! requests keep using the key the token was bound to.
+//!
+//! ## Example
+//!
+//! ```zig
+//! client.withDpop();
+//! var token = try client.exchangeCode(code, verifier, redirect_uri);
+//!
+//! const proof = try dpop.proofForToken(allocator, &token, "GET", "https://api.example.com/me", null);
+//! defer allocator.free(proof);
+//! // Authorization: DPoP <token.access_token>
+//! // DPoP: <proof>
+//! ```
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+
+const clock = @import("clock.zig");
+const jwt = @import("jwt.zig");
+const session = @import("session.zig");
+
+const Token = session.Token;
+const Ecdsa = std.crypto.sign.ecdsa.EcdsaP256Sha256;
+const base64 = std.base64.url_safe_no_pad;
+
+/// Public key in JWK form (members in RFC 7638 thumbprint order)
+const Jwk = struct {
+    crv: []const u8 = "P-256",
+    kty: []const u8 = "EC",
+    x: []const u8,
+    y: []const u8,
+};
+
+const ProofHeader = struct {
+    typ: []const u8 = "dpop+jwt",
+    alg: []const u8 = "ES256",
+    jwk: Jwk,
+};
+
+const ProofClaims = struct {
+    jti: []const u8,
+    htm: []const u8,
+    htu: []const u8,
+    iat: u64,
+    nonce: ?[]const u8 = null,
+    ath: ?[]const u8 = null,
+};
+
+/// A DPoP signing key (ES256)
+pub const DpopKey = struct {
+    key_pair: Ecdsa.KeyPair,
+
+    /// Length of encode() output
+    pub const encoded_len = base64.Encoder.calcSize(Ecdsa.SecretKey.encoded_length);
+
+    /// Options for a single proof
+    pub const ProofOptions = struct {
+        /// Value of the server's latest `DPoP-Nonce` header
+        nonce: ?[]const u8 = null,
+        /// Access token sent with the request; adds the `ath` claim
+        access_token: ?[]const u8 = null,
+    };
+
+    /// Generate a fresh key pair
+    pub fn generate() DpopKey {
+        return .{ .key_pair = Ecdsa.KeyPair.generate() };
+    }
+
+    /// Restore a key from encode() output
+    pub fn decode(encoded: []const u8) !DpopKey {
+        var bytes: [Ecdsa.SecretKey.encoded_length]u8 = undefined;
+        defer std.crypto.secureZero(u8, &bytes);
+
+        const size = base64.Decoder.calcSizeForSlice(encoded) catch return error.InvalidParameter;
+        if (size != bytes.len) return error.InvalidParameter;
+        base64.Decoder.decode(&bytes, encoded) catch return error.InvalidParameter;
+
+        const secret_key = Ecdsa.SecretKey.fromBytes(bytes) catch return error.InvalidParameter;
+        const key_pair = Ecdsa.KeyPair.fromSecretKey(secret_key) catch return error.InvalidParameter;
+        return .{ .key_pair = key_pair };
+    }
+
+    /// Encode the private key for storage (base64url scalar)
+    pub fn encode(self: *const DpopKey) [encoded_len]u8 {
+        var bytes = self.key_pair.secret_key.toBytes();
+        defer std.crypto.secureZero(u8, &bytes);
+
+        var out: [encoded_len]u8 = undefined;
+        _ = base64.Encoder.encode(&out, &bytes);
+        return out;
+    }
+
+    /// Create a proof JWT for a `method` request to `url`
+    ///
+    /// The `htu` claim is `url` without its query and fragment. Caller owns
+    /// the returned proof.
+    pub fn proof(
+        self: *const DpopKey,
+        allocator: Allocator,
+        method: []const u8,
+        url: []const u8,
+        options: ProofOptions,
+    ) ![]u8 {
+        const point = self.key_pair.public_key.toUncompressedSec1();
+        var x: [base64.Encoder.calcSize(32)]u8 = undefined;
+        var y: [base64.Encoder.calcSize(32)]u8 = undefined;
+        _ = base64.Encoder.encode(&x, point[1..33]);
+        _ = base64.Encoder.encode(&y, point[33..65]);
+
+        var jti_bytes: [16]u8 = undefined;
+        std.crypto.random.bytes(&jti_bytes);
+        var jti: [base64.Encoder.calcSize(16)]u8 = undefined;
+        _ = base64.Encoder.encode(&jti, &jti_bytes);
+
+        var ath: [base64.Encoder.calcSize(32)]u8 = undefined;
+        if (options.access_token) |access_token| {
+            var digest: [32]u8 = undefined;
+            std.crypto.hash.sha2.Sha256.hash(access_token, &digest, .{});
+            _ = base64.Encoder.encode(&ath, &digest);
+        }
+
+        const header_json = try json.Stringify.valueAlloc(allocator, ProofHeader{
+            .jwk = .{ .x = &x, .y = &y },
+        }, .{});
+        defer allocator.free(header_json);
+
+        const claims_json = try json.Stringify.valueAlloc(allocator, ProofClaims{
+            .jti = &jti,
+            .htm = method,
+            .htu = url[0 .. std.mem.indexOfAny(u8, url, "?#") orelse url.len],
+            .iat = clock.now(),
+            .nonce = options.nonce,
+            .ath = if (options.access_token != null) &ath else null,
+        }, .{ .emit_null_optional_fields = false });
+        defer allocator.free(claims_json);
+
+        const header = try jwt.encodeSegment(allocator, header_json);
+        defer allocator.free(header);
+        const claims = try jwt.encodeSegment(allocator, claims_json);
+        defer allocator.free(claims);
+
+        const signing_input = try std.fmt.allocPrint(allocator, "{s}.{s}", .{ header, claims });
+        defer allocator.free(signing_input);
+
+        const signature = self.key_pair.sign(signing_input, null) catch return error.InvalidParameter;
+        const signature_bytes = signature.toBytes();
+        const encoded_signature = try jwt.encodeSegment(allocator, &signature_bytes);
+        defer allocator.free(encoded_signature);
+
+        return std.fmt.allocPrint(allocator, "{s}.{s}", .{ signing_input, encoded_signature });
+    }
+};
+
+/// Create a proof for a resource request made with a DPoP-bound `token`
+///
+/// Returns `error.InvalidParameter` if the token is not bound to a key.
+pub fn proofForToken(
+    allocator: Allocator,
+    token: *const Token,
+    method: []const u8,
+    url: []const u8,
+    nonce: ?[]const u8,
+) ![]u8 {
+    const encoded = token.dpop_key orelse return error.InvalidParameter;
+    const key = try DpopKey.decode(encoded);
+    return key.proof(allocator, method, url, .{ .nonce = nonce, .access_token = token.access_token });
+}
+
+test "DpopKey.proof: signed ES256 proof with the public key in the header" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    const key = DpopKey.generate();
+    const proof = try key.proof(allocator, "POST", "https://example.com/token?x=1#frag", .{
+        .nonce = "server-nonce",
+        .access_token = "access",
+    });
+    defer allocator.free(proof);
+
+    const parts = try jwt.split(proof);
+
+    var header = try jwt.decodeHeader(allocator, proof);
+    defer header.deinit();
+    try std.testing.expectEqual(jwt.Algorithm.ES256, header.alg);
+    try std.testing.expectEqualStrings("dpop+jwt", header.typ.?);
+
+    // The signature verifies against the key's public half
+    const signature_bytes = try jwt.decodeSegment(allocator, parts.signature);
+    defer allocator.free(signature_bytes);
+    try std.testing.expectEqual(@as(usize, Ecdsa.Signature.encoded_length), signature_bytes.len);
+    const signature = Ecdsa.Signature.fromBytes(signature_bytes[0..Ecdsa.Signature.encoded_length].*);
+    try signature.verify(parts.signing_input, key.key_pair.public_key);
+
+    const claims_json = try jwt.decodeSegment(allocator, parts.payload);
+    defer allocator.free(claims_json);
+    const parsed = try json.parseFromSlice(json.Value, allocator, claims_json, .{});
+    defer parsed.deinit();
+    const claims = parsed.value.object;
+
+    try std.testing.expectEqualStrings("POST", claims.get("htm").?.string);
+    try std.testing.expectEqualStrings("https://example.com/token", claims.get("htu").?.string);
+    try std.testing.expectEqual(@as(i64, 1_700_000_000), claims.get("iat").?.integer);
+    try std.testing.expectEqualStrings("server-nonce", claims.get("nonce").?.string);
+    // SHA-256("access"), base64url
+    try std.testing.expectEqualStrings("oFYf1knNtrqnhAVfBRuteW6gr-8X_KOCGVSd7rpOjBo", claims.get("ath").?.string);
+    try std.testing.expect(claims.get("jti").?.string.len > 0);
+}
+
+test "DpopKey.encode round-trips and proofForToken needs a bound token" {
+    const allocator = std.testing.allocator;
+
+    const key = DpopKey.generate();
+    const encoded = key.encode();
+    const restored = try DpopKey.decode(&encoded);
+    try std.testing.expectEqualSlices(
+        u8,
+        &key.key_pair.public_key.toUncompressedSec1(),
+        &restored.key_pair.public_key.toUncompressedSec1(),
+    );
+    try std.testing.expectError(error.InvalidParameter, DpopKey.decode("not-a-key"));
+
+    var token = try Token.init(allocator, "access", "DPoP");
+    defer token.deinit();
+    try std.testing.expectError(error.InvalidParameter, proofForToken(allocator, &token, "GET", "https://api.example.com", null));
+
+    token.dpop_key = try allocator.dupe(u8, &encoded);
+    const proof = try proofForToken(allocator, &token, "GET", "https://api.example.com", null);
+    defer allocator.free(proof);
+    _ = try jwt.split(proof);
+}
@@ -47,6 +47,58 @@ pub const OAuthError = error{
     ConnectionFailed,
     /// Request timed out
     Timeout,
+    /// Token signed with an algorithm outside the configured allowlist
+    DisallowedAlgorithm,
+    /// Operation was cancelled before it completed
+    Cancelled,
+    /// Grant (code, refresh token or assertion) is invalid, expired or revoked
+    InvalidGrant,
+    /// Refreshes for this key are suspended after repeated failures
+    CircuitOpen,
+    /// Configured redirect URI is not registered with the provider
+    UnregisteredRedirectUri,
+    /// ID token nonce does not match the authorization request
+    InvalidNonce,
+    /// Stored data could not be decrypted (wrong key or tampered)
+    DecryptionFailed,
+    /// No token is stored under the requested key
+    TokenNotFound,
+    /// Discovered metadata names a different issuer than requested
+    IssuerMismatch,
+    /// Token signature does not verify against the provider keys
+    InvalidSignature,
+    /// ID token claims (iss, aud, exp, iat, azp) failed validation
+    InvalidIdToken,
+    /// Backchannel authentication request (CIBA) expired before the user responded
+    AuthRequestExpired,
+    /// JWT-secured authorization response (JARM) failed validation
+    InvalidAuthorizationResponse,
+    /// Refresh token was already rotated (concurrent refresh or replay)
+    RefreshTokenReused,
+    /// Server rejected the request as malformed (invalid_request)
+    InvalidRequest,
+    /// Client authentication failed (invalid_client)
+    InvalidClient,
+    /// Client is not allowed to use this grant (unauthorized_client)
+    UnauthorizedClient,
+    /// Requested scope is invalid or exceeds the grant (invalid_scope)
+    InvalidScope,
+    /// Server is temporarily unable to handle the request
+    TemporarilyUnavailable,
+    /// User authenticated longer ago than the requested max_age
+    StaleAuthentication,
+    /// Authentication weaker than the required acr or amr; sign in again with stronger factors
+    InsufficientAuthentication,
+    /// Back-channel logout token failed validation
+    InvalidLogoutToken,
+    /// No Secret Service is running (e.g. on a headless server)
+    SecretServiceUnavailable,
+    /// Storage file or directory is readable or writable by other users
+    InsecurePermissions,
+    /// Stored document was written by a newer schema version
+    UnsupportedSchemaVersion,
+    /// Access token is bound to a different client certificate
+    CertificateMismatch,
 };
 
 /// Extended error information for debugging
@@ -78,6 +130,109 @@ pub const ErrorInfo = struct {
     }
 };
 
+/// `error` codes of OAuth error responses
+///
+/// Covers RFC 6749 (Sections 4.1.2.1 and 5.2), device authorization
+/// (RFC 8628 Section 3.5) and token revocation (RFC 7009 Section 2.2.1).
+pub const ErrorCode = enum {
+    invalid_request,
+    invalid_client,
+    invalid_grant,
+    unauthorized_client,
+    unsupported_grant_type,
+    unsupported_response_type,
+    unsupported_token_type,
+    invalid_scope,
+    access_denied,
+    server_error,
+    temporarily_unavailable,
+    authorization_pending,
+    slow_down,
+    expired_token,
+
+    /// Parse a code as sent by the server; null for codes outside the specs
+    pub fn fromString(code: []const u8) ?ErrorCode {
+        return std.meta.stringToEnum(ErrorCode, code);
+    }
+
+    /// The OAuthError callers branch on
+    pub fn toError(self: ErrorCode) OAuthError {
+        return switch (self) {
+            .invalid_request => error.InvalidRequest,
+            .invalid_client => error.InvalidClient,
+            .invalid_grant => error.InvalidGrant,
+            .unauthorized_client => error.UnauthorizedClient,
+            .unsupported_grant_type,
+            .unsupported_response_type,
+            .unsupported_token_type,
+            => error.UnsupportedOperation,
+            .invalid_scope => error.InvalidScope,
+            .access_denied => error.AuthorizationDenied,
+            .server_error => error.ServerError,
+            .temporarily_unavailable => error.TemporarilyUnavailable,
+            .authorization_pending => error.AuthorizationPending,
+            .slow_down => error.SlowDown,
+            .expired_token => error.DeviceCodeExpired,
+        };
+    }
+};
+
+/// An OAuth error response (RFC 6749 Section 5.2)
+pub const ErrorResponse = struct {
+    allocator: std.mem.Allocator,
+    /// The `error` code as sent
+    code: []const u8,
+    /// Human-readable `error_description`
+    description: ?[]const u8 = null,
+    /// `error_uri` pointing at documentation for the error
+    uri: ?[]const u8 = null,
+
+    /// Parse a JSON error body; null if `body` is not an error response
+    pub fn parse(allocator: std.mem.Allocator, body: []const u8) !?ErrorResponse {
+        const parsed = std.json.parseFromSlice(std.json.Value, allocator, body, .{}) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return null,
+        };
+        defer parsed.deinit();
+
+        if (parsed.value != .object) return null;
+        const obj = parsed.value.object;
+        const code = obj.get("error") orelse return null;
+        if (code != .string) return null;
+
+        var response = ErrorResponse{
+            .allocator = allocator,
+            .code = try allocator.dupe(u8, code.string),
+        };
+        errdefer response.deinit();
+
+        if (obj.get("error_description")) |value| {
+            if (value == .string) response.description = try allocator.dupe(u8, value.string);
+        }
+        if (obj.get("error_uri")) |value| {
+            if (value == .string) response.uri = try allocator.dupe(u8, value.string);
+        }
+        return response;
+    }
+
+    pub fn deinit(self: *ErrorResponse) void {
+        self.allocator.free(self.code);
+        if (self.description) |d| self.allocator.free(d);
+        if (self.uri) |u| self.allocator.free(u);
+    }
+
+    /// The standard code, or null for a vendor-specific one
+    pub fn kind(self: *const ErrorResponse) ?ErrorCode {
+        return ErrorCode.fromString(self.code);
+    }
+
+    /// The matching OAuthError; `fallback` for vendor-specific codes
+    pub fn toError(self: *const ErrorResponse, fallback: OAuthError) OAuthError {
+        const code = self.kind() orelse return fallback;
+        return code.toError();
+    }
+};
+
 /// Convert an OAuth error to an FFI error code
 pub fn toErrorCode(err: OAuthError) i32 {
     return switch (err) {
@@ -101,6 +256,32 @@ pub fn toErrorCode(err: OAuthError) i32 {
         error.Timeout => 18,
         error.AuthorizationPending => 19,
         error.SlowDown => 20,
+        error.DisallowedAlgorithm => 21,
+        error.Cancelled => 22,
+        error.InvalidGrant => 23,
+        error.CircuitOpen => 24,
+        error.UnregisteredRedirectUri => 25,
+        error.InvalidNonce => 26,
+        error.DecryptionFailed => 27,
+        error.TokenNotFound => 28,
+        error.IssuerMismatch => 29,
+        error.InvalidSignature => 30,
+        error.InvalidIdToken => 31,
+        error.AuthRequestExpired => 32,
+        error.InvalidAuthorizationResponse => 33,
+        error.RefreshTokenReused => 34,
+        error.InvalidRequest => 35,
+        error.InvalidClient => 36,
+        error.UnauthorizedClient => 37,
+        error.InvalidScope => 38,
+        error.TemporarilyUnavailable => 39,
+        error.StaleAuthentication => 40,
+        error.InsufficientAuthentication => 41,
+        error.InvalidLogoutToken => 42,
+        error.SecretServiceUnavailable => 43,
+        error.InsecurePermissions => 44,
+        error.UnsupportedSchemaVersion => 45,
+        error.CertificateMismatch => 46,
     };
 }
 
@@ -128,6 +309,32 @@ pub fn fromErrorCode(code: i32) ?OAuthError {
         18 => error.Timeout,
         19 => error.AuthorizationPending,
         20 => error.SlowDown,
+        21 => error.DisallowedAlgorithm,
+        22 => error.Cancelled,
+        23 => error.InvalidGrant,
+        24 => error.CircuitOpen,
+        25 => error.UnregisteredRedirectUri,
+        26 => error.InvalidNonce,
+        27 => error.DecryptionFailed,
+        28 => error.TokenNotFound,
+        29 => error.IssuerMismatch,
+        30 => error.InvalidSignature,
+        31 => error.InvalidIdToken,
+        32 => error.AuthRequestExpired,
+        33 => error.InvalidAuthorizationResponse,
+        34 => error.RefreshTokenReused,
+        35 => error.InvalidRequest,
+        36 => error.InvalidClient,
+        37 => error.UnauthorizedClient,
+        38 => error.InvalidScope,
+        39 => error.TemporarilyUnavailable,
+        40 => error.StaleAuthentication,
+        41 => error.InsufficientAuthentication,
+        42 => error.InvalidLogoutToken,
+        43 => error.SecretServiceUnavailable,
+        44 => error.InsecurePermissions,
+        45 => error.UnsupportedSchemaVersion,
+        46 => error.CertificateMismatch,
         else => error.IoError, // Unknown error
     };
 }
@@ -153,6 +360,26 @@ test "error code conversion roundtrip" {
     }
 }
 
+test "ErrorResponse.parse maps standard codes and keeps the details" {
+    const allocator = std.testing.allocator;
+
+    var response = (try ErrorResponse.parse(allocator,
+        \\{"error":"invalid_client","error_description":"Unknown client","error_uri":"https://as.example.com/errors/client"}
+    )).?;
+    defer response.deinit();
+    try std.testing.expectEqual(ErrorCode.invalid_client, response.kind().?);
+    try std.testing.expectEqual(@as(OAuthError, error.InvalidClient), response.toError(error.ServerError));
+    try std.testing.expectEqualStrings("Unknown client", response.description.?);
+    try std.testing.expectEqualStrings("https://as.example.com/errors/client", response.uri.?);
+
+    var vendor = (try ErrorResponse.parse(allocator, "{\"error\":\"mfa_required\"}")).?;
+    defer vendor.deinit();
+    try std.testing.expect(vendor.kind() == null);
+    try std.testing.expectEqual(@as(OAuthError, error.ServerError), vendor.toError(error.ServerError));
+
+    try std.testing.expect((try ErrorResponse.parse(allocator, "<html>Bad Gateway</html>")) == null);
+}
+
 test "error code zero is success" {
     try std.testing.expectEqual(@as(?OAuthError, null), fromErrorCode(0));
 }
@@ -0,0 +1,160 @@
+//! Structured lifecycle events
+//!
+//! `OAuthClient` and `TokenRefresher` report what they do to an optional
+//! `EventSink`, for audit logs or for shipping to a log pipeline. Events
+//! never carry token material: they name endpoints, storage keys, scopes
+//! and expiry times, but no access tokens, refresh tokens, codes, verifiers
+//! or client secrets.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var lines = events.JsonLinesSink.init(&stderr_writer.interface);
+//! client.withEventSink(lines.sink());
+//! // {"event":"token_refreshed","timestamp":1700000000,"key":"github",...}
+//! ```
+
+const std = @import("std");
+const json = std.json;
+
+const clock = @import("clock.zig");
+
+/// A lifecycle event with the time it happened
+pub const Event = struct {
+    /// Unix time in seconds
+    timestamp: u64,
+    kind: Kind,
+
+    pub const Kind = union(enum) {
+        /// An authorization URL was built for the user to visit
+        flow_started: struct {
+            authorization_endpoint: []const u8,
+            redirect_uri: []const u8,
+            scope: ?[]const u8 = null,
+        },
+        /// An authorization code was exchanged for a token
+        code_exchanged: struct {
+            token_endpoint: []const u8,
+            scope: ?[]const u8 = null,
+            expires_at: ?u64 = null,
+        },
+        /// The token stored under `key` was refreshed
+        token_refreshed: struct {
+            key: []const u8,
+            scope: ?[]const u8 = null,
+            expires_at: ?u64 = null,
+            /// The server issued a new refresh token
+            refresh_token_rotated: bool,
+        },
+        /// Refreshing the token stored under `key` failed
+        refresh_failed: struct {
+            key: []const u8,
+            @"error": []const u8,
+        },
+        /// The token stored under `key` was revoked at the server and removed
+        token_revoked: struct {
+            key: []const u8,
+        },
+    };
+
+    /// Create an event stamped with the current time
+    pub fn now(kind: Kind) Event {
+        return .{ .timestamp = clock.now(), .kind = kind };
+    }
+
+    /// Write the event as a single JSON object
+    ///
+    /// The object carries `event` (the kind's name) and `timestamp`, followed
+    /// by the kind's fields; null fields are omitted.
+    pub fn writeJson(self: *const Event, writer: *std.Io.Writer) std.Io.Writer.Error!void {
+        var jw: json.Stringify = .{ .writer = writer };
+        try jw.beginObject();
+        try jw.objectField("event");
+        try jw.write(@tagName(self.kind));
+        try jw.objectField("timestamp");
+        try jw.write(self.timestamp);
+
+        switch (self.kind) {
+            inline else => |payload| {
+                inline for (std.meta.fields(@TypeOf(payload))) |field| {
+                    const value = @field(payload, field.name);
+                    if (@typeInfo(field.type) == .optional) {
+                        if (value) |v| {
+                            try jw.objectField(field.name);
+                            try jw.write(v);
+                        }
+                    } else {
+                        try jw.objectField(field.name);
+                        try jw.write(value);
+                    }
+                }
+            },
+        }
+
+        try jw.endObject();
+    }
+};
+
+/// Receives lifecycle events
+///
+/// Emission is best-effort: a sink cannot fail the operation that produced
+/// the event, and is called on whichever thread performed it.
+pub const EventSink = struct {
+    ptr: *anyopaque,
+    vtable: *const VTable,
+
+    pub const VTable = struct {
+        emit: *const fn (ptr: *anyopaque, event: *const Event) void,
+    };
+
+    pub fn emit(self: EventSink, event: *const Event) void {
+        self.vtable.emit(self.ptr, event);
+    }
+};
+
+/// Sink writing one JSON object per line
+pub const JsonLinesSink = struct {
+    writer: *std.Io.Writer,
+    /// Serializes lines from concurrent refreshes
+    mutex: std.Thread.Mutex = .{},
+
+    pub fn init(writer: *std.Io.Writer) JsonLinesSink {
+        return .{ .writer = writer };
+    }
+
+    pub fn sink(self: *JsonLinesSink) EventSink {
+        return .{ .ptr = self, .vtable = &.{ .emit = emitLine } };
+    }
+
+    fn emitLine(ptr: *anyopaque, event: *const Event) void {
+        const self: *JsonLinesSink = @ptrCast(@alignCast(ptr));
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        event.writeJson(self.writer) catch return;
+        self.writer.writeByte('\n') catch return;
+        self.writer.flush() catch return;
+    }
+};
+
+test "JsonLinesSink writes one object per event and omits null fields" {
+    const allocator = std.testing.allocator;
+
+    var out: std.Io.Writer.Allocating = .init(allocator);
+    defer out.deinit();
+
+    var lines = JsonLinesSink.init(&out.writer);
+    const sink = lines.sink();
+
+    sink.emit(&.{ .timestamp = 1_700_000_000, .kind = .{ .token_revoked = .{ .key = "svc" } } });
+    sink.emit(&.{ .timestamp = 1_700_000_001, .kind = .{ .code_exchanged = .{
+        .token_endpoint = "https://example.com/token",
+        .expires_at = 1_700_003_601,
+    } } });
+
+    try std.testing.expectEqualStrings(
+        "{\"event\":\"token_revoked\",\"timestamp\":1700000000,\"key\":\"svc\"}\n" ++
+            "{\"event\":\"code_exchanged\",\"timestamp\":1700000001,\"token_endpoint\":\"https://example.com/token\",\"expires_at\":1700003601}\n",
+        out.written(),
+    );
+}
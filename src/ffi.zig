@@ -25,6 +25,10 @@ const registration = @import("registration.zig");
 const formulas = @import("formulas.zig");
 const callback = @import("callback.zig");
 const pkce = @import("pkce.zig");
+const clock = @import("clock.zig");
+const transport = @import("transport.zig");
+const dpop = @import("dpop.zig");
+const build_options = @import("build_options");
 
 const Token = session.Token;
 const MemoryStorage = session.MemoryStorage;
@@ -32,6 +36,8 @@ const FileStorage = session.FileStorage;
 const SecureStorage = session.SecureStorage;
 const OAuthConfig = oauth.OAuthConfig;
 const OAuthClient = oauth.OAuthClient;
+const TokenRefresher = oauth.TokenRefresher;
+const AuthorizationFlow = oauth.AuthorizationFlow;
 const ClientMetadata = registration.ClientMetadata;
 const ClientRegistrationResponse = registration.ClientRegistrationResponse;
 const DynamicRegistration = registration.DynamicRegistration;
@@ -118,6 +124,38 @@ fn errorCodeFromAny(err: anyerror) c_int {
         error.OutOfMemory => error_types.toErrorCode(error.OutOfMemory),
         error.ConnectionFailed => error_types.toErrorCode(error.ConnectionFailed),
         error.Timeout => error_types.toErrorCode(error.Timeout),
+        error.DisallowedAlgorithm => error_types.toErrorCode(error.DisallowedAlgorithm),
+        error.Cancelled => error_types.toErrorCode(error.Cancelled),
+        error.InvalidGrant => error_types.toErrorCode(error.InvalidGrant),
+        error.CircuitOpen => error_types.toErrorCode(error.CircuitOpen),
+        error.UnregisteredRedirectUri => error_types.toErrorCode(error.UnregisteredRedirectUri),
+        error.InvalidNonce => error_types.toErrorCode(error.InvalidNonce),
+        error.DecryptionFailed => error_types.toErrorCode(error.DecryptionFailed),
+        error.TokenNotFound => error_types.toErrorCode(error.TokenNotFound),
+        error.IssuerMismatch => error_types.toErrorCode(error.IssuerMismatch),
+        error.InvalidSignature => error_types.toErrorCode(error.InvalidSignature),
+        error.InvalidIdToken => error_types.toErrorCode(error.InvalidIdToken),
+        error.AuthRequestExpired => error_types.toErrorCode(error.AuthRequestExpired),
+        error.InvalidAuthorizationResponse => error_types.toErrorCode(error.InvalidAuthorizationResponse),
+        error.RefreshTokenReused => error_types.toErrorCode(error.RefreshTokenReused),
+        error.InvalidRequest => error_types.toErrorCode(error.InvalidRequest),
+        error.InvalidClient => error_types.toErrorCode(error.InvalidClient),
+        error.UnauthorizedClient => error_types.toErrorCode(error.UnauthorizedClient),
+        error.InvalidScope => error_types.toErrorCode(error.InvalidScope),
+        error.TemporarilyUnavailable => error_types.toErrorCode(error.TemporarilyUnavailable),
+        error.StaleAuthentication => error_types.toErrorCode(error.StaleAuthentication),
+        error.InsufficientAuthentication => error_types.toErrorCode(error.InsufficientAuthentication),
+        error.InvalidLogoutToken => error_types.toErrorCode(error.InvalidLogoutToken),
+        error.SecretServiceUnavailable => error_types.toErrorCode(error.SecretServiceUnavailable),
+        error.InsecurePermissions => error_types.toErrorCode(error.InsecurePermissions),
+        error.UnsupportedSchemaVersion => error_types.toErrorCode(error.UnsupportedSchemaVersion),
+        error.CertificateMismatch => error_types.toErrorCode(error.CertificateMismatch),
+        // session.StorageError members without a code of their own
+        error.NotFound => error_types.toErrorCode(error.TokenNotFound),
+        error.Io => error_types.toErrorCode(error.IoError),
+        error.Serialization => error_types.toErrorCode(error.JsonError),
+        error.Locked => error_types.toErrorCode(error.LockError),
+        error.Backend => error_types.toErrorCode(error.StorageError),
         error.InsecureEndpoint => error_types.toErrorCode(error.ConfigurationError),
         error.InvalidSchema => error_types.toErrorCode(error.ConfigurationError),
         error.MissingEndpoint => error_types.toErrorCode(error.ConfigurationError),
@@ -161,6 +199,8 @@ pub const SchlusselClient = extern struct {
     client: *OAuthClient,
     storage: *anyopaque,
     storage_type: StorageType,
+    /// Refresher shared by schlussel_get_valid_token, created on first use
+    refresher: ?*TokenRefresher = null,
 
     const StorageType = enum(u8) {
         memory,
@@ -174,6 +214,16 @@ pub const SchlusselToken = extern struct {
     token: *Token,
 };
 
+/// Opaque token refresher handle
+pub const SchlusselTokenRefresher = extern struct {
+    refresher: *TokenRefresher,
+};
+
+/// Opaque authorization flow handle
+pub const SchlusselAuthFlow = extern struct {
+    flow: *AuthorizationFlow,
+};
+
 /// Opaque dynamic registration client handle
 pub const SchlusselRegistrationClient = extern struct {
     client: *DynamicRegistration,
@@ -370,7 +420,13 @@ export fn schlussel_client_free(client: ?*SchlusselClient) void {
     const handle = client orelse return;
     const allocator = getAllocator();
 
-    // First, deinit the client (this doesn't use storage, just cleans up http_client if any)
+    // The shared refresher references the client, so it goes first
+    if (handle.refresher) |refresher| {
+        refresher.deinit();
+        allocator.destroy(refresher);
+    }
+
+    // Then deinit the client (this doesn't use storage, just cleans up http_client if any)
     handle.client.deinit();
 
     // Then free the storage (while client pointer still exists but is deinitialized)
@@ -465,6 +521,136 @@ export fn schlussel_authorize(client: ?*SchlusselClient) ?*SchlusselToken {
     return token_handle;
 }
 
+/// Start an Authorization Code flow without a callback server or browser
+///
+/// Returns the URL together with the state, PKCE verifier and (for OIDC)
+/// nonce so a native SDK can run the authorization session itself.
+export fn schlussel_start_flow_parts(client: ?*SchlusselClient) ?*SchlusselAuthFlow {
+    clearLastError();
+    const handle = client orelse {
+        setLastError(error.InvalidParameter);
+        return null;
+    };
+    const allocator = getAllocator();
+
+    var flow = handle.client.startAuthorization(handle.client.config.redirect_uri) catch |err| {
+        setLastError(err);
+        return null;
+    };
+
+    const flow_ptr = allocator.create(AuthorizationFlow) catch |err| {
+        setLastError(err);
+        flow.deinit();
+        return null;
+    };
+    flow_ptr.* = flow;
+
+    const flow_handle = allocator.create(SchlusselAuthFlow) catch |err| {
+        setLastError(err);
+        flow.deinit();
+        allocator.destroy(flow_ptr);
+        return null;
+    };
+    flow_handle.* = .{ .flow = flow_ptr };
+
+    return flow_handle;
+}
+
+/// Exchange an authorization code without any stored flow state
+///
+/// For native SDKs that keep the verifier and redirect URI themselves (see
+/// schlussel_start_flow_parts). The token is not saved; on success
+/// `out_token_json` receives it as JSON, to be freed with schlussel_string_free.
+/// The JSON never carries the DPoP private key of a DPoP-bound token; the
+/// key stays with the client.
+export fn schlussel_exchange_code_stateless(
+    client: ?*SchlusselClient,
+    code: [*c]const u8,
+    code_verifier: [*c]const u8,
+    redirect_uri: [*c]const u8,
+    out_token_json: ?*?[*:0]u8,
+) c_int {
+    clearLastError();
+    const handle = client orelse {
+        setLastError(error.InvalidParameter);
+        return errorCodeFromAny(error.InvalidParameter);
+    };
+    const out = out_token_json orelse {
+        setLastError(error.InvalidParameter);
+        return errorCodeFromAny(error.InvalidParameter);
+    };
+    out.* = null;
+    if (code == null or code_verifier == null or redirect_uri == null) {
+        setLastError(error.InvalidParameter);
+        return errorCodeFromAny(error.InvalidParameter);
+    }
+    const allocator = getAllocator();
+
+    var token = handle.client.exchangeCode(
+        std.mem.span(code),
+        std.mem.span(code_verifier),
+        std.mem.span(redirect_uri),
+    ) catch |err| {
+        setLastError(err);
+        return errorCodeFromAny(err);
+    };
+    defer token.deinit();
+
+    // The JSON leaves the process in plain text
+    if (token.dpop_key) |key| {
+        std.crypto.secureZero(u8, @constCast(key));
+        token.allocator.free(key);
+        token.dpop_key = null;
+    }
+
+    const json_data = token.toJson(allocator) catch |err| {
+        setLastError(err);
+        return errorCodeFromAny(err);
+    };
+    defer allocator.free(json_data);
+
+    out.* = dupeToC(json_data) orelse {
+        setLastError(error.OutOfMemory);
+        return errorCodeFromAny(error.OutOfMemory);
+    };
+    return 0; // SCHLUSSEL_OK
+}
+
+/// Get the authorization URL to open
+export fn schlussel_auth_flow_get_url(flow: ?*SchlusselAuthFlow) ?[*:0]u8 {
+    const handle = flow orelse return null;
+    return dupeToC(handle.flow.url);
+}
+
+/// Get the state to compare against the callback
+export fn schlussel_auth_flow_get_state(flow: ?*SchlusselAuthFlow) ?[*:0]u8 {
+    const handle = flow orelse return null;
+    return dupeToC(handle.flow.getState());
+}
+
+/// Get the PKCE code verifier to send with the code exchange
+export fn schlussel_auth_flow_get_code_verifier(flow: ?*SchlusselAuthFlow) ?[*:0]u8 {
+    const handle = flow orelse return null;
+    return dupeToC(handle.flow.pkce_pair.getVerifier());
+}
+
+/// Get the OIDC nonce, or NULL if the request did not carry one
+export fn schlussel_auth_flow_get_nonce(flow: ?*SchlusselAuthFlow) ?[*:0]u8 {
+    const handle = flow orelse return null;
+    const nonce = handle.flow.sentNonce() orelse return null;
+    return dupeToC(nonce);
+}
+
+/// Free an authorization flow
+export fn schlussel_auth_flow_free(flow: ?*SchlusselAuthFlow) void {
+    const handle = flow orelse return;
+    const allocator = getAllocator();
+
+    handle.flow.deinit();
+    allocator.destroy(handle.flow);
+    allocator.destroy(handle);
+}
+
 // ============================================================================
 // Token storage operations
 // ============================================================================
@@ -516,7 +702,10 @@ export fn schlussel_get_token(
     var token = (handle.client.getToken(std.mem.span(key)) catch |err| {
         setLastError(err);
         return null;
-    }) orelse return null;
+    }) orelse {
+        setLastError(error.TokenNotFound);
+        return null;
+    };
 
     const token_ptr = allocator.create(Token) catch |err| {
         setLastError(err);
@@ -536,6 +725,136 @@ export fn schlussel_get_token(
     return token_handle;
 }
 
+/// Get a valid token from storage, refreshing it first if needed
+///
+/// All calls for one client go through a single shared refresher, so
+/// concurrent callers wait for one in-flight refresh instead of racing.
+/// A negative `threshold` uses the default refresh threshold.
+export fn schlussel_get_valid_token(
+    client: ?*SchlusselClient,
+    key: [*c]const u8,
+    threshold: f64,
+) ?*SchlusselToken {
+    clearLastError();
+    const handle = client orelse {
+        setLastError(error.InvalidParameter);
+        return null;
+    };
+    const refresher = sharedRefresher(handle) catch |err| {
+        setLastError(err);
+        return null;
+    };
+    return validTokenHandle(refresher, key, threshold);
+}
+
+/// Guards lazy creation of each client's shared refresher
+var shared_refresher_mutex: std.Thread.Mutex = .{};
+
+fn sharedRefresher(handle: *SchlusselClient) !*TokenRefresher {
+    shared_refresher_mutex.lock();
+    defer shared_refresher_mutex.unlock();
+
+    if (handle.refresher) |refresher| return refresher;
+
+    const allocator = getAllocator();
+    const refresher = try allocator.create(TokenRefresher);
+    refresher.* = TokenRefresher.init(allocator, handle.client);
+    handle.refresher = refresher;
+    return refresher;
+}
+
+fn validTokenHandle(refresher: *TokenRefresher, key: [*c]const u8, threshold: f64) ?*SchlusselToken {
+    if (key == null or threshold > 1.0 or std.math.isNan(threshold)) {
+        setLastError(error.InvalidParameter);
+        return null;
+    }
+    const allocator = getAllocator();
+
+    var token = refresher.getValidTokenWithThreshold(
+        std.mem.span(key),
+        if (threshold < 0) refresher.refresh_threshold else threshold,
+    ) catch |err| {
+        setLastError(err);
+        return null;
+    };
+
+    const token_ptr = allocator.create(Token) catch |err| {
+        setLastError(err);
+        token.deinit();
+        return null;
+    };
+    token_ptr.* = token;
+
+    const token_handle = allocator.create(SchlusselToken) catch |err| {
+        setLastError(err);
+        token.deinit();
+        allocator.destroy(token_ptr);
+        return null;
+    };
+    token_handle.* = .{ .token = token_ptr };
+
+    return token_handle;
+}
+
+// ============================================================================
+// Token refresher functions
+// ============================================================================
+
+/// Create a token refresher for a client
+///
+/// The refresher keeps its single-flight and failure state across calls.
+/// It must be freed before the client it was created from.
+export fn schlussel_token_refresher_new(client: ?*SchlusselClient) ?*SchlusselTokenRefresher {
+    clearLastError();
+    const handle = client orelse {
+        setLastError(error.InvalidParameter);
+        return null;
+    };
+    const allocator = getAllocator();
+
+    const refresher_ptr = allocator.create(TokenRefresher) catch |err| {
+        setLastError(err);
+        return null;
+    };
+    refresher_ptr.* = TokenRefresher.init(allocator, handle.client);
+
+    const refresher_handle = allocator.create(SchlusselTokenRefresher) catch |err| {
+        setLastError(err);
+        refresher_ptr.deinit();
+        allocator.destroy(refresher_ptr);
+        return null;
+    };
+    refresher_handle.* = .{ .refresher = refresher_ptr };
+
+    return refresher_handle;
+}
+
+/// Get a valid token through a refresher, refreshing it first if needed
+///
+/// A negative `threshold` uses the default refresh threshold.
+export fn schlussel_token_refresher_get_valid_token(
+    refresher: ?*SchlusselTokenRefresher,
+    key: [*c]const u8,
+    threshold: f64,
+) ?*SchlusselToken {
+    clearLastError();
+    const handle = refresher orelse {
+        setLastError(error.InvalidParameter);
+        return null;
+    };
+    return validTokenHandle(handle.refresher, key, threshold);
+}
+
+/// Free a token refresher
+export fn schlussel_token_refresher_free(refresher: ?*SchlusselTokenRefresher) void {
+    const handle = refresher orelse return;
+    const allocator = getAllocator();
+
+    handle.refresher.deinit();
+    allocator.destroy(handle.refresher);
+    allocator.destroy(handle);
+}
+
 /// Delete a token from storage
 export fn schlussel_delete_token(
     client: ?*SchlusselClient,
@@ -649,6 +968,30 @@ export fn schlussel_token_free(token: ?*SchlusselToken) void {
     allocator.destroy(handle);
 }
 
+// ============================================================================
+// Test utilities (built with -Dffi-test-util only)
+// ============================================================================
+
+comptime {
+    if (build_options.ffi_test_util) {
+        @export(&schlussel_set_mock_time, .{ .name = "schlussel_set_mock_time" });
+        @export(&schlussel_clear_mock_time, .{ .name = "schlussel_clear_mock_time" });
+    }
+}
+
+/// Pin the clock used for expiry and refresh decisions (test only)
+///
+/// Process-wide: affects every client, token and thread until
+/// schlussel_clear_mock_time() is called.
+fn schlussel_set_mock_time(unix_secs: u64) callconv(.c) void {
+    clock.setMockTime(unix_secs);
+}
+
+/// Return to the system clock (test only)
+fn schlussel_clear_mock_time() callconv(.c) void {
+    clock.clearMockTime();
+}
+
 // ============================================================================
 // String operations
 // ============================================================================
@@ -875,6 +1218,10 @@ export fn schlussel_run_script(client: ?*SchlusselClient, script_json: [*c]const
             return null;
         };
         defer result.deinit();
+        handle.client.unwrapJarmResponse(&result) catch |err| {
+            setLastError(err);
+            return null;
+        };
 
         if (result.state) |callback_state| {
             if (!std.mem.eql(u8, callback_state, state)) {
@@ -883,8 +1230,8 @@ export fn schlussel_run_script(client: ?*SchlusselClient, script_json: [*c]const
             }
         }
 
-        if (result.error_code != null) {
-            setLastError(error.AuthorizationDenied);
+        if (result.oauthError()) |err| {
+            setLastError(err);
             return null;
         }
 
@@ -1128,6 +1475,7 @@ fn resolveScriptFromFormula(
             config.scope,
             &state,
             pair.getChallenge(),
+            pair.getChallengeMethod(),
         );
         const authorize_dup = try allocator.dupe(u8, authorize_url);
         allocator.free(authorize_url);
@@ -1649,3 +1997,207 @@ test "FFI last error clears" {
     try std.testing.expectEqual(@as(c_int, 0), schlussel_last_error_code());
     try std.testing.expect(schlussel_last_error_message() == null);
 }
+
+test "FFI mock clock drives token expiry" {
+    const allocator = getAllocator();
+
+    // Build a token handle the way the C API hands them out
+    const token_ptr = try allocator.create(Token);
+    token_ptr.* = try Token.initFull(allocator, "access", "Bearer", null, 3600, null, null);
+    const handle = try allocator.create(SchlusselToken);
+    handle.* = .{ .token = token_ptr };
+    defer schlussel_token_free(handle);
+
+    const expires_at = schlussel_token_get_expires_at(handle);
+    try std.testing.expect(expires_at != 0);
+
+    schlussel_set_mock_time(expires_at - 1);
+    defer schlussel_clear_mock_time();
+    try std.testing.expectEqual(@as(c_int, 0), schlussel_token_is_expired(handle));
+
+    schlussel_set_mock_time(expires_at + 1);
+    try std.testing.expectEqual(@as(c_int, 1), schlussel_token_is_expired(handle));
+
+    schlussel_clear_mock_time();
+    try std.testing.expectEqual(@as(c_int, 0), schlussel_token_is_expired(handle));
+}
+
+test "FFI get valid token reports missing keys and returns stored tokens" {
+    const client = schlussel_client_new(
+        "test-client",
+        "https://example.com/authorize",
+        "https://example.com/token",
+        "http://127.0.0.1/callback",
+        null,
+        null,
+    ) orelse return error.TestUnexpectedResult;
+    defer schlussel_client_free(client);
+
+    try std.testing.expect(schlussel_get_valid_token(client, "missing", -1) == null);
+    try std.testing.expectEqual(error_types.toErrorCode(error.TokenNotFound), schlussel_last_error_code());
+    try std.testing.expect(schlussel_get_token(client, "missing") == null);
+    try std.testing.expectEqual(error_types.toErrorCode(error.TokenNotFound), schlussel_last_error_code());
+
+    var token = try Token.initFull(getAllocator(), "access", "Bearer", "refresh", 3600, null, null);
+    defer token.deinit();
+    try client.client.saveToken("svc", token);
+
+    // Fresh token: no refresh, no network
+    const valid = schlussel_get_valid_token(client, "svc", -1) orelse return error.TestUnexpectedResult;
+    defer schlussel_token_free(valid);
+    try std.testing.expectEqual(@as(c_int, 0), schlussel_last_error_code());
+
+    const access_token = schlussel_token_get_access_token(valid) orelse return error.TestUnexpectedResult;
+    defer schlussel_string_free(access_token);
+    try std.testing.expectEqualStrings("access", std.mem.span(access_token));
+
+    try std.testing.expect(schlussel_get_valid_token(client, "svc", 1.5) == null);
+    try std.testing.expectEqual(error_types.toErrorCode(error.InvalidParameter), schlussel_last_error_code());
+
+    // Every call reuses the client's one refresher
+    const shared = client.refresher orelse return error.TestUnexpectedResult;
+    const again = schlussel_get_valid_token(client, "svc", -1) orelse return error.TestUnexpectedResult;
+    defer schlussel_token_free(again);
+    try std.testing.expectEqual(shared, client.refresher.?);
+}
+
+test "FFI token refresher returns stored tokens across calls" {
+    const client = schlussel_client_new(
+        "test-client",
+        "https://example.com/authorize",
+        "https://example.com/token",
+        "http://127.0.0.1/callback",
+        null,
+        null,
+    ) orelse return error.TestUnexpectedResult;
+    defer schlussel_client_free(client);
+
+    const refresher = schlussel_token_refresher_new(client) orelse return error.TestUnexpectedResult;
+    defer schlussel_token_refresher_free(refresher);
+
+    try std.testing.expect(schlussel_token_refresher_get_valid_token(refresher, "missing", -1) == null);
+    try std.testing.expectEqual(error_types.toErrorCode(error.TokenNotFound), schlussel_last_error_code());
+
+    var token = try Token.initFull(getAllocator(), "access", "Bearer", "refresh", 3600, null, null);
+    defer token.deinit();
+    try client.client.saveToken("svc", token);
+
+    for (0..2) |_| {
+        const valid = schlussel_token_refresher_get_valid_token(refresher, "svc", -1) orelse return error.TestUnexpectedResult;
+        defer schlussel_token_free(valid);
+
+        const access_token = schlussel_token_get_access_token(valid) orelse return error.TestUnexpectedResult;
+        defer schlussel_string_free(access_token);
+        try std.testing.expectEqualStrings("access", std.mem.span(access_token));
+    }
+
+    try std.testing.expect(schlussel_token_refresher_get_valid_token(null, "svc", -1) == null);
+    try std.testing.expectEqual(error_types.toErrorCode(error.InvalidParameter), schlussel_last_error_code());
+}
+
+test "FFI start flow parts expose a verifier matching the URL challenge" {
+    const allocator = getAllocator();
+
+    const client = schlussel_client_new(
+        "test-client",
+        "https://example.com/authorize",
+        "https://example.com/token",
+        "com.example.app:/callback",
+        "openid profile",
+        null,
+    ) orelse return error.TestUnexpectedResult;
+    defer schlussel_client_free(client);
+
+    const flow = schlussel_start_flow_parts(client) orelse return error.TestUnexpectedResult;
+    defer schlussel_auth_flow_free(flow);
+
+    const url = schlussel_auth_flow_get_url(flow) orelse return error.TestUnexpectedResult;
+    defer schlussel_string_free(url);
+    const state = schlussel_auth_flow_get_state(flow) orelse return error.TestUnexpectedResult;
+    defer schlussel_string_free(state);
+    const verifier = schlussel_auth_flow_get_code_verifier(flow) orelse return error.TestUnexpectedResult;
+    defer schlussel_string_free(verifier);
+    const nonce = schlussel_auth_flow_get_nonce(flow) orelse return error.TestUnexpectedResult;
+    defer schlussel_string_free(nonce);
+
+    var request = try callback.AuthorizationRequest.parse(allocator, std.mem.span(url));
+    defer request.deinit();
+
+    const recomputed = try pkce.Pkce.fromVerifier(std.mem.span(verifier));
+    try std.testing.expectEqualStrings(recomputed.getChallenge(), request.get("code_challenge").?);
+    try std.testing.expectEqualStrings(std.mem.span(state), request.get("state").?);
+    try std.testing.expectEqualStrings(std.mem.span(nonce), request.get("nonce").?);
+
+    try std.testing.expect(schlussel_auth_flow_get_url(null) == null);
+    schlussel_auth_flow_free(null);
+}
+
+test "FFI stateless exchange returns token JSON and maps server errors" {
+    const allocator = getAllocator();
+
+    const client = schlussel_client_new(
+        "test-client",
+        "https://example.com/authorize",
+        "https://example.com/token",
+        "com.example.app:/callback",
+        null,
+        null,
+    ) orelse return error.TestUnexpectedResult;
+    defer schlussel_client_free(client);
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"native-token\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"bound-token\",\"token_type\":\"DPoP\",\"expires_in\":3600}" });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"invalid_grant\"}" });
+    client.client.http_transport = mock.transport();
+
+    var token_json: ?[*:0]u8 = null;
+    try std.testing.expectEqual(@as(c_int, 0), schlussel_exchange_code_stateless(
+        client,
+        "auth-code",
+        "verifier",
+        "com.example.app:/callback",
+        &token_json,
+    ));
+    defer schlussel_string_free(token_json);
+
+    var token = try Token.fromJson(allocator, std.mem.span(token_json.?));
+    defer token.deinit();
+    try std.testing.expectEqualStrings("native-token", token.access_token);
+
+    const body = mock.lastRequest().?.body.?;
+    try std.testing.expect(std.mem.indexOf(u8, body, "code=auth-code") != null);
+    try std.testing.expect(std.mem.indexOf(u8, body, "code_verifier=verifier") != null);
+
+    // Nothing is stored in the client's memory storage
+    try std.testing.expectEqual(SchlusselClient.StorageType.memory, client.storage_type);
+    const stored_keys = try client.client.storage.listKeys(allocator);
+    defer session.SessionStorage.freeKeys(allocator, stored_keys);
+    try std.testing.expectEqual(@as(usize, 0), stored_keys.len);
+
+    // A DPoP-bound token comes back without its private key
+    client.client.dpop_key = dpop.DpopKey.generate();
+    var bound_json: ?[*:0]u8 = null;
+    try std.testing.expectEqual(@as(c_int, 0), schlussel_exchange_code_stateless(
+        client,
+        "bound-code",
+        "verifier",
+        "com.example.app:/callback",
+        &bound_json,
+    ));
+    defer schlussel_string_free(bound_json);
+    try std.testing.expect(std.mem.indexOf(u8, std.mem.span(bound_json.?), "bound-token") != null);
+    try std.testing.expect(std.mem.indexOf(u8, std.mem.span(bound_json.?), "dpop_key") == null);
+
+    var rejected: ?[*:0]u8 = null;
+    try std.testing.expectEqual(error_types.toErrorCode(error.InvalidGrant), schlussel_exchange_code_stateless(
+        client,
+        "used-code",
+        "verifier",
+        "com.example.app:/callback",
+        &rejected,
+    ));
+    try std.testing.expect(rejected == null);
+    try std.testing.expectEqual(error_types.toErrorCode(error.InvalidGrant), schlussel_last_error_code());
+}
@@ -0,0 +1,449 @@
+//! OpenID Connect ID token validation (OpenID Connect Core 1.0 Section 3.1.3.7)
+//!
+//! An ID token is only trustworthy once its signature verifies against the
+//! provider's keys and its claims say it was issued by the expected provider,
+//! for this client, recently, and for this authorization request. `IdToken`
+//! performs all of those checks; `OAuthClient` runs them automatically on
+//! token responses carrying an `id_token` when the config names an issuer.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var keys = try jwks.fetch(allocator, http_transport, config.jwks_uri.?);
+//! defer keys.deinit();
+//!
+//! var id_token = try IdToken.validate(allocator, token.id_token.?, &keys, .{
+//!     .issuer = config.issuer.?,
+//!     .client_id = config.client_id,
+//!     .nonce = flow.sentNonce(),
+//! });
+//! defer id_token.deinit();
+//! ```
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+
+const clock = @import("clock.zig");
+const jwks = @import("jwks.zig");
+const jwt = @import("jwt.zig");
+
+/// Validated ID token claims
+pub const IdToken = struct {
+    allocator: Allocator,
+    /// Issuer identifier
+    iss: []const u8,
+    /// Subject (the end user's identifier at the issuer)
+    sub: []const u8,
+    /// Audiences; always contains the client ID once validated
+    aud: []const []const u8,
+    /// Expiry (Unix time)
+    exp: u64,
+    /// Issue time (Unix time)
+    iat: u64,
+    nonce: ?[]const u8 = null,
+    /// Authorized party the token was issued to
+    azp: ?[]const u8 = null,
+    /// Session ID at the provider, referenced by logout tokens
+    sid: ?[]const u8 = null,
+    /// Time the end user authenticated (Unix time)
+    auth_time: ?u64 = null,
+    /// Authentication context class the provider satisfied
+    acr: ?[]const u8 = null,
+    /// Authentication methods used, such as `pwd`, `otp` or `mfa` (RFC 8176)
+    amr: []const []const u8 = &.{},
+
+    /// Clock skew tolerated unless a Validation says otherwise, in seconds
+    pub const default_leeway: u64 = 60;
+
+    /// What a valid ID token must match
+    pub const Validation = struct {
+        /// Expected `iss` (the provider's issuer identifier)
+        issuer: []const u8,
+        /// Client ID that must appear in `aud`
+        client_id: []const u8,
+        /// Nonce sent in the authorization request, if any
+        nonce: ?[]const u8 = null,
+        /// `max_age` sent in the authorization request, if any
+        max_age: ?u64 = null,
+        /// Space-separated `acr_values` requested; `acr` must be one of them
+        acr_values: ?[]const u8 = null,
+        /// Space-separated methods that must all appear in `amr`
+        required_amr: ?[]const u8 = null,
+        /// Tolerated clock skew for `exp` and `iat`, in seconds
+        leeway: u64 = default_leeway,
+        /// Accepted signing algorithms
+        verification: jwt.VerificationConfig = .{},
+        /// Key for HMAC-signed tokens (the client secret)
+        client_secret: ?[]const u8 = null,
+    };
+
+    /// Verify the signature of `raw` against `keys` and check its claims
+    ///
+    /// Returns `error.InvalidSignature` for a bad signature,
+    /// `error.InvalidNonce` for a nonce mismatch, `error.StaleAuthentication`
+    /// when the user authenticated longer ago than `max_age` allows,
+    /// `error.InsufficientAuthentication` when `acr` or `amr` fall short of
+    /// the requirements and `error.InvalidIdToken` for any other failed
+    /// claim check.
+    pub fn validate(allocator: Allocator, raw: []const u8, keys: *const jwks.JwkSet, validation: Validation) !IdToken {
+        try keys.verify(allocator, raw, validation.verification, validation.client_secret);
+
+        var id_token = try decode(allocator, raw);
+        errdefer id_token.deinit();
+
+        try id_token.checkClaims(validation);
+        return id_token;
+    }
+
+    /// Decode the claims of `raw` without verifying anything
+    pub fn decode(allocator: Allocator, raw: []const u8) !IdToken {
+        const parts = try jwt.split(raw);
+        const payload = try jwt.decodeSegment(allocator, parts.payload);
+        defer allocator.free(payload);
+
+        const parsed = json.parseFromSlice(json.Value, allocator, payload, .{}) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.InvalidIdToken,
+        };
+        defer parsed.deinit();
+
+        if (parsed.value != .object) return error.InvalidIdToken;
+        const claims = parsed.value.object;
+
+        const iss = stringClaim(claims, "iss") orelse return error.InvalidIdToken;
+        const sub = stringClaim(claims, "sub") orelse return error.InvalidIdToken;
+        const exp = timeClaim(claims, "exp") orelse return error.InvalidIdToken;
+        const iat = timeClaim(claims, "iat") orelse return error.InvalidIdToken;
+
+        var id_token = IdToken{
+            .allocator = allocator,
+            .iss = try allocator.dupe(u8, iss),
+            .sub = &.{},
+            .aud = &.{},
+            .exp = exp,
+            .iat = iat,
+            .auth_time = timeClaim(claims, "auth_time"),
+        };
+        errdefer id_token.deinit();
+
+        id_token.sub = try allocator.dupe(u8, sub);
+        id_token.aud = try audienceClaim(allocator, claims.get("aud") orelse return error.InvalidIdToken);
+        if (stringClaim(claims, "nonce")) |nonce| id_token.nonce = try allocator.dupe(u8, nonce);
+        if (stringClaim(claims, "azp")) |azp| id_token.azp = try allocator.dupe(u8, azp);
+        if (stringClaim(claims, "sid")) |sid| id_token.sid = try allocator.dupe(u8, sid);
+        if (stringClaim(claims, "acr")) |acr| id_token.acr = try allocator.dupe(u8, acr);
+        if (claims.get("amr")) |amr| id_token.amr = try methodsClaim(allocator, amr);
+
+        return id_token;
+    }
+
+    pub fn deinit(self: *IdToken) void {
+        self.allocator.free(self.iss);
+        self.allocator.free(self.sub);
+        for (self.aud) |aud| self.allocator.free(aud);
+        self.allocator.free(self.aud);
+        if (self.nonce) |n| self.allocator.free(n);
+        if (self.azp) |a| self.allocator.free(a);
+        if (self.sid) |sid| self.allocator.free(sid);
+        if (self.acr) |a| self.allocator.free(a);
+        for (self.amr) |method| self.allocator.free(method);
+        self.allocator.free(self.amr);
+    }
+
+    /// Check `iss`, `aud`, `azp`, `exp`, `iat`, `nonce`, `auth_time`, `acr`
+    /// and `amr` against `validation`
+    pub fn checkClaims(self: *const IdToken, validation: Validation) !void {
+        if (!std.mem.eql(u8, self.iss, validation.issuer)) return error.InvalidIdToken;
+        if (!self.hasAudience(validation.client_id)) return error.InvalidIdToken;
+
+        // With several audiences the token must say which one it was issued to
+        if (self.azp) |azp| {
+            if (!std.mem.eql(u8, azp, validation.client_id)) return error.InvalidIdToken;
+        } else if (self.aud.len > 1) {
+            return error.InvalidIdToken;
+        }
+
+        const now = clock.now();
+        if (now > self.exp +| validation.leeway) return error.InvalidIdToken;
+        if (self.iat > now +| validation.leeway) return error.InvalidIdToken;
+
+        if (validation.nonce) |expected| {
+            const nonce = self.nonce orelse return error.InvalidNonce;
+            if (!constantTimeEql(nonce, expected)) return error.InvalidNonce;
+        }
+
+        if (validation.max_age) |max_age| try self.checkAuthTime(max_age, validation.leeway);
+        try self.checkAuthenticationStrength(validation.acr_values, validation.required_amr);
+    }
+
+    /// Check that the user authenticated within the last `max_age` seconds
+    ///
+    /// The `auth_time` claim is required once `max_age` was requested
+    /// (OpenID Connect Core Section 3.1.2.1), so a token without it fails
+    /// with `error.InvalidIdToken`. An older authentication yields
+    /// `error.StaleAuthentication`.
+    pub fn checkAuthTime(self: *const IdToken, max_age: u64, leeway: u64) !void {
+        const auth_time = self.auth_time orelse return error.InvalidIdToken;
+        if (clock.now() > auth_time +| max_age +| leeway) return error.StaleAuthentication;
+    }
+
+    /// Check that the user authenticated as strongly as required
+    ///
+    /// `acr` must be one of the space-separated `acr_values` and every
+    /// method in the space-separated `required_amr` must appear in `amr`.
+    /// Otherwise returns `error.InsufficientAuthentication`: the user has
+    /// to sign in again with stronger factors, for instance by repeating
+    /// the flow with the same `acr_values`.
+    pub fn checkAuthenticationStrength(self: *const IdToken, acr_values: ?[]const u8, required_amr: ?[]const u8) !void {
+        if (acr_values) |accepted| {
+            const acr = self.acr orelse return error.InsufficientAuthentication;
+            var values = std.mem.tokenizeScalar(u8, accepted, ' ');
+            while (values.next()) |value| {
+                if (std.mem.eql(u8, value, acr)) break;
+            } else return error.InsufficientAuthentication;
+        }
+        if (required_amr) |required| {
+            var methods = std.mem.tokenizeScalar(u8, required, ' ');
+            while (methods.next()) |method| {
+                if (!self.usedMethod(method)) return error.InsufficientAuthentication;
+            }
+        }
+    }
+
+    /// Whether `method` is one of the token's authentication methods
+    pub fn usedMethod(self: *const IdToken, method: []const u8) bool {
+        for (self.amr) |used| {
+            if (std.mem.eql(u8, used, method)) return true;
+        }
+        return false;
+    }
+
+    /// Whether `client_id` is one of the token's audiences
+    pub fn hasAudience(self: *const IdToken, client_id: []const u8) bool {
+        for (self.aud) |aud| {
+            if (std.mem.eql(u8, aud, client_id)) return true;
+        }
+        return false;
+    }
+};
+
+pub fn stringClaim(claims: json.ObjectMap, name: []const u8) ?[]const u8 {
+    const value = claims.get(name) orelse return null;
+    return if (value == .string) value.string else null;
+}
+
+pub fn timeClaim(claims: json.ObjectMap, name: []const u8) ?u64 {
+    const value = claims.get(name) orelse return null;
+    return switch (value) {
+        .integer => |i| if (i >= 0) @intCast(i) else null,
+        .float => |f| if (f >= 0 and f < 1e18) @intFromFloat(f) else null,
+        else => null,
+    };
+}
+
+/// `aud` is a single string or an array of strings
+pub fn audienceClaim(allocator: Allocator, value: json.Value) ![]const []const u8 {
+    const single = [_]json.Value{value};
+    const items: []const json.Value = switch (value) {
+        .string => &single,
+        .array => |array| array.items,
+        else => return error.InvalidIdToken,
+    };
+    if (items.len == 0) return error.InvalidIdToken;
+
+    const aud = try allocator.alloc([]const u8, items.len);
+    var copied: usize = 0;
+    errdefer {
+        for (aud[0..copied]) |a| allocator.free(a);
+        allocator.free(aud);
+    }
+    for (items) |item| {
+        if (item != .string) return error.InvalidIdToken;
+        aud[copied] = try allocator.dupe(u8, item.string);
+        copied += 1;
+    }
+    return aud;
+}
+
+/// `amr` is an array of strings; entries that are not strings are skipped
+fn methodsClaim(allocator: Allocator, value: json.Value) ![]const []const u8 {
+    if (value != .array) return error.InvalidIdToken;
+
+    var methods: std.ArrayListUnmanaged([]const u8) = .{};
+    errdefer {
+        for (methods.items) |method| allocator.free(method);
+        methods.deinit(allocator);
+    }
+    for (value.array.items) |item| {
+        if (item != .string) continue;
+        const method = try allocator.dupe(u8, item.string);
+        errdefer allocator.free(method);
+        try methods.append(allocator, method);
+    }
+    return methods.toOwnedSlice(allocator);
+}
+
+/// Compare two secrets in constant time
+///
+/// std.crypto.timing_safe.eql only takes fixed-size arrays, so both sides
+/// are hashed first; neither the contents nor the length leak.
+pub fn constantTimeEql(a: []const u8, b: []const u8) bool {
+    const Sha256 = std.crypto.hash.sha2.Sha256;
+    var digest_a: [Sha256.digest_length]u8 = undefined;
+    var digest_b: [Sha256.digest_length]u8 = undefined;
+    Sha256.hash(a, &digest_a, .{});
+    Sha256.hash(b, &digest_b, .{});
+    return std.crypto.timing_safe.eql([Sha256.digest_length]u8, digest_a, digest_b);
+}
+
+/// Sign `claims_json` as an ES256 ID token with key ID `k1`
+fn testSignedToken(allocator: Allocator, key_pair: std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair, claims_json: []const u8) ![]u8 {
+    const header = try jwt.encodeSegment(allocator, "{\"alg\":\"ES256\",\"kid\":\"k1\"}");
+    defer allocator.free(header);
+    const payload = try jwt.encodeSegment(allocator, claims_json);
+    defer allocator.free(payload);
+
+    const signing_input = try std.fmt.allocPrint(allocator, "{s}.{s}", .{ header, payload });
+    defer allocator.free(signing_input);
+    const signature = (try key_pair.sign(signing_input, null)).toBytes();
+    const encoded_signature = try jwt.encodeSegment(allocator, &signature);
+    defer allocator.free(encoded_signature);
+
+    return std.fmt.allocPrint(allocator, "{s}.{s}", .{ signing_input, encoded_signature });
+}
+
+fn testKeySet(allocator: Allocator, key_pair: std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair) !jwks.JwkSet {
+    const point = key_pair.public_key.toUncompressedSec1();
+    const x = try jwt.encodeSegment(allocator, point[1..33]);
+    defer allocator.free(x);
+    const y = try jwt.encodeSegment(allocator, point[33..65]);
+    defer allocator.free(y);
+
+    const document = try std.fmt.allocPrint(allocator, "{{\"keys\":[{{\"kty\":\"EC\",\"crv\":\"P-256\",\"kid\":\"k1\",\"x\":\"{s}\",\"y\":\"{s}\"}}]}}", .{ x, y });
+    defer allocator.free(document);
+    return jwks.JwkSet.parse(allocator, document);
+}
+
+test "IdToken.validate accepts a signed token issued for the client" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    const key_pair = std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair.generate();
+    var keys = try testKeySet(allocator, key_pair);
+    defer keys.deinit();
+
+    const raw = try testSignedToken(allocator, key_pair,
+        \\{"iss":"https://id.example.com","sub":"user-1","aud":["client","api"],"azp":"client",
+        \\ "exp":1700000600,"iat":1700000000,"nonce":"n-0S6","auth_time":1699999990}
+    );
+    defer allocator.free(raw);
+
+    const validation = IdToken.Validation{ .issuer = "https://id.example.com", .client_id = "client", .nonce = "n-0S6" };
+    var id_token = try IdToken.validate(allocator, raw, &keys, validation);
+    defer id_token.deinit();
+
+    try std.testing.expectEqualStrings("user-1", id_token.sub);
+    try std.testing.expectEqual(@as(usize, 2), id_token.aud.len);
+    try std.testing.expectEqual(@as(?u64, 1_699_999_990), id_token.auth_time);
+
+    var other_issuer = validation;
+    other_issuer.issuer = "https://evil.example.com";
+    try std.testing.expectError(error.InvalidIdToken, IdToken.validate(allocator, raw, &keys, other_issuer));
+
+    var other_client = validation;
+    other_client.client_id = "api";
+    try std.testing.expectError(error.InvalidIdToken, IdToken.validate(allocator, raw, &keys, other_client));
+
+    var other_nonce = validation;
+    other_nonce.nonce = "replayed";
+    try std.testing.expectError(error.InvalidNonce, IdToken.validate(allocator, raw, &keys, other_nonce));
+
+    // Past expiry plus leeway
+    clock.setMockTime(1_700_000_661);
+    try std.testing.expectError(error.InvalidIdToken, IdToken.validate(allocator, raw, &keys, validation));
+}
+
+test "IdToken.checkClaims enforces max_age against auth_time" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    const key_pair = std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair.generate();
+    const raw = try testSignedToken(allocator, key_pair,
+        \\{"iss":"https://id.example.com","sub":"user-1","aud":"client","exp":1700000600,"iat":1700000000,"auth_time":1699999000}
+    );
+    defer allocator.free(raw);
+
+    var id_token = try IdToken.decode(allocator, raw);
+    defer id_token.deinit();
+
+    const validation = IdToken.Validation{ .issuer = "https://id.example.com", .client_id = "client", .max_age = 3600 };
+    try id_token.checkClaims(validation);
+
+    var strict = validation;
+    strict.max_age = 300;
+    try std.testing.expectError(error.StaleAuthentication, id_token.checkClaims(strict));
+
+    id_token.auth_time = null;
+    try std.testing.expectError(error.InvalidIdToken, id_token.checkClaims(validation));
+}
+
+test "IdToken.checkClaims enforces acr_values and required amr" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    const key_pair = std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair.generate();
+    const raw = try testSignedToken(allocator, key_pair,
+        \\{"iss":"https://id.example.com","sub":"user-1","aud":"client","exp":1700000600,"iat":1700000000,"acr":"urn:example:silver","amr":["pwd","otp"]}
+    );
+    defer allocator.free(raw);
+
+    var id_token = try IdToken.decode(allocator, raw);
+    defer id_token.deinit();
+    try std.testing.expectEqualStrings("urn:example:silver", id_token.acr.?);
+    try std.testing.expect(id_token.usedMethod("otp"));
+
+    const validation = IdToken.Validation{
+        .issuer = "https://id.example.com",
+        .client_id = "client",
+        .acr_values = "urn:example:gold urn:example:silver",
+        .required_amr = "pwd otp",
+    };
+    try id_token.checkClaims(validation);
+
+    var gold = validation;
+    gold.acr_values = "urn:example:gold";
+    try std.testing.expectError(error.InsufficientAuthentication, id_token.checkClaims(gold));
+
+    var mfa = validation;
+    mfa.required_amr = "mfa";
+    try std.testing.expectError(error.InsufficientAuthentication, id_token.checkClaims(mfa));
+}
+
+test "IdToken.validate rejects tokens signed by another key" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    const published = std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair.generate();
+    const attacker = std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair.generate();
+    var keys = try testKeySet(allocator, published);
+    defer keys.deinit();
+
+    const raw = try testSignedToken(allocator, attacker,
+        \\{"iss":"https://id.example.com","sub":"user-1","aud":"client","exp":1700000600,"iat":1700000000}
+    );
+    defer allocator.free(raw);
+
+    try std.testing.expectError(error.InvalidSignature, IdToken.validate(allocator, raw, &keys, .{
+        .issuer = "https://id.example.com",
+        .client_id = "client",
+    }));
+}
@@ -0,0 +1,843 @@
+//! Importing credentials kept by other tools
+//!
+//! Users moving from curl- or git-based workflows already have machine
+//! credentials in `~/.netrc`. `importNetrc()` turns each `machine` entry
+//! into a token saved under the machine name: the password becomes the
+//! access token and the login is kept in `Token.metadata`.
+//!
+//! Tools that sit next to a vendor CLI can reuse its sign-in instead:
+//!
+//! - `parseGhHosts()`: GitHub CLI `hosts.yml`, keyed by host
+//! - `parseGcloudAdc()`: gcloud application default credentials, keyed `gcloud`
+//! - `parseAzureTokenCache()`: Azure CLI MSAL token cache, keyed
+//!   `azure.<account>.<tenant>.<resource>`
+//!
+//! `importCli()` finds the CLI's file, parses it and saves the tokens.
+//!
+//! ## Example
+//!
+//! ```zig
+//! const report = try import.importNetrcFile(allocator, keyring.storage(), null, .{});
+//! std.log.info("imported {d} machines", .{report.copied});
+//!
+//! _ = try import.importCli(allocator, keyring.storage(), .gh, null, .{});
+//! ```
+
+const std = @import("std");
+const json = std.json;
+const mem = std.mem;
+const Allocator = mem.Allocator;
+
+const migration = @import("migration.zig");
+const session = @import("session.zig");
+
+const Token = session.Token;
+const SessionStorage = session.SessionStorage;
+
+pub const Report = migration.Report;
+
+/// One `machine` (or `default`) block of a netrc file
+pub const NetrcEntry = struct {
+    /// Host name, or null for the `default` entry
+    machine: ?[]const u8,
+    login: ?[]const u8 = null,
+    password: ?[]const u8 = null,
+    account: ?[]const u8 = null,
+};
+
+/// Parsed netrc file
+pub const Netrc = struct {
+    allocator: Allocator,
+    entries: []NetrcEntry,
+
+    /// Parse netrc `data`
+    ///
+    /// Accepts the format read by curl and ftp: whitespace-separated
+    /// `machine`, `default`, `login`, `password`, `account` and `macdef`
+    /// tokens, double-quoted values with backslash escapes and `#`
+    /// comments. Macro definitions are skipped. Returns
+    /// `error.InvalidParameter` for a keyword outside an entry or without
+    /// its value.
+    pub fn parse(allocator: Allocator, data: []const u8) !Netrc {
+        var entries: std.ArrayListUnmanaged(NetrcEntry) = .{};
+        errdefer {
+            for (entries.items) |entry| freeEntry(allocator, entry);
+            entries.deinit(allocator);
+        }
+
+        var lexer = Lexer{ .data = data };
+        var current: ?*NetrcEntry = null;
+        while (try lexer.next(allocator)) |word| {
+            defer allocator.free(word);
+
+            if (mem.eql(u8, word, "machine") or mem.eql(u8, word, "default")) {
+                const machine = if (word[0] == 'm')
+                    (try lexer.next(allocator)) orelse return error.InvalidParameter
+                else
+                    null;
+                errdefer if (machine) |m| allocator.free(m);
+                try entries.append(allocator, .{ .machine = machine });
+                current = &entries.items[entries.items.len - 1];
+            } else if (mem.eql(u8, word, "macdef")) {
+                const name = (try lexer.next(allocator)) orelse return error.InvalidParameter;
+                allocator.free(name);
+                lexer.skipMacro();
+            } else {
+                const entry = current orelse return error.InvalidParameter;
+                const field: *?[]const u8 = if (mem.eql(u8, word, "login"))
+                    &entry.login
+                else if (mem.eql(u8, word, "password"))
+                    &entry.password
+                else if (mem.eql(u8, word, "account"))
+                    &entry.account
+                else
+                    return error.InvalidParameter;
+
+                const value = (try lexer.next(allocator)) orelse return error.InvalidParameter;
+                if (field.*) |previous| {
+                    // A repeated keyword may overwrite a password
+                    std.crypto.secureZero(u8, @constCast(previous));
+                    allocator.free(previous);
+                }
+                field.* = value;
+            }
+        }
+
+        return .{ .allocator = allocator, .entries = try entries.toOwnedSlice(allocator) };
+    }
+
+    pub fn deinit(self: *Netrc) void {
+        for (self.entries) |entry| freeEntry(self.allocator, entry);
+        self.allocator.free(self.entries);
+    }
+
+    /// Entry for `machine`, falling back to the `default` entry
+    pub fn find(self: *const Netrc, machine: []const u8) ?*const NetrcEntry {
+        var fallback: ?*const NetrcEntry = null;
+        for (self.entries) |*entry| {
+            const name = entry.machine orelse {
+                if (fallback == null) fallback = entry;
+                continue;
+            };
+            if (std.ascii.eqlIgnoreCase(name, machine)) return entry;
+        }
+        return fallback;
+    }
+
+    fn freeEntry(allocator: Allocator, entry: NetrcEntry) void {
+        if (entry.machine) |m| allocator.free(m);
+        if (entry.login) |l| allocator.free(l);
+        if (entry.password) |p| {
+            std.crypto.secureZero(u8, @constCast(p));
+            allocator.free(p);
+        }
+        if (entry.account) |a| allocator.free(a);
+    }
+};
+
+/// Word splitter for netrc files
+const Lexer = struct {
+    data: []const u8,
+    pos: usize = 0,
+
+    /// Next word, unquoted; caller owns it
+    fn next(self: *Lexer, allocator: Allocator) !?[]u8 {
+        while (self.pos < self.data.len) {
+            const c = self.data[self.pos];
+            if (std.ascii.isWhitespace(c)) {
+                self.pos += 1;
+            } else if (c == '#') {
+                self.skipLine();
+            } else break;
+        }
+        if (self.pos >= self.data.len) return null;
+
+        var word: std.ArrayListUnmanaged(u8) = .{};
+        errdefer word.deinit(allocator);
+
+        if (self.data[self.pos] == '"') {
+            self.pos += 1;
+            while (true) {
+                if (self.pos >= self.data.len) return error.InvalidParameter;
+                var c = self.data[self.pos];
+                self.pos += 1;
+                if (c == '"') break;
+                if (c == '\\' and self.pos < self.data.len) {
+                    c = switch (self.data[self.pos]) {
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        else => |escaped| escaped,
+                    };
+                    self.pos += 1;
+                }
+                try word.append(allocator, c);
+            }
+        } else {
+            const start = self.pos;
+            while (self.pos < self.data.len and !std.ascii.isWhitespace(self.data[self.pos])) self.pos += 1;
+            try word.appendSlice(allocator, self.data[start..self.pos]);
+        }
+        return try word.toOwnedSlice(allocator);
+    }
+
+    fn skipLine(self: *Lexer) void {
+        while (self.pos < self.data.len and self.data[self.pos] != '\n') self.pos += 1;
+    }
+
+    /// Skip a macro body, which ends at the first empty line
+    fn skipMacro(self: *Lexer) void {
+        self.skipLine();
+        while (self.pos < self.data.len) {
+            self.pos += 1;
+            if (self.pos >= self.data.len or self.data[self.pos] == '\n') return;
+            self.skipLine();
+        }
+    }
+};
+
+pub const NetrcOptions = struct {
+    conflict: migration.ConflictPolicy = .skip,
+    /// Count what would be imported without writing to the storage
+    dry_run: bool = false,
+    /// Token type of the imported tokens
+    token_type: []const u8 = "Bearer",
+    /// Import only these machines (every entry when null)
+    machines: ?[]const []const u8 = null,
+};
+
+/// Save every netrc `machine` entry with a password in `to`
+///
+/// Entries are saved under their machine name. The `default` entry has no
+/// machine to key it by and is left out; entries without a password are
+/// counted as incomplete.
+pub fn importNetrc(allocator: Allocator, to: SessionStorage, data: []const u8, options: NetrcOptions) !Report {
+    var netrc = try Netrc.parse(allocator, data);
+    defer netrc.deinit();
+
+    var report: Report = .{};
+    for (netrc.entries) |entry| {
+        const machine = entry.machine orelse continue;
+        if (options.machines) |wanted| {
+            if (!containsIgnoreCase(wanted, machine)) continue;
+        }
+
+        const password = entry.password orelse {
+            report.incomplete += 1;
+            continue;
+        };
+        if (options.conflict == .skip and to.exists(machine)) {
+            report.skipped += 1;
+            continue;
+        }
+
+        report.copied += 1;
+        if (options.dry_run) continue;
+
+        var token = try Token.init(allocator, password, options.token_type);
+        defer token.deinit();
+        if (entry.login) |login| token.metadata = try allocator.dupe(u8, login);
+        try to.save(machine, token);
+    }
+    return report;
+}
+
+/// importNetrc() on the file at `path`, or the user's netrc when null
+///
+/// The default file is `$NETRC`, else `~/.netrc` (`%USERPROFILE%\_netrc`
+/// on Windows). A missing file imports nothing.
+pub fn importNetrcFile(allocator: Allocator, to: SessionStorage, path: ?[]const u8, options: NetrcOptions) !Report {
+    const file_path = if (path) |p| try allocator.dupe(u8, p) else (try defaultNetrcPath(allocator)) orelse return .{};
+    defer allocator.free(file_path);
+
+    const data = std.fs.cwd().readFileAlloc(allocator, file_path, 1024 * 1024) catch |err| switch (err) {
+        error.FileNotFound => return .{},
+        else => return err,
+    };
+    defer {
+        std.crypto.secureZero(u8, data);
+        allocator.free(data);
+    }
+    return importNetrc(allocator, to, data, options);
+}
+
+/// Path of the user's netrc file, or null without a home directory
+fn defaultNetrcPath(allocator: Allocator) !?[]u8 {
+    if (getEnvVar(allocator, "NETRC")) |path| return path;
+
+    const windows = @import("builtin").os.tag == .windows;
+    const home = getEnvVar(allocator, if (windows) "USERPROFILE" else "HOME") orelse return null;
+    defer allocator.free(home);
+    return try std.fs.path.join(allocator, &.{ home, if (windows) "_netrc" else ".netrc" });
+}
+
+/// A token read from another tool, with the key it is saved under
+pub const Credential = struct {
+    key: []const u8,
+    token: Token,
+    /// OAuth client the tool refreshes the token with, when the file names
+    /// one; not saved by saveCredentials()
+    client_id: ?[]const u8 = null,
+    client_secret: ?[]const u8 = null,
+
+    fn deinit(self: *Credential, allocator: Allocator) void {
+        allocator.free(self.key);
+        self.token.deinit();
+        if (self.client_id) |client_id| allocator.free(client_id);
+        if (self.client_secret) |secret| {
+            std.crypto.secureZero(u8, @constCast(secret));
+            allocator.free(secret);
+        }
+    }
+};
+
+/// Credentials parsed from one file
+pub const Credentials = struct {
+    allocator: Allocator,
+    items: []Credential,
+
+    pub fn deinit(self: *Credentials) void {
+        for (self.items) |*item| item.deinit(self.allocator);
+        self.allocator.free(self.items);
+    }
+};
+
+/// Collects credentials while a file is parsed
+const Builder = struct {
+    allocator: Allocator,
+    items: std.ArrayListUnmanaged(Credential) = .{},
+
+    fn deinit(self: *Builder) void {
+        for (self.items.items) |*item| item.deinit(self.allocator);
+        self.items.deinit(self.allocator);
+    }
+
+    /// Take ownership of `key` and `token`
+    fn add(self: *Builder, key: []const u8, token: Token) !void {
+        try self.addCredential(.{ .key = key, .token = token });
+    }
+
+    /// Take ownership of `credential`
+    fn addCredential(self: *Builder, credential: Credential) !void {
+        try self.items.append(self.allocator, credential);
+    }
+
+    /// Take ownership of `key` and `token`, keeping only the token that
+    /// expires last when `key` was added before
+    fn addLatest(self: *Builder, key: []const u8, token: Token) !void {
+        for (self.items.items) |*item| {
+            if (!mem.eql(u8, item.key, key)) continue;
+
+            var incoming = Credential{ .key = key, .token = token };
+            if ((token.expires_at orelse 0) > (item.token.expires_at orelse 0)) mem.swap(Credential, item, &incoming);
+            incoming.deinit(self.allocator);
+            return;
+        }
+        try self.add(key, token);
+    }
+
+    fn finish(self: *Builder) !Credentials {
+        return .{ .allocator = self.allocator, .items = try self.items.toOwnedSlice(self.allocator) };
+    }
+};
+
+/// Parse the GitHub CLI `hosts.yml`
+///
+/// Reads the `oauth_token` and `user` of each host. Hosts whose token gh
+/// keeps in the OS keyring have no `oauth_token` and are left out. Only
+/// the flat `key: value` layout gh writes is understood.
+pub fn parseGhHosts(allocator: Allocator, data: []const u8) !Credentials {
+    var builder = Builder{ .allocator = allocator };
+    errdefer builder.deinit();
+
+    const Host = struct { name: []const u8, indent: ?usize = null, token: ?[]const u8 = null, user: ?[]const u8 = null };
+    var host: ?Host = null;
+
+    var lines = mem.splitScalar(u8, data, '\n');
+    while (true) {
+        const raw = lines.next();
+        const line = if (raw) |r| mem.trimRight(u8, r, " \t\r") else "";
+        const content = mem.trimLeft(u8, line, " ");
+        if (raw != null and (content.len == 0 or content[0] == '#')) continue;
+        const indent = line.len - content.len;
+
+        // A new top-level key (or the end of the file) closes the current host
+        if (raw == null or indent == 0) {
+            if (host) |h| {
+                if (h.token) |oauth_token| {
+                    var token = try Token.init(allocator, oauth_token, "Bearer");
+                    errdefer token.deinit();
+                    if (h.user) |user| token.metadata = try allocator.dupe(u8, user);
+                    const key = try allocator.dupe(u8, h.name);
+                    errdefer allocator.free(key);
+                    try builder.add(key, token);
+                }
+            }
+            const name_line = if (raw != null) content else break;
+            if (!mem.endsWith(u8, name_line, ":")) return error.InvalidParameter;
+            host = .{ .name = unquote(mem.trimRight(u8, name_line[0 .. name_line.len - 1], " ")) };
+            continue;
+        }
+
+        const current = if (host) |*h| h else return error.InvalidParameter;
+        // Only the host's own fields, not nested blocks such as `users:`
+        const field_indent = current.indent orelse indent;
+        current.indent = field_indent;
+        if (indent != field_indent) continue;
+
+        const colon = mem.indexOfScalar(u8, content, ':') orelse return error.InvalidParameter;
+        const name = content[0..colon];
+        const value = unquote(mem.trim(u8, content[colon + 1 ..], " "));
+        if (value.len == 0) continue;
+        if (mem.eql(u8, name, "oauth_token")) current.token = value else if (mem.eql(u8, name, "user")) current.user = value;
+    }
+
+    return builder.finish();
+}
+
+/// Parse gcloud application default credentials
+///
+/// Accepts the `authorized_user` file written by `gcloud auth
+/// application-default login`. It holds no access token, so the token is
+/// saved already expired with its refresh token (and `client_id` in
+/// `Token.metadata`). Refreshing it at Google's token endpoint also needs
+/// the client secret, which is only returned in the credential's
+/// `client_id` and `client_secret`: configure the OAuthClient with them.
+/// A service account key returns `error.UnsupportedOperation`.
+pub fn parseGcloudAdc(allocator: Allocator, data: []const u8) !Credentials {
+    const parsed = json.parseFromSlice(json.Value, allocator, data, .{}) catch |err| switch (err) {
+        error.OutOfMemory => return err,
+        else => return error.JsonError,
+    };
+    defer parsed.deinit();
+    if (parsed.value != .object) return error.InvalidParameter;
+    const obj = parsed.value.object;
+
+    const kind = stringField(obj, "type") orelse return error.InvalidParameter;
+    if (!mem.eql(u8, kind, "authorized_user")) return error.UnsupportedOperation;
+    const refresh_token = stringField(obj, "refresh_token") orelse return error.InvalidParameter;
+
+    var builder = Builder{ .allocator = allocator };
+    errdefer builder.deinit();
+
+    {
+        var token = try Token.initFull(allocator, "", "Bearer", refresh_token, null, null, null);
+        errdefer token.deinit();
+        token.expires_at = 0;
+        const client_id = stringField(obj, "client_id");
+        if (client_id) |id| token.metadata = try allocator.dupe(u8, id);
+
+        const owned_id = if (client_id) |id| try allocator.dupe(u8, id) else null;
+        errdefer if (owned_id) |id| allocator.free(id);
+        const owned_secret = if (stringField(obj, "client_secret")) |secret| try allocator.dupe(u8, secret) else null;
+        errdefer if (owned_secret) |secret| allocator.free(secret);
+        const key = try allocator.dupe(u8, "gcloud");
+        errdefer allocator.free(key);
+
+        try builder.addCredential(.{ .key = key, .token = token, .client_id = owned_id, .client_secret = owned_secret });
+    }
+    return builder.finish();
+}
+
+/// Parse the Azure CLI MSAL token cache (`msal_token_cache.json`)
+///
+/// Each access token is paired with the refresh token of its account and
+/// client and saved as `azure.<account>.<tenant>.<resource>`, where the
+/// account is its `home_account_id` and the resource the host of its first
+/// scope. When several tokens map to one key, the one that expires last is
+/// kept. The encrypted cache the CLI keeps on Windows cannot be read.
+pub fn parseAzureTokenCache(allocator: Allocator, data: []const u8) !Credentials {
+    const parsed = json.parseFromSlice(json.Value, allocator, data, .{}) catch |err| switch (err) {
+        error.OutOfMemory => return err,
+        else => return error.JsonError,
+    };
+    defer parsed.deinit();
+    if (parsed.value != .object) return error.InvalidParameter;
+    const cache = parsed.value.object;
+
+    var builder = Builder{ .allocator = allocator };
+    errdefer builder.deinit();
+
+    const access_tokens = cache.get("AccessToken") orelse return builder.finish();
+    if (access_tokens != .object) return error.InvalidParameter;
+
+    var it = access_tokens.object.iterator();
+    while (it.next()) |kv| {
+        if (kv.value_ptr.* != .object) continue;
+        const entry = kv.value_ptr.object;
+        const secret = stringField(entry, "secret") orelse continue;
+        const account = stringField(entry, "home_account_id") orelse continue;
+        const realm = stringField(entry, "realm") orelse continue;
+        const target = stringField(entry, "target") orelse "";
+
+        var token = try Token.init(allocator, secret, "Bearer");
+        errdefer token.deinit();
+        if (target.len > 0) token.scope = try allocator.dupe(u8, target);
+        if (stringField(entry, "expires_on")) |expires_on| {
+            token.expires_at = std.fmt.parseInt(u64, expires_on, 10) catch null;
+        }
+        if (findAzureRefreshToken(cache, entry)) |refresh_token| {
+            token.refresh_token = try allocator.dupe(u8, refresh_token);
+        }
+
+        const key = try std.fmt.allocPrint(allocator, "azure.{s}.{s}.{s}", .{ account, realm, resourceHost(target) });
+        errdefer allocator.free(key);
+        try builder.addLatest(key, token);
+    }
+    return builder.finish();
+}
+
+/// Refresh token cached for the account and client of `access_token`
+fn findAzureRefreshToken(cache: json.ObjectMap, access_token: json.ObjectMap) ?[]const u8 {
+    const refresh_tokens = cache.get("RefreshToken") orelse return null;
+    if (refresh_tokens != .object) return null;
+
+    var it = refresh_tokens.object.iterator();
+    while (it.next()) |kv| {
+        if (kv.value_ptr.* != .object) continue;
+        const entry = kv.value_ptr.object;
+        if (!sameField(entry, access_token, "home_account_id")) continue;
+        if (!sameField(entry, access_token, "environment")) continue;
+        if (!sameField(entry, access_token, "client_id")) continue;
+        return stringField(entry, "secret");
+    }
+    return null;
+}
+
+/// Host of the first scope in `target` (`management.core.windows.net` for
+/// `https://management.core.windows.net//.default`)
+fn resourceHost(target: []const u8) []const u8 {
+    var scopes = mem.tokenizeScalar(u8, target, ' ');
+    var scope = scopes.next() orelse return "default";
+    if (mem.indexOf(u8, scope, "://")) |i| scope = scope[i + 3 ..];
+    const end = mem.indexOfScalar(u8, scope, '/') orelse scope.len;
+    return if (end == 0) "default" else scope[0..end];
+}
+
+fn stringField(obj: json.ObjectMap, name: []const u8) ?[]const u8 {
+    const value = obj.get(name) orelse return null;
+    return if (value == .string) value.string else null;
+}
+
+fn sameField(a: json.ObjectMap, b: json.ObjectMap, name: []const u8) bool {
+    const left = stringField(a, name) orelse return false;
+    const right = stringField(b, name) orelse return false;
+    return mem.eql(u8, left, right);
+}
+
+/// Strip one pair of matching YAML quotes
+fn unquote(value: []const u8) []const u8 {
+    if (value.len >= 2 and (value[0] == '"' or value[0] == '\'') and value[value.len - 1] == value[0]) {
+        return value[1 .. value.len - 1];
+    }
+    return value;
+}
+
+pub const SaveOptions = struct {
+    conflict: migration.ConflictPolicy = .skip,
+    /// Count what would be saved without writing to the storage
+    dry_run: bool = false,
+};
+
+/// Save parsed credentials in `to`
+pub fn saveCredentials(to: SessionStorage, credentials: Credentials, options: SaveOptions) !Report {
+    var report: Report = .{};
+    for (credentials.items) |item| {
+        if (options.conflict == .skip and to.exists(item.key)) {
+            report.skipped += 1;
+            continue;
+        }
+        report.copied += 1;
+        if (!options.dry_run) try to.save(item.key, item.token);
+    }
+    return report;
+}
+
+/// Command-line tools whose credentials can be imported
+pub const Cli = enum {
+    /// GitHub CLI
+    gh,
+    /// Google Cloud SDK
+    gcloud,
+    /// Azure CLI
+    azure,
+};
+
+/// Import the credentials of `cli` from `path`, or its default file when null
+///
+/// The default files are `$GH_CONFIG_DIR/hosts.yml` (else the gh config
+/// directory), `$GOOGLE_APPLICATION_CREDENTIALS` (else
+/// `application_default_credentials.json` in the gcloud config directory)
+/// and `msal_token_cache.json` in `$AZURE_CONFIG_DIR` (else `~/.azure`).
+/// A missing file imports nothing. Only tokens are saved; read gcloud's
+/// client secret with parseGcloudAdc().
+pub fn importCli(allocator: Allocator, to: SessionStorage, cli: Cli, path: ?[]const u8, options: SaveOptions) !Report {
+    const file_path = if (path) |p| try allocator.dupe(u8, p) else (try defaultCliPath(allocator, cli)) orelse return .{};
+    defer allocator.free(file_path);
+
+    const data = std.fs.cwd().readFileAlloc(allocator, file_path, 16 * 1024 * 1024) catch |err| switch (err) {
+        error.FileNotFound => return .{},
+        else => return err,
+    };
+    defer {
+        std.crypto.secureZero(u8, data);
+        allocator.free(data);
+    }
+
+    var credentials = switch (cli) {
+        .gh => try parseGhHosts(allocator, data),
+        .gcloud => try parseGcloudAdc(allocator, data),
+        .azure => try parseAzureTokenCache(allocator, data),
+    };
+    defer credentials.deinit();
+    return saveCredentials(to, credentials, options);
+}
+
+/// Default credentials file of `cli`, or null without a home directory
+fn defaultCliPath(allocator: Allocator, cli: Cli) !?[]u8 {
+    const windows = @import("builtin").os.tag == .windows;
+    const path = std.fs.path;
+
+    switch (cli) {
+        .gh => {
+            if (getEnvVar(allocator, "GH_CONFIG_DIR")) |dir| {
+                defer allocator.free(dir);
+                return try path.join(allocator, &.{ dir, "hosts.yml" });
+            }
+            if (windows) {
+                const app_data = getEnvVar(allocator, "APPDATA") orelse return null;
+                defer allocator.free(app_data);
+                return try path.join(allocator, &.{ app_data, "GitHub CLI", "hosts.yml" });
+            }
+            if (getEnvVar(allocator, "XDG_CONFIG_HOME")) |config| {
+                defer allocator.free(config);
+                return try path.join(allocator, &.{ config, "gh", "hosts.yml" });
+            }
+            const home = getEnvVar(allocator, "HOME") orelse return null;
+            defer allocator.free(home);
+            return try path.join(allocator, &.{ home, ".config", "gh", "hosts.yml" });
+        },
+        .gcloud => {
+            if (getEnvVar(allocator, "GOOGLE_APPLICATION_CREDENTIALS")) |file| return file;
+            const file_name = "application_default_credentials.json";
+            if (getEnvVar(allocator, "CLOUDSDK_CONFIG")) |dir| {
+                defer allocator.free(dir);
+                return try path.join(allocator, &.{ dir, file_name });
+            }
+            if (windows) {
+                const app_data = getEnvVar(allocator, "APPDATA") orelse return null;
+                defer allocator.free(app_data);
+                return try path.join(allocator, &.{ app_data, "gcloud", file_name });
+            }
+            const home = getEnvVar(allocator, "HOME") orelse return null;
+            defer allocator.free(home);
+            return try path.join(allocator, &.{ home, ".config", "gcloud", file_name });
+        },
+        .azure => {
+            const file_name = "msal_token_cache.json";
+            if (getEnvVar(allocator, "AZURE_CONFIG_DIR")) |dir| {
+                defer allocator.free(dir);
+                return try path.join(allocator, &.{ dir, file_name });
+            }
+            const home = getEnvVar(allocator, if (windows) "USERPROFILE" else "HOME") orelse return null;
+            defer allocator.free(home);
+            return try path.join(allocator, &.{ home, ".azure", file_name });
+        },
+    }
+}
+
+fn getEnvVar(allocator: Allocator, name: []const u8) ?[]u8 {
+    return std.process.getEnvVarOwned(allocator, name) catch null;
+}
+
+fn containsIgnoreCase(haystack: []const []const u8, needle: []const u8) bool {
+    for (haystack) |item| {
+        if (std.ascii.eqlIgnoreCase(item, needle)) return true;
+    }
+    return false;
+}
+
+test "Netrc.parse: reads entries, quoted values, comments and macros" {
+    const allocator = std.testing.allocator;
+
+    const data =
+        \\# work machines
+        \\machine github.com login octocat password ghp_secret
+        \\machine "gitlab.example.com"
+        \\  login "ada lovelace" password "p\"w d"
+        \\macdef init
+        \\cd /pub
+        \\machine ignored.example.com
+        \\
+        \\machine api.example.com account team
+        \\default login anonymous password guest
+        \\
+    ;
+
+    var netrc = try Netrc.parse(allocator, data);
+    defer netrc.deinit();
+
+    try std.testing.expectEqual(@as(usize, 4), netrc.entries.len);
+    try std.testing.expectEqualStrings("ghp_secret", netrc.find("GitHub.com").?.password.?);
+
+    const gitlab = netrc.find("gitlab.example.com").?;
+    try std.testing.expectEqualStrings("ada lovelace", gitlab.login.?);
+    try std.testing.expectEqualStrings("p\"w d", gitlab.password.?);
+
+    try std.testing.expect(netrc.find("api.example.com").?.password == null);
+    // Unknown machines fall back to the default entry
+    try std.testing.expectEqualStrings("anonymous", netrc.find("ftp.example.com").?.login.?);
+    // The macro body is not parsed as entries
+    try std.testing.expect(netrc.find("ignored.example.com").?.machine == null);
+
+    try std.testing.expectError(error.InvalidParameter, Netrc.parse(allocator, "login orphan"));
+    try std.testing.expectError(error.InvalidParameter, Netrc.parse(allocator, "machine host password"));
+    try std.testing.expectError(error.InvalidParameter, Netrc.parse(allocator, "machine host password \"open"));
+}
+
+test "importNetrc saves machine entries as tokens" {
+    const allocator = std.testing.allocator;
+
+    var memory = session.MemoryStorage.init(allocator);
+    defer memory.deinit();
+    const store = memory.storage();
+
+    var existing = try Token.init(allocator, "kept", "Bearer");
+    defer existing.deinit();
+    try store.save("gitlab.com", existing);
+
+    const data = "machine github.com login octocat password ghp_1\n" ++
+        "machine gitlab.com password glpat\n" ++
+        "machine nopass.example.com login x\n" ++
+        "default password guest\n";
+
+    const dry = try importNetrc(allocator, store, data, .{ .dry_run = true });
+    try std.testing.expectEqual(Report{ .copied = 1, .skipped = 1, .incomplete = 1 }, dry);
+    try std.testing.expect(!store.exists("github.com"));
+
+    const report = try importNetrc(allocator, store, data, .{});
+    try std.testing.expectEqual(Report{ .copied = 1, .skipped = 1, .incomplete = 1 }, report);
+
+    var github = (try store.load(allocator, "github.com")).?;
+    defer github.deinit();
+    try std.testing.expectEqualStrings("ghp_1", github.access_token);
+    try std.testing.expectEqualStrings("octocat", github.metadata.?);
+
+    var gitlab = (try store.load(allocator, "gitlab.com")).?;
+    defer gitlab.deinit();
+    try std.testing.expectEqualStrings("kept", gitlab.access_token);
+
+    const only = try importNetrc(allocator, store, data, .{ .conflict = .overwrite, .machines = &.{"GITLAB.com"} });
+    try std.testing.expectEqual(Report{ .copied = 1 }, only);
+}
+
+test "parseGhHosts reads each host's token and user" {
+    const allocator = std.testing.allocator;
+
+    const data =
+        \\github.com:
+        \\    users:
+        \\        octocat:
+        \\            oauth_token: gho_nested
+        \\    oauth_token: gho_main
+        \\    git_protocol: https
+        \\    user: octocat
+        \\# token kept in the keyring
+        \\ghe.example.com:
+        \\    user: "ada"
+        \\"github.example.org":
+        \\    oauth_token: 'gho_enterprise'
+        \\
+    ;
+
+    var credentials = try parseGhHosts(allocator, data);
+    defer credentials.deinit();
+    try std.testing.expectEqual(@as(usize, 2), credentials.items.len);
+
+    const github = credentials.items[0];
+    try std.testing.expectEqualStrings("github.com", github.key);
+    try std.testing.expectEqualStrings("gho_main", github.token.access_token);
+    try std.testing.expectEqualStrings("octocat", github.token.metadata.?);
+
+    const enterprise = credentials.items[1];
+    try std.testing.expectEqualStrings("github.example.org", enterprise.key);
+    try std.testing.expectEqualStrings("gho_enterprise", enterprise.token.access_token);
+    try std.testing.expect(enterprise.token.metadata == null);
+
+    try std.testing.expectError(error.InvalidParameter, parseGhHosts(allocator, "    oauth_token: orphan\n"));
+}
+
+test "parseGcloudAdc keeps the refresh token of an authorized user" {
+    const allocator = std.testing.allocator;
+
+    var credentials = try parseGcloudAdc(allocator,
+        \\{"client_id":"123.apps.googleusercontent.com","client_secret":"s","refresh_token":"1//0g","type":"authorized_user"}
+    );
+    defer credentials.deinit();
+
+    const token = credentials.items[0].token;
+    try std.testing.expectEqualStrings("gcloud", credentials.items[0].key);
+    try std.testing.expectEqualStrings("1//0g", token.refresh_token.?);
+    try std.testing.expectEqualStrings("123.apps.googleusercontent.com", token.metadata.?);
+    try std.testing.expect(token.isExpired());
+    // Google refreshes authorized users only with the client's secret
+    try std.testing.expectEqualStrings("123.apps.googleusercontent.com", credentials.items[0].client_id.?);
+    try std.testing.expectEqualStrings("s", credentials.items[0].client_secret.?);
+
+    try std.testing.expectError(
+        error.UnsupportedOperation,
+        parseGcloudAdc(allocator, "{\"type\":\"service_account\",\"private_key\":\"...\"}"),
+    );
+}
+
+test "parseAzureTokenCache pairs access tokens with their refresh tokens" {
+    const allocator = std.testing.allocator;
+
+    const data =
+        \\{
+        \\  "AccessToken": {
+        \\    "uid.utid-login.microsoftonline.com-accesstoken-04b07795-utid-https://management.core.windows.net//user_impersonation": {
+        \\      "credential_type": "AccessToken", "secret": "eyJ.at", "home_account_id": "uid.utid",
+        \\      "environment": "login.microsoftonline.com", "client_id": "04b07795", "realm": "utid",
+        \\      "target": "https://management.core.windows.net//user_impersonation https://management.core.windows.net//.default",
+        \\      "cached_at": "1700000000", "expires_on": "1700003600"
+        \\    },
+        \\    "uid.utid-login.microsoftonline.com-accesstoken-04b07795-utid-https://management.core.windows.net//.default": {
+        \\      "credential_type": "AccessToken", "secret": "eyJ.older", "home_account_id": "uid.utid",
+        \\      "environment": "login.microsoftonline.com", "client_id": "04b07795", "realm": "utid",
+        \\      "target": "https://management.core.windows.net//.default", "expires_on": "1700001800"
+        \\    },
+        \\    "guest.home-login.microsoftonline.com-accesstoken-04b07795-utid-https://management.core.windows.net//.default": {
+        \\      "credential_type": "AccessToken", "secret": "eyJ.guest", "home_account_id": "guest.home",
+        \\      "environment": "login.microsoftonline.com", "client_id": "04b07795", "realm": "utid",
+        \\      "target": "https://management.core.windows.net//.default", "expires_on": "1700003600"
+        \\    }
+        \\  },
+        \\  "RefreshToken": {
+        \\    "other": {"credential_type": "RefreshToken", "secret": "wrong", "home_account_id": "someone.else",
+        \\      "environment": "login.microsoftonline.com", "client_id": "04b07795"},
+        \\    "uid.utid-login.microsoftonline.com-refreshtoken-04b07795--": {"credential_type": "RefreshToken",
+        \\      "secret": "0.rt", "home_account_id": "uid.utid", "environment": "login.microsoftonline.com", "client_id": "04b07795"}
+        \\  }
+        \\}
+    ;
+
+    var credentials = try parseAzureTokenCache(allocator, data);
+    defer credentials.deinit();
+    // Accounts signed in to one tenant stay apart; the stale duplicate is dropped
+    try std.testing.expectEqual(@as(usize, 2), credentials.items.len);
+    try std.testing.expectEqualStrings("azure.guest.home.utid.management.core.windows.net", credentials.items[1].key);
+    try std.testing.expect(credentials.items[1].token.refresh_token == null);
+
+    const item = credentials.items[0];
+    try std.testing.expectEqualStrings("azure.uid.utid.utid.management.core.windows.net", item.key);
+    try std.testing.expectEqualStrings("eyJ.at", item.token.access_token);
+    try std.testing.expectEqualStrings("0.rt", item.token.refresh_token.?);
+    try std.testing.expectEqual(@as(?u64, 1700003600), item.token.expires_at);
+
+    var memory = session.MemoryStorage.init(allocator);
+    defer memory.deinit();
+    try std.testing.expectEqual(Report{ .copied = 2 }, try saveCredentials(memory.storage(), credentials, .{}));
+    try std.testing.expectEqual(Report{ .skipped = 2 }, try saveCredentials(memory.storage(), credentials, .{}));
+    try std.testing.expect(memory.storage().exists("azure.uid.utid.utid.management.core.windows.net"));
+}
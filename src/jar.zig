@@ -0,0 +1,226 @@
+//! JWT-secured authorization requests (JAR, RFC 9101)
+//!
+//! Instead of sending the authorization parameters as plain query
+//! parameters, the client signs them into a `request` object with its own
+//! key, so the provider can tell they were not tampered with in the
+//! browser. The URL then carries only `client_id` and `request` (or, with
+//! PAR, the `request_uri` the provider issued for the pushed object).
+//! `OAuthClient` does this for every authorization request when
+//! `OAuthConfig.request_signing` is set.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var config = OAuthConfig.custom(...);
+//! config.request_signing = .{ .key = .{ .es256 = key_pair }, .kid = "req-1" };
+//!
+//! var flow = try client.startAuthorization(redirect_uri);
+//! defer flow.deinit();
+//! // flow.url is `<authorization_endpoint>?client_id=...&request=eyJ...`
+//! ```
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+
+const callback = @import("callback.zig");
+const clock = @import("clock.zig");
+const jwt = @import("jwt.zig");
+
+const QueryParam = callback.AuthorizationRequest.QueryParam;
+
+/// `typ` header of request objects (RFC 9101 Section 10.8)
+pub const token_type = "oauth-authz-req+jwt";
+
+/// How request objects are signed
+pub const RequestSigning = struct {
+    /// The client's private key, as for `private_key_jwt`
+    key: jwt.SigningKey,
+    /// ID of the public key registered with the server, sent as `kid`
+    kid: ?[]const u8 = null,
+    /// Seconds until the request object expires
+    lifetime: u64 = 300,
+};
+
+/// Sign `params` into a request object from `client_id` for `audience`
+///
+/// `audience` is the provider's issuer identifier. Parameters become string
+/// claims, except the JSON-valued ones (`authorization_details`, `claims`)
+/// and `max_age`, which keep their JSON type. Parameters that repeat, like
+/// `resource`, become arrays. Caller owns the returned token.
+pub fn signRequest(
+    allocator: Allocator,
+    signing: RequestSigning,
+    client_id: []const u8,
+    audience: []const u8,
+    params: []const QueryParam,
+) ![]u8 {
+    var jti_bytes: [16]u8 = undefined;
+    std.crypto.random.bytes(&jti_bytes);
+    var jti: [std.base64.url_safe_no_pad.Encoder.calcSize(16)]u8 = undefined;
+    _ = std.base64.url_safe_no_pad.Encoder.encode(&jti, &jti_bytes);
+
+    var out: std.Io.Writer.Allocating = .init(allocator);
+    defer out.deinit();
+    writeClaims(allocator, &out.writer, client_id, audience, signing.lifetime, &jti, params) catch |err| switch (err) {
+        error.WriteFailed => return error.OutOfMemory,
+        else => |e| return e,
+    };
+
+    return signing.key.signWithType(allocator, out.written(), token_type, signing.kid);
+}
+
+/// Parameters whose value is a JSON document rather than a string
+const json_params = [_][]const u8{ "authorization_details", "claims" };
+
+/// Parameters whose value is a number (OpenID Connect Core Section 6.1)
+const number_params = [_][]const u8{"max_age"};
+
+fn writeClaims(
+    allocator: Allocator,
+    writer: *std.Io.Writer,
+    client_id: []const u8,
+    audience: []const u8,
+    lifetime: u64,
+    jti: []const u8,
+    params: []const QueryParam,
+) !void {
+    const now = clock.now();
+
+    var jw: json.Stringify = .{ .writer = writer };
+    try jw.beginObject();
+    try jw.objectField("iss");
+    try jw.write(client_id);
+    try jw.objectField("aud");
+    try jw.write(audience);
+    try jw.objectField("iat");
+    try jw.write(now);
+    try jw.objectField("nbf");
+    try jw.write(now);
+    try jw.objectField("exp");
+    try jw.write(now + lifetime);
+    try jw.objectField("jti");
+    try jw.write(jti);
+
+    for (params, 0..) |param, i| {
+        // Repeated parameters are written together at their first occurrence
+        if (firstIndex(params, param.name) != i) continue;
+
+        try jw.objectField(param.name);
+        if (countParam(params, param.name) == 1) {
+            try writeValue(allocator, &jw, param);
+            continue;
+        }
+        try jw.beginArray();
+        for (params[i..]) |other| {
+            if (std.mem.eql(u8, other.name, param.name)) try writeValue(allocator, &jw, other);
+        }
+        try jw.endArray();
+    }
+
+    try jw.endObject();
+}
+
+/// Write `param.value` with the JSON type its parameter has
+///
+/// Malformed JSON or numeric values yield `error.InvalidParameter`.
+fn writeValue(allocator: Allocator, jw: *json.Stringify, param: QueryParam) !void {
+    if (isOneOf(&json_params, param.name)) {
+        if (!try json.validate(allocator, param.value)) return error.InvalidParameter;
+        return jw.print("{s}", .{param.value});
+    }
+    if (isOneOf(&number_params, param.name)) {
+        const number = std.fmt.parseInt(u64, param.value, 10) catch return error.InvalidParameter;
+        return jw.write(number);
+    }
+    return jw.write(param.value);
+}
+
+fn isOneOf(names: []const []const u8, name: []const u8) bool {
+    for (names) |candidate| {
+        if (std.mem.eql(u8, candidate, name)) return true;
+    }
+    return false;
+}
+
+fn firstIndex(params: []const QueryParam, name: []const u8) usize {
+    for (params, 0..) |param, i| {
+        if (std.mem.eql(u8, param.name, name)) return i;
+    }
+    unreachable;
+}
+
+fn countParam(params: []const QueryParam, name: []const u8) usize {
+    var count: usize = 0;
+    for (params) |param| {
+        if (std.mem.eql(u8, param.name, name)) count += 1;
+    }
+    return count;
+}
+
+test "signRequest signs the parameters as claims" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    const key_pair = std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair.generate();
+    const params = [_]QueryParam{
+        .{ .name = "response_type", .value = "code" },
+        .{ .name = "resource", .value = "https://a.example.com" },
+        .{ .name = "state", .value = "xyz" },
+        .{ .name = "resource", .value = "https://b.example.com" },
+    };
+
+    const request = try signRequest(allocator, .{ .key = .{ .es256 = key_pair }, .kid = "req-1" }, "client", "https://as.example.com", &params);
+    defer allocator.free(request);
+
+    var header = try jwt.decodeHeader(allocator, request);
+    defer header.deinit();
+    try std.testing.expectEqualStrings(token_type, header.typ.?);
+    try std.testing.expectEqualStrings("req-1", header.kid.?);
+
+    const parts = try jwt.split(request);
+    const payload = try jwt.decodeSegment(allocator, parts.payload);
+    defer allocator.free(payload);
+
+    const parsed = try json.parseFromSlice(json.Value, allocator, payload, .{});
+    defer parsed.deinit();
+    const claims = parsed.value.object;
+    try std.testing.expectEqualStrings("client", claims.get("iss").?.string);
+    try std.testing.expectEqualStrings("https://as.example.com", claims.get("aud").?.string);
+    try std.testing.expectEqual(@as(i64, 1_700_000_300), claims.get("exp").?.integer);
+    try std.testing.expectEqualStrings("xyz", claims.get("state").?.string);
+
+    const resources = claims.get("resource").?.array.items;
+    try std.testing.expectEqual(@as(usize, 2), resources.len);
+    try std.testing.expectEqualStrings("https://b.example.com", resources[1].string);
+}
+
+test "signRequest keeps JSON and numeric parameters typed" {
+    const allocator = std.testing.allocator;
+
+    const key_pair = std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair.generate();
+    const signing = RequestSigning{ .key = .{ .es256 = key_pair } };
+    const params = [_]QueryParam{
+        .{ .name = "authorization_details", .value = "[{\"type\":\"payment_initiation\"}]" },
+        .{ .name = "max_age", .value = "600" },
+    };
+
+    const request = try signRequest(allocator, signing, "client", "https://as.example.com", &params);
+    defer allocator.free(request);
+
+    const parts = try jwt.split(request);
+    const payload = try jwt.decodeSegment(allocator, parts.payload);
+    defer allocator.free(payload);
+
+    const parsed = try json.parseFromSlice(json.Value, allocator, payload, .{});
+    defer parsed.deinit();
+    const claims = parsed.value.object;
+    const details = claims.get("authorization_details").?.array.items;
+    try std.testing.expectEqualStrings("payment_initiation", details[0].object.get("type").?.string);
+    try std.testing.expectEqual(@as(i64, 600), claims.get("max_age").?.integer);
+
+    const bad_age = [_]QueryParam{.{ .name = "max_age", .value = "soon" }};
+    try std.testing.expectError(error.InvalidParameter, signRequest(allocator, signing, "client", "https://as.example.com", &bad_age));
+}
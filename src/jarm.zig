@@ -0,0 +1,199 @@
+//! JWT-secured authorization responses (JARM)
+//!
+//! With `response_mode=jwt` the provider does not put `code` and `state`
+//! in the redirect directly; it signs them into a single `response`
+//! parameter. The response only counts once its signature verifies
+//! against the provider's keys and its claims say it was issued by the
+//! expected provider, for this client, recently. `OAuthClient` verifies
+//! the signature and then calls `unwrap()` for the claim checks.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var config = OAuthConfig.google("client-id", "openid");
+//! config.response_mode = .query_jwt;
+//!
+//! var result = try server.waitForCallback(120);
+//! defer result.deinit();
+//! try client.unwrapJarmResponse(&result);
+//! // result.code and result.state now hold the verified values
+//! ```
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+
+const callback = @import("callback.zig");
+const clock = @import("clock.zig");
+const jwt = @import("jwt.zig");
+
+/// How the provider returns the authorization response
+pub const ResponseMode = enum {
+    /// Plain `code` and `state` query parameters (the OAuth default)
+    query,
+    /// A signed `response` parameter, in the mode's default location
+    jwt,
+    /// A signed `response` query parameter
+    query_jwt,
+    /// Parameters posted to the redirect URI as an HTML form
+    form_post,
+    /// A signed `response` parameter posted as an HTML form
+    form_post_jwt,
+
+    /// Value of the `response_mode` request parameter
+    pub fn toString(self: ResponseMode) []const u8 {
+        return switch (self) {
+            .query => "query",
+            .jwt => "jwt",
+            .query_jwt => "query.jwt",
+            .form_post => "form_post",
+            .form_post_jwt => "form_post.jwt",
+        };
+    }
+
+    /// Whether the provider signs the response
+    pub fn isJwt(self: ResponseMode) bool {
+        return switch (self) {
+            .query, .form_post => false,
+            .jwt, .query_jwt, .form_post_jwt => true,
+        };
+    }
+};
+
+/// What a valid authorization response must match
+pub const Validation = struct {
+    /// Expected `iss` (the provider's issuer identifier)
+    issuer: []const u8,
+    /// Client ID that must appear in `aud`
+    client_id: []const u8,
+    /// Tolerated clock skew for `exp`, in seconds
+    leeway: u64 = 60,
+};
+
+/// Check the claims of the already verified `raw` response and move its
+/// parameters into `result`
+///
+/// Replaces `code`, `state` and the error fields with the signed values
+/// and clears `result.response`. Returns `error.InvalidAuthorizationResponse`
+/// if the response is malformed, was issued by another provider or for
+/// another client, or has expired.
+pub fn unwrap(allocator: Allocator, raw: []const u8, validation: Validation, result: *callback.CallbackResult) !void {
+    const parts = jwt.split(raw) catch return error.InvalidAuthorizationResponse;
+    const payload = jwt.decodeSegment(allocator, parts.payload) catch |err| switch (err) {
+        error.OutOfMemory => return err,
+        else => return error.InvalidAuthorizationResponse,
+    };
+    defer allocator.free(payload);
+
+    const parsed = json.parseFromSlice(json.Value, allocator, payload, .{}) catch |err| switch (err) {
+        error.OutOfMemory => return err,
+        else => return error.InvalidAuthorizationResponse,
+    };
+    defer parsed.deinit();
+
+    if (parsed.value != .object) return error.InvalidAuthorizationResponse;
+    const claims = parsed.value.object;
+
+    const iss = stringClaim(claims, "iss") orelse return error.InvalidAuthorizationResponse;
+    if (!std.mem.eql(u8, iss, validation.issuer)) return error.InvalidAuthorizationResponse;
+    if (!hasAudience(claims.get("aud") orelse return error.InvalidAuthorizationResponse, validation.client_id)) {
+        return error.InvalidAuthorizationResponse;
+    }
+
+    const exp = claims.get("exp") orelse return error.InvalidAuthorizationResponse;
+    if (exp != .integer or exp.integer < 0) return error.InvalidAuthorizationResponse;
+    if (clock.now() > @as(u64, @intCast(exp.integer)) +| validation.leeway) return error.InvalidAuthorizationResponse;
+
+    const code = stringClaim(claims, "code");
+    const error_code = stringClaim(claims, "error");
+    if (code == null and error_code == null) return error.InvalidAuthorizationResponse;
+
+    try replace(allocator, &result.code, code);
+    try replace(allocator, &result.state, stringClaim(claims, "state"));
+    try replace(allocator, &result.error_code, error_code);
+    try replace(allocator, &result.error_description, stringClaim(claims, "error_description"));
+    try replace(allocator, &result.error_uri, stringClaim(claims, "error_uri"));
+    try replace(allocator, &result.response, null);
+}
+
+fn replace(allocator: Allocator, field: *?[]const u8, value: ?[]const u8) !void {
+    const copy = if (value) |v| try allocator.dupe(u8, v) else null;
+    if (field.*) |old| allocator.free(old);
+    field.* = copy;
+}
+
+fn stringClaim(claims: json.ObjectMap, name: []const u8) ?[]const u8 {
+    const value = claims.get(name) orelse return null;
+    return if (value == .string) value.string else null;
+}
+
+/// `aud` is a single string or an array of strings
+fn hasAudience(aud: json.Value, client_id: []const u8) bool {
+    switch (aud) {
+        .string => |single| return std.mem.eql(u8, single, client_id),
+        .array => |array| {
+            for (array.items) |item| {
+                if (item == .string and std.mem.eql(u8, item.string, client_id)) return true;
+            }
+            return false;
+        },
+        else => return false,
+    }
+}
+
+fn testResponse(allocator: Allocator, claims_json: []const u8) ![]u8 {
+    const header = try jwt.encodeSegment(allocator, "{\"alg\":\"ES256\"}");
+    defer allocator.free(header);
+    const payload = try jwt.encodeSegment(allocator, claims_json);
+    defer allocator.free(payload);
+    return std.fmt.allocPrint(allocator, "{s}.{s}.c2ln", .{ header, payload });
+}
+
+test "unwrap moves the signed parameters into the callback result" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    const raw = try testResponse(allocator,
+        \\{"iss":"https://as.example.com","aud":"client","exp":1700000600,"code":"abc","state":"xyz"}
+    );
+    defer allocator.free(raw);
+
+    var result = callback.CallbackResult.init(allocator);
+    defer result.deinit();
+    result.response = try allocator.dupe(u8, raw);
+
+    try unwrap(allocator, raw, .{ .issuer = "https://as.example.com", .client_id = "client" }, &result);
+    try std.testing.expectEqualStrings("abc", result.code.?);
+    try std.testing.expectEqualStrings("xyz", result.state.?);
+    try std.testing.expect(result.response == null);
+}
+
+test "unwrap rejects responses for another issuer, client or time" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    const validation = Validation{ .issuer = "https://as.example.com", .client_id = "client" };
+    const cases = [_][]const u8{
+        \\{"iss":"https://evil.example.com","aud":"client","exp":1700000600,"code":"abc"}
+        ,
+        \\{"iss":"https://as.example.com","aud":["other"],"exp":1700000600,"code":"abc"}
+        ,
+        \\{"iss":"https://as.example.com","aud":"client","exp":1699999000,"code":"abc"}
+        ,
+        \\{"iss":"https://as.example.com","aud":"client","exp":1700000600}
+        ,
+    };
+
+    for (cases) |claims| {
+        const raw = try testResponse(allocator, claims);
+        defer allocator.free(raw);
+
+        var result = callback.CallbackResult.init(allocator);
+        defer result.deinit();
+        try std.testing.expectError(error.InvalidAuthorizationResponse, unwrap(allocator, raw, validation, &result));
+    }
+}
@@ -0,0 +1,548 @@
+//! JSON Web Key Sets (RFC 7517) for verifying signed tokens
+//!
+//! Parses a provider's `jwks_uri` document into the signing keys schlussel
+//! can verify with: RSA (`RS*`, `PS*`), P-256 and P-384 (`ES256`, `ES384`)
+//! and Ed25519 (`EdDSA`). Keys of other types and encryption keys
+//! (`use: enc`) are skipped rather than failing the whole set, since
+//! providers routinely publish keys for other purposes alongside.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var keys = try jwks.fetch(allocator, transport.defaultTransport(), config.jwks_uri.?);
+//! defer keys.deinit();
+//!
+//! try keys.verify(allocator, id_token, .{}, null);
+//! ```
+//!
+//! `JwksCache` keeps a downloaded set for reuse across tokens and picks up
+//! rolled-over keys when a token names a key ID it has not seen.
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+
+const clock = @import("clock.zig");
+const discovery = @import("discovery.zig");
+const jwt = @import("jwt.zig");
+const transport = @import("transport.zig");
+
+const HttpTransport = transport.HttpTransport;
+const rsa = std.crypto.Certificate.rsa;
+const EcdsaP256 = std.crypto.sign.ecdsa.EcdsaP256Sha256;
+const EcdsaP384 = std.crypto.sign.ecdsa.EcdsaP384Sha384;
+const Ed25519 = std.crypto.sign.Ed25519;
+const sha2 = std.crypto.hash.sha2;
+const hmac = std.crypto.auth.hmac.sha2;
+const base64 = std.base64.url_safe_no_pad;
+
+/// Largest RSA modulus accepted, in bytes (4096 bits)
+const max_rsa_len = 512;
+
+/// A public signing key
+pub const Jwk = struct {
+    /// Key ID (`kid`)
+    kid: ?[]const u8 = null,
+    /// Algorithm the key is restricted to (`alg`), if any
+    alg: ?jwt.Algorithm = null,
+    key: Key,
+
+    pub const Key = union(enum) {
+        /// Big-endian modulus and public exponent
+        rsa: struct { n: []const u8, e: []const u8 },
+        /// Uncompressed SEC1 point
+        p256: [65]u8,
+        /// Uncompressed SEC1 point
+        p384: [97]u8,
+        ed25519: [32]u8,
+    };
+
+    fn deinit(self: *Jwk, allocator: Allocator) void {
+        if (self.kid) |kid| allocator.free(kid);
+        switch (self.key) {
+            .rsa => |key| {
+                allocator.free(key.n);
+                allocator.free(key.e);
+            },
+            else => {},
+        }
+    }
+
+    /// Whether this key can verify `alg` signatures
+    pub fn supports(self: *const Jwk, alg: jwt.Algorithm) bool {
+        if (self.alg) |restricted| {
+            if (restricted != alg) return false;
+        }
+        return switch (alg) {
+            .RS256, .RS384, .RS512, .PS256, .PS384, .PS512 => self.key == .rsa,
+            .ES256 => self.key == .p256,
+            .ES384 => self.key == .p384,
+            .EdDSA => self.key == .ed25519,
+            else => false,
+        };
+    }
+
+    /// Verify `signature` over `message`
+    ///
+    /// Returns `error.InvalidSignature` if it does not verify or the key
+    /// does not support `alg`.
+    pub fn verify(self: *const Jwk, alg: jwt.Algorithm, message: []const u8, signature: []const u8) !void {
+        if (!self.supports(alg)) return error.InvalidSignature;
+
+        switch (self.key) {
+            .rsa => |key| return verifyRsa(alg, key.n, key.e, message, signature),
+            .p256 => |point| {
+                if (signature.len != EcdsaP256.Signature.encoded_length) return error.InvalidSignature;
+                const public_key = EcdsaP256.PublicKey.fromSec1(&point) catch return error.InvalidSignature;
+                const sig = EcdsaP256.Signature.fromBytes(signature[0..EcdsaP256.Signature.encoded_length].*);
+                sig.verify(message, public_key) catch return error.InvalidSignature;
+            },
+            .p384 => |point| {
+                if (signature.len != EcdsaP384.Signature.encoded_length) return error.InvalidSignature;
+                const public_key = EcdsaP384.PublicKey.fromSec1(&point) catch return error.InvalidSignature;
+                const sig = EcdsaP384.Signature.fromBytes(signature[0..EcdsaP384.Signature.encoded_length].*);
+                sig.verify(message, public_key) catch return error.InvalidSignature;
+            },
+            .ed25519 => |bytes| {
+                if (signature.len != Ed25519.Signature.encoded_length) return error.InvalidSignature;
+                const public_key = Ed25519.PublicKey.fromBytes(bytes) catch return error.InvalidSignature;
+                const sig = Ed25519.Signature.fromBytes(signature[0..Ed25519.Signature.encoded_length].*);
+                sig.verify(message, public_key) catch return error.InvalidSignature;
+            },
+        }
+    }
+};
+
+/// A parsed key set
+pub const JwkSet = struct {
+    allocator: Allocator,
+    keys: []Jwk,
+
+    /// Parse a JWKS document (`{"keys":[...]}`)
+    pub fn parse(allocator: Allocator, body: []const u8) !JwkSet {
+        const parsed = json.parseFromSlice(json.Value, allocator, body, .{}) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.JsonError,
+        };
+        defer parsed.deinit();
+
+        if (parsed.value != .object) return error.JsonError;
+        const entries = parsed.value.object.get("keys") orelse return error.JsonError;
+        if (entries != .array) return error.JsonError;
+
+        var keys: std.ArrayListUnmanaged(Jwk) = .{};
+        errdefer {
+            for (keys.items) |*key| key.deinit(allocator);
+            keys.deinit(allocator);
+        }
+
+        for (entries.array.items) |entry| {
+            var key = (try parseKey(allocator, entry)) orelse continue;
+            errdefer key.deinit(allocator);
+            try keys.append(allocator, key);
+        }
+
+        return .{ .allocator = allocator, .keys = try keys.toOwnedSlice(allocator) };
+    }
+
+    pub fn deinit(self: *JwkSet) void {
+        for (self.keys) |*key| key.deinit(self.allocator);
+        self.allocator.free(self.keys);
+    }
+
+    /// Find the key with ID `kid`
+    pub fn find(self: *const JwkSet, kid: []const u8) ?*const Jwk {
+        for (self.keys) |*key| {
+            if (key.kid) |id| {
+                if (std.mem.eql(u8, id, kid)) return key;
+            }
+        }
+        return null;
+    }
+
+    /// Verify the signature of the compact JWS `token`
+    ///
+    /// The header's `alg` must pass `config`. When the header names a `kid`
+    /// only that key is tried, otherwise every key supporting the algorithm.
+    /// HMAC-signed tokens are checked with `shared_secret` (for OIDC, the
+    /// client secret) instead of the set. Returns `error.InvalidSignature`
+    /// when no key verifies.
+    pub fn verify(
+        self: *const JwkSet,
+        allocator: Allocator,
+        token: []const u8,
+        config: jwt.VerificationConfig,
+        shared_secret: ?[]const u8,
+    ) !void {
+        var header = try jwt.decodeHeader(allocator, token);
+        defer header.deinit();
+        try config.checkAlgorithm(header.alg);
+
+        const parts = try jwt.split(token);
+        const signature = try jwt.decodeSegment(allocator, parts.signature);
+        defer allocator.free(signature);
+
+        if (header.alg.isSymmetric()) {
+            const secret = shared_secret orelse return error.InvalidSignature;
+            return verifyHmac(header.alg, secret, parts.signing_input, signature);
+        }
+
+        if (header.kid) |kid| {
+            const key = self.find(kid) orelse return error.InvalidSignature;
+            return key.verify(header.alg, parts.signing_input, signature);
+        }
+
+        for (self.keys) |*key| {
+            if (!key.supports(header.alg)) continue;
+            key.verify(header.alg, parts.signing_input, signature) catch continue;
+            return;
+        }
+        return error.InvalidSignature;
+    }
+};
+
+/// Default seconds a downloaded key set is used before it is fetched again
+pub const default_ttl: u64 = 3600;
+
+/// A key set downloaded on demand and reused until it expires
+///
+/// Safe to share between threads and clients verifying tokens of the same
+/// provider.
+pub const JwksCache = struct {
+    allocator: Allocator,
+    http_transport: HttpTransport,
+    /// Borrowed, must outlive the cache
+    jwks_uri: []const u8,
+    /// Seconds a downloaded set is used before it is fetched again
+    ttl: u64 = default_ttl,
+    /// Minimum seconds between early downloads caused by unknown key IDs
+    refetch_interval: u64 = 60,
+    keys: ?JwkSet = null,
+    /// When `keys` was downloaded (Unix time)
+    fetched_at: u64 = 0,
+    mutex: std.Thread.Mutex = .{},
+
+    pub fn init(allocator: Allocator, http_transport: HttpTransport, jwks_uri: []const u8) JwksCache {
+        return .{ .allocator = allocator, .http_transport = http_transport, .jwks_uri = jwks_uri };
+    }
+
+    pub fn deinit(self: *JwksCache) void {
+        if (self.keys) |*keys| keys.deinit();
+    }
+
+    /// Verify the signature of `token` with the cached set (see JwkSet.verify)
+    ///
+    /// The set is downloaded on first use and again once `ttl` has passed.
+    /// A token whose `kid` is missing from the set triggers one early
+    /// download, at most every `refetch_interval` seconds, so keys the
+    /// provider rolled over are picked up while tokens with made-up key IDs
+    /// cannot flood the jwks_uri.
+    pub fn verify(
+        self: *JwksCache,
+        token: []const u8,
+        config: jwt.VerificationConfig,
+        shared_secret: ?[]const u8,
+    ) !void {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        const now = clock.now();
+        if (self.keys == null or now >= self.fetched_at +| self.ttl) try self.refresh(now);
+
+        self.keys.?.verify(self.allocator, token, config, shared_secret) catch |err| {
+            if (err != error.InvalidSignature) return err;
+            if (!try self.hasUnknownKid(token)) return err;
+            if (now < self.fetched_at +| self.refetch_interval) return err;
+
+            try self.refresh(now);
+            return self.keys.?.verify(self.allocator, token, config, shared_secret);
+        };
+    }
+
+    /// Drop the cached set so the next verification downloads it again
+    pub fn invalidate(self: *JwksCache) void {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        if (self.keys) |*keys| keys.deinit();
+        self.keys = null;
+    }
+
+    fn refresh(self: *JwksCache, now: u64) !void {
+        const keys = try fetch(self.allocator, self.http_transport, self.jwks_uri);
+        if (self.keys) |*previous| previous.deinit();
+        self.keys = keys;
+        self.fetched_at = now;
+    }
+
+    fn hasUnknownKid(self: *const JwksCache, token: []const u8) !bool {
+        var header = try jwt.decodeHeader(self.allocator, token);
+        defer header.deinit();
+
+        const kid = header.kid orelse return false;
+        return self.keys.?.find(kid) == null;
+    }
+};
+
+/// Download and parse the key set at `jwks_uri`
+pub fn fetch(allocator: Allocator, http_transport: HttpTransport, jwks_uri: []const u8) !JwkSet {
+    const body = try discovery.fetchDocument(allocator, http_transport, jwks_uri);
+    defer allocator.free(body);
+
+    return JwkSet.parse(allocator, body);
+}
+
+/// Parse one key, or null if it is not a signing key schlussel supports
+fn parseKey(allocator: Allocator, value: json.Value) !?Jwk {
+    if (value != .object) return null;
+    const obj = value.object;
+
+    if (stringMember(obj, "use")) |use| {
+        if (!std.mem.eql(u8, use, "sig")) return null;
+    }
+
+    var alg: ?jwt.Algorithm = null;
+    if (stringMember(obj, "alg")) |name| {
+        alg = jwt.Algorithm.fromString(name) orelse return null;
+    }
+
+    const kty = stringMember(obj, "kty") orelse return null;
+    const crv = stringMember(obj, "crv") orelse "";
+
+    var key: Jwk.Key = undefined;
+    if (std.mem.eql(u8, kty, "RSA")) {
+        var n_buf: [max_rsa_len + 1]u8 = undefined;
+        var e_buf: [8]u8 = undefined;
+        const n = decodeMember(&n_buf, obj, "n") orelse return null;
+        const e = decodeMember(&e_buf, obj, "e") orelse return null;
+        key = try rsaKey(allocator, std.mem.trimLeft(u8, n, &.{0}), e);
+    } else if (std.mem.eql(u8, kty, "EC") and std.mem.eql(u8, crv, "P-256")) {
+        key = .{ .p256 = ecPoint(32, obj) orelse return null };
+    } else if (std.mem.eql(u8, kty, "EC") and std.mem.eql(u8, crv, "P-384")) {
+        key = .{ .p384 = ecPoint(48, obj) orelse return null };
+    } else if (std.mem.eql(u8, kty, "OKP") and std.mem.eql(u8, crv, "Ed25519")) {
+        var x: [32]u8 = undefined;
+        const decoded = decodeMember(&x, obj, "x") orelse return null;
+        if (decoded.len != x.len) return null;
+        key = .{ .ed25519 = x };
+    } else {
+        return null;
+    }
+
+    var jwk = Jwk{ .alg = alg, .key = key };
+    errdefer jwk.deinit(allocator);
+
+    if (stringMember(obj, "kid")) |kid| jwk.kid = try allocator.dupe(u8, kid);
+    return jwk;
+}
+
+fn rsaKey(allocator: Allocator, n: []const u8, e: []const u8) !Jwk.Key {
+    const n_owned = try allocator.dupe(u8, n);
+    errdefer allocator.free(n_owned);
+    const e_owned = try allocator.dupe(u8, e);
+    return .{ .rsa = .{ .n = n_owned, .e = e_owned } };
+}
+
+/// Read `x` and `y` into an uncompressed SEC1 point
+fn ecPoint(comptime coordinate_len: usize, obj: json.ObjectMap) ?[1 + 2 * coordinate_len]u8 {
+    var point: [1 + 2 * coordinate_len]u8 = undefined;
+    point[0] = 0x04;
+
+    const x = decodeMember(point[1 .. 1 + coordinate_len], obj, "x") orelse return null;
+    const y = decodeMember(point[1 + coordinate_len ..], obj, "y") orelse return null;
+    if (x.len != coordinate_len or y.len != coordinate_len) return null;
+    return point;
+}
+
+fn stringMember(obj: json.ObjectMap, name: []const u8) ?[]const u8 {
+    const value = obj.get(name) orelse return null;
+    return if (value == .string) value.string else null;
+}
+
+/// Decode a base64url member into `buf`, or null if missing, malformed or too long
+fn decodeMember(buf: []u8, obj: json.ObjectMap, name: []const u8) ?[]u8 {
+    const encoded = stringMember(obj, name) orelse return null;
+    const size = base64.Decoder.calcSizeForSlice(encoded) catch return null;
+    if (size > buf.len) return null;
+    base64.Decoder.decode(buf[0..size], encoded) catch return null;
+    return buf[0..size];
+}
+
+fn verifyRsa(alg: jwt.Algorithm, n: []const u8, e: []const u8, message: []const u8, signature: []const u8) !void {
+    const public_key = rsa.PublicKey.fromBytes(e, n) catch return error.InvalidSignature;
+    if (signature.len != n.len) return error.InvalidSignature;
+
+    switch (n.len) {
+        inline 256, 384, 512 => |len| {
+            const sig = signature[0..len].*;
+            switch (alg) {
+                .RS256 => rsa.PKCS1v1_5Signature.verify(len, sig, message, public_key, sha2.Sha256) catch return error.InvalidSignature,
+                .RS384 => rsa.PKCS1v1_5Signature.verify(len, sig, message, public_key, sha2.Sha384) catch return error.InvalidSignature,
+                .RS512 => rsa.PKCS1v1_5Signature.verify(len, sig, message, public_key, sha2.Sha512) catch return error.InvalidSignature,
+                .PS256 => rsa.PSSSignature.verify(len, sig, message, public_key, sha2.Sha256) catch return error.InvalidSignature,
+                .PS384 => rsa.PSSSignature.verify(len, sig, message, public_key, sha2.Sha384) catch return error.InvalidSignature,
+                .PS512 => rsa.PSSSignature.verify(len, sig, message, public_key, sha2.Sha512) catch return error.InvalidSignature,
+                else => return error.InvalidSignature,
+            }
+        },
+        else => return error.InvalidSignature,
+    }
+}
+
+fn verifyHmac(alg: jwt.Algorithm, secret: []const u8, message: []const u8, signature: []const u8) !void {
+    switch (alg) {
+        inline .HS256, .HS384, .HS512 => |a| {
+            const Hmac = switch (a) {
+                .HS256 => hmac.HmacSha256,
+                .HS384 => hmac.HmacSha384,
+                .HS512 => hmac.HmacSha512,
+                else => unreachable,
+            };
+            if (signature.len != Hmac.mac_length) return error.InvalidSignature;
+
+            var mac: [Hmac.mac_length]u8 = undefined;
+            Hmac.create(&mac, message, secret);
+            if (!std.crypto.timing_safe.eql([Hmac.mac_length]u8, mac, signature[0..Hmac.mac_length].*)) {
+                return error.InvalidSignature;
+            }
+        },
+        else => return error.InvalidSignature,
+    }
+}
+
+// RS256 token {"alg":"RS256","kid":"rsa-1"}.{"sub":"user"} and its signing key
+const test_rsa_jwks =
+    \\{"keys":[
+    \\ {"kty":"RSA","use":"enc","kid":"enc-1","n":"AQAB","e":"AQAB"},
+    \\ {"kty":"oct","kid":"oct-1","k":"c2VjcmV0"},
+    \\ {"kty":"RSA","use":"sig","alg":"RS256","kid":"rsa-1","e":"AQAB",
+    \\  "n":"pACtBwGaluVP6ls9o3LLv_n4Q4OzBVY4SU-03zBliyHO3tFE3353mHq5kXVtdw2rf7S4xhSF8DaHiJZq4cFb3AuTZbqZIjcRJmh0E_36XF-lBpcYLjXmcZca11dBw387fqiJz_EGqVcn7G-4yprUKQc6RcZbHRI8ehnhe-UMSZVcT2AD9NYt4gZ_5l0RPu-3ApVC83IfoddYte_UmLGABpDH1Afu0fL57KUHXVMmfXeouwN933NDaVeem_1aKjGVH9LYf00boXPlNPL-WAGQsG6S3CxWNgIuFLrOps4I21oxv_adRnQtsxJMajrL6M3SgP9B87VQe9XRjb8pDdbznw"}
+    \\]}
+;
+const test_rsa_token = "eyJhbGciOiJSUzI1NiIsImtpZCI6InJzYS0xIn0.eyJzdWIiOiJ1c2VyIn0." ++
+    "MfObH9NAfW1K6kTZGu4h_ejmAR_EWkljOR1p8tT1_lgYhvTRPfQigVdzAmd_5QxiDZIGv-jhEVnDVi317W3ayWtMQhC7ngEJjWLCwJHd5DkefSpbhbeKoCIwjsgba9Z8m7E_PdOSFLnlneXXNEQ2oJHYdcJ6b50ZgyrHzxZUK4TZ1l_oNB5Y5nTNh05SAwK6VniQgCXXiFfVEpZFswzRwbmYMJQnIoWFQ5dhVYU_aDJQRG0FVEJknsZ9vK-3la9OyQeJENjS7xfBnInKukiBdpQottMD-63_Ar7s6PDLN5OfCmbni4v3INfoJ9AvNw_b3VSYFNfAIzGebquip-j1oQ";
+
+test "JwkSet.parse keeps supported signing keys and verifies RS256" {
+    const allocator = std.testing.allocator;
+
+    var keys = try JwkSet.parse(allocator, test_rsa_jwks);
+    defer keys.deinit();
+
+    // The encryption and symmetric keys are skipped
+    try std.testing.expectEqual(@as(usize, 1), keys.keys.len);
+    try std.testing.expect(keys.find("rsa-1") != null);
+    try std.testing.expect(keys.find("enc-1") == null);
+
+    try keys.verify(allocator, test_rsa_token, .{}, null);
+
+    // Flipping a payload byte breaks the signature
+    var tampered = test_rsa_token.*;
+    tampered[std.mem.indexOfScalar(u8, &tampered, '.').? + 2] ^= 1;
+    try std.testing.expectError(error.InvalidSignature, keys.verify(allocator, &tampered, .{}, null));
+
+    // Outside the allowlist the signature is not even looked at
+    try std.testing.expectError(error.DisallowedAlgorithm, keys.verify(allocator, test_rsa_token, .{ .allowed_algorithms = &.{.ES256} }, null));
+}
+
+/// JWKS document publishing `key_pair` under `kid`
+fn testEcDocument(allocator: Allocator, key_pair: EcdsaP256.KeyPair, kid: []const u8) ![]u8 {
+    const point = key_pair.public_key.toUncompressedSec1();
+    var x: [base64.Encoder.calcSize(32)]u8 = undefined;
+    var y: [base64.Encoder.calcSize(32)]u8 = undefined;
+    _ = base64.Encoder.encode(&x, point[1..33]);
+    _ = base64.Encoder.encode(&y, point[33..65]);
+
+    return std.fmt.allocPrint(allocator, "{{\"keys\":[{{\"kty\":\"EC\",\"crv\":\"P-256\",\"kid\":\"{s}\",\"x\":\"{s}\",\"y\":\"{s}\"}}]}}", .{ kid, &x, &y });
+}
+
+/// ES256 token signed by `key_pair`, naming `kid` in its header if given
+fn testEcToken(allocator: Allocator, key_pair: EcdsaP256.KeyPair, kid: ?[]const u8) ![]u8 {
+    const header_json = if (kid) |id|
+        try std.fmt.allocPrint(allocator, "{{\"alg\":\"ES256\",\"kid\":\"{s}\"}}", .{id})
+    else
+        try allocator.dupe(u8, "{\"alg\":\"ES256\"}");
+    defer allocator.free(header_json);
+    const header = try jwt.encodeSegment(allocator, header_json);
+    defer allocator.free(header);
+
+    const signing_input = try std.fmt.allocPrint(allocator, "{s}.eyJzdWIiOiJ1c2VyIn0", .{header});
+    defer allocator.free(signing_input);
+    const signature = (try key_pair.sign(signing_input, null)).toBytes();
+    const encoded_signature = try jwt.encodeSegment(allocator, &signature);
+    defer allocator.free(encoded_signature);
+    return std.fmt.allocPrint(allocator, "{s}.{s}", .{ signing_input, encoded_signature });
+}
+
+test "JwkSet.verify checks ES256 by key and HS256 with the shared secret" {
+    const allocator = std.testing.allocator;
+
+    const key_pair = EcdsaP256.KeyPair.generate();
+    const document = try testEcDocument(allocator, key_pair, "k1");
+    defer allocator.free(document);
+    var keys = try JwkSet.parse(allocator, document);
+    defer keys.deinit();
+
+    const token = try testEcToken(allocator, key_pair, null);
+    defer allocator.free(token);
+
+    // No kid in the header: any P-256 key may verify it
+    try keys.verify(allocator, token, .{}, null);
+
+    var mac: [hmac.HmacSha256.mac_length]u8 = undefined;
+    const hs_input = "eyJhbGciOiJIUzI1NiJ9.eyJzdWIiOiJ1c2VyIn0";
+    hmac.HmacSha256.create(&mac, hs_input, "client-secret");
+    const encoded_mac = try jwt.encodeSegment(allocator, &mac);
+    defer allocator.free(encoded_mac);
+    const hs_token = try std.fmt.allocPrint(allocator, "{s}.{s}", .{ hs_input, encoded_mac });
+    defer allocator.free(hs_token);
+
+    const with_hmac = jwt.VerificationConfig{ .allowed_algorithms = &.{.HS256} };
+    try keys.verify(allocator, hs_token, with_hmac, "client-secret");
+    try std.testing.expectError(error.InvalidSignature, keys.verify(allocator, hs_token, with_hmac, "other-secret"));
+    try std.testing.expectError(error.InvalidSignature, keys.verify(allocator, hs_token, with_hmac, null));
+}
+
+test "JwksCache reuses the set and re-fetches for rolled-over keys" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    const old_key = EcdsaP256.KeyPair.generate();
+    const new_key = EcdsaP256.KeyPair.generate();
+    const old_document = try testEcDocument(allocator, old_key, "old");
+    defer allocator.free(old_document);
+    const new_document = try testEcDocument(allocator, new_key, "new");
+    defer allocator.free(new_document);
+    const old_token = try testEcToken(allocator, old_key, "old");
+    defer allocator.free(old_token);
+    const new_token = try testEcToken(allocator, new_key, "new");
+    defer allocator.free(new_token);
+
+    var cache = JwksCache.init(allocator, mock.transport(), "https://id.example.com/jwks");
+    defer cache.deinit();
+
+    try mock.enqueue(.{ .body = old_document });
+    try cache.verify(old_token, .{}, null);
+    try cache.verify(old_token, .{}, null);
+    try std.testing.expectEqual(@as(usize, 1), mock.requestCount());
+
+    // An unknown kid right after a download does not fetch again
+    try std.testing.expectError(error.InvalidSignature, cache.verify(new_token, .{}, null));
+    try std.testing.expectEqual(@as(usize, 1), mock.requestCount());
+
+    // Once the refetch interval has passed the rolled-over key is picked up
+    clock.setMockTime(1_700_000_061);
+    try mock.enqueue(.{ .body = new_document });
+    try cache.verify(new_token, .{}, null);
+    try std.testing.expectEqual(@as(usize, 2), mock.requestCount());
+
+    // An expired set is downloaded again
+    clock.setMockTime(1_700_000_061 + default_ttl);
+    try mock.enqueue(.{ .body = new_document });
+    try cache.verify(new_token, .{}, null);
+    try std.testing.expectEqual(@as(usize, 3), mock.requestCount());
+}
@@ -0,0 +1,361 @@
+//! macOS Keychain storage through the Security framework
+//!
+//! `KeychainStorage` keeps every token as a generic password item, with
+//! the service name as `kSecAttrService` and the storage key as
+//! `kSecAttrAccount`. Unlike SecureStorage, which shells out to
+//! `security(1)` and passes the token on its command line, the token only
+//! ever crosses the Security framework API. Items are created with
+//! `kSecAttrAccessibleAfterFirstUnlockThisDeviceOnly`, so they are neither
+//! synced to iCloud nor restored onto another device.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var keychain = try KeychainStorage.init(allocator, "dev.tuist.cli");
+//! defer keychain.deinit();
+//!
+//! var client = OAuthClient.init(allocator, config, keychain.storage());
+//! ```
+
+const std = @import("std");
+const builtin = @import("builtin");
+const Allocator = std.mem.Allocator;
+
+const codec = @import("codec.zig");
+const session = @import("session.zig");
+
+const Token = session.Token;
+const SessionStorage = session.SessionStorage;
+const StorageCapabilities = session.StorageCapabilities;
+
+/// Whether the Security framework is available on the target
+pub const supported = builtin.os.tag == .macos;
+
+/// Token storage in the macOS login keychain
+pub const KeychainStorage = struct {
+    allocator: Allocator,
+    service_name: []const u8,
+
+    /// Returns `error.UnsupportedOperation` on platforms other than macOS
+    pub fn init(allocator: Allocator, service_name: []const u8) !KeychainStorage {
+        if (!supported) return error.UnsupportedOperation;
+        if (service_name.len == 0) return error.InvalidParameter;
+        return .{
+            .allocator = allocator,
+            .service_name = try allocator.dupe(u8, service_name),
+        };
+    }
+
+    pub fn deinit(self: *KeychainStorage) void {
+        self.allocator.free(self.service_name);
+    }
+
+    pub fn storage(self: *KeychainStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+                .capabilities = capabilities,
+                .list_keys = listKeys,
+            }),
+        };
+    }
+
+    fn capabilities(_: *anyopaque) StorageCapabilities {
+        return .{ .list_keys = true, .atomic_swap = true, .os_protected = true };
+    }
+
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *KeychainStorage = @ptrCast(@alignCast(ptr));
+        const data = try codec.default.encode(self.allocator, &token);
+        defer {
+            @memset(data, 0);
+            self.allocator.free(data);
+        }
+        try security.store(self.service_name, key, data);
+    }
+
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *KeychainStorage = @ptrCast(@alignCast(ptr));
+        const data = (try security.find(self.allocator, self.service_name, key)) orelse return null;
+        defer {
+            @memset(data, 0);
+            self.allocator.free(data);
+        }
+        return try codec.default.decode(allocator, data);
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *KeychainStorage = @ptrCast(@alignCast(ptr));
+        try security.remove(self.service_name, key);
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        if (!supported) return false;
+        const self: *KeychainStorage = @ptrCast(@alignCast(ptr));
+        return security.contains(self.service_name, key) catch false;
+    }
+
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) ![][]const u8 {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *KeychainStorage = @ptrCast(@alignCast(ptr));
+        return security.accounts(allocator, self.service_name);
+    }
+};
+
+/// Generic password items through the Security and CoreFoundation C APIs
+const security = struct {
+    const CFTypeRef = *const anyopaque;
+    const CFIndex = isize;
+    const OSStatus = i32;
+
+    const err_sec_success: OSStatus = 0;
+    const err_sec_item_not_found: OSStatus = -25300;
+    const string_encoding_utf8: u32 = 0x08000100;
+
+    const DictionaryKeyCallBacks = extern struct {
+        version: CFIndex,
+        retain: ?*const anyopaque,
+        release: ?*const anyopaque,
+        copy_description: ?*const anyopaque,
+        equal: ?*const anyopaque,
+        hash: ?*const anyopaque,
+    };
+
+    const DictionaryValueCallBacks = extern struct {
+        version: CFIndex,
+        retain: ?*const anyopaque,
+        release: ?*const anyopaque,
+        copy_description: ?*const anyopaque,
+        equal: ?*const anyopaque,
+    };
+
+    extern const kCFTypeDictionaryKeyCallBacks: DictionaryKeyCallBacks;
+    extern const kCFTypeDictionaryValueCallBacks: DictionaryValueCallBacks;
+    extern const kCFBooleanTrue: CFTypeRef;
+
+    extern const kSecClass: CFTypeRef;
+    extern const kSecClassGenericPassword: CFTypeRef;
+    extern const kSecAttrService: CFTypeRef;
+    extern const kSecAttrAccount: CFTypeRef;
+    extern const kSecAttrAccessible: CFTypeRef;
+    extern const kSecAttrAccessibleAfterFirstUnlockThisDeviceOnly: CFTypeRef;
+    extern const kSecValueData: CFTypeRef;
+    extern const kSecReturnData: CFTypeRef;
+    extern const kSecReturnAttributes: CFTypeRef;
+    extern const kSecMatchLimit: CFTypeRef;
+    extern const kSecMatchLimitAll: CFTypeRef;
+
+    extern fn CFRelease(cf: CFTypeRef) void;
+    extern fn CFStringCreateWithBytes(allocator: ?CFTypeRef, bytes: [*]const u8, len: CFIndex, encoding: u32, is_external: u8) ?CFTypeRef;
+    extern fn CFStringGetLength(string: CFTypeRef) CFIndex;
+    extern fn CFStringGetMaximumSizeForEncoding(len: CFIndex, encoding: u32) CFIndex;
+    extern fn CFStringGetCString(string: CFTypeRef, buffer: [*]u8, size: CFIndex, encoding: u32) u8;
+    extern fn CFDataCreate(allocator: ?CFTypeRef, bytes: [*]const u8, len: CFIndex) ?CFTypeRef;
+    extern fn CFDataGetLength(data: CFTypeRef) CFIndex;
+    extern fn CFDataGetBytePtr(data: CFTypeRef) [*]const u8;
+    extern fn CFArrayGetCount(array: CFTypeRef) CFIndex;
+    extern fn CFArrayGetValueAtIndex(array: CFTypeRef, index: CFIndex) CFTypeRef;
+    extern fn CFDictionaryGetValue(dictionary: CFTypeRef, key: CFTypeRef) ?CFTypeRef;
+    extern fn CFDictionaryCreate(
+        allocator: ?CFTypeRef,
+        keys: [*]const CFTypeRef,
+        values: [*]const CFTypeRef,
+        count: CFIndex,
+        key_callbacks: *const DictionaryKeyCallBacks,
+        value_callbacks: *const DictionaryValueCallBacks,
+    ) ?CFTypeRef;
+
+    extern fn SecItemAdd(attributes: CFTypeRef, result: ?*?CFTypeRef) OSStatus;
+    extern fn SecItemUpdate(query: CFTypeRef, attributes: CFTypeRef) OSStatus;
+    extern fn SecItemCopyMatching(query: CFTypeRef, result: ?*?CFTypeRef) OSStatus;
+    extern fn SecItemDelete(query: CFTypeRef) OSStatus;
+
+    /// Create or replace the item for `account`
+    fn store(service: []const u8, account: []const u8, data: []const u8) !void {
+        const service_ref = try createString(service);
+        defer CFRelease(service_ref);
+        const account_ref = try createString(account);
+        defer CFRelease(account_ref);
+        const data_ref = CFDataCreate(null, data.ptr, @intCast(data.len)) orelse return error.OutOfMemory;
+        defer CFRelease(data_ref);
+
+        const query = try createDictionary(
+            &.{ kSecClass, kSecAttrService, kSecAttrAccount },
+            &.{ kSecClassGenericPassword, service_ref, account_ref },
+        );
+        defer CFRelease(query);
+        const update = try createDictionary(&.{kSecValueData}, &.{data_ref});
+        defer CFRelease(update);
+
+        // Updating in place keeps the item's access control list
+        const status = SecItemUpdate(query, update);
+        if (status == err_sec_success) return;
+        if (status != err_sec_item_not_found) return error.StorageError;
+
+        const attributes = try createDictionary(
+            &.{ kSecClass, kSecAttrService, kSecAttrAccount, kSecAttrAccessible, kSecValueData },
+            &.{ kSecClassGenericPassword, service_ref, account_ref, kSecAttrAccessibleAfterFirstUnlockThisDeviceOnly, data_ref },
+        );
+        defer CFRelease(attributes);
+        if (SecItemAdd(attributes, null) != err_sec_success) return error.StorageError;
+    }
+
+    /// Data of the item for `account`, or null if there is none; caller owns it
+    fn find(allocator: Allocator, service: []const u8, account: []const u8) !?[]u8 {
+        const service_ref = try createString(service);
+        defer CFRelease(service_ref);
+        const account_ref = try createString(account);
+        defer CFRelease(account_ref);
+
+        const query = try createDictionary(
+            &.{ kSecClass, kSecAttrService, kSecAttrAccount, kSecReturnData },
+            &.{ kSecClassGenericPassword, service_ref, account_ref, kCFBooleanTrue },
+        );
+        defer CFRelease(query);
+
+        var result: ?CFTypeRef = null;
+        const status = SecItemCopyMatching(query, &result);
+        if (status == err_sec_item_not_found) return null;
+        if (status != err_sec_success) return error.StorageError;
+        const data_ref = result orelse return error.StorageError;
+        defer CFRelease(data_ref);
+
+        const len: usize = @intCast(CFDataGetLength(data_ref));
+        return try allocator.dupe(u8, CFDataGetBytePtr(data_ref)[0..len]);
+    }
+
+    fn contains(service: []const u8, account: []const u8) !bool {
+        const service_ref = try createString(service);
+        defer CFRelease(service_ref);
+        const account_ref = try createString(account);
+        defer CFRelease(account_ref);
+
+        const query = try createDictionary(
+            &.{ kSecClass, kSecAttrService, kSecAttrAccount },
+            &.{ kSecClassGenericPassword, service_ref, account_ref },
+        );
+        defer CFRelease(query);
+
+        return switch (SecItemCopyMatching(query, null)) {
+            err_sec_success => true,
+            err_sec_item_not_found => false,
+            else => error.StorageError,
+        };
+    }
+
+    /// Delete the item for `account`; a missing item is not an error
+    fn remove(service: []const u8, account: []const u8) !void {
+        const service_ref = try createString(service);
+        defer CFRelease(service_ref);
+        const account_ref = try createString(account);
+        defer CFRelease(account_ref);
+
+        const query = try createDictionary(
+            &.{ kSecClass, kSecAttrService, kSecAttrAccount },
+            &.{ kSecClassGenericPassword, service_ref, account_ref },
+        );
+        defer CFRelease(query);
+
+        const status = SecItemDelete(query);
+        if (status != err_sec_success and status != err_sec_item_not_found) return error.StorageError;
+    }
+
+    /// Accounts of every item stored under `service`
+    fn accounts(allocator: Allocator, service: []const u8) ![][]const u8 {
+        const service_ref = try createString(service);
+        defer CFRelease(service_ref);
+
+        const query = try createDictionary(
+            &.{ kSecClass, kSecAttrService, kSecReturnAttributes, kSecMatchLimit },
+            &.{ kSecClassGenericPassword, service_ref, kCFBooleanTrue, kSecMatchLimitAll },
+        );
+        defer CFRelease(query);
+
+        var result: ?CFTypeRef = null;
+        const status = SecItemCopyMatching(query, &result);
+        if (status == err_sec_item_not_found) return allocator.alloc([]const u8, 0);
+        if (status != err_sec_success) return error.StorageError;
+        const items = result orelse return error.StorageError;
+        defer CFRelease(items);
+
+        var keys: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
+
+        const count: usize = @intCast(CFArrayGetCount(items));
+        for (0..count) |i| {
+            const item = CFArrayGetValueAtIndex(items, @intCast(i));
+            const account_ref = CFDictionaryGetValue(item, kSecAttrAccount) orelse continue;
+            const account = try copyString(allocator, account_ref);
+            errdefer allocator.free(account);
+            try keys.append(allocator, account);
+        }
+        return keys.toOwnedSlice(allocator);
+    }
+
+    fn createString(value: []const u8) !CFTypeRef {
+        return CFStringCreateWithBytes(null, value.ptr, @intCast(value.len), string_encoding_utf8, 0) orelse
+            error.InvalidParameter;
+    }
+
+    fn copyString(allocator: Allocator, string: CFTypeRef) ![]const u8 {
+        const size: usize = @intCast(CFStringGetMaximumSizeForEncoding(CFStringGetLength(string), string_encoding_utf8) + 1);
+        const buffer = try allocator.alloc(u8, size);
+        defer allocator.free(buffer);
+
+        if (CFStringGetCString(string, buffer.ptr, @intCast(size), string_encoding_utf8) == 0) return error.StorageError;
+        return allocator.dupe(u8, std.mem.sliceTo(buffer, 0));
+    }
+
+    fn createDictionary(keys: []const CFTypeRef, values: []const CFTypeRef) !CFTypeRef {
+        std.debug.assert(keys.len == values.len);
+        return CFDictionaryCreate(
+            null,
+            keys.ptr,
+            values.ptr,
+            @intCast(keys.len),
+            &kCFTypeDictionaryKeyCallBacks,
+            &kCFTypeDictionaryValueCallBacks,
+        ) orelse error.OutOfMemory;
+    }
+};
+
+test "KeychainStorage.init is only available on macOS" {
+    const allocator = std.testing.allocator;
+
+    if (!supported) {
+        try std.testing.expectError(error.UnsupportedOperation, KeychainStorage.init(allocator, "dev.schlussel.test"));
+        return;
+    }
+
+    try std.testing.expectError(error.InvalidParameter, KeychainStorage.init(allocator, ""));
+
+    var keychain = try KeychainStorage.init(allocator, "dev.schlussel.test");
+    defer keychain.deinit();
+
+    const caps = keychain.storage().capabilities();
+    try std.testing.expect(caps.list_keys);
+    try std.testing.expect(caps.atomic_swap);
+    try std.testing.expect(caps.os_protected);
+
+    const store = keychain.storage();
+    var token = try Token.initFull(allocator, "keychain-access", "Bearer", "keychain-refresh", 3600, null, null);
+    defer token.deinit();
+    try store.save("round-trip", token);
+    defer store.delete("round-trip") catch {};
+
+    var loaded = (try store.load(allocator, "round-trip")).?;
+    defer loaded.deinit();
+    try std.testing.expectEqualStrings("keychain-access", loaded.access_token);
+    try std.testing.expectEqualStrings("keychain-refresh", loaded.refresh_token.?);
+    try std.testing.expect(store.exists("round-trip"));
+}
@@ -0,0 +1,151 @@
+//! Token storage in the platform's credential store
+//!
+//! `KeyringStorage` picks the backend once, at init():
+//!
+//! - macOS: `KeychainStorage`
+//! - Windows: `CredentialManagerStorage`
+//! - Linux: `SecretServiceStorage` when built with `-Dlibsecret=true` and
+//!   a Secret Service is running, else `SecureStorage` when `secret-tool`
+//!   reaches one
+//! - otherwise: plaintext `FileStorage` in the application data directory
+//!
+//! Applications that must not write plaintext files check
+//! `storage().capabilities().os_protected` (or backend()) before saving.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var keyring = try KeyringStorage.init(allocator, "dev.tuist.cli");
+//! defer keyring.deinit();
+//!
+//! if (!keyring.storage().capabilities().os_protected) {
+//!     std.log.warn("no credential store available; tokens are stored in plain files", .{});
+//! }
+//! var client = OAuthClient.init(allocator, config, keyring.storage());
+//! ```
+
+const std = @import("std");
+const Allocator = std.mem.Allocator;
+
+const session = @import("session.zig");
+const keychain = @import("keychain.zig");
+const wincred = @import("wincred.zig");
+const secret_service = @import("secret_service.zig");
+
+const SessionStorage = session.SessionStorage;
+
+/// Storage backend chosen by KeyringStorage
+pub const Backend = enum {
+    keychain,
+    credential_manager,
+    secret_service,
+    secret_tool,
+    file,
+};
+
+/// The platform credential store, or files where there is none
+pub const KeyringStorage = struct {
+    inner: Inner,
+
+    const Inner = union(Backend) {
+        keychain: keychain.KeychainStorage,
+        credential_manager: wincred.CredentialManagerStorage,
+        secret_service: secret_service.SecretServiceStorage,
+        secret_tool: session.SecureStorage,
+        file: session.FileStorage,
+    };
+
+    /// Open the credential store for `service_name`
+    ///
+    /// On Linux without libsecret the Secret Service is reached through
+    /// `secret-tool` (SecureStorage). Falls back to FileStorage under
+    /// `service_name` when the platform has no supported credential store
+    /// or, on Linux, no Secret Service runs.
+    pub fn init(allocator: Allocator, service_name: []const u8) !KeyringStorage {
+        if (keychain.supported) {
+            return .{ .inner = .{ .keychain = try keychain.KeychainStorage.init(allocator, service_name) } };
+        }
+        if (wincred.supported) {
+            return .{ .inner = .{ .credential_manager = try wincred.CredentialManagerStorage.init(allocator, service_name) } };
+        }
+        if (secret_service.supported) {
+            if (secret_service.SecretServiceStorage.init(allocator, service_name)) |keyring| {
+                return .{ .inner = .{ .secret_service = keyring } };
+            } else |err| switch (err) {
+                error.SecretServiceUnavailable => {},
+                else => return err,
+            }
+        }
+
+        if (session.SecureStorage.secretToolAvailable(allocator)) {
+            return .{ .inner = .{ .secret_tool = try session.SecureStorage.init(allocator, service_name) } };
+        }
+
+        return .{ .inner = .{ .file = try session.FileStorage.init(allocator, service_name) } };
+    }
+
+    /// Suffix of the service name initSessions() stores under
+    pub const session_namespace = ".sessions";
+
+    /// Open the credential store for short-lived session records
+    ///
+    /// Like init(), but under `<service_name>.sessions`, so pending
+    /// authorization state never collides with (or is listed next to) the
+    /// long-lived tokens kept under `service_name`.
+    pub fn initSessions(allocator: Allocator, service_name: []const u8) !KeyringStorage {
+        const namespaced = try std.mem.concat(allocator, u8, &.{ service_name, session_namespace });
+        defer allocator.free(namespaced);
+        return init(allocator, namespaced);
+    }
+
+    pub fn deinit(self: *KeyringStorage) void {
+        switch (self.inner) {
+            inline else => |*backend_storage| backend_storage.deinit(),
+        }
+    }
+
+    /// The backend init() picked
+    pub fn backend(self: *const KeyringStorage) Backend {
+        return self.inner;
+    }
+
+    /// Whether tokens end up in plain files instead of a credential store
+    pub fn isFallback(self: *const KeyringStorage) bool {
+        return self.inner == .file;
+    }
+
+    pub fn storage(self: *KeyringStorage) SessionStorage {
+        return switch (self.inner) {
+            inline else => |*backend_storage| backend_storage.storage(),
+        };
+    }
+};
+
+test "KeyringStorage reports the backend it picked" {
+    const allocator = std.testing.allocator;
+
+    var keyring = try KeyringStorage.init(allocator, "dev.schlussel.test");
+    defer keyring.deinit();
+
+    const caps = keyring.storage().capabilities();
+    try std.testing.expectEqual(!keyring.isFallback(), caps.os_protected);
+    if (!keychain.supported and !wincred.supported and !secret_service.supported) {
+        const expected: Backend = if (session.SecureStorage.secretToolAvailable(allocator)) .secret_tool else .file;
+        try std.testing.expectEqual(expected, keyring.backend());
+    }
+}
+
+test "KeyringStorage.initSessions keeps sessions apart from tokens" {
+    const allocator = std.testing.allocator;
+
+    var tokens = try KeyringStorage.init(allocator, "dev.schlussel.test");
+    defer tokens.deinit();
+    var sessions = try KeyringStorage.initSessions(allocator, "dev.schlussel.test");
+    defer sessions.deinit();
+
+    try std.testing.expectEqual(tokens.backend(), sessions.backend());
+    if (sessions.inner == .file) {
+        try std.testing.expect(std.mem.endsWith(u8, sessions.inner.file.base_path, "dev.schlussel.test.sessions"));
+        try std.testing.expect(!std.mem.eql(u8, tokens.inner.file.base_path, sessions.inner.file.base_path));
+    }
+}
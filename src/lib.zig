@@ -41,28 +41,129 @@ pub const oauth = @import("oauth.zig");
 pub const callback = @import("callback.zig");
 pub const lock = @import("lock.zig");
 pub const registration = @import("registration.zig");
+pub const transport = @import("transport.zig");
+pub const jwt = @import("jwt.zig");
+pub const clock = @import("clock.zig");
+pub const codec = @import("codec.zig");
+pub const events = @import("events.zig");
+pub const dpop = @import("dpop.zig");
+pub const discovery = @import("discovery.zig");
+pub const oidc = @import("oidc.zig");
+pub const jwks = @import("jwks.zig");
+pub const id_token = @import("id_token.zig");
+pub const logout_token = @import("logout_token.zig");
+pub const ciba = @import("ciba.zig");
+pub const jarm = @import("jarm.zig");
+pub const jar = @import("jar.zig");
+pub const keychain = @import("keychain.zig");
+pub const wincred = @import("wincred.zig");
+pub const secret_service = @import("secret_service.zig");
+pub const keyring = @import("keyring.zig");
+pub const sqlite = @import("sqlite.zig");
+pub const redis = @import("redis.zig");
+pub const vault = @import("vault.zig");
+pub const aws_secrets = @import("aws_secrets.zig");
+pub const migration = @import("migration.zig");
+pub const async_storage = @import("async_storage.zig");
+pub const import = @import("import.zig");
 
 // Re-export commonly used types for convenience
 pub const Pkce = pkce.Pkce;
+pub const PkceOptions = pkce.Options;
+pub const PkceMethod = pkce.Method;
 pub const Token = session.Token;
 pub const Session = session.Session;
+pub const AuthorizationParam = session.AuthorizationParam;
 pub const SessionStorage = session.SessionStorage;
+pub const StorageCapabilities = session.StorageCapabilities;
+pub const StorageError = session.StorageError;
 pub const MemoryStorage = session.MemoryStorage;
 pub const FileStorage = session.FileStorage;
 pub const SecureStorage = session.SecureStorage;
+pub const KeychainStorage = keychain.KeychainStorage;
+pub const CredentialManagerStorage = wincred.CredentialManagerStorage;
+pub const SecretServiceStorage = secret_service.SecretServiceStorage;
+pub const KeyringStorage = keyring.KeyringStorage;
+pub const SqliteStorage = sqlite.SqliteStorage;
+pub const RedisStorage = redis.RedisStorage;
+pub const VaultStorage = vault.VaultStorage;
+pub const AwsSecretsStorage = aws_secrets.AwsSecretsStorage;
+pub const RefreshTokenOnlyStorage = session.RefreshTokenOnlyStorage;
+pub const ChainedStorage = session.ChainedStorage;
+pub const NamespacedStorage = session.NamespacedStorage;
+pub const CachedStorage = session.CachedStorage;
+pub const EnvStorage = session.EnvStorage;
+pub const TokenChange = session.TokenChange;
+pub const TokenWatcher = session.TokenWatcher;
+pub const FileWatch = session.FileWatch;
+pub const MigrationOptions = migration.Options;
+pub const MigrationReport = migration.Report;
+pub const migrate = migration.migrate;
+pub const exportTokens = migration.exportTokens;
+pub const importTokens = migration.importTokens;
+pub const Netrc = import.Netrc;
+pub const importNetrc = import.importNetrc;
+pub const importCli = import.importCli;
+pub const AsyncSessionStorage = async_storage.AsyncSessionStorage;
+pub const PooledStorage = async_storage.PooledStorage;
+pub const Keystore = session.Keystore;
+pub const KeyProvider = session.KeyProvider;
+pub const StaticKeyProvider = session.StaticKeyProvider;
 pub const OAuthError = error_types.OAuthError;
+pub const OAuthErrorCode = error_types.ErrorCode;
+pub const ErrorResponse = error_types.ErrorResponse;
 pub const OAuthConfig = oauth.OAuthConfig;
+pub const ClientAuth = oauth.ClientAuth;
+pub const ComplianceMode = oauth.ComplianceMode;
 pub const OAuthClient = oauth.OAuthClient;
 pub const TokenRefresher = oauth.TokenRefresher;
+pub const TokenTransform = oauth.TokenTransform;
+pub const GrantRequest = oauth.GrantRequest;
+pub const GrantParams = oauth.GrantParams;
 pub const DeviceAuthorizationResponse = oauth.DeviceAuthorizationResponse;
+pub const DeviceFlow = oauth.DeviceFlow;
+pub const BackchannelFlow = oauth.BackchannelFlow;
+pub const BackchannelRequest = ciba.BackchannelRequest;
+pub const TokenExchangeRequest = oauth.TokenExchangeRequest;
+pub const LogoutOptions = oauth.LogoutOptions;
+pub const IntrospectionResponse = oauth.IntrospectionResponse;
 pub const AuthFlowResult = oauth.AuthFlowResult;
+pub const AuthorizationFlow = oauth.AuthorizationFlow;
+pub const AuthorizationOptions = oauth.AuthorizationOptions;
+pub const ExchangeResult = oauth.ExchangeResult;
+pub const ResponseMeta = oauth.ResponseMeta;
+pub const DetailedToken = oauth.DetailedToken;
+pub const NonceMode = oauth.NonceMode;
+pub const ResponseMode = jarm.ResponseMode;
+pub const RequestSigning = jar.RequestSigning;
+pub const RequestAudit = oauth.RequestAudit;
 pub const CallbackServer = callback.CallbackServer;
 pub const CallbackResult = callback.CallbackResult;
+pub const AuthorizationRequest = callback.AuthorizationRequest;
 pub const RefreshLockManager = lock.RefreshLockManager;
 pub const RefreshLock = lock.RefreshLock;
 pub const DynamicRegistration = registration.DynamicRegistration;
 pub const ClientMetadata = registration.ClientMetadata;
 pub const ClientRegistrationResponse = registration.ClientRegistrationResponse;
+pub const HttpTransport = transport.HttpTransport;
+pub const ClientCertificate = transport.ClientCertificate;
+pub const MockTransport = transport.MockTransport;
+pub const JsonCodec = codec.JsonCodec;
+pub const VerificationConfig = jwt.VerificationConfig;
+pub const SigningKey = jwt.SigningKey;
+pub const AssertionClaims = jwt.AssertionClaims;
+pub const RsaPrivateKey = jwt.RsaPrivateKey;
+pub const Event = events.Event;
+pub const EventSink = events.EventSink;
+pub const JsonLinesSink = events.JsonLinesSink;
+pub const DpopKey = dpop.DpopKey;
+pub const ServerMetadata = discovery.ServerMetadata;
+pub const OidcConfig = oidc.OidcConfig;
+pub const UserInfo = oidc.UserInfo;
+pub const JwkSet = jwks.JwkSet;
+pub const JwksCache = jwks.JwksCache;
+pub const IdToken = id_token.IdToken;
+pub const LogoutToken = logout_token.LogoutToken;
 
 // FFI exports (only when building as library)
 pub const ffi = @import("ffi.zig");
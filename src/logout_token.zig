@@ -0,0 +1,243 @@
+//! OpenID Connect back-channel logout tokens (OpenID Connect Back-Channel Logout 1.0)
+//!
+//! When a user signs out at the provider, it posts a signed `logout_token`
+//! to every client the user signed in to. The token names the provider
+//! session (`sid`), the user (`sub`), or both, and every local session that
+//! matches has to end. `LogoutToken` checks the token the same way
+//! `IdToken` checks ID tokens; OAuthClient.handleBackchannelLogout() also
+//! removes the matching tokens from storage.
+//!
+//! ## Example
+//!
+//! ```zig
+//! // `raw` is the `logout_token` form parameter the provider posted to
+//! // the client's backchannel_logout_uri
+//! const removed = try client.handleBackchannelLogout(raw);
+//! // Answer the provider with 200 OK, or 400 Bad Request on error
+//! ```
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+
+const clock = @import("clock.zig");
+const jwks = @import("jwks.zig");
+const jwt = @import("jwt.zig");
+const IdToken = @import("id_token.zig").IdToken;
+
+// Shared with ID tokens, which carry the same registered claims
+const stringClaim = @import("id_token.zig").stringClaim;
+const timeClaim = @import("id_token.zig").timeClaim;
+const audienceClaim = @import("id_token.zig").audienceClaim;
+
+/// Member of `events` that marks a logout token
+pub const logout_event = "http://schemas.openid.net/event/backchannel-logout";
+
+/// Validated logout token claims
+pub const LogoutToken = struct {
+    allocator: Allocator,
+    /// Issuer identifier
+    iss: []const u8,
+    /// Audiences; always contains the client ID once validated
+    aud: []const []const u8,
+    /// Issue time (Unix time)
+    iat: u64,
+    /// Expiry (Unix time), if the provider sets one
+    exp: ?u64 = null,
+    /// Unique token ID
+    ///
+    /// Not checked for replays: handling a logout token twice only ends
+    /// sessions that have already ended. Record it until `exp` where a
+    /// replay must be rejected.
+    jti: []const u8,
+    /// User whose sessions end
+    sub: ?[]const u8 = null,
+    /// Provider session that ended
+    sid: ?[]const u8 = null,
+
+    /// What a valid logout token must match
+    pub const Validation = struct {
+        /// Expected `iss` (the provider's issuer identifier)
+        issuer: []const u8,
+        /// Client ID that must appear in `aud`
+        client_id: []const u8,
+        /// Tolerated clock skew for `iat` and `exp`, in seconds
+        leeway: u64 = IdToken.default_leeway,
+        /// Accepted signing algorithms
+        verification: jwt.VerificationConfig = .{},
+        /// Key for HMAC-signed tokens (the client secret)
+        client_secret: ?[]const u8 = null,
+    };
+
+    /// Where validate() finds the provider's signing keys
+    pub const Keys = union(enum) {
+        set: *const jwks.JwkSet,
+        cache: *jwks.JwksCache,
+    };
+
+    /// Verify the signature of `raw` against `keys` and check its claims
+    ///
+    /// Returns `error.InvalidSignature` for a bad signature and
+    /// `error.InvalidLogoutToken` for a malformed token or a failed claim
+    /// check.
+    pub fn validate(allocator: Allocator, raw: []const u8, keys: Keys, validation: Validation) !LogoutToken {
+        switch (keys) {
+            .set => |set| try set.verify(allocator, raw, validation.verification, validation.client_secret),
+            .cache => |cache| try cache.verify(raw, validation.verification, validation.client_secret),
+        }
+
+        var logout_token = try decode(allocator, raw);
+        errdefer logout_token.deinit();
+
+        try logout_token.checkClaims(validation);
+        return logout_token;
+    }
+
+    /// Decode the claims of `raw` without verifying the signature
+    ///
+    /// Already rejects tokens that cannot be logout tokens: ones without
+    /// the logout event, without `sub` and `sid`, or with a `nonce` (which
+    /// would let an ID token pass as a logout token).
+    pub fn decode(allocator: Allocator, raw: []const u8) !LogoutToken {
+        const parts = jwt.split(raw) catch return error.InvalidLogoutToken;
+        const payload = jwt.decodeSegment(allocator, parts.payload) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.InvalidLogoutToken,
+        };
+        defer allocator.free(payload);
+
+        const parsed = json.parseFromSlice(json.Value, allocator, payload, .{}) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.InvalidLogoutToken,
+        };
+        defer parsed.deinit();
+
+        if (parsed.value != .object) return error.InvalidLogoutToken;
+        const claims = parsed.value.object;
+
+        const event_set = claims.get("events") orelse return error.InvalidLogoutToken;
+        if (event_set != .object) return error.InvalidLogoutToken;
+        const event = event_set.object.get(logout_event) orelse return error.InvalidLogoutToken;
+        if (event != .object) return error.InvalidLogoutToken;
+        if (claims.get("nonce") != null) return error.InvalidLogoutToken;
+
+        const iss = stringClaim(claims, "iss") orelse return error.InvalidLogoutToken;
+        const jti = stringClaim(claims, "jti") orelse return error.InvalidLogoutToken;
+        const iat = timeClaim(claims, "iat") orelse return error.InvalidLogoutToken;
+        const sub = stringClaim(claims, "sub");
+        const sid = stringClaim(claims, "sid");
+        if (sub == null and sid == null) return error.InvalidLogoutToken;
+
+        var logout_token = LogoutToken{
+            .allocator = allocator,
+            .iss = try allocator.dupe(u8, iss),
+            .aud = &.{},
+            .iat = iat,
+            .exp = timeClaim(claims, "exp"),
+            .jti = &.{},
+        };
+        errdefer logout_token.deinit();
+
+        logout_token.jti = try allocator.dupe(u8, jti);
+        const aud = claims.get("aud") orelse return error.InvalidLogoutToken;
+        logout_token.aud = audienceClaim(allocator, aud) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.InvalidLogoutToken,
+        };
+        if (sub) |value| logout_token.sub = try allocator.dupe(u8, value);
+        if (sid) |value| logout_token.sid = try allocator.dupe(u8, value);
+
+        return logout_token;
+    }
+
+    pub fn deinit(self: *LogoutToken) void {
+        self.allocator.free(self.iss);
+        for (self.aud) |aud| self.allocator.free(aud);
+        self.allocator.free(self.aud);
+        self.allocator.free(self.jti);
+        if (self.sub) |sub| self.allocator.free(sub);
+        if (self.sid) |sid| self.allocator.free(sid);
+    }
+
+    /// Check `iss`, `aud`, `iat` and `exp` against `validation`
+    pub fn checkClaims(self: *const LogoutToken, validation: Validation) !void {
+        if (!std.mem.eql(u8, self.iss, validation.issuer)) return error.InvalidLogoutToken;
+        for (self.aud) |aud| {
+            if (std.mem.eql(u8, aud, validation.client_id)) break;
+        } else return error.InvalidLogoutToken;
+
+        const now = clock.now();
+        if (self.iat > now +| validation.leeway) return error.InvalidLogoutToken;
+        if (self.exp) |exp| {
+            if (now > exp +| validation.leeway) return error.InvalidLogoutToken;
+        }
+    }
+
+    /// Whether the session behind `id_token` is one this logout ends
+    ///
+    /// The ID token must come from the same issuer and match every
+    /// identifier the logout token carries.
+    pub fn matches(self: *const LogoutToken, id_token: *const IdToken) bool {
+        if (!std.mem.eql(u8, self.iss, id_token.iss)) return false;
+        if (self.sub) |sub| {
+            if (!std.mem.eql(u8, sub, id_token.sub)) return false;
+        }
+        if (self.sid) |sid| {
+            const session_id = id_token.sid orelse return false;
+            if (!std.mem.eql(u8, sid, session_id)) return false;
+        }
+        return true;
+    }
+};
+
+fn testToken(allocator: Allocator, claims_json: []const u8) ![]u8 {
+    const payload = try jwt.encodeSegment(allocator, claims_json);
+    defer allocator.free(payload);
+    return std.fmt.allocPrint(allocator, "eyJhbGciOiJFUzI1NiJ9.{s}.c2ln", .{payload});
+}
+
+test "LogoutToken.decode requires the logout event and a subject or session" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    const raw = try testToken(allocator,
+        \\{"iss":"https://id.example.com","aud":"client","iat":1700000000,"jti":"j1","sid":"s1",
+        \\ "events":{"http://schemas.openid.net/event/backchannel-logout":{}}}
+    );
+    defer allocator.free(raw);
+
+    var logout_token = try LogoutToken.decode(allocator, raw);
+    defer logout_token.deinit();
+    try std.testing.expectEqualStrings("s1", logout_token.sid.?);
+    try logout_token.checkClaims(.{ .issuer = "https://id.example.com", .client_id = "client" });
+    try std.testing.expectError(error.InvalidLogoutToken, logout_token.checkClaims(.{ .issuer = "https://id.example.com", .client_id = "other" }));
+
+    // Times written as floats decode like ID token times
+    const float_iat = try testToken(allocator,
+        \\{"iss":"https://id.example.com","aud":["client"],"iat":1700000000.0,"jti":"j2","sub":"u",
+        \\ "events":{"http://schemas.openid.net/event/backchannel-logout":{}}}
+    );
+    defer allocator.free(float_iat);
+    var float_token = try LogoutToken.decode(allocator, float_iat);
+    defer float_token.deinit();
+    try std.testing.expectEqual(@as(u64, 1_700_000_000), float_token.iat);
+
+    const invalid = [_][]const u8{
+        // No logout event
+        \\{"iss":"https://id.example.com","aud":"client","iat":1700000000,"jti":"j1","sid":"s1","events":{}}
+        ,
+        // Neither sub nor sid
+        \\{"iss":"https://id.example.com","aud":"client","iat":1700000000,"jti":"j1","events":{"http://schemas.openid.net/event/backchannel-logout":{}}}
+        ,
+        // An ID token, not a logout token
+        \\{"iss":"https://id.example.com","aud":"client","iat":1700000000,"jti":"j1","sub":"u","nonce":"n","events":{"http://schemas.openid.net/event/backchannel-logout":{}}}
+        ,
+    };
+    for (invalid) |claims| {
+        const token = try testToken(allocator, claims);
+        defer allocator.free(token);
+        try std.testing.expectError(error.InvalidLogoutToken, LogoutToken.decode(allocator, token));
+    }
+}
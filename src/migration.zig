@@ -0,0 +1,385 @@
+//! Copying tokens between storage backends
+//!
+//! `migrate()` moves every token from one `SessionStorage` to another, for
+//! example from plaintext FileStorage to KeyringStorage after an upgrade.
+//! The source must support listKeys(). Keys already present in the target
+//! are skipped or overwritten according to `Options.conflict`, and a dry
+//! run reports what would happen without writing anything.
+//!
+//! `exportTokens()` and `importTokens()` do the same across machines: the
+//! export is a versioned JSON bundle, encrypted with a passphrase-derived
+//! key (Argon2id, ChaCha20-Poly1305) when one is given.
+//!
+//! ## Example
+//!
+//! ```zig
+//! const report = try migrate(allocator, files.storage(), keyring.storage(), .{ .delete_source = true });
+//! std.log.info("moved {d} tokens, {d} already present", .{ report.copied, report.skipped });
+//!
+//! const bundle = try exportTokens(allocator, keyring.storage(), .{ .passphrase = passphrase });
+//! defer allocator.free(bundle);
+//! // ...on the new machine
+//! _ = try importTokens(allocator, keyring.storage(), bundle, .{ .passphrase = passphrase });
+//! ```
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+const Aead = std.crypto.aead.chacha_poly.ChaCha20Poly1305;
+const argon2 = std.crypto.pwhash.argon2;
+const base64 = std.base64.standard;
+
+const codec = @import("codec.zig");
+const session = @import("session.zig");
+
+const Token = session.Token;
+const SessionStorage = session.SessionStorage;
+const MemoryStorage = session.MemoryStorage;
+const FileStorage = session.FileStorage;
+
+/// What to do with a key the target already holds
+pub const ConflictPolicy = enum {
+    /// Keep the target's token
+    skip,
+    /// Replace it with the source's token
+    overwrite,
+};
+
+pub const Options = struct {
+    conflict: ConflictPolicy = .skip,
+    /// Count what would be copied without writing to either storage
+    dry_run: bool = false,
+    /// Delete each token from the source once the target holds it
+    delete_source: bool = false,
+};
+
+/// Number of tokens per outcome
+pub const Report = struct {
+    copied: usize = 0,
+    /// Left alone because the target already had the key
+    skipped: usize = 0,
+    /// Listed by the source but gone by the time it was read
+    missing: usize = 0,
+    /// Found in the source without a secret to save (importers only)
+    incomplete: usize = 0,
+};
+
+/// Copy every token in `from` to `to`
+///
+/// Stops at the first failing read or write; tokens copied until then stay
+/// in the target (and, with `delete_source`, leave the source).
+pub fn migrate(allocator: Allocator, from: SessionStorage, to: SessionStorage, options: Options) !Report {
+    const keys = try from.listKeys(allocator);
+    defer SessionStorage.freeKeys(allocator, keys);
+
+    var report: Report = .{};
+    for (keys) |key| {
+        if (options.conflict == .skip and to.exists(key)) {
+            report.skipped += 1;
+            continue;
+        }
+
+        var token = (try from.load(allocator, key)) orelse {
+            report.missing += 1;
+            continue;
+        };
+        defer token.deinit();
+
+        report.copied += 1;
+        if (options.dry_run) continue;
+
+        try to.save(key, token);
+        if (options.delete_source) try from.delete(key);
+    }
+    return report;
+}
+
+/// Highest Argon2id costs accepted in a bundle: 16 passes, 1 GiB and 16
+/// lanes, well above default_kdf_params
+pub const max_kdf_params: argon2.Params = .{ .t = 16, .m = 1024 * 1024, .p = 16 };
+
+/// `format` field of every bundle
+pub const bundle_format = "schlussel-tokens";
+/// Newest bundle version this build reads and the one it writes
+pub const bundle_version = 1;
+
+pub const ExportOptions = struct {
+    /// Encrypt the bundle with a key derived from this passphrase
+    passphrase: ?[]const u8 = null,
+    kdf_params: argon2.Params = session.FileStorage.default_kdf_params,
+    /// Export only these keys (all listed keys when null)
+    keys: ?[]const []const u8 = null,
+};
+
+pub const ImportOptions = struct {
+    /// Needed for encrypted bundles
+    passphrase: ?[]const u8 = null,
+    conflict: ConflictPolicy = .skip,
+    /// Count what would be imported without writing to the storage
+    dry_run: bool = false,
+};
+
+const BundleEntry = struct {
+    key: []const u8,
+    /// The token in the storage codec's encoding
+    token: []const u8,
+};
+
+const BundleKdf = struct {
+    algorithm: []const u8,
+    salt: []const u8,
+    t: u32,
+    m: u32,
+    p: u24,
+};
+
+const Bundle = struct {
+    format: []const u8,
+    version: u32,
+    /// Plaintext bundles
+    tokens: ?[]const BundleEntry = null,
+    /// Encrypted bundles: `tokens` as JSON, sealed under the derived key
+    kdf: ?BundleKdf = null,
+    nonce: ?[]const u8 = null,
+    ciphertext: ?[]const u8 = null,
+};
+
+/// Bytes authenticated along with an encrypted bundle
+const bundle_ad = bundle_format ++ "/1";
+const salt_length = 16;
+
+/// Serialize the tokens in `from` as a bundle for importTokens()
+///
+/// Without `keys`, the storage must support listKeys(). Caller owns the
+/// returned bytes; they hold the tokens in plaintext unless a passphrase
+/// is given.
+pub fn exportTokens(allocator: Allocator, from: SessionStorage, options: ExportOptions) ![]u8 {
+    const listed = if (options.keys == null) try from.listKeys(allocator) else null;
+    defer if (listed) |keys| SessionStorage.freeKeys(allocator, keys);
+    const keys = options.keys orelse listed.?;
+
+    var entries: std.ArrayListUnmanaged(BundleEntry) = .{};
+    defer {
+        for (entries.items) |entry| {
+            std.crypto.secureZero(u8, @constCast(entry.token));
+            allocator.free(entry.token);
+        }
+        entries.deinit(allocator);
+    }
+    for (keys) |key| {
+        var token = (try from.load(allocator, key)) orelse continue;
+        defer token.deinit();
+
+        const data = try codec.default.encode(allocator, &token);
+        errdefer allocator.free(data);
+        try entries.append(allocator, .{ .key = key, .token = data });
+    }
+
+    const passphrase = options.passphrase orelse {
+        return json.Stringify.valueAlloc(allocator, Bundle{
+            .format = bundle_format,
+            .version = bundle_version,
+            .tokens = entries.items,
+        }, .{ .emit_null_optional_fields = false });
+    };
+
+    const plaintext = try json.Stringify.valueAlloc(allocator, entries.items, .{});
+    defer {
+        std.crypto.secureZero(u8, plaintext);
+        allocator.free(plaintext);
+    }
+
+    var salt: [salt_length]u8 = undefined;
+    std.crypto.random.bytes(&salt);
+    // Exports stay importable
+    try checkKdfParams(options.kdf_params);
+    var key = try FileStorage.deriveKey(allocator, passphrase, &salt, options.kdf_params);
+    defer std.crypto.secureZero(u8, &key);
+
+    var nonce: [Aead.nonce_length]u8 = undefined;
+    std.crypto.random.bytes(&nonce);
+    const sealed = try allocator.alloc(u8, plaintext.len + Aead.tag_length);
+    defer allocator.free(sealed);
+    Aead.encrypt(sealed[0..plaintext.len], sealed[plaintext.len..][0..Aead.tag_length], plaintext, bundle_ad, nonce, key);
+
+    const salt_b64 = try encodeBase64(allocator, &salt);
+    defer allocator.free(salt_b64);
+    const nonce_b64 = try encodeBase64(allocator, &nonce);
+    defer allocator.free(nonce_b64);
+    const ciphertext_b64 = try encodeBase64(allocator, sealed);
+    defer allocator.free(ciphertext_b64);
+
+    return json.Stringify.valueAlloc(allocator, Bundle{
+        .format = bundle_format,
+        .version = bundle_version,
+        .kdf = .{
+            .algorithm = "argon2id",
+            .salt = salt_b64,
+            .t = options.kdf_params.t,
+            .m = options.kdf_params.m,
+            .p = options.kdf_params.p,
+        },
+        .nonce = nonce_b64,
+        .ciphertext = ciphertext_b64,
+    }, .{ .emit_null_optional_fields = false });
+}
+
+/// Save the tokens of a bundle from exportTokens() into `to`
+///
+/// Returns `error.InvalidParameter` for data that is not a bundle or an
+/// encrypted bundle without a passphrase, `error.UnsupportedOperation` for
+/// bundles from a newer version, and `error.DecryptionFailed` for a wrong
+/// passphrase or tampered bundle.
+pub fn importTokens(allocator: Allocator, to: SessionStorage, data: []const u8, options: ImportOptions) !Report {
+    const parsed = json.parseFromSlice(Bundle, allocator, data, .{ .ignore_unknown_fields = true }) catch return error.InvalidParameter;
+    defer parsed.deinit();
+    const bundle = parsed.value;
+
+    if (!std.mem.eql(u8, bundle.format, bundle_format)) return error.InvalidParameter;
+    if (bundle.version > bundle_version) return error.UnsupportedOperation;
+
+    if (bundle.tokens) |entries| return importEntries(allocator, to, entries, options);
+
+    const kdf = bundle.kdf orelse return error.InvalidParameter;
+    if (!std.mem.eql(u8, kdf.algorithm, "argon2id")) return error.UnsupportedOperation;
+    const passphrase = options.passphrase orelse return error.InvalidParameter;
+
+    const salt = try decodeBase64(allocator, kdf.salt);
+    defer allocator.free(salt);
+    const nonce = try decodeBase64(allocator, bundle.nonce orelse return error.InvalidParameter);
+    defer allocator.free(nonce);
+    const sealed = try decodeBase64(allocator, bundle.ciphertext orelse return error.InvalidParameter);
+    defer allocator.free(sealed);
+    if (nonce.len != Aead.nonce_length or sealed.len < Aead.tag_length) return error.InvalidParameter;
+
+    // The parameters come from the bundle: bound the memory and time a
+    // crafted one can make the import spend
+    const params: argon2.Params = .{ .t = kdf.t, .m = kdf.m, .p = kdf.p };
+    try checkKdfParams(params);
+    var key = try FileStorage.deriveKey(allocator, passphrase, salt, params);
+    defer std.crypto.secureZero(u8, &key);
+
+    const ciphertext_len = sealed.len - Aead.tag_length;
+    const plaintext = try allocator.alloc(u8, ciphertext_len);
+    defer {
+        std.crypto.secureZero(u8, plaintext);
+        allocator.free(plaintext);
+    }
+    Aead.decrypt(plaintext, sealed[0..ciphertext_len], sealed[ciphertext_len..][0..Aead.tag_length].*, bundle_ad, nonce[0..Aead.nonce_length].*, key) catch return error.DecryptionFailed;
+
+    const entries = json.parseFromSlice([]const BundleEntry, allocator, plaintext, .{}) catch return error.InvalidParameter;
+    defer entries.deinit();
+    return importEntries(allocator, to, entries.value, options);
+}
+
+fn importEntries(allocator: Allocator, to: SessionStorage, entries: []const BundleEntry, options: ImportOptions) !Report {
+    var report: Report = .{};
+    for (entries) |entry| {
+        if (options.conflict == .skip and to.exists(entry.key)) {
+            report.skipped += 1;
+            continue;
+        }
+
+        var token = try codec.default.decode(allocator, entry.token);
+        defer token.deinit();
+
+        report.copied += 1;
+        if (!options.dry_run) try to.save(entry.key, token);
+    }
+    return report;
+}
+
+/// Reject KDF costs above max_kdf_params
+fn checkKdfParams(params: argon2.Params) !void {
+    if (params.t > max_kdf_params.t or params.m > max_kdf_params.m or params.p > max_kdf_params.p) {
+        return error.InvalidParameter;
+    }
+}
+
+fn encodeBase64(allocator: Allocator, bytes: []const u8) ![]u8 {
+    const out = try allocator.alloc(u8, base64.Encoder.calcSize(bytes.len));
+    _ = base64.Encoder.encode(out, bytes);
+    return out;
+}
+
+fn decodeBase64(allocator: Allocator, text: []const u8) ![]u8 {
+    const len = base64.Decoder.calcSizeForSlice(text) catch return error.InvalidParameter;
+    const out = try allocator.alloc(u8, len);
+    errdefer allocator.free(out);
+    base64.Decoder.decode(out, text) catch return error.InvalidParameter;
+    return out;
+}
+
+test "migrate copies tokens and honours the conflict policy" {
+    const allocator = std.testing.allocator;
+
+    var from = MemoryStorage.init(allocator);
+    defer from.deinit();
+    var to = MemoryStorage.init(allocator);
+    defer to.deinit();
+
+    var old = try Token.init(allocator, "old", "Bearer");
+    defer old.deinit();
+    var existing = try Token.init(allocator, "existing", "Bearer");
+    defer existing.deinit();
+    try from.storage().save("github", old);
+    try from.storage().save("gitlab", old);
+    try to.storage().save("gitlab", existing);
+
+    const planned = try migrate(allocator, from.storage(), to.storage(), .{ .dry_run = true });
+    try std.testing.expectEqual(@as(usize, 1), planned.copied);
+    try std.testing.expectEqual(@as(usize, 1), planned.skipped);
+    try std.testing.expect(!to.storage().exists("github"));
+
+    const report = try migrate(allocator, from.storage(), to.storage(), .{ .delete_source = true });
+    try std.testing.expectEqual(@as(usize, 1), report.copied);
+    try std.testing.expect(to.storage().exists("github"));
+    try std.testing.expect(!from.storage().exists("github"));
+    // Skipped tokens stay in the source
+    try std.testing.expect(from.storage().exists("gitlab"));
+
+    _ = try migrate(allocator, from.storage(), to.storage(), .{ .conflict = .overwrite });
+    var replaced = (try to.storage().load(allocator, "gitlab")).?;
+    defer replaced.deinit();
+    try std.testing.expectEqualStrings("old", replaced.access_token);
+}
+
+test "exportTokens and importTokens move tokens through an encrypted bundle" {
+    const allocator = std.testing.allocator;
+    const fast_kdf: argon2.Params = .{ .t = 1, .m = 64, .p = 1 };
+
+    var laptop = MemoryStorage.init(allocator);
+    defer laptop.deinit();
+    var token = try Token.initFull(allocator, "access", "Bearer", "refresh", 3600, "repo", null);
+    defer token.deinit();
+    try laptop.storage().save("github", token);
+
+    const bundle = try exportTokens(allocator, laptop.storage(), .{ .passphrase = "correct horse", .kdf_params = fast_kdf });
+    defer allocator.free(bundle);
+    try std.testing.expect(std.mem.indexOf(u8, bundle, "refresh") == null);
+
+    var new_laptop = MemoryStorage.init(allocator);
+    defer new_laptop.deinit();
+    try std.testing.expectError(error.DecryptionFailed, importTokens(allocator, new_laptop.storage(), bundle, .{ .passphrase = "wrong" }));
+    try std.testing.expectError(error.InvalidParameter, importTokens(allocator, new_laptop.storage(), bundle, .{}));
+
+    const report = try importTokens(allocator, new_laptop.storage(), bundle, .{ .passphrase = "correct horse" });
+    try std.testing.expectEqual(@as(usize, 1), report.copied);
+    var imported = (try new_laptop.storage().load(allocator, "github")).?;
+    defer imported.deinit();
+    try std.testing.expectEqualStrings("refresh", imported.refresh_token.?);
+
+    // A bundle demanding absurd KDF costs is refused before deriving
+    const greedy = try std.mem.replaceOwned(u8, allocator, bundle, "\"m\":64", "\"m\":4000000000");
+    defer allocator.free(greedy);
+    try std.testing.expect(!std.mem.eql(u8, greedy, bundle));
+    try std.testing.expectError(error.InvalidParameter, importTokens(allocator, new_laptop.storage(), greedy, .{ .passphrase = "correct horse" }));
+
+    // Plaintext bundles round-trip too; existing keys are skipped by default
+    const plain = try exportTokens(allocator, laptop.storage(), .{});
+    defer allocator.free(plain);
+    const again = try importTokens(allocator, new_laptop.storage(), plain, .{});
+    try std.testing.expectEqual(@as(usize, 1), again.skipped);
+
+    try std.testing.expectError(error.UnsupportedOperation, importTokens(allocator, new_laptop.storage(), "{\"format\":\"schlussel-tokens\",\"version\":2}", .{}));
+}
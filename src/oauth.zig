@@ -25,17 +25,70 @@ const http = std.http;
 const Uri = std.Uri;
 const Allocator = std.mem.Allocator;
 
+const build_options = @import("build_options");
 const pkce = @import("pkce.zig");
 const session = @import("session.zig");
 const callback = @import("callback.zig");
 const lock = @import("lock.zig");
 const formulas = @import("formulas.zig");
+const transport = @import("transport.zig");
+const clock = @import("clock.zig");
+const jwt = @import("jwt.zig");
+const jwks = @import("jwks.zig");
+const IdToken = @import("id_token.zig").IdToken;
+const constantTimeEql = @import("id_token.zig").constantTimeEql;
+const timeClaim = @import("id_token.zig").timeClaim;
+const LogoutToken = @import("logout_token.zig").LogoutToken;
+const UserInfo = @import("oidc.zig").UserInfo;
+const events = @import("events.zig");
+const dpop = @import("dpop.zig");
+const discovery = @import("discovery.zig");
+const ciba = @import("ciba.zig");
+const error_types = @import("error.zig");
+const jarm = @import("jarm.zig");
+const jar = @import("jar.zig");
 
 const Token = session.Token;
+const AuthorizationParam = session.AuthorizationParam;
 const SessionStorage = session.SessionStorage;
 const Pkce = pkce.Pkce;
 const CallbackServer = callback.CallbackServer;
+const CallbackResult = callback.CallbackResult;
 const RefreshLockManager = lock.RefreshLockManager;
+const HttpTransport = transport.HttpTransport;
+const HttpResponse = transport.Response;
+
+/// How the client authenticates to the authorization server
+///
+/// Applies to token, PAR, introspection and revocation requests.
+pub const ClientAuth = union(enum) {
+    /// `client_secret` in the form body when one is configured; public
+    /// clients send only `client_id`
+    client_secret_post,
+    /// `client_id` and `client_secret` in an HTTP Basic `Authorization`
+    /// header (RFC 6749 Section 2.3.1)
+    client_secret_basic,
+    /// A JWT signed with the client's own key (RFC 7523 Section 2.2)
+    ///
+    /// The assertion is issued by and about `client_id`, addressed to the
+    /// token endpoint, and re-signed with a fresh `jti` for every request.
+    private_key_jwt: PrivateKeyJwt,
+
+    pub const PrivateKeyJwt = struct {
+        key: jwt.SigningKey,
+        /// ID of the public key registered with the server, sent as `kid`
+        kid: ?[]const u8 = null,
+    };
+};
+
+/// Protocol profile a client enforces
+pub const ComplianceMode = enum {
+    /// OAuth 2.0 with whatever the config and build enable
+    standard,
+    /// OAuth 2.1: PKCE with S256 only, no implicit or password grants,
+    /// exact redirect URI matching and no bearer tokens in query strings
+    oauth21,
+};
 
 /// OAuth 2.0 configuration
 pub const OAuthConfig = struct {
@@ -53,6 +106,79 @@ pub const OAuthConfig = struct {
     scope: ?[]const u8 = null,
     /// Device authorization endpoint (for Device Code Flow)
     device_authorization_endpoint: ?[]const u8 = null,
+    /// Pushed authorization request endpoint (RFC 9126)
+    ///
+    /// When set, authorization requests are posted here first and the
+    /// browser URL only carries the returned `request_uri`.
+    pushed_authorization_request_endpoint: ?[]const u8 = null,
+    /// Token revocation endpoint (RFC 7009)
+    revocation_endpoint: ?[]const u8 = null,
+    /// Token introspection endpoint (RFC 7662)
+    introspection_endpoint: ?[]const u8 = null,
+    /// Backchannel authentication endpoint (OpenID Connect CIBA)
+    backchannel_authentication_endpoint: ?[]const u8 = null,
+    /// Issuer identifier, checked against the `iss` of ID tokens (OpenID Connect)
+    ///
+    /// When set, every `id_token` in a token response is validated against
+    /// the issuer and the keys at `jwks_uri` before the token is returned.
+    issuer: ?[]const u8 = null,
+    /// JWK Set with the provider's signing keys (OpenID Connect)
+    jwks_uri: ?[]const u8 = null,
+    /// UserInfo endpoint (OpenID Connect)
+    userinfo_endpoint: ?[]const u8 = null,
+    /// RP-initiated logout endpoint (OpenID Connect)
+    end_session_endpoint: ?[]const u8 = null,
+    /// Client certificate for mutual TLS (RFC 8705)
+    ///
+    /// Presented on every request to the authorization server. The client
+    /// then authenticates with the certificate (`tls_client_auth`) instead
+    /// of `client_secret`, and the server may bind issued tokens to it; use
+    /// the same certificate for resource requests with such tokens. JWT
+    /// access tokens whose `cnf.x5t#S256` names another certificate are
+    /// rejected with `error.CertificateMismatch`.
+    ///
+    /// Only the OAuth side of mutual TLS is implemented here. The TLS
+    /// handshake with the certificate is left to a custom HttpTransport:
+    /// StdTransport cannot present client certificates and fails such
+    /// requests with `error.UnsupportedOperation` (see transport.zig).
+    client_certificate: ?transport.ClientCertificate = null,
+    /// Client authentication method; ignored when `client_certificate` is set
+    client_auth: ClientAuth = .client_secret_post,
+    /// Audience of requested access tokens (Auth0, Okta)
+    ///
+    /// Sent as `audience` with authorization, device, client credentials
+    /// and refresh requests, and recorded on issued tokens. Per-call
+    /// audiences override it (see AuthorizationOptions.audience and
+    /// clientCredentialsWithAudience()); their tokens are stored apart
+    /// (see OAuthClient.saveTokenForAudience()).
+    audience: ?[]const u8 = null,
+    /// Authorization response mode; sent as `response_mode` when set
+    ///
+    /// The JWT modes (JARM) make the provider sign the response. Callbacks
+    /// must then carry a `response` parameter, which authorize() verifies
+    /// against `issuer` and the provider keys.
+    response_mode: ?jarm.ResponseMode = null,
+    /// Sign authorization requests into a `request` object (RFC 9101)
+    ///
+    /// The browser URL then carries only `client_id` and the signed
+    /// parameters, or the `request_uri` of the pushed object when
+    /// `pushed_authorization_request_endpoint` is set. The object is
+    /// addressed to `issuer`, falling back to the authorization endpoint.
+    request_signing: ?jar.RequestSigning = null,
+    /// Protocol profile to enforce
+    ///
+    /// With `.oauth21`, validate() rejects endpoints that carry an
+    /// `access_token` query parameter and redirect URIs with wildcards or
+    /// fragments, startAuthorization() only accepts `redirect_uri` itself
+    /// (or the same loopback URI on another port) and passwordGrant()
+    /// returns `error.UnsupportedOperation`. Authorization requests always
+    /// use the code flow; the `plain` PKCE method is a configuration error.
+    compliance: ComplianceMode = .standard,
+    /// PKCE verifier length and challenge method
+    ///
+    /// Defaults to 32 random bytes and S256. Configs built from discovery
+    /// negotiate the method from `code_challenge_methods_supported`.
+    pkce: pkce.Options = .{},
 
     /// Validate that OAuth endpoints use HTTPS (except localhost)
     pub fn validate(self: *const OAuthConfig) !void {
@@ -61,6 +187,118 @@ pub const OAuthConfig = struct {
         if (self.device_authorization_endpoint) |endpoint| {
             try validateEndpointSecurity(endpoint);
         }
+        inline for (.{
+            self.pushed_authorization_request_endpoint,
+            self.revocation_endpoint,
+            self.introspection_endpoint,
+            self.backchannel_authentication_endpoint,
+            self.jwks_uri,
+            self.userinfo_endpoint,
+            self.end_session_endpoint,
+        }) |optional_endpoint| {
+            if (optional_endpoint) |endpoint| try validateEndpointSecurity(endpoint);
+        }
+        if (self.compliance == .oauth21) try self.validateOAuth21();
+    }
+
+    /// The OAuth 2.1 invariants that can be checked on the config alone
+    fn validateOAuth21(self: *const OAuthConfig) !void {
+        if (self.pkce.method != .s256) return error.ConfigurationError;
+        if (std.mem.indexOfAny(u8, self.redirect_uri, "#*") != null) return error.ConfigurationError;
+
+        inline for (.{
+            self.authorization_endpoint,
+            self.token_endpoint,
+            self.device_authorization_endpoint,
+            self.pushed_authorization_request_endpoint,
+            self.revocation_endpoint,
+            self.introspection_endpoint,
+            self.backchannel_authentication_endpoint,
+            self.jwks_uri,
+            self.userinfo_endpoint,
+            self.end_session_endpoint,
+        }) |endpoint| {
+            const url: ?[]const u8 = endpoint;
+            if (url) |value| {
+                if (hasQueryParam(value, "access_token")) return error.ConfigurationError;
+            }
+        }
+    }
+
+    /// Check that `redirect_uri` exactly matches the configured one
+    ///
+    /// Loopback URIs may differ in the port only, since native apps bind
+    /// an ephemeral port for each flow (RFC 8252 Section 7.3). Returns
+    /// `error.UnregisteredRedirectUri` otherwise.
+    pub fn matchRedirectUri(self: *const OAuthConfig, redirect_uri: []const u8) !void {
+        if (std.mem.eql(u8, redirect_uri, self.redirect_uri)) return;
+
+        const expected = splitLoopbackUri(self.redirect_uri) orelse return error.UnregisteredRedirectUri;
+        const actual = splitLoopbackUri(redirect_uri) orelse return error.UnregisteredRedirectUri;
+        if (!std.mem.eql(u8, expected.host, actual.host) or !std.mem.eql(u8, expected.rest, actual.rest)) {
+            return error.UnregisteredRedirectUri;
+        }
+    }
+
+    /// Configure a client from the issuer's metadata (RFC 8414)
+    ///
+    /// Fetches `/.well-known/oauth-authorization-server`, checks that it
+    /// was published for `issuer_url`, and fills in every endpoint it
+    /// lists. The caller owns the returned config and must call deinit().
+    /// Use discovery.discover() to fetch through a custom transport.
+    pub fn discover(
+        allocator: Allocator,
+        issuer_url: []const u8,
+        client_id: []const u8,
+        redirect_uri: []const u8,
+        scope: ?[]const u8,
+    ) !OAuthConfigOwned {
+        var metadata = try discovery.discover(allocator, transport.defaultTransport(), issuer_url);
+        defer metadata.deinit();
+
+        return metadata.toConfig(allocator, client_id, redirect_uri, scope);
+    }
+
+    /// Check `redirect_uri` against the URIs permitted by a metadata document
+    ///
+    /// `metadata_json` is a client registration or discovery document. When it
+    /// lists `redirect_uris`, the configured URI must match one of them exactly,
+    /// otherwise `error.UnregisteredRedirectUri` is returned. Documents without
+    /// a `redirect_uris` list are accepted as-is.
+    ///
+    /// On mismatch, if `message` is non-null it receives a description listing
+    /// the allowed URIs. Caller owns the message.
+    pub fn validateAgainstMetadata(
+        self: *const OAuthConfig,
+        allocator: Allocator,
+        metadata_json: []const u8,
+        message: ?*?[]u8,
+    ) !void {
+        const parsed = json.parseFromSlice(json.Value, allocator, metadata_json, .{}) catch return error.JsonError;
+        defer parsed.deinit();
+
+        if (parsed.value != .object) return error.JsonError;
+        const uris = parsed.value.object.get("redirect_uris") orelse return;
+        if (uris != .array) return error.JsonError;
+
+        for (uris.array.items) |uri| {
+            if (uri != .string) return error.JsonError;
+            if (std.mem.eql(u8, uri.string, self.redirect_uri)) return;
+        }
+
+        if (message) |out| {
+            var buf: std.ArrayListUnmanaged(u8) = .{};
+            errdefer buf.deinit(allocator);
+            const writer = buf.writer(allocator);
+
+            try writer.print("redirect_uri \"{s}\" is not registered; allowed:", .{self.redirect_uri});
+            if (uris.array.items.len == 0) try writer.writeAll(" (none)");
+            for (uris.array.items, 0..) |uri, i| {
+                try writer.print("{s} {s}", .{ if (i == 0) "" else ",", uri.string });
+            }
+            out.* = try buf.toOwnedSlice(allocator);
+        }
+        return error.UnregisteredRedirectUri;
     }
 
     /// Create configuration for GitHub
@@ -84,6 +322,9 @@ pub const OAuthConfig = struct {
             .device_authorization_endpoint = "https://oauth2.googleapis.com/device/code",
             .redirect_uri = "http://127.0.0.1/callback",
             .scope = scope,
+            .issuer = "https://accounts.google.com",
+            .jwks_uri = "https://www.googleapis.com/oauth2/v3/certs",
+            .userinfo_endpoint = "https://openidconnect.googleapis.com/v1/userinfo",
         };
     }
 
@@ -251,6 +492,15 @@ pub const OAuthConfigOwned = struct {
     redirect_uri: []const u8,
     scope: ?[]const u8 = null,
     device_authorization_endpoint: ?[]const u8 = null,
+    pushed_authorization_request_endpoint: ?[]const u8 = null,
+    revocation_endpoint: ?[]const u8 = null,
+    introspection_endpoint: ?[]const u8 = null,
+    backchannel_authentication_endpoint: ?[]const u8 = null,
+    issuer: ?[]const u8 = null,
+    jwks_uri: ?[]const u8 = null,
+    userinfo_endpoint: ?[]const u8 = null,
+    end_session_endpoint: ?[]const u8 = null,
+    pkce: pkce.Options = .{},
 
     pub fn deinit(self: *OAuthConfigOwned) void {
         self.allocator.free(self.client_id);
@@ -259,6 +509,14 @@ pub const OAuthConfigOwned = struct {
         self.allocator.free(self.redirect_uri);
         if (self.scope) |s| self.allocator.free(s);
         if (self.device_authorization_endpoint) |e| self.allocator.free(e);
+        if (self.pushed_authorization_request_endpoint) |e| self.allocator.free(e);
+        if (self.revocation_endpoint) |e| self.allocator.free(e);
+        if (self.introspection_endpoint) |e| self.allocator.free(e);
+        if (self.backchannel_authentication_endpoint) |e| self.allocator.free(e);
+        if (self.issuer) |e| self.allocator.free(e);
+        if (self.jwks_uri) |e| self.allocator.free(e);
+        if (self.userinfo_endpoint) |e| self.allocator.free(e);
+        if (self.end_session_endpoint) |e| self.allocator.free(e);
         if (self.client_secret) |s| self.allocator.free(s);
     }
 
@@ -272,6 +530,15 @@ pub const OAuthConfigOwned = struct {
             .redirect_uri = self.redirect_uri,
             .scope = self.scope,
             .device_authorization_endpoint = self.device_authorization_endpoint,
+            .pushed_authorization_request_endpoint = self.pushed_authorization_request_endpoint,
+            .revocation_endpoint = self.revocation_endpoint,
+            .introspection_endpoint = self.introspection_endpoint,
+            .backchannel_authentication_endpoint = self.backchannel_authentication_endpoint,
+            .issuer = self.issuer,
+            .jwks_uri = self.jwks_uri,
+            .userinfo_endpoint = self.userinfo_endpoint,
+            .end_session_endpoint = self.end_session_endpoint,
+            .pkce = self.pkce,
         };
     }
 };
@@ -329,7 +596,7 @@ pub fn configFromFormula(
 }
 
 /// Validate that an endpoint URL uses HTTPS (or is localhost for development)
-fn validateEndpointSecurity(url: []const u8) !void {
+pub fn validateEndpointSecurity(url: []const u8) !void {
     // Allow HTTPS
     if (std.mem.startsWith(u8, url, "https://")) return;
 
@@ -344,6 +611,46 @@ fn validateEndpointSecurity(url: []const u8) !void {
     return error.InsecureEndpoint;
 }
 
+/// A loopback redirect URI without its port
+const LoopbackUri = struct {
+    host: []const u8,
+    /// Path and query after the port
+    rest: []const u8,
+};
+
+fn splitLoopbackUri(uri: []const u8) ?LoopbackUri {
+    inline for (.{ "http://127.0.0.1", "http://[::1]", "http://localhost" }) |host| {
+        if (std.mem.startsWith(u8, uri, host)) {
+            const after = uri[host.len..];
+            if (after.len == 0 or after[0] != ':') {
+                if (after.len > 0 and after[0] != '/' and after[0] != '?') return null;
+                return .{ .host = host, .rest = after };
+            }
+            const port_end = std.mem.indexOfAnyPos(u8, after, 1, "/?") orelse after.len;
+            if (port_end == 1) return null;
+            for (after[1..port_end]) |c| {
+                if (!std.ascii.isDigit(c)) return null;
+            }
+            return .{ .host = host, .rest = after[port_end..] };
+        }
+    }
+    return null;
+}
+
+/// Whether the query of `url` has a parameter called `name`
+fn hasQueryParam(url: []const u8, name: []const u8) bool {
+    const query_start = std.mem.indexOfScalar(u8, url, '?') orelse return false;
+    const end = std.mem.indexOfScalar(u8, url, '#') orelse url.len;
+    if (query_start > end) return false;
+
+    var pairs = std.mem.splitScalar(u8, url[query_start + 1 .. end], '&');
+    while (pairs.next()) |pair| {
+        const eq = std.mem.indexOfScalar(u8, pair, '=') orelse pair.len;
+        if (std.mem.eql(u8, pair[0..eq], name)) return true;
+    }
+    return false;
+}
+
 /// Device authorization response from RFC 8628
 pub const DeviceAuthorizationResponse = struct {
     allocator: Allocator,
@@ -370,6 +677,205 @@ pub const DeviceAuthorizationResponse = struct {
     }
 };
 
+/// A pending device authorization (RFC 8628) started by startDeviceFlow()
+pub const DeviceFlow = struct {
+    client: *OAuthClient,
+    /// Codes and verification URI to show the user
+    device: DeviceAuthorizationResponse,
+    /// Seconds to wait between polls (raised when the server answers `slow_down`)
+    interval: u64,
+    /// Unix time at which the device code lapses
+    expires_at: u64,
+
+    pub fn deinit(self: *DeviceFlow) void {
+        self.device.deinit();
+    }
+
+    /// Ask the token endpoint once whether the user has approved
+    ///
+    /// Returns null while authorization is pending; wait `interval` seconds
+    /// before polling again. Returns `error.AuthorizationDenied` if the user
+    /// declines and `error.DeviceCodeExpired` once the code lapses.
+    pub fn poll(self: *DeviceFlow) !?Token {
+        if (clock.now() >= self.expires_at) return error.DeviceCodeExpired;
+        return self.client.pollDeviceCodeOnce(self.device.device_code, &self.interval);
+    }
+
+    /// Poll until the user approves, declines or the code lapses
+    pub fn wait(self: *DeviceFlow) !Token {
+        while (true) {
+            if (try self.poll()) |token| return token;
+            if (self.interval > 0) std.Thread.sleep(self.interval * std.time.ns_per_s);
+        }
+    }
+};
+
+/// A pending backchannel authentication (CIBA) started by startBackchannelAuth()
+pub const BackchannelFlow = struct {
+    client: *OAuthClient,
+    /// The provider's answer, including `auth_req_id`
+    response: ciba.BackchannelAuthResponse,
+    delivery_mode: ciba.DeliveryMode,
+    /// Bearer token the provider presents on ping callbacks
+    client_notification_token: [notification_token_len]u8,
+    /// Seconds to wait between polls (raised when the server answers `slow_down`)
+    interval: u64,
+    /// Unix time at which the request lapses
+    expires_at: u64,
+
+    const notification_token_len = 43;
+
+    pub fn deinit(self: *BackchannelFlow) void {
+        self.response.deinit();
+    }
+
+    /// Ask the token endpoint once whether the user has responded
+    ///
+    /// Returns null while authorization is pending; wait `interval` seconds
+    /// before polling again. Returns `error.AuthorizationDenied` if the user
+    /// declines and `error.AuthRequestExpired` once the request lapses.
+    pub fn poll(self: *BackchannelFlow) !?Token {
+        if (clock.now() >= self.expires_at) return error.AuthRequestExpired;
+
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer body.deinit(self.client.allocator);
+
+        try body.appendSlice(self.client.allocator, "grant_type=" ++ ciba.grant_type);
+        try body.appendSlice(self.client.allocator, "&auth_req_id=");
+        try appendUrlEncoded(self.client.allocator, &body, self.response.auth_req_id);
+        try self.client.appendClientAuth(&body);
+
+        return self.client.pollTokenEndpoint(body.items, &self.interval) catch |err| switch (err) {
+            error.DeviceCodeExpired => return error.AuthRequestExpired,
+            else => return err,
+        };
+    }
+
+    /// Poll until the user responds or the request lapses, and save the token under `key`
+    pub fn wait(self: *BackchannelFlow, key: []const u8) !Token {
+        var token = while (true) {
+            if (try self.poll()) |polled| break polled;
+            if (self.interval > 0) std.Thread.sleep(self.interval * std.time.ns_per_s);
+        };
+        errdefer token.deinit();
+
+        try self.client.saveTokenForAudience(key, token);
+        return token;
+    }
+
+    /// Handle the provider's ping callback and fetch the token (ping mode)
+    ///
+    /// Pass the callback's `Authorization` header and body. Returns
+    /// `error.InvalidParameter` if the callback does not carry this flow's
+    /// notification token and request ID; the token is saved under `key`.
+    pub fn handlePing(self: *BackchannelFlow, key: []const u8, authorization: ?[]const u8, body: []const u8) !Token {
+        if (self.delivery_mode != .ping) return error.UnsupportedOperation;
+        try ciba.verifyPing(self.client.allocator, authorization, body, &self.client_notification_token, self.response.auth_req_id);
+
+        var token = (try self.poll()) orelse return error.AuthorizationPending;
+        errdefer token.deinit();
+
+        try self.client.saveTokenForAudience(key, token);
+        return token;
+    }
+};
+
+// Token type identifiers for token exchange (RFC 8693 Section 3)
+pub const token_type_access_token = "urn:ietf:params:oauth:token-type:access_token";
+pub const token_type_refresh_token = "urn:ietf:params:oauth:token-type:refresh_token";
+pub const token_type_id_token = "urn:ietf:params:oauth:token-type:id_token";
+pub const token_type_jwt = "urn:ietf:params:oauth:token-type:jwt";
+pub const token_type_saml2 = "urn:ietf:params:oauth:token-type:saml2";
+
+/// Parameters of a token exchange request (RFC 8693 Section 2.1)
+pub const TokenExchangeRequest = struct {
+    /// Token representing the party on whose behalf the request is made
+    subject_token: []const u8,
+    subject_token_type: []const u8 = token_type_access_token,
+    /// Token representing the acting party, for delegation
+    actor_token: ?[]const u8 = null,
+    /// Required when `actor_token` is set
+    actor_token_type: ?[]const u8 = null,
+    /// Type of token wanted back (server default when null)
+    requested_token_type: ?[]const u8 = null,
+    /// Logical name of the downstream service
+    audience: ?[]const u8 = null,
+    /// URI of the downstream resource
+    resource: ?[]const u8 = null,
+    scope: ?[]const u8 = null,
+};
+
+/// Token introspection response (RFC 7662 Section 2.2)
+pub const IntrospectionResponse = struct {
+    /// Whether the token is active; the other fields only describe active tokens
+    active: bool,
+    scope: ?[]const u8 = null,
+    client_id: ?[]const u8 = null,
+    username: ?[]const u8 = null,
+    token_type: ?[]const u8 = null,
+    /// Expiry (Unix time)
+    exp: ?u64 = null,
+    /// Issue time (Unix time)
+    iat: ?u64 = null,
+    sub: ?[]const u8 = null,
+    iss: ?[]const u8 = null,
+    /// Every member of the response, including the ones above
+    claims: json.ObjectMap,
+    parsed: json.Parsed(json.Value),
+
+    /// Parse an introspection response; `active` is required
+    pub fn parse(allocator: Allocator, body: []const u8) !IntrospectionResponse {
+        var parsed = json.parseFromSlice(json.Value, allocator, body, .{}) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.JsonError,
+        };
+        errdefer parsed.deinit();
+
+        if (parsed.value != .object) return error.JsonError;
+        const claims = parsed.value.object;
+
+        const active = claims.get("active") orelse return error.ServerError;
+        if (active != .bool) return error.ServerError;
+
+        var response = IntrospectionResponse{ .active = active.bool, .claims = claims, .parsed = parsed };
+        inline for (.{ "scope", "client_id", "username", "token_type", "sub", "iss" }) |name| {
+            if (claims.get(name)) |v| {
+                if (v == .string) @field(response, name) = v.string;
+            }
+        }
+        inline for (.{ "exp", "iat" }) |name| {
+            if (claims.get(name)) |v| {
+                if (v == .integer and v.integer >= 0) @field(response, name) = @as(u64, @intCast(v.integer));
+            }
+        }
+        return response;
+    }
+
+    pub fn deinit(self: *IntrospectionResponse) void {
+        self.parsed.deinit();
+    }
+
+    /// Look up any member, e.g. `aud` or a server-specific extension
+    pub fn claim(self: *const IntrospectionResponse, name: []const u8) ?json.Value {
+        return self.claims.get(name);
+    }
+
+    /// Whether the active token was granted `scope`
+    pub fn hasScope(self: *const IntrospectionResponse, scope: []const u8) bool {
+        return self.active and scopeIncludes(self.scope, scope);
+    }
+};
+
+/// Options for OAuthClient.logout() (OpenID Connect RP-Initiated Logout 1.0)
+pub const LogoutOptions = struct {
+    /// Where the provider sends the browser afterwards (must be registered)
+    post_logout_redirect_uri: ?[]const u8 = null,
+    /// Opaque value passed back to `post_logout_redirect_uri`
+    state: ?[]const u8 = null,
+    /// Revoke the refresh token at `revocation_endpoint` before deleting it
+    revoke_refresh_token: bool = false,
+};
+
 /// Result from authorization flow
 pub const AuthFlowResult = struct {
     token: Token,
@@ -382,884 +888,6766 @@ pub const AuthFlowResult = struct {
     }
 };
 
-/// OAuth 2.0 client
-pub const OAuthClient = struct {
+/// HTTP status and headers of a successful token endpoint response
+pub const ResponseMeta = struct {
     allocator: Allocator,
-    config: OAuthConfig,
-    storage: SessionStorage,
-    http_client: ?HttpClient = null,
-
-    const HttpClient = struct {
-        allocator: Allocator,
-
-        /// Maximum response size (1 MB) to prevent unbounded memory allocation
-        const max_response_size: usize = 1024 * 1024;
-
-        pub fn init(allocator: Allocator) HttpClient {
-            return .{ .allocator = allocator };
-        }
-
-        pub fn deinit(self: *HttpClient) void {
-            _ = self;
-        }
-
-        pub fn post(self: *HttpClient, url: []const u8, body: []const u8, content_type: []const u8) !HttpResponse {
-            var client = http.Client{ .allocator = self.allocator };
-            defer client.deinit();
-
-            // Create response body storage using the Io.Writer.Allocating interface
-            var response_writer = std.Io.Writer.Allocating.init(self.allocator);
-            errdefer response_writer.deinit();
-
-            const result = try client.fetch(.{
-                .location = .{ .url = url },
-                .method = .POST,
-                .payload = body,
-                .extra_headers = &.{
-                    .{ .name = "Content-Type", .value = content_type },
-                    .{ .name = "Accept", .value = "application/json" },
-                },
-                .response_writer = &response_writer.writer,
-            });
+    status: u16,
+    /// Response headers, minus cookies unless requested (see Options)
+    headers: []transport.Header,
 
-            const response_body = try response_writer.toOwnedSlice();
+    pub const Options = struct {
+        /// Keep `Set-Cookie` headers, which may carry session secrets
+        include_cookies: bool = false,
+    };
 
-            // Check if response exceeds maximum allowed size
-            if (response_body.len > max_response_size) {
-                self.allocator.free(response_body);
-                return error.ResponseTooLarge;
+    fn fromResponse(allocator: Allocator, response: *const HttpResponse, options: Options) !ResponseMeta {
+        var headers: std.ArrayListUnmanaged(transport.Header) = .{};
+        errdefer {
+            for (headers.items) |h| {
+                allocator.free(h.name);
+                allocator.free(h.value);
             }
-
-            return HttpResponse{
-                .status = @intFromEnum(result.status),
-                .body = response_body,
-                .allocator = self.allocator,
-            };
+            headers.deinit(allocator);
         }
-    };
 
-    const HttpResponse = struct {
-        status: u16,
-        body: []const u8,
-        allocator: Allocator,
+        for (response.headers) |h| {
+            if (!options.include_cookies and isCookieHeader(h.name)) continue;
 
-        pub fn deinit(self: *HttpResponse) void {
-            self.allocator.free(self.body);
+            const name = try allocator.dupe(u8, h.name);
+            errdefer allocator.free(name);
+            const value = try allocator.dupe(u8, h.value);
+            errdefer allocator.free(value);
+            try headers.append(allocator, .{ .name = name, .value = value });
         }
-    };
 
-    /// Initialize a new OAuth client
-    pub fn init(allocator: Allocator, config: OAuthConfig, storage: SessionStorage) OAuthClient {
         return .{
             .allocator = allocator,
-            .config = config,
-            .storage = storage,
+            .status = response.status,
+            .headers = try headers.toOwnedSlice(allocator),
         };
     }
 
-    pub fn deinit(self: *OAuthClient) void {
-        if (self.http_client) |*client| {
-            client.deinit();
+    pub fn deinit(self: *ResponseMeta) void {
+        for (self.headers) |h| {
+            self.allocator.free(h.name);
+            self.allocator.free(h.value);
         }
+        self.allocator.free(self.headers);
     }
 
-    /// Perform Device Code Flow authorization (RFC 8628)
-    ///
-    /// This is the recommended flow for CLI applications:
-    /// 1. Request device code
-    /// 2. Display user code and verification URL
-    /// 3. Open browser for user to authorize
-    /// 4. Poll for token until user completes authorization
-    pub fn authorizeDevice(self: *OAuthClient) !Token {
-        const device_endpoint = self.config.device_authorization_endpoint orelse {
-            return error.UnsupportedOperation;
-        };
-        _ = device_endpoint;
-
-        // Step 1: Request device code
-        var device_response = try self.requestDeviceCode();
-        defer device_response.deinit();
-
-        // Step 2: Display user code and verification URL
-        var stderr_writer = std.fs.File.stderr().writer(&.{});
-        const stderr = &stderr_writer.interface;
-        try stderr.print("\nTo authorize, visit: {s}\n", .{device_response.verification_uri});
-        try stderr.print("And enter code: {s}\n\n", .{device_response.user_code});
-
-        // Open browser if available
-        if (device_response.verification_uri_complete) |uri| {
-            callback.openBrowser(uri) catch {};
-        } else {
-            callback.openBrowser(device_response.verification_uri) catch {};
+    /// Look up a header value (case-insensitive)
+    pub fn header(self: *const ResponseMeta, name: []const u8) ?[]const u8 {
+        for (self.headers) |h| {
+            if (std.ascii.eqlIgnoreCase(h.name, name)) return h.value;
         }
+        return null;
+    }
 
-        // Step 3: Poll for token
-        return try self.pollDeviceCode(
-            device_response.device_code,
-            device_response.interval,
-            device_response.expires_in,
-        );
+    fn isCookieHeader(name: []const u8) bool {
+        return std.ascii.eqlIgnoreCase(name, "set-cookie") or std.ascii.eqlIgnoreCase(name, "set-cookie2");
     }
+};
 
-    /// Request a device code without polling or browser side effects.
-    pub fn requestDeviceCode(self: *OAuthClient) !DeviceAuthorizationResponse {
-        const device_endpoint = self.config.device_authorization_endpoint orelse {
-            return error.UnsupportedOperation;
-        };
+/// A token together with the metadata of the response that issued it
+pub const DetailedToken = struct {
+    token: Token,
+    meta: ResponseMeta,
 
-        var http_client = HttpClient.init(self.allocator);
-        defer http_client.deinit();
+    pub fn deinit(self: *DetailedToken) void {
+        self.token.deinit();
+        self.meta.deinit();
+    }
+};
 
-        var body_buf: std.ArrayListUnmanaged(u8) = .{};
-        defer body_buf.deinit(self.allocator);
+/// Masked record of a token endpoint request, persisted with the token it issued
+///
+/// Only kept when the client has withRequestAudit() enabled. Secret
+/// parameter values (see masked_params) are replaced with `***` before the
+/// record is built, so it is safe to log or show during incident review.
+pub const RequestAudit = struct {
+    allocator: Allocator,
+    /// `grant_type` of the request (e.g. "refresh_token")
+    grant_type: []const u8,
+    /// Token endpoint the request was sent to
+    endpoint: []const u8,
+    /// Scope requested, if the request carried one
+    scope: ?[]const u8 = null,
+    /// Form-encoded request body with secret values masked
+    params: []const u8,
+    /// When the response arrived (Unix seconds)
+    timestamp: u64,
+    /// HTTP status of the response
+    status: u16,
+
+    /// Request parameters whose values are never recorded
+    pub const masked_params = [_][]const u8{
+        "code",
+        "code_verifier",
+        "refresh_token",
+        "client_secret",
+        "client_assertion",
+        "assertion",
+        "device_code",
+        "password",
+        "subject_token",
+        "actor_token",
+    };
 
-        try body_buf.appendSlice(self.allocator, "client_id=");
-        try appendUrlEncoded(self.allocator, &body_buf, self.config.client_id);
-        if (self.config.scope) |scope| {
-            try body_buf.appendSlice(self.allocator, "&scope=");
-            try appendUrlEncoded(self.allocator, &body_buf, scope);
-        }
+    const mask = "***";
 
-        var response = try http_client.post(
-            device_endpoint,
-            body_buf.items,
-            "application/x-www-form-urlencoded",
-        );
-        defer response.deinit();
+    /// JSON shape of a persisted record
+    const Record = struct {
+        grant_type: []const u8,
+        endpoint: []const u8,
+        scope: ?[]const u8 = null,
+        params: []const u8,
+        timestamp: u64,
+        status: u16,
+    };
 
-        if (response.status != 200) {
-            return error.ServerError;
+    /// Build the JSON record for a request `body` sent to `endpoint`
+    fn record(allocator: Allocator, endpoint: []const u8, body: []const u8, status: u16) ![]u8 {
+        var params: std.ArrayListUnmanaged(u8) = .{};
+        defer params.deinit(allocator);
+
+        var grant_type: ?[]const u8 = null;
+        defer if (grant_type) |g| allocator.free(g);
+        var scope: ?[]const u8 = null;
+        defer if (scope) |s| allocator.free(s);
+
+        var pairs = std.mem.splitScalar(u8, body, '&');
+        while (pairs.next()) |pair| {
+            if (pair.len == 0) continue;
+            const eq = std.mem.indexOfScalar(u8, pair, '=') orelse pair.len;
+            const name = pair[0..eq];
+            const value = if (eq < pair.len) pair[eq + 1 ..] else "";
+
+            if (params.items.len > 0) try params.append(allocator, '&');
+            try params.appendSlice(allocator, name);
+            try params.append(allocator, '=');
+            try params.appendSlice(allocator, if (isMasked(name)) mask else value);
+
+            if (std.mem.eql(u8, name, "grant_type") and grant_type == null) {
+                grant_type = try CallbackServer.urlDecode(allocator, value);
+            } else if (std.mem.eql(u8, name, "scope") and scope == null) {
+                scope = try CallbackServer.urlDecode(allocator, value);
+            }
         }
 
-        const parsed = try json.parseFromSlice(json.Value, self.allocator, response.body, .{});
-        defer parsed.deinit();
+        return json.Stringify.valueAlloc(allocator, Record{
+            .grant_type = grant_type orelse "",
+            .endpoint = endpoint,
+            .scope = scope,
+            .params = params.items,
+            .timestamp = clock.now(),
+            .status = status,
+        }, .{ .emit_null_optional_fields = false });
+    }
 
-        return try parseDeviceResponse(self.allocator, parsed.value);
+    fn isMasked(name: []const u8) bool {
+        for (masked_params) |masked| {
+            if (std.mem.eql(u8, name, masked)) return true;
+        }
+        return false;
     }
 
-    /// Poll the token endpoint with a previously issued device code.
-    pub fn pollDeviceCode(
-        self: *OAuthClient,
-        device_code: []const u8,
-        poll_interval: u64,
-        expires_in: ?u64,
-    ) !Token {
-        var http_client = HttpClient.init(self.allocator);
-        defer http_client.deinit();
+    /// Parse a record stored in `Token.request_audit`
+    pub fn fromJson(allocator: Allocator, data: []const u8) !RequestAudit {
+        const parsed = json.parseFromSlice(Record, allocator, data, .{ .ignore_unknown_fields = true }) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.JsonError,
+        };
+        defer parsed.deinit();
+        const rec = parsed.value;
 
-        const start_time = @as(u64, @intCast(std.time.timestamp()));
-        var interval = poll_interval;
-        if (interval < 5) interval = 5; // Minimum 5 seconds
-        const ttl = expires_in orelse 900;
+        const grant_type = try allocator.dupe(u8, rec.grant_type);
+        errdefer allocator.free(grant_type);
+        const endpoint = try allocator.dupe(u8, rec.endpoint);
+        errdefer allocator.free(endpoint);
+        const scope = if (rec.scope) |s| try allocator.dupe(u8, s) else null;
+        errdefer if (scope) |s| allocator.free(s);
 
-        // Maximum polling iterations (safety limit to prevent infinite loops)
-        // With 5s minimum interval and typical 15min expiry, max ~180 iterations is reasonable
-        const max_iterations: u32 = 500;
-        var iterations: u32 = 0;
+        return .{
+            .allocator = allocator,
+            .grant_type = grant_type,
+            .endpoint = endpoint,
+            .scope = scope,
+            .params = try allocator.dupe(u8, rec.params),
+            .timestamp = rec.timestamp,
+            .status = rec.status,
+        };
+    }
 
-        while (iterations < max_iterations) : (iterations += 1) {
-            const now = @as(u64, @intCast(std.time.timestamp()));
-            if (ttl > 0 and now - start_time >= ttl) {
-                return error.DeviceCodeExpired;
-            }
+    pub fn deinit(self: *RequestAudit) void {
+        self.allocator.free(self.grant_type);
+        self.allocator.free(self.endpoint);
+        if (self.scope) |s| self.allocator.free(s);
+        self.allocator.free(self.params);
+    }
+};
 
-            // Wait for polling interval
-            std.Thread.sleep(interval * std.time.ns_per_s);
-
-            // Poll token endpoint
-            var poll_body: std.ArrayListUnmanaged(u8) = .{};
-            defer poll_body.deinit(self.allocator);
-
-            try poll_body.appendSlice(self.allocator, "grant_type=urn:ietf:params:oauth:grant-type:device_code");
-            try poll_body.appendSlice(self.allocator, "&device_code=");
-            try appendUrlEncoded(self.allocator, &poll_body, device_code);
-            try poll_body.appendSlice(self.allocator, "&client_id=");
-            try appendUrlEncoded(self.allocator, &poll_body, self.config.client_id);
-
-            var token_response = try http_client.post(
-                self.config.token_endpoint,
-                poll_body.items,
-                "application/x-www-form-urlencoded",
-            );
-            defer token_response.deinit();
+/// Maps a provider's token response field to schlussel's canonical name
+pub const FieldMapping = struct {
+    /// Canonical field (`access_token`, `token_type`, `refresh_token`,
+    /// `expires_in`, `scope` or `id_token`)
+    canonical: []const u8,
+    /// Field name the provider actually sends
+    provider: []const u8,
+};
 
-            const token_parsed = try json.parseFromSlice(json.Value, self.allocator, token_response.body, .{});
-            defer token_parsed.deinit();
+/// Result of a code exchange checked against the requested scopes
+pub const ExchangeResult = struct {
+    allocator: Allocator,
+    token: Token,
+    /// Whether the provider granted every requested scope
+    scopes_fully_granted: bool,
+    /// Requested scopes missing from the granted scope
+    missing: []const []const u8,
 
-            const obj = token_parsed.value.object;
+    /// Compare the token's granted scope with `requested_scope`
+    ///
+    /// Takes ownership of `token` on success. A token response without a
+    /// `scope` means the request was granted as-is (RFC 6749 Section 5.1).
+    pub fn init(allocator: Allocator, token: Token, requested_scope: ?[]const u8) !ExchangeResult {
+        var missing: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (missing.items) |m| allocator.free(m);
+            missing.deinit(allocator);
+        }
 
-            // Check for error response
-            if (obj.get("error")) |err_val| {
-                const err_code = err_val.string;
-                if (std.mem.eql(u8, err_code, "authorization_pending")) {
-                    continue;
-                } else if (std.mem.eql(u8, err_code, "slow_down")) {
-                    interval += 5;
-                    continue;
-                } else if (std.mem.eql(u8, err_code, "access_denied")) {
-                    return error.AuthorizationDenied;
-                } else if (std.mem.eql(u8, err_code, "expired_token")) {
-                    return error.DeviceCodeExpired;
-                } else {
-                    return error.ServerError;
+        if (requested_scope) |requested| {
+            if (token.scope) |granted| {
+                var wanted = std.mem.tokenizeScalar(u8, requested, ' ');
+                while (wanted.next()) |scope| {
+                    if (hasScope(granted, scope)) continue;
+                    const copy = try allocator.dupe(u8, scope);
+                    errdefer allocator.free(copy);
+                    try missing.append(allocator, copy);
                 }
             }
-
-            // Success - parse token
-            return try Token.fromJsonValue(self.allocator, token_parsed.value);
         }
 
-        // If we exit the loop without returning, max iterations was exceeded
-        return error.DeviceCodeExpired;
+        const owned = try missing.toOwnedSlice(allocator);
+        return .{
+            .allocator = allocator,
+            .token = token,
+            .scopes_fully_granted = owned.len == 0,
+            .missing = owned,
+        };
     }
 
-    /// Perform Authorization Code Flow with PKCE
-    ///
-    /// This flow:
-    /// 1. Start local callback server
-    /// 2. Generate PKCE and state
-    /// 3. Open browser for authorization
-    /// 4. Wait for callback with authorization code
-    /// 5. Exchange code for token
-    pub fn authorize(self: *OAuthClient) !Token {
-        // Generate PKCE
-        const pkce_pair = Pkce.generate();
-
-        // Generate state for CSRF protection
-        var state_bytes: [16]u8 = undefined;
-        std.crypto.random.bytes(&state_bytes);
-        var state: [22]u8 = undefined;
-        _ = std.base64.url_safe_no_pad.Encoder.encode(&state, &state_bytes);
-
-        // Start callback server
-        var server = try CallbackServer.init(self.allocator, 0);
-        defer server.deinit();
-
-        const callback_url = try server.getCallbackUrl(self.allocator);
-        defer self.allocator.free(callback_url);
-
-        // Build authorization URL
-        const auth_url = try callback.buildAuthorizationUrl(
-            self.allocator,
-            self.config.authorization_endpoint,
-            self.config.client_id,
-            callback_url,
-            self.config.scope,
-            &state,
-            pkce_pair.getChallenge(),
-        );
-        defer self.allocator.free(auth_url);
-
-        // Open browser
-        var stderr_writer2 = std.fs.File.stderr().writer(&.{});
-        const stderr2 = &stderr_writer2.interface;
-        try stderr2.print("\nOpening browser for authorization...\n", .{});
-        try stderr2.print("If the browser doesn't open, visit:\n{s}\n\n", .{auth_url});
-
-        callback.openBrowser(auth_url) catch {};
-
-        // Wait for callback
-        var result = try server.waitForCallback(120); // 2 minute timeout
-        defer result.deinit();
-
-        // Verify state
-        if (result.state) |callback_state| {
-            if (!std.mem.eql(u8, callback_state, &state)) {
-                return error.InvalidState;
-            }
-        }
-
-        // Check for error
-        if (result.error_code != null) {
-            return error.AuthorizationDenied;
-        }
-
-        const code = result.code orelse return error.ServerError;
-
-        // Exchange code for token
-        return try self.exchangeCode(code, pkce_pair.getVerifier(), callback_url);
+    pub fn deinit(self: *ExchangeResult) void {
+        self.token.deinit();
+        for (self.missing) |m| self.allocator.free(m);
+        self.allocator.free(self.missing);
     }
 
-    /// Exchange an authorization code for a token
-    pub fn exchangeCode(self: *OAuthClient, code: []const u8, verifier: []const u8, redirect_uri: []const u8) !Token {
-        var http_client = HttpClient.init(self.allocator);
-        defer http_client.deinit();
-
-        var body: std.ArrayListUnmanaged(u8) = .{};
-        defer body.deinit(self.allocator);
-
-        try body.appendSlice(self.allocator, "grant_type=authorization_code");
-        try body.appendSlice(self.allocator, "&code=");
-        try appendUrlEncoded(self.allocator, &body, code);
-        try body.appendSlice(self.allocator, "&redirect_uri=");
-        try appendUrlEncoded(self.allocator, &body, redirect_uri);
-        try body.appendSlice(self.allocator, "&client_id=");
-        try appendUrlEncoded(self.allocator, &body, self.config.client_id);
-        try body.appendSlice(self.allocator, "&code_verifier=");
-        try appendUrlEncoded(self.allocator, &body, verifier);
-
-        if (self.config.client_secret) |secret| {
-            try body.appendSlice(self.allocator, "&client_secret=");
-            try appendUrlEncoded(self.allocator, &body, secret);
+    fn hasScope(granted: []const u8, scope: []const u8) bool {
+        var iter = std.mem.tokenizeScalar(u8, granted, ' ');
+        while (iter.next()) |g| {
+            if (std.mem.eql(u8, g, scope)) return true;
         }
+        return false;
+    }
+};
 
-        var response = try http_client.post(
-            self.config.token_endpoint,
-            body.items,
-            "application/x-www-form-urlencoded",
-        );
-        defer response.deinit();
+/// Per-request parameters for startAuthorizationWithOptions()
+pub const AuthorizationOptions = struct {
+    /// Rich Authorization Request details (RFC 9396): a JSON array of
+    /// objects, each with a `type`. The details the server granted come
+    /// back in `Token.authorization_details`.
+    authorization_details: ?[]const u8 = null,
+    /// Resource indicators (RFC 8707): absolute URIs of the APIs the
+    /// grant should cover, each sent as a `resource` parameter. Fetch a
+    /// token for one of them with OAuthClient.requestResourceToken().
+    resources: []const []const u8 = &.{},
+    /// Further query parameters (`prompt`, `login_hint`, vendor
+    /// parameters), appended in order. Parameters the client sets
+    /// itself cannot be overridden. Keep them on the Session with
+    /// Session.setAuthorizationParams() for auditing.
+    extra_params: []const AuthorizationParam = &.{},
+    /// Audience for this request, instead of `config.audience`
+    audience: ?[]const u8 = null,
+    /// Maximum age of the user's authentication in seconds (OpenID Connect)
+    ///
+    /// The provider re-authenticates users who signed in longer ago, and
+    /// exchangeCodeForFlow() rejects ID tokens whose `auth_time` is older
+    /// with `error.StaleAuthentication`. Zero forces a fresh login.
+    max_age: ?u64 = null,
+    /// Space-separated authentication context classes to request, in order
+    /// of preference (OpenID Connect `acr_values`)
+    ///
+    /// exchangeCodeForFlow() rejects ID tokens whose `acr` is not one of
+    /// them with `error.InsufficientAuthentication`.
+    acr_values: ?[]const u8 = null,
+    /// Space-separated authentication methods (RFC 8176), such as `mfa`,
+    /// that must all appear in the ID token's `amr`
+    ///
+    /// Not sent to the provider; ask for them with `acr_values` or a
+    /// vendor parameter. Tokens missing one fail with
+    /// `error.InsufficientAuthentication`.
+    required_amr: ?[]const u8 = null,
+    /// Scope for this request, instead of `config.scope`
+    scope: ?[]const u8 = null,
+    /// Ask the provider to keep the scopes granted earlier
+    /// (`include_granted_scopes=true`), for incremental authorization
+    include_granted_scopes: bool = false,
+};
 
-        if (response.status != 200) {
-            return error.ServerError;
-        }
+/// Parameters startAuthorizationWithOptions() manages itself
+const reserved_authorization_params = [_][]const u8{
+    "response_type",
+    "client_id",
+    "redirect_uri",
+    "scope",
+    "state",
+    "code_challenge",
+    "code_challenge_method",
+    "nonce",
+    "response_mode",
+    "request",
+    "request_uri",
+    "authorization_details",
+    "resource",
+    "audience",
+    "max_age",
+    "acr_values",
+    "include_granted_scopes",
+};
 
-        return try Token.fromJson(self.allocator, response.body);
+fn validateExtraParam(param: AuthorizationParam) !void {
+    if (param.name.len == 0) return error.InvalidParameter;
+    for (reserved_authorization_params) |reserved| {
+        if (std.mem.eql(u8, param.name, reserved)) return error.InvalidParameter;
     }
+}
 
-    /// Refresh an access token using a refresh token
-    pub fn refreshToken(self: *OAuthClient, refresh_token: []const u8) !Token {
-        var http_client = HttpClient.init(self.allocator);
-        defer http_client.deinit();
+/// Storage key of the token for `resource` derived from the grant under `key`
+///
+/// Resource URIs are not valid storage keys, so the key carries a digest of
+/// the resource instead. Caller owns the returned key.
+pub fn resourceKey(allocator: Allocator, key: []const u8, resource: []const u8) ![]u8 {
+    return derivedKey(allocator, key, "resource", resource);
+}
 
-        var body: std.ArrayListUnmanaged(u8) = .{};
-        defer body.deinit(self.allocator);
+/// Storage key of the token for `audience` derived from `key`
+///
+/// Like resourceKey(), so tokens for different audiences never overwrite
+/// each other. Caller owns the returned key.
+pub fn audienceKey(allocator: Allocator, key: []const u8, audience: []const u8) ![]u8 {
+    return derivedKey(allocator, key, "audience", audience);
+}
 
-        try body.appendSlice(self.allocator, "grant_type=refresh_token");
-        try body.appendSlice(self.allocator, "&refresh_token=");
-        try appendUrlEncoded(self.allocator, &body, refresh_token);
-        try body.appendSlice(self.allocator, "&client_id=");
-        try appendUrlEncoded(self.allocator, &body, self.config.client_id);
+/// Storage key of the token downscoped to `scope` from the grant under `key`
+///
+/// `scope` is hashed as given, so request it in a consistent order. Caller
+/// owns the returned key.
+pub fn downscopedKey(allocator: Allocator, key: []const u8, scope: []const u8) ![]u8 {
+    return derivedKey(allocator, key, "scope", scope);
+}
 
-        if (self.config.client_secret) |secret| {
-            try body.appendSlice(self.allocator, "&client_secret=");
-            try appendUrlEncoded(self.allocator, &body, secret);
-        }
+/// Record `audience` on `token`, replacing one from the token response
+fn replaceAudience(token: *Token, audience: []const u8) !void {
+    const copy = try token.allocator.dupe(u8, audience);
+    if (token.audience) |previous| token.allocator.free(previous);
+    token.audience = copy;
+}
 
-        var response = try http_client.post(
-            self.config.token_endpoint,
-            body.items,
-            "application/x-www-form-urlencoded",
-        );
-        defer response.deinit();
+fn derivedKey(allocator: Allocator, key: []const u8, comptime kind: []const u8, value: []const u8) ![]u8 {
+    var digest: [std.crypto.hash.sha2.Sha256.digest_length]u8 = undefined;
+    std.crypto.hash.sha2.Sha256.hash(value, &digest, .{});
+    const suffix = std.fmt.bytesToHex(digest[0..8], .lower);
+    return std.fmt.allocPrint(allocator, "{s}." ++ kind ++ "-{s}", .{ key, &suffix });
+}
 
-        if (response.status != 200) {
-            return error.ServerError;
-        }
+/// Whether `resource` is an absolute URI without a fragment (RFC 8707 Section 2)
+fn validateResource(resource: []const u8) !void {
+    const scheme_end = std.mem.indexOf(u8, resource, "://") orelse return error.InvalidParameter;
+    if (scheme_end == 0 or scheme_end + 3 == resource.len) return error.InvalidParameter;
+    if (std.mem.indexOfScalar(u8, resource, '#') != null) return error.InvalidParameter;
+}
 
-        return try Token.fromJson(self.allocator, response.body);
+/// An authorization code flow that has been started but not yet completed
+///
+/// Holds everything needed to finish the flow: the URL to open, the state to
+/// verify on callback, and the PKCE verifier for the code exchange.
+pub const AuthorizationFlow = struct {
+    allocator: Allocator,
+    /// Authorization URL to open in the browser
+    url: []const u8,
+    /// Redirect URI embedded in the URL (repeated in the code exchange)
+    redirect_uri: []const u8,
+    /// CSRF state embedded in the URL
+    state: [22]u8,
+    /// OIDC nonce, sent when the scope includes `openid` (see NonceMode)
+    nonce: [nonce_len]u8,
+    /// Whether `nonce` was included in the URL
+    includes_nonce: bool = false,
+    /// PKCE pair whose challenge is embedded in the URL
+    pkce_pair: Pkce,
+    /// Audience sent in the URL, recorded on the exchanged token
+    audience: ?[]const u8 = null,
+    /// Resource indicators sent in the URL, repeated in the code exchange
+    resources: []const []const u8 = &.{},
+    /// `max_age` sent in the URL, enforced on the ID token
+    max_age: ?u64 = null,
+    /// `acr_values` sent in the URL, enforced on the ID token
+    acr_values: ?[]const u8 = null,
+    /// Authentication methods the ID token's `amr` must include
+    required_amr: ?[]const u8 = null,
+
+    pub fn deinit(self: *AuthorizationFlow) void {
+        self.allocator.free(self.url);
+        self.allocator.free(self.redirect_uri);
+        if (self.audience) |audience| self.allocator.free(audience);
+        for (self.resources) |resource| self.allocator.free(resource);
+        self.allocator.free(self.resources);
+        if (self.acr_values) |values| self.allocator.free(values);
+        if (self.required_amr) |methods| self.allocator.free(methods);
     }
 
-    /// Save a token to storage
-    pub fn saveToken(self: *OAuthClient, key: []const u8, token: Token) !void {
-        try self.storage.save(key, token);
+    /// Whether the ID token must be checked for `max_age`, `acr` or `amr`
+    fn hasAuthenticationRequirements(self: *const AuthorizationFlow) bool {
+        return self.max_age != null or self.acr_values != null or self.required_amr != null;
     }
 
-    /// Get a token from storage
-    pub fn getToken(self: *OAuthClient, key: []const u8) !?Token {
-        return try self.storage.load(self.allocator, key);
+    /// Get the state as a slice
+    pub fn getState(self: *const AuthorizationFlow) []const u8 {
+        return &self.state;
     }
 
-    /// Delete a token from storage
-    pub fn deleteToken(self: *OAuthClient, key: []const u8) !void {
-        try self.storage.delete(key);
+    /// Get the nonce as a slice
+    pub fn getNonce(self: *const AuthorizationFlow) []const u8 {
+        return &self.nonce;
     }
 
-    fn parseDeviceResponse(allocator: Allocator, value: json.Value) !DeviceAuthorizationResponse {
-        // Validate input is an object
-        if (value != .object) return error.ServerError;
-        const obj = value.object;
+    /// Get the nonce if it was sent in the URL (OIDC requests only)
+    pub fn sentNonce(self: *const AuthorizationFlow) ?[]const u8 {
+        return if (self.includes_nonce) &self.nonce else null;
+    }
 
-        // Validate required fields exist and have correct types
-        const device_code_val = obj.get("device_code") orelse return error.ServerError;
-        if (device_code_val != .string) return error.ServerError;
+    /// Split the URL into its endpoint and decoded query parameters
+    ///
+    /// Caller owns the returned request.
+    pub fn parsedUrl(self: *const AuthorizationFlow, allocator: Allocator) !callback.AuthorizationRequest {
+        return callback.AuthorizationRequest.parse(allocator, self.url);
+    }
+};
 
-        const user_code_val = obj.get("user_code") orelse return error.ServerError;
-        if (user_code_val != .string) return error.ServerError;
+/// Length of a base64url-encoded (unpadded) 32-byte nonce
+pub const nonce_len = std.base64.url_safe_no_pad.Encoder.calcSize(32);
+
+/// How the OIDC `nonce` of an authorization request is generated
+///
+/// `random` draws an independent value per request. `state_bound` derives
+/// it as HMAC-SHA256(secret, state), so the nonce only verifies together
+/// with the state it was issued for: a nonce lifted from one ID token
+/// cannot be replayed against a session with a different state, and the
+/// client can recompute the expected value from the state alone.
+pub const NonceMode = union(enum) {
+    random,
+    /// Per-client secret keying the HMAC (borrowed, must outlive the client)
+    state_bound: []const u8,
+};
 
-        const verification_uri_val = obj.get("verification_uri") orelse return error.ServerError;
-        if (verification_uri_val != .string) return error.ServerError;
+/// Derive the state-bound nonce for `state` (see NonceMode)
+pub fn deriveNonce(secret: []const u8, state: []const u8) [nonce_len]u8 {
+    const HmacSha256 = std.crypto.auth.hmac.sha2.HmacSha256;
+    var mac: [HmacSha256.mac_length]u8 = undefined;
+    HmacSha256.create(&mac, state, secret);
 
-        const expires_in_val = obj.get("expires_in") orelse return error.ServerError;
-        if (expires_in_val != .integer) return error.ServerError;
+    var nonce: [nonce_len]u8 = undefined;
+    _ = std.base64.url_safe_no_pad.Encoder.encode(&nonce, &mac);
+    return nonce;
+}
 
-        // Safe integer cast with validation
-        const expires_in_raw = expires_in_val.integer;
-        if (expires_in_raw < 0 or expires_in_raw > std.math.maxInt(u64)) {
-            return error.ServerError;
-        }
-        const expires_in: u64 = @intCast(expires_in_raw);
+/// Hook invoked on every token obtained from the token endpoint
+///
+/// The hook may modify the token in place (for example to prefix the access
+/// token or attach `metadata`). Replaced fields must be allocated with
+/// `token.allocator` and the previous value freed.
+pub const TokenTransform = struct {
+    context: ?*anyopaque = null,
+    apply: *const fn (context: ?*anyopaque, token: *Token) anyerror!void,
+};
 
-        // Parse optional interval with safe cast
-        var interval: u64 = 5; // default
-        if (obj.get("interval")) |iv| {
-            if (iv == .integer) {
-                const interval_raw = iv.integer;
-                if (interval_raw > 0 and interval_raw <= 300) { // max 5 minutes
-                    interval = @intCast(interval_raw);
-                }
-            }
-        }
+/// A grant type the library does not implement itself
+///
+/// Vendor extensions such as `urn:okta:params:oauth:grant-type:otp` plug
+/// into OAuthClient.requestGrant(), which builds the token request around
+/// the grant's parameters (adding client authentication, DPoP and the
+/// audit record), parses the response as for the built-in grants and
+/// stores the token. Tokens with a refresh token are kept fresh by
+/// TokenRefresher like any other.
+pub const GrantRequest = struct {
+    ptr: *anyopaque,
+    vtable: *const VTable,
+
+    pub const VTable = struct {
+        /// The `grant_type` value sent to the token endpoint
+        grantType: *const fn (ptr: *anyopaque) []const u8,
+        /// Add the grant's parameters to the request
+        appendParams: *const fn (ptr: *anyopaque, params: *GrantParams) anyerror!void,
+        /// Read vendor fields from the response document into the parsed token
+        parseResponse: ?*const fn (ptr: *anyopaque, token: *Token, document: json.Value) anyerror!void = null,
+    };
 
-        // Parse optional verification_uri_complete
-        var uri_complete: ?[]const u8 = null;
-        if (obj.get("verification_uri_complete")) |uri| {
-            if (uri == .string) {
-                uri_complete = try allocator.dupe(u8, uri.string);
-            }
-        }
-        errdefer if (uri_complete) |uc| allocator.free(uc);
+    pub fn grantType(self: GrantRequest) []const u8 {
+        return self.vtable.grantType(self.ptr);
+    }
 
-        // Allocate all required fields with proper errdefer cleanup
-        const device_code = try allocator.dupe(u8, device_code_val.string);
-        errdefer allocator.free(device_code);
+    pub fn appendParams(self: GrantRequest, params: *GrantParams) !void {
+        try self.vtable.appendParams(self.ptr, params);
+    }
 
-        const user_code = try allocator.dupe(u8, user_code_val.string);
-        errdefer allocator.free(user_code);
+    pub fn parseResponse(self: GrantRequest, token: *Token, document: json.Value) !void {
+        const parse = self.vtable.parseResponse orelse return;
+        try parse(self.ptr, token, document);
+    }
+};
 
-        const verification_uri = try allocator.dupe(u8, verification_uri_val.string);
-        // No errdefer needed - this is the last allocation, success path
+/// Form body of a custom grant's token request
+pub const GrantParams = struct {
+    allocator: Allocator,
+    body: *std.ArrayListUnmanaged(u8),
+
+    /// Parameters the client sets itself
+    const reserved = [_][]const u8{
+        "grant_type",
+        "client_id",
+        "client_secret",
+        "client_assertion",
+        "client_assertion_type",
+    };
 
-        return .{
-            .allocator = allocator,
-            .device_code = device_code,
-            .user_code = user_code,
-            .verification_uri = verification_uri,
-            .verification_uri_complete = uri_complete,
-            .expires_in = expires_in,
-            .interval = interval,
-        };
+    /// Append `name=value`, URL-encoding the value
+    ///
+    /// Returns `error.InvalidParameter` for an empty name or one the client
+    /// sets itself, like `grant_type` or `client_id`.
+    pub fn add(self: *GrantParams, name: []const u8, value: []const u8) !void {
+        if (name.len == 0) return error.InvalidParameter;
+        for (reserved) |reserved_name| {
+            if (std.mem.eql(u8, name, reserved_name)) return error.InvalidParameter;
+        }
+        try self.body.append(self.allocator, '&');
+        try appendUrlEncoded(self.allocator, self.body, name);
+        try self.body.append(self.allocator, '=');
+        try appendUrlEncoded(self.allocator, self.body, value);
     }
 };
 
-/// Token refresher with automatic refresh and cross-process locking
-pub const TokenRefresher = struct {
+/// OAuth 2.0 client
+pub const OAuthClient = struct {
     allocator: Allocator,
-    client: *OAuthClient,
-    lock_manager: ?RefreshLockManager,
-    /// Refresh threshold as fraction of token lifetime (0.0-1.0)
-    refresh_threshold: f64,
+    config: OAuthConfig,
+    storage: SessionStorage,
+    /// HTTP transport override (defaults to std.http when null)
+    http_transport: ?HttpTransport = null,
+    /// Post-processing hook for exchanged and refreshed tokens
+    token_transform: ?TokenTransform = null,
+    /// Correct token expiry using the token endpoint's `Date` header
+    use_server_clock: bool = false,
+    /// Provider field names translated before parsing token responses
+    field_mapping: []const FieldMapping = &.{},
+    /// How authorization request nonces are generated
+    nonce_mode: NonceMode = .random,
+    /// Attach a masked RequestAudit to every token from the token endpoint
+    record_request_audit: bool = false,
+    /// Clock drift (seconds, either direction) above which a warning is logged
+    clock_drift_threshold: u64 = 60,
+    /// Drift measured on the latest token response with a `Date` header
+    clock_drift: std.atomic.Value(i64) = .init(no_clock_drift),
+    /// Floor for the device flow polling interval in seconds (RFC 8628 default: 5)
+    min_poll_interval: u64 = 5,
+    /// Receives lifecycle events from this client and its refreshers
+    event_sink: ?events.EventSink = null,
+    /// Key used to sign DPoP proofs for new grants (see withDpop)
+    dpop_key: ?dpop.DpopKey = null,
+    /// Signing algorithms accepted for ID tokens
+    id_token_verification: jwt.VerificationConfig = .{},
+    /// Provider keys reused across ID tokens (see withJwksCache)
+    jwks_cache: ?*jwks.JwksCache = null,
+
+    /// clock_drift value before any response carried a `Date` header
+    const no_clock_drift = std.math.minInt(i64);
 
-    /// Create a new token refresher
-    pub fn init(allocator: Allocator, client: *OAuthClient) TokenRefresher {
+    /// Initialize a new OAuth client
+    pub fn init(allocator: Allocator, config: OAuthConfig, storage: SessionStorage) OAuthClient {
         return .{
             .allocator = allocator,
-            .client = client,
-            .lock_manager = null,
-            .refresh_threshold = 0.1, // Refresh at 10% remaining lifetime
+            .config = config,
+            .storage = storage,
         };
     }
 
-    /// Enable cross-process locking
-    pub fn withFileLocking(self: *TokenRefresher, app_name: []const u8) !void {
-        self.lock_manager = try RefreshLockManager.init(self.allocator, app_name);
+    pub fn deinit(self: *OAuthClient) void {
+        _ = self;
     }
 
-    pub fn deinit(self: *TokenRefresher) void {
-        if (self.lock_manager) |*lm| {
-            lm.deinit();
-        }
+    /// Get the transport used for requests to the authorization server
+    pub fn httpTransport(self: *const OAuthClient) HttpTransport {
+        return self.http_transport orelse transport.defaultTransport();
     }
 
-    /// Get a valid token, refreshing if necessary
+    /// Install a hook that normalizes or annotates tokens centrally
     ///
-    /// This is the primary method for obtaining tokens. It:
-    /// 1. Loads the token from storage
-    /// 2. Checks if it's expired or about to expire
-    /// 3. Refreshes if needed (with cross-process locking if enabled)
-    /// 4. Returns a valid token
-    pub fn getValidToken(self: *TokenRefresher, key: []const u8) !Token {
-        return self.getValidTokenWithThreshold(key, self.refresh_threshold);
+    /// The hook runs after every successful exchange or refresh, before the
+    /// token is returned or stored. The result is validated afterwards: the
+    /// access token and type must be non-empty, and a token that carried an
+    /// expiry must still carry one so refresh decisions keep working.
+    pub fn withTokenTransform(
+        self: *OAuthClient,
+        context: ?*anyopaque,
+        apply: *const fn (context: ?*anyopaque, token: *Token) anyerror!void,
+    ) void {
+        self.token_transform = .{ .context = context, .apply = apply };
     }
 
-    /// Get a valid token with a custom refresh threshold
+    /// Translate non-standard token response field names
     ///
-    /// threshold: Fraction of lifetime at which to refresh (0.0-1.0)
-    /// - 0.0: Only refresh when expired
-    /// - 0.5: Refresh when 50% of lifetime remains
-    /// - 0.8: Refresh when 20% of lifetime remains
-    pub fn getValidTokenWithThreshold(self: *TokenRefresher, key: []const u8, threshold: f64) !Token {
-        var token = (try self.client.getToken(key)) orelse return error.TokenNotFound;
+    /// Each provider field is renamed to its canonical name before the response
+    /// is parsed, e.g. `.{ .canonical = "access_token", .provider = "tok" }`.
+    /// The mapping is borrowed and must outlive the client. Returns
+    /// `error.InvalidParameter` for an unknown canonical name.
+    pub fn withFieldMapping(self: *OAuthClient, mapping: []const FieldMapping) !void {
+        const canonical_fields = [_][]const u8{ "access_token", "token_type", "refresh_token", "expires_in", "scope", "id_token" };
+        for (mapping) |entry| {
+            for (canonical_fields) |field| {
+                if (std.mem.eql(u8, entry.canonical, field)) break;
+            } else return error.InvalidParameter;
+            if (entry.provider.len == 0) return error.InvalidParameter;
+        }
+        self.field_mapping = mapping;
+    }
 
-        // Check if refresh is needed
-        const needs_refresh = blk: {
-            if (token.isExpired()) break :blk true;
-            if (token.remainingLifetimeFraction()) |fraction| {
-                if (fraction <= threshold) break :blk true;
-            }
-            break :blk false;
-        };
+    /// Judge token expiry by the token endpoint's clock instead of the local one
+    ///
+    /// Each token response's `Date` header is compared with the local clock
+    /// and the difference is stored on the token as `clock_offset`; expiry and
+    /// refresh decisions then use the corrected time. The offset is measured
+    /// once per response, so it goes stale if the local clock is adjusted
+    /// afterwards and is only re-measured on the next refresh. Responses
+    /// without a valid `Date` header leave the token on the local clock.
+    pub fn withServerClock(self: *OAuthClient) void {
+        self.use_server_clock = true;
+    }
 
-        if (!needs_refresh) {
-            return token;
-        }
+    /// Record a masked audit of the request behind every issued token
+    ///
+    /// The record travels in `Token.request_audit`, so it is persisted
+    /// together with the token and reflects the last request that produced
+    /// the token stored under a key. Read it back with lastTokenRequestAudit().
+    pub fn withRequestAudit(self: *OAuthClient) void {
+        self.record_request_audit = true;
+    }
 
-        // Need to refresh
-        const refresh_token = token.refresh_token orelse {
-            token.deinit();
-            return error.NoRefreshToken;
-        };
+    /// Server clock minus local clock, in seconds, from the latest token response
+    ///
+    /// Measured on every token endpoint response that carries a valid
+    /// `Date` header, whether or not withServerClock() is enabled. Returns
+    /// null until such a response has been seen.
+    pub fn clockDriftSecs(self: *const OAuthClient) ?i64 {
+        const drift = self.clock_drift.load(.monotonic);
+        return if (drift == no_clock_drift) null else drift;
+    }
 
-        // Acquire lock if enabled
-        var lock_guard: ?lock.RefreshLock = null;
-        if (self.lock_manager) |*lm| {
-            lock_guard = try lm.acquire(key);
-        }
-        defer if (lock_guard) |*lg| lg.release();
+    /// Report flow, exchange, refresh and deletion events to `event_sink`
+    ///
+    /// Events carry no token material; see `events.Event`.
+    pub fn withEventSink(self: *OAuthClient, event_sink: events.EventSink) void {
+        self.event_sink = event_sink;
+    }
 
-        // Check again after acquiring lock (another process might have refreshed)
-        if (self.lock_manager != null) {
-            token.deinit();
-            token = (try self.client.getToken(key)) orelse return error.TokenNotFound;
+    fn emitEvent(self: *const OAuthClient, kind: events.Event.Kind) void {
+        const event_sink = self.event_sink orelse return;
+        event_sink.emit(&events.Event.now(kind));
+    }
 
-            const still_needs_refresh = blk: {
-                if (token.isExpired()) break :blk true;
-                if (token.remainingLifetimeFraction()) |fraction| {
-                    if (fraction <= threshold) break :blk true;
-                }
-                break :blk false;
-            };
+    /// Sender-constrain tokens with DPoP (RFC 9449) using a fresh key
+    ///
+    /// Every request to the authorization server carries a proof. Tokens the
+    /// server binds to the key keep it in `Token.dpop_key`; refreshing such a
+    /// token reuses the key it was bound to, and dpop.proofForToken() signs
+    /// proofs for resource requests.
+    pub fn withDpop(self: *OAuthClient) void {
+        self.dpop_key = dpop.DpopKey.generate();
+    }
 
-            if (!still_needs_refresh) {
-                return token;
-            }
-        }
+    /// POST a form-encoded body and return the raw response
+    fn postForm(self: *OAuthClient, url: []const u8, body: []const u8) !HttpResponse {
+        return self.postFormWithDpop(url, body, self.dpop_key);
+    }
+
+    /// POST a form-encoded body, with a DPoP proof signed by `dpop_key` when set
+    ///
+    /// A server demanding a nonce (`use_dpop_nonce`) gets one retry with the
+    /// nonce from its `DPoP-Nonce` header.
+    fn postFormWithDpop(self: *OAuthClient, url: []const u8, body: []const u8, dpop_key: ?dpop.DpopKey) !HttpResponse {
+        const key = dpop_key orelse return self.sendForm(url, body, null);
+
+        const first_proof = try key.proof(self.allocator, "POST", url, .{});
+        defer self.allocator.free(first_proof);
+
+        var response = try self.sendForm(url, body, first_proof);
+        if (response.status != 400 and response.status != 401) return response;
+
+        const nonce = response.header("DPoP-Nonce") orelse return response;
+        if (!isOAuthError(self.allocator, response.body, "use_dpop_nonce")) return response;
+
+        const retry_proof = proof: {
+            defer response.deinit();
+            break :proof try key.proof(self.allocator, "POST", url, .{ .nonce = nonce });
+        };
+        defer self.allocator.free(retry_proof);
+
+        return self.sendForm(url, body, retry_proof);
+    }
+
+    fn sendForm(self: *OAuthClient, url: []const u8, body: []const u8, dpop_proof: ?[]const u8) !HttpResponse {
+        const authorization = try self.basicAuthorization();
+        defer if (authorization) |value| {
+            std.crypto.secureZero(u8, value);
+            self.allocator.free(value);
+        };
+
+        var headers: [4]transport.Header = undefined;
+        var count: usize = 0;
+        headers[count] = .{ .name = "Content-Type", .value = "application/x-www-form-urlencoded" };
+        count += 1;
+        headers[count] = .{ .name = "Accept", .value = "application/json" };
+        count += 1;
+        if (dpop_proof) |proof| {
+            headers[count] = .{ .name = "DPoP", .value = proof };
+            count += 1;
+        }
+        if (authorization) |value| {
+            headers[count] = .{ .name = "Authorization", .value = value };
+            count += 1;
+        }
+
+        return self.httpTransport().send(self.allocator, .{
+            .method = .POST,
+            .url = url,
+            .headers = headers[0..count],
+            .body = body,
+            .client_certificate = self.config.client_certificate,
+        });
+    }
+
+    /// `Authorization` header value for `client_secret_basic`, if it applies
+    ///
+    /// Both halves are form-encoded before being joined (RFC 6749 Section 2.3.1).
+    fn basicAuthorization(self: *OAuthClient) !?[]u8 {
+        if (self.config.client_auth != .client_secret_basic) return null;
+        if (self.config.client_certificate != null) return null;
+        const secret = self.config.client_secret orelse return null;
+
+        var credentials: std.ArrayListUnmanaged(u8) = .{};
+        defer {
+            std.crypto.secureZero(u8, credentials.items);
+            credentials.deinit(self.allocator);
+        }
+        try appendUrlEncoded(self.allocator, &credentials, self.config.client_id);
+        try credentials.append(self.allocator, ':');
+        try appendUrlEncoded(self.allocator, &credentials, secret);
+
+        const encoder = std.base64.standard.Encoder;
+        const prefix = "Basic ";
+        const value = try self.allocator.alloc(u8, prefix.len + encoder.calcSize(credentials.items.len));
+        @memcpy(value[0..prefix.len], prefix);
+        _ = encoder.encode(value[prefix.len..], credentials.items);
+        return value;
+    }
+
+    /// Perform Device Code Flow authorization (RFC 8628)
+    ///
+    /// This is the recommended flow for CLI applications:
+    /// 1. Request device code
+    /// 2. Display user code and verification URL
+    /// 3. Open browser for user to authorize
+    /// 4. Poll for token until user completes authorization
+    pub fn authorizeDevice(self: *OAuthClient) !Token {
+        const device_endpoint = self.config.device_authorization_endpoint orelse {
+            return error.UnsupportedOperation;
+        };
+        _ = device_endpoint;
+
+        // Step 1: Request device code
+        var device_response = try self.requestDeviceCode();
+        defer device_response.deinit();
+
+        // Step 2: Display user code and verification URL
+        var stderr_writer = std.fs.File.stderr().writer(&.{});
+        const stderr = &stderr_writer.interface;
+        try stderr.print("\nTo authorize, visit: {s}\n", .{device_response.verification_uri});
+        try stderr.print("And enter code: {s}\n\n", .{device_response.user_code});
+
+        // Open browser if available
+        if (device_response.verification_uri_complete) |uri| {
+            callback.openBrowser(uri) catch {};
+        } else {
+            callback.openBrowser(device_response.verification_uri) catch {};
+        }
+
+        // Step 3: Poll for token
+        return try self.pollDeviceCode(
+            device_response.device_code,
+            device_response.interval,
+            device_response.expires_in,
+        );
+    }
+
+    /// Request a device code without polling or browser side effects.
+    pub fn requestDeviceCode(self: *OAuthClient) !DeviceAuthorizationResponse {
+        const device_endpoint = self.config.device_authorization_endpoint orelse {
+            return error.UnsupportedOperation;
+        };
+
+        var body_buf: std.ArrayListUnmanaged(u8) = .{};
+        defer body_buf.deinit(self.allocator);
+
+        try body_buf.appendSlice(self.allocator, "client_id=");
+        try appendUrlEncoded(self.allocator, &body_buf, self.config.client_id);
+        if (self.config.scope) |scope| {
+            try body_buf.appendSlice(self.allocator, "&scope=");
+            try appendUrlEncoded(self.allocator, &body_buf, scope);
+        }
+        if (self.config.audience) |audience| {
+            try body_buf.appendSlice(self.allocator, "&audience=");
+            try appendUrlEncoded(self.allocator, &body_buf, audience);
+        }
+
+        var response = try self.postForm(device_endpoint, body_buf.items);
+        defer response.deinit();
+
+        if (response.status != 200) {
+            return error.ServerError;
+        }
+
+        const parsed = try json.parseFromSlice(json.Value, self.allocator, response.body, .{});
+        defer parsed.deinit();
+
+        return try parseDeviceResponse(self.allocator, parsed.value);
+    }
+
+    /// Start a device authorization and return a handle to poll it with
+    ///
+    /// Show `flow.device.user_code` and `flow.device.verification_uri` to
+    /// the user, then either call `flow.wait()` or drive `flow.poll()` from
+    /// your own loop. Unlike authorizeDevice() nothing is printed and no
+    /// browser is opened.
+    pub fn startDeviceFlow(self: *OAuthClient) !DeviceFlow {
+        const device = try self.requestDeviceCode();
+        return .{
+            .client = self,
+            .device = device,
+            .interval = @max(device.interval, self.min_poll_interval),
+            .expires_at = clock.now() +| device.expires_in,
+        };
+    }
+
+    /// Poll the token endpoint with a previously issued device code.
+    pub fn pollDeviceCode(
+        self: *OAuthClient,
+        device_code: []const u8,
+        poll_interval: u64,
+        expires_in: ?u64,
+    ) !Token {
+        const start_time = @as(u64, @intCast(std.time.timestamp()));
+        var interval = @max(poll_interval, self.min_poll_interval);
+        const ttl = expires_in orelse 900;
+
+        // Maximum polling iterations (safety limit to prevent infinite loops)
+        // With 5s minimum interval and typical 15min expiry, max ~180 iterations is reasonable
+        const max_iterations: u32 = 500;
+        var iterations: u32 = 0;
+
+        while (iterations < max_iterations) : (iterations += 1) {
+            const now = @as(u64, @intCast(std.time.timestamp()));
+            if (ttl > 0 and now - start_time >= ttl) {
+                return error.DeviceCodeExpired;
+            }
+
+            // Wait for polling interval
+            if (interval > 0) std.Thread.sleep(interval * std.time.ns_per_s);
+
+            if (try self.pollDeviceCodeOnce(device_code, &interval)) |token| return token;
+        }
+
+        // If we exit the loop without returning, max iterations was exceeded
+        return error.DeviceCodeExpired;
+    }
+
+    /// Make a single device code token request
+    ///
+    /// Returns null while authorization is pending; `slow_down` also raises
+    /// `interval` by five seconds as RFC 8628 Section 3.5 requires.
+    fn pollDeviceCodeOnce(self: *OAuthClient, device_code: []const u8, interval: *u64) !?Token {
+        var poll_body: std.ArrayListUnmanaged(u8) = .{};
+        defer poll_body.deinit(self.allocator);
+
+        try poll_body.appendSlice(self.allocator, "grant_type=urn:ietf:params:oauth:grant-type:device_code");
+        try poll_body.appendSlice(self.allocator, "&device_code=");
+        try appendUrlEncoded(self.allocator, &poll_body, device_code);
+        try self.appendClientAuth(&poll_body);
+
+        return self.pollTokenEndpoint(poll_body.items, interval);
+    }
+
+    /// Post a polled grant (device code or CIBA) to the token endpoint
+    ///
+    /// Returns null on `authorization_pending` and `slow_down`, and
+    /// `error.DeviceCodeExpired` on `expired_token`.
+    fn pollTokenEndpoint(self: *OAuthClient, body: []const u8, interval: *u64) !?Token {
+        var token_response = try self.postForm(self.config.token_endpoint, body);
+        defer token_response.deinit();
+
+        const token_parsed = json.parseFromSlice(json.Value, self.allocator, token_response.body, .{}) catch {
+            return error.ServerError;
+        };
+        defer token_parsed.deinit();
+
+        if (token_parsed.value != .object) return error.ServerError;
+        const obj = token_parsed.value.object;
+
+        // Check for error response
+        if (obj.get("error")) |err_val| {
+            if (err_val != .string) return error.ServerError;
+            const err_code = error_types.ErrorCode.fromString(err_val.string) orelse return error.ServerError;
+            switch (err_code) {
+                .authorization_pending => return null,
+                .slow_down => {
+                    interval.* += 5;
+                    return null;
+                },
+                else => return err_code.toError(),
+            }
+        }
+
+        if (token_response.status != 200) return error.ServerError;
+
+        // Success - parse token
+        var token = try self.tokenFromResponse(&token_response, null);
+        errdefer token.deinit();
+
+        try bindDpopKey(&token, self.dpop_key);
+        return token;
+    }
+
+    /// Poll for the token of a pending device authorization and save it under `key`
+    ///
+    /// `device` is the response from requestDeviceCode(). Returns
+    /// `error.AuthorizationDenied` if the user declines and
+    /// `error.DeviceCodeExpired` once the device code lapses.
+    pub fn pollDeviceToken(self: *OAuthClient, key: []const u8, device: *const DeviceAuthorizationResponse) !Token {
+        var token = try self.pollDeviceCode(device.device_code, device.interval, device.expires_in);
+        errdefer token.deinit();
+
+        try self.saveTokenForAudience(key, token);
+        return token;
+    }
+
+    /// Start a backchannel authentication (OpenID Connect CIBA)
+    ///
+    /// Asks the provider to authenticate the user named by the request's
+    /// hint on their own device. Show `request.binding_message` to the user,
+    /// then call `flow.wait()` (poll mode) or `flow.handlePing()` once the
+    /// provider notifies your endpoint (ping mode). Requires
+    /// `config.backchannel_authentication_endpoint`.
+    pub fn startBackchannelAuth(self: *OAuthClient, request: ciba.BackchannelRequest) !BackchannelFlow {
+        const endpoint = self.config.backchannel_authentication_endpoint orelse return error.UnsupportedOperation;
+        try request.validate();
+
+        var notification_bytes: [32]u8 = undefined;
+        std.crypto.random.bytes(&notification_bytes);
+        var notification_token: [BackchannelFlow.notification_token_len]u8 = undefined;
+        _ = std.base64.url_safe_no_pad.Encoder.encode(&notification_token, &notification_bytes);
+
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer body.deinit(self.allocator);
+
+        // CIBA is an OpenID flow; the scope always carries openid
+        const scope = request.scope orelse self.config.scope;
+        try body.appendSlice(self.allocator, "scope=");
+        if (!scopeIncludes(scope, "openid")) {
+            try body.appendSlice(self.allocator, "openid");
+            if (scope != null) try body.appendSlice(self.allocator, "%20");
+        }
+        if (scope) |s| try appendUrlEncoded(self.allocator, &body, s);
+
+        const optional_params = .{
+            .{ "login_hint", request.login_hint },
+            .{ "login_hint_token", request.login_hint_token },
+            .{ "id_token_hint", request.id_token_hint },
+            .{ "binding_message", request.binding_message },
+            .{ "user_code", request.user_code },
+        };
+        inline for (optional_params) |param| {
+            if (param[1]) |value| {
+                try body.appendSlice(self.allocator, "&" ++ param[0] ++ "=");
+                try appendUrlEncoded(self.allocator, &body, value);
+            }
+        }
+        if (request.requested_expiry) |expiry| {
+            try body.writer(self.allocator).print("&requested_expiry={d}", .{expiry});
+        }
+        if (request.delivery_mode == .ping) {
+            try body.appendSlice(self.allocator, "&client_notification_token=");
+            try body.appendSlice(self.allocator, &notification_token);
+        }
+        try self.appendClientAuth(&body);
+
+        var response = try self.postForm(endpoint, body.items);
+        defer response.deinit();
+
+        if (response.status != 200) {
+            return tokenErrorFromResponse(self.allocator, response.body);
+        }
+
+        const auth_response = try ciba.BackchannelAuthResponse.parse(self.allocator, response.body);
+        return .{
+            .client = self,
+            .response = auth_response,
+            .delivery_mode = request.delivery_mode,
+            .client_notification_token = notification_token,
+            .interval = @max(auth_response.interval, self.min_poll_interval),
+            .expires_at = clock.now() +| auth_response.expires_in,
+        };
+    }
+
+    /// Perform Authorization Code Flow with PKCE
+    ///
+    /// This flow:
+    /// 1. Start local callback server
+    /// 2. Generate PKCE and state
+    /// 3. Open browser for authorization
+    /// 4. Wait for callback with authorization code
+    /// 5. Exchange code for token
+    pub fn authorize(self: *OAuthClient) !Token {
+        return self.authorizeWithOptions(.{});
+    }
+
+    /// Like authorize(), with per-request parameters
+    pub fn authorizeWithOptions(self: *OAuthClient, options: AuthorizationOptions) !Token {
+        // Start callback server
+        var server = try CallbackServer.init(self.allocator, 0);
+        defer server.deinit();
+
+        const callback_url = try server.getCallbackUrl(self.allocator);
+        defer self.allocator.free(callback_url);
+
+        // Generate PKCE and state, and build the authorization URL
+        var flow = try self.startAuthorizationWithOptions(callback_url, options);
+        defer flow.deinit();
+        const auth_url = flow.url;
+
+        // Open browser
+        var stderr_writer2 = std.fs.File.stderr().writer(&.{});
+        const stderr2 = &stderr_writer2.interface;
+        try stderr2.print("\nOpening browser for authorization...\n", .{});
+        try stderr2.print("If the browser doesn't open, visit:\n{s}\n\n", .{auth_url});
+
+        callback.openBrowser(auth_url) catch {};
+
+        // Wait for callback
+        var result = try server.waitForCallback(120); // 2 minute timeout
+        defer result.deinit();
+        try self.unwrapJarmResponse(&result);
+
+        // Verify state
+        if (result.state) |callback_state| {
+            if (!std.mem.eql(u8, callback_state, flow.getState())) {
+                return error.InvalidState;
+            }
+        }
+
+        // Check for error
+        if (result.oauthError()) |err| return err;
+
+        const code = result.code orelse return error.ServerError;
+
+        // Exchange code for token
+        return try self.exchangeCodeForFlow(&flow, code);
+    }
+
+    /// Start an authorization code flow without running a callback server
+    ///
+    /// Generates PKCE and state and builds the authorization URL for
+    /// `redirect_uri`. Finish the flow with exchangeCode() once the provider
+    /// redirects back.
+    pub fn startAuthorization(self: *OAuthClient, redirect_uri: []const u8) !AuthorizationFlow {
+        return self.startAuthorizationWithOptions(redirect_uri, .{});
+    }
+
+    /// Like startAuthorizationWithOptions(), recording the request on `auth_session`
+    ///
+    /// Sets the session's requested scope, nonce and extra parameters, so
+    /// exchangeCodeForSession() can report declined scopes and check the
+    /// nonce. Persist the session with Session.toJson() if the callback is
+    /// handled elsewhere.
+    pub fn startAuthorizationForSession(
+        self: *OAuthClient,
+        auth_session: *session.Session,
+        redirect_uri: []const u8,
+        options: AuthorizationOptions,
+    ) !AuthorizationFlow {
+        var flow = try self.startAuthorizationWithOptions(redirect_uri, options);
+        errdefer flow.deinit();
+
+        try auth_session.setRequestedScope(options.scope orelse self.config.scope);
+        try auth_session.setNonce(flow.sentNonce());
+        try auth_session.setAuthorizationParams(options.extra_params);
+        return flow;
+    }
+
+    /// Like startAuthorization(), with extra request parameters
+    ///
+    /// Malformed `authorization_details`, resources or extra parameters
+    /// yield `error.InvalidParameter` before anything is sent.
+    pub fn startAuthorizationWithOptions(
+        self: *OAuthClient,
+        redirect_uri: []const u8,
+        options: AuthorizationOptions,
+    ) !AuthorizationFlow {
+        if (options.authorization_details) |details| try validateAuthorizationDetails(self.allocator, details);
+        for (options.resources) |resource| try validateResource(resource);
+        for (options.extra_params) |param| try validateExtraParam(param);
+        if (self.config.compliance == .oauth21) {
+            if (self.config.pkce.method != .s256) return error.ConfigurationError;
+            try self.config.matchRedirectUri(redirect_uri);
+        }
+
+        const pkce_pair = try Pkce.generateWithOptions(self.config.pkce);
+        const scope = options.scope orelse self.config.scope;
+
+        // Generate state for CSRF protection
+        var state_bytes: [16]u8 = undefined;
+        std.crypto.random.bytes(&state_bytes);
+        var state: [22]u8 = undefined;
+        _ = std.base64.url_safe_no_pad.Encoder.encode(&state, &state_bytes);
+
+        const nonce = switch (self.nonce_mode) {
+            .random => random: {
+                var nonce_bytes: [32]u8 = undefined;
+                std.crypto.random.bytes(&nonce_bytes);
+                var encoded: [nonce_len]u8 = undefined;
+                _ = std.base64.url_safe_no_pad.Encoder.encode(&encoded, &nonce_bytes);
+                break :random encoded;
+            },
+            .state_bound => |secret| deriveNonce(secret, &state),
+        };
+
+        const base_url = try callback.buildAuthorizationUrl(
+            self.allocator,
+            self.config.authorization_endpoint,
+            self.config.client_id,
+            redirect_uri,
+            scope,
+            &state,
+            pkce_pair.getChallenge(),
+            pkce_pair.getChallengeMethod(),
+        );
+
+        var request: std.ArrayListUnmanaged(u8) = .{};
+        errdefer request.deinit(self.allocator);
+        {
+            defer self.allocator.free(base_url);
+            try request.appendSlice(self.allocator, base_url);
+        }
+
+        // The nonce is an OIDC parameter; plain OAuth servers never see it
+        const includes_nonce = scopeIncludes(scope, "openid");
+        if (includes_nonce) {
+            try request.appendSlice(self.allocator, "&nonce=");
+            try request.appendSlice(self.allocator, &nonce);
+        }
+        if (options.authorization_details) |details| {
+            try request.appendSlice(self.allocator, "&authorization_details=");
+            try appendUrlEncoded(self.allocator, &request, details);
+        }
+        for (options.resources) |resource| {
+            try request.appendSlice(self.allocator, "&resource=");
+            try appendUrlEncoded(self.allocator, &request, resource);
+        }
+        const audience = options.audience orelse self.config.audience;
+        if (audience) |value| {
+            try request.appendSlice(self.allocator, "&audience=");
+            try appendUrlEncoded(self.allocator, &request, value);
+        }
+        if (options.max_age) |max_age| {
+            try request.writer(self.allocator).print("&max_age={d}", .{max_age});
+        }
+        if (options.acr_values) |values| {
+            try request.appendSlice(self.allocator, "&acr_values=");
+            try appendUrlEncoded(self.allocator, &request, values);
+        }
+        if (options.include_granted_scopes) {
+            try request.appendSlice(self.allocator, "&include_granted_scopes=true");
+        }
+        if (self.config.response_mode) |mode| {
+            try request.appendSlice(self.allocator, "&response_mode=");
+            try request.appendSlice(self.allocator, mode.toString());
+        }
+        for (options.extra_params) |param| {
+            try request.append(self.allocator, '&');
+            try appendUrlEncoded(self.allocator, &request, param.name);
+            try request.append(self.allocator, '=');
+            try appendUrlEncoded(self.allocator, &request, param.value);
+        }
+        const plain_url = try request.toOwnedSlice(self.allocator);
+        const request_url = if (self.config.request_signing) |signing| signed: {
+            defer self.allocator.free(plain_url);
+            break :signed try self.signAuthorizationRequest(signing, plain_url);
+        } else plain_url;
+
+        const url = if (self.config.pushed_authorization_request_endpoint) |par_endpoint| url: {
+            defer self.allocator.free(request_url);
+            break :url try self.pushAuthorizationRequest(par_endpoint, request_url);
+        } else request_url;
+        errdefer self.allocator.free(url);
+
+        const owned_redirect_uri = try self.allocator.dupe(u8, redirect_uri);
+        errdefer self.allocator.free(owned_redirect_uri);
+        const owned_audience = if (audience) |value| try self.allocator.dupe(u8, value) else null;
+        errdefer if (owned_audience) |value| self.allocator.free(value);
+        const owned_acr_values = if (options.acr_values) |values| try self.allocator.dupe(u8, values) else null;
+        errdefer if (owned_acr_values) |values| self.allocator.free(values);
+        const owned_required_amr = if (options.required_amr) |methods| try self.allocator.dupe(u8, methods) else null;
+        errdefer if (owned_required_amr) |methods| self.allocator.free(methods);
+        const owned_resources = try self.allocator.alloc([]const u8, options.resources.len);
+        var duped: usize = 0;
+        errdefer {
+            for (owned_resources[0..duped]) |resource| self.allocator.free(resource);
+            self.allocator.free(owned_resources);
+        }
+        for (options.resources) |resource| {
+            owned_resources[duped] = try self.allocator.dupe(u8, resource);
+            duped += 1;
+        }
+
+        self.emitEvent(.{ .flow_started = .{
+            .authorization_endpoint = self.config.authorization_endpoint,
+            .redirect_uri = redirect_uri,
+            .scope = scope,
+        } });
+
+        return .{
+            .allocator = self.allocator,
+            .url = url,
+            .redirect_uri = owned_redirect_uri,
+            .state = state,
+            .nonce = nonce,
+            .includes_nonce = includes_nonce,
+            .pkce_pair = pkce_pair,
+            .audience = owned_audience,
+            .resources = owned_resources,
+            .max_age = options.max_age,
+            .acr_values = owned_acr_values,
+            .required_amr = owned_required_amr,
+        };
+    }
+
+    /// Check that `details` is a JSON array of objects with a string `type` (RFC 9396 Section 2)
+    fn validateAuthorizationDetails(allocator: Allocator, details: []const u8) !void {
+        const parsed = json.parseFromSlice(json.Value, allocator, details, .{}) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.InvalidParameter,
+        };
+        defer parsed.deinit();
+
+        if (parsed.value != .array or parsed.value.array.items.len == 0) return error.InvalidParameter;
+        for (parsed.value.array.items) |entry| {
+            if (entry != .object) return error.InvalidParameter;
+            const kind = entry.object.get("type") orelse return error.InvalidParameter;
+            if (kind != .string) return error.InvalidParameter;
+        }
+    }
+
+    /// Replace the parameters of `request_url` with a signed request object (RFC 9101)
+    ///
+    /// Returns a URL carrying only `client_id` and `request`.
+    fn signAuthorizationRequest(self: *OAuthClient, signing: jar.RequestSigning, request_url: []const u8) ![]const u8 {
+        var parsed = try callback.AuthorizationRequest.parse(self.allocator, request_url);
+        defer parsed.deinit();
+
+        const audience = self.config.issuer orelse self.config.authorization_endpoint;
+        const request_object = try jar.signRequest(self.allocator, signing, self.config.client_id, audience, parsed.query_params);
+        defer self.allocator.free(request_object);
+
+        var url: std.ArrayListUnmanaged(u8) = .{};
+        errdefer url.deinit(self.allocator);
+
+        try url.appendSlice(self.allocator, self.config.authorization_endpoint);
+        try url.appendSlice(self.allocator, "?client_id=");
+        try appendUrlEncoded(self.allocator, &url, self.config.client_id);
+        try url.appendSlice(self.allocator, "&request=");
+        try url.appendSlice(self.allocator, request_object);
+        return url.toOwnedSlice(self.allocator);
+    }
+
+    /// Post the parameters of `request_url` to the PAR endpoint (RFC 9126)
+    ///
+    /// Returns the browser URL, which carries only `client_id` and the
+    /// `request_uri` the server issued for the pushed parameters.
+    fn pushAuthorizationRequest(self: *OAuthClient, par_endpoint: []const u8, request_url: []const u8) ![]const u8 {
+        const query_start = std.mem.indexOfScalar(u8, request_url, '?') orelse return error.InvalidParameter;
+
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer body.deinit(self.allocator);
+
+        // The query already carries client_id
+        try body.appendSlice(self.allocator, request_url[query_start + 1 ..]);
+        try self.appendClientCredentials(&body);
+
+        var response = try self.postForm(par_endpoint, body.items);
+        defer response.deinit();
+
+        if (response.status != 201 and response.status != 200) {
+            return tokenErrorFromResponse(self.allocator, response.body);
+        }
+
+        const parsed = json.parseFromSlice(json.Value, self.allocator, response.body, .{}) catch return error.ServerError;
+        defer parsed.deinit();
+
+        if (parsed.value != .object) return error.ServerError;
+        const request_uri = parsed.value.object.get("request_uri") orelse return error.ServerError;
+        if (request_uri != .string or request_uri.string.len == 0) return error.ServerError;
+
+        var url: std.ArrayListUnmanaged(u8) = .{};
+        errdefer url.deinit(self.allocator);
+
+        try url.appendSlice(self.allocator, self.config.authorization_endpoint);
+        try url.appendSlice(self.allocator, "?client_id=");
+        try appendUrlEncoded(self.allocator, &url, self.config.client_id);
+        try url.appendSlice(self.allocator, "&request_uri=");
+        try appendUrlEncoded(self.allocator, &url, request_uri.string);
+        return url.toOwnedSlice(self.allocator);
+    }
+
+    /// Check the `nonce` claim of an ID token issued for `flow`
+    ///
+    /// With `.state_bound` nonces the expected value is recomputed from the
+    /// flow's state, so a tampered state fails verification. Returns
+    /// `error.InvalidNonce` on mismatch or when the claim is missing. The
+    /// token's signature is not checked here.
+    pub fn verifyIdTokenNonce(self: *OAuthClient, flow: *const AuthorizationFlow, id_token: []const u8) !void {
+        const expected = switch (self.nonce_mode) {
+            .random => flow.nonce,
+            .state_bound => |secret| deriveNonce(secret, flow.getState()),
+        };
+
+        return checkNonceClaim(self.allocator, id_token, &expected);
+    }
+
+    /// Check that the `nonce` claim of `id_token` equals `expected`
+    fn checkNonceClaim(allocator: Allocator, id_token: []const u8, expected: []const u8) !void {
+        const parts = try jwt.split(id_token);
+        const payload = try jwt.decodeSegment(allocator, parts.payload);
+        defer allocator.free(payload);
+
+        const parsed = json.parseFromSlice(json.Value, allocator, payload, .{}) catch return error.InvalidParameter;
+        defer parsed.deinit();
+
+        if (parsed.value != .object) return error.InvalidParameter;
+        const claim = parsed.value.object.get("nonce") orelse return error.InvalidNonce;
+        if (claim != .string or !constantTimeEql(claim.string, expected)) return error.InvalidNonce;
+    }
+
+    /// Exchange the code returned to `flow`'s redirect URI
+    ///
+    /// Uses the flow's PKCE verifier, redirect URI and resource indicators.
+    /// When the flow sent a nonce, an ID token in the response must carry it (see
+    /// verifyIdTokenNonce), which stops ID tokens injected from another
+    /// session. When it sent `max_age` or asked for `acr_values` or
+    /// `required_amr`, the ID token must show a recent and strong enough
+    /// authentication (see IdToken.checkAuthTime() and
+    /// IdToken.checkAuthenticationStrength()).
+    pub fn exchangeCodeForFlow(self: *OAuthClient, flow: *const AuthorizationFlow, code: []const u8) !Token {
+        var token = try self.exchangeCodeWithResources(code, flow.pkce_pair.getVerifier(), flow.redirect_uri, flow.resources);
+        errdefer token.deinit();
+
+        if (flow.includes_nonce) {
+            if (token.id_token) |raw| try self.verifyIdTokenNonce(flow, raw);
+        }
+        if (flow.hasAuthenticationRequirements()) {
+            // The signature was checked with the token response when the
+            // config names an issuer
+            const raw = token.id_token orelse return error.InvalidIdToken;
+            var claims = try IdToken.decode(self.allocator, raw);
+            defer claims.deinit();
+            if (flow.max_age) |max_age| try claims.checkAuthTime(max_age, IdToken.default_leeway);
+            try claims.checkAuthenticationStrength(flow.acr_values, flow.required_amr);
+        }
+        if (flow.audience) |audience| try replaceAudience(&token, audience);
+        return token;
+    }
+
+    /// Ask the user for `scopes` on top of the grant stored under `key`
+    ///
+    /// Runs the browser flow of authorize() for the stored scopes plus
+    /// `scopes`, with `include_granted_scopes=true` so providers that
+    /// support incremental authorization keep the earlier consent. The
+    /// scopes granted before and now are merged into the stored token.
+    /// Returns `error.TokenNotFound` without a stored token.
+    pub fn requestAdditionalScopes(self: *OAuthClient, key: []const u8, scopes: []const u8) !Token {
+        var existing = (try self.getToken(key)) orelse return error.TokenNotFound;
+        defer existing.deinit();
+
+        const requested = try mergeScopes(self.allocator, existing.scope orelse self.config.scope, scopes);
+        defer self.allocator.free(requested);
+
+        var token = try self.authorizeWithOptions(.{
+            .scope = requested,
+            .include_granted_scopes = true,
+            .audience = existing.audience,
+        });
+        errdefer token.deinit();
+
+        try self.recordAdditionalScopes(key, &existing, &token, requested);
+        return token;
+    }
+
+    /// Merge an incremental grant into `token` and save it under `key`
+    ///
+    /// Servers that answer with only the new scopes, or none, still end up
+    /// with the union recorded. The earlier refresh token is kept if the
+    /// server did not issue a new one.
+    fn recordAdditionalScopes(self: *OAuthClient, key: []const u8, existing: *const Token, token: *Token, requested: []const u8) !void {
+        const merged = try mergeScopes(token.allocator, existing.scope, token.scope orelse requested);
+        if (token.scope) |previous| token.allocator.free(previous);
+        token.scope = merged;
+
+        if (token.refresh_token == null) {
+            if (existing.refresh_token) |refresh_token| token.refresh_token = try token.allocator.dupe(u8, refresh_token);
+        }
+        try self.saveToken(key, token.*);
+    }
+
+    /// Verify ID token signatures with a shared, caching key set
+    ///
+    /// Without a cache the keys at `config.jwks_uri` are downloaded for every
+    /// ID token. The cache is borrowed and must outlive the client; its
+    /// `jwks_uri` takes precedence over the config's.
+    pub fn withJwksCache(self: *OAuthClient, cache: *jwks.JwksCache) void {
+        self.jwks_cache = cache;
+    }
+
+    /// Validate `raw` as an ID token issued to this client
+    ///
+    /// Verifies the signature against the provider keys (or the client
+    /// secret for HMAC-signed tokens) and checks the claims against
+    /// `config.issuer`, `config.client_id` and, when given, `nonce`. Returns
+    /// `error.ConfigurationError` without an issuer, or without a JWKS URI
+    /// or cache. Caller owns the returned claims.
+    pub fn validateIdToken(self: *OAuthClient, raw: []const u8, nonce: ?[]const u8) !IdToken {
+        const validation = IdToken.Validation{
+            .issuer = self.config.issuer orelse return error.ConfigurationError,
+            .client_id = self.config.client_id,
+            .nonce = nonce,
+            .verification = self.id_token_verification,
+            .client_secret = self.config.client_secret,
+        };
+
+        try self.verifyProviderSignature(raw);
+
+        var claims = try IdToken.decode(self.allocator, raw);
+        errdefer claims.deinit();
+
+        try claims.checkClaims(validation);
+        return claims;
+    }
+
+    /// Verify and unpack a JWT-secured authorization response (JARM)
+    ///
+    /// When `result` carries a `response` parameter, verifies its signature
+    /// against the provider keys, checks it against `config.issuer` and
+    /// `config.client_id`, and replaces the result's code, state and error
+    /// with the signed values. Plain callbacks pass through unless
+    /// `config.response_mode` asks for a JWT mode, in which case they are
+    /// rejected with `error.InvalidAuthorizationResponse`. Returns
+    /// `error.ConfigurationError` without an issuer.
+    pub fn unwrapJarmResponse(self: *OAuthClient, result: *CallbackResult) !void {
+        const raw = result.response orelse {
+            if (self.config.response_mode) |mode| {
+                if (mode.isJwt()) return error.InvalidAuthorizationResponse;
+            }
+            return;
+        };
+        const issuer = self.config.issuer orelse return error.ConfigurationError;
+
+        try self.verifyProviderSignature(raw);
+        try jarm.unwrap(self.allocator, raw, .{
+            .issuer = issuer,
+            .client_id = self.config.client_id,
+        }, result);
+    }
+
+    /// Verify the signature of a JWT issued by the provider
+    ///
+    /// Uses the JWKS cache when set, otherwise downloads `config.jwks_uri`.
+    fn verifyProviderSignature(self: *OAuthClient, raw: []const u8) !void {
+        if (self.jwks_cache) |cache| {
+            try cache.verify(raw, self.id_token_verification, self.config.client_secret);
+        } else {
+            const jwks_uri = self.config.jwks_uri orelse return error.ConfigurationError;
+            var keys = try jwks.fetch(self.allocator, self.httpTransport(), jwks_uri);
+            defer keys.deinit();
+            try keys.verify(self.allocator, raw, self.id_token_verification, self.config.client_secret);
+        }
+    }
+
+    /// Fetch the UserInfo claims of the user the token under `key` belongs to
+    ///
+    /// Calls `config.userinfo_endpoint` with the stored access token, adding
+    /// a proof for DPoP-bound tokens. When the token carries an ID token the
+    /// returned `sub` must match it, or `error.InvalidIdToken` is returned.
+    /// Returns `error.TokenNotFound` without a stored token and
+    /// `error.TokenExpired` when the endpoint rejects it. Caller owns the
+    /// result.
+    pub fn fetchUserInfo(self: *OAuthClient, key: []const u8) !UserInfo {
+        const endpoint = self.config.userinfo_endpoint orelse return error.ConfigurationError;
+
+        var token = (try self.getToken(key)) orelse return error.TokenNotFound;
+        defer token.deinit();
+
+        const scheme = if (token.dpop_key != null) "DPoP" else "Bearer";
+        const authorization = try std.fmt.allocPrint(self.allocator, "{s} {s}", .{ scheme, token.access_token });
+        defer self.allocator.free(authorization);
+
+        const proof = if (token.dpop_key != null)
+            try dpop.proofForToken(self.allocator, &token, "GET", endpoint, null)
+        else
+            null;
+        defer if (proof) |p| self.allocator.free(p);
+
+        const headers = [_]transport.Header{
+            .{ .name = "Authorization", .value = authorization },
+            .{ .name = "Accept", .value = "application/json" },
+            .{ .name = "DPoP", .value = proof orelse "" },
+        };
+        const sent: []const transport.Header = if (proof != null) &headers else headers[0..2];
+
+        var response = try self.httpTransport().send(self.allocator, .{
+            .method = .GET,
+            .url = endpoint,
+            .headers = sent,
+            .client_certificate = self.config.client_certificate,
+        });
+        defer response.deinit();
+
+        if (response.status == 401) return error.TokenExpired;
+        if (response.status != 200) return error.ServerError;
+
+        var info = try UserInfo.parse(self.allocator, response.body);
+        errdefer info.deinit();
+
+        // Guards against UserInfo responses for a different user
+        if (token.id_token) |raw| {
+            var claims = try IdToken.decode(self.allocator, raw);
+            defer claims.deinit();
+            if (!std.mem.eql(u8, claims.sub, info.sub)) return error.InvalidIdToken;
+        }
+        return info;
+    }
+
+    /// Exchange an authorization code for a token
+    pub fn exchangeCode(self: *OAuthClient, code: []const u8, verifier: []const u8, redirect_uri: []const u8) !Token {
+        return self.exchangeCodeWithResources(code, verifier, redirect_uri, &.{});
+    }
+
+    /// Exchange an authorization code, sending each of `resources` (RFC 8707 Section 2.2)
+    fn exchangeCodeWithResources(
+        self: *OAuthClient,
+        code: []const u8,
+        verifier: []const u8,
+        redirect_uri: []const u8,
+        resources: []const []const u8,
+    ) !Token {
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer body.deinit(self.allocator);
+
+        try self.buildCodeExchangeBody(&body, code, verifier, redirect_uri, resources);
+        const token = try self.requestToken(body.items);
+        self.emitCodeExchanged(&token);
+        return token;
+    }
+
+    /// Exchange an authorization code and also return the response status and headers
+    ///
+    /// Useful for debugging and for providers that report rate limits or
+    /// request IDs in headers. `Set-Cookie` headers are dropped unless
+    /// `options.include_cookies` is set.
+    pub fn exchangeCodeDetailed(
+        self: *OAuthClient,
+        code: []const u8,
+        verifier: []const u8,
+        redirect_uri: []const u8,
+        options: ResponseMeta.Options,
+    ) !DetailedToken {
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer body.deinit(self.allocator);
+
+        try self.buildCodeExchangeBody(&body, code, verifier, redirect_uri, &.{});
+
+        var meta: ResponseMeta = undefined;
+        const token = try self.requestTokenWithMeta(body.items, &meta, options);
+        self.emitCodeExchanged(&token);
+        return .{ .token = token, .meta = meta };
+    }
+
+    fn emitCodeExchanged(self: *const OAuthClient, token: *const Token) void {
+        self.emitEvent(.{ .code_exchanged = .{
+            .token_endpoint = self.config.token_endpoint,
+            .scope = token.scope,
+            .expires_at = token.expires_at,
+        } });
+    }
+
+    fn buildCodeExchangeBody(
+        self: *OAuthClient,
+        body: *std.ArrayListUnmanaged(u8),
+        code: []const u8,
+        verifier: []const u8,
+        redirect_uri: []const u8,
+        resources: []const []const u8,
+    ) !void {
+        try body.appendSlice(self.allocator, "grant_type=authorization_code");
+        try body.appendSlice(self.allocator, "&code=");
+        try appendUrlEncoded(self.allocator, body, code);
+        try body.appendSlice(self.allocator, "&redirect_uri=");
+        try appendUrlEncoded(self.allocator, body, redirect_uri);
+        try body.appendSlice(self.allocator, "&code_verifier=");
+        try appendUrlEncoded(self.allocator, body, verifier);
+        for (resources) |resource| {
+            try body.appendSlice(self.allocator, "&resource=");
+            try appendUrlEncoded(self.allocator, body, resource);
+        }
+        try self.appendClientAuth(body);
+    }
+
+    /// Exchange an authorization code and check the grant against the session
+    ///
+    /// Compares the granted scope with `auth_session.requested_scope` so the
+    /// caller can re-prompt or disable features when the user declined some
+    /// of the requested scopes. When the session recorded a nonce, an ID
+    /// token in the response must carry it or `error.InvalidNonce` is
+    /// returned.
+    pub fn exchangeCodeForSession(
+        self: *OAuthClient,
+        auth_session: *const session.Session,
+        code: []const u8,
+        verifier: []const u8,
+        redirect_uri: []const u8,
+    ) !ExchangeResult {
+        var token = try self.exchangeCode(code, verifier, redirect_uri);
+        errdefer token.deinit();
+
+        if (auth_session.nonce) |expected| {
+            if (token.id_token) |raw| try checkNonceClaim(self.allocator, raw, expected);
+        }
+
+        return ExchangeResult.init(self.allocator, token, auth_session.requested_scope);
+    }
+
+    /// Refresh an access token using a refresh token
+    pub fn refreshToken(self: *OAuthClient, refresh_token: []const u8) !Token {
+        return self.refreshTokenWithScope(refresh_token, null);
+    }
+
+    /// Refresh an access token, sending `scope` when not null
+    ///
+    /// Per RFC 6749 Section 6 the scope must not include anything the
+    /// original grant did not.
+    pub fn refreshTokenWithScope(self: *OAuthClient, refresh_token: []const u8, scope: ?[]const u8) !Token {
+        return self.refreshTokenWithKey(refresh_token, scope, null, self.config.audience, self.dpop_key);
+    }
+
+    /// Obtain an access token for `resource` from the grant stored under `key` (RFC 8707)
+    ///
+    /// Uses the grant's refresh token with a `resource` parameter, so the
+    /// server can issue a token restricted to that API. The result is saved
+    /// under resourceKey(key, resource) with `resource` recorded but no
+    /// refresh token: the grant under `key` keeps the only copy, and a
+    /// rotated one is written back to it. Mint a new resource token from
+    /// the grant once it expires (TokenRefresher.getValidTokenForResource()
+    /// does, serialized with refreshes of the grant).
+    pub fn requestResourceToken(self: *OAuthClient, key: []const u8, resource: []const u8) !Token {
+        try validateResource(resource);
+
+        var grant = (try self.getToken(key)) orelse return error.TokenNotFound;
+        defer grant.deinit();
+        const refresh_token = grant.refresh_token orelse return error.NoRefreshToken;
+
+        const dpop_key = if (grant.dpop_key) |encoded| try dpop.DpopKey.decode(encoded) else self.dpop_key;
+        var token = try self.refreshTokenWithKey(refresh_token, null, resource, grant.audience orelse self.config.audience, dpop_key);
+        errdefer token.deinit();
+
+        if (token.refresh_token) |issued| {
+            try self.adoptRotatedRefreshToken(key, &grant, issued);
+            self.allocator.free(issued);
+            token.refresh_token = null;
+        }
+        if (token.resource) |previous| self.allocator.free(previous);
+        token.resource = try self.allocator.dupe(u8, resource);
+
+        const stored_key = try resourceKey(self.allocator, key, resource);
+        defer self.allocator.free(stored_key);
+        try self.saveToken(stored_key, token);
+        return token;
+    }
+
+    /// Mint a token restricted to `scope` from the grant stored under `key`
+    ///
+    /// Refreshes the grant with the narrower `scope`, which must be covered
+    /// by the grant's scope, and saves the result under
+    /// downscopedKey(key, scope). The downscoped token gets no refresh
+    /// token, so the broad grant never leaves `key`; mint a new one once it
+    /// expires (TokenRefresher.getValidTokenForScope() does). A rotated
+    /// refresh token is written back to the grant.
+    pub fn requestDownscopedToken(self: *OAuthClient, key: []const u8, scope: []const u8) !Token {
+        if (std.mem.trim(u8, scope, " ").len == 0) return error.InvalidParameter;
+
+        var grant = (try self.getToken(key)) orelse return error.TokenNotFound;
+        defer grant.deinit();
+        const refresh_token = grant.refresh_token orelse return error.NoRefreshToken;
+        if (grant.scope) |granted| try checkScopeSubset(granted, scope);
+
+        const dpop_key = if (grant.dpop_key) |encoded| try dpop.DpopKey.decode(encoded) else self.dpop_key;
+        var token = try self.refreshTokenWithKey(refresh_token, scope, grant.resource, grant.audience orelse self.config.audience, dpop_key);
+        errdefer token.deinit();
+
+        if (token.refresh_token) |issued| {
+            try self.adoptRotatedRefreshToken(key, &grant, issued);
+            self.allocator.free(issued);
+            token.refresh_token = null;
+        }
+        if (token.scope == null) token.scope = try self.allocator.dupe(u8, scope);
+
+        const stored_key = try downscopedKey(self.allocator, key, scope);
+        defer self.allocator.free(stored_key);
+        try self.saveToken(stored_key, token);
+        return token;
+    }
+
+    /// Save `issued` as the refresh token of `grant` if the server rotated it
+    fn adoptRotatedRefreshToken(self: *OAuthClient, key: []const u8, grant: *Token, issued: []const u8) !void {
+        const previous = grant.refresh_token orelse return;
+        if (std.mem.eql(u8, issued, previous)) return;
+
+        grant.refresh_token = try self.allocator.dupe(u8, issued);
+        self.allocator.free(previous);
+        try self.saveToken(key, grant.*);
+    }
+
+    /// Refresh with proofs signed by `dpop_key` (the key a DPoP-bound token is bound to)
+    fn refreshTokenWithKey(
+        self: *OAuthClient,
+        refresh_token: []const u8,
+        scope: ?[]const u8,
+        resource: ?[]const u8,
+        audience: ?[]const u8,
+        dpop_key: ?dpop.DpopKey,
+    ) !Token {
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer body.deinit(self.allocator);
+
+        try body.appendSlice(self.allocator, "grant_type=refresh_token");
+        try body.appendSlice(self.allocator, "&refresh_token=");
+        try appendUrlEncoded(self.allocator, &body, refresh_token);
+        if (scope) |s| {
+            try body.appendSlice(self.allocator, "&scope=");
+            try appendUrlEncoded(self.allocator, &body, s);
+        }
+        if (resource) |r| {
+            try body.appendSlice(self.allocator, "&resource=");
+            try appendUrlEncoded(self.allocator, &body, r);
+        }
+        if (audience) |a| {
+            try body.appendSlice(self.allocator, "&audience=");
+            try appendUrlEncoded(self.allocator, &body, a);
+        }
+        try self.appendClientAuth(&body);
+
+        return self.sendTokenRequest(body.items, null, .{}, dpop_key, null);
+    }
+
+    /// Exchange a SAML 2.0 assertion for an access token (RFC 7522)
+    ///
+    /// `assertion_xml` is the raw assertion document; it is base64url-encoded
+    /// (unpadded) before being sent. The resulting token is saved under `key`.
+    /// An expired or otherwise rejected assertion yields `error.InvalidGrant`.
+    pub fn exchangeSamlAssertion(
+        self: *OAuthClient,
+        key: []const u8,
+        assertion_xml: []const u8,
+        scope: ?[]const u8,
+    ) !Token {
+        if (assertion_xml.len == 0) return error.InvalidParameter;
+
+        const encoder = std.base64.url_safe_no_pad.Encoder;
+        const encoded = try self.allocator.alloc(u8, encoder.calcSize(assertion_xml.len));
+        defer self.allocator.free(encoded);
+        _ = encoder.encode(encoded, assertion_xml);
+
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer body.deinit(self.allocator);
+
+        try body.appendSlice(self.allocator, "grant_type=urn:ietf:params:oauth:grant-type:saml2-bearer");
+        try body.appendSlice(self.allocator, "&assertion=");
+        try appendUrlEncoded(self.allocator, &body, encoded);
+        if (scope orelse self.config.scope) |s| {
+            try body.appendSlice(self.allocator, "&scope=");
+            try appendUrlEncoded(self.allocator, &body, s);
+        }
+        try self.appendClientAuth(&body);
+
+        var token = try self.requestToken(body.items);
+        errdefer token.deinit();
+
+        try self.saveTokenForAudience(key, token);
+        return token;
+    }
+
+    /// Exchange a signed JWT assertion for an access token (RFC 7523 Section 2.1)
+    ///
+    /// `assertion` is a compact JWS issued by a party the server trusts.
+    /// The resulting token is saved under `key`. An expired or otherwise
+    /// rejected assertion yields `error.InvalidGrant`.
+    pub fn exchangeJwtAssertion(
+        self: *OAuthClient,
+        key: []const u8,
+        assertion: []const u8,
+        scope: ?[]const u8,
+    ) !Token {
+        _ = try jwt.split(assertion);
+
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer body.deinit(self.allocator);
+
+        try body.appendSlice(self.allocator, "grant_type=urn:ietf:params:oauth:grant-type:jwt-bearer");
+        try body.appendSlice(self.allocator, "&assertion=");
+        try appendUrlEncoded(self.allocator, &body, assertion);
+        if (scope orelse self.config.scope) |s| {
+            try body.appendSlice(self.allocator, "&scope=");
+            try appendUrlEncoded(self.allocator, &body, s);
+        }
+        try self.appendClientAuth(&body);
+
+        var token = try self.requestToken(body.items);
+        errdefer token.deinit();
+
+        try self.saveTokenForAudience(key, token);
+        return token;
+    }
+
+    /// Sign `claims` with `signing_key` and exchange the assertion (see exchangeJwtAssertion)
+    ///
+    /// `claims.audience` defaults to the token endpoint when empty.
+    pub fn exchangeSignedJwt(
+        self: *OAuthClient,
+        key: []const u8,
+        signing_key: jwt.SigningKey,
+        claims: jwt.AssertionClaims,
+        kid: ?[]const u8,
+        scope: ?[]const u8,
+    ) !Token {
+        var assertion_claims = claims;
+        if (assertion_claims.audience.len == 0) assertion_claims.audience = self.config.token_endpoint;
+
+        const assertion = try jwt.signAssertion(self.allocator, signing_key, assertion_claims, kid);
+        defer self.allocator.free(assertion);
+
+        return self.exchangeJwtAssertion(key, assertion, scope);
+    }
+
+    /// Obtain a token for the client itself (RFC 6749 Section 4.4)
+    ///
+    /// For backend services where no user is present. Requires client
+    /// credentials (a secret, a private key or a client certificate); the
+    /// token is saved under `key`. The scope
+    /// defaults to `config.scope`. Servers do not issue refresh tokens for
+    /// this grant, so request a new token once it expires.
+    pub fn clientCredentials(self: *OAuthClient, key: []const u8, scope: ?[]const u8) !Token {
+        return self.clientCredentialsWithAudience(key, scope, null);
+    }
+
+    /// Like clientCredentials(), for `audience` instead of `config.audience`
+    ///
+    /// A token for an audience other than the configured one is saved under
+    /// audienceKey(key, audience), so it does not replace the default token.
+    pub fn clientCredentialsWithAudience(
+        self: *OAuthClient,
+        key: []const u8,
+        scope: ?[]const u8,
+        audience: ?[]const u8,
+    ) !Token {
+        const confidential = self.config.client_secret != null or
+            self.config.client_certificate != null or
+            self.config.client_auth == .private_key_jwt;
+        if (!confidential) return error.InvalidParameter;
+
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer body.deinit(self.allocator);
+
+        try body.appendSlice(self.allocator, "grant_type=client_credentials");
+        if (scope orelse self.config.scope) |s| {
+            try body.appendSlice(self.allocator, "&scope=");
+            try appendUrlEncoded(self.allocator, &body, s);
+        }
+        const requested_audience = audience orelse self.config.audience;
+        if (requested_audience) |value| {
+            try body.appendSlice(self.allocator, "&audience=");
+            try appendUrlEncoded(self.allocator, &body, value);
+        }
+        try self.appendClientAuth(&body);
+
+        var token = try self.requestToken(body.items);
+        errdefer token.deinit();
+
+        if (requested_audience) |value| try replaceAudience(&token, value);
+
+        try self.saveTokenForAudience(key, token);
+        return token;
+    }
+
+    /// Obtain a token with the user's username and password (RFC 6749 Section 4.3)
+    ///
+    /// Only for identity providers that support nothing else; the grant is
+    /// removed in OAuth 2.1 and needs `-Dlegacy-grants=true`; clients in
+    /// `.oauth21` compliance mode get `error.UnsupportedOperation`. The token is
+    /// saved under `key`, so TokenRefresher keeps it fresh like any other.
+    /// The password is not retained.
+    pub fn passwordGrant(
+        self: *OAuthClient,
+        key: []const u8,
+        username: []const u8,
+        password: []const u8,
+        scope: ?[]const u8,
+    ) !Token {
+        if (!build_options.legacy_grants) {
+            @compileError("passwordGrant requires building with -Dlegacy-grants=true");
+        }
+        if (self.config.compliance == .oauth21) return error.UnsupportedOperation;
+        if (username.len == 0 or password.len == 0) return error.InvalidParameter;
+
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer {
+            // The body holds the password
+            @memset(body.items, 0);
+            body.deinit(self.allocator);
+        }
+
+        try body.appendSlice(self.allocator, "grant_type=password");
+        try body.appendSlice(self.allocator, "&username=");
+        try appendUrlEncoded(self.allocator, &body, username);
+        try body.appendSlice(self.allocator, "&password=");
+        try appendUrlEncoded(self.allocator, &body, password);
+        if (scope orelse self.config.scope) |s| {
+            try body.appendSlice(self.allocator, "&scope=");
+            try appendUrlEncoded(self.allocator, &body, s);
+        }
+        if (self.config.audience) |audience| {
+            try body.appendSlice(self.allocator, "&audience=");
+            try appendUrlEncoded(self.allocator, &body, audience);
+        }
+        try self.appendClientAuth(&body);
+
+        var token = try self.requestToken(body.items);
+        errdefer token.deinit();
+
+        try self.saveTokenForAudience(key, token);
+        return token;
+    }
+
+    /// Exchange one token for another (RFC 8693)
+    ///
+    /// Typically used to trade the CLI's token for one scoped to a
+    /// downstream API. The issued token is returned but not stored.
+    pub fn exchangeToken(self: *OAuthClient, request: TokenExchangeRequest) !Token {
+        if (request.subject_token.len == 0) return error.InvalidParameter;
+        if (request.actor_token != null and request.actor_token_type == null) return error.InvalidParameter;
+
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer body.deinit(self.allocator);
+
+        try body.appendSlice(self.allocator, "grant_type=urn:ietf:params:oauth:grant-type:token-exchange");
+        try body.appendSlice(self.allocator, "&subject_token=");
+        try appendUrlEncoded(self.allocator, &body, request.subject_token);
+        try body.appendSlice(self.allocator, "&subject_token_type=");
+        try appendUrlEncoded(self.allocator, &body, request.subject_token_type);
+
+        const optional_params = [_]struct { []const u8, ?[]const u8 }{
+            .{ "&actor_token=", request.actor_token },
+            .{ "&actor_token_type=", request.actor_token_type },
+            .{ "&requested_token_type=", request.requested_token_type },
+            .{ "&audience=", request.audience },
+            .{ "&resource=", request.resource },
+            .{ "&scope=", request.scope },
+        };
+        for (optional_params) |param| {
+            const value = param[1] orelse continue;
+            try body.appendSlice(self.allocator, param[0]);
+            try appendUrlEncoded(self.allocator, &body, value);
+        }
+        try self.appendClientAuth(&body);
+
+        return self.requestToken(body.items);
+    }
+
+    /// Obtain a token with a custom grant type (see GrantRequest)
+    ///
+    /// The token is saved under `key`. Error responses map to errors as for
+    /// the built-in grants.
+    pub fn requestGrant(self: *OAuthClient, key: []const u8, grant: GrantRequest) !Token {
+        const grant_type = grant.grantType();
+        if (grant_type.len == 0) return error.InvalidParameter;
+
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer {
+            // Custom grants often carry one-time passwords or assertions
+            @memset(body.items, 0);
+            body.deinit(self.allocator);
+        }
+
+        try body.appendSlice(self.allocator, "grant_type=");
+        try appendUrlEncoded(self.allocator, &body, grant_type);
+        var params = GrantParams{ .allocator = self.allocator, .body = &body };
+        try grant.appendParams(&params);
+        try self.appendClientAuth(&body);
+
+        var token = try self.sendTokenRequest(body.items, null, .{}, self.dpop_key, grant);
+        errdefer token.deinit();
+
+        try self.saveTokenForAudience(key, token);
+        return token;
+    }
+
+    /// Save a token to storage
+    pub fn saveToken(self: *OAuthClient, key: []const u8, token: Token) !void {
+        try self.storage.save(key, token);
+    }
+
+    /// Save a token under the key for its audience
+    ///
+    /// Tokens issued for an audience other than `config.audience` go to
+    /// audienceKey(key, token.audience), so they never replace the token
+    /// for the configured audience. Grants that save under a key use this.
+    pub fn saveTokenForAudience(self: *OAuthClient, key: []const u8, token: Token) !void {
+        const stored_key = try self.audienceStorageKey(key, token.audience);
+        defer self.allocator.free(stored_key);
+        try self.saveToken(stored_key, token);
+    }
+
+    /// Storage key saveTokenForAudience() uses for `audience` under `key`
+    ///
+    /// `key` itself for no audience or `config.audience`. Caller owns the
+    /// returned key.
+    pub fn audienceStorageKey(self: *OAuthClient, key: []const u8, audience: ?[]const u8) ![]u8 {
+        const value = audience orelse return self.allocator.dupe(u8, key);
+        if (self.config.audience) |configured| {
+            if (std.mem.eql(u8, value, configured)) return self.allocator.dupe(u8, key);
+        }
+        return audienceKey(self.allocator, key, value);
+    }
+
+    /// Get a token from storage
+    pub fn getToken(self: *OAuthClient, key: []const u8) !?Token {
+        return try self.storage.load(self.allocator, key);
+    }
+
+    /// Get the audit record of the request that issued the token under `key`
+    ///
+    /// Returns null if there is no token or it was issued without
+    /// withRequestAudit() enabled.
+    pub fn lastTokenRequestAudit(self: *OAuthClient, key: []const u8) !?RequestAudit {
+        var token = try self.storage.load(self.allocator, key) orelse return null;
+        defer token.deinit();
+
+        const data = token.request_audit orelse return null;
+        return try RequestAudit.fromJson(self.allocator, data);
+    }
+
+    /// Delete a token from storage
+    ///
+    /// Only removes the local copy; the token stays valid at the server
+    /// (see revokeToken()).
+    pub fn deleteToken(self: *OAuthClient, key: []const u8) !void {
+        try self.storage.delete(key);
+    }
+
+    /// Delete a token the server has just revoked and report it
+    fn deleteRevokedToken(self: *OAuthClient, key: []const u8) !void {
+        try self.deleteToken(key);
+        self.emitEvent(.{ .token_revoked = .{ .key = key } });
+    }
+
+    /// End the local sessions a provider-initiated logout names
+    ///
+    /// `raw` is the `logout_token` the provider posted to the client's
+    /// back-channel logout URI. Its signature is verified against the
+    /// provider keys and its claims against `config.issuer` and
+    /// `config.client_id`; then every stored token whose ID token matches
+    /// its `sid` and `sub` is deleted. Returns the number of tokens
+    /// removed. Needs storage that can list its keys; tokens without an ID
+    /// token are never matched.
+    pub fn handleBackchannelLogout(self: *OAuthClient, raw: []const u8) !usize {
+        const validation = LogoutToken.Validation{
+            .issuer = self.config.issuer orelse return error.ConfigurationError,
+            .client_id = self.config.client_id,
+            .verification = self.id_token_verification,
+            .client_secret = self.config.client_secret,
+        };
+
+        var fetched: ?jwks.JwkSet = null;
+        defer if (fetched) |*set| set.deinit();
+        const signing_keys: LogoutToken.Keys = if (self.jwks_cache) |cache| .{ .cache = cache } else fetch: {
+            const jwks_uri = self.config.jwks_uri orelse return error.ConfigurationError;
+            fetched = try jwks.fetch(self.allocator, self.httpTransport(), jwks_uri);
+            break :fetch .{ .set = &fetched.? };
+        };
+
+        var logout_token = try LogoutToken.validate(self.allocator, raw, signing_keys, validation);
+        defer logout_token.deinit();
+
+        const keys = try self.storage.listKeys(self.allocator);
+        defer SessionStorage.freeKeys(self.allocator, keys);
+
+        var removed: usize = 0;
+        for (keys) |key| {
+            var token = (try self.storage.load(self.allocator, key)) orelse continue;
+            defer token.deinit();
+
+            const id_token = token.id_token orelse continue;
+            var claims = IdToken.decode(self.allocator, id_token) catch |err| switch (err) {
+                error.OutOfMemory => return err,
+                else => continue,
+            };
+            defer claims.deinit();
+
+            if (!logout_token.matches(&claims)) continue;
+            try self.deleteToken(key);
+            removed += 1;
+        }
+        return removed;
+    }
+
+    /// Sign the user out of the session behind the token stored under `key`
+    ///
+    /// Optionally revokes the refresh token first; if revocation fails the
+    /// token is kept so the call can be retried. The token is then removed
+    /// from storage. Returns the provider's end_session URL, carrying the ID
+    /// token as `id_token_hint`, for the caller to open in the browser, or
+    /// null when the provider has no `end_session_endpoint`. Signing out
+    /// without a stored token is not an error. Caller owns the URL.
+    pub fn logout(self: *OAuthClient, key: []const u8, options: LogoutOptions) !?[]u8 {
+        var stored = try self.getToken(key);
+        defer if (stored) |*token| token.deinit();
+
+        var revoked = false;
+        if (options.revoke_refresh_token) {
+            if (stored) |token| {
+                if (token.refresh_token) |refresh_token| {
+                    try self.revokeAtServer(refresh_token, "refresh_token");
+                    revoked = true;
+                }
+            }
+        }
+
+        const url = if (self.config.end_session_endpoint) |endpoint|
+            try self.buildEndSessionUrl(endpoint, if (stored) |token| token.id_token else null, options)
+        else
+            null;
+        errdefer if (url) |u| self.allocator.free(u);
+
+        if (revoked) try self.deleteRevokedToken(key) else try self.deleteToken(key);
+        return url;
+    }
+
+    fn buildEndSessionUrl(
+        self: *OAuthClient,
+        endpoint: []const u8,
+        id_token_hint: ?[]const u8,
+        options: LogoutOptions,
+    ) ![]u8 {
+        var url: std.ArrayListUnmanaged(u8) = .{};
+        errdefer url.deinit(self.allocator);
+
+        try url.appendSlice(self.allocator, endpoint);
+        try url.append(self.allocator, if (std.mem.indexOfScalar(u8, endpoint, '?') == null) '?' else '&');
+        try url.appendSlice(self.allocator, "client_id=");
+        try appendUrlEncoded(self.allocator, &url, self.config.client_id);
+
+        const params = .{
+            .{ "id_token_hint", id_token_hint },
+            .{ "post_logout_redirect_uri", options.post_logout_redirect_uri },
+            .{ "state", options.state },
+        };
+        inline for (params) |param| {
+            if (param[1]) |value| {
+                try url.appendSlice(self.allocator, "&" ++ param[0] ++ "=");
+                try appendUrlEncoded(self.allocator, &url, value);
+            }
+        }
+        return url.toOwnedSlice(self.allocator);
+    }
+
+    /// Ask the authorization server about `token` (RFC 7662)
+    ///
+    /// Posts to `config.introspection_endpoint` with the client's
+    /// credentials, which most servers require. `token_type_hint`
+    /// ("access_token" or "refresh_token") speeds up the lookup. An unknown,
+    /// expired or revoked token is reported as `active: false`, not as an
+    /// error. Caller owns the result.
+    pub fn introspect(self: *OAuthClient, token: []const u8, token_type_hint: ?[]const u8) !IntrospectionResponse {
+        const endpoint = self.config.introspection_endpoint orelse return error.ConfigurationError;
+
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer {
+            std.crypto.secureZero(u8, body.items);
+            body.deinit(self.allocator);
+        }
+
+        try body.appendSlice(self.allocator, "token=");
+        try appendUrlEncoded(self.allocator, &body, token);
+        if (token_type_hint) |hint| {
+            try body.appendSlice(self.allocator, "&token_type_hint=");
+            try appendUrlEncoded(self.allocator, &body, hint);
+        }
+        try self.appendClientAuth(&body);
+
+        var response = try self.postForm(endpoint, body.items);
+        defer response.deinit();
+
+        if (response.status != 200) return tokenErrorFromResponse(self.allocator, response.body);
+        return IntrospectionResponse.parse(self.allocator, response.body);
+    }
+
+    /// Revoke the token stored under `key` at the server, then delete it
+    ///
+    /// Revokes the refresh token (if any) and the access token at
+    /// `config.revocation_endpoint` (RFC 7009). The token stays in storage
+    /// if revocation fails, so the call can be retried. Returns
+    /// `error.TokenNotFound` without a stored token and
+    /// `error.UnsupportedOperation` without a revocation endpoint.
+    pub fn revokeToken(self: *OAuthClient, key: []const u8) !void {
+        var token = (try self.getToken(key)) orelse return error.TokenNotFound;
+        defer token.deinit();
+
+        if (token.refresh_token) |refresh_token| {
+            try self.revokeAtServer(refresh_token, "refresh_token");
+        }
+        try self.revokeAtServer(token.access_token, "access_token");
+        try self.deleteRevokedToken(key);
+    }
+
+    /// Revoke only the refresh token stored under `key`, then delete the token
+    ///
+    /// Servers typically revoke access tokens issued from the same grant
+    /// along with it. Returns `error.NoRefreshToken` if the token has none.
+    pub fn revokeRefreshToken(self: *OAuthClient, key: []const u8) !void {
+        var token = (try self.getToken(key)) orelse return error.TokenNotFound;
+        defer token.deinit();
+
+        const refresh_token = token.refresh_token orelse return error.NoRefreshToken;
+        try self.revokeAtServer(refresh_token, "refresh_token");
+        try self.deleteRevokedToken(key);
+    }
+
+    /// Revoke `token_value` at `config.revocation_endpoint` (RFC 7009)
+    ///
+    /// `token_type_hint` is "access_token" or "refresh_token".
+    fn revokeAtServer(self: *OAuthClient, token_value: []const u8, token_type_hint: []const u8) !void {
+        const endpoint = self.config.revocation_endpoint orelse return error.UnsupportedOperation;
+
+        var body: std.ArrayListUnmanaged(u8) = .{};
+        defer {
+            std.crypto.secureZero(u8, body.items);
+            body.deinit(self.allocator);
+        }
+
+        try body.appendSlice(self.allocator, "token=");
+        try appendUrlEncoded(self.allocator, &body, token_value);
+        try body.appendSlice(self.allocator, "&token_type_hint=");
+        try body.appendSlice(self.allocator, token_type_hint);
+        try self.appendClientAuth(&body);
+
+        var response = try self.postForm(endpoint, body.items);
+        defer response.deinit();
+
+        // Unknown or already invalid tokens are answered with 200 too
+        if (response.status == 200) return;
+        if (isOAuthError(self.allocator, response.body, "unsupported_token_type")) return error.UnsupportedOperation;
+        return tokenErrorFromResponse(self.allocator, response.body);
+    }
+
+    /// Append client identification to a token request body
+    fn appendClientAuth(self: *OAuthClient, body: *std.ArrayListUnmanaged(u8)) !void {
+        try body.appendSlice(self.allocator, "&client_id=");
+        try appendUrlEncoded(self.allocator, body, self.config.client_id);
+        try self.appendClientCredentials(body);
+    }
+
+    /// Append the credentials of `config.client_auth` to a form body
+    fn appendClientCredentials(self: *OAuthClient, body: *std.ArrayListUnmanaged(u8)) !void {
+        // With tls_client_auth the certificate is the credential
+        if (self.config.client_certificate != null) return;
+
+        switch (self.config.client_auth) {
+            // Sent as a header by sendForm()
+            .client_secret_basic => {},
+            .client_secret_post => if (self.config.client_secret) |secret| {
+                try body.appendSlice(self.allocator, "&client_secret=");
+                try appendUrlEncoded(self.allocator, body, secret);
+            },
+            .private_key_jwt => |auth| {
+                const assertion = try jwt.signAssertion(self.allocator, auth.key, .{
+                    .issuer = self.config.client_id,
+                    .subject = self.config.client_id,
+                    .audience = self.config.token_endpoint,
+                    .lifetime = 60,
+                }, auth.kid);
+                defer self.allocator.free(assertion);
+
+                try body.appendSlice(self.allocator, "&client_assertion_type=urn%3Aietf%3Aparams%3Aoauth%3Aclient-assertion-type%3Ajwt-bearer");
+                try body.appendSlice(self.allocator, "&client_assertion=");
+                try body.appendSlice(self.allocator, assertion);
+            },
+        }
+    }
+
+    /// POST a grant to the token endpoint and parse the issued token
+    fn requestToken(self: *OAuthClient, body: []const u8) !Token {
+        return self.requestTokenWithMeta(body, null, .{});
+    }
+
+    /// Like requestToken(), optionally capturing the response metadata in `meta`
+    fn requestTokenWithMeta(
+        self: *OAuthClient,
+        body: []const u8,
+        meta: ?*ResponseMeta,
+        options: ResponseMeta.Options,
+    ) !Token {
+        return self.sendTokenRequest(body, meta, options, self.dpop_key, null);
+    }
+
+    fn sendTokenRequest(
+        self: *OAuthClient,
+        body: []const u8,
+        meta: ?*ResponseMeta,
+        options: ResponseMeta.Options,
+        dpop_key: ?dpop.DpopKey,
+        grant: ?GrantRequest,
+    ) !Token {
+        var response = try self.postFormWithDpop(self.config.token_endpoint, body, dpop_key);
+        defer response.deinit();
+
+        if (response.status != 200) {
+            return tokenErrorFromResponse(self.allocator, response.body);
+        }
+
+        var token = try self.tokenFromResponse(&response, grant);
+        errdefer token.deinit();
+
+        try bindDpopKey(&token, dpop_key);
+
+        if (self.record_request_audit) {
+            const audit = try RequestAudit.record(self.allocator, self.config.token_endpoint, body, response.status);
+            if (token.request_audit) |previous| self.allocator.free(previous);
+            token.request_audit = audit;
+        }
+
+        if (meta) |out| {
+            out.* = try ResponseMeta.fromResponse(self.allocator, &response, options);
+        }
+        return token;
+    }
+
+    /// Keep `dpop_key` with a token the server bound to it
+    ///
+    /// Only `token_type: DPoP` tokens are bound; any key carried over from
+    /// the response document is dropped.
+    fn bindDpopKey(token: *Token, dpop_key: ?dpop.DpopKey) !void {
+        if (token.dpop_key) |previous| token.allocator.free(previous);
+        token.dpop_key = null;
+
+        const key = dpop_key orelse return;
+        if (!std.ascii.eqlIgnoreCase(token.token_type, "DPoP")) return;
+        const encoded = key.encode();
+        token.dpop_key = try token.allocator.dupe(u8, &encoded);
+    }
+
+    /// Whether `body` is an OAuth error response with the given `error` code
+    fn isOAuthError(allocator: Allocator, body: []const u8, code: []const u8) bool {
+        const parsed = json.parseFromSlice(json.Value, allocator, body, .{}) catch return false;
+        defer parsed.deinit();
+
+        if (parsed.value != .object) return false;
+        const value = parsed.value.object.get("error") orelse return false;
+        return value == .string and std.mem.eql(u8, value.string, code);
+    }
+
+    /// Map an error response from the token endpoint (RFC 6749 Section 5.2)
+    ///
+    /// Bodies that are not error responses and vendor-specific codes yield
+    /// `error.ServerError`.
+    fn tokenErrorFromResponse(allocator: Allocator, body: []const u8) error_types.OAuthError {
+        var response = (error_types.ErrorResponse.parse(allocator, body) catch return error.OutOfMemory) orelse {
+            return error.ServerError;
+        };
+        defer response.deinit();
+        return response.toError(error.ServerError);
+    }
+
+    /// Parse a successful token endpoint response body
+    ///
+    /// A custom `grant` reads its own fields before the token transform runs.
+    fn tokenFromResponse(self: *OAuthClient, response: *const HttpResponse, grant: ?GrantRequest) !Token {
+        var parsed = try json.parseFromSlice(json.Value, self.allocator, response.body, .{});
+        defer parsed.deinit();
+
+        try self.applyFieldMapping(&parsed.value);
+        var token = try Token.fromJsonValue(self.allocator, parsed.value);
+        errdefer token.deinit();
+
+        if (grant) |custom| try custom.parseResponse(&token, parsed.value);
+        try self.checkCertificateBinding(token.access_token);
+        try self.readAccessTokenTimes(&token);
+
+        // An ID token from an OIDC provider is never passed on unchecked
+        if (token.id_token) |raw| {
+            if (self.config.issuer != null) {
+                var claims = try self.validateIdToken(raw, null);
+                claims.deinit();
+            }
+        }
+
+        _ = self.measureClockDrift(response);
+        self.applyServerClock(&token, response);
+        try self.applyTokenTransform(&token);
+        return token;
+    }
+
+    /// Record `nbf` and `iat` of a JWT access token (see Token.offlineUsability)
+    ///
+    /// Opaque access tokens are left alone.
+    fn readAccessTokenTimes(self: *OAuthClient, token: *Token) !void {
+        const parts = jwt.split(token.access_token) catch return;
+        const payload = jwt.decodeSegment(self.allocator, parts.payload) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return,
+        };
+        defer self.allocator.free(payload);
+
+        var parsed = json.parseFromSlice(json.Value, self.allocator, payload, .{}) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return,
+        };
+        defer parsed.deinit();
+        if (parsed.value != .object) return;
+        token.not_before = timeClaim(parsed.value.object, "nbf");
+        token.issued_at = timeClaim(parsed.value.object, "iat");
+    }
+
+    /// Reject a JWT access token bound to another certificate (RFC 8705 Section 3.1)
+    ///
+    /// Opaque access tokens and JWTs without `cnf.x5t#S256` are accepted.
+    fn checkCertificateBinding(self: *OAuthClient, access_token: []const u8) !void {
+        const certificate = self.config.client_certificate orelse return;
+        const parts = jwt.split(access_token) catch return;
+        const payload = jwt.decodeSegment(self.allocator, parts.payload) catch return;
+        defer self.allocator.free(payload);
+
+        var parsed = json.parseFromSlice(json.Value, self.allocator, payload, .{}) catch return;
+        defer parsed.deinit();
+        if (parsed.value != .object) return;
+        const cnf = parsed.value.object.get("cnf") orelse return;
+        if (cnf != .object) return error.CertificateMismatch;
+        const bound = cnf.object.get("x5t#S256") orelse return;
+        if (bound != .string) return error.CertificateMismatch;
+
+        const thumbprint = try certificate.thumbprint(self.allocator);
+        if (!std.mem.eql(u8, bound.string, &thumbprint)) return error.CertificateMismatch;
+    }
+
+    /// Record the drift against the response's `Date` header
+    ///
+    /// Logs a warning and returns true when it exceeds clock_drift_threshold,
+    /// which usually points at a broken NTP setup on one side.
+    fn measureClockDrift(self: *OAuthClient, response: *const HttpResponse) bool {
+        const date = response.header("Date") orelse return false;
+        const server_now = clock.parseHttpDate(date) orelse return false;
+
+        const drift = @as(i64, @intCast(server_now)) - @as(i64, @intCast(clock.now()));
+        self.clock_drift.store(drift, .monotonic);
+
+        if (@abs(drift) <= self.clock_drift_threshold) return false;
+        std.log.warn("token endpoint clock differs from the local clock by {d}s (threshold {d}s)", .{
+            drift,
+            self.clock_drift_threshold,
+        });
+        return true;
+    }
+
+    /// Rename provider fields in a parsed token response to canonical names
+    fn applyFieldMapping(self: *OAuthClient, value: *json.Value) !void {
+        if (self.field_mapping.len == 0 or value.* != .object) return;
+        const obj = &value.object;
+
+        for (self.field_mapping) |entry| {
+            const field = obj.fetchOrderedRemove(entry.provider) orelse continue;
+            try obj.put(entry.canonical, field.value);
+        }
+    }
+
+    /// Record the server clock offset on `token` and re-anchor its expiry
+    fn applyServerClock(self: *OAuthClient, token: *Token, response: *const HttpResponse) void {
+        if (!self.use_server_clock) return;
+        const date = response.header("Date") orelse return;
+        const server_now = clock.parseHttpDate(date) orelse return;
+
+        token.clock_offset = @as(i64, @intCast(server_now)) - @as(i64, @intCast(clock.now()));
+        if (token.expires_in) |expires_in| {
+            token.expires_at = server_now + expires_in;
+        }
+    }
+
+    fn applyTokenTransform(self: *OAuthClient, token: *Token) !void {
+        const hook = self.token_transform orelse return;
+        const had_expiry = token.expires_at != null;
+
+        try hook.apply(hook.context, token);
+
+        // The hook must leave a usable, refreshable token behind
+        if (token.access_token.len == 0 or token.token_type.len == 0) {
+            return error.InvalidParameter;
+        }
+        if (had_expiry and token.expires_at == null) {
+            return error.InvalidParameter;
+        }
+    }
+
+    fn parseDeviceResponse(allocator: Allocator, value: json.Value) !DeviceAuthorizationResponse {
+        // Validate input is an object
+        if (value != .object) return error.ServerError;
+        const obj = value.object;
+
+        // Validate required fields exist and have correct types
+        const device_code_val = obj.get("device_code") orelse return error.ServerError;
+        if (device_code_val != .string) return error.ServerError;
+
+        const user_code_val = obj.get("user_code") orelse return error.ServerError;
+        if (user_code_val != .string) return error.ServerError;
+
+        const verification_uri_val = obj.get("verification_uri") orelse return error.ServerError;
+        if (verification_uri_val != .string) return error.ServerError;
+
+        const expires_in_val = obj.get("expires_in") orelse return error.ServerError;
+        if (expires_in_val != .integer) return error.ServerError;
+
+        // Safe integer cast with validation
+        const expires_in_raw = expires_in_val.integer;
+        if (expires_in_raw < 0 or expires_in_raw > std.math.maxInt(u64)) {
+            return error.ServerError;
+        }
+        const expires_in: u64 = @intCast(expires_in_raw);
+
+        // Parse optional interval with safe cast
+        var interval: u64 = 5; // default
+        if (obj.get("interval")) |iv| {
+            if (iv == .integer) {
+                const interval_raw = iv.integer;
+                if (interval_raw > 0 and interval_raw <= 300) { // max 5 minutes
+                    interval = @intCast(interval_raw);
+                }
+            }
+        }
+
+        // Parse optional verification_uri_complete
+        var uri_complete: ?[]const u8 = null;
+        if (obj.get("verification_uri_complete")) |uri| {
+            if (uri == .string) {
+                uri_complete = try allocator.dupe(u8, uri.string);
+            }
+        }
+        errdefer if (uri_complete) |uc| allocator.free(uc);
+
+        // Allocate all required fields with proper errdefer cleanup
+        const device_code = try allocator.dupe(u8, device_code_val.string);
+        errdefer allocator.free(device_code);
+
+        const user_code = try allocator.dupe(u8, user_code_val.string);
+        errdefer allocator.free(user_code);
+
+        const verification_uri = try allocator.dupe(u8, verification_uri_val.string);
+        // No errdefer needed - this is the last allocation, success path
+
+        return .{
+            .allocator = allocator,
+            .device_code = device_code,
+            .user_code = user_code,
+            .verification_uri = verification_uri,
+            .verification_uri_complete = uri_complete,
+            .expires_in = expires_in,
+            .interval = interval,
+        };
+    }
+};
+
+/// Token refresher with automatic refresh and cross-process locking
+pub const TokenRefresher = struct {
+    allocator: Allocator,
+    client: *OAuthClient,
+    lock_manager: ?RefreshLockManager,
+    /// Refresh threshold as fraction of token lifetime (0.0-1.0)
+    refresh_threshold: f64,
+    /// Thread pool for stale-while-revalidate refreshes (see withBackgroundRefreshPool)
+    background_pool: ?*std.Thread.Pool = null,
+    /// Seconds past expiry during which a stale token is still served while
+    /// a background refresh is running
+    grace_window: u64 = 0,
+    background_wait_group: std.Thread.WaitGroup = .{},
+    /// Consecutive failures after which refreshes of a key are suspended (0 = disabled)
+    circuit_max_failures: u32 = 0,
+    /// How long a tripped circuit stays open, in milliseconds
+    circuit_cooldown_ms: u64 = 0,
+    /// Guards background_keys, in_flight, cancel_generation, refresh_failures
+    /// and retired_refresh_tokens
+    mutex: std.Thread.Mutex = .{},
+    /// Keys with a background refresh scheduled or running
+    background_keys: std.StringHashMapUnmanaged(void) = .{},
+    /// Keys with a refresh currently talking to the token endpoint
+    in_flight: std.StringHashMapUnmanaged(void) = .{},
+    /// Signalled whenever an in-flight refresh finishes or is cancelled
+    refresh_done: std.Thread.Condition = .{},
+    /// Bumped by cancelAllRefreshes() to release current waiters
+    cancel_generation: u64 = 0,
+    /// Number of callers waiting on another caller's refresh
+    waiting: usize = 0,
+    /// Refresh failure history for keys whose last refresh failed
+    refresh_failures: std.StringHashMapUnmanaged(RefreshFailures) = .{},
+    /// SHA-256 of the refresh token each key's latest rotation replaced
+    retired_refresh_tokens: std.StringHashMapUnmanaged([32]u8) = .{},
+    /// What to send as `scope` on refresh requests
+    refresh_scope_behavior: RefreshScopeBehavior = .omit,
+    /// Refresh ahead of the predicted next access (see withAdaptiveRefresh)
+    adaptive_refresh: bool = false,
+    /// Observed access pattern per key, when adaptive refresh is enabled
+    access_history: std.StringHashMapUnmanaged(AccessHistory) = .{},
+
+    /// Access pattern learned for a key
+    const AccessHistory = struct {
+        /// Local time of the latest access (Unix seconds)
+        last_access: u64,
+        /// Smoothed gap between accesses in seconds, once two were seen
+        interval: ?u64 = null,
+    };
+
+    /// Gaps outside this range do not describe a regular access pattern
+    const min_access_interval: u64 = 1;
+    const max_access_interval: u64 = std.time.s_per_day;
+
+    /// `scope` parameter policy for refresh requests
+    ///
+    /// Providers disagree: some reject any `scope` on refresh, others
+    /// require it. Omitting it is the safest default, since RFC 6749 then
+    /// grants the original scope.
+    pub const RefreshScopeBehavior = union(enum) {
+        /// Send no `scope`
+        omit,
+        /// Send the scope stored with the token (omitted if it has none)
+        echo_original,
+        /// Send this scope; it must be a subset of the stored scope
+        custom: []const u8,
+    };
+
+    /// A key that ensureValid() could not bring to a valid token
+    pub const KeyFailure = struct {
+        key: []const u8,
+        err: anyerror,
+    };
+
+    /// A stored token found by tokensExpiringWithin()
+    pub const ExpiringToken = struct {
+        key: []const u8,
+        /// Expiry as Unix seconds
+        expires_at: u64,
+    };
+
+    /// Consecutive refresh failures recorded for a key
+    pub const RefreshFailures = struct {
+        /// Number of refreshes that failed in a row
+        consecutive: u32,
+        /// When the most recent failure happened (milliseconds since epoch)
+        last_failure_ms: i64,
+    };
+
+    /// Create a new token refresher
+    pub fn init(allocator: Allocator, client: *OAuthClient) TokenRefresher {
+        return .{
+            .allocator = allocator,
+            .client = client,
+            .lock_manager = null,
+            .refresh_threshold = 0.1, // Refresh at 10% remaining lifetime
+        };
+    }
+
+    /// Enable cross-process locking
+    pub fn withFileLocking(self: *TokenRefresher, app_name: []const u8) !void {
+        self.lock_manager = try RefreshLockManager.init(self.allocator, app_name);
+    }
+
+    /// Refresh tokens on a background thread pool (stale-while-revalidate)
+    ///
+    /// When enabled, getValidToken() returns a token that is due for refresh
+    /// immediately and schedules the refresh on the pool, so callers never
+    /// block on HTTP. Only tokens past expiry plus the grace window (see
+    /// withGraceWindow) are refreshed inline.
+    ///
+    /// The refresher must not be moved after the pool is enabled.
+    pub fn withBackgroundRefreshPool(self: *TokenRefresher, threads: u32) !void {
+        if (threads == 0) return error.InvalidParameter;
+        if (self.background_pool != null) return error.InvalidParameter;
+
+        const pool = try self.allocator.create(std.Thread.Pool);
+        errdefer self.allocator.destroy(pool);
+        try pool.init(.{ .allocator = self.allocator, .n_jobs = threads });
+
+        self.background_pool = pool;
+    }
+
+    /// Refresh tokens just before they are next expected to be used
+    ///
+    /// Records when each key is read and tracks the typical gap between
+    /// reads (smoothed, ignoring gaps under a second or over a day). When a
+    /// token would cross the refresh threshold before the predicted next
+    /// read, it is refreshed now: in the background if a pool is enabled,
+    /// otherwise inline. Keys without a regular pattern, or read less often
+    /// than a fresh token stays ahead of the threshold, fall back to the
+    /// fixed threshold.
+    pub fn withAdaptiveRefresh(self: *TokenRefresher) void {
+        self.adaptive_refresh = true;
+    }
+
+    /// Local time at which `key` is next expected to be read, if known
+    pub fn predictedNextAccess(self: *TokenRefresher, key: []const u8) ?u64 {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        const history = self.access_history.get(key) orelse return null;
+        return history.last_access + (history.interval orelse return null);
+    }
+
+    /// Record a read of `key` and return the smoothed access interval
+    fn recordAccess(self: *TokenRefresher, key: []const u8) !?u64 {
+        const now = clock.now();
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        const entry = try self.access_history.getOrPut(self.allocator, key);
+        if (!entry.found_existing) {
+            entry.key_ptr.* = self.allocator.dupe(u8, key) catch |err| {
+                self.access_history.removeByPtr(entry.key_ptr);
+                return err;
+            };
+            entry.value_ptr.* = .{ .last_access = now };
+            return null;
+        }
+
+        const history = entry.value_ptr;
+        const gap = now -| history.last_access;
+        history.last_access = now;
+
+        if (gap < min_access_interval) return history.interval;
+        if (gap > max_access_interval) {
+            history.interval = null;
+            return null;
+        }
+        history.interval = if (history.interval) |previous| (previous * 3 + gap) / 4 else gap;
+        return history.interval;
+    }
+
+    /// Whether `token` will need refreshing by the time it is next read
+    ///
+    /// Always false when reads are further apart than a token stays fresh:
+    /// refreshing early could not carry the token over to the next read.
+    fn dueBeforeNextAccess(token: *const Token, threshold: f64, interval: u64) bool {
+        const expires_at = token.expires_at orelse return false;
+        const lifetime = token.expires_in orelse return false;
+
+        const margin: u64 = @intFromFloat(@as(f64, @floatFromInt(lifetime)) * threshold);
+        if (interval >= lifetime -| margin) return false;
+        const next_access = clock.nowWithOffset(token.clock_offset) +| interval;
+        return next_access >= expires_at -| margin;
+    }
+
+    /// When a refresh is due: at `threshold`, or, when refreshing ahead,
+    /// before the read expected `ahead` seconds from now
+    ///
+    /// Every check along a refresh uses the same Due, so a caller that waited
+    /// for a lock or another flight reuses a token refreshed in the meantime.
+    const Due = struct {
+        threshold: f64,
+        ahead: ?u64 = null,
+
+        fn isDue(due: Due, token: *const Token) bool {
+            if (needsRefresh(token, due.threshold)) return true;
+            const interval = due.ahead orelse return false;
+            return dueBeforeNextAccess(token, due.threshold, interval);
+        }
+    };
+
+    /// Serve expired tokens for up to `seconds` past expiry while a
+    /// background refresh is in flight
+    pub fn withGraceWindow(self: *TokenRefresher, seconds: u64) void {
+        self.grace_window = seconds;
+    }
+
+    /// Stop refreshing a key after `max_failures` consecutive failures
+    ///
+    /// While the circuit is open, refreshes of that key return
+    /// `error.CircuitOpen` without contacting the token endpoint. Once
+    /// `cooldown_ms` has passed since the last failure one attempt is let
+    /// through: success closes the circuit, failure reopens it.
+    pub fn withCircuitBreaker(self: *TokenRefresher, max_failures: u32, cooldown_ms: u64) !void {
+        if (max_failures == 0) return error.InvalidParameter;
+        self.circuit_max_failures = max_failures;
+        self.circuit_cooldown_ms = cooldown_ms;
+    }
+
+    /// Choose what `scope` refresh requests carry (see RefreshScopeBehavior)
+    ///
+    /// A `.custom` scope is checked against each token's stored scope before
+    /// refreshing; a scope outside it fails with `error.InvalidParameter`.
+    pub fn withRefreshScope(self: *TokenRefresher, behavior: RefreshScopeBehavior) void {
+        self.refresh_scope_behavior = behavior;
+    }
+
+    /// Resolve the `scope` to send when refreshing `token`
+    fn refreshScope(self: *const TokenRefresher, token: *const Token) !?[]const u8 {
+        return switch (self.refresh_scope_behavior) {
+            .omit => null,
+            .echo_original => token.scope,
+            .custom => |requested| custom: {
+                try checkScopeSubset(token.scope orelse return error.InvalidParameter, requested);
+                break :custom requested;
+            },
+        };
+    }
+
+    /// Get the failure history for `key`, or null if its last refresh succeeded
+    pub fn refreshFailures(self: *TokenRefresher, key: []const u8) ?RefreshFailures {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        return self.refresh_failures.get(key);
+    }
+
+    pub fn deinit(self: *TokenRefresher) void {
+        if (self.background_pool) |pool| {
+            self.background_wait_group.wait();
+            pool.deinit();
+            self.allocator.destroy(pool);
+            self.background_pool = null;
+        }
+        self.background_keys.deinit(self.allocator);
+        self.in_flight.deinit(self.allocator);
+        var failures = self.refresh_failures.keyIterator();
+        while (failures.next()) |k| self.allocator.free(k.*);
+        self.refresh_failures.deinit(self.allocator);
+        var retired = self.retired_refresh_tokens.keyIterator();
+        while (retired.next()) |k| self.allocator.free(k.*);
+        self.retired_refresh_tokens.deinit(self.allocator);
+        var history = self.access_history.keyIterator();
+        while (history.next()) |k| self.allocator.free(k.*);
+        self.access_history.deinit(self.allocator);
+        if (self.lock_manager) |*lm| {
+            lm.deinit();
+        }
+    }
+
+    /// Block until all scheduled background refreshes have finished
+    pub fn waitForBackgroundRefreshes(self: *TokenRefresher) void {
+        self.background_wait_group.wait();
+    }
+
+    /// List the keys currently being refreshed
+    ///
+    /// Caller owns the returned slice and each key in it.
+    pub fn inFlightKeys(self: *TokenRefresher, allocator: Allocator) ![][]const u8 {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        var keys: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (keys.items) |k| allocator.free(k);
+            keys.deinit(allocator);
+        }
+
+        var iter = self.in_flight.keyIterator();
+        while (iter.next()) |key| {
+            const copy = try allocator.dupe(u8, key.*);
+            errdefer allocator.free(copy);
+            try keys.append(allocator, copy);
+        }
+
+        return keys.toOwnedSlice(allocator);
+    }
+
+    /// Release every caller waiting on another caller's refresh
+    ///
+    /// Waiters return `error.Cancelled`. Refreshes that are already talking to
+    /// the token endpoint are not interrupted: the blocking HTTP request runs
+    /// to completion and its result is still persisted, so storage never ends
+    /// up with a half-written token.
+    pub fn cancelAllRefreshes(self: *TokenRefresher) void {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        self.cancel_generation += 1;
+        self.refresh_done.broadcast();
+    }
+
+    /// Get a valid token restricted to `scope` from the grant under `key`
+    ///
+    /// Returns the token stored under downscopedKey(key, scope) while it is
+    /// valid, and otherwise mints a new one with
+    /// OAuthClient.requestDownscopedToken().
+    pub fn getValidTokenForScope(self: *TokenRefresher, key: []const u8, scope: []const u8) !Token {
+        const stored_key = try downscopedKey(self.allocator, key, scope);
+        defer self.allocator.free(stored_key);
+
+        if (try self.client.getToken(stored_key)) |stored| {
+            var token = stored;
+            if (!needsRefresh(&token, self.refresh_threshold)) return token;
+            token.deinit();
+        }
+        return self.client.requestDownscopedToken(key, scope);
+    }
+
+    /// Get a valid token for `resource` from the grant under `key` (RFC 8707)
+    ///
+    /// Returns the token stored under resourceKey(key, resource) while it
+    /// is valid, so every API keeps its own access token, and otherwise
+    /// mints one with OAuthClient.requestResourceToken(). Minting spends
+    /// the grant's refresh token, so it shares the single-flight and
+    /// cross-process locks of `key` with refreshes of the grant.
+    pub fn getValidTokenForResource(self: *TokenRefresher, key: []const u8, resource: []const u8) !Token {
+        const stored_key = try resourceKey(self.allocator, key, resource);
+        defer self.allocator.free(stored_key);
+
+        if (try self.client.getToken(stored_key)) |stored| {
+            var token = stored;
+            if (!needsRefresh(&token, self.refresh_threshold)) return token;
+            token.deinit();
+        }
+
+        const Mint = struct {
+            key: []const u8,
+            resource: []const u8,
+            stored_key: []const u8,
+
+            fn run(job: @This(), refresher: *TokenRefresher) !Token {
+                var lock_guard: ?lock.RefreshLock = null;
+                if (refresher.lock_manager) |*lm| {
+                    lock_guard = try lm.acquire(job.key);
+                }
+                defer if (lock_guard) |*lg| lg.release();
+
+                // Another process may have minted one while we waited
+                if (try refresher.client.getToken(job.stored_key)) |stored| {
+                    var token = stored;
+                    if (!needsRefresh(&token, refresher.refresh_threshold)) return token;
+                    token.deinit();
+                }
+                return refresher.client.requestResourceToken(job.key, job.resource);
+            }
+        };
+        return self.singleFlight(key, stored_key, .{ .threshold = self.refresh_threshold }, Mint{
+            .key = key,
+            .resource = resource,
+            .stored_key = stored_key,
+        });
+    }
+
+    /// Get a valid token for `audience` from the grant under `key`
+    ///
+    /// Like getValidToken() for the key OAuthClient.saveTokenForAudience()
+    /// picked, so each audience is refreshed and cached on its own.
+    pub fn getValidTokenForAudience(self: *TokenRefresher, key: []const u8, audience: ?[]const u8) !Token {
+        const stored_key = try self.client.audienceStorageKey(key, audience);
+        defer self.allocator.free(stored_key);
+        return self.getValidToken(stored_key);
+    }
+
+    /// Get a valid token, refreshing if necessary
+    ///
+    /// This is the primary method for obtaining tokens. It:
+    /// 1. Loads the token from storage
+    /// 2. Checks if it's expired or about to expire
+    /// 3. Refreshes if needed (with cross-process locking if enabled)
+    /// 4. Returns a valid token
+    pub fn getValidToken(self: *TokenRefresher, key: []const u8) !Token {
+        return self.getValidTokenWithThreshold(key, self.refresh_threshold);
+    }
+
+    /// Get a valid token with a custom refresh threshold
+    ///
+    /// threshold: Fraction of lifetime at which to refresh (0.0-1.0)
+    /// - 0.0: Only refresh when expired
+    /// - 0.5: Refresh when 50% of lifetime remains
+    /// - 0.8: Refresh when 20% of lifetime remains
+    pub fn getValidTokenWithThreshold(self: *TokenRefresher, key: []const u8, threshold: f64) !Token {
+        var token = (try self.client.getToken(key)) orelse return error.TokenNotFound;
+
+        var interval: ?u64 = null;
+        if (self.adaptive_refresh) {
+            interval = self.recordAccess(key) catch |err| {
+                token.deinit();
+                return err;
+            };
+        }
+
+        var due: Due = .{ .threshold = threshold };
+        if (!needsRefresh(&token, threshold)) {
+            const expected_gap = interval orelse return token;
+            if (token.refresh_token == null or !dueBeforeNextAccess(&token, threshold, expected_gap)) {
+                return token;
+            }
+
+            // Refresh ahead of the next read
+            due.ahead = expected_gap;
+        }
+
+        if (token.refresh_token == null) {
+            token.deinit();
+            return error.NoRefreshToken;
+        }
+
+        // Stale-while-revalidate: hand back the current token and refresh off-thread
+        if (self.background_pool != null and self.withinGraceWindow(&token)) {
+            self.scheduleBackgroundRefresh(key, due) catch |err| {
+                token.deinit();
+                return err;
+            };
+            return token;
+        }
+
+        token.deinit();
+        return self.refreshSingleFlight(key, due);
+    }
+
+    /// List stored tokens that expire within `window_secs`, soonest first
+    ///
+    /// Scans every key in storage without refreshing anything, e.g. to flag
+    /// accounts that need attention. Tokens that have already expired are
+    /// included; tokens without an expiry are skipped. Requires a backend
+    /// that can list its keys.
+    ///
+    /// Caller owns the result; free it with freeExpiringTokens().
+    pub fn tokensExpiringWithin(self: *TokenRefresher, allocator: Allocator, window_secs: u64) ![]ExpiringToken {
+        const storage = self.client.storage;
+
+        const keys = try storage.listKeys(allocator);
+        defer SessionStorage.freeKeys(allocator, keys);
+
+        var expiring: std.ArrayListUnmanaged(ExpiringToken) = .{};
+        errdefer {
+            for (expiring.items) |t| allocator.free(t.key);
+            expiring.deinit(allocator);
+        }
+
+        const Expiry = struct {
+            fn read(window: u64, token: ?*const Token) ?u64 {
+                const t = token orelse return null;
+                const expires_at = t.expires_at orelse return null;
+                const now = clock.nowWithOffset(t.clock_offset);
+                return if (expires_at <= now +| window) expires_at else null;
+            }
+        };
+
+        for (keys) |key| {
+            const expires_at = try storage.withToken(allocator, key, window_secs, Expiry.read) orelse continue;
+
+            const key_copy = try allocator.dupe(u8, key);
+            errdefer allocator.free(key_copy);
+            try expiring.append(allocator, .{ .key = key_copy, .expires_at = expires_at });
+        }
+
+        std.mem.sort(ExpiringToken, expiring.items, {}, struct {
+            fn soonerFirst(_: void, a: ExpiringToken, b: ExpiringToken) bool {
+                return a.expires_at < b.expires_at;
+            }
+        }.soonerFirst);
+
+        return expiring.toOwnedSlice(allocator);
+    }
+
+    /// Free a result of tokensExpiringWithin()
+    pub fn freeExpiringTokens(allocator: Allocator, tokens: []const ExpiringToken) void {
+        for (tokens) |t| allocator.free(t.key);
+        allocator.free(tokens);
+    }
+
+    /// Ensure every key in `keys` has a currently valid token
+    ///
+    /// Keys are checked concurrently on up to `ensure_valid_threads` pool
+    /// threads and the caller's, and refreshed inline where needed (a
+    /// background pool, if enabled, is not used: stale tokens do not count
+    /// as valid here). Returns the keys that failed along with their
+    /// errors; an empty slice means every key is ready.
+    ///
+    /// Caller owns the returned slice. Its keys borrow from `keys`.
+    pub fn ensureValid(self: *TokenRefresher, allocator: Allocator, keys: []const []const u8) ![]KeyFailure {
+        const results = try allocator.alloc(?anyerror, keys.len);
+        defer allocator.free(results);
+        @memset(results, null);
+
+        if (keys.len > 0) {
+            var pool: std.Thread.Pool = undefined;
+            try pool.init(.{ .allocator = allocator, .n_jobs = @min(keys.len, ensure_valid_threads) });
+            defer pool.deinit();
+
+            var wait_group: std.Thread.WaitGroup = .{};
+            for (keys, results) |key, *result| {
+                pool.spawnWg(&wait_group, ensureValidWorker, .{ self, key, result });
+            }
+            pool.waitAndWork(&wait_group);
+        }
+
+        var failures: std.ArrayListUnmanaged(KeyFailure) = .{};
+        errdefer failures.deinit(allocator);
+        for (keys, results) |key, result| {
+            if (result) |err| try failures.append(allocator, .{ .key = key, .err = err });
+        }
+        return failures.toOwnedSlice(allocator);
+    }
+
+    /// Most keys ensureValid() checks at once
+    pub const ensure_valid_threads = 8;
+
+    fn ensureValidWorker(self: *TokenRefresher, key: []const u8, result: *?anyerror) void {
+        self.ensureValidKey(key) catch |err| {
+            result.* = err;
+        };
+    }
+
+    fn ensureValidKey(self: *TokenRefresher, key: []const u8) !void {
+        var token = (try self.client.getToken(key)) orelse return error.TokenNotFound;
+        defer token.deinit();
+
+        if (!needsRefresh(&token, self.refresh_threshold)) return;
+        if (token.refresh_token == null) return error.NoRefreshToken;
+
+        var fresh = try self.refreshSingleFlight(key, .{ .threshold = self.refresh_threshold });
+        fresh.deinit();
+    }
+
+    /// Refresh `key`, coalescing concurrent refreshes of the same key
+    ///
+    /// The first caller performs the refresh; later callers wait for it and
+    /// then reuse the stored result.
+    fn refreshSingleFlight(self: *TokenRefresher, key: []const u8, due: Due) !Token {
+        const Refresh = struct {
+            key: []const u8,
+            due: Due,
+
+            fn run(job: @This(), refresher: *TokenRefresher) !Token {
+                return refresher.refreshLocked(job.key, job.due);
+            }
+        };
+        return self.singleFlight(key, key, due, Refresh{ .key = key, .due = due });
+    }
+
+    /// Run `job` as the only flight for `flight_key`
+    ///
+    /// Callers that find a flight already running wait for it and then
+    /// reuse the token stored under `result_key` if it is still valid.
+    fn singleFlight(self: *TokenRefresher, flight_key: []const u8, result_key: []const u8, due: Due, job: anytype) !Token {
+        const key = flight_key;
+        while (true) {
+            self.mutex.lock();
+
+            if (self.in_flight.contains(key)) {
+                const generation = self.cancel_generation;
+                self.waiting += 1;
+                const cancelled = while (self.in_flight.contains(key)) {
+                    if (self.cancel_generation != generation) break true;
+                    self.refresh_done.wait(&self.mutex);
+                } else false;
+                self.waiting -= 1;
+                self.mutex.unlock();
+
+                if (cancelled) return error.Cancelled;
+
+                // The other refresh finished; use its result if it succeeded
+                var token = (try self.client.getToken(result_key)) orelse {
+                    if (std.mem.eql(u8, result_key, key)) return error.TokenNotFound;
+                    continue;
+                };
+                if (!due.isDue(&token)) {
+                    return token;
+                }
+                token.deinit();
+                continue;
+            }
+
+            const owned_key = self.allocator.dupe(u8, key) catch |err| {
+                self.mutex.unlock();
+                return err;
+            };
+            self.in_flight.put(self.allocator, owned_key, {}) catch |err| {
+                self.mutex.unlock();
+                self.allocator.free(owned_key);
+                return err;
+            };
+            self.mutex.unlock();
+
+            defer {
+                self.mutex.lock();
+                _ = self.in_flight.remove(owned_key);
+                self.refresh_done.broadcast();
+                self.mutex.unlock();
+                self.allocator.free(owned_key);
+            }
+
+            return job.run(self);
+        }
+    }
+
+    /// Refresh `key` under the refresh lock and persist the result
+    fn refreshLocked(self: *TokenRefresher, key: []const u8, due: Due) !Token {
+        // Acquire lock if enabled
+        var lock_guard: ?lock.RefreshLock = null;
+        if (self.lock_manager) |*lm| {
+            lock_guard = try lm.acquire(key);
+        }
+        defer if (lock_guard) |*lg| lg.release();
+
+        // Load again after acquiring the lock (another process might have refreshed)
+        var token = (try self.client.getToken(key)) orelse return error.TokenNotFound;
+        if (!due.isDue(&token)) {
+            return token;
+        }
+        defer token.deinit();
+
+        const refresh_token = token.refresh_token orelse return error.NoRefreshToken;
+        const scope = try self.refreshScope(&token);
+
+        try self.checkCircuit(key);
 
         // Perform refresh
-        var new_token = try self.client.refreshToken(refresh_token);
+        // A DPoP-bound token can only be refreshed with the key it is bound to
+        const dpop_key = if (token.dpop_key) |encoded| try dpop.DpopKey.decode(encoded) else self.client.dpop_key;
+
+        const audience = token.audience orelse self.client.config.audience;
+        var new_token = self.client.refreshTokenWithKey(refresh_token, scope, token.resource, audience, dpop_key) catch |refresh_err| switch (refresh_err) {
+            // With rotation, a concurrent refresher may have spent the token first
+            error.InvalidGrant => return self.resolveRotationRace(key, refresh_token, due) catch |err| {
+                self.failRefresh(key, err);
+                return err;
+            },
+            else => {
+                self.failRefresh(key, refresh_err);
+                return refresh_err;
+            },
+        };
         errdefer new_token.deinit();
+        self.recordRefreshSuccess(key);
+
+        // Preserve refresh token if not included in response
+        const rotated = if (new_token.refresh_token) |rt| !std.mem.eql(u8, rt, refresh_token) else false;
+        if (rotated) self.recordRetiredRefreshToken(key, refresh_token);
+        if (new_token.refresh_token == null) {
+            new_token.refresh_token = try new_token.allocator.dupe(u8, refresh_token);
+        }
+        // An omitted scope is the one requested (RFC 6749 Section 5.1), so
+        // later refreshes can still echo or check against it
+        if (new_token.scope == null) {
+            if (scope orelse token.scope) |granted| new_token.scope = try new_token.allocator.dupe(u8, granted);
+        }
+        if (token.resource) |resource| {
+            if (new_token.resource) |previous| new_token.allocator.free(previous);
+            new_token.resource = try new_token.allocator.dupe(u8, resource);
+        }
+        if (token.audience) |previous_audience| try replaceAudience(&new_token, previous_audience);
+
+        // Saves replace the stored token atomically, so the previous token
+        // stays in place until the new one is written. A rotated refresh
+        // token is the only copy of the grant, so retry a failed write once.
+        self.client.saveToken(key, new_token) catch |err| {
+            if (!rotated) return err;
+            try self.client.saveToken(key, new_token);
+        };
+
+        self.client.emitEvent(.{ .token_refreshed = .{
+            .key = key,
+            .scope = new_token.scope,
+            .expires_at = new_token.expires_at,
+            .refresh_token_rotated = rotated,
+        } });
+
+        return new_token;
+    }
+
+    /// Explain an `invalid_grant` response to a refresh with `sent`
+    ///
+    /// Returns the stored token if another refresher already rotated the
+    /// grant and saved a valid token, `error.RefreshTokenReused` if `sent`
+    /// had already been rotated away, and `error.InvalidGrant` if the grant
+    /// itself is no longer valid.
+    fn resolveRotationRace(self: *TokenRefresher, key: []const u8, sent: []const u8, due: Due) !Token {
+        if (self.isRetiredRefreshToken(key, sent)) return error.RefreshTokenReused;
+
+        var current = (try self.client.getToken(key)) orelse return error.InvalidGrant;
+        errdefer current.deinit();
+
+        const current_refresh_token = current.refresh_token orelse return error.InvalidGrant;
+        if (std.mem.eql(u8, current_refresh_token, sent)) return error.InvalidGrant;
+        if (due.isDue(&current)) return error.RefreshTokenReused;
+        return current;
+    }
+
+    fn recordRetiredRefreshToken(self: *TokenRefresher, key: []const u8, refresh_token: []const u8) void {
+        var digest: [32]u8 = undefined;
+        std.crypto.hash.sha2.Sha256.hash(refresh_token, &digest, .{});
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        if (self.retired_refresh_tokens.getPtr(key)) |retired| {
+            retired.* = digest;
+            return;
+        }
+
+        // Failing to record only makes a later reuse look like invalid_grant
+        const owned_key = self.allocator.dupe(u8, key) catch return;
+        self.retired_refresh_tokens.put(self.allocator, owned_key, digest) catch self.allocator.free(owned_key);
+    }
+
+    fn isRetiredRefreshToken(self: *TokenRefresher, key: []const u8, refresh_token: []const u8) bool {
+        var digest: [32]u8 = undefined;
+        std.crypto.hash.sha2.Sha256.hash(refresh_token, &digest, .{});
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        const retired = self.retired_refresh_tokens.get(key) orelse return false;
+        return std.mem.eql(u8, &retired, &digest);
+    }
+
+    fn failRefresh(self: *TokenRefresher, key: []const u8, err: anyerror) void {
+        self.recordRefreshFailure(key);
+        self.client.emitEvent(.{ .refresh_failed = .{ .key = key, .@"error" = @errorName(err) } });
+    }
+
+    fn checkCircuit(self: *TokenRefresher, key: []const u8) !void {
+        if (self.circuit_max_failures == 0) return;
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        const failures = self.refresh_failures.get(key) orelse return;
+        if (failures.consecutive < self.circuit_max_failures) return;
+
+        const elapsed = clock.nowMillis() - failures.last_failure_ms;
+        if (elapsed < @as(i64, @intCast(self.circuit_cooldown_ms))) return error.CircuitOpen;
+    }
+
+    fn recordRefreshFailure(self: *TokenRefresher, key: []const u8) void {
+        if (self.circuit_max_failures == 0) return;
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        const now = clock.nowMillis();
+        if (self.refresh_failures.getPtr(key)) |failures| {
+            failures.consecutive +|= 1;
+            failures.last_failure_ms = now;
+            return;
+        }
+
+        // Failing to record only means the breaker trips later
+        const owned_key = self.allocator.dupe(u8, key) catch return;
+        self.refresh_failures.put(self.allocator, owned_key, .{
+            .consecutive = 1,
+            .last_failure_ms = now,
+        }) catch self.allocator.free(owned_key);
+    }
+
+    fn recordRefreshSuccess(self: *TokenRefresher, key: []const u8) void {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        if (self.refresh_failures.fetchRemove(key)) |entry| {
+            self.allocator.free(entry.key);
+        }
+    }
+
+    fn scheduleBackgroundRefresh(self: *TokenRefresher, key: []const u8, due: Due) !void {
+        const pool = self.background_pool orelse return error.UnsupportedOperation;
+
+        const owned_key = blk: {
+            self.mutex.lock();
+            defer self.mutex.unlock();
+
+            // A refresh for this key is already on its way
+            if (self.background_keys.contains(key)) return;
+
+            const owned = try self.allocator.dupe(u8, key);
+            errdefer self.allocator.free(owned);
+            try self.background_keys.put(self.allocator, owned, {});
+            break :blk owned;
+        };
+
+        self.background_wait_group.start();
+        pool.spawn(runBackgroundRefresh, .{ self, owned_key, due }) catch |err| {
+            self.finishBackgroundRefresh(owned_key);
+            return err;
+        };
+    }
+
+    fn runBackgroundRefresh(self: *TokenRefresher, key: []const u8, due: Due) void {
+        defer self.finishBackgroundRefresh(key);
+
+        var token = self.refreshSingleFlight(key, due) catch |err| {
+            std.log.debug("background refresh for '{s}' failed: {s}", .{ key, @errorName(err) });
+            return;
+        };
+        token.deinit();
+    }
+
+    fn finishBackgroundRefresh(self: *TokenRefresher, key: []const u8) void {
+        self.mutex.lock();
+        _ = self.background_keys.remove(key);
+        self.mutex.unlock();
+        self.allocator.free(key);
+        self.background_wait_group.finish();
+    }
+
+    fn waiterCount(self: *TokenRefresher) usize {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        return self.waiting;
+    }
+
+    fn withinGraceWindow(self: *const TokenRefresher, token: *const Token) bool {
+        const expires_at = token.expires_at orelse return true;
+        const now = clock.nowWithOffset(token.clock_offset);
+        return now < expires_at +| self.grace_window;
+    }
+
+    fn needsRefresh(token: *const Token, threshold: f64) bool {
+        if (token.isExpired()) return true;
+        if (token.remainingLifetimeFraction()) |fraction| {
+            if (fraction <= threshold) return true;
+        }
+        return false;
+    }
+};
+
+/// Re-export appendUrlEncoded from callback module to avoid duplication
+const appendUrlEncoded = callback.appendUrlEncoded;
+
+/// `scope` with `offline_access` added, so OpenID providers issue a refresh token
+///
+/// Returns `scope` unchanged if it already asks for offline access. Pair
+/// with session.RefreshTokenOnlyStorage to persist nothing but the refresh
+/// token. Caller owns the returned scope.
+pub fn withOfflineAccess(allocator: Allocator, scope: ?[]const u8) ![]u8 {
+    const existing = scope orelse return allocator.dupe(u8, "offline_access");
+    if (scopeIncludes(existing, "offline_access")) return allocator.dupe(u8, existing);
+    return std.fmt.allocPrint(allocator, "{s} offline_access", .{existing});
+}
+
+/// Whether a space-separated scope string contains `name`
+fn scopeIncludes(scope: ?[]const u8, name: []const u8) bool {
+    var it = std.mem.tokenizeScalar(u8, scope orelse return false, ' ');
+    while (it.next()) |s| {
+        if (std.mem.eql(u8, s, name)) return true;
+    }
+    return false;
+}
+
+/// Space-separated union of `base` and `extra`, keeping the order of first appearance
+fn mergeScopes(allocator: Allocator, base: ?[]const u8, extra: []const u8) ![]u8 {
+    var merged: std.ArrayListUnmanaged(u8) = .{};
+    errdefer merged.deinit(allocator);
+
+    inline for (.{ base orelse "", extra }) |list| {
+        var it = std.mem.tokenizeScalar(u8, list, ' ');
+        while (it.next()) |name| {
+            if (scopeIncludes(merged.items, name)) continue;
+            if (merged.items.len > 0) try merged.append(allocator, ' ');
+            try merged.appendSlice(allocator, name);
+        }
+    }
+    return merged.toOwnedSlice(allocator);
+}
+
+/// Whether every scope in `requested` is part of `granted`
+fn checkScopeSubset(granted: []const u8, requested: []const u8) !void {
+    var it = std.mem.tokenizeScalar(u8, requested, ' ');
+    while (it.next()) |s| {
+        if (!scopeIncludes(granted, s)) return error.InvalidParameter;
+    }
+}
+
+test "OAuthConfig GitHub preset" {
+    const config = OAuthConfig.github("test-client-id", "repo user");
+
+    try std.testing.expectEqualStrings("test-client-id", config.client_id);
+    try std.testing.expectEqualStrings("https://github.com/login/oauth/authorize", config.authorization_endpoint);
+    try std.testing.expectEqualStrings("https://github.com/login/oauth/access_token", config.token_endpoint);
+    try std.testing.expect(config.device_authorization_endpoint != null);
+    try std.testing.expectEqualStrings("repo user", config.scope.?);
+}
+
+test "OAuthConfig Google preset" {
+    const config = OAuthConfig.google("google-client-id", "openid email");
+
+    try std.testing.expectEqualStrings("google-client-id", config.client_id);
+    try std.testing.expectEqualStrings("https://accounts.google.com/o/oauth2/v2/auth", config.authorization_endpoint);
+    try std.testing.expect(config.device_authorization_endpoint != null);
+}
+
+test "OAuthConfig Microsoft preset" {
+    const config = OAuthConfig.microsoft("ms-client-id", "common", "user.read");
+
+    try std.testing.expectEqualStrings("ms-client-id", config.client_id);
+    try std.testing.expect(std.mem.indexOf(u8, config.authorization_endpoint, "microsoftonline.com") != null);
+}
+
+test "OAuthConfig Tuist preset" {
+    const config = OAuthConfig.tuist("tuist-client-id", "project:read");
+
+    try std.testing.expectEqualStrings("tuist-client-id", config.client_id);
+    try std.testing.expect(std.mem.indexOf(u8, config.authorization_endpoint, "tuist.io") != null);
+}
+
+test "OAuthClient initialization" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    const config = OAuthConfig.github("test-client", null);
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+
+    try std.testing.expectEqualStrings("test-client", client.config.client_id);
+}
+
+test "OAuthClient token storage" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    const config = OAuthConfig.github("test-client", null);
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+
+    var token = try Token.init(allocator, "test_access_token", "Bearer");
+    defer token.deinit();
+
+    try client.saveToken("test_key", token);
+
+    var loaded = (try client.getToken("test_key")).?;
+    defer loaded.deinit();
+
+    try std.testing.expectEqualStrings("test_access_token", loaded.access_token);
+}
+
+test "OAuthConfigOwned: allocation and cleanup without leaks" {
+    const allocator = std.testing.allocator;
+
+    var owned = try OAuthConfig.custom(
+        allocator,
+        "test-client-id",
+        "https://example.com/authorize",
+        "https://example.com/token",
+        "http://127.0.0.1/callback",
+        "read write",
+        "https://example.com/device",
+    );
+    defer owned.deinit();
+
+    try std.testing.expectEqualStrings("test-client-id", owned.client_id);
+    try std.testing.expectEqualStrings("https://example.com/authorize", owned.authorization_endpoint);
+    try std.testing.expectEqualStrings("read write", owned.scope.?);
+    try std.testing.expectEqualStrings("https://example.com/device", owned.device_authorization_endpoint.?);
+}
+
+test "OAuthConfigOwned: toConfig borrows correctly" {
+    const allocator = std.testing.allocator;
+
+    var owned = try OAuthConfig.custom(
+        allocator,
+        "borrowed-client",
+        "https://example.com/auth",
+        "https://example.com/token",
+        "http://127.0.0.1/callback",
+        null,
+        null,
+    );
+    defer owned.deinit();
+
+    const config = owned.toConfig();
+
+    // Config borrows from owned - verify same pointers
+    try std.testing.expect(config.client_id.ptr == owned.client_id.ptr);
+    try std.testing.expect(config.authorization_endpoint.ptr == owned.authorization_endpoint.ptr);
+    try std.testing.expect(config.scope == null);
+    try std.testing.expect(config.device_authorization_endpoint == null);
+}
+
+test "configFromFormula: with public client without leaks" {
+    const allocator = std.testing.allocator;
+
+    // First, ensure builtin formulas are clean
+    formulas.deinitBuiltinFormulas();
+
+    // Create a v2 test formula with clients
+    const json_formula =
+        \\{
+        \\  "schema": "v2",
+        \\  "id": "test-provider",
+        \\  "label": "Test",
+        \\  "clients": [
+        \\    {"name": "default", "id": "default-id", "secret": "default-secret", "methods": ["device_code"]}
+        \\  ],
+        \\  "methods": {
+        \\    "device_code": {
+        \\      "endpoints": {
+        \\        "authorize": "https://test.com/authorize",
+        \\        "token": "https://test.com/token",
+        \\        "device": "https://test.com/device"
+        \\      },
+        \\      "scope": "default-scope"
+        \\    }
+        \\  },
+        \\  "apis": {
+        \\    "rest": {
+        \\      "base_url": "https://api.test.com",
+        \\      "auth_header": "Authorization: Bearer {token}",
+        \\      "methods": ["device_code"]
+        \\    }
+        \\  }
+        \\}
+    ;
+
+    var formula_owned = try formulas.loadFromJsonSlice(allocator, json_formula);
+    defer formula_owned.deinit();
+
+    // Test using default public client
+    var config = try configFromFormula(
+        allocator,
+        formula_owned.asConst(),
+        "device_code", // Method name
+        null, // No override - should use public client
+        null,
+        "http://127.0.0.1/callback",
+        null, // No scope override
+    );
+    defer config.deinit();
+
+    try std.testing.expectEqualStrings("default-id", config.client_id);
+    try std.testing.expectEqualStrings("default-secret", config.client_secret.?);
+    try std.testing.expectEqualStrings("default-scope", config.scope.?);
+    try std.testing.expectEqualStrings("https://test.com/device", config.device_authorization_endpoint.?);
+}
+
+test "configFromFormula: with client_id override without leaks" {
+    const allocator = std.testing.allocator;
+
+    const json_formula =
+        \\{
+        \\  "schema": "v2",
+        \\  "id": "test",
+        \\  "label": "Test",
+        \\  "clients": [
+        \\    {"name": "default", "id": "default-id", "secret": "default-secret"}
+        \\  ],
+        \\  "methods": {
+        \\    "oauth": {
+        \\      "endpoints": {
+        \\        "authorize": "https://test.com/authorize",
+        \\        "token": "https://test.com/token"
+        \\      }
+        \\    }
+        \\  },
+        \\  "apis": {
+        \\    "rest": {
+        \\      "base_url": "https://api.test.com",
+        \\      "auth_header": "Authorization: Bearer {token}",
+        \\      "methods": ["oauth"]
+        \\    }
+        \\  }
+        \\}
+    ;
+
+    var formula_owned = try formulas.loadFromJsonSlice(allocator, json_formula);
+    defer formula_owned.deinit();
+
+    // Test with client_id override
+    var config = try configFromFormula(
+        allocator,
+        formula_owned.asConst(),
+        "oauth", // Method name
+        "override-id", // Override client ID
+        "override-secret", // Override secret
+        "http://127.0.0.1/callback",
+        "custom-scope",
+    );
+    defer config.deinit();
+
+    try std.testing.expectEqualStrings("override-id", config.client_id);
+    try std.testing.expectEqualStrings("override-secret", config.client_secret.?);
+    try std.testing.expectEqualStrings("custom-scope", config.scope.?);
+}
+
+test "configFromFormula: missing client_id returns error" {
+    const allocator = std.testing.allocator;
+
+    const json_formula =
+        \\{
+        \\  "schema": "v2",
+        \\  "id": "no-clients",
+        \\  "label": "No Clients",
+        \\  "methods": {
+        \\    "oauth": {
+        \\      "endpoints": {
+        \\        "authorize": "https://test.com/authorize",
+        \\        "token": "https://test.com/token"
+        \\      }
+        \\    }
+        \\  },
+        \\  "apis": {
+        \\    "rest": {
+        \\      "base_url": "https://api.test.com",
+        \\      "auth_header": "Authorization: Bearer {token}",
+        \\      "methods": ["oauth"]
+        \\    }
+        \\  }
+        \\}
+    ;
+
+    var formula_owned = try formulas.loadFromJsonSlice(allocator, json_formula);
+    defer formula_owned.deinit();
+
+    // Should fail because no clients and no override
+    const result = configFromFormula(
+        allocator,
+        formula_owned.asConst(),
+        "oauth", // Method name
+        null,
+        null,
+        "http://127.0.0.1/callback",
+        null,
+    );
+
+    try std.testing.expectError(error.MissingClientId, result);
+}
+
+test "OAuthConfig.gitlabSelfHosted: without leaks" {
+    const allocator = std.testing.allocator;
+
+    var config = try OAuthConfig.gitlabSelfHosted(
+        allocator,
+        "gitlab-client",
+        "https://gitlab.mycompany.com",
+        "api read_user",
+    );
+    defer config.deinit();
+
+    try std.testing.expectEqualStrings("gitlab-client", config.client_id);
+    try std.testing.expectEqualStrings("https://gitlab.mycompany.com/oauth/authorize", config.authorization_endpoint);
+    try std.testing.expectEqualStrings("https://gitlab.mycompany.com/oauth/token", config.token_endpoint);
+    try std.testing.expectEqualStrings("api read_user", config.scope.?);
+}
+
+test "OAuthConfig.gitlabSelfHosted: strips trailing slash" {
+    const allocator = std.testing.allocator;
+
+    var config = try OAuthConfig.gitlabSelfHosted(
+        allocator,
+        "gitlab-client",
+        "https://gitlab.mycompany.com/",
+        null,
+    );
+    defer config.deinit();
+
+    try std.testing.expectEqualStrings("https://gitlab.mycompany.com/oauth/authorize", config.authorization_endpoint);
+}
+
+test "OAuthConfig.gitlabSelfHosted: rejects insecure URL" {
+    const allocator = std.testing.allocator;
+
+    const result = OAuthConfig.gitlabSelfHosted(
+        allocator,
+        "client",
+        "http://insecure.example.com",
+        null,
+    );
+
+    try std.testing.expectError(error.InsecureEndpoint, result);
+}
+
+test "OAuthConfig.validateAgainstMetadata: rejects unregistered redirect URI" {
+    const allocator = std.testing.allocator;
+
+    const metadata =
+        \\{"client_id":"abc","redirect_uris":["http://127.0.0.1:8080/callback","myapp://oauth"]}
+    ;
+
+    var config = OAuthConfig.github("test-client", null);
+    config.redirect_uri = "http://127.0.0.1:9999/callback";
+
+    var message: ?[]u8 = null;
+    defer if (message) |m| allocator.free(m);
+    try std.testing.expectError(
+        error.UnregisteredRedirectUri,
+        config.validateAgainstMetadata(allocator, metadata, &message),
+    );
+    try std.testing.expectEqualStrings(
+        "redirect_uri \"http://127.0.0.1:9999/callback\" is not registered; allowed: http://127.0.0.1:8080/callback, myapp://oauth",
+        message.?,
+    );
+
+    // Registered URI passes
+    config.redirect_uri = "myapp://oauth";
+    try config.validateAgainstMetadata(allocator, metadata, null);
+
+    // Metadata without redirect_uris is a no-op
+    config.redirect_uri = "http://127.0.0.1:9999/callback";
+    try config.validateAgainstMetadata(allocator, "{\"issuer\":\"https://example.com\"}", null);
+}
+
+test "validateEndpointSecurity: allows HTTPS" {
+    try validateEndpointSecurity("https://example.com/oauth");
+    try validateEndpointSecurity("https://sub.domain.example.com:8443/path");
+}
+
+test "validateEndpointSecurity: allows localhost HTTP" {
+    try validateEndpointSecurity("http://localhost/callback");
+    try validateEndpointSecurity("http://localhost:8080/callback");
+    try validateEndpointSecurity("http://127.0.0.1/callback");
+    try validateEndpointSecurity("http://127.0.0.1:3000/callback");
+    try validateEndpointSecurity("http://[::1]/callback");
+}
+
+test "validateEndpointSecurity: rejects insecure HTTP" {
+    try std.testing.expectError(error.InsecureEndpoint, validateEndpointSecurity("http://example.com/oauth"));
+    try std.testing.expectError(error.InsecureEndpoint, validateEndpointSecurity("http://192.168.1.1/oauth"));
+}
+
+test "TokenRefresher: background pool serves near-expiry token while refreshing" {
+    if (@import("builtin").single_threaded) return error.SkipZigTest;
+
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"fresh\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+    mock.block();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    // 60 of 3600 seconds left: well below the 10% refresh threshold
+    var token = try Token.initFull(allocator, "stale", "Bearer", "refresh-1", 3600, null, null);
+    defer token.deinit();
+    token.expires_at = @as(u64, @intCast(std.time.timestamp())) + 60;
+    try client.saveToken("svc", token);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+    try refresher.withBackgroundRefreshPool(1);
+
+    // Returned right away even though the token endpoint is stalled
+    var served = try refresher.getValidToken("svc");
+    defer served.deinit();
+    try std.testing.expectEqualStrings("stale", served.access_token);
+
+    // The refresh request goes out on the pool
+    var waited_ms: u32 = 0;
+    while (mock.requestCount() == 0 and waited_ms < 2000) : (waited_ms += 1) {
+        std.Thread.sleep(std.time.ns_per_ms);
+    }
+    try std.testing.expectEqual(@as(usize, 1), mock.requestCount());
+
+    mock.unblock();
+    refresher.waitForBackgroundRefreshes();
+
+    var stored = (try client.getToken("svc")).?;
+    defer stored.deinit();
+    try std.testing.expectEqualStrings("fresh", stored.access_token);
+    try std.testing.expectEqualStrings("refresh-1", stored.refresh_token.?);
+}
+
+test "TokenRefresher: expired token past grace window refreshes inline" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"fresh\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var token = try Token.initFull(allocator, "expired", "Bearer", "refresh-1", 3600, null, null);
+    defer token.deinit();
+    token.expires_at = @as(u64, @intCast(std.time.timestamp())) - 120;
+    try client.saveToken("svc", token);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+    try refresher.withBackgroundRefreshPool(1);
+    refresher.withGraceWindow(30);
+
+    var result = try refresher.getValidToken("svc");
+    defer result.deinit();
+    try std.testing.expectEqualStrings("fresh", result.access_token);
+}
+
+test "TokenRefresher: cancelAllRefreshes releases waiters of an in-flight refresh" {
+    if (@import("builtin").single_threaded) return error.SkipZigTest;
+
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"fresh\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+    mock.block();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var token = try Token.initFull(allocator, "expired", "Bearer", "refresh-1", 3600, null, null);
+    defer token.deinit();
+    token.expires_at = 1;
+    try client.saveToken("svc", token);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+
+    const Outcome = struct {
+        token: ?Token = null,
+        err: ?anyerror = null,
+
+        fn run(self: *@This(), r: *TokenRefresher) void {
+            if (r.getValidToken("svc")) |t| {
+                self.token = t;
+            } else |e| {
+                self.err = e;
+            }
+        }
+    };
+
+    // Leader: stalls on the token endpoint
+    var leader: Outcome = .{};
+    const leader_thread = try std.Thread.spawn(.{}, Outcome.run, .{ &leader, &refresher });
+
+    var waited_ms: u32 = 0;
+    while (mock.requestCount() == 0 and waited_ms < 2000) : (waited_ms += 1) {
+        std.Thread.sleep(std.time.ns_per_ms);
+    }
+
+    const keys = try refresher.inFlightKeys(allocator);
+    defer {
+        for (keys) |k| allocator.free(k);
+        allocator.free(keys);
+    }
+    try std.testing.expectEqual(@as(usize, 1), keys.len);
+    try std.testing.expectEqualStrings("svc", keys[0]);
+
+    // Waiter: blocks on the leader's refresh
+    var waiter: Outcome = .{};
+    const waiter_thread = try std.Thread.spawn(.{}, Outcome.run, .{ &waiter, &refresher });
+
+    waited_ms = 0;
+    while (refresher.waiterCount() == 0 and waited_ms < 2000) : (waited_ms += 1) {
+        std.Thread.sleep(std.time.ns_per_ms);
+    }
+    try std.testing.expectEqual(@as(usize, 1), refresher.waiterCount());
+
+    refresher.cancelAllRefreshes();
+    waiter_thread.join();
+    try std.testing.expectEqual(@as(?anyerror, error.Cancelled), waiter.err);
+
+    // The leader still completes and persists its result
+    mock.unblock();
+    leader_thread.join();
+    var leader_token = leader.token.?;
+    defer leader_token.deinit();
+    try std.testing.expectEqualStrings("fresh", leader_token.access_token);
+
+    const remaining = try refresher.inFlightKeys(allocator);
+    defer allocator.free(remaining);
+    try std.testing.expectEqual(@as(usize, 0), remaining.len);
+}
+
+fn testTagTenant(context: ?*anyopaque, token: *Token) anyerror!void {
+    const tenant: *const []const u8 = @ptrCast(@alignCast(context.?));
+    if (token.metadata) |m| token.allocator.free(m);
+    token.metadata = try std.fmt.allocPrint(token.allocator, "tenant={s}", .{tenant.*});
+}
+
+fn testClearExpiry(_: ?*anyopaque, token: *Token) anyerror!void {
+    token.expires_at = null;
+}
+
+test "OAuthClient: token transform annotates exchanged and refreshed tokens" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"exchanged\",\"token_type\":\"Bearer\",\"refresh_token\":\"r1\",\"expires_in\":60}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"refreshed\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var tenant: []const u8 = "acme";
+    client.withTokenTransform(&tenant, testTagTenant);
+
+    var exchanged = try client.exchangeCode("code", "verifier", "http://127.0.0.1/callback");
+    defer exchanged.deinit();
+    try client.saveToken("svc", exchanged);
+
+    var stored = (try client.getToken("svc")).?;
+    defer stored.deinit();
+    try std.testing.expectEqualStrings("tenant=acme", stored.metadata.?);
+
+    // 60 of 60 seconds is not due yet; force a refresh with a full threshold
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+    var refreshed = try refresher.getValidTokenWithThreshold("svc", 1.0);
+    defer refreshed.deinit();
+    try std.testing.expectEqualStrings("refreshed", refreshed.access_token);
+
+    var stored_after_refresh = (try client.getToken("svc")).?;
+    defer stored_after_refresh.deinit();
+    try std.testing.expectEqualStrings("refreshed", stored_after_refresh.access_token);
+    try std.testing.expectEqualStrings("tenant=acme", stored_after_refresh.metadata.?);
+}
+
+test "OAuthClient: token transform cannot drop the expiry" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"abc\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+    client.withTokenTransform(null, testClearExpiry);
+
+    try std.testing.expectError(error.InvalidParameter, client.refreshToken("r1"));
+}
+
+test "OAuthClient.exchangeSamlAssertion: sends saml2-bearer grant with encoded assertion" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"saml-token\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    const assertion = "<saml:Assertion ID=\"_a1\">subject+/=</saml:Assertion>";
+    var token = try client.exchangeSamlAssertion("enterprise", assertion, "read");
+    defer token.deinit();
+    try std.testing.expectEqualStrings("saml-token", token.access_token);
+
+    const sent = mock.lastRequest().?;
+    try std.testing.expectEqualStrings("https://github.com/login/oauth/access_token", sent.url);
+    const body = sent.body.?;
+    try std.testing.expect(std.mem.startsWith(u8, body, "grant_type=urn:ietf:params:oauth:grant-type:saml2-bearer&"));
+    try std.testing.expect(std.mem.indexOf(u8, body, "&scope=read") != null);
+
+    // The assertion is unpadded base64url, which needs no further escaping
+    var encoded: [std.base64.url_safe_no_pad.Encoder.calcSize(assertion.len)]u8 = undefined;
+    _ = std.base64.url_safe_no_pad.Encoder.encode(&encoded, assertion);
+    const expected = try std.fmt.allocPrint(allocator, "&assertion={s}&", .{&encoded});
+    defer allocator.free(expected);
+    try std.testing.expect(std.mem.indexOf(u8, body, expected) != null);
+    try std.testing.expect(std.mem.indexOfScalar(u8, &encoded, '=') == null);
+
+    // Saved through the regular storage path
+    var stored = (try client.getToken("enterprise")).?;
+    defer stored.deinit();
+    try std.testing.expectEqualStrings("saml-token", stored.access_token);
+}
+
+test "OAuthClient.exchangeSamlAssertion: expired assertion maps to InvalidGrant" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{
+        .status = 400,
+        .body = "{\"error\":\"invalid_grant\",\"error_description\":\"Assertion has expired\"}",
+    });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    try std.testing.expectError(
+        error.InvalidGrant,
+        client.exchangeSamlAssertion("enterprise", "<saml:Assertion/>", null),
+    );
+    try std.testing.expect(!storage.storage().exists("enterprise"));
+}
+
+test "TokenRefresher: circuit breaker short-circuits after repeated failures" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .status = 500, .body = "{\"error\":\"server_error\"}" });
+    try mock.enqueue(.{ .status = 500, .body = "{\"error\":\"server_error\"}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var token = try Token.initFull(allocator, "expired", "Bearer", "refresh-1", 3600, null, null);
+    defer token.deinit();
+    token.expires_at = @as(u64, @intCast(std.time.timestamp())) - 120;
+    try client.saveToken("svc", token);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+    try refresher.withCircuitBreaker(2, 30_000);
+
+    const start = clock.now();
+    clock.setMockTime(start);
+    defer clock.clearMockTime();
+
+    // Two failures trip the breaker
+    try std.testing.expectError(error.ServerError, refresher.getValidToken("svc"));
+    try std.testing.expectError(error.ServerError, refresher.getValidToken("svc"));
+    try std.testing.expectEqual(@as(u32, 2), refresher.refreshFailures("svc").?.consecutive);
+
+    // Open: no further requests reach the token endpoint
+    try std.testing.expectError(error.CircuitOpen, refresher.getValidToken("svc"));
+    try std.testing.expectError(error.CircuitOpen, refresher.getValidToken("svc"));
+    try std.testing.expectEqual(@as(usize, 2), mock.requestCount());
+
+    // Still open just before the cooldown ends
+    clock.setMockTime(start + 29);
+    try std.testing.expectError(error.CircuitOpen, refresher.getValidToken("svc"));
+
+    // After the cooldown one attempt goes through and success closes the circuit
+    clock.setMockTime(start + 30);
+    try mock.enqueue(.{ .body = "{\"access_token\":\"fresh\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var result = try refresher.getValidToken("svc");
+    defer result.deinit();
+    try std.testing.expectEqualStrings("fresh", result.access_token);
+    try std.testing.expectEqual(@as(usize, 3), mock.requestCount());
+    try std.testing.expect(refresher.refreshFailures("svc") == null);
+}
+
+test "TokenRefresher: detects reuse of a rotated refresh token" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"fresh\",\"token_type\":\"Bearer\",\"expires_in\":3600,\"refresh_token\":\"refresh-2\"}" });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"invalid_grant\"}" });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"invalid_grant\"}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var token = try Token.initFull(allocator, "expired", "Bearer", "refresh-1", 3600, null, null);
+    defer token.deinit();
+    token.expires_at = @as(u64, @intCast(std.time.timestamp())) - 120;
+    try client.saveToken("svc", token);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+
+    var rotated = try refresher.getValidToken("svc");
+    defer rotated.deinit();
+    try std.testing.expectEqualStrings("refresh-2", rotated.refresh_token.?);
+
+    // The retired refresh token comes back (e.g. a restored backup)
+    try client.saveToken("svc", token);
+    try std.testing.expectError(error.RefreshTokenReused, refresher.getValidToken("svc"));
+
+    // A refresh token that was never rotated is simply invalid
+    var revoked = try Token.initFull(allocator, "expired", "Bearer", "refresh-9", 3600, null, null);
+    defer revoked.deinit();
+    revoked.expires_at = token.expires_at;
+    try client.saveToken("svc", revoked);
+    try std.testing.expectError(error.InvalidGrant, refresher.getValidToken("svc"));
+}
+
+test "TokenRefresher.ensureValid: reports keys that cannot be made valid" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"fresh\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    const expired_at = @as(u64, @intCast(std.time.timestamp())) - 120;
+
+    var valid = try Token.initFull(allocator, "valid", "Bearer", null, 3600, null, null);
+    defer valid.deinit();
+    try client.saveToken("valid", valid);
+
+    var refreshable = try Token.initFull(allocator, "stale", "Bearer", "refresh-1", 3600, null, null);
+    defer refreshable.deinit();
+    refreshable.expires_at = expired_at;
+    try client.saveToken("refreshable", refreshable);
+
+    var dead = try Token.initFull(allocator, "dead", "Bearer", null, 3600, null, null);
+    defer dead.deinit();
+    dead.expires_at = expired_at;
+    try client.saveToken("dead", dead);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+
+    const keys = [_][]const u8{ "valid", "refreshable", "dead", "missing" };
+    const failures = try refresher.ensureValid(allocator, &keys);
+    defer allocator.free(failures);
+
+    try std.testing.expectEqual(@as(usize, 2), failures.len);
+    try std.testing.expectEqualStrings("dead", failures[0].key);
+    try std.testing.expectEqual(error.NoRefreshToken, failures[0].err);
+    try std.testing.expectEqualStrings("missing", failures[1].key);
+    try std.testing.expectEqual(error.TokenNotFound, failures[1].err);
+
+    var refreshed = (try client.getToken("refreshable")).?;
+    defer refreshed.deinit();
+    try std.testing.expectEqualStrings("fresh", refreshed.access_token);
+
+    // Everything valid now except the two failures
+    const again = try refresher.ensureValid(allocator, keys[0..2]);
+    defer allocator.free(again);
+    try std.testing.expectEqual(@as(usize, 0), again.len);
+}
+
+test "AuthorizationFlow.parsedUrl exposes the same state and code challenge as the URL" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "repo user"), storage.storage());
+    defer client.deinit();
+
+    var flow = try client.startAuthorization("http://127.0.0.1:8080/callback");
+    defer flow.deinit();
+
+    var request = try flow.parsedUrl(allocator);
+    defer request.deinit();
+
+    try std.testing.expectEqualStrings("https://github.com/login/oauth/authorize", request.base);
+    try std.testing.expectEqualStrings(flow.getState(), request.get("state").?);
+    try std.testing.expectEqualStrings(flow.pkce_pair.getChallenge(), request.get("code_challenge").?);
+    try std.testing.expectEqualStrings("http://127.0.0.1:8080/callback", request.get("redirect_uri").?);
+    try std.testing.expectEqualStrings("repo user", request.get("scope").?);
+
+    // The string URL carries the same values
+    const state_param = try std.fmt.allocPrint(allocator, "state={s}", .{flow.getState()});
+    defer allocator.free(state_param);
+    try std.testing.expect(std.mem.indexOf(u8, flow.url, state_param) != null);
+    const challenge_param = try std.fmt.allocPrint(allocator, "code_challenge={s}", .{flow.pkce_pair.getChallenge()});
+    defer allocator.free(challenge_param);
+    try std.testing.expect(std.mem.indexOf(u8, flow.url, challenge_param) != null);
+}
+
+test "OAuthClient.withServerClock: expiry follows the server Date header" {
+    const allocator = std.testing.allocator;
+
+    // The local clock runs two hours ahead of the server
+    const local_now: u64 = 1_700_000_000;
+    clock.setMockTime(local_now);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{
+        .headers = &.{.{ .name = "Date", .value = "Tue, 14 Nov 2023 20:13:20 GMT" }},
+        .body = "{\"access_token\":\"skewed\",\"token_type\":\"Bearer\",\"expires_in\":3600}",
+    });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+    client.withServerClock();
+
+    var token = try client.refreshToken("refresh-1");
+    defer token.deinit();
+
+    try std.testing.expectEqual(@as(i64, -7200), token.clock_offset);
+    try std.testing.expectEqual(local_now - 7200 + 3600, token.expires_at.?);
+    try std.testing.expect(!token.isExpired());
+
+    // Past expiry by the local clock, but not by the server's
+    clock.setMockTime(local_now + 3601);
+    try std.testing.expect(!token.isExpired());
+
+    // Past expiry by the corrected time
+    clock.setMockTime(local_now + 7200 + 3600);
+    try std.testing.expect(token.isExpired());
+
+    // The offset survives a storage roundtrip
+    try client.saveToken("svc", token);
+    var stored = (try client.getToken("svc")).?;
+    defer stored.deinit();
+    try std.testing.expectEqual(@as(i64, -7200), stored.clock_offset);
+    try std.testing.expect(stored.isExpired());
+}
+
+test "OAuthClient.exchangeCodeForSession: reports scopes the user declined" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"narrow\",\"token_type\":\"Bearer\",\"scope\":\"read:user repo\"}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"full\",\"token_type\":\"Bearer\"}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var auth_session = try session.Session.init(allocator, "github.com");
+    defer auth_session.deinit();
+    try auth_session.setRequestedScope("repo read:user gist workflow");
+
+    var partial = try client.exchangeCodeForSession(&auth_session, "code-1", "verifier", "http://127.0.0.1/callback");
+    defer partial.deinit();
+    try std.testing.expect(!partial.scopes_fully_granted);
+    try std.testing.expectEqual(@as(usize, 2), partial.missing.len);
+    try std.testing.expectEqualStrings("gist", partial.missing[0]);
+    try std.testing.expectEqualStrings("workflow", partial.missing[1]);
+
+    // No scope in the response means everything requested was granted
+    var full = try client.exchangeCodeForSession(&auth_session, "code-2", "verifier", "http://127.0.0.1/callback");
+    defer full.deinit();
+    try std.testing.expect(full.scopes_fully_granted);
+    try std.testing.expectEqual(@as(usize, 0), full.missing.len);
+}
+
+test "OAuthClient.startAuthorizationForSession: records the requested scope" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "repo read:user"), storage.storage());
+    defer client.deinit();
+
+    var auth_session = try session.Session.init(allocator, "github.com");
+    defer auth_session.deinit();
+
+    var flow = try client.startAuthorizationForSession(&auth_session, "http://127.0.0.1/callback", .{
+        .extra_params = &.{.{ .name = "prompt", .value = "consent" }},
+    });
+    defer flow.deinit();
+    try std.testing.expectEqualStrings("repo read:user", auth_session.requested_scope.?);
+    try std.testing.expectEqual(@as(usize, 1), auth_session.authorization_params.len);
+
+    var narrower = try client.startAuthorizationForSession(&auth_session, "http://127.0.0.1/callback", .{ .scope = "repo" });
+    defer narrower.deinit();
+    try std.testing.expectEqualStrings("repo", auth_session.requested_scope.?);
+    try std.testing.expectEqual(@as(usize, 0), auth_session.authorization_params.len);
+}
+
+test "OAuthClient.exchangeCodeForSession: rejects an ID token with another session's nonce" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "openid"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var flow = try client.startAuthorization("http://127.0.0.1/callback");
+    defer flow.deinit();
+
+    var auth_session = try session.Session.init(allocator, "github.com");
+    defer auth_session.deinit();
+    try auth_session.setNonce(flow.sentNonce());
+    try std.testing.expectEqualStrings(flow.getNonce(), auth_session.nonce.?);
+
+    const own = try testIdToken(allocator, flow.getNonce());
+    defer allocator.free(own);
+    const injected = try testIdToken(allocator, "nonce-of-another-session");
+    defer allocator.free(injected);
+
+    const own_response = try std.fmt.allocPrint(allocator, "{{\"access_token\":\"at\",\"token_type\":\"Bearer\",\"id_token\":\"{s}\"}}", .{own});
+    defer allocator.free(own_response);
+    const injected_response = try std.fmt.allocPrint(allocator, "{{\"access_token\":\"at\",\"token_type\":\"Bearer\",\"id_token\":\"{s}\"}}", .{injected});
+    defer allocator.free(injected_response);
+
+    try mock.enqueue(.{ .body = own_response });
+    var result = try client.exchangeCodeForSession(&auth_session, "code", flow.pkce_pair.getVerifier(), flow.redirect_uri);
+    result.deinit();
+
+    try mock.enqueue(.{ .body = injected_response });
+    try std.testing.expectError(
+        error.InvalidNonce,
+        client.exchangeCodeForSession(&auth_session, "code", flow.pkce_pair.getVerifier(), flow.redirect_uri),
+    );
+
+    // The flow-based exchange checks the same nonce
+    try mock.enqueue(.{ .body = injected_response });
+    try std.testing.expectError(error.InvalidNonce, client.exchangeCodeForFlow(&flow, "code"));
+}
+
+test "OAuthClient.fetchUserInfo: sends the stored token and checks the subject" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.google("test-client", "openid email"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    try std.testing.expectError(error.TokenNotFound, client.fetchUserInfo("google"));
+
+    var token = try Token.init(allocator, "user-access", "Bearer");
+    const claims = try jwt.encodeSegment(allocator, "{\"iss\":\"https://accounts.google.com\",\"sub\":\"110169\",\"aud\":\"test-client\",\"exp\":1,\"iat\":1}");
+    defer allocator.free(claims);
+    token.id_token = try std.fmt.allocPrint(allocator, "eyJhbGciOiJSUzI1NiJ9.{s}.c2ln", .{claims});
+    try client.saveToken("google", token);
+    token.deinit();
+
+    try mock.enqueue(.{ .body = "{\"sub\":\"110169\",\"email\":\"jane@example.com\",\"email_verified\":true,\"name\":\"Jane\",\"locale\":\"de\"}" });
+    var info = try client.fetchUserInfo("google");
+    defer info.deinit();
+
+    const request = mock.lastRequest().?;
+    try std.testing.expectEqualStrings("https://openidconnect.googleapis.com/v1/userinfo", request.url);
+    try std.testing.expectEqualStrings("Bearer user-access", request.header("Authorization").?);
+    try std.testing.expectEqualStrings("110169", info.sub);
+    try std.testing.expectEqualStrings("jane@example.com", info.email.?);
+    try std.testing.expectEqual(@as(?bool, true), info.email_verified);
+    try std.testing.expectEqualStrings("Jane", info.name.?);
+    try std.testing.expectEqualStrings("de", info.claim("locale").?.string);
+
+    // A response about someone else is rejected
+    try mock.enqueue(.{ .body = "{\"sub\":\"other\"}" });
+    try std.testing.expectError(error.InvalidIdToken, client.fetchUserInfo("google"));
+
+    try mock.enqueue(.{ .status = 401, .body = "" });
+    try std.testing.expectError(error.TokenExpired, client.fetchUserInfo("google"));
+}
+
+test "OAuthClient.logout: revokes, deletes and builds the end_session URL" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var config = OAuthConfig.google("test-client", "openid");
+    config.end_session_endpoint = "https://id.example.com/logout";
+    config.revocation_endpoint = "https://id.example.com/revoke";
+
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var token = try Token.init(allocator, "at", "Bearer");
+    token.refresh_token = try allocator.dupe(u8, "rt");
+    token.id_token = try allocator.dupe(u8, "header.payload.sig");
+    try client.saveToken("google", token);
+    token.deinit();
+
+    // A failed revocation keeps the token
+    try mock.enqueue(.{ .status = 503, .body = "" });
+    try std.testing.expectError(error.ServerError, client.logout("google", .{ .revoke_refresh_token = true }));
+    try std.testing.expect(storage.storage().exists("google"));
+
+    try mock.enqueue(.{ .body = "" });
+    const url = (try client.logout("google", .{
+        .revoke_refresh_token = true,
+        .post_logout_redirect_uri = "http://127.0.0.1/bye",
+        .state = "s1",
+    })).?;
+    defer allocator.free(url);
+
+    try std.testing.expectEqualStrings(
+        "https://id.example.com/logout?client_id=test-client&id_token_hint=header.payload.sig&post_logout_redirect_uri=http%3A%2F%2F127.0.0.1%2Fbye&state=s1",
+        url,
+    );
+    const revocation = mock.lastRequest().?;
+    try std.testing.expectEqualStrings("https://id.example.com/revoke", revocation.url);
+    try std.testing.expect(std.mem.indexOf(u8, revocation.body.?, "token=rt&token_type_hint=refresh_token") != null);
+    try std.testing.expect(!storage.storage().exists("google"));
+
+    // Signing out again is harmless
+    const again = (try client.logout("google", .{})).?;
+    defer allocator.free(again);
+    try std.testing.expectEqualStrings("https://id.example.com/logout?client_id=test-client", again);
+}
+
+test "OAuthClient.introspect: authenticates and parses the response" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var config = OAuthConfig.github("resource-server", null);
+    config.client_secret = "rs-secret";
+    config.introspection_endpoint = "https://auth.example.com/introspect";
+
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    try mock.enqueue(.{ .body =
+        \\{"active":true,"scope":"read write","client_id":"app","sub":"user-1","exp":1700003600,"aud":"https://api.example.com","tenant":"acme"}
+    });
+    var active = try client.introspect("opaque-token", "access_token");
+    defer active.deinit();
+
+    try std.testing.expectEqualStrings(
+        "token=opaque-token&token_type_hint=access_token&client_id=resource-server&client_secret=rs-secret",
+        mock.lastRequest().?.body.?,
+    );
+    try std.testing.expect(active.active);
+    try std.testing.expect(active.hasScope("write"));
+    try std.testing.expect(!active.hasScope("admin"));
+    try std.testing.expectEqualStrings("app", active.client_id.?);
+    try std.testing.expectEqualStrings("user-1", active.sub.?);
+    try std.testing.expectEqual(@as(?u64, 1_700_003_600), active.exp);
+    try std.testing.expectEqualStrings("acme", active.claim("tenant").?.string);
+
+    try mock.enqueue(.{ .body = "{\"active\":false}" });
+    var inactive = try client.introspect("revoked-token", null);
+    defer inactive.deinit();
+    try std.testing.expect(!inactive.active);
+    try std.testing.expect(!inactive.hasScope("read"));
+}
+
+test "OAuthClient.revokeToken: revokes both tokens before deleting" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var config = OAuthConfig.github("test-client", null);
+    config.revocation_endpoint = "https://auth.example.com/revoke";
+
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    try std.testing.expectError(error.TokenNotFound, client.revokeToken("svc"));
+
+    var token = try Token.init(allocator, "at", "Bearer");
+    token.refresh_token = try allocator.dupe(u8, "rt");
+    try client.saveToken("svc", token);
+    try client.saveToken("other", token);
+    token.deinit();
+
+    try mock.enqueue(.{ .body = "" });
+    try mock.enqueue(.{ .body = "" });
+    try client.revokeToken("svc");
+
+    try std.testing.expectEqual(@as(usize, 2), mock.requestCount());
+    try std.testing.expect(std.mem.startsWith(u8, mock.lastRequest().?.body.?, "token=at&token_type_hint=access_token&client_id=test-client"));
+    try std.testing.expect(!storage.storage().exists("svc"));
+
+    // A server that cannot revoke the token type leaves the token in place
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"unsupported_token_type\"}" });
+    try std.testing.expectError(error.UnsupportedOperation, client.revokeRefreshToken("other"));
+    try std.testing.expect(storage.storage().exists("other"));
+
+    try mock.enqueue(.{ .body = "" });
+    try client.revokeRefreshToken("other");
+    try std.testing.expect(std.mem.startsWith(u8, mock.lastRequest().?.body.?, "token=rt&token_type_hint=refresh_token"));
+    try std.testing.expect(!storage.storage().exists("other"));
+}
+
+test "OAuthClient.exchangeSignedJwt: posts a jwt-bearer grant for the token endpoint" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"service-token\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "read"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    const key_pair = std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair.generate();
+    var token = try client.exchangeSignedJwt("svc", .{ .es256 = key_pair }, .{
+        .issuer = "svc@example.com",
+        .subject = "svc@example.com",
+        .audience = "",
+    }, null, null);
+    defer token.deinit();
+    try std.testing.expectEqualStrings("service-token", token.access_token);
+    try std.testing.expect(storage.storage().exists("svc"));
+
+    const body = mock.lastRequest().?.body.?;
+    try std.testing.expect(std.mem.startsWith(u8, body, "grant_type=urn:ietf:params:oauth:grant-type:jwt-bearer&assertion="));
+    try std.testing.expect(std.mem.indexOf(u8, body, "&scope=read") != null);
+
+    // The assertion is addressed to the token endpoint
+    const start = std.mem.indexOf(u8, body, "assertion=").? + "assertion=".len;
+    const assertion = body[start..std.mem.indexOfScalarPos(u8, body, start, '&').?];
+    const payload = try jwt.decodeSegment(allocator, (try jwt.split(assertion)).payload);
+    defer allocator.free(payload);
+    try std.testing.expect(std.mem.indexOf(u8, payload, "\"aud\":\"https://github.com/login/oauth/access_token\"") != null);
+
+    try std.testing.expectError(error.InvalidParameter, client.exchangeJwtAssertion("svc", "not-a-jwt", null));
+}
+
+test "ClientAuth.private_key_jwt: signs a client assertion instead of sending the secret" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"service-token\",\"token_type\":\"Bearer\"}" });
+    try mock.enqueue(.{ .body = "{\"active\":false}" });
+
+    const key_pair = std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair.generate();
+    var config = OAuthConfig.github("service", "read");
+    config.introspection_endpoint = "https://github.com/introspect";
+    config.client_auth = .{ .private_key_jwt = .{ .key = .{ .es256 = key_pair }, .kid = "svc-1" } };
+
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var token = try client.clientCredentials("svc", null);
+    defer token.deinit();
+
+    const body = mock.lastRequest().?.body.?;
+    try std.testing.expect(std.mem.indexOf(u8, body, "client_secret=") == null);
+    try std.testing.expect(std.mem.indexOf(u8, body, "&client_assertion_type=urn%3Aietf%3Aparams%3Aoauth%3Aclient-assertion-type%3Ajwt-bearer") != null);
+
+    // The assertion is issued by the client for the token endpoint
+    const start = std.mem.indexOf(u8, body, "&client_assertion=").? + "&client_assertion=".len;
+    const end = std.mem.indexOfScalarPos(u8, body, start, '&') orelse body.len;
+    const assertion = body[start..end];
+    var header = try jwt.decodeHeader(allocator, assertion);
+    defer header.deinit();
+    try std.testing.expectEqual(jwt.Algorithm.ES256, header.alg);
+    try std.testing.expectEqualStrings("svc-1", header.kid.?);
+
+    const parts = try jwt.split(assertion);
+    const payload = try jwt.decodeSegment(allocator, parts.payload);
+    defer allocator.free(payload);
+    try std.testing.expect(std.mem.indexOf(u8, payload, "\"iss\":\"service\",\"sub\":\"service\",\"aud\":\"https://github.com/login/oauth/access_token\"") != null);
+
+    const signature = try jwt.decodeSegment(allocator, parts.signature);
+    defer allocator.free(signature);
+    try std.crypto.sign.ecdsa.EcdsaP256Sha256.Signature.fromBytes(signature[0..64].*).verify(parts.signing_input, key_pair.public_key);
+
+    // Introspection authenticates the same way
+    var introspection = try client.introspect("opaque", null);
+    defer introspection.deinit();
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "&client_assertion=") != null);
+}
+
+test "ClientAuth.client_secret_basic: sends the secret in an Authorization header" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"service-token\",\"token_type\":\"Bearer\",\"refresh_token\":\"rt\"}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"renewed\",\"token_type\":\"Bearer\"}" });
+
+    var config = OAuthConfig.github("svc:1", "read");
+    config.client_secret = "s3cret";
+    config.client_auth = .client_secret_basic;
+
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var token = try client.clientCredentials("svc", null);
+    defer token.deinit();
+
+    // base64("svc%3A1:s3cret")
+    const sent = mock.lastRequest().?;
+    try std.testing.expectEqualStrings("Basic c3ZjJTNBMTpzM2NyZXQ=", sent.header("Authorization").?);
+    try std.testing.expect(std.mem.indexOf(u8, sent.body.?, "client_secret") == null);
+
+    var renewed = try client.refreshToken("rt");
+    defer renewed.deinit();
+    try std.testing.expectEqualStrings("Basic c3ZjJTNBMTpzM2NyZXQ=", mock.lastRequest().?.header("Authorization").?);
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "client_secret") == null);
+}
+
+test "OAuthClient.startAuthorizationWithOptions: requests and stores authorization details" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body =
+        \\{"access_token":"payment-token","token_type":"Bearer",
+        \\ "authorization_details":[{"type":"payment_initiation","instructedAmount":{"currency":"EUR","amount":"123.50"}}]}
+    });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "read"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    const details = "[{\"type\":\"payment_initiation\",\"instructedAmount\":{\"currency\":\"EUR\",\"amount\":\"123.50\"}}]";
+    var flow = try client.startAuthorizationWithOptions("http://127.0.0.1:8080/callback", .{ .authorization_details = details });
+    defer flow.deinit();
+    try std.testing.expect(std.mem.indexOf(u8, flow.url, "&authorization_details=%5B%7B%22type%22%3A%22payment_initiation%22") != null);
+
+    var token = try client.exchangeCodeForFlow(&flow, "code");
+    defer token.deinit();
+    try std.testing.expectEqualStrings(details, token.authorization_details.?);
+
+    // Granted details survive persistence
+    try client.saveToken("payments", token);
+    var stored = (try client.getToken("payments")).?;
+    defer stored.deinit();
+    try std.testing.expectEqualStrings(details, stored.authorization_details.?);
+
+    try std.testing.expectError(error.InvalidParameter, client.startAuthorizationWithOptions("http://127.0.0.1:8080/callback", .{
+        .authorization_details = "{\"type\":\"payment_initiation\"}",
+    }));
+    try std.testing.expectError(error.InvalidParameter, client.startAuthorizationWithOptions("http://127.0.0.1:8080/callback", .{
+        .authorization_details = "[{\"actions\":[\"read\"]}]",
+    }));
+}
+
+test "OAuthClient.exchangeCodeForFlow: repeats the flow's resource indicators" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"orders\",\"token_type\":\"Bearer\"}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "read"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    const resources = [_][]const u8{ "https://orders.example.com", "https://billing.example.com" };
+    var flow = try client.startAuthorizationWithOptions("http://127.0.0.1:8080/callback", .{ .resources = &resources });
+    defer flow.deinit();
+
+    var token = try client.exchangeCodeForFlow(&flow, "code");
+    defer token.deinit();
+    try std.testing.expect(std.mem.indexOf(
+        u8,
+        mock.lastRequest().?.body.?,
+        "&resource=https%3A%2F%2Forders.example.com&resource=https%3A%2F%2Fbilling.example.com",
+    ) != null);
+}
+
+test "TokenRefresher.getValidTokenForScope: mints least-privilege tokens from a broad grant" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"read-1\",\"token_type\":\"Bearer\",\"expires_in\":300,\"refresh_token\":\"rt-2\"}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"read-2\",\"token_type\":\"Bearer\",\"expires_in\":300}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "read write"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var grant = try Token.initFull(allocator, "grant", "Bearer", "rt-1", 3600, "read write admin", null);
+    defer grant.deinit();
+    try client.saveToken("acct", grant);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+
+    var narrow = try refresher.getValidTokenForScope("acct", "read");
+    defer narrow.deinit();
+    try std.testing.expectEqualStrings("read-1", narrow.access_token);
+    try std.testing.expectEqualStrings("read", narrow.scope.?);
+    try std.testing.expect(narrow.refresh_token == null);
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "&refresh_token=rt-1&scope=read") != null);
+
+    // The broad grant keeps the rotated refresh token
+    var updated = (try client.getToken("acct")).?;
+    defer updated.deinit();
+    try std.testing.expectEqualStrings("rt-2", updated.refresh_token.?);
+    try std.testing.expectEqualStrings("read write admin", updated.scope.?);
+
+    // Served from storage until it expires, then minted again
+    var cached = try refresher.getValidTokenForScope("acct", "read");
+    defer cached.deinit();
+    try std.testing.expectEqualStrings("read-1", cached.access_token);
+    try std.testing.expectEqual(@as(usize, 1), mock.requestCount());
+
+    clock.setMockTime(1_700_000_400);
+    var renewed = try refresher.getValidTokenForScope("acct", "read");
+    defer renewed.deinit();
+    try std.testing.expectEqualStrings("read-2", renewed.access_token);
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "&refresh_token=rt-2&scope=read") != null);
+
+    // Downscoping cannot widen the grant
+    try std.testing.expectError(error.InvalidParameter, refresher.getValidTokenForScope("acct", "read billing"));
+}
+
+test "TokenRefresher.getValidTokenForResource: keeps one access token per resource" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"orders-1\",\"token_type\":\"Bearer\",\"expires_in\":600}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"billing-1\",\"token_type\":\"Bearer\",\"expires_in\":600,\"refresh_token\":\"rt-2\"}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"orders-2\",\"token_type\":\"Bearer\",\"expires_in\":600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "read"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var grant = try Token.initFull(allocator, "grant", "Bearer", "rt-1", 600, "read", null);
+    defer grant.deinit();
+    try client.saveToken("acct", grant);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+
+    var orders = try refresher.getValidTokenForResource("acct", "https://orders.example.com");
+    defer orders.deinit();
+    try std.testing.expectEqualStrings("orders-1", orders.access_token);
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "&refresh_token=rt-1&resource=https%3A%2F%2Forders.example.com") != null);
+    // Only the grant keeps the refresh token
+    try std.testing.expect(orders.refresh_token == null);
+
+    // A rotated refresh token is written back to the grant
+    var billing = try refresher.getValidTokenForResource("acct", "https://billing.example.com");
+    defer billing.deinit();
+    try std.testing.expectEqualStrings("billing-1", billing.access_token);
+    var updated = (try client.getToken("acct")).?;
+    defer updated.deinit();
+    try std.testing.expectEqualStrings("rt-2", updated.refresh_token.?);
+
+    // Cached until it expires, then refreshed for the same resource
+    var cached = try refresher.getValidTokenForResource("acct", "https://orders.example.com");
+    defer cached.deinit();
+    try std.testing.expectEqualStrings("orders-1", cached.access_token);
+    try std.testing.expectEqual(@as(usize, 2), mock.requestCount());
+
+    clock.setMockTime(1_700_000_700);
+    var renewed = try refresher.getValidTokenForResource("acct", "https://orders.example.com");
+    defer renewed.deinit();
+    try std.testing.expectEqualStrings("orders-2", renewed.access_token);
+    try std.testing.expectEqualStrings("https://orders.example.com", renewed.resource.?);
+    // Minted again from the grant's rotated refresh token
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "&refresh_token=rt-2&resource=https%3A%2F%2Forders.example.com") != null);
+
+    try std.testing.expectError(error.InvalidParameter, client.requestResourceToken("acct", "https://api.example.com/#frag"));
+}
+
+test "OAuthClient.startBackchannelAuth: polls until the user approves on their device" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"auth_req_id\":\"req-1\",\"expires_in\":120,\"interval\":2}" });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"authorization_pending\"}" });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"slow_down\"}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"agent-token\",\"token_type\":\"Bearer\",\"refresh_token\":\"rt\"}" });
+
+    var config = OAuthConfig.github("call-center", "profile");
+    config.backchannel_authentication_endpoint = "https://github.com/bc-authorize";
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+    client.min_poll_interval = 0;
+
+    var flow = try client.startBackchannelAuth(.{ .login_hint = "alice@example.com", .binding_message = "W4SCT" });
+    defer flow.deinit();
+    try std.testing.expectEqualStrings(
+        "scope=openid%20profile&login_hint=alice%40example.com&binding_message=W4SCT&client_id=call-center",
+        mock.lastRequest().?.body.?,
+    );
+    try std.testing.expectEqual(@as(u64, 2), flow.interval);
+
+    try std.testing.expect((try flow.poll()) == null);
+    try std.testing.expect((try flow.poll()) == null);
+    try std.testing.expectEqual(@as(u64, 7), flow.interval);
+    try std.testing.expect(std.mem.startsWith(u8, mock.lastRequest().?.body.?, "grant_type=urn:openid:params:grant-type:ciba&auth_req_id=req-1"));
+
+    var token = (try flow.poll()).?;
+    defer token.deinit();
+    try std.testing.expectEqualStrings("agent-token", token.access_token);
+
+    // The request lapses after expires_in
+    clock.setMockTime(1_700_000_120);
+    try std.testing.expectError(error.AuthRequestExpired, flow.poll());
+    try std.testing.expectError(error.InvalidParameter, client.startBackchannelAuth(.{}));
+}
+
+test "BackchannelFlow.handlePing: fetches the token after a verified notification" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"auth_req_id\":\"req-9\",\"expires_in\":120}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"agent-token\",\"token_type\":\"Bearer\"}" });
+
+    var config = OAuthConfig.github("call-center", null);
+    config.backchannel_authentication_endpoint = "https://github.com/bc-authorize";
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var flow = try client.startBackchannelAuth(.{ .login_hint = "alice", .delivery_mode = .ping });
+    defer flow.deinit();
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, &flow.client_notification_token) != null);
+
+    const forged = "Bearer not-the-token";
+    try std.testing.expectError(error.InvalidParameter, flow.handlePing("alice", forged, "{\"auth_req_id\":\"req-9\"}"));
+    try std.testing.expectEqual(@as(usize, 1), mock.requestCount());
+
+    const authorization = try std.fmt.allocPrint(allocator, "Bearer {s}", .{&flow.client_notification_token});
+    defer allocator.free(authorization);
+    var token = try flow.handlePing("alice", authorization, "{\"auth_req_id\":\"req-9\"}");
+    defer token.deinit();
+    try std.testing.expect(storage.storage().exists("alice"));
+}
+
+test "OAuthClient.startAuthorizationWithOptions: appends extra parameters in order" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "repo"), storage.storage());
+    defer client.deinit();
+
+    const params = [_]AuthorizationParam{
+        .{ .name = "prompt", .value = "consent" },
+        .{ .name = "login_hint", .value = "alice@example.com" },
+        .{ .name = "domain_hint", .value = "example.com" },
+    };
+    var flow = try client.startAuthorizationWithOptions("http://127.0.0.1/callback", .{ .extra_params = &params });
+    defer flow.deinit();
+    try std.testing.expect(std.mem.endsWith(
+        u8,
+        flow.url,
+        "&prompt=consent&login_hint=alice%40example.com&domain_hint=example.com",
+    ));
+
+    var auth_session = try session.Session.init(allocator, "github.com");
+    defer auth_session.deinit();
+    try auth_session.setAuthorizationParams(&params);
+    try std.testing.expectEqual(@as(usize, 3), auth_session.authorization_params.len);
+    try std.testing.expectEqualStrings("login_hint", auth_session.authorization_params[1].name);
+    try std.testing.expectEqualStrings("alice@example.com", auth_session.authorization_params[1].value);
+
+    // Protocol parameters stay under the client's control
+    const override = [_]AuthorizationParam{.{ .name = "redirect_uri", .value = "https://evil.example.com" }};
+    try std.testing.expectError(
+        error.InvalidParameter,
+        client.startAuthorizationWithOptions("http://127.0.0.1/callback", .{ .extra_params = &override }),
+    );
+}
+
+test "OAuthConfig.audience: requested per call and stored per audience" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"default-token\",\"token_type\":\"Bearer\"}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"billing-token\",\"token_type\":\"Bearer\"}" });
+
+    var config = OAuthConfig.github("svc", "read");
+    config.client_secret = "s3cret";
+    config.audience = "https://api.example.com";
+
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var default_token = try client.clientCredentials("svc", null);
+    defer default_token.deinit();
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "&audience=https%3A%2F%2Fapi.example.com") != null);
+    try std.testing.expectEqualStrings("https://api.example.com", default_token.audience.?);
+
+    var billing_token = try client.clientCredentialsWithAudience("svc", null, "https://billing.example.com");
+    defer billing_token.deinit();
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "&audience=https%3A%2F%2Fbilling.example.com") != null);
+
+    // Each audience keeps its own token
+    const billing_key = try audienceKey(allocator, "svc", "https://billing.example.com");
+    defer allocator.free(billing_key);
+    var stored_default = (try client.getToken("svc")).?;
+    defer stored_default.deinit();
+    try std.testing.expectEqualStrings("default-token", stored_default.access_token);
+    var stored_billing = (try client.getToken(billing_key)).?;
+    defer stored_billing.deinit();
+    try std.testing.expectEqualStrings("billing-token", stored_billing.access_token);
+    try std.testing.expectEqualStrings("https://billing.example.com", stored_billing.audience.?);
+
+    var flow = try client.startAuthorizationWithOptions("http://127.0.0.1/callback", .{ .audience = "https://billing.example.com" });
+    defer flow.deinit();
+    try std.testing.expect(std.mem.indexOf(u8, flow.url, "&audience=https%3A%2F%2Fbilling.example.com") != null);
+    try std.testing.expectEqualStrings("https://billing.example.com", flow.audience.?);
+}
+
+test "AuthorizationOptions.max_age: sent with the request and enforced on auth_time" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "repo"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var flow = try client.startAuthorizationWithOptions("http://127.0.0.1/callback", .{ .max_age = 300 });
+    defer flow.deinit();
+    try std.testing.expect(std.mem.indexOf(u8, flow.url, "&max_age=300") != null);
+
+    const responses = [_][]const u8{
+        \\{"iss":"https://id.example.com","sub":"user","aud":"test-client","exp":1700000600,"iat":1700000000,"auth_time":1699999900}
+        ,
+        \\{"iss":"https://id.example.com","sub":"user","aud":"test-client","exp":1700000600,"iat":1700000000,"auth_time":1699990000}
+        ,
+    };
+    for (responses) |claims| {
+        const payload = try jwt.encodeSegment(allocator, claims);
+        defer allocator.free(payload);
+        const body = try std.fmt.allocPrint(allocator, "{{\"access_token\":\"at\",\"token_type\":\"Bearer\",\"id_token\":\"eyJhbGciOiJSUzI1NiJ9.{s}.c2ln\"}}", .{payload});
+        defer allocator.free(body);
+        try mock.enqueue(.{ .body = body });
+    }
+
+    var recent = try client.exchangeCodeForFlow(&flow, "code-1");
+    defer recent.deinit();
+    try std.testing.expectError(error.StaleAuthentication, client.exchangeCodeForFlow(&flow, "code-2"));
+}
+
+test "AuthorizationOptions.acr_values: rejects ID tokens without the required factors" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "repo"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var flow = try client.startAuthorizationWithOptions("http://127.0.0.1/callback", .{
+        .acr_values = "urn:example:mfa",
+        .required_amr = "mfa",
+    });
+    defer flow.deinit();
+    try std.testing.expect(std.mem.indexOf(u8, flow.url, "&acr_values=urn%3Aexample%3Amfa") != null);
+    try std.testing.expect(std.mem.indexOf(u8, flow.url, "amr=") == null);
+
+    const responses = [_][]const u8{
+        \\{"iss":"https://id.example.com","sub":"user","aud":"test-client","exp":1700000600,"iat":1700000000,"acr":"urn:example:mfa","amr":["pwd","mfa"]}
+        ,
+        \\{"iss":"https://id.example.com","sub":"user","aud":"test-client","exp":1700000600,"iat":1700000000,"acr":"urn:example:mfa","amr":["pwd"]}
+        ,
+    };
+    for (responses) |claims| {
+        const payload = try jwt.encodeSegment(allocator, claims);
+        defer allocator.free(payload);
+        const body = try std.fmt.allocPrint(allocator, "{{\"access_token\":\"at\",\"token_type\":\"Bearer\",\"id_token\":\"eyJhbGciOiJSUzI1NiJ9.{s}.c2ln\"}}", .{payload});
+        defer allocator.free(body);
+        try mock.enqueue(.{ .body = body });
+    }
+
+    var strong = try client.exchangeCodeForFlow(&flow, "code-1");
+    defer strong.deinit();
+    try std.testing.expectError(error.InsufficientAuthentication, client.exchangeCodeForFlow(&flow, "code-2"));
+}
+
+test "OAuthConfig.request_signing: sends the parameters as a signed request object" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .status = 201, .body = "{\"request_uri\":\"urn:example:pushed\",\"expires_in\":60}" });
+
+    const key_pair = std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair.generate();
+    var config = OAuthConfig.github("test-client", "repo");
+    config.request_signing = .{ .key = .{ .es256 = key_pair }, .kid = "req-1" };
+
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var flow = try client.startAuthorization("http://127.0.0.1/callback");
+    defer flow.deinit();
+
+    var request = try flow.parsedUrl(allocator);
+    defer request.deinit();
+    try std.testing.expectEqual(@as(usize, 2), request.query_params.len);
+    try std.testing.expectEqualStrings("test-client", request.get("client_id").?);
+
+    const parts = try jwt.split(request.get("request").?);
+    const signature = try jwt.decodeSegment(allocator, parts.signature);
+    defer allocator.free(signature);
+    try std.crypto.sign.ecdsa.EcdsaP256Sha256.Signature.fromBytes(signature[0..64].*).verify(parts.signing_input, key_pair.public_key);
+
+    const payload = try jwt.decodeSegment(allocator, parts.payload);
+    defer allocator.free(payload);
+    const claims = try json.parseFromSlice(json.Value, allocator, payload, .{});
+    defer claims.deinit();
+    try std.testing.expectEqualStrings("https://github.com/login/oauth/authorize", claims.value.object.get("aud").?.string);
+    try std.testing.expectEqualStrings(flow.getState(), claims.value.object.get("state").?.string);
+    try std.testing.expectEqualStrings(flow.pkce_pair.getChallenge(), claims.value.object.get("code_challenge").?.string);
+
+    // With PAR only the signed object is pushed
+    client.config.pushed_authorization_request_endpoint = "https://github.com/par";
+    var pushed = try client.startAuthorization("http://127.0.0.1/callback");
+    defer pushed.deinit();
+    try std.testing.expect(std.mem.endsWith(u8, pushed.url, "&request_uri=urn%3Aexample%3Apushed"));
+    const body = mock.lastRequest().?.body.?;
+    try std.testing.expect(std.mem.startsWith(u8, body, "client_id=test-client&request=ey"));
+    try std.testing.expect(std.mem.indexOf(u8, body, "state=") == null);
+}
+
+test "OAuthClient.requestAdditionalScopes: asks for the union and merges the grant" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.google("test-client", "openid"), storage.storage());
+    defer client.deinit();
+
+    var flow = try client.startAuthorizationWithOptions("http://127.0.0.1/callback", .{
+        .scope = "openid email calendar",
+        .include_granted_scopes = true,
+    });
+    defer flow.deinit();
+    try std.testing.expect(std.mem.indexOf(u8, flow.url, "&scope=openid%20email%20calendar") != null);
+    try std.testing.expect(std.mem.indexOf(u8, flow.url, "&include_granted_scopes=true") != null);
+
+    var existing = try Token.initFull(allocator, "at-1", "Bearer", "rt-1", 3600, "openid email", null);
+    defer existing.deinit();
+
+    // The server only reports the new scope and issues no refresh token
+    var token = try Token.initFull(allocator, "at-2", "Bearer", null, 3600, "calendar", null);
+    defer token.deinit();
+    try client.recordAdditionalScopes("google", &existing, &token, "openid email calendar");
+
+    var stored = (try client.getToken("google")).?;
+    defer stored.deinit();
+    try std.testing.expectEqualStrings("openid email calendar", stored.scope.?);
+    try std.testing.expectEqualStrings("rt-1", stored.refresh_token.?);
+    try std.testing.expectEqualStrings("at-2", stored.access_token);
+}
+
+test "OAuthConfig.audience: other grants and the refresher key by audience" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    // The response names an audience of its own; the requested one wins
+    try mock.enqueue(.{ .body = "{\"access_token\":\"billing-token\",\"token_type\":\"Bearer\",\"audience\":\"other\"}" });
+
+    var config = OAuthConfig.github("svc", "read");
+    config.client_secret = "s3cret";
+    config.audience = "https://api.example.com";
+
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var billing = try client.clientCredentialsWithAudience("svc", null, "https://billing.example.com");
+    defer billing.deinit();
+    try std.testing.expectEqualStrings("https://billing.example.com", billing.audience.?);
+
+    const default_key = try client.audienceStorageKey("svc", "https://api.example.com");
+    defer allocator.free(default_key);
+    try std.testing.expectEqualStrings("svc", default_key);
+
+    // A device grant token for another audience does not replace svc
+    var device = try Token.init(allocator, "device-token", "Bearer");
+    defer device.deinit();
+    try replaceAudience(&device, "https://reports.example.com");
+    try client.saveTokenForAudience("svc", device);
+    try std.testing.expect((try client.getToken("svc")) == null);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+    var cached = try refresher.getValidTokenForAudience("svc", "https://billing.example.com");
+    defer cached.deinit();
+    try std.testing.expectEqualStrings("billing-token", cached.access_token);
+    var reports = try refresher.getValidTokenForAudience("svc", "https://reports.example.com");
+    defer reports.deinit();
+    try std.testing.expectEqualStrings("device-token", reports.access_token);
+}
+
+test "OAuthClient.withFieldMapping: parses remapped token response fields" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"tok\":\"abc\",\"rtok\":\"def\",\"ttl\":900,\"token_type\":\"Bearer\"}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    try client.withFieldMapping(&.{
+        .{ .canonical = "access_token", .provider = "tok" },
+        .{ .canonical = "refresh_token", .provider = "rtok" },
+        .{ .canonical = "expires_in", .provider = "ttl" },
+    });
+
+    var token = try client.refreshToken("old-refresh");
+    defer token.deinit();
+
+    try std.testing.expectEqualStrings("abc", token.access_token);
+    try std.testing.expectEqualStrings("def", token.refresh_token.?);
+    try std.testing.expectEqualStrings("Bearer", token.token_type);
+    try std.testing.expectEqual(@as(?u64, 900), token.expires_in);
+    try std.testing.expect(token.expires_at != null);
+
+    try std.testing.expectError(
+        error.InvalidParameter,
+        client.withFieldMapping(&.{.{ .canonical = "tok", .provider = "access_token" }}),
+    );
+}
+
+test "OAuthClient.exchangeCodeDetailed: surfaces status and response headers" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    const headers = [_]transport.Header{
+        .{ .name = "X-RateLimit-Remaining", .value = "4999" },
+        .{ .name = "X-Request-Id", .value = "req-123" },
+        .{ .name = "Set-Cookie", .value = "session=secret" },
+    };
+    try mock.enqueue(.{
+        .headers = &headers,
+        .body = "{\"access_token\":\"abc\",\"token_type\":\"Bearer\"}",
+    });
+    try mock.enqueue(.{
+        .headers = &headers,
+        .body = "{\"access_token\":\"abc\",\"token_type\":\"Bearer\"}",
+    });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var detailed = try client.exchangeCodeDetailed("code", "verifier", "http://127.0.0.1/callback", .{});
+    defer detailed.deinit();
+
+    try std.testing.expectEqualStrings("abc", detailed.token.access_token);
+    try std.testing.expectEqual(@as(u16, 200), detailed.meta.status);
+    try std.testing.expectEqualStrings("4999", detailed.meta.header("x-ratelimit-remaining").?);
+    try std.testing.expectEqualStrings("req-123", detailed.meta.header("X-Request-Id").?);
+    try std.testing.expect(detailed.meta.header("Set-Cookie") == null);
+
+    // Cookies only when asked for
+    var with_cookies = try client.exchangeCodeDetailed("code", "verifier", "http://127.0.0.1/callback", .{ .include_cookies = true });
+    defer with_cookies.deinit();
+    try std.testing.expectEqualStrings("session=secret", with_cookies.meta.header("set-cookie").?);
+}
+
+test "OAuthClient.pollDeviceToken: waits out pending responses and saves the token" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{
+        .body = "{\"device_code\":\"dev-123\",\"user_code\":\"ABCD-EFGH\",\"verification_uri\":\"https://github.com/login/device\",\"expires_in\":900,\"interval\":5}",
+    });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"authorization_pending\"}" });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"authorization_pending\"}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"device-token\",\"token_type\":\"Bearer\"}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+    client.min_poll_interval = 0;
+
+    var device = try client.requestDeviceCode();
+    defer device.deinit();
+    try std.testing.expectEqualStrings("ABCD-EFGH", device.user_code);
+    device.interval = 0;
+
+    var token = try client.pollDeviceToken("github", &device);
+    defer token.deinit();
+    try std.testing.expectEqualStrings("device-token", token.access_token);
+    try std.testing.expectEqual(@as(usize, 4), mock.requestCount());
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "device_code=dev-123") != null);
+
+    var saved = (try client.getToken("github")).?;
+    defer saved.deinit();
+    try std.testing.expectEqualStrings("device-token", saved.access_token);
+}
+
+test "OAuthClient.pollDeviceCode: terminal errors are distinct" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"access_denied\"}" });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"expired_token\"}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+    client.min_poll_interval = 0;
+
+    try std.testing.expectError(error.AuthorizationDenied, client.pollDeviceCode("dev-123", 0, 900));
+    try std.testing.expectError(error.DeviceCodeExpired, client.pollDeviceCode("dev-123", 0, 900));
+}
+
+test "OAuthClient.requestDeviceCode: requires a device authorization endpoint" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var config = OAuthConfig.github("test-client", null);
+    config.device_authorization_endpoint = null;
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+
+    try std.testing.expectError(error.UnsupportedOperation, client.requestDeviceCode());
+}
+
+/// Build an unsigned ID token carrying `nonce`
+fn testIdToken(allocator: Allocator, nonce: []const u8) ![]u8 {
+    const claims = try std.fmt.allocPrint(allocator, "{{\"sub\":\"user\",\"nonce\":\"{s}\"}}", .{nonce});
+    defer allocator.free(claims);
+    const payload = try jwt.encodeSegment(allocator, claims);
+    defer allocator.free(payload);
+    return std.fmt.allocPrint(allocator, "eyJhbGciOiJSUzI1NiJ9.{s}.c2ln", .{payload});
+}
+
+test "OAuthClient.verifyIdTokenNonce: state-bound nonce verifies and detects tampered state" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.google("test-client", "openid email"), storage.storage());
+    defer client.deinit();
+    client.nonce_mode = .{ .state_bound = "per-client-secret" };
+
+    var flow = try client.startAuthorization("http://127.0.0.1:8080/callback");
+    defer flow.deinit();
+
+    const expected = deriveNonce("per-client-secret", flow.getState());
+    try std.testing.expectEqualStrings(&expected, flow.getNonce());
+
+    // The nonce travels in the authorization URL
+    var request = try flow.parsedUrl(allocator);
+    defer request.deinit();
+    try std.testing.expectEqualStrings(flow.getNonce(), request.get("nonce").?);
+
+    const id_token = try testIdToken(allocator, flow.getNonce());
+    defer allocator.free(id_token);
+    try client.verifyIdTokenNonce(&flow, id_token);
+
+    // A different state no longer matches the nonce
+    flow.state[0] = if (flow.state[0] == 'A') 'B' else 'A';
+    try std.testing.expectError(error.InvalidNonce, client.verifyIdTokenNonce(&flow, id_token));
+}
+
+test "OAuthClient.verifyIdTokenNonce: random nonce is the default" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.google("test-client", "openid"), storage.storage());
+    defer client.deinit();
+
+    var flow = try client.startAuthorization("http://127.0.0.1:8080/callback");
+    defer flow.deinit();
+
+    const id_token = try testIdToken(allocator, flow.getNonce());
+    defer allocator.free(id_token);
+    try client.verifyIdTokenNonce(&flow, id_token);
+
+    const forged = try testIdToken(allocator, "forged-nonce");
+    defer allocator.free(forged);
+    try std.testing.expectError(error.InvalidNonce, client.verifyIdTokenNonce(&flow, forged));
+}
+
+const TestKeyPair = std.crypto.sign.ecdsa.EcdsaP256Sha256.KeyPair;
+
+/// JWKS document publishing `key_pair`
+fn testJwksDocument(allocator: Allocator, key_pair: TestKeyPair) ![]u8 {
+    const point = key_pair.public_key.toUncompressedSec1();
+    const x = try jwt.encodeSegment(allocator, point[1..33]);
+    defer allocator.free(x);
+    const y = try jwt.encodeSegment(allocator, point[33..65]);
+    defer allocator.free(y);
+    return std.fmt.allocPrint(allocator, "{{\"keys\":[{{\"kty\":\"EC\",\"crv\":\"P-256\",\"x\":\"{s}\",\"y\":\"{s}\"}}]}}", .{ x, y });
+}
+
+/// `claims_json` as an ES256 JWT signed by `key_pair`
+fn testSignedJwt(allocator: Allocator, key_pair: TestKeyPair, claims_json: []const u8) ![]u8 {
+    const header = try jwt.encodeSegment(allocator, "{\"alg\":\"ES256\"}");
+    defer allocator.free(header);
+    const payload = try jwt.encodeSegment(allocator, claims_json);
+    defer allocator.free(payload);
+    const signing_input = try std.fmt.allocPrint(allocator, "{s}.{s}", .{ header, payload });
+    defer allocator.free(signing_input);
+    const signature = (try key_pair.sign(signing_input, null)).toBytes();
+    const encoded_signature = try jwt.encodeSegment(allocator, &signature);
+    defer allocator.free(encoded_signature);
+    return std.fmt.allocPrint(allocator, "{s}.{s}", .{ signing_input, encoded_signature });
+}
+
+/// Token response carrying `claims_json` as an ES256 ID token signed by `key_pair`
+fn testIdTokenResponse(allocator: Allocator, key_pair: TestKeyPair, claims_json: []const u8) ![]u8 {
+    const id_token = try testSignedJwt(allocator, key_pair, claims_json);
+    defer allocator.free(id_token);
+    return std.fmt.allocPrint(allocator, "{{\"access_token\":\"at\",\"token_type\":\"Bearer\",\"id_token\":\"{s}\"}}", .{id_token});
+}
+
+test "OAuthClient.exchangeCode: validates the ID token of an OIDC provider" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.google("test-client", "openid"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    const key_pair = TestKeyPair.generate();
+    const jwks_document = try testJwksDocument(allocator, key_pair);
+    defer allocator.free(jwks_document);
+
+    const valid = try testIdTokenResponse(allocator, key_pair,
+        \\{"iss":"https://accounts.google.com","sub":"user","aud":"test-client","exp":1700000600,"iat":1700000000}
+    );
+    defer allocator.free(valid);
+    try mock.enqueue(.{ .body = valid });
+    try mock.enqueue(.{ .body = jwks_document });
+
+    var token = try client.exchangeCode("code", "verifier", "http://127.0.0.1/callback");
+    defer token.deinit();
+    try std.testing.expect(token.id_token != null);
+    try std.testing.expectEqualStrings("https://www.googleapis.com/oauth2/v3/certs", mock.lastRequest().?.url);
+
+    // A token minted for another client is rejected
+    const foreign = try testIdTokenResponse(allocator, key_pair,
+        \\{"iss":"https://accounts.google.com","sub":"user","aud":"other-client","exp":1700000600,"iat":1700000000}
+    );
+    defer allocator.free(foreign);
+    try mock.enqueue(.{ .body = foreign });
+    try mock.enqueue(.{ .body = jwks_document });
+
+    try std.testing.expectError(error.InvalidIdToken, client.exchangeCode("code", "verifier", "http://127.0.0.1/callback"));
+}
+
+test "OAuthClient.unwrapJarmResponse: verifies the signed authorization response" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var config = OAuthConfig.google("test-client", "openid");
+    config.response_mode = .query_jwt;
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var flow = try client.startAuthorization("http://127.0.0.1/callback");
+    defer flow.deinit();
+    try std.testing.expect(std.mem.indexOf(u8, flow.url, "&response_mode=query.jwt") != null);
+
+    const key_pair = TestKeyPair.generate();
+    const jwks_document = try testJwksDocument(allocator, key_pair);
+    defer allocator.free(jwks_document);
+    const response = try testSignedJwt(allocator, key_pair,
+        \\{"iss":"https://accounts.google.com","aud":"test-client","exp":1700000600,"code":"signed-code","state":"signed-state"}
+    );
+    defer allocator.free(response);
+
+    var result = CallbackResult.init(allocator);
+    defer result.deinit();
+    result.code = try allocator.dupe(u8, "unsigned-code");
+    result.response = try allocator.dupe(u8, response);
+
+    try mock.enqueue(.{ .body = jwks_document });
+    try client.unwrapJarmResponse(&result);
+    try std.testing.expectEqualStrings("signed-code", result.code.?);
+    try std.testing.expectEqualStrings("signed-state", result.state.?);
+
+    // A tampered signature fails before any claim is trusted
+    var tampered = CallbackResult.init(allocator);
+    defer tampered.deinit();
+    const forged = try allocator.dupe(u8, response);
+    forged[forged.len - 10] = if (forged[forged.len - 10] == 'A') 'B' else 'A';
+    tampered.response = forged;
+    try mock.enqueue(.{ .body = jwks_document });
+    try std.testing.expectError(error.InvalidSignature, client.unwrapJarmResponse(&tampered));
+
+    // Plain responses are refused once a JWT mode is configured
+    var plain = CallbackResult.init(allocator);
+    defer plain.deinit();
+    plain.code = try allocator.dupe(u8, "code");
+    try std.testing.expectError(error.InvalidAuthorizationResponse, client.unwrapJarmResponse(&plain));
+}
+
+test "OAuthClient.handleBackchannelLogout: removes the tokens of the ended session" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.google("test-client", "openid"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
 
-        // Preserve refresh token if not included in response
-        if (new_token.refresh_token == null and token.refresh_token != null) {
-            new_token.refresh_token = try self.allocator.dupe(u8, token.refresh_token.?);
+    const sessions = [_][2][]const u8{
+        .{ "laptop", "{\"iss\":\"https://accounts.google.com\",\"sub\":\"user-1\",\"sid\":\"s1\",\"aud\":\"test-client\",\"exp\":1700003600,\"iat\":1700000000}" },
+        .{ "desktop", "{\"iss\":\"https://accounts.google.com\",\"sub\":\"user-1\",\"sid\":\"s2\",\"aud\":\"test-client\",\"exp\":1700003600,\"iat\":1700000000}" },
+    };
+    for (sessions) |entry| {
+        var token = try Token.init(allocator, "at", "Bearer");
+        defer token.deinit();
+        const claims = try jwt.encodeSegment(allocator, entry[1]);
+        defer allocator.free(claims);
+        token.id_token = try std.fmt.allocPrint(allocator, "eyJhbGciOiJSUzI1NiJ9.{s}.c2ln", .{claims});
+        try client.saveToken(entry[0], token);
+    }
+
+    const key_pair = TestKeyPair.generate();
+    const jwks_document = try testJwksDocument(allocator, key_pair);
+    defer allocator.free(jwks_document);
+    const logout_token = try testSignedJwt(allocator, key_pair,
+        \\{"iss":"https://accounts.google.com","aud":"test-client","iat":1700000000,"jti":"l1","sid":"s1",
+        \\ "events":{"http://schemas.openid.net/event/backchannel-logout":{}}}
+    );
+    defer allocator.free(logout_token);
+
+    try mock.enqueue(.{ .body = jwks_document });
+    try std.testing.expectEqual(@as(usize, 1), try client.handleBackchannelLogout(logout_token));
+    try std.testing.expect(!storage.storage().exists("laptop"));
+    try std.testing.expect(storage.storage().exists("desktop"));
+
+    // Tokens from another signer are ignored
+    const forged = try testSignedJwt(allocator, TestKeyPair.generate(),
+        \\{"iss":"https://accounts.google.com","aud":"test-client","iat":1700000000,"jti":"l2","sub":"user-1",
+        \\ "events":{"http://schemas.openid.net/event/backchannel-logout":{}}}
+    );
+    defer allocator.free(forged);
+    try mock.enqueue(.{ .body = jwks_document });
+    try std.testing.expectError(error.InvalidSignature, client.handleBackchannelLogout(forged));
+    try std.testing.expect(storage.storage().exists("desktop"));
+}
+
+test "OAuthClient.withJwksCache: one key download for many ID tokens" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    const config = OAuthConfig.google("test-client", "openid");
+    var cache = jwks.JwksCache.init(allocator, mock.transport(), config.jwks_uri.?);
+    defer cache.deinit();
+
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+    client.withJwksCache(&cache);
+
+    const key_pair = TestKeyPair.generate();
+    const jwks_document = try testJwksDocument(allocator, key_pair);
+    defer allocator.free(jwks_document);
+    const response = try testIdTokenResponse(allocator, key_pair,
+        \\{"iss":"https://accounts.google.com","sub":"user","aud":"test-client","exp":1700000600,"iat":1700000000}
+    );
+    defer allocator.free(response);
+
+    try mock.enqueue(.{ .body = response });
+    try mock.enqueue(.{ .body = jwks_document });
+    try mock.enqueue(.{ .body = response });
+
+    var first = try client.exchangeCode("code", "verifier", "http://127.0.0.1/callback");
+    defer first.deinit();
+    var second = try client.exchangeCode("code", "verifier", "http://127.0.0.1/callback");
+    defer second.deinit();
+
+    try std.testing.expectEqual(@as(usize, 3), mock.requestCount());
+}
+
+test "TokenRefresher.tokensExpiringWithin: returns keys in the window, soonest first" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+
+    const Entry = struct { key: []const u8, expires_in: ?u64 };
+    const entries = [_]Entry{
+        .{ .key = "in-50-min", .expires_in = 3000 },
+        .{ .key = "in-2-hours", .expires_in = 7200 },
+        .{ .key = "in-5-min", .expires_in = 300 },
+        .{ .key = "no-expiry", .expires_in = null },
+    };
+    for (entries) |entry| {
+        var token = try Token.initFull(allocator, "access", "Bearer", null, entry.expires_in, null, null);
+        defer token.deinit();
+        try client.saveToken(entry.key, token);
+    }
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+
+    const expiring = try refresher.tokensExpiringWithin(allocator, 3600);
+    defer TokenRefresher.freeExpiringTokens(allocator, expiring);
+
+    try std.testing.expectEqual(@as(usize, 2), expiring.len);
+    try std.testing.expectEqualStrings("in-5-min", expiring[0].key);
+    try std.testing.expectEqual(@as(u64, 1_700_000_300), expiring[0].expires_at);
+    try std.testing.expectEqualStrings("in-50-min", expiring[1].key);
+    try std.testing.expectEqual(@as(u64, 1_700_003_000), expiring[1].expires_at);
+}
+
+test "OAuthClient.withRequestAudit: refresh records grant type with masked secrets" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"fresh\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "repo read:user"), storage.storage());
+    defer client.deinit();
+    client.config.client_secret = "super-secret";
+    client.http_transport = mock.transport();
+    client.withRequestAudit();
+
+    var token = try Token.initFull(allocator, "expired", "Bearer", "refresh-secret", 3600, null, null);
+    defer token.deinit();
+    token.expires_at = 1_699_999_000;
+    try client.saveToken("svc", token);
+    try std.testing.expect((try client.lastTokenRequestAudit("svc")) == null);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+
+    var refreshed = try refresher.getValidToken("svc");
+    defer refreshed.deinit();
+    try std.testing.expectEqualStrings("fresh", refreshed.access_token);
+
+    var audit = (try client.lastTokenRequestAudit("svc")).?;
+    defer audit.deinit();
+
+    try std.testing.expectEqualStrings("refresh_token", audit.grant_type);
+    try std.testing.expectEqualStrings(client.config.token_endpoint, audit.endpoint);
+    try std.testing.expectEqual(@as(u64, 1_700_000_000), audit.timestamp);
+    try std.testing.expectEqual(@as(u16, 200), audit.status);
+    try std.testing.expect(std.mem.indexOf(u8, audit.params, "refresh_token=***") != null);
+    try std.testing.expect(std.mem.indexOf(u8, audit.params, "client_secret=***") != null);
+    try std.testing.expect(std.mem.indexOf(u8, audit.params, "client_id=test-client") != null);
+    try std.testing.expect(std.mem.indexOf(u8, audit.params, "refresh-secret") == null);
+    try std.testing.expect(std.mem.indexOf(u8, audit.params, "super-secret") == null);
+}
+
+test "TokenRefresher.withRefreshScope: controls the scope sent on refresh" {
+    const allocator = std.testing.allocator;
+
+    const Case = struct {
+        behavior: TokenRefresher.RefreshScopeBehavior,
+        expected_scope: ?[]const u8,
+    };
+    const cases = [_]Case{
+        .{ .behavior = .omit, .expected_scope = null },
+        .{ .behavior = .echo_original, .expected_scope = "scope=repo%20read%3Auser" },
+        .{ .behavior = .{ .custom = "read:user" }, .expected_scope = "scope=read%3Auser" },
+    };
+
+    for (cases) |case| {
+        var storage = session.MemoryStorage.init(allocator);
+        defer storage.deinit();
+
+        var mock = transport.MockTransport.init(allocator);
+        defer mock.deinit();
+        try mock.enqueue(.{ .body = "{\"access_token\":\"fresh\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+        var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+        defer client.deinit();
+        client.http_transport = mock.transport();
+
+        var token = try Token.initFull(allocator, "expired", "Bearer", "refresh-1", 3600, "repo read:user", null);
+        defer token.deinit();
+        token.expires_at = @as(u64, @intCast(std.time.timestamp())) - 120;
+        try client.saveToken("svc", token);
+
+        var refresher = TokenRefresher.init(allocator, &client);
+        defer refresher.deinit();
+        refresher.withRefreshScope(case.behavior);
+
+        var refreshed = try refresher.getValidToken("svc");
+        defer refreshed.deinit();
+
+        const body = mock.lastRequest().?.body.?;
+        if (case.expected_scope) |expected| {
+            try std.testing.expect(std.mem.indexOf(u8, body, expected) != null);
+        } else {
+            try std.testing.expect(std.mem.indexOf(u8, body, "scope=") == null);
         }
+    }
+}
 
-        // Save new token
-        try self.client.saveToken(key, new_token);
+test "TokenRefresher.withRefreshScope: keeps the scope across consecutive refreshes" {
+    const allocator = std.testing.allocator;
 
-        token.deinit();
-        return new_token;
+    const Case = struct {
+        behavior: TokenRefresher.RefreshScopeBehavior,
+        expected_scope: []const u8,
+        stored_scope: []const u8,
+    };
+    const cases = [_]Case{
+        .{ .behavior = .echo_original, .expected_scope = "scope=repo%20read%3Auser", .stored_scope = "repo read:user" },
+        .{ .behavior = .{ .custom = "read:user" }, .expected_scope = "scope=read%3Auser", .stored_scope = "read:user" },
+    };
+
+    for (cases) |case| {
+        var storage = session.MemoryStorage.init(allocator);
+        defer storage.deinit();
+
+        // Neither response repeats the scope
+        var mock = transport.MockTransport.init(allocator);
+        defer mock.deinit();
+        try mock.enqueue(.{ .body = "{\"access_token\":\"fresh-1\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+        try mock.enqueue(.{ .body = "{\"access_token\":\"fresh-2\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+        var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+        defer client.deinit();
+        client.http_transport = mock.transport();
+
+        const start = clock.now();
+        var token = try Token.initFull(allocator, "expired", "Bearer", "refresh-1", 3600, "repo read:user", null);
+        defer token.deinit();
+        token.expires_at = start - 120;
+        try client.saveToken("svc", token);
+
+        var refresher = TokenRefresher.init(allocator, &client);
+        defer refresher.deinit();
+        refresher.withRefreshScope(case.behavior);
+
+        var first = try refresher.getValidToken("svc");
+        defer first.deinit();
+        try std.testing.expectEqualStrings(case.stored_scope, first.scope.?);
+
+        // Once the refreshed token expires, the next refresh sends the same scope
+        clock.setMockTime(start + 7200);
+        defer clock.clearMockTime();
+
+        var second = try refresher.getValidToken("svc");
+        defer second.deinit();
+        try std.testing.expectEqualStrings("fresh-2", second.access_token);
+        try std.testing.expectEqualStrings(case.stored_scope, second.scope.?);
+        try std.testing.expectEqual(@as(usize, 2), mock.requestCount());
+        try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, case.expected_scope) != null);
     }
-};
+}
 
-/// Re-export appendUrlEncoded from callback module to avoid duplication
-const appendUrlEncoded = callback.appendUrlEncoded;
+test "TokenRefresher.withRefreshScope: rejects a custom scope outside the grant" {
+    const allocator = std.testing.allocator;
 
-test "OAuthConfig GitHub preset" {
-    const config = OAuthConfig.github("test-client-id", "repo user");
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
 
-    try std.testing.expectEqualStrings("test-client-id", config.client_id);
-    try std.testing.expectEqualStrings("https://github.com/login/oauth/authorize", config.authorization_endpoint);
-    try std.testing.expectEqualStrings("https://github.com/login/oauth/access_token", config.token_endpoint);
-    try std.testing.expect(config.device_authorization_endpoint != null);
-    try std.testing.expectEqualStrings("repo user", config.scope.?);
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var token = try Token.initFull(allocator, "expired", "Bearer", "refresh-1", 3600, "read:user", null);
+    defer token.deinit();
+    token.expires_at = @as(u64, @intCast(std.time.timestamp())) - 120;
+    try client.saveToken("svc", token);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+    refresher.withRefreshScope(.{ .custom = "read:user admin:org" });
+
+    try std.testing.expectError(error.InvalidParameter, refresher.getValidToken("svc"));
+    try std.testing.expectEqual(@as(usize, 0), mock.requestCount());
 }
 
-test "OAuthConfig Google preset" {
-    const config = OAuthConfig.google("google-client-id", "openid email");
+test "OAuthClient.clockDriftSecs: measures drift against the server Date header" {
+    const allocator = std.testing.allocator;
 
-    try std.testing.expectEqualStrings("google-client-id", config.client_id);
-    try std.testing.expectEqualStrings("https://accounts.google.com/o/oauth2/v2/auth", config.authorization_endpoint);
-    try std.testing.expect(config.device_authorization_endpoint != null);
+    // Server says 20:13:20, local clock is five minutes behind
+    clock.setMockTime(1_699_992_800 - 300);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    const headers = [_]transport.Header{.{ .name = "Date", .value = "Tue, 14 Nov 2023 20:13:20 GMT" }};
+    try mock.enqueue(.{
+        .headers = &headers,
+        .body = "{\"access_token\":\"abc\",\"token_type\":\"Bearer\",\"expires_in\":3600}",
+    });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    try std.testing.expect(client.clockDriftSecs() == null);
+
+    var token = try client.exchangeCode("code", "verifier", "http://127.0.0.1/callback");
+    defer token.deinit();
+    try std.testing.expectEqual(@as(?i64, 300), client.clockDriftSecs());
+
+    // Drift is diagnostic only: without withServerClock() expiry stays local
+    try std.testing.expectEqual(@as(i64, 0), token.clock_offset);
+
+    // The warning fires above the threshold only
+    var header_copy = headers;
+    const response = HttpResponse{ .allocator = allocator, .status = 200, .headers = &header_copy, .body = "" };
+    try std.testing.expect(client.measureClockDrift(&response));
+    client.clock_drift_threshold = 600;
+    try std.testing.expect(!client.measureClockDrift(&response));
+    try std.testing.expectEqual(@as(?i64, 300), client.clockDriftSecs());
 }
 
-test "OAuthConfig Microsoft preset" {
-    const config = OAuthConfig.microsoft("ms-client-id", "common", "user.read");
+test "TokenRefresher.withAdaptiveRefresh: refreshes ahead of the predicted next access" {
+    const allocator = std.testing.allocator;
 
-    try std.testing.expectEqualStrings("ms-client-id", config.client_id);
-    try std.testing.expect(std.mem.indexOf(u8, config.authorization_endpoint, "microsoftonline.com") != null);
+    const start: u64 = 1_700_000_000;
+    clock.setMockTime(start);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"fresh\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    // Expires at start + 3600; the fixed 10% threshold would refresh from start + 3240
+    var token = try Token.initFull(allocator, "original", "Bearer", "refresh-1", 3600, null, null);
+    defer token.deinit();
+    try client.saveToken("svc", token);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+    refresher.withAdaptiveRefresh();
+
+    // Read every ten minutes; the next read would land past the threshold
+    var at = start;
+    while (at <= start + 2400) : (at += 600) {
+        clock.setMockTime(at);
+        var current = try refresher.getValidToken("svc");
+        defer current.deinit();
+        try std.testing.expectEqualStrings("original", current.access_token);
+    }
+    try std.testing.expectEqual(@as(usize, 0), mock.requestCount());
+
+    clock.setMockTime(start + 3000);
+    var refreshed = try refresher.getValidToken("svc");
+    defer refreshed.deinit();
+
+    // Refreshed now, before the read predicted at start + 3600
+    try std.testing.expectEqual(@as(?u64, start + 3600), refresher.predictedNextAccess("svc"));
+    try std.testing.expectEqual(@as(usize, 1), mock.requestCount());
+    try std.testing.expectEqualStrings("fresh", refreshed.access_token);
 }
 
-test "OAuthConfig Tuist preset" {
-    const config = OAuthConfig.tuist("tuist-client-id", "project:read");
+test "TokenRefresher.withAdaptiveRefresh: concurrent readers share one refresh ahead" {
+    if (@import("builtin").single_threaded) return error.SkipZigTest;
 
-    try std.testing.expectEqualStrings("tuist-client-id", config.client_id);
-    try std.testing.expect(std.mem.indexOf(u8, config.authorization_endpoint, "tuist.io") != null);
+    const allocator = std.testing.allocator;
+
+    const start: u64 = 1_700_000_000;
+    clock.setMockTime(start);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    // A second refresh would take the second response
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"fresh\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"again\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var token = try Token.initFull(allocator, "original", "Bearer", "refresh-1", 3600, null, null);
+    defer token.deinit();
+    try client.saveToken("svc", token);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+    refresher.withAdaptiveRefresh();
+
+    // Learn a ten minute access interval
+    var at = start;
+    while (at <= start + 2400) : (at += 600) {
+        clock.setMockTime(at);
+        var current = try refresher.getValidToken("svc");
+        current.deinit();
+    }
+
+    // At start + 3000 the next read lands past the threshold, so both
+    // readers want to refresh ahead; the second waits for the first
+    clock.setMockTime(start + 3000);
+    mock.block();
+
+    const Reader = struct {
+        token: ?Token = null,
+        err: ?anyerror = null,
+
+        fn run(self: *@This(), r: *TokenRefresher) void {
+            if (r.getValidToken("svc")) |t| {
+                self.token = t;
+            } else |e| {
+                self.err = e;
+            }
+        }
+    };
+
+    var first: Reader = .{};
+    const first_thread = try std.Thread.spawn(.{}, Reader.run, .{ &first, &refresher });
+    var waited_ms: u32 = 0;
+    while (mock.requestCount() == 0 and waited_ms < 2000) : (waited_ms += 1) {
+        std.Thread.sleep(std.time.ns_per_ms);
+    }
+
+    var second: Reader = .{};
+    const second_thread = try std.Thread.spawn(.{}, Reader.run, .{ &second, &refresher });
+    waited_ms = 0;
+    while (refresher.waiterCount() == 0 and waited_ms < 2000) : (waited_ms += 1) {
+        std.Thread.sleep(std.time.ns_per_ms);
+    }
+    try std.testing.expectEqual(@as(usize, 1), refresher.waiterCount());
+
+    mock.unblock();
+    first_thread.join();
+    second_thread.join();
+
+    var first_token = first.token.?;
+    defer first_token.deinit();
+    var second_token = second.token.?;
+    defer second_token.deinit();
+    try std.testing.expectEqualStrings("fresh", first_token.access_token);
+    try std.testing.expectEqualStrings("fresh", second_token.access_token);
+    try std.testing.expectEqual(@as(usize, 1), mock.requestCount());
 }
 
-test "OAuthClient initialization" {
+test "OAuthClient.withEventSink: emits lifecycle events without secrets" {
     const allocator = std.testing.allocator;
 
+    const start: u64 = 1_700_000_000;
+    clock.setMockTime(start);
+    defer clock.clearMockTime();
+
     var storage = session.MemoryStorage.init(allocator);
     defer storage.deinit();
 
-    const config = OAuthConfig.github("test-client", null);
-    var client = OAuthClient.init(allocator, config, storage.storage());
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"access-secret\",\"token_type\":\"Bearer\",\"refresh_token\":\"refresh-secret\",\"expires_in\":3600}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"access-rotated\",\"token_type\":\"Bearer\",\"refresh_token\":\"refresh-rotated\",\"expires_in\":3600}" });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"invalid_grant\"}" });
+    try mock.enqueue(.{ .body = "" });
+
+    var out: std.Io.Writer.Allocating = .init(allocator);
+    defer out.deinit();
+    var lines = events.JsonLinesSink.init(&out.writer);
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", "repo"), storage.storage());
     defer client.deinit();
+    client.config.client_secret = "client-secret";
+    client.config.revocation_endpoint = "https://github.com/revoke";
+    client.http_transport = mock.transport();
+    client.withEventSink(lines.sink());
 
-    try std.testing.expectEqualStrings("test-client", client.config.client_id);
+    var flow = try client.startAuthorization("http://127.0.0.1:8080/callback");
+    defer flow.deinit();
+
+    var token = try client.exchangeCode("code-secret", "verifier-secret", flow.redirect_uri);
+    defer token.deinit();
+    try client.saveToken("svc", token);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+
+    clock.setMockTime(start + 4000);
+    var refreshed = try refresher.getValidToken("svc");
+    defer refreshed.deinit();
+
+    clock.setMockTime(start + 8000);
+    try std.testing.expectError(error.InvalidGrant, refresher.getValidToken("svc"));
+
+    // Deleting the local copy is not a revocation
+    try client.saveToken("local", token);
+    try client.deleteToken("local");
+    try client.revokeRefreshToken("svc");
+
+    const expected_events = [_][]const u8{ "flow_started", "code_exchanged", "token_refreshed", "refresh_failed", "token_revoked" };
+    var it = std.mem.splitScalar(u8, std.mem.trimRight(u8, out.written(), "\n"), '\n');
+    var count: usize = 0;
+    while (it.next()) |line| : (count += 1) {
+        const parsed = try json.parseFromSlice(json.Value, allocator, line, .{});
+        defer parsed.deinit();
+
+        const obj = parsed.value.object;
+        try std.testing.expectEqualStrings(expected_events[count], obj.get("event").?.string);
+        try std.testing.expect(obj.get("timestamp").? == .integer);
+        if (count >= 2) try std.testing.expectEqualStrings("svc", obj.get("key").?.string);
+    }
+    try std.testing.expectEqual(expected_events.len, count);
+
+    const written = out.written();
+    try std.testing.expect(std.mem.indexOf(u8, written, "\"refresh_token_rotated\":true") != null);
+    try std.testing.expect(std.mem.indexOf(u8, written, "\"error\":\"InvalidGrant\"") != null);
+    for ([_][]const u8{ "access-secret", "refresh-secret", "access-rotated", "refresh-rotated", "code-secret", "verifier-secret", "client-secret", flow.getState() }) |secret| {
+        try std.testing.expect(std.mem.indexOf(u8, written, secret) == null);
+    }
 }
 
-test "OAuthClient token storage" {
+test "OAuthClient.startDeviceFlow: poll handles pending, slow_down and approval" {
     const allocator = std.testing.allocator;
 
+    const start: u64 = 1_700_000_000;
+    clock.setMockTime(start);
+    defer clock.clearMockTime();
+
     var storage = session.MemoryStorage.init(allocator);
     defer storage.deinit();
 
-    const config = OAuthConfig.github("test-client", null);
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{
+        .body = "{\"device_code\":\"dev-123\",\"user_code\":\"ABCD-EFGH\",\"verification_uri\":\"https://github.com/login/device\",\"expires_in\":600,\"interval\":5}",
+    });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"authorization_pending\"}" });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"slow_down\"}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"device-token\",\"token_type\":\"Bearer\"}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var flow = try client.startDeviceFlow();
+    defer flow.deinit();
+    try std.testing.expectEqualStrings("ABCD-EFGH", flow.device.user_code);
+    try std.testing.expectEqual(@as(u64, 5), flow.interval);
+    try std.testing.expectEqual(start + 600, flow.expires_at);
+
+    try std.testing.expect((try flow.poll()) == null);
+    try std.testing.expect((try flow.poll()) == null);
+    try std.testing.expectEqual(@as(u64, 10), flow.interval);
+
+    var token = (try flow.poll()).?;
+    defer token.deinit();
+    try std.testing.expectEqualStrings("device-token", token.access_token);
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "device_code=dev-123") != null);
+}
+
+test "DeviceFlow.poll: lapsed device code fails without a request" {
+    const allocator = std.testing.allocator;
+
+    const start: u64 = 1_700_000_000;
+    clock.setMockTime(start);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{
+        .body = "{\"device_code\":\"dev-123\",\"user_code\":\"ABCD-EFGH\",\"verification_uri\":\"https://github.com/login/device\",\"expires_in\":600}",
+    });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"expired_token\"}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var flow = try client.startDeviceFlow();
+    defer flow.deinit();
+
+    // The server reports expiry
+    try std.testing.expectError(error.DeviceCodeExpired, flow.poll());
+    try std.testing.expectEqual(@as(usize, 2), mock.requestCount());
+
+    // And so does the local clock once the lifetime has passed
+    clock.setMockTime(start + 600);
+    try std.testing.expectError(error.DeviceCodeExpired, flow.poll());
+    try std.testing.expectEqual(@as(usize, 2), mock.requestCount());
+}
+
+test "OAuthClient.clientCredentials: authenticates the client and saves the token" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"m2m-token\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("service-client", "read write"), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    // A public client has nothing to authenticate with
+    try std.testing.expectError(error.InvalidParameter, client.clientCredentials("svc", null));
+    try std.testing.expectEqual(@as(usize, 0), mock.requestCount());
+
+    client.config.client_secret = "service-secret";
+    var token = try client.clientCredentials("svc", "read");
+    defer token.deinit();
+    try std.testing.expectEqualStrings("m2m-token", token.access_token);
+
+    const body = mock.lastRequest().?.body.?;
+    try std.testing.expect(std.mem.startsWith(u8, body, "grant_type=client_credentials"));
+    try std.testing.expect(std.mem.indexOf(u8, body, "&scope=read&") != null);
+    try std.testing.expect(std.mem.indexOf(u8, body, "client_id=service-client") != null);
+    try std.testing.expect(std.mem.indexOf(u8, body, "client_secret=service-secret") != null);
+
+    var saved = (try client.getToken("svc")).?;
+    defer saved.deinit();
+    try std.testing.expectEqualStrings("m2m-token", saved.access_token);
+}
+
+test "OAuthClient.exchangeToken: sends RFC 8693 parameters" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"downstream\",\"token_type\":\"Bearer\",\"issued_token_type\":\"urn:ietf:params:oauth:token-type:access_token\",\"expires_in\":300}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    // An actor token needs its type
+    try std.testing.expectError(error.InvalidParameter, client.exchangeToken(.{
+        .subject_token = "cli-token",
+        .actor_token = "actor",
+    }));
+
+    var token = try client.exchangeToken(.{
+        .subject_token = "cli-token",
+        .actor_token = "actor-token",
+        .actor_token_type = token_type_jwt,
+        .audience = "https://api.example.com",
+    });
+    defer token.deinit();
+    try std.testing.expectEqualStrings("downstream", token.access_token);
+
+    const body = mock.lastRequest().?.body.?;
+    try std.testing.expect(std.mem.startsWith(u8, body, "grant_type=urn:ietf:params:oauth:grant-type:token-exchange"));
+    try std.testing.expect(std.mem.indexOf(u8, body, "subject_token=cli-token") != null);
+    try std.testing.expect(std.mem.indexOf(u8, body, "actor_token=actor-token") != null);
+    try std.testing.expect(std.mem.indexOf(u8, body, "audience=https%3A%2F%2Fapi.example.com") != null);
+    try std.testing.expect(std.mem.indexOf(u8, body, "requested_token_type") == null);
+}
+
+test "ComplianceMode.oauth21: enforces the OAuth 2.1 invariants" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var config = OAuthConfig.github("test-client", "repo");
+    config.compliance = .oauth21;
+    try config.validate();
+
+    var leaky = config;
+    leaky.userinfo_endpoint = "https://api.github.com/user?access_token=secret";
+    try std.testing.expectError(error.ConfigurationError, leaky.validate());
+    var wildcard = config;
+    wildcard.redirect_uri = "http://127.0.0.1/*";
+    try std.testing.expectError(error.ConfigurationError, wildcard.validate());
+
     var client = OAuthClient.init(allocator, config, storage.storage());
     defer client.deinit();
 
-    var token = try Token.init(allocator, "test_access_token", "Bearer");
+    // Loopback redirect URIs may pick any port, nothing else may differ
+    var flow = try client.startAuthorization("http://127.0.0.1:8123/callback");
+    flow.deinit();
+    try std.testing.expectError(error.UnregisteredRedirectUri, client.startAuthorization("http://127.0.0.1:8123/other"));
+    try std.testing.expectError(error.UnregisteredRedirectUri, client.startAuthorization("http://127.0.0.1.evil.com/callback"));
+    try std.testing.expectError(error.UnregisteredRedirectUri, client.startAuthorization("https://example.com/callback"));
+
+    try std.testing.expectError(error.UnsupportedOperation, client.passwordGrant("idp", "alice", "secret", null));
+
+    // The plain PKCE method is only for OAuth 2.0 servers without S256
+    client.config.pkce.method = .plain;
+    try std.testing.expectError(error.ConfigurationError, client.startAuthorization("http://127.0.0.1/callback"));
+    client.config.compliance = .standard;
+    var plain = try client.startAuthorization("http://127.0.0.1/callback");
+    defer plain.deinit();
+    try std.testing.expect(std.mem.indexOf(u8, plain.url, "&code_challenge_method=plain") != null);
+}
+
+test "withOfflineAccess: adds offline_access once" {
+    const allocator = std.testing.allocator;
+
+    const added = try withOfflineAccess(allocator, "openid email");
+    defer allocator.free(added);
+    try std.testing.expectEqualStrings("openid email offline_access", added);
+
+    const kept = try withOfflineAccess(allocator, "offline_access openid");
+    defer allocator.free(kept);
+    try std.testing.expectEqualStrings("offline_access openid", kept);
+
+    const only = try withOfflineAccess(allocator, null);
+    defer allocator.free(only);
+    try std.testing.expectEqualStrings("offline_access", only);
+}
+
+test "RefreshTokenOnlyStorage: the refresher mints access tokens from the persisted refresh token" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var disk = session.MemoryStorage.init(allocator);
+    defer disk.deinit();
+    var storage = session.RefreshTokenOnlyStorage.init(allocator, disk.storage());
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"minted\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    // What a previous process left behind
+    var issued = try Token.initFull(allocator, "old-access", "Bearer", "refresh-1", 3600, null, null);
+    defer issued.deinit();
+    var persisted = try session.RefreshTokenOnlyStorage.persistedCopy(allocator, &issued);
+    defer persisted.deinit();
+    try disk.storage().save("user", persisted);
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+
+    var token = try refresher.getValidToken("user");
+    defer token.deinit();
+    try std.testing.expectEqualStrings("minted", token.access_token);
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "refresh_token=refresh-1") != null);
+
+    // The minted access token stays in memory
+    var on_disk = (try disk.storage().load(allocator, "user")).?;
+    defer on_disk.deinit();
+    try std.testing.expectEqualStrings("", on_disk.access_token);
+}
+
+test "OAuthClient.passwordGrant: stores a refreshable token" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"legacy\",\"token_type\":\"Bearer\",\"refresh_token\":\"legacy-refresh\",\"expires_in\":3600}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"refreshed\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    try std.testing.expectError(error.InvalidParameter, client.passwordGrant("idp", "alice", "", null));
+
+    var token = try client.passwordGrant("idp", "alice", "p@ss word", null);
     defer token.deinit();
+    try std.testing.expectEqualStrings("legacy", token.access_token);
 
-    try client.saveToken("test_key", token);
+    const body = mock.lastRequest().?.body.?;
+    try std.testing.expect(std.mem.startsWith(u8, body, "grant_type=password&username=alice&password=p%40ss%20word"));
 
-    var loaded = (try client.getToken("test_key")).?;
-    defer loaded.deinit();
+    // The refresher treats it like any other stored token
+    clock.setMockTime(1_700_004_000);
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
 
-    try std.testing.expectEqualStrings("test_access_token", loaded.access_token);
+    var refreshed = try refresher.getValidToken("idp");
+    defer refreshed.deinit();
+    try std.testing.expectEqualStrings("refreshed", refreshed.access_token);
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "refresh_token=legacy-refresh") != null);
 }
 
-test "OAuthConfigOwned: allocation and cleanup without leaks" {
-    const allocator = std.testing.allocator;
+/// Vendor OTP grant used by the GrantRequest tests
+const TestOtpGrant = struct {
+    otp: []const u8,
 
-    var owned = try OAuthConfig.custom(
-        allocator,
-        "test-client-id",
-        "https://example.com/authorize",
-        "https://example.com/token",
-        "http://127.0.0.1/callback",
-        "read write",
-        "https://example.com/device",
-    );
-    defer owned.deinit();
+    fn grant(self: *TestOtpGrant) GrantRequest {
+        return .{ .ptr = self, .vtable = &.{
+            .grantType = grantType,
+            .appendParams = appendParams,
+            .parseResponse = parseResponse,
+        } };
+    }
 
-    try std.testing.expectEqualStrings("test-client-id", owned.client_id);
-    try std.testing.expectEqualStrings("https://example.com/authorize", owned.authorization_endpoint);
-    try std.testing.expectEqualStrings("read write", owned.scope.?);
-    try std.testing.expectEqualStrings("https://example.com/device", owned.device_authorization_endpoint.?);
-}
+    fn grantType(_: *anyopaque) []const u8 {
+        return "urn:okta:params:oauth:grant-type:otp";
+    }
 
-test "OAuthConfigOwned: toConfig borrows correctly" {
+    fn appendParams(ptr: *anyopaque, params: *GrantParams) anyerror!void {
+        const self: *TestOtpGrant = @ptrCast(@alignCast(ptr));
+        try params.add("otp", self.otp);
+    }
+
+    fn parseResponse(_: *anyopaque, token: *Token, document: json.Value) anyerror!void {
+        const factor = document.object.get("factor") orelse return;
+        token.metadata = try token.allocator.dupe(u8, factor.string);
+    }
+};
+
+test "OAuthClient.requestGrant: sends a custom grant and stores the token" {
     const allocator = std.testing.allocator;
 
-    var owned = try OAuthConfig.custom(
-        allocator,
-        "borrowed-client",
-        "https://example.com/auth",
-        "https://example.com/token",
-        "http://127.0.0.1/callback",
-        null,
-        null,
-    );
-    defer owned.deinit();
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
 
-    const config = owned.toConfig();
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
 
-    // Config borrows from owned - verify same pointers
-    try std.testing.expect(config.client_id.ptr == owned.client_id.ptr);
-    try std.testing.expect(config.authorization_endpoint.ptr == owned.authorization_endpoint.ptr);
-    try std.testing.expect(config.scope == null);
-    try std.testing.expect(config.device_authorization_endpoint == null);
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"otp-token\",\"token_type\":\"Bearer\",\"refresh_token\":\"otp-refresh\",\"expires_in\":3600,\"factor\":\"sms\"}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"refreshed\",\"token_type\":\"Bearer\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var otp = TestOtpGrant{ .otp = "123 456" };
+    var token = try client.requestGrant("okta", otp.grant());
+    defer token.deinit();
+    try std.testing.expectEqualStrings("otp-token", token.access_token);
+    try std.testing.expectEqualStrings("sms", token.metadata.?);
+
+    const body = mock.lastRequest().?.body.?;
+    try std.testing.expect(std.mem.startsWith(u8, body, "grant_type=urn%3Aokta%3Aparams%3Aoauth%3Agrant-type%3Aotp&otp=123%20456&client_id=test-client"));
+
+    // Parameters the client sets itself are rejected
+    var reserved_body: std.ArrayListUnmanaged(u8) = .{};
+    defer reserved_body.deinit(allocator);
+    var params = GrantParams{ .allocator = allocator, .body = &reserved_body };
+    try std.testing.expectError(error.InvalidParameter, params.add("client_id", "other"));
+
+    // The refresher treats it like any other stored token
+    clock.setMockTime(1_700_004_000);
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+
+    var refreshed = try refresher.getValidToken("okta");
+    defer refreshed.deinit();
+    try std.testing.expectEqualStrings("refreshed", refreshed.access_token);
+    try std.testing.expect(std.mem.indexOf(u8, mock.lastRequest().?.body.?, "refresh_token=otp-refresh") != null);
 }
 
-test "configFromFormula: with public client without leaks" {
+test "OAuthClient.startAuthorization: pushes the request to the PAR endpoint" {
     const allocator = std.testing.allocator;
 
-    // First, ensure builtin formulas are clean
-    formulas.deinitBuiltinFormulas();
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
 
-    // Create a v2 test formula with clients
-    const json_formula =
-        \\{
-        \\  "schema": "v2",
-        \\  "id": "test-provider",
-        \\  "label": "Test",
-        \\  "clients": [
-        \\    {"name": "default", "id": "default-id", "secret": "default-secret", "methods": ["device_code"]}
-        \\  ],
-        \\  "methods": {
-        \\    "device_code": {
-        \\      "endpoints": {
-        \\        "authorize": "https://test.com/authorize",
-        \\        "token": "https://test.com/token",
-        \\        "device": "https://test.com/device"
-        \\      },
-        \\      "scope": "default-scope"
-        \\    }
-        \\  },
-        \\  "apis": {
-        \\    "rest": {
-        \\      "base_url": "https://api.test.com",
-        \\      "auth_header": "Authorization: Bearer {token}",
-        \\      "methods": ["device_code"]
-        \\    }
-        \\  }
-        \\}
-    ;
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .status = 201, .body = "{\"request_uri\":\"urn:ietf:params:oauth:request_uri:abc\",\"expires_in\":60}" });
+    try mock.enqueue(.{ .status = 400, .body = "{\"error\":\"invalid_request\"}" });
 
-    var formula_owned = try formulas.loadFromJsonSlice(allocator, json_formula);
-    defer formula_owned.deinit();
+    var config = OAuthConfig.github("test-client", "repo");
+    config.pushed_authorization_request_endpoint = "https://example.com/par";
+    config.client_secret = "secret";
 
-    // Test using default public client
-    var config = try configFromFormula(
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var flow = try client.startAuthorization("http://127.0.0.1:8080/callback");
+    defer flow.deinit();
+
+    const expected_url = try std.fmt.allocPrint(
         allocator,
-        formula_owned.asConst(),
-        "device_code", // Method name
-        null, // No override - should use public client
-        null,
-        "http://127.0.0.1/callback",
-        null, // No scope override
+        "{s}?client_id=test-client&request_uri=urn%3Aietf%3Aparams%3Aoauth%3Arequest_uri%3Aabc",
+        .{config.authorization_endpoint},
     );
-    defer config.deinit();
-
-    try std.testing.expectEqualStrings("default-id", config.client_id);
-    try std.testing.expectEqualStrings("default-secret", config.client_secret.?);
-    try std.testing.expectEqualStrings("default-scope", config.scope.?);
-    try std.testing.expectEqualStrings("https://test.com/device", config.device_authorization_endpoint.?);
+    defer allocator.free(expected_url);
+    try std.testing.expectEqualStrings(expected_url, flow.url);
+
+    // The pushed parameters carry what the URL no longer does
+    const pushed = mock.lastRequest().?;
+    try std.testing.expectEqualStrings("https://example.com/par", pushed.url);
+    const state_param = try std.fmt.allocPrint(allocator, "state={s}", .{flow.getState()});
+    defer allocator.free(state_param);
+    try std.testing.expect(std.mem.indexOf(u8, pushed.body.?, state_param) != null);
+    try std.testing.expect(std.mem.indexOf(u8, pushed.body.?, "code_challenge=") != null);
+    try std.testing.expect(std.mem.indexOf(u8, pushed.body.?, "client_secret=secret") != null);
+
+    // A rejected push fails the flow
+    try std.testing.expectError(error.InvalidRequest, client.startAuthorization("http://127.0.0.1:8080/callback"));
 }
 
-test "configFromFormula: with client_id override without leaks" {
+test "OAuthClient.withDpop: proofs on token requests, nonce retry and key-bound refresh" {
     const allocator = std.testing.allocator;
 
-    const json_formula =
-        \\{
-        \\  "schema": "v2",
-        \\  "id": "test",
-        \\  "label": "Test",
-        \\  "clients": [
-        \\    {"name": "default", "id": "default-id", "secret": "default-secret"}
-        \\  ],
-        \\  "methods": {
-        \\    "oauth": {
-        \\      "endpoints": {
-        \\        "authorize": "https://test.com/authorize",
-        \\        "token": "https://test.com/token"
-        \\      }
-        \\    }
-        \\  },
-        \\  "apis": {
-        \\    "rest": {
-        \\      "base_url": "https://api.test.com",
-        \\      "auth_header": "Authorization: Bearer {token}",
-        \\      "methods": ["oauth"]
-        \\    }
-        \\  }
-        \\}
-    ;
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
 
-    var formula_owned = try formulas.loadFromJsonSlice(allocator, json_formula);
-    defer formula_owned.deinit();
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
 
-    // Test with client_id override
-    var config = try configFromFormula(
-        allocator,
-        formula_owned.asConst(),
-        "oauth", // Method name
-        "override-id", // Override client ID
-        "override-secret", // Override secret
-        "http://127.0.0.1/callback",
-        "custom-scope",
-    );
-    defer config.deinit();
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{
+        .status = 400,
+        .headers = &[_]transport.Header{.{ .name = "DPoP-Nonce", .value = "nonce-1" }},
+        .body = "{\"error\":\"use_dpop_nonce\"}",
+    });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"bound\",\"token_type\":\"DPoP\",\"refresh_token\":\"refresh-1\",\"expires_in\":3600}" });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"bound-2\",\"token_type\":\"DPoP\",\"expires_in\":3600}" });
+
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+    client.withDpop();
+    const original_key = client.dpop_key.?;
 
-    try std.testing.expectEqualStrings("override-id", config.client_id);
-    try std.testing.expectEqualStrings("override-secret", config.client_secret.?);
-    try std.testing.expectEqualStrings("custom-scope", config.scope.?);
+    var token = try client.exchangeCode("code", "verifier", "http://127.0.0.1/callback");
+    defer token.deinit();
+    try std.testing.expectEqual(@as(usize, 2), mock.requestCount());
+
+    // The retry carries the server nonce
+    const retry_proof = mock.lastRequest().?.header("DPoP").?;
+    const retry_claims = try jwt.decodeSegment(allocator, (try jwt.split(retry_proof)).payload);
+    defer allocator.free(retry_claims);
+    try std.testing.expect(std.mem.indexOf(u8, retry_claims, "\"nonce\":\"nonce-1\"") != null);
+    try std.testing.expect(std.mem.indexOf(u8, retry_claims, "\"htu\":\"https://github.com/login/oauth/access_token\"") != null);
+
+    // The bound token keeps its key
+    const encoded = original_key.encode();
+    try std.testing.expectEqualStrings(&encoded, token.dpop_key.?);
+    try client.saveToken("svc", token);
+
+    // A new client key does not break refreshing the bound token
+    client.withDpop();
+    clock.setMockTime(1_700_004_000);
+
+    var refresher = TokenRefresher.init(allocator, &client);
+    defer refresher.deinit();
+    var refreshed = try refresher.getValidToken("svc");
+    defer refreshed.deinit();
+    try std.testing.expectEqualStrings("bound-2", refreshed.access_token);
+    try std.testing.expectEqualStrings(&encoded, refreshed.dpop_key.?);
+
+    // And resource proofs come from the token's key
+    const proof = try dpop.proofForToken(allocator, &refreshed, "GET", "https://api.github.com/user", null);
+    defer allocator.free(proof);
+    var header = try jwt.decodeHeader(allocator, proof);
+    defer header.deinit();
+    try std.testing.expectEqual(jwt.Algorithm.ES256, header.alg);
 }
 
-test "configFromFormula: missing client_id returns error" {
+test "OAuthConfig.client_certificate: presented on token requests in place of the secret" {
     const allocator = std.testing.allocator;
 
-    const json_formula =
-        \\{
-        \\  "schema": "v2",
-        \\  "id": "no-clients",
-        \\  "label": "No Clients",
-        \\  "methods": {
-        \\    "oauth": {
-        \\      "endpoints": {
-        \\        "authorize": "https://test.com/authorize",
-        \\        "token": "https://test.com/token"
-        \\      }
-        \\    }
-        \\  },
-        \\  "apis": {
-        \\    "rest": {
-        \\      "base_url": "https://api.test.com",
-        \\      "auth_header": "Authorization: Bearer {token}",
-        \\      "methods": ["oauth"]
-        \\    }
-        \\  }
-        \\}
-    ;
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
 
-    var formula_owned = try formulas.loadFromJsonSlice(allocator, json_formula);
-    defer formula_owned.deinit();
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body = "{\"access_token\":\"bound\",\"token_type\":\"Bearer\"}" });
 
-    // Should fail because no clients and no override
-    const result = configFromFormula(
-        allocator,
-        formula_owned.asConst(),
-        "oauth", // Method name
-        null,
-        null,
-        "http://127.0.0.1/callback",
-        null,
-    );
+    var config = OAuthConfig.github("fapi-client", null);
+    config.client_secret = "unused-secret";
+    config.client_certificate = .{ .cert_pem = "cert", .key_pem = "key" };
 
-    try std.testing.expectError(error.MissingClientId, result);
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var token = try client.exchangeCode("code", "verifier", "http://127.0.0.1/callback");
+    defer token.deinit();
+
+    const sent = mock.lastRequest().?;
+    try std.testing.expect(sent.client_certificate);
+    try std.testing.expect(std.mem.indexOf(u8, sent.body.?, "client_id=fapi-client") != null);
+    try std.testing.expect(std.mem.indexOf(u8, sent.body.?, "client_secret") == null);
 }
 
-test "OAuthConfig.gitlabSelfHosted: without leaks" {
+test "OAuthClient.exchangeCode: records nbf and iat of JWT access tokens" {
     const allocator = std.testing.allocator;
 
-    var config = try OAuthConfig.gitlabSelfHosted(
-        allocator,
-        "gitlab-client",
-        "https://gitlab.mycompany.com",
-        "api read_user",
-    );
-    defer config.deinit();
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
 
-    try std.testing.expectEqualStrings("gitlab-client", config.client_id);
-    try std.testing.expectEqualStrings("https://gitlab.mycompany.com/oauth/authorize", config.authorization_endpoint);
-    try std.testing.expectEqualStrings("https://gitlab.mycompany.com/oauth/token", config.token_endpoint);
-    try std.testing.expectEqualStrings("api read_user", config.scope.?);
-}
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
 
-test "OAuthConfig.gitlabSelfHosted: strips trailing slash" {
-    const allocator = std.testing.allocator;
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
 
-    var config = try OAuthConfig.gitlabSelfHosted(
+    const payload = try jwt.encodeSegment(allocator, "{\"iat\":1700000000,\"nbf\":1700000120}");
+    defer allocator.free(payload);
+    const response = try std.fmt.allocPrint(
         allocator,
-        "gitlab-client",
-        "https://gitlab.mycompany.com/",
-        null,
+        "{{\"access_token\":\"eyJhbGciOiJSUzI1NiJ9.{s}.c2ln\",\"token_type\":\"Bearer\",\"expires_in\":3600}}",
+        .{payload},
     );
-    defer config.deinit();
+    defer allocator.free(response);
+    try mock.enqueue(.{ .body = response });
+    try mock.enqueue(.{ .body = "{\"access_token\":\"opaque\",\"token_type\":\"Bearer\"}" });
 
-    try std.testing.expectEqualStrings("https://gitlab.mycompany.com/oauth/authorize", config.authorization_endpoint);
+    var client = OAuthClient.init(allocator, OAuthConfig.github("test-client", null), storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
+
+    var token = try client.exchangeCode("code", "verifier", "http://127.0.0.1/callback");
+    defer token.deinit();
+    try std.testing.expectEqual(@as(?u64, 1_700_000_000), token.issued_at);
+    try std.testing.expectEqual(@as(?u64, 1_700_000_120), token.not_before);
+    try std.testing.expectEqual(Token.OfflineUsability{ .not_yet_valid = .{ .in = 120 } }, token.offlineUsability());
+
+    var opaque_token = try client.exchangeCode("code", "verifier", "http://127.0.0.1/callback");
+    defer opaque_token.deinit();
+    try std.testing.expect(opaque_token.not_before == null and opaque_token.issued_at == null);
 }
 
-test "OAuthConfig.gitlabSelfHosted: rejects insecure URL" {
+test "OAuthConfig.client_certificate: rejects access tokens bound to another certificate" {
     const allocator = std.testing.allocator;
 
-    const result = OAuthConfig.gitlabSelfHosted(
-        allocator,
-        "client",
-        "http://insecure.example.com",
-        null,
-    );
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
 
-    try std.testing.expectError(error.InsecureEndpoint, result);
-}
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
 
-test "validateEndpointSecurity: allows HTTPS" {
-    try validateEndpointSecurity("https://example.com/oauth");
-    try validateEndpointSecurity("https://sub.domain.example.com:8443/path");
-}
+    var config = OAuthConfig.github("fapi-client", null);
+    config.client_certificate = .{
+        .cert_pem = "-----BEGIN CERTIFICATE-----\nc2NobHVzc2Vs\n-----END CERTIFICATE-----\n",
+        .key_pem = "key",
+    };
 
-test "validateEndpointSecurity: allows localhost HTTP" {
-    try validateEndpointSecurity("http://localhost/callback");
-    try validateEndpointSecurity("http://localhost:8080/callback");
-    try validateEndpointSecurity("http://127.0.0.1/callback");
-    try validateEndpointSecurity("http://127.0.0.1:3000/callback");
-    try validateEndpointSecurity("http://[::1]/callback");
-}
+    var client = OAuthClient.init(allocator, config, storage.storage());
+    defer client.deinit();
+    client.http_transport = mock.transport();
 
-test "validateEndpointSecurity: rejects insecure HTTP" {
-    try std.testing.expectError(error.InsecureEndpoint, validateEndpointSecurity("http://example.com/oauth"));
-    try std.testing.expectError(error.InsecureEndpoint, validateEndpointSecurity("http://192.168.1.1/oauth"));
+    const bodies = [_][]const u8{
+        "{\"cnf\":{\"x5t#S256\":\"TkAiFpuWAxsfpA8DGBG09oH5grKHuENs6bVWR9CwK3Q\"}}",
+        "{\"cnf\":{\"x5t#S256\":\"bm90LXRoZS1jbGllbnQtY2VydGlmaWNhdGUtdGh1bWI\"}}",
+    };
+    for (bodies, 0..) |claims, i| {
+        const payload = try jwt.encodeSegment(allocator, claims);
+        defer allocator.free(payload);
+        const response = try std.fmt.allocPrint(
+            allocator,
+            "{{\"access_token\":\"eyJhbGciOiJSUzI1NiJ9.{s}.c2ln\",\"token_type\":\"Bearer\"}}",
+            .{payload},
+        );
+        defer allocator.free(response);
+        try mock.enqueue(.{ .body = response });
+
+        if (i == 0) {
+            var token = try client.exchangeCode("code", "verifier", "http://127.0.0.1/callback");
+            token.deinit();
+        } else {
+            try std.testing.expectError(
+                error.CertificateMismatch,
+                client.exchangeCode("code", "verifier", "http://127.0.0.1/callback"),
+            );
+        }
+    }
 }
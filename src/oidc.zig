@@ -0,0 +1,271 @@
+//! OpenID Connect provider configuration (OpenID Connect Discovery 1.0)
+//!
+//! Loads `/.well-known/openid-configuration` for an issuer. On top of the
+//! OAuth endpoints (see discovery.zig) the document names the provider's
+//! signing keys, UserInfo and logout endpoints, and the response types and
+//! ID token algorithms it supports.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var provider = try OidcConfig.fromIssuer(allocator, transport.defaultTransport(), "https://accounts.google.com");
+//! defer provider.deinit();
+//!
+//! var config = try provider.toConfig(allocator, "client-id", "http://127.0.0.1:8080/callback", "email");
+//! defer config.deinit();
+//! // config.scope is "openid email"
+//! ```
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+
+const discovery = @import("discovery.zig");
+const oauth = @import("oauth.zig");
+const transport = @import("transport.zig");
+
+const HttpTransport = transport.HttpTransport;
+const ServerMetadata = discovery.ServerMetadata;
+
+/// OpenID Provider metadata
+pub const OidcConfig = struct {
+    allocator: Allocator,
+    /// OAuth endpoints and issuer from the same document
+    metadata: ServerMetadata,
+    userinfo_endpoint: ?[]const u8 = null,
+    end_session_endpoint: ?[]const u8 = null,
+    response_types_supported: []const []const u8 = &.{},
+    id_token_signing_alg_values_supported: []const []const u8 = &.{},
+    scopes_supported: []const []const u8 = &.{},
+
+    /// OIDC-only fields of the provider document
+    const Document = struct {
+        userinfo_endpoint: ?[]const u8 = null,
+        end_session_endpoint: ?[]const u8 = null,
+        response_types_supported: []const []const u8 = &.{},
+        id_token_signing_alg_values_supported: []const []const u8 = &.{},
+        scopes_supported: []const []const u8 = &.{},
+    };
+
+    /// Fetch the provider configuration of `issuer`
+    ///
+    /// Returns `error.IssuerMismatch` if the document was published for a
+    /// different issuer.
+    pub fn fromIssuer(allocator: Allocator, http_transport: HttpTransport, issuer: []const u8) !OidcConfig {
+        try oauth.validateEndpointSecurity(issuer);
+
+        const url = try std.fmt.allocPrint(allocator, "{s}/.well-known/openid-configuration", .{
+            std.mem.trimRight(u8, issuer, "/"),
+        });
+        defer allocator.free(url);
+
+        const body = try discovery.fetchDocument(allocator, http_transport, url);
+        defer allocator.free(body);
+
+        return parse(allocator, issuer, body);
+    }
+
+    /// Parse a provider configuration document published for `expected_issuer`
+    pub fn parse(allocator: Allocator, expected_issuer: []const u8, body: []const u8) !OidcConfig {
+        var metadata = try ServerMetadata.parse(allocator, expected_issuer, body);
+        errdefer metadata.deinit();
+
+        const parsed = json.parseFromSlice(Document, allocator, body, .{
+            .ignore_unknown_fields = true,
+        }) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.JsonError,
+        };
+        defer parsed.deinit();
+        const doc = parsed.value;
+
+        var config = OidcConfig{ .allocator = allocator, .metadata = metadata };
+        errdefer config.freeExtras();
+
+        if (doc.userinfo_endpoint) |e| config.userinfo_endpoint = try allocator.dupe(u8, e);
+        if (doc.end_session_endpoint) |e| config.end_session_endpoint = try allocator.dupe(u8, e);
+        config.response_types_supported = try dupeStrings(allocator, doc.response_types_supported);
+        config.id_token_signing_alg_values_supported = try dupeStrings(allocator, doc.id_token_signing_alg_values_supported);
+        config.scopes_supported = try dupeStrings(allocator, doc.scopes_supported);
+
+        return config;
+    }
+
+    pub fn deinit(self: *OidcConfig) void {
+        self.freeExtras();
+        self.metadata.deinit();
+    }
+
+    fn freeExtras(self: *OidcConfig) void {
+        if (self.userinfo_endpoint) |e| self.allocator.free(e);
+        if (self.end_session_endpoint) |e| self.allocator.free(e);
+        freeStrings(self.allocator, self.response_types_supported);
+        freeStrings(self.allocator, self.id_token_signing_alg_values_supported);
+        freeStrings(self.allocator, self.scopes_supported);
+        self.userinfo_endpoint = null;
+        self.end_session_endpoint = null;
+        self.response_types_supported = &.{};
+        self.id_token_signing_alg_values_supported = &.{};
+        self.scopes_supported = &.{};
+    }
+
+    /// Whether the provider lists `response_type` (e.g. "code")
+    pub fn supportsResponseType(self: *const OidcConfig, response_type: []const u8) bool {
+        for (self.response_types_supported) |supported| {
+            if (std.mem.eql(u8, supported, response_type)) return true;
+        }
+        return false;
+    }
+
+    /// Build a client configuration for this provider
+    ///
+    /// `openid` is added to `scope` when missing. The provider's issuer,
+    /// JWKS, UserInfo and logout endpoints are carried over, so the client's
+    /// OIDC helpers need no further setup. The caller owns the result.
+    pub fn toConfig(
+        self: *const OidcConfig,
+        allocator: Allocator,
+        client_id: []const u8,
+        redirect_uri: []const u8,
+        scope: ?[]const u8,
+    ) !oauth.OAuthConfigOwned {
+        const requested = scope orelse "";
+        const openid_scope = if (scopeHasOpenid(requested))
+            try allocator.dupe(u8, requested)
+        else if (requested.len == 0)
+            try allocator.dupe(u8, "openid")
+        else
+            try std.fmt.allocPrint(allocator, "openid {s}", .{requested});
+        defer allocator.free(openid_scope);
+
+        var owned = try self.metadata.toConfig(allocator, client_id, redirect_uri, openid_scope);
+        errdefer owned.deinit();
+
+        if (self.userinfo_endpoint) |e| {
+            try oauth.validateEndpointSecurity(e);
+            owned.userinfo_endpoint = try allocator.dupe(u8, e);
+        }
+        if (self.end_session_endpoint) |e| {
+            try oauth.validateEndpointSecurity(e);
+            owned.end_session_endpoint = try allocator.dupe(u8, e);
+        }
+        return owned;
+    }
+};
+
+/// Claims returned by the UserInfo endpoint (OpenID Connect Core Section 5.3)
+pub const UserInfo = struct {
+    /// Subject; the same identifier as the ID token's `sub`
+    sub: []const u8,
+    email: ?[]const u8 = null,
+    email_verified: ?bool = null,
+    name: ?[]const u8 = null,
+    /// Every claim in the response, including the ones above
+    claims: json.ObjectMap,
+    parsed: json.Parsed(json.Value),
+
+    /// Parse a UserInfo response; `sub` is required
+    pub fn parse(allocator: Allocator, body: []const u8) !UserInfo {
+        var parsed = json.parseFromSlice(json.Value, allocator, body, .{}) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.JsonError,
+        };
+        errdefer parsed.deinit();
+
+        if (parsed.value != .object) return error.JsonError;
+        const claims = parsed.value.object;
+
+        const sub = claims.get("sub") orelse return error.ServerError;
+        if (sub != .string) return error.ServerError;
+
+        var info = UserInfo{ .sub = sub.string, .claims = claims, .parsed = parsed };
+        if (claims.get("email")) |v| {
+            if (v == .string) info.email = v.string;
+        }
+        if (claims.get("email_verified")) |v| {
+            if (v == .bool) info.email_verified = v.bool;
+        }
+        if (claims.get("name")) |v| {
+            if (v == .string) info.name = v.string;
+        }
+        return info;
+    }
+
+    pub fn deinit(self: *UserInfo) void {
+        self.parsed.deinit();
+    }
+
+    /// Look up any claim, e.g. `picture` or a provider-specific one
+    pub fn claim(self: *const UserInfo, name: []const u8) ?json.Value {
+        return self.claims.get(name);
+    }
+};
+
+fn scopeHasOpenid(scope: []const u8) bool {
+    var it = std.mem.tokenizeScalar(u8, scope, ' ');
+    while (it.next()) |s| {
+        if (std.mem.eql(u8, s, "openid")) return true;
+    }
+    return false;
+}
+
+fn dupeStrings(allocator: Allocator, strings: []const []const u8) ![]const []const u8 {
+    const out = try allocator.alloc([]const u8, strings.len);
+    var copied: usize = 0;
+    errdefer {
+        for (out[0..copied]) |s| allocator.free(s);
+        allocator.free(out);
+    }
+    for (strings) |s| {
+        out[copied] = try allocator.dupe(u8, s);
+        copied += 1;
+    }
+    return out;
+}
+
+fn freeStrings(allocator: Allocator, strings: []const []const u8) void {
+    for (strings) |s| allocator.free(s);
+    allocator.free(strings);
+}
+
+test "OidcConfig.fromIssuer loads provider metadata into a client config" {
+    const allocator = std.testing.allocator;
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+    try mock.enqueue(.{ .body =
+        \\{"issuer":"https://id.example.com",
+        \\ "authorization_endpoint":"https://id.example.com/authorize",
+        \\ "token_endpoint":"https://id.example.com/token",
+        \\ "jwks_uri":"https://id.example.com/jwks",
+        \\ "userinfo_endpoint":"https://id.example.com/userinfo",
+        \\ "end_session_endpoint":"https://id.example.com/logout",
+        \\ "response_types_supported":["code","id_token"],
+        \\ "subject_types_supported":["public"],
+        \\ "id_token_signing_alg_values_supported":["RS256"]}
+    });
+
+    var provider = try OidcConfig.fromIssuer(allocator, mock.transport(), "https://id.example.com/");
+    defer provider.deinit();
+    try std.testing.expectEqualStrings("https://id.example.com/.well-known/openid-configuration", mock.lastRequest().?.url);
+
+    try std.testing.expect(provider.supportsResponseType("code"));
+    try std.testing.expect(!provider.supportsResponseType("token"));
+    try std.testing.expectEqualStrings("RS256", provider.id_token_signing_alg_values_supported[0]);
+
+    var config = try provider.toConfig(allocator, "client", "http://127.0.0.1/callback", "email");
+    defer config.deinit();
+    try std.testing.expectEqualStrings("openid email", config.scope.?);
+    try std.testing.expectEqualStrings("https://id.example.com", config.issuer.?);
+    try std.testing.expectEqualStrings("https://id.example.com/jwks", config.jwks_uri.?);
+    try std.testing.expectEqualStrings("https://id.example.com/userinfo", config.userinfo_endpoint.?);
+    try std.testing.expectEqualStrings("https://id.example.com/logout", config.end_session_endpoint.?);
+}
+
+test "OidcConfig.parse rejects a document for another issuer" {
+    const allocator = std.testing.allocator;
+    try std.testing.expectError(
+        error.IssuerMismatch,
+        OidcConfig.parse(allocator, "https://id.example.com", "{\"issuer\":\"https://other.example.com\"}"),
+    );
+}
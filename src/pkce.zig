@@ -3,85 +3,167 @@
 //! RFC 7636 compliant implementation for OAuth 2.0 PKCE extension.
 //! Generates cryptographically secure code verifiers and challenges.
 //!
+//! By default the verifier carries 32 random bytes (43 characters) and the
+//! challenge is its SHA-256 hash (`S256`). `Options` raises the entropy up
+//! to the 128-character maximum or, for servers that support nothing else,
+//! selects the `plain` method; negotiateMethod() picks the method from a
+//! server's `code_challenge_methods_supported`.
+//!
 //! ## Example
 //!
 //! ```zig
 //! const pkce = Pkce.generate();
-//! const verifier = pkce.verifier;    // 43-character base64url string
-//! const challenge = pkce.challenge;  // SHA256 hash of verifier, base64url encoded
+//! const verifier = pkce.getVerifier();    // 43-character base64url string
+//! const challenge = pkce.getChallenge();  // SHA256 hash of verifier, base64url encoded
 //! ```
 
 const std = @import("std");
 const crypto = std.crypto;
 
+/// Shortest verifier RFC 7636 allows, in characters
+pub const min_verifier_len = 43;
+/// Longest verifier RFC 7636 allows, in characters
+pub const max_verifier_len = 128;
+
+/// How the challenge is derived from the verifier (RFC 7636 Section 4.2)
+pub const Method = enum {
+    /// BASE64URL(SHA256(verifier))
+    s256,
+    /// The verifier itself; only for servers that cannot do S256
+    plain,
+
+    /// Value of the `code_challenge_method` parameter
+    pub fn toString(self: Method) []const u8 {
+        return switch (self) {
+            .s256 => "S256",
+            .plain => "plain",
+        };
+    }
+
+    /// Parse a `code_challenge_method` value
+    pub fn fromString(value: []const u8) ?Method {
+        if (std.mem.eql(u8, value, "S256")) return .s256;
+        if (std.mem.eql(u8, value, "plain")) return .plain;
+        return null;
+    }
+};
+
+/// How verifiers are generated
+pub const Options = struct {
+    /// Random bytes in the verifier, from 32 (43 characters) to 96 (128 characters)
+    verifier_bytes: usize = 32,
+    /// Challenge method
+    method: Method = .s256,
+};
+
+/// Choose the challenge method from a server's `code_challenge_methods_supported`
+///
+/// Prefers S256 and falls back to `plain` only when the server lists it
+/// without S256. Servers that do not list any methods get S256. Returns
+/// `error.UnsupportedOperation` when the server supports neither.
+pub fn negotiateMethod(supported: []const []const u8) !Method {
+    if (supported.len == 0) return .s256;
+
+    var plain = false;
+    for (supported) |value| {
+        const method = Method.fromString(value) orelse continue;
+        if (method == .s256) return .s256;
+        plain = true;
+    }
+    return if (plain) .plain else error.UnsupportedOperation;
+}
+
 /// PKCE code verifier and challenge pair
 pub const Pkce = struct {
-    /// The code verifier (43 characters, base64url without padding)
-    verifier: [43]u8,
-    /// The code challenge (43 characters, SHA256 of verifier, base64url without padding)
-    challenge: [43]u8,
+    /// The code verifier (base64url without padding); see getVerifier()
+    verifier_buf: [max_verifier_len]u8,
+    verifier_len: u8,
+    /// The code challenge (base64url without padding); see getChallenge()
+    challenge_buf: [max_verifier_len]u8,
+    challenge_len: u8,
+    method: Method = .s256,
 
     /// Generate a new PKCE code verifier and challenge pair
     ///
-    /// Uses cryptographically secure random bytes for the verifier,
+    /// Uses 32 cryptographically secure random bytes for the verifier,
     /// and SHA256 for the challenge transformation.
     pub fn generate() Pkce {
-        var verifier_bytes: [32]u8 = undefined;
-        crypto.random.bytes(&verifier_bytes);
-
-        var verifier: [43]u8 = undefined;
-        _ = base64UrlEncode(&verifier_bytes, &verifier);
+        return generateWithOptions(.{}) catch unreachable;
+    }
 
-        // Calculate SHA256 of the verifier
-        var hash: [32]u8 = undefined;
-        crypto.hash.sha2.Sha256.hash(&verifier, &hash, .{});
+    /// Generate a pair with a custom verifier length or challenge method
+    ///
+    /// Returns `error.InvalidParameter` when `options.verifier_bytes` would
+    /// give a verifier outside 43 to 128 characters.
+    pub fn generateWithOptions(options: Options) !Pkce {
+        if (options.verifier_bytes < 32 or options.verifier_bytes > 96) return error.InvalidParameter;
 
-        var challenge: [43]u8 = undefined;
-        _ = base64UrlEncode(&hash, &challenge);
+        var verifier_bytes: [96]u8 = undefined;
+        crypto.random.bytes(verifier_bytes[0..options.verifier_bytes]);
 
-        return .{
-            .verifier = verifier,
-            .challenge = challenge,
-        };
+        var verifier: [max_verifier_len]u8 = undefined;
+        const len = base64UrlEncode(verifier_bytes[0..options.verifier_bytes], &verifier);
+        return fromVerifierWithMethod(verifier[0..len], options.method);
     }
 
     /// Create PKCE from an existing verifier string
     ///
     /// Useful for testing or when verifier is provided externally.
     pub fn fromVerifier(verifier_str: []const u8) !Pkce {
-        if (verifier_str.len != 43) {
+        return fromVerifierWithMethod(verifier_str, .s256);
+    }
+
+    /// Like fromVerifier(), deriving the challenge with `method`
+    ///
+    /// The verifier must be 43 to 128 unreserved characters
+    /// (RFC 7636 Section 4.1), otherwise `error.InvalidParameter` is returned.
+    pub fn fromVerifierWithMethod(verifier_str: []const u8, method: Method) !Pkce {
+        if (verifier_str.len < min_verifier_len or verifier_str.len > max_verifier_len) {
             return error.InvalidParameter;
         }
+        for (verifier_str) |c| {
+            if (!std.ascii.isAlphanumeric(c) and c != '-' and c != '.' and c != '_' and c != '~') {
+                return error.InvalidParameter;
+            }
+        }
 
-        var verifier: [43]u8 = undefined;
-        @memcpy(&verifier, verifier_str);
-
-        // Calculate SHA256 of the verifier
-        var hash: [32]u8 = undefined;
-        crypto.hash.sha2.Sha256.hash(&verifier, &hash, .{});
-
-        var challenge: [43]u8 = undefined;
-        _ = base64UrlEncode(&hash, &challenge);
-
-        return .{
-            .verifier = verifier,
-            .challenge = challenge,
+        var pair = Pkce{
+            .verifier_buf = undefined,
+            .verifier_len = @intCast(verifier_str.len),
+            .challenge_buf = undefined,
+            .challenge_len = undefined,
+            .method = method,
         };
+        @memcpy(pair.verifier_buf[0..verifier_str.len], verifier_str);
+
+        switch (method) {
+            .s256 => {
+                // Calculate SHA256 of the verifier
+                var hash: [32]u8 = undefined;
+                crypto.hash.sha2.Sha256.hash(verifier_str, &hash, .{});
+                pair.challenge_len = @intCast(base64UrlEncode(&hash, &pair.challenge_buf));
+            },
+            .plain => {
+                @memcpy(pair.challenge_buf[0..verifier_str.len], verifier_str);
+                pair.challenge_len = pair.verifier_len;
+            },
+        }
+        return pair;
     }
 
     /// Get the verifier as a slice
     pub fn getVerifier(self: *const Pkce) []const u8 {
-        return &self.verifier;
+        return self.verifier_buf[0..self.verifier_len];
     }
 
     /// Get the challenge as a slice
     pub fn getChallenge(self: *const Pkce) []const u8 {
-        return &self.challenge;
+        return self.challenge_buf[0..self.challenge_len];
     }
 
-    /// Get the challenge method (always "S256")
-    pub fn getChallengeMethod() []const u8 {
-        return "S256";
+    /// Get the challenge method ("S256" unless `plain` was requested)
+    pub fn getChallengeMethod(self: *const Pkce) []const u8 {
+        return self.method.toString();
     }
 };
 
@@ -180,13 +262,13 @@ fn decodeChar(c: u8) ?u8 {
 
 test "PKCE generation produces correct lengths" {
     const pkce = Pkce.generate();
-    try std.testing.expectEqual(@as(usize, 43), pkce.verifier.len);
-    try std.testing.expectEqual(@as(usize, 43), pkce.challenge.len);
+    try std.testing.expectEqual(@as(usize, 43), pkce.getVerifier().len);
+    try std.testing.expectEqual(@as(usize, 43), pkce.getChallenge().len);
 }
 
 test "PKCE verifier only contains valid base64url characters" {
     const pkce = Pkce.generate();
-    for (pkce.verifier) |c| {
+    for (pkce.getVerifier()) |c| {
         const valid = (c >= 'A' and c <= 'Z') or
             (c >= 'a' and c <= 'z') or
             (c >= '0' and c <= '9') or
@@ -200,25 +282,47 @@ test "PKCE challenge is SHA256 of verifier" {
 
     // Manually compute SHA256 of verifier
     var hash: [32]u8 = undefined;
-    crypto.hash.sha2.Sha256.hash(&pkce.verifier, &hash, .{});
+    crypto.hash.sha2.Sha256.hash(pkce.getVerifier(), &hash, .{});
 
     var expected_challenge: [43]u8 = undefined;
     _ = base64UrlEncode(&hash, &expected_challenge);
 
-    try std.testing.expectEqualSlices(u8, &expected_challenge, &pkce.challenge);
+    try std.testing.expectEqualSlices(u8, &expected_challenge, pkce.getChallenge());
 }
 
 test "PKCE from verifier produces consistent challenge" {
     // Test with a known verifier
     const pkce1 = Pkce.generate();
-    const pkce2 = try Pkce.fromVerifier(&pkce1.verifier);
+    const pkce2 = try Pkce.fromVerifier(pkce1.getVerifier());
 
-    try std.testing.expectEqualSlices(u8, &pkce1.verifier, &pkce2.verifier);
-    try std.testing.expectEqualSlices(u8, &pkce1.challenge, &pkce2.challenge);
+    try std.testing.expectEqualSlices(u8, pkce1.getVerifier(), pkce2.getVerifier());
+    try std.testing.expectEqualSlices(u8, pkce1.getChallenge(), pkce2.getChallenge());
 }
 
 test "PKCE challenge method is S256" {
-    try std.testing.expectEqualStrings("S256", Pkce.getChallengeMethod());
+    const pkce = Pkce.generate();
+    try std.testing.expectEqualStrings("S256", pkce.getChallengeMethod());
+}
+
+test "PKCE options control verifier length and method" {
+    const long = try Pkce.generateWithOptions(.{ .verifier_bytes = 96 });
+    try std.testing.expectEqual(@as(usize, max_verifier_len), long.getVerifier().len);
+    try std.testing.expectEqual(@as(usize, 43), long.getChallenge().len);
+
+    const plain = try Pkce.generateWithOptions(.{ .method = .plain });
+    try std.testing.expectEqualStrings(plain.getVerifier(), plain.getChallenge());
+    try std.testing.expectEqualStrings("plain", plain.getChallengeMethod());
+
+    try std.testing.expectError(error.InvalidParameter, Pkce.generateWithOptions(.{ .verifier_bytes = 16 }));
+    try std.testing.expectError(error.InvalidParameter, Pkce.fromVerifier("too-short"));
+    try std.testing.expectError(error.InvalidParameter, Pkce.fromVerifier("a" ** 42 ++ "!"));
+}
+
+test "negotiateMethod prefers S256" {
+    try std.testing.expectEqual(Method.s256, try negotiateMethod(&.{}));
+    try std.testing.expectEqual(Method.s256, try negotiateMethod(&.{ "plain", "S256" }));
+    try std.testing.expectEqual(Method.plain, try negotiateMethod(&.{"plain"}));
+    try std.testing.expectError(error.UnsupportedOperation, negotiateMethod(&.{"S512"}));
 }
 
 test "base64url encode and decode roundtrip" {
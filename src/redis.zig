@@ -0,0 +1,630 @@
+//! Redis token storage
+//!
+//! `RedisStorage` keeps every token as a string under `<prefix><key>`, so
+//! every instance of a horizontally scaled service shares the same tokens.
+//! Tokens that cannot be refreshed get a native TTL matching their expiry
+//! (plus `Options.ttl_grace`), so Redis drops finished sessions by itself;
+//! tokens with a refresh token are kept until deleted.
+//!
+//! The client speaks RESP over one TCP connection (optionally TLS),
+//! serialized by a mutex; it authenticates with `AUTH` and selects the
+//! database on connect. A connection that fails mid-command is dropped and
+//! re-established by the next command, so a half-read reply never leaks
+//! into the next one.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var redis = try RedisStorage.connect(allocator, .{ .host = "redis.internal", .password = secret });
+//! defer redis.deinit();
+//!
+//! var client = OAuthClient.init(allocator, config, redis.storage());
+//! ```
+
+const std = @import("std");
+const Allocator = std.mem.Allocator;
+
+const clock = @import("clock.zig");
+const codec = @import("codec.zig");
+const session = @import("session.zig");
+
+const Token = session.Token;
+const SessionStorage = session.SessionStorage;
+const StorageCapabilities = session.StorageCapabilities;
+
+/// Token storage in a Redis server
+pub const RedisStorage = struct {
+    allocator: Allocator,
+    options: Options,
+    /// Null after a failure, until the next command reconnects
+    connection: ?*Connection = null,
+    /// Roots for verifying the server certificate with `Options.tls`
+    ca_bundle: std.crypto.Certificate.Bundle = .{},
+    /// Serializes commands on the shared connection
+    mutex: std.Thread.Mutex = .{},
+    read_buf: [4096]u8 = undefined,
+    read_start: usize = 0,
+    read_end: usize = 0,
+
+    pub const Options = struct {
+        host: []const u8 = "127.0.0.1",
+        port: u16 = 6379,
+        /// ACL user for `AUTH` (Redis 6+); the default user when null
+        username: ?[]const u8 = null,
+        password: ?[]const u8 = null,
+        database: u32 = 0,
+        /// Prepended to every storage key
+        key_prefix: []const u8 = "schlussel:",
+        /// Seconds a non-refreshable token is kept past its expiry
+        ttl_grace: u64 = 0,
+        /// Connect over TLS, verifying the server against the system roots
+        tls: bool = false,
+    };
+
+    /// Connect to the server and authenticate
+    ///
+    /// Returns `error.ConnectionFailed` if the server cannot be reached and
+    /// `error.StorageError` if it rejects `AUTH` or `SELECT`.
+    pub fn connect(allocator: Allocator, options: Options) !RedisStorage {
+        var self = RedisStorage{
+            .allocator = allocator,
+            .options = try dupeOptions(allocator, options),
+        };
+        errdefer self.deinit();
+
+        if (options.tls) {
+            self.ca_bundle.rescan(allocator) catch |err| switch (err) {
+                error.OutOfMemory => return err,
+                else => return error.ConnectionFailed,
+            };
+        }
+        try self.reconnect();
+        return self;
+    }
+
+    pub fn deinit(self: *RedisStorage) void {
+        self.disconnect();
+        self.ca_bundle.deinit(self.allocator);
+        freeOptions(self.allocator, self.options);
+    }
+
+    pub fn storage(self: *RedisStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+                .capabilities = capabilities,
+                .list_keys = listKeys,
+            }),
+        };
+    }
+
+    fn capabilities(_: *anyopaque) StorageCapabilities {
+        return .{ .list_keys = true, .atomic_swap = true, .ttl = true };
+    }
+
+    /// Seconds until Redis should drop `token`, or null to keep it
+    fn expirySeconds(self: *const RedisStorage, token: *const Token) ?u64 {
+        if (token.refresh_token != null) return null;
+        const expires_at = token.expires_at orelse return null;
+        const remaining = expires_at -| clock.now();
+        return @max(1, remaining +| self.options.ttl_grace);
+    }
+
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        const self: *RedisStorage = @ptrCast(@alignCast(ptr));
+
+        const redis_key = try self.redisKey(key);
+        defer self.allocator.free(redis_key);
+        const data = try codec.default.encode(self.allocator, &token);
+        defer {
+            std.crypto.secureZero(u8, data);
+            self.allocator.free(data);
+        }
+
+        var reply = if (self.expirySeconds(&token)) |seconds| blk: {
+            var digits: [20]u8 = undefined;
+            const ttl = std.fmt.bufPrint(&digits, "{d}", .{seconds}) catch unreachable;
+            break :blk try self.command(&.{ "SET", redis_key, data, "EX", ttl });
+        } else try self.command(&.{ "SET", redis_key, data });
+        defer reply.deinit(self.allocator);
+        try reply.expectOk();
+    }
+
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *RedisStorage = @ptrCast(@alignCast(ptr));
+
+        const redis_key = try self.redisKey(key);
+        defer self.allocator.free(redis_key);
+
+        var reply = try self.command(&.{ "GET", redis_key });
+        defer reply.deinit(self.allocator);
+        const data = switch (reply) {
+            .bulk => |bulk| bulk orelse return null,
+            else => return replyError(reply),
+        };
+        return try codec.default.decode(allocator, data);
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        const self: *RedisStorage = @ptrCast(@alignCast(ptr));
+
+        const redis_key = try self.redisKey(key);
+        defer self.allocator.free(redis_key);
+
+        var reply = try self.command(&.{ "DEL", redis_key });
+        defer reply.deinit(self.allocator);
+        if (reply != .integer) return replyError(reply);
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        const self: *RedisStorage = @ptrCast(@alignCast(ptr));
+
+        const redis_key = self.redisKey(key) catch return false;
+        defer self.allocator.free(redis_key);
+
+        var reply = self.command(&.{ "EXISTS", redis_key }) catch return false;
+        defer reply.deinit(self.allocator);
+        return reply == .integer and reply.integer > 0;
+    }
+
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) ![][]const u8 {
+        const self: *RedisStorage = @ptrCast(@alignCast(ptr));
+
+        const pattern = try globPrefix(self.allocator, self.options.key_prefix);
+        defer self.allocator.free(pattern);
+
+        var keys: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
+
+        // SCAN may return a key more than once
+        var seen: std.StringHashMapUnmanaged(void) = .{};
+        defer seen.deinit(self.allocator);
+
+        var cursor = try self.allocator.dupe(u8, "0");
+        defer self.allocator.free(cursor);
+        while (true) {
+            var reply = try self.command(&.{ "SCAN", cursor, "MATCH", pattern, "COUNT", "100" });
+            defer reply.deinit(self.allocator);
+
+            const page = switch (reply) {
+                .array => |items| items,
+                else => return replyError(reply),
+            };
+            if (page.len != 2 or page[0] != .bulk or page[0].bulk == null or page[1] != .array) return error.StorageError;
+
+            for (page[1].array) |item| {
+                if (item != .bulk or item.bulk == null) return error.StorageError;
+                const name = item.bulk.?;
+                if (!std.mem.startsWith(u8, name, self.options.key_prefix)) continue;
+                const key = name[self.options.key_prefix.len..];
+                if (seen.contains(key)) continue;
+
+                const copy = try allocator.dupe(u8, key);
+                errdefer allocator.free(copy);
+                try keys.append(allocator, copy);
+                try seen.put(self.allocator, copy, {});
+            }
+
+            const next = page[0].bulk.?;
+            if (std.mem.eql(u8, next, "0")) break;
+            const next_cursor = try self.allocator.dupe(u8, next);
+            self.allocator.free(cursor);
+            cursor = next_cursor;
+        }
+        return keys.toOwnedSlice(allocator);
+    }
+
+    fn redisKey(self: *RedisStorage, key: []const u8) ![]u8 {
+        if (key.len == 0) return error.InvalidParameter;
+        return std.mem.concat(self.allocator, u8, &.{ self.options.key_prefix, key });
+    }
+
+    /// Send one command and read its reply; caller deinits the reply
+    ///
+    /// Reconnects first if an earlier command dropped the connection.
+    fn command(self: *RedisStorage, args: []const []const u8) !Reply {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        if (self.connection == null) try self.reconnect();
+        return self.exchange(args);
+    }
+
+    /// Write `args` and read the reply, dropping the connection on failure
+    ///
+    /// After a failed write or a partial reply the stream position is
+    /// unknown, so the connection cannot be reused.
+    fn exchange(self: *RedisStorage, args: []const []const u8) !Reply {
+        errdefer self.disconnect();
+        const connection = self.connection orelse return error.ConnectionFailed;
+
+        const request = try encodeCommand(self.allocator, args);
+        defer {
+            std.crypto.secureZero(u8, request);
+            self.allocator.free(request);
+        }
+
+        try connection.writeAll(request);
+        return self.readReply();
+    }
+
+    /// Open a new connection, then authenticate and select the database
+    fn reconnect(self: *RedisStorage) !void {
+        self.disconnect();
+        self.connection = try Connection.open(self.allocator, self.options, if (self.options.tls) self.ca_bundle else null);
+        errdefer self.disconnect();
+
+        if (self.options.password) |password| {
+            var reply = if (self.options.username) |username|
+                try self.exchange(&.{ "AUTH", username, password })
+            else
+                try self.exchange(&.{ "AUTH", password });
+            defer reply.deinit(self.allocator);
+            try reply.expectOk();
+        }
+        if (self.options.database != 0) {
+            var digits: [10]u8 = undefined;
+            const database = std.fmt.bufPrint(&digits, "{d}", .{self.options.database}) catch unreachable;
+            var reply = try self.exchange(&.{ "SELECT", database });
+            defer reply.deinit(self.allocator);
+            try reply.expectOk();
+        }
+    }
+
+    /// Close the connection and discard anything buffered from it
+    fn disconnect(self: *RedisStorage) void {
+        if (self.connection) |connection| {
+            connection.close(self.allocator);
+            self.connection = null;
+        }
+        self.read_start = 0;
+        self.read_end = 0;
+    }
+
+    fn readReply(self: *RedisStorage) anyerror!Reply {
+        const line = try self.readLine();
+        if (line.len == 0) return error.StorageError;
+        const payload = line[1..];
+
+        switch (line[0]) {
+            '+' => return .{ .simple = try self.allocator.dupe(u8, payload) },
+            '-' => return .{ .err = try self.allocator.dupe(u8, payload) },
+            ':' => return .{ .integer = std.fmt.parseInt(i64, payload, 10) catch return error.StorageError },
+            '$' => {
+                const len = std.fmt.parseInt(i64, payload, 10) catch return error.StorageError;
+                if (len < 0) return .{ .bulk = null };
+                const data = try self.allocator.alloc(u8, @intCast(len));
+                errdefer self.allocator.free(data);
+                try self.readExact(data);
+
+                var crlf: [2]u8 = undefined;
+                try self.readExact(&crlf);
+                if (!std.mem.eql(u8, &crlf, "\r\n")) return error.StorageError;
+                return .{ .bulk = data };
+            },
+            '*' => {
+                const count = std.fmt.parseInt(i64, payload, 10) catch return error.StorageError;
+                if (count < 0) return .{ .array = &.{} };
+                const items = try self.allocator.alloc(Reply, @intCast(count));
+                var parsed: usize = 0;
+                errdefer {
+                    for (items[0..parsed]) |*item| item.deinit(self.allocator);
+                    self.allocator.free(items);
+                }
+                while (parsed < items.len) : (parsed += 1) items[parsed] = try self.readReply();
+                return .{ .array = items };
+            },
+            else => return error.StorageError,
+        }
+    }
+
+    /// Next CRLF-terminated line, without the CRLF; valid until the next read
+    fn readLine(self: *RedisStorage) ![]const u8 {
+        while (true) {
+            const buffered = self.read_buf[self.read_start..self.read_end];
+            if (std.mem.indexOf(u8, buffered, "\r\n")) |end| {
+                self.read_start += end + 2;
+                return buffered[0..end];
+            }
+
+            // Make room behind the partial line
+            std.mem.copyForwards(u8, &self.read_buf, buffered);
+            self.read_end = buffered.len;
+            self.read_start = 0;
+            if (self.read_end == self.read_buf.len) return error.StorageError;
+            try self.fill();
+        }
+    }
+
+    fn readExact(self: *RedisStorage, dest: []u8) !void {
+        var copied: usize = 0;
+        while (copied < dest.len) {
+            if (self.read_start == self.read_end) {
+                self.read_start = 0;
+                self.read_end = 0;
+                try self.fill();
+            }
+            const n = @min(dest.len - copied, self.read_end - self.read_start);
+            @memcpy(dest[copied..][0..n], self.read_buf[self.read_start..][0..n]);
+            self.read_start += n;
+            copied += n;
+        }
+    }
+
+    fn fill(self: *RedisStorage) !void {
+        const connection = self.connection orelse return error.ConnectionFailed;
+        const n = try connection.read(self.read_buf[self.read_end..]);
+        self.read_end += n;
+    }
+};
+
+fn dupeOptions(allocator: Allocator, options: RedisStorage.Options) !RedisStorage.Options {
+    var owned = options;
+    owned.host = try allocator.dupe(u8, options.host);
+    errdefer allocator.free(owned.host);
+    owned.key_prefix = try allocator.dupe(u8, options.key_prefix);
+    errdefer allocator.free(owned.key_prefix);
+    owned.username = if (options.username) |username| try allocator.dupe(u8, username) else null;
+    errdefer if (owned.username) |username| allocator.free(username);
+    owned.password = if (options.password) |password| try allocator.dupe(u8, password) else null;
+    return owned;
+}
+
+fn freeOptions(allocator: Allocator, options: RedisStorage.Options) void {
+    allocator.free(options.host);
+    allocator.free(options.key_prefix);
+    if (options.username) |username| allocator.free(username);
+    if (options.password) |password| {
+        std.crypto.secureZero(u8, @constCast(password));
+        allocator.free(password);
+    }
+}
+
+/// One TCP connection to the server, optionally wrapped in TLS
+///
+/// Heap-allocated because the TLS client points into its own buffers.
+const Connection = struct {
+    stream: std.net.Stream,
+    tls: ?Tls = null,
+
+    const Tls = struct {
+        stream_reader: std.net.Stream.Reader,
+        stream_writer: std.net.Stream.Writer,
+        client: std.crypto.tls.Client,
+        socket_read_buf: [std.crypto.tls.Client.min_buffer_len]u8,
+        socket_write_buf: [std.crypto.tls.Client.min_buffer_len]u8,
+        read_buf: [std.crypto.tls.Client.min_buffer_len]u8,
+        write_buf: [std.crypto.tls.Client.min_buffer_len]u8,
+    };
+
+    fn open(allocator: Allocator, options: RedisStorage.Options, ca_bundle: ?std.crypto.Certificate.Bundle) !*Connection {
+        const stream = std.net.tcpConnectToHost(allocator, options.host, options.port) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.ConnectionFailed,
+        };
+        errdefer stream.close();
+
+        const self = try allocator.create(Connection);
+        errdefer allocator.destroy(self);
+        self.* = .{ .stream = stream };
+
+        if (ca_bundle) |bundle| {
+            self.tls = @as(Tls, undefined);
+            const tls = &self.tls.?;
+            tls.stream_reader = stream.reader(&tls.socket_read_buf);
+            tls.stream_writer = stream.writer(&tls.socket_write_buf);
+            tls.client = std.crypto.tls.Client.init(tls.stream_reader.interface(), &tls.stream_writer.interface, .{
+                .host = .{ .explicit = options.host },
+                .ca = .{ .bundle = bundle },
+                .read_buffer = &tls.read_buf,
+                .write_buffer = &tls.write_buf,
+            }) catch return error.ConnectionFailed;
+        }
+        return self;
+    }
+
+    fn close(self: *Connection, allocator: Allocator) void {
+        self.stream.close();
+        allocator.destroy(self);
+    }
+
+    fn writeAll(self: *Connection, bytes: []const u8) !void {
+        if (self.tls) |*tls| {
+            tls.client.writer.writeAll(bytes) catch return error.ConnectionFailed;
+            tls.client.writer.flush() catch return error.ConnectionFailed;
+            tls.stream_writer.interface.flush() catch return error.ConnectionFailed;
+            return;
+        }
+        var written: usize = 0;
+        while (written < bytes.len) {
+            written += self.stream.write(bytes[written..]) catch return error.ConnectionFailed;
+        }
+    }
+
+    /// Read at least one byte into `dest`
+    fn read(self: *Connection, dest: []u8) !usize {
+        if (self.tls) |*tls| {
+            const reader = &tls.client.reader;
+            if (reader.bufferedLen() == 0) reader.fillMore() catch return error.ConnectionFailed;
+            const n = @min(dest.len, reader.bufferedLen());
+            @memcpy(dest[0..n], reader.buffered()[0..n]);
+            reader.toss(n);
+            return n;
+        }
+        const n = self.stream.read(dest) catch return error.ConnectionFailed;
+        if (n == 0) return error.ConnectionFailed;
+        return n;
+    }
+};
+
+/// Parsed RESP reply
+const Reply = union(enum) {
+    simple: []u8,
+    err: []u8,
+    integer: i64,
+    bulk: ?[]u8,
+    array: []Reply,
+
+    fn deinit(self: *Reply, allocator: Allocator) void {
+        switch (self.*) {
+            .simple, .err => |text| allocator.free(text),
+            .integer => {},
+            .bulk => |bulk| if (bulk) |data| {
+                std.crypto.secureZero(u8, data);
+                allocator.free(data);
+            },
+            .array => |items| {
+                for (items) |*item| item.deinit(allocator);
+                allocator.free(items);
+            },
+        }
+    }
+
+    fn expectOk(self: Reply) !void {
+        if (self == .simple and std.mem.eql(u8, self.simple, "OK")) return;
+        return replyError(self);
+    }
+};
+
+fn replyError(reply: Reply) error{StorageError} {
+    if (reply == .err) std.log.warn("Redis error: {s}", .{reply.err});
+    return error.StorageError;
+}
+
+/// RESP array of bulk strings
+fn encodeCommand(allocator: Allocator, args: []const []const u8) ![]u8 {
+    var out: std.ArrayListUnmanaged(u8) = .{};
+    errdefer out.deinit(allocator);
+
+    try out.writer(allocator).print("*{d}\r\n", .{args.len});
+    for (args) |arg| {
+        try out.writer(allocator).print("${d}\r\n", .{arg.len});
+        try out.appendSlice(allocator, arg);
+        try out.appendSlice(allocator, "\r\n");
+    }
+    return out.toOwnedSlice(allocator);
+}
+
+/// `MATCH` pattern for every key starting with `prefix`
+fn globPrefix(allocator: Allocator, prefix: []const u8) ![]u8 {
+    var out: std.ArrayListUnmanaged(u8) = .{};
+    errdefer out.deinit(allocator);
+
+    for (prefix) |ch| {
+        if (std.mem.indexOfScalar(u8, "*?[]\\", ch) != null) try out.append(allocator, '\\');
+        try out.append(allocator, ch);
+    }
+    try out.append(allocator, '*');
+    return out.toOwnedSlice(allocator);
+}
+
+/// Single-connection server answering each request with the next canned reply
+const TestRedisServer = struct {
+    server: std.net.Server,
+    replies: []const []const u8,
+    requests: std.ArrayListUnmanaged(u8) = .{},
+    allocator: Allocator,
+
+    fn run(self: *TestRedisServer) void {
+        const connection = self.server.accept() catch return;
+        defer connection.stream.close();
+
+        var buf: [4096]u8 = undefined;
+        for (self.replies) |reply| {
+            const n = connection.stream.read(&buf) catch return;
+            if (n == 0) return;
+            self.requests.appendSlice(self.allocator, buf[0..n]) catch return;
+            _ = connection.stream.write(reply) catch return;
+        }
+    }
+};
+
+test "RedisStorage reconnects after a connection fails mid-reply" {
+    const allocator = std.testing.allocator;
+
+    const Flaky = struct {
+        server: std.net.Server,
+
+        // The first connection sends half a reply, the second a full one
+        fn run(self: *@This()) void {
+            const replies = [_][]const u8{ "$10\r\nabc", "$-1\r\n" };
+            var buf: [4096]u8 = undefined;
+            for (replies) |reply| {
+                const connection = self.server.accept() catch return;
+                defer connection.stream.close();
+                _ = connection.stream.read(&buf) catch return;
+                _ = connection.stream.write(reply) catch return;
+            }
+        }
+    };
+
+    const address = try std.net.Address.parseIp("127.0.0.1", 0);
+    var flaky = Flaky{ .server = try address.listen(.{ .reuse_address = true }) };
+    defer flaky.server.deinit();
+
+    const thread = try std.Thread.spawn(.{}, Flaky.run, .{&flaky});
+    defer thread.join();
+
+    var redis = try RedisStorage.connect(allocator, .{ .port = flaky.server.listen_address.getPort() });
+    defer redis.deinit();
+    const store = redis.storage();
+
+    try std.testing.expectError(error.Io, store.load(allocator, "ci"));
+    try std.testing.expect(redis.connection == null);
+    try std.testing.expectEqual(@as(usize, 0), redis.read_end);
+
+    try std.testing.expect((try store.load(allocator, "ci")) == null);
+}
+
+test "RedisStorage stores tokens with a TTL unless they can be refreshed" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var token = try Token.initFull(allocator, "short-lived", "Bearer", null, 60, null, null);
+    defer token.deinit();
+    const data = try codec.default.encode(allocator, &token);
+    defer allocator.free(data);
+    const get_reply = try std.fmt.allocPrint(allocator, "${d}\r\n{s}\r\n", .{ data.len, data });
+    defer allocator.free(get_reply);
+
+    const address = try std.net.Address.parseIp("127.0.0.1", 0);
+    var fake = TestRedisServer{
+        .server = try address.listen(.{ .reuse_address = true }),
+        .replies = &.{ "+OK\r\n", get_reply, ":1\r\n", ":1\r\n", "$-1\r\n" },
+        .allocator = allocator,
+    };
+    defer fake.server.deinit();
+    defer fake.requests.deinit(allocator);
+
+    const thread = try std.Thread.spawn(.{}, TestRedisServer.run, .{&fake});
+    var redis = try RedisStorage.connect(allocator, .{ .port = fake.server.listen_address.getPort(), .ttl_grace = 30 });
+    defer redis.deinit();
+    const store = redis.storage();
+
+    try store.save("ci", token);
+    var loaded = (try store.load(allocator, "ci")).?;
+    defer loaded.deinit();
+    try std.testing.expectEqualStrings("short-lived", loaded.access_token);
+    try std.testing.expect(store.exists("ci"));
+    try store.delete("ci");
+    try std.testing.expect((try store.load(allocator, "ci")) == null);
+    thread.join();
+
+    // 60 seconds of lifetime plus 30 of grace
+    try std.testing.expect(std.mem.startsWith(u8, fake.requests.items, "*5\r\n$3\r\nSET\r\n$12\r\nschlussel:ci\r\n"));
+    try std.testing.expect(std.mem.indexOf(u8, fake.requests.items, "$2\r\nEX\r\n$2\r\n90\r\n") != null);
+    try std.testing.expect(std.mem.indexOf(u8, fake.requests.items, "*2\r\n$6\r\nEXISTS\r\n") != null);
+
+    const pattern = try globPrefix(allocator, "app[1]:");
+    defer allocator.free(pattern);
+    try std.testing.expectEqualStrings("app\\[1\\]:*", pattern);
+}
@@ -34,6 +34,11 @@ const json = std.json;
 const net = std.net;
 const Uri = std.Uri;
 
+const session = @import("session.zig");
+const oauth = @import("oauth.zig");
+
+const SessionStorage = session.SessionStorage;
+
 /// JSON parsing struct for client registration response
 const ClientRegistrationResponseJson = struct {
     client_id: []const u8,
@@ -166,6 +171,84 @@ pub const ClientRegistrationResponse = struct {
         if (self.registration_access_token) |s| self.allocator.free(s);
         if (self.registration_client_uri) |s| self.allocator.free(s);
     }
+
+    /// Persist the registration in `storage` under `key`
+    ///
+    /// The registration access token is stored as the token's access token
+    /// (it is a bearer token for the client configuration endpoint); the
+    /// client credentials travel in its metadata.
+    pub fn save(self: *const ClientRegistrationResponse, storage: SessionStorage, key: []const u8) !void {
+        const metadata = try json.Stringify.valueAlloc(self.allocator, ClientRegistrationResponseJson{
+            .client_id = self.client_id,
+            .client_secret = self.client_secret,
+            .client_id_issued_at = self.client_id_issued_at,
+            .client_secret_expires_at = self.client_secret_expires_at,
+            .registration_client_uri = self.registration_client_uri,
+        }, .{ .emit_null_optional_fields = false });
+        defer self.allocator.free(metadata);
+
+        var token = try session.Token.init(self.allocator, self.registration_access_token orelse "", "Bearer");
+        defer token.deinit();
+        token.metadata = try self.allocator.dupe(u8, metadata);
+
+        try storage.save(key, token);
+    }
+
+    /// Load a registration persisted with save()
+    ///
+    /// Returns null if nothing is stored under `key`, and
+    /// `error.InvalidParameter` if the entry is not a registration.
+    pub fn load(allocator: Allocator, storage: SessionStorage, key: []const u8) !?ClientRegistrationResponse {
+        var token = try storage.load(allocator, key) orelse return null;
+        defer token.deinit();
+
+        const metadata = token.metadata orelse return error.InvalidParameter;
+        var parsed = json.parseFromSlice(ClientRegistrationResponseJson, allocator, metadata, .{
+            .ignore_unknown_fields = true,
+        }) catch return error.InvalidParameter;
+        defer parsed.deinit();
+
+        var response = try fromJson(allocator, parsed.value);
+        errdefer response.deinit();
+        if (token.access_token.len > 0) {
+            response.registration_access_token = try allocator.dupe(u8, token.access_token);
+        }
+        return response;
+    }
+
+    /// `base` with the registered client credentials (borrowed from self)
+    pub fn configure(self: *const ClientRegistrationResponse, base: oauth.OAuthConfig) oauth.OAuthConfig {
+        var config = base;
+        config.client_id = self.client_id;
+        config.client_secret = self.client_secret;
+        return config;
+    }
+
+    /// Whether the client secret has expired (`client_secret_expires_at` of 0 means never)
+    pub fn secretExpired(self: *const ClientRegistrationResponse, now: i64) bool {
+        const expires_at = self.client_secret_expires_at orelse return false;
+        return expires_at != 0 and now >= expires_at;
+    }
+
+    fn fromJson(allocator: Allocator, value: ClientRegistrationResponseJson) !ClientRegistrationResponse {
+        const client_id = try allocator.dupe(u8, value.client_id);
+        errdefer allocator.free(client_id);
+        const client_secret = if (value.client_secret) |s| try allocator.dupe(u8, s) else null;
+        errdefer if (client_secret) |s| allocator.free(s);
+        const registration_access_token = if (value.registration_access_token) |s| try allocator.dupe(u8, s) else null;
+        errdefer if (registration_access_token) |s| allocator.free(s);
+        const registration_client_uri = if (value.registration_client_uri) |s| try allocator.dupe(u8, s) else null;
+
+        return .{
+            .allocator = allocator,
+            .client_id = client_id,
+            .client_secret = client_secret,
+            .client_id_issued_at = value.client_id_issued_at,
+            .client_secret_expires_at = value.client_secret_expires_at,
+            .registration_access_token = registration_access_token,
+            .registration_client_uri = registration_client_uri,
+        };
+    }
 };
 
 /// Dynamic client registration client
@@ -312,18 +395,13 @@ pub const DynamicRegistration = struct {
     }
 
     fn parseRegistrationResponse(self: *DynamicRegistration, response_body: []const u8) !ClientRegistrationResponse {
-        var parsed = try json.parseFromSlice(ClientRegistrationResponseJson, self.allocator, response_body, .{});
+        // Servers echo the registered metadata alongside the credentials
+        var parsed = try json.parseFromSlice(ClientRegistrationResponseJson, self.allocator, response_body, .{
+            .ignore_unknown_fields = true,
+        });
         defer parsed.deinit();
 
-        return ClientRegistrationResponse{
-            .allocator = self.allocator,
-            .client_id = try self.allocator.dupe(u8, parsed.value.client_id),
-            .client_secret = if (parsed.value.client_secret) |s| try self.allocator.dupe(u8, s) else null,
-            .client_id_issued_at = parsed.value.client_id_issued_at,
-            .client_secret_expires_at = parsed.value.client_secret_expires_at,
-            .registration_access_token = if (parsed.value.registration_access_token) |s| try self.allocator.dupe(u8, s) else null,
-            .registration_client_uri = if (parsed.value.registration_client_uri) |s| try self.allocator.dupe(u8, s) else null,
-        };
+        return ClientRegistrationResponse.fromJson(self.allocator, parsed.value);
     }
 
     /// Read client configuration from the registration endpoint
@@ -499,6 +577,41 @@ test "dynamic registration response parsing handles minimal fields" {
     try std.testing.expect(response.registration_client_uri == null);
 }
 
+test "registration persists in storage and rehydrates an OAuthConfig" {
+    const allocator = std.testing.allocator;
+
+    var storage = session.MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    var registration = try DynamicRegistration.init(allocator, "http://localhost/register");
+    defer registration.deinit();
+
+    var response = try registration.parseRegistrationResponse(
+        "{\"client_id\":\"dyn-client\",\"client_secret\":\"dyn-secret\",\"client_secret_expires_at\":2000000000," ++
+            "\"registration_access_token\":\"rat\",\"registration_client_uri\":\"https://example.com/clients/dyn\"," ++
+            "\"redirect_uris\":[\"http://127.0.0.1/callback\"]}",
+    );
+    defer response.deinit();
+
+    try std.testing.expect((try ClientRegistrationResponse.load(allocator, storage.storage(), "registration")) == null);
+    try response.save(storage.storage(), "registration");
+
+    var restored = (try ClientRegistrationResponse.load(allocator, storage.storage(), "registration")).?;
+    defer restored.deinit();
+
+    try std.testing.expectEqualStrings("dyn-client", restored.client_id);
+    try std.testing.expectEqualStrings("dyn-secret", restored.client_secret.?);
+    try std.testing.expectEqualStrings("rat", restored.registration_access_token.?);
+    try std.testing.expectEqualStrings("https://example.com/clients/dyn", restored.registration_client_uri.?);
+    try std.testing.expect(!restored.secretExpired(1_999_999_999));
+    try std.testing.expect(restored.secretExpired(2_000_000_000));
+
+    const config = restored.configure(oauth.OAuthConfig.github("placeholder", "repo"));
+    try std.testing.expectEqualStrings("dyn-client", config.client_id);
+    try std.testing.expectEqualStrings("dyn-secret", config.client_secret.?);
+    try std.testing.expectEqualStrings("repo", config.scope.?);
+}
+
 const TestRegistrationServer = struct {
     server: net.Server,
     port: u16,
@@ -0,0 +1,257 @@
+//! Linux Secret Service storage through libsecret
+//!
+//! `SecretServiceStorage` keeps every token in the user's default keyring
+//! (GNOME Keyring, KWallet) over the D-Bus Secret Service API, with the
+//! same `service` and `account` attributes SecureStorage writes through
+//! `secret-tool`, so both read each other's items. It needs libsecret and
+//! is only built with `-Dlibsecret=true`.
+//!
+//! Headless servers and containers usually run no Secret Service; init()
+//! then fails with `error.SecretServiceUnavailable` so callers can fall
+//! back to FileStorage.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var keyring = SecretServiceStorage.init(allocator, "dev.tuist.cli") catch |err| switch (err) {
+//!     error.SecretServiceUnavailable => return useFileStorage(),
+//!     else => return err,
+//! };
+//! defer keyring.deinit();
+//! ```
+
+const std = @import("std");
+const builtin = @import("builtin");
+const build_options = @import("build_options");
+const Allocator = std.mem.Allocator;
+
+const codec = @import("codec.zig");
+const session = @import("session.zig");
+
+const Token = session.Token;
+const SessionStorage = session.SessionStorage;
+const StorageCapabilities = session.StorageCapabilities;
+
+/// Whether libsecret is linked on the target
+pub const supported = builtin.os.tag == .linux and build_options.libsecret;
+
+/// Token storage in the Secret Service keyring
+pub const SecretServiceStorage = struct {
+    allocator: Allocator,
+    service_name: [:0]const u8,
+
+    /// Connect to the Secret Service and open a session with it
+    ///
+    /// The default keyring is not unlocked here: if it is locked, the
+    /// service prompts the user on the first save or load. Returns
+    /// `error.SecretServiceUnavailable` when none is running and
+    /// `error.UnsupportedOperation` in builds without libsecret.
+    pub fn init(allocator: Allocator, service_name: []const u8) !SecretServiceStorage {
+        if (!supported) return error.UnsupportedOperation;
+        if (service_name.len == 0) return error.InvalidParameter;
+        try libsecret.connect();
+        return .{
+            .allocator = allocator,
+            .service_name = try allocator.dupeZ(u8, service_name),
+        };
+    }
+
+    pub fn deinit(self: *SecretServiceStorage) void {
+        self.allocator.free(self.service_name);
+    }
+
+    pub fn storage(self: *SecretServiceStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+                .capabilities = capabilities,
+            }),
+        };
+    }
+
+    fn capabilities(_: *anyopaque) StorageCapabilities {
+        // The Secret Service replaces the secret of a matching item in place
+        return .{ .atomic_swap = true, .os_protected = true };
+    }
+
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *SecretServiceStorage = @ptrCast(@alignCast(ptr));
+
+        const account = try self.allocator.dupeZ(u8, key);
+        defer self.allocator.free(account);
+
+        const data = try codec.default.encode(self.allocator, &token);
+        defer {
+            @memset(data, 0);
+            self.allocator.free(data);
+        }
+        const secret = try self.allocator.dupeZ(u8, data);
+        defer {
+            @memset(secret, 0);
+            self.allocator.free(secret);
+        }
+
+        try libsecret.store(self.service_name, account, secret);
+    }
+
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *SecretServiceStorage = @ptrCast(@alignCast(ptr));
+
+        const account = try self.allocator.dupeZ(u8, key);
+        defer self.allocator.free(account);
+
+        const secret = (try libsecret.lookup(self.service_name, account)) orelse return null;
+        defer libsecret.secret_password_free(secret);
+
+        return try codec.default.decode(allocator, std.mem.span(secret));
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *SecretServiceStorage = @ptrCast(@alignCast(ptr));
+
+        const account = try self.allocator.dupeZ(u8, key);
+        defer self.allocator.free(account);
+
+        try libsecret.clear(self.service_name, account);
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        if (!supported) return false;
+        const self: *SecretServiceStorage = @ptrCast(@alignCast(ptr));
+
+        const account = self.allocator.dupeZ(u8, key) catch return false;
+        defer self.allocator.free(account);
+
+        const secret = (libsecret.lookup(self.service_name, account) catch return false) orelse return false;
+        libsecret.secret_password_free(secret);
+        return true;
+    }
+};
+
+/// Password items through the libsecret C API
+const libsecret = struct {
+    const GError = extern struct {
+        domain: u32,
+        code: c_int,
+        message: ?[*:0]u8,
+    };
+
+    const SchemaAttribute = extern struct {
+        name: ?[*:0]const u8,
+        type: c_int,
+    };
+
+    const Schema = extern struct {
+        name: [*:0]const u8,
+        flags: c_int,
+        attributes: [32]SchemaAttribute,
+        reserved: c_int = 0,
+        reserved1: ?*anyopaque = null,
+        reserved2: ?*anyopaque = null,
+        reserved3: ?*anyopaque = null,
+        reserved4: ?*anyopaque = null,
+        reserved5: ?*anyopaque = null,
+        reserved6: ?*anyopaque = null,
+        reserved7: ?*anyopaque = null,
+    };
+
+    const schema_dont_match_name: c_int = 1 << 1;
+    const service_open_session: c_int = 1 << 1;
+    const service_load_collections: c_int = 1 << 2;
+    const attribute_string: c_int = 0;
+    const collection_default: [*:0]const u8 = "default";
+    const service_attribute: [*:0]const u8 = "service";
+    const account_attribute: [*:0]const u8 = "account";
+    const no_attribute: ?[*:0]const u8 = null;
+
+    /// Matches items regardless of schema name, like `secret-tool` does
+    const schema: Schema = blk: {
+        var attributes = [_]SchemaAttribute{.{ .name = null, .type = 0 }} ** 32;
+        attributes[0] = .{ .name = service_attribute, .type = attribute_string };
+        attributes[1] = .{ .name = account_attribute, .type = attribute_string };
+        break :blk .{
+            .name = "dev.schlussel.Token",
+            .flags = schema_dont_match_name,
+            .attributes = attributes,
+        };
+    };
+
+    extern fn secret_service_get_sync(flags: c_int, cancellable: ?*anyopaque, err: *?*GError) ?*anyopaque;
+    extern fn secret_password_store_sync(
+        schema: *const Schema,
+        collection: ?[*:0]const u8,
+        label: [*:0]const u8,
+        password: [*:0]const u8,
+        cancellable: ?*anyopaque,
+        err: *?*GError,
+        ...,
+    ) c_int;
+    extern fn secret_password_lookup_sync(schema: *const Schema, cancellable: ?*anyopaque, err: *?*GError, ...) ?[*:0]u8;
+    extern fn secret_password_clear_sync(schema: *const Schema, cancellable: ?*anyopaque, err: *?*GError, ...) c_int;
+    extern fn secret_password_free(password: [*:0]u8) void;
+    extern fn g_error_free(err: *GError) void;
+    extern fn g_object_unref(object: *anyopaque) void;
+
+    /// Fail with `error.SecretServiceUnavailable` unless a Secret Service answers
+    fn connect() !void {
+        var err: ?*GError = null;
+        const service = secret_service_get_sync(service_open_session | service_load_collections, null, &err) orelse {
+            if (err) |e| g_error_free(e);
+            return error.SecretServiceUnavailable;
+        };
+        g_object_unref(service);
+    }
+
+    fn store(service: [*:0]const u8, account: [*:0]const u8, secret: [*:0]const u8) !void {
+        var err: ?*GError = null;
+        const stored = secret_password_store_sync(&schema, collection_default, service, secret, null, &err, service_attribute, service, account_attribute, account, no_attribute);
+        try check(err);
+        if (stored == 0) return error.StorageError;
+    }
+
+    /// Secret stored for `account`, or null; free it with secret_password_free()
+    fn lookup(service: [*:0]const u8, account: [*:0]const u8) !?[*:0]u8 {
+        var err: ?*GError = null;
+        const secret = secret_password_lookup_sync(&schema, null, &err, service_attribute, service, account_attribute, account, no_attribute);
+        try check(err);
+        return secret;
+    }
+
+    /// Delete the item for `account`; a missing item is not an error
+    fn clear(service: [*:0]const u8, account: [*:0]const u8) !void {
+        var err: ?*GError = null;
+        _ = secret_password_clear_sync(&schema, null, &err, service_attribute, service, account_attribute, account, no_attribute);
+        try check(err);
+    }
+
+    fn check(err: ?*GError) !void {
+        const e = err orelse return;
+        defer g_error_free(e);
+        if (e.message) |message| std.log.warn("Secret Service error: {s}", .{message});
+        return error.StorageError;
+    }
+};
+
+test "SecretServiceStorage.init needs libsecret" {
+    const allocator = std.testing.allocator;
+
+    if (!supported) {
+        try std.testing.expectError(error.UnsupportedOperation, SecretServiceStorage.init(allocator, "dev.schlussel.test"));
+        return;
+    }
+
+    try std.testing.expectError(error.InvalidParameter, SecretServiceStorage.init(allocator, ""));
+    var keyring = SecretServiceStorage.init(allocator, "dev.schlussel.test") catch |err| switch (err) {
+        error.SecretServiceUnavailable => return error.SkipZigTest,
+        else => return err,
+    };
+    defer keyring.deinit();
+    try std.testing.expect(keyring.storage().capabilities().atomic_swap);
+}
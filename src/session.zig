@@ -6,8 +6,20 @@
 //! ## Storage Backends
 //!
 //! - `MemoryStorage`: In-memory storage for testing
-//! - `FileStorage`: JSON file-based storage for development
+//! - `FileStorage`: JSON file-based storage for development, optionally encrypted
 //! - `SecureStorage`: OS credential manager (Keychain, Credential Manager, Secret Service)
+//! - `KeychainStorage` (keychain.zig): macOS Keychain through the Security framework
+//! - `CredentialManagerStorage` (wincred.zig): Windows Credential Manager
+//! - `SecretServiceStorage` (secret_service.zig): Linux Secret Service through libsecret
+//! - `KeyringStorage` (keyring.zig): whichever of the above the platform offers
+//! - `SqliteStorage` (sqlite.zig): one SQLite database for many tokens
+//! - `RedisStorage` (redis.zig): tokens shared by several service instances
+//! - `VaultStorage` (vault.zig): HashiCorp Vault KV v2 secrets
+//! - `AwsSecretsStorage` (aws_secrets.zig): AWS Secrets Manager, with `-Daws-secrets=true`
+//! - `ChainedStorage`: several of the above, tried in order
+//! - `NamespacedStorage`: a tenant or profile's keys inside another storage
+//! - `EnvStorage`: read-only tokens from environment variables, for CI
+//! - `CachedStorage`: in-memory cache in front of a slow backend
 //!
 //! ## Example
 //!
@@ -27,6 +39,10 @@ const fs = std.fs;
 const mem = std.mem;
 const Allocator = std.mem.Allocator;
 
+const clock = @import("clock.zig");
+const codec = @import("codec.zig");
+const lock = @import("lock.zig");
+
 /// Cross-platform helper to get environment variable
 /// Returns null if not found
 fn getEnvVar(allocator: Allocator, name: []const u8) ?[]const u8 {
@@ -49,10 +65,34 @@ pub const Token = struct {
     expires_in: ?u64 = null,
     /// Absolute expiration timestamp (Unix seconds)
     expires_at: ?u64 = null,
+    /// Server clock minus local clock, in seconds, measured when the token
+    /// was issued (see OAuthClient.withServerClock)
+    ///
+    /// Expiry checks add this to the local time. The offset is only as fresh
+    /// as the response it came from: if the local clock is adjusted later,
+    /// decisions stay skewed until the token is refreshed and re-measured.
+    clock_offset: i64 = 0,
+    /// Start of the access token's validity (`nbf` of a JWT access token)
+    not_before: ?u64 = null,
+    /// When the access token was issued (`iat` of a JWT access token)
+    issued_at: ?u64 = null,
     /// Space-separated list of scopes
     scope: ?[]const u8 = null,
     /// ID token for OpenID Connect
     id_token: ?[]const u8 = null,
+    /// Application-defined metadata (opaque string, e.g. set by a token transform)
+    metadata: ?[]const u8 = null,
+    /// Masked record of the request that issued this token, as a JSON
+    /// document (see OAuthClient.withRequestAudit)
+    request_audit: ?[]const u8 = null,
+    /// DPoP private key the token is bound to (see dpop.zig)
+    dpop_key: ?[]const u8 = null,
+    /// Authorization details the server granted (RFC 9396), as a JSON array
+    authorization_details: ?[]const u8 = null,
+    /// Resource indicator (RFC 8707) the access token was requested for
+    resource: ?[]const u8 = null,
+    /// Audience the token was requested for (Auth0/Okta `audience`)
+    audience: ?[]const u8 = null,
 
     /// Create a new token with the minimum required fields
     pub fn init(allocator: Allocator, access_token: []const u8, token_type: []const u8) !Token {
@@ -73,7 +113,7 @@ pub const Token = struct {
         scope: ?[]const u8,
         id_token: ?[]const u8,
     ) !Token {
-        const now = @as(u64, @intCast(std.time.timestamp()));
+        const now = clock.now();
 
         return .{
             .allocator = allocator,
@@ -94,6 +134,12 @@ pub const Token = struct {
         if (self.refresh_token) |rt| self.allocator.free(rt);
         if (self.scope) |s| self.allocator.free(s);
         if (self.id_token) |id| self.allocator.free(id);
+        if (self.metadata) |m| self.allocator.free(m);
+        if (self.request_audit) |a| self.allocator.free(a);
+        if (self.dpop_key) |k| self.allocator.free(k);
+        if (self.authorization_details) |d| self.allocator.free(d);
+        if (self.resource) |r| self.allocator.free(r);
+        if (self.audience) |aud| self.allocator.free(aud);
     }
 
     /// Clone this token
@@ -112,6 +158,24 @@ pub const Token = struct {
         errdefer if (scope) |s| allocator.free(s);
 
         const id_token = if (self.id_token) |id| try allocator.dupe(u8, id) else null;
+        errdefer if (id_token) |id| allocator.free(id);
+
+        const metadata = if (self.metadata) |m| try allocator.dupe(u8, m) else null;
+        errdefer if (metadata) |m| allocator.free(m);
+
+        const request_audit = if (self.request_audit) |a| try allocator.dupe(u8, a) else null;
+        errdefer if (request_audit) |a| allocator.free(a);
+
+        const dpop_key = if (self.dpop_key) |k| try allocator.dupe(u8, k) else null;
+        errdefer if (dpop_key) |k| allocator.free(k);
+
+        const authorization_details = if (self.authorization_details) |d| try allocator.dupe(u8, d) else null;
+        errdefer if (authorization_details) |d| allocator.free(d);
+
+        const resource = if (self.resource) |r| try allocator.dupe(u8, r) else null;
+        errdefer if (resource) |r| allocator.free(r);
+
+        const audience = if (self.audience) |aud| try allocator.dupe(u8, aud) else null;
         // No errdefer for last allocation - success path
 
         return .{
@@ -121,15 +185,29 @@ pub const Token = struct {
             .refresh_token = refresh_token,
             .expires_in = self.expires_in,
             .expires_at = self.expires_at,
+            .clock_offset = self.clock_offset,
+            .not_before = self.not_before,
+            .issued_at = self.issued_at,
             .scope = scope,
             .id_token = id_token,
+            .metadata = metadata,
+            .request_audit = request_audit,
+            .dpop_key = dpop_key,
+            .authorization_details = authorization_details,
+            .resource = resource,
+            .audience = audience,
         };
     }
 
+    /// Current time in the token's clock frame (local time plus clock_offset)
+    fn currentTime(self: *const Token) u64 {
+        return clock.nowWithOffset(self.clock_offset);
+    }
+
     /// Check if the token is expired
     pub fn isExpired(self: *const Token) bool {
         if (self.expires_at) |expires_at| {
-            const now = @as(u64, @intCast(std.time.timestamp()));
+            const now = self.currentTime();
             return now >= expires_at;
         }
         return false;
@@ -138,7 +216,7 @@ pub const Token = struct {
     /// Check if the token expires within the given number of seconds
     pub fn expiresWithin(self: *const Token, seconds: u64) bool {
         if (self.expires_at) |expires_at| {
-            const now = @as(u64, @intCast(std.time.timestamp()));
+            const now = self.currentTime();
             return now + seconds >= expires_at;
         }
         return false;
@@ -152,7 +230,7 @@ pub const Token = struct {
 
         const expires_at = self.expires_at.?;
         const expires_in = self.expires_in.?;
-        const now = @as(u64, @intCast(std.time.timestamp()));
+        const now = self.currentTime();
 
         if (now >= expires_at) return 0.0;
 
@@ -160,6 +238,44 @@ pub const Token = struct {
         return @as(f64, @floatFromInt(remaining)) / @as(f64, @floatFromInt(expires_in));
     }
 
+    /// Offline estimate of whether a token can be used right now
+    pub const OfflineUsability = union(enum) {
+        /// Not expired; `remaining` seconds of lifetime left
+        usable: struct { remaining: u64 },
+        /// Not valid before `not_before`, `in` seconds from now
+        not_yet_valid: struct { in: u64 },
+        /// Expired `since` seconds ago
+        stale: struct { since: u64 },
+        /// No expiry information, or the clock contradicts it
+        unknown,
+    };
+
+    /// Estimate, without any network access, whether the token is usable now
+    ///
+    /// Pure function of the stored expiry, `not_before`, `issued_at` and
+    /// the (offset-corrected) clock. When the issue time (`issued_at`, else
+    /// `expires_at - expires_in`) lies in the future, the local clock has
+    /// moved backwards since the token was issued and the result is
+    /// `.unknown` rather than a misleading estimate.
+    pub fn offlineUsability(self: *const Token) OfflineUsability {
+        const expires_at = self.expires_at orelse return .unknown;
+        const now = self.currentTime();
+
+        const issued_at: ?u64 = self.issued_at orelse derived: {
+            const lifetime = self.expires_in orelse break :derived null;
+            break :derived if (lifetime <= expires_at) expires_at - lifetime else null;
+        };
+        if (issued_at) |iat| {
+            if (now < iat) return .unknown;
+        }
+
+        if (now >= expires_at) return .{ .stale = .{ .since = now - expires_at } };
+        if (self.not_before) |nbf| {
+            if (now < nbf) return .{ .not_yet_valid = .{ .in = nbf - now } };
+        }
+        return .{ .usable = .{ .remaining = expires_at - now } };
+    }
+
     /// Serialize token to JSON
     pub fn toJson(self: *const Token, allocator: Allocator) ![]u8 {
         var buf: std.ArrayListUnmanaged(u8) = .{};
@@ -187,6 +303,21 @@ pub const Token = struct {
             try buf.writer(allocator).print("{d}", .{exp});
         }
 
+        if (self.clock_offset != 0) {
+            try buf.appendSlice(allocator, ",\"clock_offset\":");
+            try buf.writer(allocator).print("{d}", .{self.clock_offset});
+        }
+
+        if (self.not_before) |nbf| {
+            try buf.appendSlice(allocator, ",\"not_before\":");
+            try buf.writer(allocator).print("{d}", .{nbf});
+        }
+
+        if (self.issued_at) |iat| {
+            try buf.appendSlice(allocator, ",\"issued_at\":");
+            try buf.writer(allocator).print("{d}", .{iat});
+        }
+
         if (self.scope) |s| {
             try buf.appendSlice(allocator, ",\"scope\":\"");
             try appendJsonEscaped(allocator, &buf, s);
@@ -199,10 +330,132 @@ pub const Token = struct {
             try buf.append(allocator, '"');
         }
 
+        if (self.metadata) |m| {
+            try buf.appendSlice(allocator, ",\"metadata\":\"");
+            try appendJsonEscaped(allocator, &buf, m);
+            try buf.append(allocator, '"');
+        }
+
+        if (self.request_audit) |a| {
+            try buf.appendSlice(allocator, ",\"request_audit\":\"");
+            try appendJsonEscaped(allocator, &buf, a);
+            try buf.append(allocator, '"');
+        }
+
+        if (self.dpop_key) |k| {
+            try buf.appendSlice(allocator, ",\"dpop_key\":\"");
+            try appendJsonEscaped(allocator, &buf, k);
+            try buf.append(allocator, '"');
+        }
+
+        if (self.authorization_details) |d| {
+            try buf.appendSlice(allocator, ",\"authorization_details\":\"");
+            try appendJsonEscaped(allocator, &buf, d);
+            try buf.append(allocator, '"');
+        }
+
+        if (self.resource) |r| {
+            try buf.appendSlice(allocator, ",\"resource\":\"");
+            try appendJsonEscaped(allocator, &buf, r);
+            try buf.append(allocator, '"');
+        }
+
+        if (self.audience) |aud| {
+            try buf.appendSlice(allocator, ",\"audience\":\"");
+            try appendJsonEscaped(allocator, &buf, aud);
+            try buf.append(allocator, '"');
+        }
+
         try buf.append(allocator, '}');
         return buf.toOwnedSlice(allocator);
     }
 
+    /// Shell dialect for toEnvExports()
+    pub const EnvDialect = enum {
+        /// POSIX shells (sh, bash, zsh): `export NAME='value'`
+        posix,
+        /// PowerShell: `$env:NAME = 'value'`
+        powershell,
+    };
+
+    /// Format the token as environment variable assignments for `eval`
+    ///
+    /// Emits `<PREFIX>_ACCESS_TOKEN`, `<PREFIX>_TOKEN_TYPE` and, when known,
+    /// `<PREFIX>_EXPIRES_AT`, one per line. Values are single-quoted so they
+    /// are never expanded by the shell. `prefix` must be a valid variable
+    /// name made of letters, digits and underscores.
+    pub fn toEnvExports(self: *const Token, allocator: Allocator, prefix: []const u8, dialect: EnvDialect) ![]u8 {
+        try validateEnvPrefix(prefix);
+
+        var buf: std.ArrayListUnmanaged(u8) = .{};
+        errdefer buf.deinit(allocator);
+
+        try appendEnvAssignment(allocator, &buf, dialect, prefix, "ACCESS_TOKEN", self.access_token);
+        try appendEnvAssignment(allocator, &buf, dialect, prefix, "TOKEN_TYPE", self.token_type);
+
+        if (self.expires_at) |exp| {
+            var digits: [20]u8 = undefined;
+            const value = std.fmt.bufPrint(&digits, "{d}", .{exp}) catch unreachable;
+            try appendEnvAssignment(allocator, &buf, dialect, prefix, "EXPIRES_AT", value);
+        }
+
+        return buf.toOwnedSlice(allocator);
+    }
+
+    fn validateEnvPrefix(prefix: []const u8) !void {
+        if (prefix.len == 0) return error.InvalidParameter;
+        if (std.ascii.isDigit(prefix[0])) return error.InvalidParameter;
+        for (prefix) |c| {
+            if (!std.ascii.isAlphanumeric(c) and c != '_') return error.InvalidParameter;
+        }
+    }
+
+    fn appendEnvAssignment(
+        allocator: Allocator,
+        buf: *std.ArrayListUnmanaged(u8),
+        dialect: EnvDialect,
+        prefix: []const u8,
+        name: []const u8,
+        value: []const u8,
+    ) !void {
+        switch (dialect) {
+            .posix => try buf.appendSlice(allocator, "export "),
+            .powershell => try buf.appendSlice(allocator, "$env:"),
+        }
+        try buf.appendSlice(allocator, prefix);
+        try buf.append(allocator, '_');
+        try buf.appendSlice(allocator, name);
+        try buf.appendSlice(allocator, if (dialect == .posix) "='" else " = '");
+
+        var i: usize = 0;
+        while (i < value.len) : (i += 1) {
+            const c = value[i];
+            switch (dialect) {
+                // Close the quote, emit an escaped quote, reopen: ' -> '\''
+                .posix => if (c == '\'') {
+                    try buf.appendSlice(allocator, "'\\''");
+                } else {
+                    try buf.append(allocator, c);
+                },
+                // Quotes are escaped by doubling. PowerShell also treats the
+                // typographic single quotes U+2018..U+201B as quote characters.
+                .powershell => if (c == '\'') {
+                    try buf.appendSlice(allocator, "''");
+                } else if (c == 0xE2 and i + 2 < value.len and value[i + 1] == 0x80 and
+                    value[i + 2] >= 0x98 and value[i + 2] <= 0x9B)
+                {
+                    try buf.appendSlice(allocator, value[i .. i + 3]);
+                    try buf.appendSlice(allocator, value[i .. i + 3]);
+                    i += 2;
+                } else {
+                    try buf.append(allocator, c);
+                },
+            }
+        }
+
+        try buf.appendSlice(allocator, "'\n");
+    }
+
     /// Deserialize token from JSON
     pub fn fromJson(allocator: Allocator, json_data: []const u8) !Token {
         const parsed = try json.parseFromSlice(json.Value, allocator, json_data, .{});
@@ -255,6 +508,12 @@ pub const Token = struct {
             }
         }
 
+        inline for (.{ .{ "not_before", &token.not_before }, .{ "issued_at", &token.issued_at } }) |field| {
+            if (obj.get(field[0])) |value| {
+                if (value == .integer and value.integer >= 0) field[1].* = @as(u64, @intCast(value.integer));
+            }
+        }
+
         if (obj.get("scope")) |s| {
             if (s == .string) {
                 token.scope = try allocator.dupe(u8, s.string);
@@ -267,8 +526,53 @@ pub const Token = struct {
             }
         }
 
+        if (obj.get("metadata")) |m| {
+            if (m == .string) {
+                token.metadata = try allocator.dupe(u8, m.string);
+            }
+        }
+
+        if (obj.get("request_audit")) |a| {
+            if (a == .string) {
+                token.request_audit = try allocator.dupe(u8, a.string);
+            }
+        }
+
+        if (obj.get("dpop_key")) |k| {
+            if (k == .string) {
+                token.dpop_key = try allocator.dupe(u8, k.string);
+            }
+        }
+
+        // Stored as a string; token responses carry the array itself
+        if (obj.get("authorization_details")) |d| {
+            switch (d) {
+                .string => |raw| token.authorization_details = try allocator.dupe(u8, raw),
+                .array => token.authorization_details = try json.Stringify.valueAlloc(allocator, d, .{}),
+                else => {},
+            }
+        }
+
+        if (obj.get("resource")) |r| {
+            if (r == .string) {
+                token.resource = try allocator.dupe(u8, r.string);
+            }
+        }
+
+        if (obj.get("audience")) |aud| {
+            if (aud == .string) {
+                token.audience = try allocator.dupe(u8, aud.string);
+            }
+        }
+
+        if (obj.get("clock_offset")) |offset| {
+            if (offset == .integer) {
+                token.clock_offset = offset.integer;
+            }
+        }
+
         if (token.expires_at == null and token.expires_in != null) {
-            const now = @as(u64, @intCast(std.time.timestamp()));
+            const now = clock.now();
             token.expires_at = now + token.expires_in.?;
         }
 
@@ -276,6 +580,12 @@ pub const Token = struct {
     }
 };
 
+/// Query parameter added to an authorization request
+pub const AuthorizationParam = struct {
+    name: []const u8,
+    value: []const u8,
+};
+
 /// Session containing authentication state
 pub const Session = struct {
     allocator: Allocator,
@@ -287,6 +597,12 @@ pub const Session = struct {
     created_at: u64,
     /// Last activity timestamp
     last_used_at: u64,
+    /// Space-separated scopes requested when authorizing this session
+    requested_scope: ?[]const u8 = null,
+    /// OIDC nonce sent with the authorization request, checked on callback
+    nonce: ?[]const u8 = null,
+    /// Extra authorization request parameters, in the order they were sent
+    authorization_params: []const AuthorizationParam = &.{},
 
     pub fn init(allocator: Allocator, domain: []const u8) !Session {
         const now = @as(u64, @intCast(std.time.timestamp()));
@@ -301,6 +617,99 @@ pub const Session = struct {
     pub fn deinit(self: *Session) void {
         self.allocator.free(self.domain);
         if (self.token) |*t| t.deinit();
+        if (self.requested_scope) |s| self.allocator.free(s);
+        if (self.nonce) |n| self.allocator.free(n);
+        freeAuthorizationParams(self.allocator, self.authorization_params);
+    }
+
+    /// Record the scopes requested for this session (copied)
+    pub fn setRequestedScope(self: *Session, scope: ?[]const u8) !void {
+        const copy = if (scope) |s| try self.allocator.dupe(u8, s) else null;
+        if (self.requested_scope) |s| self.allocator.free(s);
+        self.requested_scope = copy;
+    }
+
+    /// Record the nonce sent for this session (copied)
+    ///
+    /// Pass `AuthorizationFlow.sentNonce()` so the ID token returned on
+    /// callback can be matched against it.
+    pub fn setNonce(self: *Session, nonce: ?[]const u8) !void {
+        const copy = if (nonce) |n| try self.allocator.dupe(u8, n) else null;
+        if (self.nonce) |n| self.allocator.free(n);
+        self.nonce = copy;
+    }
+
+    /// Record the extra parameters sent for this session (copied)
+    pub fn setAuthorizationParams(self: *Session, params: []const AuthorizationParam) !void {
+        const copy = try self.allocator.alloc(AuthorizationParam, params.len);
+        var copied: usize = 0;
+        errdefer {
+            for (copy[0..copied]) |param| {
+                self.allocator.free(param.name);
+                self.allocator.free(param.value);
+            }
+            self.allocator.free(copy);
+        }
+        for (params) |param| {
+            const name = try self.allocator.dupe(u8, param.name);
+            errdefer self.allocator.free(name);
+            copy[copied] = .{ .name = name, .value = try self.allocator.dupe(u8, param.value) };
+            copied += 1;
+        }
+
+        freeAuthorizationParams(self.allocator, self.authorization_params);
+        self.authorization_params = copy;
+    }
+
+    fn freeAuthorizationParams(allocator: Allocator, params: []const AuthorizationParam) void {
+        for (params) |param| {
+            allocator.free(param.name);
+            allocator.free(param.value);
+        }
+        allocator.free(params);
+    }
+
+    const Record = struct {
+        domain: []const u8,
+        created_at: u64,
+        last_used_at: u64,
+        requested_scope: ?[]const u8 = null,
+        nonce: ?[]const u8 = null,
+        authorization_params: []const AuthorizationParam = &.{},
+    };
+
+    /// Serialize the authorization state so a callback handled by another
+    /// process (or after a restart) can still check scopes and the nonce
+    ///
+    /// The active token is not included; keep it in a SessionStorage.
+    pub fn toJson(self: *const Session, allocator: Allocator) ![]u8 {
+        return json.Stringify.valueAlloc(allocator, Record{
+            .domain = self.domain,
+            .created_at = self.created_at,
+            .last_used_at = self.last_used_at,
+            .requested_scope = self.requested_scope,
+            .nonce = self.nonce,
+            .authorization_params = self.authorization_params,
+        }, .{ .emit_null_optional_fields = false });
+    }
+
+    /// Restore a session written by toJson()
+    pub fn fromJson(allocator: Allocator, data: []const u8) !Session {
+        const parsed = json.parseFromSlice(Record, allocator, data, .{ .ignore_unknown_fields = true }) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.JsonError,
+        };
+        defer parsed.deinit();
+        const rec = parsed.value;
+
+        var restored = try Session.init(allocator, rec.domain);
+        errdefer restored.deinit();
+        restored.created_at = rec.created_at;
+        restored.last_used_at = rec.last_used_at;
+        try restored.setRequestedScope(rec.requested_scope);
+        try restored.setNonce(rec.nonce);
+        try restored.setAuthorizationParams(rec.authorization_params);
+        return restored;
     }
 
     pub fn setToken(self: *Session, token: Token) void {
@@ -315,27 +724,301 @@ pub const Session = struct {
     }
 };
 
+/// Optional features supported by a storage backend
+///
+/// Generic code (export, cleanup, decorators) can check these instead of
+/// calling a method and handling `error.UnsupportedOperation`.
+pub const StorageCapabilities = struct {
+    /// Stored keys can be enumerated
+    list_keys: bool = false,
+    /// Saving replaces the previous value atomically (no torn reads)
+    atomic_swap: bool = false,
+    /// Stored tokens can be iterated
+    iteration: bool = false,
+    /// Entries can expire on their own
+    ttl: bool = false,
+    /// Several entries can be read or written in one call
+    bulk: bool = false,
+    /// Tokens are kept in an OS credential store rather than plain files
+    os_protected: bool = false,
+    /// Authorization sessions can be stored and enumerated by state
+    sessions: bool = false,
+};
+
+/// Failures reported through SessionStorage
+///
+/// A missing token is not an error: load() returns null for it. The rest
+/// tells a caller whether to retry (Locked, Io), re-authenticate
+/// (Serialization, DecryptionFailed) or give up on the backend.
+pub const StorageError = error{
+    /// An entry the operation needs does not exist
+    NotFound,
+    /// Reading or writing the disk or the connection to the backend failed
+    Io,
+    /// A stored value could not be encoded or decoded
+    Serialization,
+    /// The store is locked or busy (locked keychain, held lock, busy database)
+    Locked,
+    /// The backend rejected the operation for a reason of its own
+    Backend,
+    /// Stored data does not open under the configured key
+    DecryptionFailed,
+    /// A token file other users can reach was refused (see FileStorage.PermissionCheck)
+    InsecurePermissions,
+    /// The key cannot be stored by this backend
+    InvalidParameter,
+    /// The backend does not implement this operation
+    UnsupportedOperation,
+    OutOfMemory,
+};
+
+/// Map a backend failure onto StorageError
+///
+/// Errors already in StorageError pass through; anything unrecognized is
+/// `error.Backend`.
+pub fn mapStorageError(err: anyerror) StorageError {
+    return switch (err) {
+        error.NotFound,
+        error.Io,
+        error.Serialization,
+        error.Locked,
+        error.Backend,
+        error.DecryptionFailed,
+        error.InsecurePermissions,
+        error.InvalidParameter,
+        error.UnsupportedOperation,
+        error.OutOfMemory,
+        => |e| e,
+        error.FileNotFound, error.TokenNotFound => error.NotFound,
+        error.IoError,
+        error.InputOutput,
+        error.AccessDenied,
+        error.NoSpaceLeft,
+        error.DiskQuota,
+        error.FileTooBig,
+        error.ReadOnlyFileSystem,
+        error.StreamTooLong,
+        error.EndOfStream,
+        error.BrokenPipe,
+        error.ConnectionResetByPeer,
+        error.ConnectionRefused,
+        error.ConnectionFailed,
+        error.ConnectionTimedOut,
+        error.NetworkUnreachable,
+        error.Timeout,
+        => error.Io,
+        error.JsonError,
+        error.SyntaxError,
+        error.UnexpectedToken,
+        error.UnexpectedEndOfInput,
+        error.InvalidCharacter,
+        error.InvalidPadding,
+        error.UnsupportedSchemaVersion,
+        => error.Serialization,
+        error.LockError, error.WouldBlock, error.LockViolation => error.Locked,
+        else => error.Backend,
+    };
+}
+
 /// Storage interface for session/token persistence
 pub const SessionStorage = struct {
     ptr: *anyopaque,
     vtable: *const VTable,
 
     pub const VTable = struct {
-        save: *const fn (ptr: *anyopaque, key: []const u8, token: Token) anyerror!void,
-        load: *const fn (ptr: *anyopaque, allocator: Allocator, key: []const u8) anyerror!?Token,
-        delete: *const fn (ptr: *anyopaque, key: []const u8) anyerror!void,
+        save: *const fn (ptr: *anyopaque, key: []const u8, token: Token) StorageError!void,
+        load: *const fn (ptr: *anyopaque, allocator: Allocator, key: []const u8) StorageError!?Token,
+        delete: *const fn (ptr: *anyopaque, key: []const u8) StorageError!void,
         exists: *const fn (ptr: *anyopaque, key: []const u8) bool,
+        /// Report optional features (backends without it support none)
+        capabilities: ?*const fn (ptr: *anyopaque) StorageCapabilities = null,
+        /// Lend the stored token to `visit` without copying it (see withToken)
+        ///
+        /// Must call `visit` exactly once, with null if the key is missing.
+        borrow: ?*const fn (
+            ptr: *anyopaque,
+            key: []const u8,
+            context: *anyopaque,
+            visit: *const fn (context: *anyopaque, token: ?*const Token) void,
+        ) StorageError!void = null,
+        /// List every stored key (see listKeys)
+        list_keys: ?*const fn (ptr: *anyopaque, allocator: Allocator) StorageError![][]const u8 = null,
+        /// Keep an authorization session under its state (see saveSession)
+        save_session: ?*const fn (ptr: *anyopaque, state: []const u8, pending: *const Session) StorageError!void = null,
+        load_session: ?*const fn (ptr: *anyopaque, allocator: Allocator, state: []const u8) StorageError!?Session = null,
+        delete_session: ?*const fn (ptr: *anyopaque, state: []const u8) StorageError!void = null,
+        /// List the state of every stored session (see listSessionStates)
+        list_session_states: ?*const fn (ptr: *anyopaque, allocator: Allocator) StorageError![][]const u8 = null,
+
+        /// Build a vtable from backend functions with their own error sets
+        ///
+        /// `impl` has the fields of VTable. Every failure goes through
+        /// mapStorageError(), so backends can `try` OS and library calls
+        /// and still only surface StorageError.
+        pub fn from(comptime impl: anytype) *const VTable {
+            const Impl = @TypeOf(impl);
+            const Mapped = struct {
+                fn save(ptr: *anyopaque, key: []const u8, token: Token) StorageError!void {
+                    return impl.save(ptr, key, token) catch |err| return mapStorageError(err);
+                }
+
+                fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) StorageError!?Token {
+                    return impl.load(ptr, allocator, key) catch |err| return mapStorageError(err);
+                }
+
+                fn delete(ptr: *anyopaque, key: []const u8) StorageError!void {
+                    return impl.delete(ptr, key) catch |err| return mapStorageError(err);
+                }
+
+                fn borrow(
+                    ptr: *anyopaque,
+                    key: []const u8,
+                    context: *anyopaque,
+                    visit: *const fn (context: *anyopaque, token: ?*const Token) void,
+                ) StorageError!void {
+                    return impl.borrow(ptr, key, context, visit) catch |err| return mapStorageError(err);
+                }
+
+                fn listKeys(ptr: *anyopaque, allocator: Allocator) StorageError![][]const u8 {
+                    return impl.list_keys(ptr, allocator) catch |err| return mapStorageError(err);
+                }
+
+                fn saveSession(ptr: *anyopaque, state: []const u8, pending: *const Session) StorageError!void {
+                    return impl.save_session(ptr, state, pending) catch |err| return mapStorageError(err);
+                }
+
+                fn loadSession(ptr: *anyopaque, allocator: Allocator, state: []const u8) StorageError!?Session {
+                    return impl.load_session(ptr, allocator, state) catch |err| return mapStorageError(err);
+                }
+
+                fn deleteSession(ptr: *anyopaque, state: []const u8) StorageError!void {
+                    return impl.delete_session(ptr, state) catch |err| return mapStorageError(err);
+                }
+
+                fn listSessionStates(ptr: *anyopaque, allocator: Allocator) StorageError![][]const u8 {
+                    return impl.list_session_states(ptr, allocator) catch |err| return mapStorageError(err);
+                }
+
+                const vtable: VTable = .{
+                    .save = save,
+                    .load = load,
+                    .delete = delete,
+                    .exists = impl.exists,
+                    .capabilities = if (@hasField(Impl, "capabilities")) impl.capabilities else null,
+                    .borrow = if (@hasField(Impl, "borrow")) borrow else null,
+                    .list_keys = if (@hasField(Impl, "list_keys")) listKeys else null,
+                    .save_session = if (@hasField(Impl, "save_session")) saveSession else null,
+                    .load_session = if (@hasField(Impl, "load_session")) loadSession else null,
+                    .delete_session = if (@hasField(Impl, "delete_session")) deleteSession else null,
+                    .list_session_states = if (@hasField(Impl, "list_session_states")) listSessionStates else null,
+                };
+            };
+            return &Mapped.vtable;
+        }
     };
 
-    pub fn save(self: SessionStorage, key: []const u8, token: Token) !void {
+    /// Return type of a withToken() visitor
+    pub fn VisitResult(comptime Visit: type) type {
+        return @typeInfo(Visit).@"fn".return_type.?;
+    }
+
+    /// Read the token stored under `key` without keeping a copy
+    ///
+    /// Calls `visit(context, token)` and returns its result; `token` is null
+    /// if the key is missing and is only valid during the call. Backends that
+    /// can lend their token (MemoryStorage) do so without cloning; others
+    /// load a temporary copy with `allocator`.
+    pub fn withToken(
+        self: SessionStorage,
+        allocator: Allocator,
+        key: []const u8,
+        context: anytype,
+        comptime visit: anytype,
+    ) StorageError!VisitResult(@TypeOf(visit)) {
+        const R = VisitResult(@TypeOf(visit));
+        const Context = @TypeOf(context);
+
+        if (self.vtable.borrow) |borrow| {
+            const Closure = struct {
+                context: Context,
+                result: R = undefined,
+
+                fn call(ptr: *anyopaque, token: ?*const Token) void {
+                    const closure_ptr: *@This() = @ptrCast(@alignCast(ptr));
+                    closure_ptr.result = visit(closure_ptr.context, token);
+                }
+            };
+
+            var closure = Closure{ .context = context };
+            try borrow(self.ptr, key, &closure, Closure.call);
+            return closure.result;
+        }
+
+        var token = try self.load(allocator, key);
+        defer if (token) |*t| t.deinit();
+        return visit(context, if (token) |*t| t else null);
+    }
+
+    /// List the keys of every stored token, in no particular order
+    ///
+    /// Returns `error.UnsupportedOperation` for backends that cannot
+    /// enumerate their entries. Free the result with freeKeys().
+    pub fn listKeys(self: SessionStorage, allocator: Allocator) StorageError![][]const u8 {
+        const list = self.vtable.list_keys orelse return error.UnsupportedOperation;
+        return list(self.ptr, allocator);
+    }
+
+    /// Store the authorization session started with `state`
+    ///
+    /// Keeps what Session.toJson() keeps, so the callback can be checked by
+    /// another process. Returns `error.UnsupportedOperation` for backends
+    /// that only store tokens.
+    pub fn saveSession(self: SessionStorage, state: []const u8, pending: *const Session) StorageError!void {
+        const save_session = self.vtable.save_session orelse return error.UnsupportedOperation;
+        return save_session(self.ptr, state, pending);
+    }
+
+    /// Load the session saved for `state`, or null if there is none
+    pub fn loadSession(self: SessionStorage, allocator: Allocator, state: []const u8) StorageError!?Session {
+        const load_session = self.vtable.load_session orelse return error.UnsupportedOperation;
+        return load_session(self.ptr, allocator, state);
+    }
+
+    pub fn deleteSession(self: SessionStorage, state: []const u8) StorageError!void {
+        const delete_session = self.vtable.delete_session orelse return error.UnsupportedOperation;
+        return delete_session(self.ptr, state);
+    }
+
+    /// List the state of every stored session, in no particular order
+    ///
+    /// Returns `error.UnsupportedOperation` for backends that cannot
+    /// enumerate sessions. Free the result with freeKeys().
+    pub fn listSessionStates(self: SessionStorage, allocator: Allocator) StorageError![][]const u8 {
+        const list = self.vtable.list_session_states orelse return error.UnsupportedOperation;
+        return list(self.ptr, allocator);
+    }
+
+    /// Free keys returned by listKeys() or listSessionStates()
+    pub fn freeKeys(allocator: Allocator, keys: [][]const u8) void {
+        for (keys) |key| allocator.free(key);
+        allocator.free(keys);
+    }
+
+    /// Get the optional features supported by this backend
+    pub fn capabilities(self: SessionStorage) StorageCapabilities {
+        const report = self.vtable.capabilities orelse return .{};
+        return report(self.ptr);
+    }
+
+    pub fn save(self: SessionStorage, key: []const u8, token: Token) StorageError!void {
         return self.vtable.save(self.ptr, key, token);
     }
 
-    pub fn load(self: SessionStorage, allocator: Allocator, key: []const u8) !?Token {
+    pub fn load(self: SessionStorage, allocator: Allocator, key: []const u8) StorageError!?Token {
         return self.vtable.load(self.ptr, allocator, key);
     }
 
-    pub fn delete(self: SessionStorage, key: []const u8) !void {
+    pub fn delete(self: SessionStorage, key: []const u8) StorageError!void {
         return self.vtable.delete(self.ptr, key);
     }
 
@@ -344,15 +1027,50 @@ pub const SessionStorage = struct {
     }
 };
 
+/// What happened to a watched token
+pub const TokenChange = enum {
+    created,
+    /// Saved again, e.g. after a refresh
+    updated,
+    deleted,
+};
+
+/// Callback for token changes (see MemoryStorage.watch and FileStorage.watch)
+pub const TokenWatcher = struct {
+    context: *anyopaque,
+    callback: *const fn (context: *anyopaque, key: []const u8, change: TokenChange) void,
+
+    pub fn notify(self: TokenWatcher, key: []const u8, change: TokenChange) void {
+        self.callback(self.context, key, change);
+    }
+};
+
 /// In-memory storage for testing
+///
+/// Safe to share between threads (e.g. with background refreshes).
 pub const MemoryStorage = struct {
     allocator: Allocator,
-    tokens: std.StringHashMap([]const u8),
+    tokens: std.StringHashMap(Token),
+    /// Session.toJson() documents by state; guarded by mutex
+    sessions: std.StringHashMap([]u8),
+    mutex: std.Thread.Mutex = .{},
+    /// Registered by watch(); guarded by watch_mutex
+    watches: std.ArrayListUnmanaged(Watch) = .{},
+    watch_mutex: std.Thread.Mutex = .{},
+    next_watch_id: u32 = 1,
+
+    const Watch = struct {
+        id: u32,
+        /// Watched key, or null for every key
+        key: ?[]u8,
+        watcher: TokenWatcher,
+    };
 
     pub fn init(allocator: Allocator) MemoryStorage {
         return .{
             .allocator = allocator,
-            .tokens = std.StringHashMap([]const u8).init(allocator),
+            .tokens = std.StringHashMap(Token).init(allocator),
+            .sessions = std.StringHashMap([]u8).init(allocator),
         };
     }
 
@@ -360,939 +1078,4274 @@ pub const MemoryStorage = struct {
         var iter = self.tokens.iterator();
         while (iter.next()) |entry| {
             self.allocator.free(entry.key_ptr.*);
-            self.allocator.free(entry.value_ptr.*);
+            entry.value_ptr.deinit();
         }
         self.tokens.deinit();
+
+        var session_iter = self.sessions.iterator();
+        while (session_iter.next()) |entry| {
+            self.allocator.free(entry.key_ptr.*);
+            self.allocator.free(entry.value_ptr.*);
+        }
+        self.sessions.deinit();
+
+        for (self.watches.items) |w| if (w.key) |key| self.allocator.free(key);
+        self.watches.deinit(self.allocator);
+    }
+
+    /// Call `watcher` whenever the token under `key` (any key when null) is
+    /// saved or deleted through this storage
+    ///
+    /// The callback runs on the thread that made the change, after the
+    /// change is visible; it must not call watch() or unwatch(). Returns an
+    /// id for unwatch().
+    pub fn watch(self: *MemoryStorage, key: ?[]const u8, watcher: TokenWatcher) !u32 {
+        const key_copy = if (key) |k| try self.allocator.dupe(u8, k) else null;
+        errdefer if (key_copy) |k| self.allocator.free(k);
+
+        self.watch_mutex.lock();
+        defer self.watch_mutex.unlock();
+
+        const id = self.next_watch_id;
+        try self.watches.append(self.allocator, .{ .id = id, .key = key_copy, .watcher = watcher });
+        self.next_watch_id += 1;
+        return id;
+    }
+
+    /// Stop a watch registered with watch()
+    pub fn unwatch(self: *MemoryStorage, id: u32) void {
+        self.watch_mutex.lock();
+        defer self.watch_mutex.unlock();
+
+        for (self.watches.items, 0..) |w, i| {
+            if (w.id != id) continue;
+            if (w.key) |key| self.allocator.free(key);
+            _ = self.watches.orderedRemove(i);
+            return;
+        }
+    }
+
+    fn notify(self: *MemoryStorage, key: []const u8, change: TokenChange) void {
+        self.watch_mutex.lock();
+        defer self.watch_mutex.unlock();
+
+        for (self.watches.items) |w| {
+            if (w.key) |watched| {
+                if (!mem.eql(u8, watched, key)) continue;
+            }
+            w.watcher.notify(key, change);
+        }
+    }
+
+    /// Read the token stored under `key` in place, without cloning it
+    ///
+    /// `visit(context, token)` runs with the storage lock held, so it should
+    /// only read fields: no I/O, no blocking, and no calls back into this
+    /// storage. The token pointer must not escape the call.
+    pub fn withToken(
+        self: *MemoryStorage,
+        key: []const u8,
+        context: anytype,
+        comptime visit: anytype,
+    ) SessionStorage.VisitResult(@TypeOf(visit)) {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        return visit(context, self.tokens.getPtr(key));
     }
 
     pub fn storage(self: *MemoryStorage) SessionStorage {
         return .{
             .ptr = self,
-            .vtable = &.{
+            .vtable = SessionStorage.VTable.from(.{
                 .save = save,
                 .load = load,
                 .delete = delete,
                 .exists = exists,
-            },
+                .capabilities = capabilities,
+                .borrow = borrow,
+                .list_keys = listKeys,
+                .save_session = saveSession,
+                .load_session = loadSession,
+                .delete_session = deleteSession,
+                .list_session_states = listSessionStates,
+            }),
         };
     }
 
-    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+    fn capabilities(_: *anyopaque) StorageCapabilities {
+        // Saves swap the entry under the mutex
+        return .{ .atomic_swap = true, .list_keys = true, .iteration = true, .sessions = true };
+    }
+
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) anyerror![][]const u8 {
         const self: *MemoryStorage = @ptrCast(@alignCast(ptr));
 
-        const json_data = try token.toJson(self.allocator);
-        errdefer self.allocator.free(json_data);
+        self.mutex.lock();
+        defer self.mutex.unlock();
 
-        const key_copy = try self.allocator.dupe(u8, key);
-        errdefer self.allocator.free(key_copy);
+        var iter = self.tokens.keyIterator();
+        return dupeKeys(allocator, &iter);
+    }
 
-        // Remove old entry if exists
-        if (self.tokens.fetchRemove(key)) |old| {
-            self.allocator.free(old.key);
-            self.allocator.free(old.value);
-        }
+    fn listSessionStates(ptr: *anyopaque, allocator: Allocator) anyerror![][]const u8 {
+        const self: *MemoryStorage = @ptrCast(@alignCast(ptr));
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
 
-        try self.tokens.put(key_copy, json_data);
+        var iter = self.sessions.keyIterator();
+        return dupeKeys(allocator, &iter);
     }
 
-    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
-        const self: *MemoryStorage = @ptrCast(@alignCast(ptr));
+    /// Copy every key of a map key iterator; the caller holds the mutex
+    fn dupeKeys(allocator: Allocator, iter: anytype) ![][]const u8 {
+        var keys: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
 
-        if (self.tokens.get(key)) |json_data| {
-            return try Token.fromJson(allocator, json_data);
+        while (iter.next()) |key| {
+            const copy = try allocator.dupe(u8, key.*);
+            errdefer allocator.free(copy);
+            try keys.append(allocator, copy);
         }
-        return null;
+
+        return keys.toOwnedSlice(allocator);
     }
 
-    fn delete(ptr: *anyopaque, key: []const u8) !void {
+    fn saveSession(ptr: *anyopaque, state: []const u8, pending: *const Session) !void {
         const self: *MemoryStorage = @ptrCast(@alignCast(ptr));
 
-        if (self.tokens.fetchRemove(key)) |old| {
-            self.allocator.free(old.key);
-            self.allocator.free(old.value);
+        const data = try pending.toJson(self.allocator);
+        errdefer self.allocator.free(data);
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        const entry = try self.sessions.getOrPut(state);
+        if (entry.found_existing) {
+            self.allocator.free(entry.value_ptr.*);
+        } else {
+            entry.key_ptr.* = self.allocator.dupe(u8, state) catch |err| {
+                self.sessions.removeByPtr(entry.key_ptr);
+                return err;
+            };
         }
+        entry.value_ptr.* = data;
     }
 
-    fn exists(ptr: *anyopaque, key: []const u8) bool {
+    fn loadSession(ptr: *anyopaque, allocator: Allocator, state: []const u8) !?Session {
         const self: *MemoryStorage = @ptrCast(@alignCast(ptr));
-        return self.tokens.contains(key);
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        const data = self.sessions.get(state) orelse return null;
+        return try Session.fromJson(allocator, data);
     }
-};
 
-/// File-based JSON storage
-///
-/// WARNING: Tokens are stored in plaintext. Use SecureStorage for production.
-pub const FileStorage = struct {
-    allocator: Allocator,
-    base_path: []const u8,
+    fn deleteSession(ptr: *anyopaque, state: []const u8) !void {
+        const self: *MemoryStorage = @ptrCast(@alignCast(ptr));
 
-    /// Initialize with a base directory path
-    ///
-    /// Supports XDG Base Directory Specification on Linux
-    pub fn init(allocator: Allocator, app_name: []const u8) !FileStorage {
-        const base_path = try getStoragePath(allocator, app_name);
-        return .{
-            .allocator = allocator,
-            .base_path = base_path,
-        };
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        const old_entry = self.sessions.fetchRemove(state) orelse return;
+        self.allocator.free(old_entry.key);
+        self.allocator.free(old_entry.value);
     }
 
-    /// Initialize with a custom directory path
-    pub fn initWithPath(allocator: Allocator, path: []const u8) !FileStorage {
-        return .{
-            .allocator = allocator,
-            .base_path = try allocator.dupe(u8, path),
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        const self: *MemoryStorage = @ptrCast(@alignCast(ptr));
+
+        var copy = try token.clone(self.allocator);
+        errdefer copy.deinit();
+
+        const key_copy = try self.allocator.dupe(u8, key);
+        errdefer self.allocator.free(key_copy);
+
+        const replaced = blk: {
+            self.mutex.lock();
+            defer self.mutex.unlock();
+
+            // Remove old entry if exists
+            const old_entry = self.tokens.fetchRemove(key);
+            if (old_entry) |old| {
+                self.allocator.free(old.key);
+                var old_token = old.value;
+                old_token.deinit();
+            }
+
+            try self.tokens.put(key_copy, copy);
+            break :blk old_entry != null;
         };
+        self.notify(key, if (replaced) .updated else .created);
     }
 
-    pub fn deinit(self: *FileStorage) void {
-        self.allocator.free(self.base_path);
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *MemoryStorage = @ptrCast(@alignCast(ptr));
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        if (self.tokens.getPtr(key)) |token| {
+            return try token.clone(allocator);
+        }
+        return null;
     }
 
-    pub fn storage(self: *FileStorage) SessionStorage {
+    fn borrow(
+        ptr: *anyopaque,
+        key: []const u8,
+        context: *anyopaque,
+        visit: *const fn (context: *anyopaque, token: ?*const Token) void,
+    ) anyerror!void {
+        const self: *MemoryStorage = @ptrCast(@alignCast(ptr));
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        visit(context, self.tokens.getPtr(key));
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        const self: *MemoryStorage = @ptrCast(@alignCast(ptr));
+
+        const removed = blk: {
+            self.mutex.lock();
+            defer self.mutex.unlock();
+
+            const old_entry = self.tokens.fetchRemove(key) orelse break :blk false;
+            self.allocator.free(old_entry.key);
+            var old_token = old_entry.value;
+            old_token.deinit();
+            break :blk true;
+        };
+        if (removed) self.notify(key, .deleted);
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        const self: *MemoryStorage = @ptrCast(@alignCast(ptr));
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        return self.tokens.contains(key);
+    }
+};
+
+/// Storage that persists refresh tokens but keeps access tokens in memory
+///
+/// Saves keep the full token in memory and write a copy to `backing`
+/// without the access token, marked as expired. After a restart, loads
+/// return that copy and TokenRefresher mints a fresh access token on first
+/// use, so a leaked storage file never holds a usable access token. Tokens
+/// without a refresh token live in memory only. Request `offline_access`
+/// (see oauth.withOfflineAccess()) so the provider issues refresh tokens.
+pub const RefreshTokenOnlyStorage = struct {
+    allocator: Allocator,
+    backing: SessionStorage,
+    memory: MemoryStorage,
+
+    pub fn init(allocator: Allocator, backing: SessionStorage) RefreshTokenOnlyStorage {
+        return .{
+            .allocator = allocator,
+            .backing = backing,
+            .memory = MemoryStorage.init(allocator),
+        };
+    }
+
+    pub fn deinit(self: *RefreshTokenOnlyStorage) void {
+        self.memory.deinit();
+    }
+
+    pub fn storage(self: *RefreshTokenOnlyStorage) SessionStorage {
         return .{
             .ptr = self,
-            .vtable = &.{
+            .vtable = SessionStorage.VTable.from(.{
                 .save = save,
                 .load = load,
                 .delete = delete,
                 .exists = exists,
-            },
+                .capabilities = capabilities,
+                .list_keys = listKeys,
+            }),
         };
     }
 
-    fn getFilePath(self: *FileStorage, key: []const u8) ![]const u8 {
-        // Validate key to prevent path traversal attacks
-        try validateStorageKey(key);
-        return std.fmt.allocPrint(self.allocator, "{s}/{s}.json", .{ self.base_path, key });
+    /// The copy of `token` written to the backing storage
+    ///
+    /// Caller owns the returned token.
+    pub fn persistedCopy(allocator: Allocator, token: *const Token) !Token {
+        var copy = try token.clone(allocator);
+        errdefer copy.deinit();
+
+        const empty = try allocator.dupe(u8, "");
+        allocator.free(copy.access_token);
+        copy.access_token = empty;
+        // Reads as expired, so the first use refreshes it
+        copy.expires_in = null;
+        copy.expires_at = 0;
+        return copy;
     }
 
-    /// Validate that a storage key is safe (no path traversal or special characters)
-    fn validateStorageKey(key: []const u8) !void {
-        if (key.len == 0) return error.InvalidParameter;
-        if (key.len > 255) return error.InvalidParameter; // Reasonable max length
-
-        for (key) |c| {
-            switch (c) {
-                // Allow alphanumeric, underscore, hyphen, dot (but not leading dot)
-                'a'...'z', 'A'...'Z', '0'...'9', '_', '-' => {},
-                '.' => {
-                    // Disallow if it could be path traversal
-                    if (key.len >= 2 and std.mem.startsWith(u8, key, "..")) {
-                        return error.InvalidParameter;
-                    }
-                },
-                // Disallow path separators and other dangerous characters
-                '/', '\\', 0, '\n', '\r' => return error.InvalidParameter,
-                else => {},
-            }
-        }
-
-        // Additional check: disallow keys that start with dot (hidden files)
-        if (key[0] == '.') return error.InvalidParameter;
+    fn capabilities(ptr: *anyopaque) StorageCapabilities {
+        const self: *RefreshTokenOnlyStorage = @ptrCast(@alignCast(ptr));
+        const backing = self.backing.capabilities();
+        return .{ .list_keys = backing.list_keys, .os_protected = backing.os_protected };
     }
 
     fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
-        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+        const self: *RefreshTokenOnlyStorage = @ptrCast(@alignCast(ptr));
+
+        if (token.refresh_token != null) {
+            var persisted = try persistedCopy(self.allocator, &token);
+            defer persisted.deinit();
+            try self.backing.save(key, persisted);
+        } else if (self.backing.exists(key)) {
+            // A stale refresh token must not outlive the token replacing it
+            try self.backing.delete(key);
+        }
+        try self.memory.storage().save(key, token);
+    }
 
-        // Ensure directory exists with restricted permissions (owner only)
-        fs.cwd().makePath(self.base_path) catch |err| {
-            if (err != error.PathAlreadyExists) return err;
-        };
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *RefreshTokenOnlyStorage = @ptrCast(@alignCast(ptr));
+        if (try self.memory.storage().load(allocator, key)) |token| return token;
+        return self.backing.load(allocator, key);
+    }
 
-        // Try to set directory permissions to owner-only (0700)
-        // This is best-effort - may fail on some filesystems
-        if (@import("builtin").os.tag != .windows) {
-            const dir = fs.cwd().openDir(self.base_path, .{}) catch null;
-            if (dir) |d| {
-                var md = d;
-                md.chmod(0o700) catch {};
-                md.close();
-            }
-        }
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        const self: *RefreshTokenOnlyStorage = @ptrCast(@alignCast(ptr));
+        try self.backing.delete(key);
+        try self.memory.storage().delete(key);
+    }
 
-        const file_path = try self.getFilePath(key);
-        defer self.allocator.free(file_path);
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        const self: *RefreshTokenOnlyStorage = @ptrCast(@alignCast(ptr));
+        return self.memory.storage().exists(key) or self.backing.exists(key);
+    }
 
-        const json_data = try token.toJson(self.allocator);
-        defer self.allocator.free(json_data);
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) anyerror![][]const u8 {
+        const self: *RefreshTokenOnlyStorage = @ptrCast(@alignCast(ptr));
 
-        // Create file with restricted permissions (owner read/write only - 0600)
-        const file = try fs.cwd().createFile(file_path, .{ .mode = 0o600 });
-        defer file.close();
+        const in_memory = try self.memory.storage().listKeys(allocator);
+        defer SessionStorage.freeKeys(allocator, in_memory);
 
-        try file.writeAll(json_data);
+        var keys = std.ArrayListUnmanaged([]const u8).fromOwnedSlice(try self.backing.listKeys(allocator));
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
+        for (in_memory) |key| {
+            if (self.backing.exists(key)) continue;
+            const copy = try allocator.dupe(u8, key);
+            errdefer allocator.free(copy);
+            try keys.append(allocator, copy);
+        }
+        return keys.toOwnedSlice(allocator);
     }
+};
 
-    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
-        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+/// Storage that tries several backends in order
+///
+/// Reads return the first token found and writes go to the first backend
+/// that accepts them, so one binary can use the keychain on laptops and
+/// fall back to encrypted files or memory on headless CI. A write that
+/// falls through also deletes the key from the backends it skipped, so an
+/// older copy there cannot shadow the new token, and deletes reach every
+/// backend so a stale copy cannot resurface. The error each backend
+/// returned during the last operation is available from lastError().
+pub const ChainedStorage = struct {
+    allocator: Allocator,
+    backends: []const SessionStorage,
+    /// Error per backend from the last operation (null if it succeeded)
+    last_errors: []?StorageError,
+    mutex: std.Thread.Mutex = .{},
+
+    pub fn init(allocator: Allocator, backends: []const SessionStorage) !ChainedStorage {
+        if (backends.len == 0) return error.InvalidParameter;
+
+        const owned = try allocator.dupe(SessionStorage, backends);
+        errdefer allocator.free(owned);
+        const last_errors = try allocator.alloc(?StorageError, backends.len);
+        @memset(last_errors, null);
+        return .{ .allocator = allocator, .backends = owned, .last_errors = last_errors };
+    }
 
-        const file_path = try self.getFilePath(key);
-        defer self.allocator.free(file_path);
+    pub fn deinit(self: *ChainedStorage) void {
+        self.allocator.free(self.backends);
+        self.allocator.free(self.last_errors);
+    }
 
-        const file = fs.cwd().openFile(file_path, .{}) catch |err| {
-            if (err == error.FileNotFound) return null;
-            return err;
+    pub fn storage(self: *ChainedStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+                .capabilities = capabilities,
+                .list_keys = listKeys,
+            }),
         };
-        defer file.close();
+    }
 
-        const json_data = try file.readToEndAlloc(allocator, 1024 * 1024);
-        defer allocator.free(json_data);
+    /// Error backend `index` returned during the last operation, if any
+    pub fn lastError(self: *ChainedStorage, index: usize) ?StorageError {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        return self.last_errors[index];
+    }
 
-        return try Token.fromJson(allocator, json_data);
+    fn record(self: *ChainedStorage, index: usize, err: ?StorageError) void {
+        self.last_errors[index] = err;
+        if (err) |e| std.log.warn("Storage backend {d} failed: {s}", .{ index, @errorName(e) });
     }
 
-    fn delete(ptr: *anyopaque, key: []const u8) !void {
-        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+    fn capabilities(ptr: *anyopaque) StorageCapabilities {
+        const self: *ChainedStorage = @ptrCast(@alignCast(ptr));
 
-        const file_path = try self.getFilePath(key);
-        defer self.allocator.free(file_path);
+        // Any backend may end up holding a token, so guarantees need all of them
+        var caps = StorageCapabilities{ .list_keys = true, .atomic_swap = true, .os_protected = true };
+        for (self.backends) |backend| {
+            const backend_caps = backend.capabilities();
+            caps.list_keys = caps.list_keys and backend_caps.list_keys;
+            caps.atomic_swap = caps.atomic_swap and backend_caps.atomic_swap;
+            caps.os_protected = caps.os_protected and backend_caps.os_protected;
+        }
+        return caps;
+    }
 
-        fs.cwd().deleteFile(file_path) catch |err| {
-            if (err != error.FileNotFound) return err;
-        };
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        const self: *ChainedStorage = @ptrCast(@alignCast(ptr));
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        @memset(self.last_errors, null);
+        var first_error: ?StorageError = null;
+        for (self.backends, 0..) |backend, i| {
+            if (backend.save(key, token)) |_| {
+                return self.evictShadowing(key, i);
+            } else |err| {
+                self.record(i, err);
+                if (first_error == null) first_error = err;
+            }
+        }
+        return first_error.?;
     }
 
-    fn exists(ptr: *anyopaque, key: []const u8) bool {
-        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+    /// Delete `key` from the backends before `saved`, which reads consult first
+    ///
+    /// Fails only if a copy is known to remain: its reads would return the
+    /// old token instead of the one just saved.
+    fn evictShadowing(self: *ChainedStorage, key: []const u8, saved: usize) !void {
+        for (self.backends[0..saved], 0..) |backend, i| {
+            backend.delete(key) catch |err| {
+                self.record(i, err);
+                if (backend.exists(key)) return err;
+            };
+        }
+    }
 
-        const file_path = self.getFilePath(key) catch return false;
-        defer self.allocator.free(file_path);
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *ChainedStorage = @ptrCast(@alignCast(ptr));
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        @memset(self.last_errors, null);
+        var failures: usize = 0;
+        var first_error: ?StorageError = null;
+        for (self.backends, 0..) |backend, i| {
+            const found = backend.load(allocator, key) catch |err| {
+                self.record(i, err);
+                failures += 1;
+                if (first_error == null) first_error = err;
+                continue;
+            };
+            if (found) |token| return token;
+        }
+        // Missing only counts as missing if some backend could answer
+        if (failures == self.backends.len) return first_error.?;
+        return null;
+    }
 
-        fs.cwd().access(file_path, .{}) catch return false;
-        return true;
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        const self: *ChainedStorage = @ptrCast(@alignCast(ptr));
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        @memset(self.last_errors, null);
+        var failures: usize = 0;
+        var first_error: ?StorageError = null;
+        for (self.backends, 0..) |backend, i| {
+            backend.delete(key) catch |err| {
+                self.record(i, err);
+                failures += 1;
+                if (first_error == null) first_error = err;
+            };
+        }
+        if (failures == self.backends.len) return first_error.?;
     }
 
-    fn getStoragePath(allocator: Allocator, app_name: []const u8) ![]const u8 {
-        const builtin = @import("builtin");
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        const self: *ChainedStorage = @ptrCast(@alignCast(ptr));
+        for (self.backends) |backend| {
+            if (backend.exists(key)) return true;
+        }
+        return false;
+    }
 
-        if (builtin.os.tag == .linux) {
-            // XDG Base Directory Specification
-            if (getEnvVar(allocator, "XDG_DATA_HOME")) |xdg_data| {
-                defer allocator.free(xdg_data);
-                return std.fmt.allocPrint(allocator, "{s}/{s}", .{ xdg_data, app_name });
-            }
-            if (getEnvVar(allocator, "HOME")) |home| {
-                defer allocator.free(home);
-                return std.fmt.allocPrint(allocator, "{s}/.local/share/{s}", .{ home, app_name });
-            }
-        } else if (builtin.os.tag == .macos) {
-            if (getEnvVar(allocator, "HOME")) |home| {
-                defer allocator.free(home);
-                return std.fmt.allocPrint(allocator, "{s}/Library/Application Support/{s}", .{ home, app_name });
-            }
-        } else if (builtin.os.tag == .windows) {
-            if (getEnvVar(allocator, "LOCALAPPDATA")) |local_app_data| {
-                defer allocator.free(local_app_data);
-                return std.fmt.allocPrint(allocator, "{s}\\{s}", .{ local_app_data, app_name });
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) anyerror![][]const u8 {
+        const self: *ChainedStorage = @ptrCast(@alignCast(ptr));
+
+        var keys: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
+        for (self.backends) |backend| {
+            const backend_keys = try backend.listKeys(allocator);
+            defer SessionStorage.freeKeys(allocator, backend_keys);
+
+            for (backend_keys) |key| {
+                const seen = for (keys.items) |existing| {
+                    if (std.mem.eql(u8, existing, key)) break true;
+                } else false;
+                if (seen) continue;
+
+                const copy = try allocator.dupe(u8, key);
+                errdefer allocator.free(copy);
+                try keys.append(allocator, copy);
             }
         }
-
-        // Fallback to temp directory
-        return std.fmt.allocPrint(allocator, "/tmp/{s}", .{app_name});
+        return keys.toOwnedSlice(allocator);
     }
 };
 
-/// Secure storage using OS credential managers
+/// Storage that keeps its keys under a tenant or profile namespace
 ///
-/// Uses:
-/// - macOS: Keychain
-/// - Windows: Credential Manager
-/// - Linux: Secret Service (libsecret)
-pub const SecureStorage = struct {
+/// Every key is stored as `<namespace>.<key>` in the backing storage, so
+/// several products or user profiles can share one keychain or directory
+/// without colliding. listKeys() only returns this namespace's keys, with
+/// the prefix removed. The separator is valid in FileStorage file names on
+/// every platform; a `:` would open an NTFS alternate data stream.
+pub const NamespacedStorage = struct {
     allocator: Allocator,
-    service_name: []const u8,
+    backing: SessionStorage,
+    namespace: []const u8,
 
-    pub fn init(allocator: Allocator, service_name: []const u8) !SecureStorage {
+    pub const separator = '.';
+
+    /// Returns `error.InvalidParameter` if `namespace` is empty or contains
+    /// the separator
+    pub fn init(allocator: Allocator, backing: SessionStorage, namespace: []const u8) !NamespacedStorage {
+        if (namespace.len == 0 or std.mem.indexOfScalar(u8, namespace, separator) != null) {
+            return error.InvalidParameter;
+        }
         return .{
             .allocator = allocator,
-            .service_name = try allocator.dupe(u8, service_name),
+            .backing = backing,
+            .namespace = try allocator.dupe(u8, namespace),
         };
     }
 
-    pub fn deinit(self: *SecureStorage) void {
-        self.allocator.free(self.service_name);
+    pub fn deinit(self: *NamespacedStorage) void {
+        self.allocator.free(self.namespace);
     }
 
-    pub fn storage(self: *SecureStorage) SessionStorage {
+    pub fn storage(self: *NamespacedStorage) SessionStorage {
         return .{
             .ptr = self,
-            .vtable = &.{
+            .vtable = SessionStorage.VTable.from(.{
                 .save = save,
                 .load = load,
                 .delete = delete,
                 .exists = exists,
-            },
+                .capabilities = capabilities,
+                .borrow = borrow,
+                .list_keys = listKeys,
+            }),
         };
     }
 
-    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
-        const self: *SecureStorage = @ptrCast(@alignCast(ptr));
-        const json_data = try token.toJson(self.allocator);
-        defer self.allocator.free(json_data);
-
-        try storeCredential(self.allocator, self.service_name, key, json_data);
+    /// Key `key` is stored under in the backing storage; caller owns it
+    pub fn qualifiedKey(self: *const NamespacedStorage, allocator: Allocator, key: []const u8) ![]u8 {
+        if (key.len == 0) return error.InvalidParameter;
+        return std.fmt.allocPrint(allocator, "{s}{c}{s}", .{ self.namespace, separator, key });
     }
 
-    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
-        const self: *SecureStorage = @ptrCast(@alignCast(ptr));
+    fn capabilities(ptr: *anyopaque) StorageCapabilities {
+        const self: *NamespacedStorage = @ptrCast(@alignCast(ptr));
+        var caps = self.backing.capabilities();
+        // Session calls are not forwarded
+        caps.sessions = false;
+        return caps;
+    }
 
-        const json_data = loadCredential(self.allocator, self.service_name, key) catch |err| {
-            if (err == error.NotFound) return null;
-            return err;
-        };
-        defer self.allocator.free(json_data);
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        const self: *NamespacedStorage = @ptrCast(@alignCast(ptr));
+        const qualified = try self.qualifiedKey(self.allocator, key);
+        defer self.allocator.free(qualified);
+        try self.backing.save(qualified, token);
+    }
 
-        return try Token.fromJson(allocator, json_data);
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *NamespacedStorage = @ptrCast(@alignCast(ptr));
+        const qualified = try self.qualifiedKey(self.allocator, key);
+        defer self.allocator.free(qualified);
+        return self.backing.load(allocator, qualified);
     }
 
     fn delete(ptr: *anyopaque, key: []const u8) !void {
-        const self: *SecureStorage = @ptrCast(@alignCast(ptr));
-        deleteCredential(self.allocator, self.service_name, key) catch |err| {
-            if (err != error.NotFound) return err;
-        };
+        const self: *NamespacedStorage = @ptrCast(@alignCast(ptr));
+        const qualified = try self.qualifiedKey(self.allocator, key);
+        defer self.allocator.free(qualified);
+        try self.backing.delete(qualified);
     }
 
     fn exists(ptr: *anyopaque, key: []const u8) bool {
-        const self: *SecureStorage = @ptrCast(@alignCast(ptr));
-        const data = loadCredential(self.allocator, self.service_name, key) catch return false;
-        self.allocator.free(data);
-        return true;
+        const self: *NamespacedStorage = @ptrCast(@alignCast(ptr));
+        const qualified = self.qualifiedKey(self.allocator, key) catch return false;
+        defer self.allocator.free(qualified);
+        return self.backing.exists(qualified);
     }
 
-    // Platform-specific credential storage
-    const builtin = @import("builtin");
-
-    fn storeCredential(allocator: Allocator, service: []const u8, account: []const u8, data: []const u8) !void {
-        if (builtin.os.tag == .macos) {
-            try macosStoreKeychain(allocator, service, account, data);
-        } else if (builtin.os.tag == .linux) {
-            try linuxStoreSecret(allocator, service, account, data);
-        } else {
-            // Fallback to file storage with warning
-            std.log.warn("SecureStorage not available on this platform, using file storage", .{});
-            try fallbackStore(allocator, service, account, data);
+    fn borrow(
+        ptr: *anyopaque,
+        key: []const u8,
+        context: *anyopaque,
+        visit: *const fn (context: *anyopaque, token: ?*const Token) void,
+    ) !void {
+        const self: *NamespacedStorage = @ptrCast(@alignCast(ptr));
+        const qualified = try self.qualifiedKey(self.allocator, key);
+        defer self.allocator.free(qualified);
+
+        if (self.backing.vtable.borrow) |backing_borrow| {
+            return backing_borrow(self.backing.ptr, qualified, context, visit);
         }
+        var token = try self.backing.load(self.allocator, qualified);
+        defer if (token) |*t| t.deinit();
+        visit(context, if (token) |*t| t else null);
     }
 
-    fn loadCredential(allocator: Allocator, service: []const u8, account: []const u8) ![]const u8 {
-        if (builtin.os.tag == .macos) {
-            return try macosLoadKeychain(allocator, service, account);
-        } else if (builtin.os.tag == .linux) {
-            return try linuxLoadSecret(allocator, service, account);
-        } else {
-            return try fallbackLoad(allocator, service, account);
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) anyerror![][]const u8 {
+        const self: *NamespacedStorage = @ptrCast(@alignCast(ptr));
+
+        const all = try self.backing.listKeys(allocator);
+        defer SessionStorage.freeKeys(allocator, all);
+
+        var keys: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
+        for (all) |qualified| {
+            if (qualified.len <= self.namespace.len + 1) continue;
+            if (!std.mem.startsWith(u8, qualified, self.namespace)) continue;
+            if (qualified[self.namespace.len] != separator) continue;
+
+            const copy = try allocator.dupe(u8, qualified[self.namespace.len + 1 ..]);
+            errdefer allocator.free(copy);
+            try keys.append(allocator, copy);
         }
+        return keys.toOwnedSlice(allocator);
     }
+};
 
-    fn deleteCredential(allocator: Allocator, service: []const u8, account: []const u8) !void {
-        if (builtin.os.tag == .macos) {
-            try macosDeleteKeychain(allocator, service, account);
-        } else if (builtin.os.tag == .linux) {
-            try linuxDeleteSecret(allocator, service, account);
-        } else {
-            try fallbackDelete(allocator, service, account);
+/// Bounded in-memory cache in front of a slow storage backend
+///
+/// Loads are served from memory after the first read of a key, so a
+/// keychain or network backend is not consulted on every token lookup.
+/// Saves and deletes go to the backing storage first (write-through) and
+/// update the cache only once it succeeded. When the cache is full the
+/// least recently used entry is dropped.
+///
+/// Backend calls and the cache update that follows them run under a
+/// per-key lock, so a slow load or a late save can never replace a newer
+/// cached token with an older one.
+///
+/// Another process writing to the same backend is not noticed until the
+/// entry is invalidated or, with `max_age`, expires.
+pub const CachedStorage = struct {
+    allocator: Allocator,
+    backing: SessionStorage,
+    capacity: usize,
+    /// Seconds a cached entry is trusted (forever when null)
+    max_age: ?u64 = null,
+    entries: std.StringHashMapUnmanaged(Entry) = .{},
+    /// Bumped on every access, for least-recently-used eviction
+    tick: u64 = 0,
+    mutex: std.Thread.Mutex = .{},
+    /// Striped per-key locks held across a backend call and the cache
+    /// update; always taken before `mutex`
+    key_locks: [key_lock_stripes]std.Thread.Mutex = [_]std.Thread.Mutex{.{}} ** key_lock_stripes,
+
+    const key_lock_stripes = 16;
+
+    const Entry = struct {
+        token: Token,
+        cached_at: u64,
+        last_used: u64,
+    };
+
+    fn keyLock(self: *CachedStorage, key: []const u8) *std.Thread.Mutex {
+        return &self.key_locks[std.hash.Wyhash.hash(0, key) % key_lock_stripes];
+    }
+
+    pub fn init(allocator: Allocator, backing: SessionStorage, capacity: usize) !CachedStorage {
+        if (capacity == 0) return error.InvalidParameter;
+        return .{ .allocator = allocator, .backing = backing, .capacity = capacity };
+    }
+
+    pub fn deinit(self: *CachedStorage) void {
+        var iter = self.entries.iterator();
+        while (iter.next()) |entry| {
+            self.allocator.free(entry.key_ptr.*);
+            entry.value_ptr.token.deinit();
         }
+        self.entries.deinit(self.allocator);
     }
 
-    fn macosStoreKeychain(allocator: Allocator, service: []const u8, account: []const u8, data: []const u8) !void {
-        // Validate inputs to prevent command injection
-        try validateCommandArgument(service);
-        try validateCommandArgument(account);
+    /// Expire cached entries after `seconds`, bounding how long a token
+    /// refreshed by another process can be served stale
+    pub fn withMaxAge(self: *CachedStorage, seconds: u64) void {
+        self.max_age = seconds;
+    }
 
-        // Use security command-line tool
-        const args = [_][]const u8{
-            "security",
-            "add-generic-password",
-            "-U", // Update if exists
-            "-s",
-            service,
-            "-a",
-            account,
-            "-w",
-            data,
+    pub fn storage(self: *CachedStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+                .capabilities = capabilities,
+                .borrow = borrow,
+                .list_keys = listKeys,
+            }),
         };
-
-        var child = std.process.Child.init(&args, allocator);
-        child.stderr_behavior = .Ignore;
-        child.stdout_behavior = .Ignore;
-        _ = try child.spawnAndWait();
     }
 
-    fn macosLoadKeychain(allocator: Allocator, service: []const u8, account: []const u8) ![]const u8 {
-        // Validate inputs to prevent command injection
-        try validateCommandArgument(service);
-        try validateCommandArgument(account);
-
-        const args = [_][]const u8{
-            "security",
-            "find-generic-password",
-            "-s",
-            service,
-            "-a",
-            account,
-            "-w",
-        };
-
-        var child = std.process.Child.init(&args, allocator);
-        child.stderr_behavior = .Ignore;
-        child.stdout_behavior = .Pipe;
-
-        try child.spawn();
-
-        const stdout = child.stdout orelse return error.NotFound;
-        const output = try stdout.readToEndAlloc(allocator, 1024 * 1024);
+    /// Drop the cached copy of `key`; the next load reads the backend
+    pub fn invalidate(self: *CachedStorage, key: []const u8) void {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        self.remove(key);
+    }
 
-        const result = try child.wait();
-        if (result.Exited != 0) {
-            allocator.free(output);
-            return error.NotFound;
-        }
+    /// Drop every cached entry
+    pub fn invalidateAll(self: *CachedStorage) void {
+        self.mutex.lock();
+        defer self.mutex.unlock();
 
-        // Remove trailing newline
-        const trimmed = mem.trimRight(u8, output, "\n");
-        if (trimmed.len == output.len) {
-            return output;
+        var iter = self.entries.iterator();
+        while (iter.next()) |entry| {
+            self.allocator.free(entry.key_ptr.*);
+            entry.value_ptr.token.deinit();
         }
-
-        const result_data = try allocator.dupe(u8, trimmed);
-        allocator.free(output);
-        return result_data;
+        self.entries.clearRetainingCapacity();
     }
 
-    fn macosDeleteKeychain(allocator: Allocator, service: []const u8, account: []const u8) !void {
-        // Validate inputs to prevent command injection
-        try validateCommandArgument(service);
-        try validateCommandArgument(account);
+    /// Number of cached entries
+    pub fn count(self: *CachedStorage) usize {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        return self.entries.count();
+    }
 
-        const args = [_][]const u8{
-            "security",
-            "delete-generic-password",
-            "-s",
-            service,
-            "-a",
-            account,
-        };
+    fn remove(self: *CachedStorage, key: []const u8) void {
+        if (self.entries.fetchRemove(key)) |old| {
+            self.allocator.free(old.key);
+            var token = old.value.token;
+            token.deinit();
+        }
+    }
 
-        var child = std.process.Child.init(&args, allocator);
-        child.stderr_behavior = .Ignore;
-        child.stdout_behavior = .Ignore;
-        _ = try child.spawnAndWait();
+    /// Fresh cached entry for `key`, marked as used; caller holds the mutex
+    fn lookup(self: *CachedStorage, key: []const u8) ?*Entry {
+        const entry = self.entries.getPtr(key) orelse return null;
+        if (self.max_age) |max_age| {
+            if (clock.now() -| entry.cached_at >= max_age) {
+                self.remove(key);
+                return null;
+            }
+        }
+        self.tick += 1;
+        entry.last_used = self.tick;
+        return entry;
     }
 
-    fn linuxStoreSecret(allocator: Allocator, service: []const u8, account: []const u8, data: []const u8) !void {
-        // Validate inputs to prevent command injection
-        try validateCommandArgument(service);
-        try validateCommandArgument(account);
+    /// Cache a copy of `token`; caller holds the mutex
+    fn put(self: *CachedStorage, key: []const u8, token: *const Token) !void {
+        var copy = try token.clone(self.allocator);
+        errdefer copy.deinit();
 
-        // Use secret-tool from libsecret
-        const args = [_][]const u8{
-            "secret-tool",
-            "store",
-            "--label",
-            service,
-            "service",
-            service,
-            "account",
-            account,
-        };
+        self.remove(key);
+        if (self.entries.count() >= self.capacity) self.evictLeastRecentlyUsed();
 
-        var child = std.process.Child.init(&args, allocator);
-        child.stdin_behavior = .Pipe;
-        child.stderr_behavior = .Ignore;
-        child.stdout_behavior = .Ignore;
+        const key_copy = try self.allocator.dupe(u8, key);
+        errdefer self.allocator.free(key_copy);
 
-        try child.spawn();
+        self.tick += 1;
+        try self.entries.put(self.allocator, key_copy, .{ .token = copy, .cached_at = clock.now(), .last_used = self.tick });
+    }
 
-        if (child.stdin) |stdin| {
-            try stdin.writeAll(data);
-            stdin.close();
-            child.stdin = null;
+    fn evictLeastRecentlyUsed(self: *CachedStorage) void {
+        var oldest: ?[]const u8 = null;
+        var oldest_tick: u64 = std.math.maxInt(u64);
+        var iter = self.entries.iterator();
+        while (iter.next()) |entry| {
+            if (entry.value_ptr.last_used < oldest_tick) {
+                oldest_tick = entry.value_ptr.last_used;
+                oldest = entry.key_ptr.*;
+            }
         }
+        if (oldest) |key| self.remove(key);
+    }
 
-        _ = try child.wait();
+    fn capabilities(ptr: *anyopaque) StorageCapabilities {
+        const self: *CachedStorage = @ptrCast(@alignCast(ptr));
+        var caps = self.backing.capabilities();
+        // Session calls are not forwarded
+        caps.sessions = false;
+        return caps;
     }
 
-    fn linuxLoadSecret(allocator: Allocator, service: []const u8, account: []const u8) ![]const u8 {
-        // Validate inputs to prevent command injection
-        try validateCommandArgument(service);
-        try validateCommandArgument(account);
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) anyerror![][]const u8 {
+        const self: *CachedStorage = @ptrCast(@alignCast(ptr));
+        return self.backing.listKeys(allocator);
+    }
 
-        const args = [_][]const u8{
-            "secret-tool",
-            "lookup",
-            "service",
-            service,
-            "account",
-            account,
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        const self: *CachedStorage = @ptrCast(@alignCast(ptr));
+        const key_lock = self.keyLock(key);
+        key_lock.lock();
+        defer key_lock.unlock();
+
+        self.backing.save(key, token) catch |err| {
+            // The backend may hold either version now
+            self.invalidate(key);
+            return err;
         };
 
-        var child = std.process.Child.init(&args, allocator);
-        child.stderr_behavior = .Ignore;
-        child.stdout_behavior = .Pipe;
-
-        try child.spawn();
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        // The token is saved; failing to cache it only costs a later read
+        self.put(key, &token) catch self.remove(key);
+    }
 
-        const stdout = child.stdout orelse return error.NotFound;
-        const output = try stdout.readToEndAlloc(allocator, 1024 * 1024);
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *CachedStorage = @ptrCast(@alignCast(ptr));
 
-        const result = try child.wait();
-        if (result.Exited != 0) {
-            allocator.free(output);
-            return error.NotFound;
+        {
+            self.mutex.lock();
+            defer self.mutex.unlock();
+            if (self.lookup(key)) |entry| return try entry.token.clone(allocator);
         }
 
-        return output;
-    }
+        const key_lock = self.keyLock(key);
+        key_lock.lock();
+        defer key_lock.unlock();
 
-    fn linuxDeleteSecret(allocator: Allocator, service: []const u8, account: []const u8) !void {
-        // Validate inputs to prevent command injection
-        try validateCommandArgument(service);
-        try validateCommandArgument(account);
+        {
+            // Another thread may have loaded or saved it meanwhile
+            self.mutex.lock();
+            defer self.mutex.unlock();
+            if (self.lookup(key)) |entry| return try entry.token.clone(allocator);
+        }
 
-        const args = [_][]const u8{
-            "secret-tool",
-            "clear",
-            "service",
-            service,
-            "account",
-            account,
-        };
+        var token = (try self.backing.load(allocator, key)) orelse return null;
+        errdefer token.deinit();
 
-        var child = std.process.Child.init(&args, allocator);
-        child.stderr_behavior = .Ignore;
-        child.stdout_behavior = .Ignore;
-        _ = try child.spawnAndWait();
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        self.put(key, &token) catch {};
+        return token;
     }
 
-    fn getFallbackPath(allocator: Allocator, service: []const u8, account: []const u8) ![]const u8 {
-        // Use XDG_RUNTIME_DIR if available (per-user, tmpfs, secure permissions)
-        // Otherwise use user-specific directory under XDG_DATA_HOME or HOME
-        if (getEnvVar(allocator, "XDG_RUNTIME_DIR")) |runtime_dir| {
-            defer allocator.free(runtime_dir);
-            return std.fmt.allocPrint(allocator, "{s}/schlussel-{s}-{s}", .{ runtime_dir, service, account });
+    fn borrow(
+        ptr: *anyopaque,
+        key: []const u8,
+        context: *anyopaque,
+        visit: *const fn (context: *anyopaque, token: ?*const Token) void,
+    ) anyerror!void {
+        const self: *CachedStorage = @ptrCast(@alignCast(ptr));
+
+        {
+            self.mutex.lock();
+            defer self.mutex.unlock();
+            if (self.lookup(key)) |entry| return visit(context, &entry.token);
         }
-        if (getEnvVar(allocator, "XDG_DATA_HOME")) |data_home| {
-            defer allocator.free(data_home);
-            return std.fmt.allocPrint(allocator, "{s}/schlussel/.{s}-{s}", .{ data_home, service, account });
+
+        const key_lock = self.keyLock(key);
+        key_lock.lock();
+        defer key_lock.unlock();
+
+        {
+            self.mutex.lock();
+            defer self.mutex.unlock();
+            if (self.lookup(key)) |entry| return visit(context, &entry.token);
         }
-        if (getEnvVar(allocator, "HOME")) |home| {
-            defer allocator.free(home);
-            return std.fmt.allocPrint(allocator, "{s}/.local/share/schlussel/.{s}-{s}", .{ home, service, account });
+
+        var token = try self.backing.load(self.allocator, key);
+        defer if (token) |*t| t.deinit();
+        if (token) |*t| {
+            self.mutex.lock();
+            defer self.mutex.unlock();
+            self.put(key, t) catch {};
         }
-        // Last resort: use a predictable but user-specific location
-        return std.fmt.allocPrint(allocator, "/tmp/.schlussel-{d}-{s}-{s}", .{ std.os.linux.getuid(), service, account });
+        visit(context, if (token) |*t| t else null);
     }
 
-    fn fallbackStore(allocator: Allocator, service: []const u8, account: []const u8, data: []const u8) !void {
-        const path = try getFallbackPath(allocator, service, account);
-        defer allocator.free(path);
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        const self: *CachedStorage = @ptrCast(@alignCast(ptr));
+        const key_lock = self.keyLock(key);
+        key_lock.lock();
+        defer key_lock.unlock();
 
-        // Ensure parent directory exists
-        if (std.fs.path.dirname(path)) |dir| {
-            fs.cwd().makePath(dir) catch |err| {
-                if (err != error.PathAlreadyExists) return err;
-            };
-        }
+        defer self.invalidate(key);
+        try self.backing.delete(key);
+    }
 
-        // Create file with restrictive permissions (owner read/write only)
-        const file = try fs.cwd().createFile(path, .{ .mode = 0o600 });
-        defer file.close();
-        try file.writeAll(data);
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        const self: *CachedStorage = @ptrCast(@alignCast(ptr));
+        {
+            self.mutex.lock();
+            defer self.mutex.unlock();
+            if (self.lookup(key) != null) return true;
+        }
+        return self.backing.exists(key);
     }
+};
 
-    fn fallbackLoad(allocator: Allocator, service: []const u8, account: []const u8) ![]const u8 {
-        const path = try getFallbackPath(allocator, service, account);
-        defer allocator.free(path);
+/// Read-only storage resolving tokens from environment variables
+///
+/// A key is looked up in `<prefix><KEY>` first, with the key upper-cased
+/// and every character other than a letter or digit replaced by `_`
+/// (`github:device` reads `SCHLUSSEL_TOKEN_GITHUB_DEVICE`), then in the
+/// JSON object held by `blob_variable`, keyed by the storage key. A value
+/// is either a bare access token or a token JSON document.
+///
+/// Lets CI jobs inject credentials without touching disk. Saving and
+/// deleting return `error.UnsupportedOperation`, so ChainedStorage cannot
+/// evict a variable that shadows a newer token: put the storage behind a
+/// writable one, which then serves tokens refreshed from the variables.
+pub const EnvStorage = struct {
+    allocator: Allocator,
+    options: Options,
+    env: *const fn (allocator: Allocator, name: []const u8) ?[]u8 = getOwnedEnvVar,
+
+    pub const Options = struct {
+        /// Prefix of per-key variables
+        prefix: []const u8 = "SCHLUSSEL_TOKEN_",
+        /// Variable holding a JSON object of tokens by key (null to skip)
+        blob_variable: ?[]const u8 = "SCHLUSSEL_TOKENS",
+        /// Token type of bare access tokens
+        token_type: []const u8 = "Bearer",
+    };
 
-        const file = fs.cwd().openFile(path, .{}) catch return error.NotFound;
-        defer file.close();
-        return try file.readToEndAlloc(allocator, 1024 * 1024);
+    pub fn init(allocator: Allocator, options: Options) EnvStorage {
+        return .{ .allocator = allocator, .options = options };
     }
 
-    fn fallbackDelete(allocator: Allocator, service: []const u8, account: []const u8) !void {
-        const path = try getFallbackPath(allocator, service, account);
-        defer allocator.free(path);
-
-        fs.cwd().deleteFile(path) catch |err| {
-            if (err != error.FileNotFound) return err;
+    pub fn storage(self: *EnvStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+            }),
         };
     }
-};
 
-/// Escape a string for JSON output
-fn appendJsonEscaped(allocator: Allocator, buf: *std.ArrayListUnmanaged(u8), input: []const u8) !void {
-    for (input) |c| {
-        switch (c) {
-            '"' => try buf.appendSlice(allocator, "\\\""),
-            '\\' => try buf.appendSlice(allocator, "\\\\"),
-            '\n' => try buf.appendSlice(allocator, "\\n"),
-            '\r' => try buf.appendSlice(allocator, "\\r"),
-            '\t' => try buf.appendSlice(allocator, "\\t"),
-            // Other control characters (excluding \n, \r, \t which are handled above)
-            0x00...0x08, 0x0B, 0x0C, 0x0E...0x1F => {
-                // Control characters - escape as \uXXXX
-                try buf.appendSlice(allocator, "\\u00");
-                const hex = "0123456789abcdef";
-                try buf.append(allocator, hex[c >> 4]);
-                try buf.append(allocator, hex[c & 0x0F]);
-            },
-            else => try buf.append(allocator, c),
+    /// Per-key variable consulted for `key`; caller owns it
+    pub fn variableName(self: *const EnvStorage, allocator: Allocator, key: []const u8) ![]u8 {
+        if (key.len == 0) return error.InvalidParameter;
+        const name = try std.fmt.allocPrint(allocator, "{s}{s}", .{ self.options.prefix, key });
+        for (name[self.options.prefix.len..]) |*c| {
+            c.* = if (std.ascii.isAlphanumeric(c.*)) std.ascii.toUpper(c.*) else '_';
         }
+        return name;
     }
-}
 
-/// Validate that a string is safe for use as a command argument
-/// Returns error if the string contains potentially dangerous characters
-fn validateCommandArgument(arg: []const u8) !void {
-    if (arg.len == 0) return error.InvalidParameter;
+    /// Read a variable into memory the caller owns and zeroes after use
+    fn getOwnedEnvVar(allocator: Allocator, name: []const u8) ?[]u8 {
+        return std.process.getEnvVarOwned(allocator, name) catch null;
+    }
 
-    // Reject arguments that look like flags (start with -)
-    if (arg[0] == '-') return error.InvalidParameter;
+    fn save(_: *anyopaque, _: []const u8, _: Token) !void {
+        return error.UnsupportedOperation;
+    }
 
-    for (arg) |c| {
-        switch (c) {
-            // Reject null bytes, newlines, and shell metacharacters
-            0, '\n', '\r', '`', '$', '|', '&', ';', '>', '<' => return error.InvalidParameter,
-            else => {},
-        }
+    fn delete(_: *anyopaque, _: []const u8) !void {
+        return error.UnsupportedOperation;
     }
-}
 
-test "Token creation and expiration" {
-    const allocator = std.testing.allocator;
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *EnvStorage = @ptrCast(@alignCast(ptr));
+
+        const name = try self.variableName(allocator, key);
+        defer allocator.free(name);
+        if (self.env(allocator, name)) |value| {
+            defer {
+                std.crypto.secureZero(u8, value);
+                allocator.free(value);
+            }
+            return try self.parseValue(allocator, value);
+        }
 
-    var token = try Token.init(allocator, "test_token", "Bearer");
-    defer token.deinit();
+        const blob_variable = self.options.blob_variable orelse return null;
+        const blob = self.env(allocator, blob_variable) orelse return null;
+        defer {
+            std.crypto.secureZero(u8, blob);
+            allocator.free(blob);
+        }
 
-    try std.testing.expectEqualStrings("test_token", token.access_token);
-    try std.testing.expectEqualStrings("Bearer", token.token_type);
-    try std.testing.expect(!token.isExpired());
-}
+        const parsed = json.parseFromSlice(json.Value, allocator, blob, .{}) catch return error.InvalidParameter;
+        defer parsed.deinit();
+        if (parsed.value != .object) return error.InvalidParameter;
+
+        const entry = parsed.value.object.get(key) orelse return null;
+        return switch (entry) {
+            .string => |access_token| try self.bareToken(allocator, access_token),
+            .object => try Token.fromJsonValue(allocator, entry),
+            else => return error.InvalidParameter,
+        };
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        const self: *EnvStorage = @ptrCast(@alignCast(ptr));
+        var token = (load(ptr, self.allocator, key) catch return false) orelse return false;
+        token.deinit();
+        return true;
+    }
+
+    fn parseValue(self: *const EnvStorage, allocator: Allocator, value: []const u8) !Token {
+        const trimmed = mem.trim(u8, value, " \t\r\n");
+        if (trimmed.len == 0) return error.InvalidParameter;
+        if (trimmed[0] == '{') return Token.fromJson(allocator, trimmed) catch |err| switch (err) {
+            error.OutOfMemory => return err,
+            else => return error.InvalidParameter,
+        };
+        return self.bareToken(allocator, trimmed);
+    }
+
+    fn bareToken(self: *const EnvStorage, allocator: Allocator, access_token: []const u8) !Token {
+        if (access_token.len == 0) return error.InvalidParameter;
+        return Token.init(allocator, access_token, self.options.token_type);
+    }
+};
+
+/// Secret store for key material (e.g. the OS keychain)
+///
+/// SecureStorage.keystore() provides one backed by the platform credential
+/// manager. FileStorage.withKeystoreKey() keeps its encryption key here.
+pub const Keystore = struct {
+    ptr: *anyopaque,
+    vtable: *const VTable,
+
+    pub const VTable = struct {
+        /// Load a secret (null if missing); caller owns the result
+        load: *const fn (ptr: *anyopaque, allocator: Allocator, name: []const u8) anyerror!?[]const u8,
+        /// Store a secret, replacing any previous value
+        store: *const fn (ptr: *anyopaque, name: []const u8, secret: []const u8) anyerror!void,
+    };
+
+    pub fn load(self: Keystore, allocator: Allocator, name: []const u8) !?[]const u8 {
+        return self.vtable.load(self.ptr, allocator, name);
+    }
+
+    pub fn store(self: Keystore, name: []const u8, secret: []const u8) !void {
+        return self.vtable.store(self.ptr, name, secret);
+    }
+};
+
+/// Source of the AEAD keys FileStorage seals token files with
+///
+/// Keys are 32 bytes and named by an id of 1 to 255 bytes that is written
+/// into every file, so a provider backed by a KMS, an HSM or an OS keystore
+/// can rotate keys while older files stay readable. Only the key material
+/// comes from the provider; the tokens stay in the storage directory.
+pub const KeyProvider = struct {
+    ptr: *anyopaque,
+    vtable: *const VTable,
+
+    pub const VTable = struct {
+        /// Fill `out` with the key new files are sealed with and return its
+        /// id, which must stay valid while the provider lives
+        current: *const fn (ptr: *anyopaque, out: *[32]u8) anyerror![]const u8,
+        /// Fill `out` with the key named `id`; false if the id is unknown
+        lookup: *const fn (ptr: *anyopaque, id: []const u8, out: *[32]u8) anyerror!bool,
+    };
+
+    pub fn current(self: KeyProvider, out: *[32]u8) ![]const u8 {
+        return self.vtable.current(self.ptr, out);
+    }
+
+    pub fn lookup(self: KeyProvider, id: []const u8, out: *[32]u8) !bool {
+        return self.vtable.lookup(self.ptr, id, out);
+    }
+};
+
+/// KeyProvider over a fixed list of keys
+///
+/// Seals with `entries[current]` and opens files sealed with any entry, so
+/// adding a key and pointing `current` at it rotates without rewriting.
+pub const StaticKeyProvider = struct {
+    entries: []const Entry,
+    current: usize = 0,
+
+    pub const Entry = struct {
+        id: []const u8,
+        key: [32]u8,
+    };
+
+    pub fn keyProvider(self: *StaticKeyProvider) KeyProvider {
+        return .{
+            .ptr = self,
+            .vtable = &.{
+                .current = currentKey,
+                .lookup = lookupKey,
+            },
+        };
+    }
+
+    fn currentKey(ptr: *anyopaque, out: *[32]u8) ![]const u8 {
+        const self: *StaticKeyProvider = @ptrCast(@alignCast(ptr));
+        if (self.current >= self.entries.len) return error.ConfigurationError;
+        const entry = self.entries[self.current];
+        out.* = entry.key;
+        return entry.id;
+    }
+
+    fn lookupKey(ptr: *anyopaque, id: []const u8, out: *[32]u8) !bool {
+        const self: *StaticKeyProvider = @ptrCast(@alignCast(ptr));
+        for (self.entries) |entry| {
+            if (mem.eql(u8, entry.id, id)) {
+                out.* = entry.key;
+                return true;
+            }
+        }
+        return false;
+    }
+};
+
+/// File-based JSON storage
+///
+/// WARNING: Tokens are stored in plaintext unless an encryption key is set
+/// with withEncryptionKey(). Use SecureStorage for production.
+pub const FileStorage = struct {
+    allocator: Allocator,
+    base_path: []const u8,
+    /// Key for encrypting tokens at rest (see withEncryptionKey)
+    encryption_key: ?[Aead.key_length]u8 = null,
+    /// Source of per-file keys, preferred over encryption_key for saves
+    /// (see withKeyProvider)
+    key_provider: ?KeyProvider = null,
+    /// Key of the rotatePassphrase() in progress, tried when encryption_key
+    /// does not open a file (an earlier, interrupted run resealed it)
+    rotation_key: ?[Aead.key_length]u8 = null,
+    /// Order concurrent writes to the same key (see withSerializedWrites)
+    serialize_writes: bool = false,
+    /// Striped per-key write locks, used when serialize_writes is set
+    write_locks: [write_lock_stripes]std.Thread.Mutex = [_]std.Thread.Mutex{.{}} ** write_lock_stripes,
+    /// Mode for the storage directory (see withPermissions)
+    dir_mode: fs.File.Mode = default_dir_mode,
+    /// Mode for token files (see withPermissions)
+    file_mode: fs.File.Mode = default_file_mode,
+    /// What load() does with files looser than file_mode
+    permission_check: PermissionCheck = .warn,
+
+    const write_lock_stripes = 16;
+    /// Extension of session files, kept apart from `<key>.json` tokens
+    const session_extension = ".session";
+    const posix_modes = @import("builtin").os.tag != .windows;
+    const default_dir_mode: fs.File.Mode = if (posix_modes) 0o700 else 0;
+    const default_file_mode: fs.File.Mode = if (posix_modes) 0o600 else 0;
+
+    /// Reaction to token files that other users may read or write, or
+    /// that another user owns
+    pub const PermissionCheck = enum {
+        ignore,
+        /// Log a warning and load the token anyway
+        warn,
+        /// Refuse to load it with `error.InsecurePermissions`, like SSH
+        /// does for private keys
+        reject,
+    };
+
+    const Aead = std.crypto.aead.chacha_poly.ChaCha20Poly1305;
+    const argon2 = std.crypto.pwhash.argon2;
+    /// Leading bytes of an encrypted token file
+    const encrypted_magic = "SCHLUSSEL-ENC1\x00";
+    /// Leading bytes of a file sealed under a KeyProvider key
+    const provider_magic = "SCHLUSSEL-ENC2\x00";
+    /// Salt for passphrase-derived keys, next to the token files
+    const salt_file = ".kdf-salt";
+    /// Salt of a rotatePassphrase() that has not finished yet
+    const pending_salt_file = ".kdf-salt.next";
+    const salt_length = 16;
+
+    /// Argon2id cost for withPassphrase(): 3 passes over 64 MiB
+    pub const default_kdf_params: argon2.Params = .{ .t = 3, .m = 64 * 1024, .p = 1 };
+
+    /// On-disk representation of a stored token
+    pub const FileFormat = enum {
+        /// Plain JSON document
+        plaintext,
+        /// ChaCha20-Poly1305 sealed document (see withEncryptionKey and
+        /// withKeyProvider)
+        encrypted,
+    };
+
+    /// Initialize with a base directory path
+    ///
+    /// Uses `$SCHLUSSEL_DATA_DIR/<app_name>` when set, otherwise the
+    /// platform data directory: `$XDG_DATA_HOME` (or `~/.local/share`) on
+    /// Linux, `~/Library/Application Support` on macOS and `%LOCALAPPDATA%`
+    /// on Windows. Use initWithPath() for an explicit directory.
+    pub fn init(allocator: Allocator, app_name: []const u8) !FileStorage {
+        const base_path = try getStoragePath(allocator, app_name);
+        return .{
+            .allocator = allocator,
+            .base_path = base_path,
+        };
+    }
+
+    /// Initialize with a custom directory path
+    pub fn initWithPath(allocator: Allocator, path: []const u8) !FileStorage {
+        return .{
+            .allocator = allocator,
+            .base_path = try allocator.dupe(u8, path),
+        };
+    }
+
+    pub fn deinit(self: *FileStorage) void {
+        if (self.encryption_key) |*key| std.crypto.secureZero(u8, key);
+        if (self.rotation_key) |*key| std.crypto.secureZero(u8, key);
+        self.allocator.free(self.base_path);
+    }
+
+    /// Serialize writes to the same key within this process
+    ///
+    /// Saves always replace the file atomically, so readers only ever see a
+    /// complete token and the last rename wins. With serialized writes,
+    /// concurrent saves and deletes of one key additionally run one after
+    /// another instead of racing at the filesystem level.
+    pub fn withSerializedWrites(self: *FileStorage) void {
+        self.serialize_writes = true;
+    }
+
+    /// Create the directory and token files with these modes
+    ///
+    /// Defaults to 0700 and 0600. Ignored on Windows, which has no POSIX
+    /// modes.
+    pub fn withPermissions(self: *FileStorage, dir_mode: fs.File.Mode, file_mode: fs.File.Mode) void {
+        self.dir_mode = dir_mode;
+        self.file_mode = file_mode;
+    }
+
+    /// Choose how load() treats files with permissions looser than
+    /// file_mode or owned by another user
+    pub fn withPermissionCheck(self: *FileStorage, check: PermissionCheck) void {
+        self.permission_check = check;
+    }
+
+    /// Encrypt tokens at rest with ChaCha20-Poly1305 under a 32-byte key
+    ///
+    /// Each save seals the JSON document with a fresh random nonce and binds
+    /// it to its storage key, so a file copied under another key does not
+    /// decrypt. Loading a file that fails authentication (wrong key, tampered
+    /// contents, or a plaintext file) returns `error.DecryptionFailed`; use
+    /// fileFormat() to find plaintext files that still need migrating.
+    pub fn withEncryptionKey(self: *FileStorage, key: [Aead.key_length]u8) void {
+        self.encryption_key = key;
+    }
+
+    /// Seal tokens at rest with keys from `provider`
+    ///
+    /// Each save asks the provider for its current key and records the
+    /// key id in the file; loads look the id up again, so files sealed
+    /// under an older key keep opening as long as the provider knows it.
+    /// An unknown id, a failed authentication or a plaintext file returns
+    /// `error.DecryptionFailed`. If an encryption key is also set, files
+    /// written with it stay readable and are resealed under the provider
+    /// the next time they are saved.
+    ///
+    /// `provider` must outlive the storage.
+    pub fn withKeyProvider(self: *FileStorage, provider: KeyProvider) void {
+        self.key_provider = provider;
+    }
+
+    /// Encrypt tokens at rest with a data key kept in `keystore`
+    ///
+    /// Loads the key stored under `name`, or generates a random one and
+    /// stores it on first use, then behaves like withEncryptionKey(). The
+    /// OS keystore guards the key, so the files are only readable by a
+    /// process that can unlock it.
+    ///
+    /// A new key is only generated when the keystore reports the entry
+    /// as missing. If the keystore is unavailable (locked keychain, denied
+    /// access, no Secret Service daemon in a headless session) its error,
+    /// `error.StorageError` for SecureStorage, is returned and the storage
+    /// is left unchanged: callers should fail rather than carry on with
+    /// plaintext files or a replacement key. A stored key that is not 32
+    /// bytes of base64 yields `error.DecryptionFailed`. Losing the keystore
+    /// entry makes every file written with it unreadable.
+    ///
+    /// Processes sharing the directory take a lock around the first load,
+    /// so two that start together cannot each store a key of their own.
+    pub fn withKeystoreKey(self: *FileStorage, keystore: Keystore, name: []const u8) !void {
+        const encoding = std.base64.standard;
+        var key: [Aead.key_length]u8 = undefined;
+        defer std.crypto.secureZero(u8, &key);
+
+        fs.cwd().makePath(self.base_path) catch |err| {
+            if (err != error.PathAlreadyExists) return err;
+        };
+        var key_lock = try lock.RefreshLock.acquire(self.allocator, self.base_path, keystore_lock);
+        defer key_lock.release();
+
+        if (try keystore.load(self.allocator, name)) |stored| {
+            defer self.allocator.free(stored);
+            const trimmed = mem.trim(u8, stored, " \r\n");
+            const size = encoding.Decoder.calcSizeForSlice(trimmed) catch return error.DecryptionFailed;
+            if (size != key.len) return error.DecryptionFailed;
+            encoding.Decoder.decode(&key, trimmed) catch return error.DecryptionFailed;
+        } else {
+            std.crypto.random.bytes(&key);
+            var encoded: [encoding.Encoder.calcSize(Aead.key_length)]u8 = undefined;
+            defer std.crypto.secureZero(u8, &encoded);
+            try keystore.store(name, encoding.Encoder.encode(&encoded, &key));
+        }
+
+        self.withEncryptionKey(key);
+    }
+
+    /// Lock file (`.keystore.lock`) held while withKeystoreKey() creates a key
+    const keystore_lock = ".keystore";
+
+    /// Encrypt tokens at rest with a key derived from `passphrase`
+    ///
+    /// The key is derived with Argon2id under `params` (usually
+    /// default_kdf_params) and a random salt kept in the storage directory,
+    /// created on first use. The same passphrase and params yield the same
+    /// key again; a different passphrase makes loads fail with
+    /// `error.DecryptionFailed`.
+    pub fn withPassphrase(self: *FileStorage, passphrase: []const u8, params: argon2.Params) !void {
+        if (passphrase.len == 0) return error.InvalidParameter;
+
+        const salt = try self.loadOrCreateSalt();
+        var key = try deriveKey(self.allocator, passphrase, &salt, params);
+        defer std.crypto.secureZero(u8, &key);
+        self.withEncryptionKey(key);
+    }
+
+    /// Re-encrypt every stored token under `new_key` and switch to it
+    ///
+    /// Tokens are read with the current key (or as plaintext if there is
+    /// none, which migrates plaintext files) before any file is rewritten,
+    /// so a wrong current key fails with `error.DecryptionFailed` and
+    /// leaves the files untouched. Returns the number of tokens rewritten.
+    /// If a write fails midway, the current key stays in use and `new_key`
+    /// is kept as the rotation key, so resealed files keep opening; calling
+    /// again finishes the rotation. With a key provider set, rotating is the
+    /// provider's job and `error.UnsupportedOperation` is returned.
+    pub fn rotateEncryptionKey(self: *FileStorage, new_key: [Aead.key_length]u8) !usize {
+        if (self.key_provider != null) return error.UnsupportedOperation;
+
+        const keys = try listKeys(self, self.allocator);
+        defer SessionStorage.freeKeys(self.allocator, keys);
+
+        const Stored = struct { key: []const u8, token: Token };
+        var stored: std.ArrayListUnmanaged(Stored) = .{};
+        defer {
+            for (stored.items) |*entry| entry.token.deinit();
+            stored.deinit(self.allocator);
+        }
+        try stored.ensureTotalCapacity(self.allocator, keys.len);
+        for (keys) |key| {
+            // Skip files deleted since they were listed
+            const token = (try load(self, self.allocator, key)) orelse continue;
+            stored.appendAssumeCapacity(.{ .key = key, .token = token });
+        }
+
+        const previous_key = self.encryption_key;
+        self.withEncryptionKey(new_key);
+        errdefer {
+            // Files resealed before the failure only open with new_key
+            self.encryption_key = previous_key;
+            self.rotation_key = new_key;
+        }
+
+        for (stored.items) |entry| try save(self, entry.key, entry.token);
+
+        if (self.rotation_key) |*pending| std.crypto.secureZero(u8, pending);
+        self.rotation_key = null;
+        return stored.items.len;
+    }
+
+    /// Re-encrypt every stored token under a key derived from `passphrase`
+    ///
+    /// Like rotateEncryptionKey(), under a fresh salt. The new salt is
+    /// stored durably (as `.kdf-salt.next`) before any token is resealed
+    /// and replaces the old one once every token is rewritten, so no file
+    /// is ever sealed under a salt that is not on disk. If the rotation is
+    /// interrupted, open the storage with the old passphrase and call
+    /// rotatePassphrase() again with the same new one: it reuses the
+    /// pending salt and finishes the job. Like rotateEncryptionKey(), fails
+    /// with `error.UnsupportedOperation` while a key provider is set.
+    pub fn rotatePassphrase(self: *FileStorage, passphrase: []const u8, params: argon2.Params) !usize {
+        if (self.key_provider != null) return error.UnsupportedOperation;
+        if (passphrase.len == 0) return error.InvalidParameter;
+
+        const salt = (try self.readSalt(pending_salt_file)) orelse blk: {
+            var fresh: [salt_length]u8 = undefined;
+            std.crypto.random.bytes(&fresh);
+            try self.writeSalt(pending_salt_file, &fresh);
+            break :blk fresh;
+        };
+        var key = try deriveKey(self.allocator, passphrase, &salt, params);
+        defer std.crypto.secureZero(u8, &key);
+
+        // Opens the files an interrupted earlier run resealed; cleared once
+        // rotateEncryptionKey() has rewritten every token
+        self.rotation_key = key;
+        const rewritten = try self.rotateEncryptionKey(key);
+
+        try self.renameInDir(pending_salt_file, salt_file);
+        return rewritten;
+    }
+
+    /// Derive a 32-byte key from `passphrase` with Argon2id
+    ///
+    /// Shared with the encrypted token bundles of migration.zig.
+    pub fn deriveKey(allocator: Allocator, passphrase: []const u8, salt: []const u8, params: argon2.Params) ![Aead.key_length]u8 {
+        if (passphrase.len == 0) return error.InvalidParameter;
+        var key: [Aead.key_length]u8 = undefined;
+        argon2.kdf(allocator, &key, passphrase, salt, params, .argon2id) catch |err| switch (err) {
+            error.OutOfMemory => return error.OutOfMemory,
+            else => return error.InvalidParameter,
+        };
+        return key;
+    }
+
+    fn loadOrCreateSalt(self: *FileStorage) ![salt_length]u8 {
+        if (try self.readSalt(salt_file)) |salt| return salt;
+
+        var salt: [salt_length]u8 = undefined;
+        std.crypto.random.bytes(&salt);
+        try self.writeSalt(salt_file, &salt);
+        return salt;
+    }
+
+    /// Salt stored in `name`, or null if there is none
+    fn readSalt(self: *FileStorage, name: []const u8) !?[salt_length]u8 {
+        const path = try std.fmt.allocPrint(self.allocator, "{s}/{s}", .{ self.base_path, name });
+        defer self.allocator.free(path);
+
+        const file = fs.cwd().openFile(path, .{}) catch |err| {
+            if (err == error.FileNotFound) return null;
+            return err;
+        };
+        defer file.close();
+
+        var salt: [salt_length]u8 = undefined;
+        if (try file.readAll(&salt) != salt.len) return error.DecryptionFailed;
+        return salt;
+    }
+
+    /// Store `salt` in `name` atomically and durably
+    fn writeSalt(self: *FileStorage, name: []const u8, salt: *const [salt_length]u8) !void {
+        fs.cwd().makePath(self.base_path) catch |err| {
+            if (err != error.PathAlreadyExists) return err;
+        };
+
+        const tmp_name = try std.fmt.allocPrint(self.allocator, "{s}.tmp", .{name});
+        defer self.allocator.free(tmp_name);
+        const tmp_path = try std.fmt.allocPrint(self.allocator, "{s}/{s}", .{ self.base_path, tmp_name });
+        defer self.allocator.free(tmp_path);
+
+        {
+            const file = try fs.cwd().createFile(tmp_path, .{ .mode = self.file_mode });
+            defer file.close();
+            errdefer fs.cwd().deleteFile(tmp_path) catch {};
+            try file.writeAll(salt);
+            try file.sync();
+        }
+        try self.renameInDir(tmp_name, name);
+    }
+
+    /// Rename `from` to `to` inside the storage directory and flush the
+    /// directory entry
+    fn renameInDir(self: *FileStorage, from: []const u8, to: []const u8) !void {
+        var dir = try fs.cwd().openDir(self.base_path, .{});
+        defer dir.close();
+        try dir.rename(from, to);
+        try syncDir(dir);
+    }
+
+    /// Flush the entries of `dir`, so a rename into it survives a power loss
+    ///
+    /// A failure is returned: the new file is in place, but it may not be
+    /// after a crash, so the caller should not report the write as durable.
+    fn syncDir(dir: fs.Dir) !void {
+        if (@import("builtin").os.tag == .windows) return;
+        std.posix.fsync(dir.fd) catch |err| {
+            std.log.warn("Could not flush the token directory: {s}", .{@errorName(err)});
+            return err;
+        };
+    }
+
+    /// Watch the file behind `key` for changes made by any process
+    ///
+    /// A background thread checks the file every `interval_ms` and calls
+    /// `watcher` from that thread when it appears, is replaced (every save
+    /// renames a new file into place) or disappears. Stop it with
+    /// FileWatch.stop(); the storage must outlive the watch.
+    pub fn watch(self: *FileStorage, key: []const u8, watcher: TokenWatcher, interval_ms: u64) !*FileWatch {
+        if (interval_ms == 0) return error.InvalidParameter;
+
+        const path = try self.getFilePath(key);
+        errdefer self.allocator.free(path);
+        const key_copy = try self.allocator.dupe(u8, key);
+        errdefer self.allocator.free(key_copy);
+
+        const file_watch = try self.allocator.create(FileWatch);
+        errdefer self.allocator.destroy(file_watch);
+        file_watch.* = .{
+            .allocator = self.allocator,
+            .path = path,
+            .key = key_copy,
+            .watcher = watcher,
+            .interval_ns = interval_ms * std.time.ns_per_ms,
+            .last = FileWatch.snapshot(path),
+            .thread = undefined,
+        };
+        file_watch.thread = try std.Thread.spawn(.{}, FileWatch.run, .{file_watch});
+        return file_watch;
+    }
+
+    /// Report whether the token stored under `key` is plaintext or encrypted
+    ///
+    /// Returns null if there is no token stored under `key`.
+    pub fn fileFormat(self: *FileStorage, key: []const u8) !?FileFormat {
+        const file_path = try self.getFilePath(key);
+        defer self.allocator.free(file_path);
+
+        const file = fs.cwd().openFile(file_path, .{}) catch |err| {
+            if (err == error.FileNotFound) return null;
+            return err;
+        };
+        defer file.close();
+
+        var header: [encrypted_magic.len]u8 = undefined;
+        const read = try file.readAll(&header);
+        if (read == header.len and
+            (mem.eql(u8, &header, encrypted_magic) or mem.eql(u8, &header, provider_magic))) return .encrypted;
+        return .plaintext;
+    }
+
+    /// Seal `plaintext` as `magic || nonce || tag || ciphertext`
+    fn seal(allocator: Allocator, key: [Aead.key_length]u8, storage_key: []const u8, plaintext: []const u8) ![]u8 {
+        const header_len = encrypted_magic.len + Aead.nonce_length + Aead.tag_length;
+        const out = try allocator.alloc(u8, header_len + plaintext.len);
+        errdefer allocator.free(out);
+
+        @memcpy(out[0..encrypted_magic.len], encrypted_magic);
+        const nonce = out[encrypted_magic.len..][0..Aead.nonce_length];
+        std.crypto.random.bytes(nonce);
+        const tag = out[encrypted_magic.len + Aead.nonce_length ..][0..Aead.tag_length];
+
+        Aead.encrypt(out[header_len..], tag, plaintext, storage_key, nonce.*, key);
+        return out;
+    }
+
+    /// Open a document produced by seal(); caller owns the plaintext
+    fn unseal(allocator: Allocator, key: [Aead.key_length]u8, storage_key: []const u8, data: []const u8) ![]u8 {
+        const header_len = encrypted_magic.len + Aead.nonce_length + Aead.tag_length;
+        if (data.len < header_len or !mem.startsWith(u8, data, encrypted_magic)) return error.DecryptionFailed;
+
+        const nonce = data[encrypted_magic.len..][0..Aead.nonce_length];
+        const tag = data[encrypted_magic.len + Aead.nonce_length ..][0..Aead.tag_length];
+        const ciphertext = data[header_len..];
+
+        const plaintext = try allocator.alloc(u8, ciphertext.len);
+        errdefer allocator.free(plaintext);
+
+        Aead.decrypt(plaintext, ciphertext, tag.*, storage_key, nonce.*, key) catch return error.DecryptionFailed;
+        return plaintext;
+    }
+
+    /// Seal `plaintext` under the provider's current key as
+    /// `magic || id_len || id || nonce || tag || ciphertext`
+    fn sealWithProvider(allocator: Allocator, provider: KeyProvider, storage_key: []const u8, plaintext: []const u8) ![]u8 {
+        var key: [Aead.key_length]u8 = undefined;
+        defer std.crypto.secureZero(u8, &key);
+        const id = try provider.current(&key);
+        if (id.len == 0 or id.len > std.math.maxInt(u8)) return error.InvalidParameter;
+
+        const id_end = provider_magic.len + 1 + id.len;
+        const header_len = id_end + Aead.nonce_length + Aead.tag_length;
+        const out = try allocator.alloc(u8, header_len + plaintext.len);
+        errdefer allocator.free(out);
+
+        @memcpy(out[0..provider_magic.len], provider_magic);
+        out[provider_magic.len] = @intCast(id.len);
+        @memcpy(out[provider_magic.len + 1 .. id_end], id);
+        const nonce = out[id_end..][0..Aead.nonce_length];
+        std.crypto.random.bytes(nonce);
+        const tag = out[id_end + Aead.nonce_length ..][0..Aead.tag_length];
+
+        Aead.encrypt(out[header_len..], tag, plaintext, storage_key, nonce.*, key);
+        return out;
+    }
+
+    /// Open a document produced by sealWithProvider(); caller owns the plaintext
+    fn unsealWithProvider(allocator: Allocator, provider: KeyProvider, storage_key: []const u8, data: []const u8) ![]u8 {
+        if (data.len <= provider_magic.len or !mem.startsWith(u8, data, provider_magic)) return error.DecryptionFailed;
+        const id_len = data[provider_magic.len];
+        const id_end = provider_magic.len + 1 + @as(usize, id_len);
+        const header_len = id_end + Aead.nonce_length + Aead.tag_length;
+        if (id_len == 0 or data.len < header_len) return error.DecryptionFailed;
+
+        var key: [Aead.key_length]u8 = undefined;
+        defer std.crypto.secureZero(u8, &key);
+        if (!try provider.lookup(data[provider_magic.len + 1 .. id_end], &key)) return error.DecryptionFailed;
+
+        const nonce = data[id_end..][0..Aead.nonce_length];
+        const tag = data[id_end + Aead.nonce_length ..][0..Aead.tag_length];
+        const ciphertext = data[header_len..];
+
+        const plaintext = try allocator.alloc(u8, ciphertext.len);
+        errdefer allocator.free(plaintext);
+
+        Aead.decrypt(plaintext, ciphertext, tag.*, storage_key, nonce.*, key) catch return error.DecryptionFailed;
+        return plaintext;
+    }
+
+    pub fn storage(self: *FileStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+                .capabilities = capabilities,
+                .list_keys = listKeys,
+                .save_session = saveSession,
+                .load_session = loadSession,
+                .delete_session = deleteSession,
+                .list_session_states = listSessionStates,
+            }),
+        };
+    }
+
+    fn capabilities(_: *anyopaque) StorageCapabilities {
+        // Saves rename a complete temporary file over the old one
+        return .{ .list_keys = true, .atomic_swap = true, .iteration = true, .sessions = true };
+    }
+
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) anyerror![][]const u8 {
+        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+        return self.listFiles(allocator, ".json");
+    }
+
+    fn listSessionStates(ptr: *anyopaque, allocator: Allocator) anyerror![][]const u8 {
+        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+        return self.listFiles(allocator, session_extension);
+    }
+
+    /// Names of the files ending in `extension`, without it
+    fn listFiles(self: *FileStorage, allocator: Allocator, extension: []const u8) ![][]const u8 {
+        var keys: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
+
+        var dir = fs.cwd().openDir(self.base_path, .{ .iterate = true }) catch |err| {
+            // Nothing has been saved yet
+            if (err == error.FileNotFound) return keys.toOwnedSlice(allocator);
+            return err;
+        };
+        defer dir.close();
+
+        var iter = dir.iterate();
+        while (try iter.next()) |entry| {
+            if (entry.kind != .file) continue;
+            // Skips in-flight `<key>.json.<suffix>.tmp` files from save()
+            if (!mem.endsWith(u8, entry.name, extension)) continue;
+
+            const key = entry.name[0 .. entry.name.len - extension.len];
+            validateStorageKey(key) catch continue;
+
+            const copy = try allocator.dupe(u8, key);
+            errdefer allocator.free(copy);
+            try keys.append(allocator, copy);
+        }
+
+        return keys.toOwnedSlice(allocator);
+    }
+
+    fn getFilePath(self: *FileStorage, key: []const u8) ![]const u8 {
+        // Validate key to prevent path traversal attacks
+        try validateStorageKey(key);
+        return std.fmt.allocPrint(self.allocator, "{s}/{s}.json", .{ self.base_path, key });
+    }
+
+    fn getSessionPath(self: *FileStorage, state: []const u8) ![]const u8 {
+        try validateStorageKey(state);
+        return std.fmt.allocPrint(self.allocator, "{s}/{s}" ++ session_extension, .{ self.base_path, state });
+    }
+
+    /// Validate that a storage key is safe (no path traversal or special characters)
+    fn validateStorageKey(key: []const u8) !void {
+        if (key.len == 0) return error.InvalidParameter;
+        if (key.len > 255) return error.InvalidParameter; // Reasonable max length
+
+        for (key) |c| {
+            switch (c) {
+                // Allow alphanumeric, underscore, hyphen, dot (but not leading dot)
+                'a'...'z', 'A'...'Z', '0'...'9', '_', '-' => {},
+                '.' => {
+                    // Disallow if it could be path traversal
+                    if (key.len >= 2 and std.mem.startsWith(u8, key, "..")) {
+                        return error.InvalidParameter;
+                    }
+                },
+                // Disallow path separators and other dangerous characters
+                '/', '\\', 0, '\n', '\r' => return error.InvalidParameter,
+                else => {},
+            }
+        }
+
+        // Additional check: disallow keys that start with dot (hidden files)
+        if (key[0] == '.') return error.InvalidParameter;
+    }
+
+    /// Apply permission_check to a token file opened for reading
+    fn checkPermissions(self: *FileStorage, file: fs.File, path: []const u8) !void {
+        if (!posix_modes or self.permission_check == .ignore) return;
+
+        const stat = try std.posix.fstat(file.handle);
+        const mode: fs.File.Mode = @intCast(stat.mode & 0o777);
+        const owner = stat.uid;
+        const foreign = owner != currentUid();
+        if (mode & ~self.file_mode == 0 and !foreign) return;
+
+        switch (self.permission_check) {
+            .ignore => {},
+            .warn => if (foreign)
+                std.log.warn("Token file {s} is owned by uid {d}, not the current user", .{ path, owner })
+            else
+                std.log.warn("Token file {s} has mode {o:0>3}, expected at most {o:0>3}", .{ path, mode, self.file_mode }),
+            .reject => return error.InsecurePermissions,
+        }
+    }
+
+    /// Effective user ID of this process, compared with token file owners
+    fn currentUid() std.posix.uid_t {
+        return if (@import("builtin").os.tag == .linux) std.os.linux.geteuid() else std.c.geteuid();
+    }
+
+    fn writeLock(self: *FileStorage, key: []const u8) ?*std.Thread.Mutex {
+        if (!self.serialize_writes) return null;
+        const stripe = std.hash.Wyhash.hash(0, key) % write_lock_stripes;
+        return &self.write_locks[stripe];
+    }
+
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+
+        const write_lock = self.writeLock(key);
+        if (write_lock) |wl| wl.lock();
+        defer if (write_lock) |wl| wl.unlock();
+
+        try self.ensureDir();
+
+        const file_path = try self.getFilePath(key);
+        defer self.allocator.free(file_path);
+
+        const json_data = try codec.default.encode(self.allocator, &token);
+        defer {
+            std.crypto.secureZero(u8, json_data);
+            self.allocator.free(json_data);
+        }
+
+        const sealed = self.key_provider != null or self.encryption_key != null;
+        const file_data = if (self.key_provider) |provider|
+            try sealWithProvider(self.allocator, provider, key, json_data)
+        else if (self.encryption_key) |enc_key|
+            try seal(self.allocator, enc_key, key, json_data)
+        else
+            json_data;
+        defer if (sealed) self.allocator.free(file_data);
+
+        try self.writeAtomic(file_path, file_data);
+    }
+
+    /// Create the storage directory with restricted permissions (owner only)
+    fn ensureDir(self: *FileStorage) !void {
+        fs.cwd().makePath(self.base_path) catch |err| {
+            if (err != error.PathAlreadyExists) return err;
+        };
+
+        // Try to restrict the directory to dir_mode (owner-only by default)
+        // This is best-effort - may fail on some filesystems
+        if (posix_modes) {
+            const dir = fs.cwd().openDir(self.base_path, .{}) catch null;
+            if (dir) |d| {
+                var md = d;
+                md.chmod(self.dir_mode) catch {};
+                md.close();
+            }
+        }
+    }
+
+    /// Replace the file at `file_path` with `file_data`
+    ///
+    /// Writes to a unique temporary file, then renames it over the target so
+    /// readers never observe a partially written file (last writer wins).
+    fn writeAtomic(self: *FileStorage, file_path: []const u8, file_data: []const u8) !void {
+        var suffix_bytes: [8]u8 = undefined;
+        std.crypto.random.bytes(&suffix_bytes);
+        const suffix = std.fmt.bytesToHex(suffix_bytes, .lower);
+        const tmp_path = try std.fmt.allocPrint(self.allocator, "{s}.{s}.tmp", .{ file_path, &suffix });
+        defer self.allocator.free(tmp_path);
+
+        {
+            // Create file with restricted permissions (owner read/write only by default)
+            const file = try fs.cwd().createFile(tmp_path, .{ .mode = self.file_mode, .exclusive = true });
+            defer file.close();
+            errdefer fs.cwd().deleteFile(tmp_path) catch {};
+
+            try file.writeAll(file_data);
+            try file.sync();
+        }
+
+        fs.cwd().rename(tmp_path, file_path) catch |err| {
+            fs.cwd().deleteFile(tmp_path) catch {};
+            return err;
+        };
+
+        // Flush the directory entry too, so after a power loss the key holds
+        // either the old or the new token, never an empty file
+        var dir = try fs.cwd().openDir(self.base_path, .{});
+        defer dir.close();
+        try syncDir(dir);
+    }
+
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+
+        const file_path = try self.getFilePath(key);
+        defer self.allocator.free(file_path);
+
+        const file = fs.cwd().openFile(file_path, .{}) catch |err| {
+            if (err == error.FileNotFound) return null;
+            return err;
+        };
+        defer file.close();
+        try self.checkPermissions(file, file_path);
+
+        const file_data = try file.readToEndAlloc(allocator, 1024 * 1024);
+        defer allocator.free(file_data);
+
+        const json_data = if (mem.startsWith(u8, file_data, provider_magic)) blk: {
+            const provider = self.key_provider orelse return error.DecryptionFailed;
+            break :blk try unsealWithProvider(allocator, provider, key, file_data);
+        } else if (self.encryption_key) |enc_key|
+            unseal(allocator, enc_key, key, file_data) catch |err| blk: {
+                const pending = self.rotation_key orelse return err;
+                break :blk try unseal(allocator, pending, key, file_data);
+            }
+        else if (self.rotation_key != null and mem.startsWith(u8, file_data, encrypted_magic))
+            // Sealed by an interrupted migration away from plaintext
+            try unseal(allocator, self.rotation_key.?, key, file_data)
+        else {
+            // Don't report a sealed file as malformed JSON
+            if (self.key_provider != null or mem.startsWith(u8, file_data, encrypted_magic)) return error.DecryptionFailed;
+            return try codec.default.decode(allocator, file_data);
+        };
+        defer {
+            std.crypto.secureZero(u8, json_data);
+            allocator.free(json_data);
+        }
+        return try codec.default.decode(allocator, json_data);
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+
+        const write_lock = self.writeLock(key);
+        if (write_lock) |wl| wl.lock();
+        defer if (write_lock) |wl| wl.unlock();
+
+        const file_path = try self.getFilePath(key);
+        defer self.allocator.free(file_path);
+
+        fs.cwd().deleteFile(file_path) catch |err| {
+            if (err != error.FileNotFound) return err;
+        };
+    }
+
+    fn saveSession(ptr: *anyopaque, state: []const u8, pending: *const Session) !void {
+        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+
+        const write_lock = self.writeLock(state);
+        if (write_lock) |wl| wl.lock();
+        defer if (write_lock) |wl| wl.unlock();
+
+        try self.ensureDir();
+
+        const file_path = try self.getSessionPath(state);
+        defer self.allocator.free(file_path);
+
+        const data = try pending.toJson(self.allocator);
+        defer {
+            std.crypto.secureZero(u8, data);
+            self.allocator.free(data);
+        }
+        try self.writeAtomic(file_path, data);
+    }
+
+    fn loadSession(ptr: *anyopaque, allocator: Allocator, state: []const u8) !?Session {
+        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+
+        const file_path = try self.getSessionPath(state);
+        defer self.allocator.free(file_path);
+
+        const file = fs.cwd().openFile(file_path, .{}) catch |err| {
+            if (err == error.FileNotFound) return null;
+            return err;
+        };
+        defer file.close();
+        try self.checkPermissions(file, file_path);
+
+        const data = try file.readToEndAlloc(allocator, 1024 * 1024);
+        defer allocator.free(data);
+        return try Session.fromJson(allocator, data);
+    }
+
+    fn deleteSession(ptr: *anyopaque, state: []const u8) !void {
+        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+
+        const write_lock = self.writeLock(state);
+        if (write_lock) |wl| wl.lock();
+        defer if (write_lock) |wl| wl.unlock();
+
+        const file_path = try self.getSessionPath(state);
+        defer self.allocator.free(file_path);
+
+        fs.cwd().deleteFile(file_path) catch |err| {
+            if (err != error.FileNotFound) return err;
+        };
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        const self: *FileStorage = @ptrCast(@alignCast(ptr));
+
+        const file_path = self.getFilePath(key) catch return false;
+        defer self.allocator.free(file_path);
+
+        fs.cwd().access(file_path, .{}) catch return false;
+        return true;
+    }
+
+    fn getStoragePath(allocator: Allocator, app_name: []const u8) ![]const u8 {
+        return resolveStoragePath(allocator, app_name, @import("builtin").os.tag, getEnvVar);
+    }
+
+    /// Storage directory for `app_name`, reading the environment through `env`
+    ///
+    /// `SCHLUSSEL_DATA_DIR` overrides the data directory everywhere (for
+    /// Flatpak, snap and other sandboxes); otherwise the platform's data
+    /// directory is used. Relative or empty values are ignored, as the XDG
+    /// specification requires.
+    fn resolveStoragePath(
+        allocator: Allocator,
+        app_name: []const u8,
+        os_tag: std.Target.Os.Tag,
+        env: *const fn (allocator: Allocator, name: []const u8) ?[]const u8,
+    ) ![]const u8 {
+        const separator: u8 = if (os_tag == .windows) '\\' else '/';
+        const Candidate = struct { variable: []const u8, suffix: []const u8 = "" };
+
+        const candidates: []const Candidate = switch (os_tag) {
+            .macos => &.{
+                .{ .variable = "SCHLUSSEL_DATA_DIR" },
+                .{ .variable = "HOME", .suffix = "/Library/Application Support" },
+            },
+            .windows => &.{
+                .{ .variable = "SCHLUSSEL_DATA_DIR" },
+                .{ .variable = "LOCALAPPDATA" },
+                .{ .variable = "APPDATA" },
+            },
+            // XDG Base Directory Specification (Linux and other Unix systems)
+            else => &.{
+                .{ .variable = "SCHLUSSEL_DATA_DIR" },
+                .{ .variable = "XDG_DATA_HOME" },
+                .{ .variable = "HOME", .suffix = "/.local/share" },
+            },
+        };
+
+        for (candidates) |candidate| {
+            const value = env(allocator, candidate.variable) orelse continue;
+            defer allocator.free(value);
+            if (!isAbsolutePath(value, os_tag)) continue;
+            return std.fmt.allocPrint(allocator, "{s}{s}{c}{s}", .{ value, candidate.suffix, separator, app_name });
+        }
+
+        // Fallback to temp directory
+        return std.fmt.allocPrint(allocator, "/tmp/{s}", .{app_name});
+    }
+
+    fn isAbsolutePath(path: []const u8, os_tag: std.Target.Os.Tag) bool {
+        if (os_tag == .windows) {
+            // `C:\...` or a UNC path
+            return (path.len >= 3 and std.ascii.isAlphabetic(path[0]) and path[1] == ':' and (path[2] == '\\' or path[2] == '/')) or
+                mem.startsWith(u8, path, "\\\\");
+        }
+        return path.len > 0 and path[0] == '/';
+    }
+};
+
+/// A FileStorage.watch() polling one token file
+pub const FileWatch = struct {
+    allocator: Allocator,
+    path: []const u8,
+    key: []const u8,
+    watcher: TokenWatcher,
+    interval_ns: u64,
+    last: ?Snapshot,
+    stopped: std.Thread.ResetEvent = .{},
+    thread: std.Thread,
+
+    /// Identity of a file version; saves replace the file, so the inode
+    /// changes even when the modification time does not
+    const Snapshot = struct {
+        inode: fs.File.INode,
+        mtime: i128,
+        size: u64,
+    };
+
+    /// Stop polling, wait for the thread and free the watch
+    pub fn stop(self: *FileWatch) void {
+        self.stopped.set();
+        self.thread.join();
+        self.allocator.free(self.path);
+        self.allocator.free(self.key);
+        self.allocator.destroy(self);
+    }
+
+    fn snapshot(path: []const u8) ?Snapshot {
+        const stat = fs.cwd().statFile(path) catch return null;
+        return .{ .inode = stat.inode, .mtime = stat.mtime, .size = stat.size };
+    }
+
+    fn run(self: *FileWatch) void {
+        while (true) {
+            self.stopped.timedWait(self.interval_ns) catch {};
+            if (self.stopped.isSet()) return;
+
+            const current = snapshot(self.path);
+            const change: ?TokenChange = if (self.last) |last| blk: {
+                const now = current orelse break :blk .deleted;
+                break :blk if (std.meta.eql(last, now)) null else .updated;
+            } else if (current != null) .created else null;
+
+            self.last = current;
+            if (change) |c| self.watcher.notify(self.key, c);
+        }
+    }
+};
+
+/// Secure storage using OS credential managers
+///
+/// Uses:
+/// - macOS: Keychain
+/// - Windows: Credential Manager
+/// - Linux: Secret Service (libsecret)
+///
+/// Each token is stored as JSON under the service name, with the storage
+/// key as the account. Loading a missing entry returns null; a failing
+/// credential tool surfaces as `error.Backend` (`error.StorageError`
+/// through keystore()).
+pub const SecureStorage = struct {
+    allocator: Allocator,
+    service_name: []const u8,
+
+    pub fn init(allocator: Allocator, service_name: []const u8) !SecureStorage {
+        return .{
+            .allocator = allocator,
+            .service_name = try allocator.dupe(u8, service_name),
+        };
+    }
+
+    pub fn deinit(self: *SecureStorage) void {
+        self.allocator.free(self.service_name);
+    }
+
+    /// Keystore backed by the same credential manager and service name
+    ///
+    /// Only macOS and Linux have a credential manager to keep keys in; on
+    /// other platforms both operations return `error.UnsupportedOperation`
+    /// rather than writing the key to a plain file.
+    pub fn keystore(self: *SecureStorage) Keystore {
+        return .{
+            .ptr = self,
+            .vtable = &.{
+                .load = keystoreLoad,
+                .store = keystoreStore,
+            },
+        };
+    }
+
+    fn keystoreLoad(ptr: *anyopaque, allocator: Allocator, name: []const u8) anyerror!?[]const u8 {
+        const self: *SecureStorage = @ptrCast(@alignCast(ptr));
+        if (!has_credential_store) return error.UnsupportedOperation;
+        return loadCredential(allocator, self.service_name, name) catch |err| {
+            if (err == error.NotFound) return null;
+            return err;
+        };
+    }
+
+    fn keystoreStore(ptr: *anyopaque, name: []const u8, secret: []const u8) anyerror!void {
+        const self: *SecureStorage = @ptrCast(@alignCast(ptr));
+        if (!has_credential_store) return error.UnsupportedOperation;
+        try storeCredential(self.allocator, self.service_name, name, secret);
+    }
+
+    pub fn storage(self: *SecureStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+                .capabilities = capabilities,
+            }),
+        };
+    }
+
+    fn capabilities(_: *anyopaque) StorageCapabilities {
+        // The keychain and secret service replace items in place; the
+        // plain-file fallback on other platforms does not
+        return .{ .atomic_swap = has_credential_store, .os_protected = has_credential_store };
+    }
+
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        const self: *SecureStorage = @ptrCast(@alignCast(ptr));
+        const json_data = try codec.default.encode(self.allocator, &token);
+        defer self.allocator.free(json_data);
+
+        try storeCredential(self.allocator, self.service_name, key, json_data);
+    }
+
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *SecureStorage = @ptrCast(@alignCast(ptr));
+
+        const json_data = loadCredential(self.allocator, self.service_name, key) catch |err| {
+            if (err == error.NotFound) return null;
+            return err;
+        };
+        defer self.allocator.free(json_data);
+
+        return try codec.default.decode(allocator, json_data);
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        const self: *SecureStorage = @ptrCast(@alignCast(ptr));
+        try deleteCredential(self.allocator, self.service_name, key);
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        const self: *SecureStorage = @ptrCast(@alignCast(ptr));
+        const data = loadCredential(self.allocator, self.service_name, key) catch return false;
+        self.allocator.free(data);
+        return true;
+    }
+
+    // Platform-specific credential storage
+    const builtin = @import("builtin");
+
+    /// Whether secrets go to a credential manager rather than plain files
+    const has_credential_store = builtin.os.tag == .macos or builtin.os.tag == .linux;
+
+    fn storeCredential(allocator: Allocator, service: []const u8, account: []const u8, data: []const u8) !void {
+        if (builtin.os.tag == .macos) {
+            try macosStoreKeychain(allocator, service, account, data);
+        } else if (builtin.os.tag == .linux) {
+            try linuxStoreSecret(allocator, service, account, data);
+        } else {
+            // Fallback to file storage with warning
+            std.log.warn("SecureStorage not available on this platform, using file storage", .{});
+            try fallbackStore(allocator, service, account, data);
+        }
+    }
+
+    fn loadCredential(allocator: Allocator, service: []const u8, account: []const u8) ![]const u8 {
+        if (builtin.os.tag == .macos) {
+            return try macosLoadKeychain(allocator, service, account);
+        } else if (builtin.os.tag == .linux) {
+            return try linuxLoadSecret(allocator, service, account);
+        } else {
+            return try fallbackLoad(allocator, service, account);
+        }
+    }
+
+    fn deleteCredential(allocator: Allocator, service: []const u8, account: []const u8) !void {
+        if (builtin.os.tag == .macos) {
+            try macosDeleteKeychain(allocator, service, account);
+        } else if (builtin.os.tag == .linux) {
+            try linuxDeleteSecret(allocator, service, account);
+        } else {
+            try fallbackDelete(allocator, service, account);
+        }
+    }
+
+    fn macosStoreKeychain(allocator: Allocator, service: []const u8, account: []const u8, data: []const u8) !void {
+        // Validate inputs to prevent command injection
+        try validateCommandArgument(service);
+        try validateCommandArgument(account);
+
+        // Use security command-line tool
+        const args = [_][]const u8{
+            "security",
+            "add-generic-password",
+            "-U", // Update if exists
+            "-s",
+            service,
+            "-a",
+            account,
+            "-w",
+            data,
+        };
+
+        var child = std.process.Child.init(&args, allocator);
+        child.stderr_behavior = .Ignore;
+        child.stdout_behavior = .Ignore;
+        if (!exitedCleanly(try child.spawnAndWait())) return error.StorageError;
+    }
+
+    fn macosLoadKeychain(allocator: Allocator, service: []const u8, account: []const u8) ![]const u8 {
+        // Validate inputs to prevent command injection
+        try validateCommandArgument(service);
+        try validateCommandArgument(account);
+
+        const args = [_][]const u8{
+            "security",
+            "find-generic-password",
+            "-s",
+            service,
+            "-a",
+            account,
+            "-w",
+        };
+
+        var child = std.process.Child.init(&args, allocator);
+        child.stderr_behavior = .Ignore;
+        child.stdout_behavior = .Pipe;
+
+        child.spawn() catch return error.StorageError;
+
+        const stdout = child.stdout orelse return error.StorageError;
+        const output = try stdout.readToEndAlloc(allocator, 1024 * 1024);
+
+        const result = try child.wait();
+        if (!exitedCleanly(result)) {
+            allocator.free(output);
+            // Only a missing item means "no secret yet"; a locked or denied
+            // keychain must not look like one
+            return if (exitCode(result) == security_item_not_found) error.NotFound else error.StorageError;
+        }
+
+        // Remove trailing newline
+        const trimmed = mem.trimRight(u8, output, "\n");
+        if (trimmed.len == output.len) {
+            return output;
+        }
+
+        const result_data = try allocator.dupe(u8, trimmed);
+        allocator.free(output);
+        return result_data;
+    }
+
+    fn macosDeleteKeychain(allocator: Allocator, service: []const u8, account: []const u8) !void {
+        // Validate inputs to prevent command injection
+        try validateCommandArgument(service);
+        try validateCommandArgument(account);
+
+        const args = [_][]const u8{
+            "security",
+            "delete-generic-password",
+            "-s",
+            service,
+            "-a",
+            account,
+        };
+
+        var child = std.process.Child.init(&args, allocator);
+        child.stderr_behavior = .Ignore;
+        child.stdout_behavior = .Ignore;
+        const result = child.spawnAndWait() catch return error.StorageError;
+        // Deleting an item that does not exist is not a failure
+        if (exitedCleanly(result) or exitCode(result) == security_item_not_found) return;
+        return error.StorageError;
+    }
+
+    fn linuxStoreSecret(allocator: Allocator, service: []const u8, account: []const u8, data: []const u8) !void {
+        // Validate inputs to prevent command injection
+        try validateCommandArgument(service);
+        try validateCommandArgument(account);
+
+        // Use secret-tool from libsecret
+        const args = [_][]const u8{
+            "secret-tool",
+            "store",
+            "--label",
+            service,
+            "service",
+            service,
+            "account",
+            account,
+        };
+
+        var child = std.process.Child.init(&args, allocator);
+        child.stdin_behavior = .Pipe;
+        child.stderr_behavior = .Ignore;
+        child.stdout_behavior = .Ignore;
+
+        try child.spawn();
+
+        if (child.stdin) |stdin| {
+            try stdin.writeAll(data);
+            stdin.close();
+            child.stdin = null;
+        }
+
+        if (!exitedCleanly(try child.wait())) return error.StorageError;
+    }
+
+    fn linuxLoadSecret(allocator: Allocator, service: []const u8, account: []const u8) ![]const u8 {
+        // Validate inputs to prevent command injection
+        try validateCommandArgument(service);
+        try validateCommandArgument(account);
+
+        const args = [_][]const u8{
+            "secret-tool",
+            "lookup",
+            "service",
+            service,
+            "account",
+            account,
+        };
+
+        var child = std.process.Child.init(&args, allocator);
+        child.stderr_behavior = .Ignore;
+        child.stdout_behavior = .Pipe;
+
+        child.spawn() catch return error.StorageError;
+
+        const stdout = child.stdout orelse return error.StorageError;
+        const output = try stdout.readToEndAlloc(allocator, 1024 * 1024);
+
+        const result = try child.wait();
+        if (!exitedCleanly(result)) {
+            defer allocator.free(output);
+            // `secret-tool lookup` exits 1 without output when nothing
+            // matches; anything else is a locked or unreachable service
+            return if (exitCode(result) == 1 and output.len == 0) error.NotFound else error.StorageError;
+        }
+
+        return output;
+    }
+
+    fn linuxDeleteSecret(allocator: Allocator, service: []const u8, account: []const u8) !void {
+        // Validate inputs to prevent command injection
+        try validateCommandArgument(service);
+        try validateCommandArgument(account);
+
+        const args = [_][]const u8{
+            "secret-tool",
+            "clear",
+            "service",
+            service,
+            "account",
+            account,
+        };
+
+        var child = std.process.Child.init(&args, allocator);
+        child.stderr_behavior = .Ignore;
+        child.stdout_behavior = .Ignore;
+        const result = child.spawnAndWait() catch return error.StorageError;
+        if (exitedCleanly(result)) return;
+
+        // Some secret-tool versions fail `clear` when nothing matches;
+        // that is success as long as the item is really gone
+        const remaining = linuxLoadSecret(allocator, service, account) catch |err| switch (err) {
+            error.NotFound => return,
+            else => return error.StorageError,
+        };
+        allocator.free(remaining);
+        return error.StorageError;
+    }
+
+    /// Whether `secret-tool` runs and reaches a Secret Service (Linux)
+    ///
+    /// Looks up an item that does not exist: a reachable service answers
+    /// with exit status 1 and nothing on stderr, while a missing tool or
+    /// D-Bus session fails to spawn or complains.
+    pub fn secretToolAvailable(allocator: Allocator) bool {
+        if (builtin.os.tag != .linux) return false;
+
+        const args = [_][]const u8{ "secret-tool", "lookup", "service", "schlussel-probe", "account", "probe" };
+        var child = std.process.Child.init(&args, allocator);
+        child.stdout_behavior = .Ignore;
+        child.stderr_behavior = .Pipe;
+        child.spawn() catch return false;
+
+        const stderr = child.stderr orelse return false;
+        const complaint = stderr.readToEndAlloc(allocator, 64 * 1024) catch {
+            _ = child.kill() catch return false;
+            return false;
+        };
+        defer allocator.free(complaint);
+
+        const result = child.wait() catch return false;
+        const code = exitCode(result) orelse return false;
+        return complaint.len == 0 and (code == 0 or code == 1);
+    }
+
+    /// Whether a credential tool exited with status 0
+    fn exitedCleanly(term: std.process.Child.Term) bool {
+        return exitCode(term) == 0;
+    }
+
+    /// Exit status of a credential tool, or null if it was killed
+    fn exitCode(term: std.process.Child.Term) ?u8 {
+        return switch (term) {
+            .Exited => |code| code,
+            else => null,
+        };
+    }
+
+    /// `security` exit status for errSecItemNotFound
+    const security_item_not_found = 44;
+
+    fn getFallbackPath(allocator: Allocator, service: []const u8, account: []const u8) ![]const u8 {
+        // Use XDG_RUNTIME_DIR if available (per-user, tmpfs, secure permissions)
+        // Otherwise use user-specific directory under XDG_DATA_HOME or HOME
+        if (getEnvVar(allocator, "XDG_RUNTIME_DIR")) |runtime_dir| {
+            defer allocator.free(runtime_dir);
+            return std.fmt.allocPrint(allocator, "{s}/schlussel-{s}-{s}", .{ runtime_dir, service, account });
+        }
+        if (getEnvVar(allocator, "XDG_DATA_HOME")) |data_home| {
+            defer allocator.free(data_home);
+            return std.fmt.allocPrint(allocator, "{s}/schlussel/.{s}-{s}", .{ data_home, service, account });
+        }
+        if (getEnvVar(allocator, "HOME")) |home| {
+            defer allocator.free(home);
+            return std.fmt.allocPrint(allocator, "{s}/.local/share/schlussel/.{s}-{s}", .{ home, service, account });
+        }
+        // Last resort: use a predictable but user-specific location
+        return std.fmt.allocPrint(allocator, "/tmp/.schlussel-{d}-{s}-{s}", .{ std.os.linux.getuid(), service, account });
+    }
+
+    fn fallbackStore(allocator: Allocator, service: []const u8, account: []const u8, data: []const u8) !void {
+        const path = try getFallbackPath(allocator, service, account);
+        defer allocator.free(path);
+
+        // Ensure parent directory exists
+        if (std.fs.path.dirname(path)) |dir| {
+            fs.cwd().makePath(dir) catch |err| {
+                if (err != error.PathAlreadyExists) return err;
+            };
+        }
+
+        // Create file with restrictive permissions (owner read/write only)
+        const file = try fs.cwd().createFile(path, .{ .mode = 0o600 });
+        defer file.close();
+        try file.writeAll(data);
+    }
+
+    fn fallbackLoad(allocator: Allocator, service: []const u8, account: []const u8) ![]const u8 {
+        const path = try getFallbackPath(allocator, service, account);
+        defer allocator.free(path);
+
+        const file = fs.cwd().openFile(path, .{}) catch return error.NotFound;
+        defer file.close();
+        return try file.readToEndAlloc(allocator, 1024 * 1024);
+    }
+
+    fn fallbackDelete(allocator: Allocator, service: []const u8, account: []const u8) !void {
+        const path = try getFallbackPath(allocator, service, account);
+        defer allocator.free(path);
+
+        fs.cwd().deleteFile(path) catch |err| {
+            if (err != error.FileNotFound) return err;
+        };
+    }
+};
+
+/// Escape a string for JSON output
+fn appendJsonEscaped(allocator: Allocator, buf: *std.ArrayListUnmanaged(u8), input: []const u8) !void {
+    for (input) |c| {
+        switch (c) {
+            '"' => try buf.appendSlice(allocator, "\\\""),
+            '\\' => try buf.appendSlice(allocator, "\\\\"),
+            '\n' => try buf.appendSlice(allocator, "\\n"),
+            '\r' => try buf.appendSlice(allocator, "\\r"),
+            '\t' => try buf.appendSlice(allocator, "\\t"),
+            // Other control characters (excluding \n, \r, \t which are handled above)
+            0x00...0x08, 0x0B, 0x0C, 0x0E...0x1F => {
+                // Control characters - escape as \uXXXX
+                try buf.appendSlice(allocator, "\\u00");
+                const hex = "0123456789abcdef";
+                try buf.append(allocator, hex[c >> 4]);
+                try buf.append(allocator, hex[c & 0x0F]);
+            },
+            else => try buf.append(allocator, c),
+        }
+    }
+}
+
+/// Validate that a string is safe for use as a command argument
+/// Returns error if the string contains potentially dangerous characters
+fn validateCommandArgument(arg: []const u8) !void {
+    if (arg.len == 0) return error.InvalidParameter;
+
+    // Reject arguments that look like flags (start with -)
+    if (arg[0] == '-') return error.InvalidParameter;
+
+    for (arg) |c| {
+        switch (c) {
+            // Reject null bytes, newlines, and shell metacharacters
+            0, '\n', '\r', '`', '$', '|', '&', ';', '>', '<' => return error.InvalidParameter,
+            else => {},
+        }
+    }
+}
+
+test "Token creation and expiration" {
+    const allocator = std.testing.allocator;
+
+    var token = try Token.init(allocator, "test_token", "Bearer");
+    defer token.deinit();
+
+    try std.testing.expectEqualStrings("test_token", token.access_token);
+    try std.testing.expectEqualStrings("Bearer", token.token_type);
+    try std.testing.expect(!token.isExpired());
+}
+
+test "Token JSON serialization roundtrip" {
+    const allocator = std.testing.allocator;
+
+    var original = try Token.initFull(
+        allocator,
+        "access123",
+        "Bearer",
+        "refresh456",
+        3600,
+        "read write",
+        null,
+    );
+    defer original.deinit();
+
+    const json_data = try original.toJson(allocator);
+    defer allocator.free(json_data);
+
+    var restored = try Token.fromJson(allocator, json_data);
+    defer restored.deinit();
+
+    try std.testing.expectEqualStrings(original.access_token, restored.access_token);
+    try std.testing.expectEqualStrings(original.token_type, restored.token_type);
+    try std.testing.expectEqualStrings(original.refresh_token.?, restored.refresh_token.?);
+    try std.testing.expectEqualStrings(original.scope.?, restored.scope.?);
+}
+
+test "Token JSON restores expires_at when missing" {
+    const allocator = std.testing.allocator;
+
+    var original = try Token.init(allocator, "access123", "Bearer");
+    defer original.deinit();
+
+    original.expires_in = 3600;
+
+    const json_data = try original.toJson(allocator);
+    defer allocator.free(json_data);
+
+    const before = @as(u64, @intCast(std.time.timestamp()));
+    var restored = try Token.fromJson(allocator, json_data);
+    defer restored.deinit();
+    const after = @as(u64, @intCast(std.time.timestamp()));
+
+    try std.testing.expect(restored.expires_at != null);
+    const expires_at = restored.expires_at.?;
+    try std.testing.expect(expires_at >= before + 3600);
+    try std.testing.expect(expires_at <= after + 3600);
+}
+
+test "MemoryStorage save and load" {
+    const allocator = std.testing.allocator;
+
+    var mem_storage = MemoryStorage.init(allocator);
+    defer mem_storage.deinit();
+
+    var token = try Token.init(allocator, "test_access", "Bearer");
+    defer token.deinit();
+
+    const storage_iface = mem_storage.storage();
+    try storage_iface.save("test_key", token);
+
+    var loaded = (try storage_iface.load(allocator, "test_key")).?;
+    defer loaded.deinit();
+
+    try std.testing.expectEqualStrings("test_access", loaded.access_token);
+}
+
+test "Token expiration checking" {
+    const allocator = std.testing.allocator;
+
+    var token = try Token.init(allocator, "expired_token", "Bearer");
+    defer token.deinit();
+
+    token.expires_in = 3600;
+    token.expires_at = 1; // Long expired
+
+    try std.testing.expect(token.isExpired());
+    try std.testing.expect(token.expiresWithin(0));
+}
+
+test "Token remaining lifetime fraction" {
+    const allocator = std.testing.allocator;
+
+    const now = @as(u64, @intCast(std.time.timestamp()));
+
+    var token = try Token.init(allocator, "test_token", "Bearer");
+    defer token.deinit();
+
+    token.expires_in = 3600;
+    token.expires_at = now + 1800; // Half expired
+
+    if (token.remainingLifetimeFraction()) |fraction| {
+        try std.testing.expect(fraction >= 0.49 and fraction <= 0.51);
+    }
+}
+
+// ============================================================================
+// Additional Memory Leak Tests
+// ============================================================================
+
+test "Token.initFull: all fields allocated without leaks" {
+    const allocator = std.testing.allocator;
+
+    var token = try Token.initFull(
+        allocator,
+        "access_token_value",
+        "Bearer",
+        "refresh_token_value",
+        3600,
+        "read write delete",
+        "id_token_value",
+    );
+    defer token.deinit();
+
+    try std.testing.expectEqualStrings("access_token_value", token.access_token);
+    try std.testing.expectEqualStrings("Bearer", token.token_type);
+    try std.testing.expectEqualStrings("refresh_token_value", token.refresh_token.?);
+    try std.testing.expectEqual(@as(u64, 3600), token.expires_in.?);
+    try std.testing.expectEqualStrings("read write delete", token.scope.?);
+    try std.testing.expectEqualStrings("id_token_value", token.id_token.?);
+}
+
+test "Token.clone: clones all fields without leaks" {
+    const allocator = std.testing.allocator;
+
+    var original = try Token.initFull(
+        allocator,
+        "original_access",
+        "Bearer",
+        "original_refresh",
+        7200,
+        "scope1 scope2",
+        "original_id",
+    );
+    defer original.deinit();
+
+    var cloned = try original.clone(allocator);
+    defer cloned.deinit();
+
+    // Verify all fields cloned correctly
+    try std.testing.expectEqualStrings("original_access", cloned.access_token);
+    try std.testing.expectEqualStrings("Bearer", cloned.token_type);
+    try std.testing.expectEqualStrings("original_refresh", cloned.refresh_token.?);
+    try std.testing.expectEqual(@as(u64, 7200), cloned.expires_in.?);
+    try std.testing.expectEqualStrings("scope1 scope2", cloned.scope.?);
+    try std.testing.expectEqualStrings("original_id", cloned.id_token.?);
+
+    // Verify independent memory (different pointers)
+    try std.testing.expect(cloned.access_token.ptr != original.access_token.ptr);
+    try std.testing.expect(cloned.refresh_token.?.ptr != original.refresh_token.?.ptr);
+}
+
+test "Token.toJson and fromJson: roundtrip with all fields" {
+    const allocator = std.testing.allocator;
+
+    var original = try Token.initFull(
+        allocator,
+        "test_access",
+        "Bearer",
+        "test_refresh",
+        3600,
+        "read write",
+        "test_id_token",
+    );
+    defer original.deinit();
+
+    // Set expires_at manually for roundtrip test
+    original.expires_at = 1234567890;
+
+    const json_data = try original.toJson(allocator);
+    defer allocator.free(json_data);
+
+    var restored = try Token.fromJson(allocator, json_data);
+    defer restored.deinit();
+
+    try std.testing.expectEqualStrings(original.access_token, restored.access_token);
+    try std.testing.expectEqualStrings(original.token_type, restored.token_type);
+    try std.testing.expectEqualStrings(original.refresh_token.?, restored.refresh_token.?);
+    try std.testing.expectEqual(original.expires_in, restored.expires_in);
+    try std.testing.expectEqual(original.expires_at, restored.expires_at);
+    try std.testing.expectEqualStrings(original.scope.?, restored.scope.?);
+    try std.testing.expectEqualStrings(original.id_token.?, restored.id_token.?);
+}
+
+test "Token.toJson: escapes special characters" {
+    const allocator = std.testing.allocator;
+
+    var token = try Token.init(allocator, "token\"with\\special\nchars", "Bearer");
+    defer token.deinit();
+
+    const json_data = try token.toJson(allocator);
+    defer allocator.free(json_data);
+
+    // Verify the JSON can be parsed back
+    var restored = try Token.fromJson(allocator, json_data);
+    defer restored.deinit();
+
+    try std.testing.expectEqualStrings("token\"with\\special\nchars", restored.access_token);
+}
+
+test "Token.toEnvExports: quotes special characters for POSIX shells" {
+    const allocator = std.testing.allocator;
+
+    var token = try Token.init(allocator, "it's a \"tok$en\" `x`", "Bearer");
+    defer token.deinit();
+    token.expires_at = 1700000000;
+
+    const exports = try token.toEnvExports(allocator, "GH", .posix);
+    defer allocator.free(exports);
+
+    try std.testing.expectEqualStrings(
+        "export GH_ACCESS_TOKEN='it'\\''s a \"tok$en\" `x`'\n" ++
+            "export GH_TOKEN_TYPE='Bearer'\n" ++
+            "export GH_EXPIRES_AT='1700000000'\n",
+        exports,
+    );
+}
+
+test "Token.toEnvExports: doubles quotes for PowerShell" {
+    const allocator = std.testing.allocator;
+
+    var token = try Token.init(allocator, "it's $(whoami) \u{2019}q", "Bearer");
+    defer token.deinit();
+
+    const exports = try token.toEnvExports(allocator, "APP_1", .powershell);
+    defer allocator.free(exports);
+
+    try std.testing.expectEqualStrings(
+        "$env:APP_1_ACCESS_TOKEN = 'it''s $(whoami) \u{2019}\u{2019}q'\n" ++
+            "$env:APP_1_TOKEN_TYPE = 'Bearer'\n",
+        exports,
+    );
+
+    try std.testing.expectError(error.InvalidParameter, token.toEnvExports(allocator, "1BAD", .powershell));
+    try std.testing.expectError(error.InvalidParameter, token.toEnvExports(allocator, "BAD-NAME", .posix));
+    try std.testing.expectError(error.InvalidParameter, token.toEnvExports(allocator, "", .posix));
+}
+
+test "Token.fromJson: handles missing optional fields" {
+    const allocator = std.testing.allocator;
+
+    const minimal_json =
+        \\{"access_token":"minimal","token_type":"Bearer"}
+    ;
+
+    var token = try Token.fromJson(allocator, minimal_json);
+    defer token.deinit();
+
+    try std.testing.expectEqualStrings("minimal", token.access_token);
+    try std.testing.expectEqualStrings("Bearer", token.token_type);
+    try std.testing.expect(token.refresh_token == null);
+    try std.testing.expect(token.expires_in == null);
+    try std.testing.expect(token.expires_at == null);
+    try std.testing.expect(token.scope == null);
+    try std.testing.expect(token.id_token == null);
+}
+
+test "Token.fromJson: rejects invalid JSON" {
+    const allocator = std.testing.allocator;
+
+    // Missing required fields
+    const result1 = Token.fromJson(allocator, "{}");
+    try std.testing.expectError(error.InvalidParameter, result1);
+
+    // Not an object
+    const result2 = Token.fromJson(allocator, "[]");
+    try std.testing.expectError(error.InvalidParameter, result2);
+
+    // Invalid syntax
+    const result3 = Token.fromJson(allocator, "not json");
+    try std.testing.expectError(error.SyntaxError, result3);
+}
+
+test "MemoryStorage: multiple save and load cycles" {
+    const allocator = std.testing.allocator;
+
+    var storage = MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    const iface = storage.storage();
+
+    // Save multiple tokens
+    var token1 = try Token.init(allocator, "token1", "Bearer");
+    defer token1.deinit();
+    try iface.save("key1", token1);
+
+    var token2 = try Token.init(allocator, "token2", "Bearer");
+    defer token2.deinit();
+    try iface.save("key2", token2);
+
+    // Load and verify
+    var loaded1 = (try iface.load(allocator, "key1")).?;
+    defer loaded1.deinit();
+    try std.testing.expectEqualStrings("token1", loaded1.access_token);
+
+    var loaded2 = (try iface.load(allocator, "key2")).?;
+    defer loaded2.deinit();
+    try std.testing.expectEqualStrings("token2", loaded2.access_token);
+
+    // Overwrite
+    var token1_new = try Token.init(allocator, "token1_updated", "Bearer");
+    defer token1_new.deinit();
+    try iface.save("key1", token1_new);
+
+    var loaded1_new = (try iface.load(allocator, "key1")).?;
+    defer loaded1_new.deinit();
+    try std.testing.expectEqualStrings("token1_updated", loaded1_new.access_token);
+}
+
+test "MemoryStorage: delete removes token" {
+    const allocator = std.testing.allocator;
+
+    var storage = MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    const iface = storage.storage();
+
+    var token = try Token.init(allocator, "to_delete", "Bearer");
+    defer token.deinit();
+    try iface.save("delete_key", token);
+
+    try std.testing.expect(iface.exists("delete_key"));
+
+    try iface.delete("delete_key");
+
+    try std.testing.expect(!iface.exists("delete_key"));
+    try std.testing.expect((try iface.load(allocator, "delete_key")) == null);
+}
 
-test "Token JSON serialization roundtrip" {
+test "MemoryStorage: load nonexistent returns null" {
     const allocator = std.testing.allocator;
 
-    var original = try Token.initFull(
-        allocator,
-        "access123",
-        "Bearer",
-        "refresh456",
-        3600,
-        "read write",
-        null,
+    var storage = MemoryStorage.init(allocator);
+    defer storage.deinit();
+
+    const result = try storage.storage().load(allocator, "nonexistent");
+    try std.testing.expect(result == null);
+}
+
+test "MemoryStorage.withToken: reads fields without cloning" {
+    const allocator = std.testing.allocator;
+
+    // Count allocations made by the storage itself
+    var counting = std.testing.FailingAllocator.init(allocator, .{});
+    var store = MemoryStorage.init(counting.allocator());
+    defer store.deinit();
+
+    var token = try Token.initFull(allocator, "a" ** 2048, "Bearer", null, 3600, null, "header.payload.signature");
+    defer token.deinit();
+    try store.storage().save("jwt", token);
+
+    const Reader = struct {
+        fn accessTokenLen(_: void, stored: ?*const Token) usize {
+            const t = stored orelse return 0;
+            return t.access_token.len;
+        }
+    };
+
+    const allocations_before = counting.allocations;
+    for (0..100) |_| {
+        try std.testing.expectEqual(@as(usize, 2048), store.withToken("jwt", {}, Reader.accessTokenLen));
+    }
+    try std.testing.expectEqual(@as(usize, 0), store.withToken("missing", {}, Reader.accessTokenLen));
+
+    // Through the generic interface MemoryStorage still lends its token
+    try std.testing.expectEqual(
+        @as(usize, 2048),
+        try store.storage().withToken(allocator, "jwt", {}, Reader.accessTokenLen),
     );
-    defer original.deinit();
+    try std.testing.expectEqual(allocations_before, counting.allocations);
+}
 
-    const json_data = try original.toJson(allocator);
-    defer allocator.free(json_data);
+test "SessionStorage.withToken: falls back to a temporary copy" {
+    const allocator = std.testing.allocator;
 
-    var restored = try Token.fromJson(allocator, json_data);
+    const Minimal = struct {
+        fn save(_: *anyopaque, _: []const u8, _: Token) anyerror!void {}
+        fn load(_: *anyopaque, alloc: Allocator, key: []const u8) anyerror!?Token {
+            if (!std.mem.eql(u8, key, "present")) return null;
+            return try Token.init(alloc, "loaded", "Bearer");
+        }
+        fn delete(_: *anyopaque, _: []const u8) anyerror!void {}
+        fn exists(_: *anyopaque, _: []const u8) bool {
+            return false;
+        }
+    };
+
+    var dummy: u8 = 0;
+    const store = SessionStorage{
+        .ptr = &dummy,
+        .vtable = SessionStorage.VTable.from(.{
+            .save = Minimal.save,
+            .load = Minimal.load,
+            .delete = Minimal.delete,
+            .exists = Minimal.exists,
+        }),
+    };
+
+    const Reader = struct {
+        fn isLoaded(expected: []const u8, stored: ?*const Token) bool {
+            const t = stored orelse return false;
+            return std.mem.eql(u8, t.access_token, expected);
+        }
+    };
+
+    try std.testing.expect(try store.withToken(allocator, "present", @as([]const u8, "loaded"), Reader.isLoaded));
+    try std.testing.expect(!try store.withToken(allocator, "absent", @as([]const u8, "loaded"), Reader.isLoaded));
+}
+
+test "StorageCapabilities: backends report what they support" {
+    const allocator = std.testing.allocator;
+
+    var mem_storage = MemoryStorage.init(allocator);
+    defer mem_storage.deinit();
+
+    const caps = mem_storage.storage().capabilities();
+    try std.testing.expect(caps.atomic_swap);
+    try std.testing.expect(caps.list_keys);
+    try std.testing.expect(caps.iteration);
+    try std.testing.expect(!caps.ttl);
+    try std.testing.expect(!caps.bulk);
+    try std.testing.expect(!caps.os_protected);
+
+    // A minimal custom backend that doesn't report anything supports nothing
+    const Minimal = struct {
+        fn save(_: *anyopaque, _: []const u8, _: Token) anyerror!void {}
+        fn load(_: *anyopaque, _: Allocator, _: []const u8) anyerror!?Token {
+            return null;
+        }
+        fn delete(_: *anyopaque, _: []const u8) anyerror!void {}
+        fn exists(_: *anyopaque, _: []const u8) bool {
+            return false;
+        }
+    };
+
+    var dummy: u8 = 0;
+    const minimal = SessionStorage{
+        .ptr = &dummy,
+        .vtable = SessionStorage.VTable.from(.{
+            .save = Minimal.save,
+            .load = Minimal.load,
+            .delete = Minimal.delete,
+            .exists = Minimal.exists,
+        }),
+    };
+
+    const minimal_caps = minimal.capabilities();
+    try std.testing.expectEqual(StorageCapabilities{}, minimal_caps);
+    try std.testing.expectError(error.UnsupportedOperation, minimal.listKeys(allocator));
+    try std.testing.expectError(error.UnsupportedOperation, minimal.listSessionStates(allocator));
+}
+
+test "SessionStorage.VTable.from: maps backend errors onto StorageError" {
+    const allocator = std.testing.allocator;
+
+    const Broken = struct {
+        fn save(_: *anyopaque, _: []const u8, _: Token) !void {
+            return error.NoSpaceLeft;
+        }
+        fn load(_: *anyopaque, _: Allocator, _: []const u8) !?Token {
+            return error.SyntaxError;
+        }
+        fn delete(_: *anyopaque, _: []const u8) !void {
+            return error.WouldBlock;
+        }
+        fn exists(_: *anyopaque, _: []const u8) bool {
+            return false;
+        }
+        fn listKeys(_: *anyopaque, _: Allocator) ![][]const u8 {
+            return error.Unexpected;
+        }
+    };
+
+    var dummy: u8 = 0;
+    const store = SessionStorage{
+        .ptr = &dummy,
+        .vtable = SessionStorage.VTable.from(.{
+            .save = Broken.save,
+            .load = Broken.load,
+            .delete = Broken.delete,
+            .exists = Broken.exists,
+            .list_keys = Broken.listKeys,
+        }),
+    };
+
+    var token = try Token.init(allocator, "access", "Bearer");
+    defer token.deinit();
+    try std.testing.expectError(error.Io, store.save("user", token));
+    try std.testing.expectError(error.Serialization, store.load(allocator, "user"));
+    try std.testing.expectError(error.Locked, store.delete("user"));
+    try std.testing.expectError(error.Backend, store.listKeys(allocator));
+
+    // Errors that are already StorageError pass through unchanged
+    try std.testing.expectEqual(@as(StorageError, error.DecryptionFailed), mapStorageError(error.DecryptionFailed));
+    try std.testing.expectEqual(@as(StorageError, error.NotFound), mapStorageError(error.FileNotFound));
+}
+
+test "MemoryStorage.listSessionStates: lists saved sessions" {
+    const allocator = std.testing.allocator;
+
+    var memory = MemoryStorage.init(allocator);
+    defer memory.deinit();
+    const store = memory.storage();
+    try std.testing.expect(store.capabilities().sessions);
+
+    var pending = try Session.init(allocator, "github.com");
+    defer pending.deinit();
+    try store.saveSession("state-a", &pending);
+    try pending.setRequestedScope("repo");
+    try store.saveSession("state-b", &pending);
+    try store.saveSession("state-b", &pending);
+
+    const states = try store.listSessionStates(allocator);
+    defer SessionStorage.freeKeys(allocator, states);
+    try std.testing.expectEqual(@as(usize, 2), states.len);
+
+    var restored = (try store.loadSession(allocator, "state-b")).?;
     defer restored.deinit();
+    try std.testing.expectEqualStrings("repo", restored.requested_scope.?);
+
+    try store.deleteSession("state-a");
+    try std.testing.expect((try store.loadSession(allocator, "state-a")) == null);
+    // Sessions are not tokens
+    const keys = try store.listKeys(allocator);
+    defer SessionStorage.freeKeys(allocator, keys);
+    try std.testing.expectEqual(@as(usize, 0), keys.len);
+}
 
-    try std.testing.expectEqualStrings(original.access_token, restored.access_token);
-    try std.testing.expectEqualStrings(original.token_type, restored.token_type);
-    try std.testing.expectEqualStrings(original.refresh_token.?, restored.refresh_token.?);
-    try std.testing.expectEqualStrings(original.scope.?, restored.scope.?);
+test "FileStorage.listKeys: lists saved tokens only" {
+    const allocator = std.testing.allocator;
+
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
+
+    var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+    defer file_storage.deinit();
+    const store = file_storage.storage();
+
+    var token = try Token.init(allocator, "access", "Bearer");
+    defer token.deinit();
+    try store.save("github", token);
+    try store.save("gitlab", token);
+
+    // Leftover temporaries and unrelated files are not keys
+    try tmp.dir.writeFile(.{ .sub_path = "github.json.0011223344556677.tmp", .data = "{" });
+    try tmp.dir.writeFile(.{ .sub_path = "notes.txt", .data = "" });
+
+    const keys = try store.listKeys(allocator);
+    defer SessionStorage.freeKeys(allocator, keys);
+
+    std.mem.sort([]const u8, keys, {}, struct {
+        fn lessThan(_: void, a: []const u8, b: []const u8) bool {
+            return mem.lessThan(u8, a, b);
+        }
+    }.lessThan);
+    try std.testing.expectEqual(@as(usize, 2), keys.len);
+    try std.testing.expectEqualStrings("github", keys[0]);
+    try std.testing.expectEqualStrings("gitlab", keys[1]);
 }
 
-test "Token JSON restores expires_at when missing" {
+test "FileStorage.listSessionStates: lists saved sessions apart from tokens" {
     const allocator = std.testing.allocator;
 
-    var original = try Token.init(allocator, "access123", "Bearer");
-    defer original.deinit();
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
 
-    original.expires_in = 3600;
+    var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+    defer file_storage.deinit();
+    const store = file_storage.storage();
 
-    const json_data = try original.toJson(allocator);
-    defer allocator.free(json_data);
+    var token = try Token.init(allocator, "access", "Bearer");
+    defer token.deinit();
+    try store.save("github", token);
 
-    const before = @as(u64, @intCast(std.time.timestamp()));
-    var restored = try Token.fromJson(allocator, json_data);
+    var pending = try Session.init(allocator, "github.com");
+    defer pending.deinit();
+    try pending.setNonce("n-0S6_WzA2Mj");
+    try store.saveSession("af0ifjsldkj", &pending);
+
+    const states = try store.listSessionStates(allocator);
+    defer SessionStorage.freeKeys(allocator, states);
+    try std.testing.expectEqual(@as(usize, 1), states.len);
+    try std.testing.expectEqualStrings("af0ifjsldkj", states[0]);
+
+    // Token enumeration does not pick up session files
+    const keys = try store.listKeys(allocator);
+    defer SessionStorage.freeKeys(allocator, keys);
+    try std.testing.expectEqual(@as(usize, 1), keys.len);
+    try std.testing.expectEqualStrings("github", keys[0]);
+
+    var restored = (try store.loadSession(allocator, "af0ifjsldkj")).?;
     defer restored.deinit();
-    const after = @as(u64, @intCast(std.time.timestamp()));
+    try std.testing.expectEqualStrings("n-0S6_WzA2Mj", restored.nonce.?);
+
+    try store.deleteSession("af0ifjsldkj");
+    try std.testing.expect((try store.loadSession(allocator, "af0ifjsldkj")) == null);
+    const remaining = try store.listSessionStates(allocator);
+    defer SessionStorage.freeKeys(allocator, remaining);
+    try std.testing.expectEqual(@as(usize, 0), remaining.len);
+}
+
+test "FileStorage.withPermissionCheck: rejects token files other users can read" {
+    if (!FileStorage.posix_modes) return error.SkipZigTest;
+    const allocator = std.testing.allocator;
+
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
+
+    var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+    defer file_storage.deinit();
+    const store = file_storage.storage();
+
+    var token = try Token.init(allocator, "access", "Bearer");
+    defer token.deinit();
+    try store.save("github", token);
+
+    const stat = try tmp.dir.statFile("github.json");
+    try std.testing.expectEqual(@as(fs.File.Mode, 0o600), stat.mode & 0o777);
+
+    var file = try tmp.dir.openFile("github.json", .{});
+    try file.chmod(0o644);
+    file.close();
+
+    // Warnings still load the token
+    var loaded = (try store.load(allocator, "github")).?;
+    loaded.deinit();
+
+    file_storage.withPermissionCheck(.reject);
+    try std.testing.expectError(error.InsecurePermissions, store.load(allocator, "github"));
+
+    // A looser configured mode accepts the file
+    file_storage.withPermissions(0o750, 0o644);
+    var relaxed = (try store.load(allocator, "github")).?;
+    relaxed.deinit();
+}
+
+test "FileStorage.resolveStoragePath: honours overrides and platform defaults" {
+    const allocator = std.testing.allocator;
+
+    const Env = struct {
+        fn sandboxed(a: Allocator, name: []const u8) ?[]const u8 {
+            if (mem.eql(u8, name, "SCHLUSSEL_DATA_DIR")) return a.dupe(u8, "/app/data") catch null;
+            return desktop(a, name);
+        }
+        fn desktop(a: Allocator, name: []const u8) ?[]const u8 {
+            if (mem.eql(u8, name, "XDG_DATA_HOME")) return a.dupe(u8, "relative/data") catch null;
+            if (mem.eql(u8, name, "HOME")) return a.dupe(u8, "/home/ada") catch null;
+            if (mem.eql(u8, name, "LOCALAPPDATA")) return a.dupe(u8, "C:\\Users\\ada\\AppData\\Local") catch null;
+            return null;
+        }
+    };
+
+    const sandboxed = try FileStorage.resolveStoragePath(allocator, "tool", .linux, Env.sandboxed);
+    defer allocator.free(sandboxed);
+    try std.testing.expectEqualStrings("/app/data/tool", sandboxed);
+
+    // A relative XDG_DATA_HOME is ignored
+    const linux = try FileStorage.resolveStoragePath(allocator, "tool", .linux, Env.desktop);
+    defer allocator.free(linux);
+    try std.testing.expectEqualStrings("/home/ada/.local/share/tool", linux);
+
+    const macos = try FileStorage.resolveStoragePath(allocator, "tool", .macos, Env.desktop);
+    defer allocator.free(macos);
+    try std.testing.expectEqualStrings("/home/ada/Library/Application Support/tool", macos);
+
+    const windows = try FileStorage.resolveStoragePath(allocator, "tool", .windows, Env.desktop);
+    defer allocator.free(windows);
+    try std.testing.expectEqualStrings("C:\\Users\\ada\\AppData\\Local\\tool", windows);
+}
+
+test "FileStorage: concurrent saves to one key never leave a torn file" {
+    if (@import("builtin").single_threaded) return error.SkipZigTest;
+
+    const allocator = std.testing.allocator;
+
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
+
+    var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+    defer file_storage.deinit();
+    file_storage.withSerializedWrites();
+    try std.testing.expect(file_storage.storage().capabilities().atomic_swap);
+
+    const writer_count = 4;
+    const Writer = struct {
+        fn save(store: SessionStorage, id: u8) !void {
+            // Large, uniform payloads make a mixed write easy to detect
+            var access_token: [4096]u8 = undefined;
+            @memset(&access_token, 'a' + id);
+
+            var token = try Token.init(std.testing.allocator, &access_token, "Bearer");
+            defer token.deinit();
+            try store.save("shared", token);
+        }
+
+        fn run(store: SessionStorage, id: u8, failures: *std.atomic.Value(u32)) void {
+            for (0..25) |_| save(store, id) catch {
+                _ = failures.fetchAdd(1, .monotonic);
+            };
+        }
+    };
+
+    // Readers always find a complete token, never a missing one
+    try Writer.save(file_storage.storage(), 0);
+
+    var failures = std.atomic.Value(u32).init(0);
+    var threads: [writer_count]std.Thread = undefined;
+    for (&threads, 0..) |*thread, i| {
+        thread.* = try std.Thread.spawn(.{}, Writer.run, .{ file_storage.storage(), @as(u8, @intCast(i)), &failures });
+    }
+
+    // Every intermediate read parses and comes from a single writer
+    for (0..50) |_| {
+        var token = (try file_storage.storage().load(allocator, "shared")).?;
+        defer token.deinit();
+        try std.testing.expectEqual(@as(usize, 4096), token.access_token.len);
+        const first = token.access_token[0];
+        try std.testing.expect(first >= 'a' and first < 'a' + writer_count);
+        for (token.access_token) |c| try std.testing.expectEqual(first, c);
+    }
+
+    for (threads) |thread| thread.join();
+    try std.testing.expectEqual(@as(u32, 0), failures.load(.monotonic));
+
+    var final = (try file_storage.storage().load(allocator, "shared")).?;
+    defer final.deinit();
+    const first = final.access_token[0];
+    try std.testing.expect(first >= 'a' and first < 'a' + writer_count);
+    for (final.access_token) |c| try std.testing.expectEqual(first, c);
+
+    // No temporary files are left behind
+    var iter = tmp.dir.iterate();
+    var entries: usize = 0;
+    while (try iter.next()) |entry| {
+        entries += 1;
+        try std.testing.expectEqualStrings("shared.json", entry.name);
+    }
+    try std.testing.expectEqual(@as(usize, 1), entries);
+}
+
+test "Session: creation and token management" {
+    const allocator = std.testing.allocator;
+
+    var session_instance = try Session.init(allocator, "github.com");
+    defer session_instance.deinit();
+
+    try std.testing.expectEqualStrings("github.com", session_instance.domain);
+    try std.testing.expect(session_instance.token == null);
+
+    // Set token
+    const token = try Token.init(allocator, "session_token", "Bearer");
+    session_instance.setToken(token);
+
+    try std.testing.expect(session_instance.token != null);
+    try std.testing.expectEqualStrings("session_token", session_instance.token.?.access_token);
+
+    // Replace token
+    const new_token = try Token.init(allocator, "new_session_token", "Bearer");
+    session_instance.setToken(new_token);
+
+    try std.testing.expectEqualStrings("new_session_token", session_instance.token.?.access_token);
+
+    // Clear token
+    session_instance.clearToken();
+    try std.testing.expect(session_instance.token == null);
+}
+
+test "Session.toJson and fromJson: the nonce survives a restart" {
+    const allocator = std.testing.allocator;
+
+    var original = try Session.init(allocator, "github.com");
+    defer original.deinit();
+    try original.setRequestedScope("openid repo");
+    try original.setNonce("n-0S6_WzA2Mj");
+    try original.setAuthorizationParams(&.{.{ .name = "prompt", .value = "consent" }});
+
+    const data = try original.toJson(allocator);
+    defer allocator.free(data);
+
+    var restored = try Session.fromJson(allocator, data);
+    defer restored.deinit();
+    try std.testing.expectEqualStrings("github.com", restored.domain);
+    try std.testing.expectEqual(original.created_at, restored.created_at);
+    try std.testing.expectEqualStrings("openid repo", restored.requested_scope.?);
+    try std.testing.expectEqualStrings("n-0S6_WzA2Mj", restored.nonce.?);
+    try std.testing.expectEqual(@as(usize, 1), restored.authorization_params.len);
+    try std.testing.expectEqualStrings("consent", restored.authorization_params[0].value);
+
+    try std.testing.expectError(error.JsonError, Session.fromJson(allocator, "{}"));
+}
+
+test "FileStorage.withEncryptionKey: tokens round-trip encrypted and tampering is detected" {
+    const allocator = std.testing.allocator;
+
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
+
+    var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+    defer file_storage.deinit();
+    file_storage.withEncryptionKey([_]u8{0x42} ** 32);
+    const store = file_storage.storage();
+
+    var token = try Token.initFull(allocator, "secret-access", "Bearer", "secret-refresh", 3600, null, null);
+    defer token.deinit();
+    try store.save("github", token);
+
+    // Nothing readable on disk
+    const raw = try tmp.dir.readFileAlloc(allocator, "github.json", 1024 * 1024);
+    defer allocator.free(raw);
+    try std.testing.expect(mem.indexOf(u8, raw, "secret-access") == null);
+    try std.testing.expectEqual(FileStorage.FileFormat.encrypted, (try file_storage.fileFormat("github")).?);
+
+    var loaded = (try store.load(allocator, "github")).?;
+    defer loaded.deinit();
+    try std.testing.expectEqualStrings("secret-access", loaded.access_token);
+    try std.testing.expectEqualStrings("secret-refresh", loaded.refresh_token.?);
+    try std.testing.expect(!loaded.isExpired());
+
+    // Flipped ciphertext bit
+    const tampered = try allocator.dupe(u8, raw);
+    defer allocator.free(tampered);
+    tampered[tampered.len - 1] ^= 0x01;
+    try tmp.dir.writeFile(.{ .sub_path = "github.json", .data = tampered });
+    try std.testing.expectError(error.DecryptionFailed, store.load(allocator, "github"));
+
+    // Wrong key
+    try tmp.dir.writeFile(.{ .sub_path = "github.json", .data = raw });
+    file_storage.withEncryptionKey([_]u8{0x24} ** 32);
+    try std.testing.expectError(error.DecryptionFailed, store.load(allocator, "github"));
+
+    // Without a key the file is reported as encrypted, not as bad JSON
+    file_storage.encryption_key = null;
+    try std.testing.expectError(error.DecryptionFailed, store.load(allocator, "github"));
+}
+
+test "FileStorage.withKeyProvider: seals each file under a named key and survives rotation" {
+    const allocator = std.testing.allocator;
+
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
+
+    const entries = [_]StaticKeyProvider.Entry{
+        .{ .id = "kms-2025", .key = [_]u8{0x11} ** 32 },
+        .{ .id = "kms-2026", .key = [_]u8{0x22} ** 32 },
+    };
+    var provider = StaticKeyProvider{ .entries = &entries };
+
+    var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+    defer file_storage.deinit();
+    // Files from the old single-key setup stay readable
+    file_storage.withEncryptionKey([_]u8{0x42} ** 32);
+    const store = file_storage.storage();
+
+    var token = try Token.initFull(allocator, "secret-access", "Bearer", "secret-refresh", 3600, null, null);
+    defer token.deinit();
+    try store.save("legacy", token);
+
+    file_storage.withKeyProvider(provider.keyProvider());
+    try store.save("github", token);
+
+    const raw = try tmp.dir.readFileAlloc(allocator, "github.json", 1024 * 1024);
+    defer allocator.free(raw);
+    try std.testing.expect(mem.indexOf(u8, raw, "secret-access") == null);
+    try std.testing.expect(mem.indexOf(u8, raw, "kms-2025") != null);
+    try std.testing.expectEqual(FileStorage.FileFormat.encrypted, (try file_storage.fileFormat("github")).?);
+
+    var legacy = (try store.load(allocator, "legacy")).?;
+    defer legacy.deinit();
+    try std.testing.expectEqualStrings("secret-access", legacy.access_token);
+
+    // Rotate: new saves use the new key, older files still open
+    provider.current = 1;
+    try store.save("gitlab", token);
+    const rotated = try tmp.dir.readFileAlloc(allocator, "gitlab.json", 1024 * 1024);
+    defer allocator.free(rotated);
+    try std.testing.expect(mem.indexOf(u8, rotated, "kms-2026") != null);
+
+    var old = (try store.load(allocator, "github")).?;
+    defer old.deinit();
+    try std.testing.expectEqualStrings("secret-refresh", old.refresh_token.?);
+
+    // Keys are rotated by the provider, not the storage
+    try std.testing.expectError(error.UnsupportedOperation, file_storage.rotateEncryptionKey([_]u8{0x42} ** 32));
+    try std.testing.expectError(error.UnsupportedOperation, file_storage.rotatePassphrase("correct horse", .{ .t = 1, .m = 64, .p = 1 }));
+
+    // A file copied under another key is rejected
+    try tmp.dir.writeFile(.{ .sub_path = "gitlab.json", .data = raw });
+    try std.testing.expectError(error.DecryptionFailed, store.load(allocator, "gitlab"));
+
+    // The provider no longer knows the key the file names
+    var pruned = StaticKeyProvider{ .entries = entries[1..] };
+    file_storage.withKeyProvider(pruned.keyProvider());
+    try std.testing.expectError(error.DecryptionFailed, store.load(allocator, "github"));
+
+    // Without a provider the sealed file is not mistaken for JSON
+    file_storage.key_provider = null;
+    file_storage.encryption_key = null;
+    try std.testing.expectError(error.DecryptionFailed, store.load(allocator, "github"));
+}
+
+test "FileStorage.fileFormat: tells plaintext from encrypted files" {
+    const allocator = std.testing.allocator;
+
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
+
+    var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+    defer file_storage.deinit();
+    const store = file_storage.storage();
+
+    var token = try Token.init(allocator, "access", "Bearer");
+    defer token.deinit();
+    try store.save("legacy", token);
+
+    try std.testing.expect((try file_storage.fileFormat("missing")) == null);
+    try std.testing.expectEqual(FileStorage.FileFormat.plaintext, (try file_storage.fileFormat("legacy")).?);
+
+    // An encrypting storage refuses plaintext rather than trusting it
+    file_storage.withEncryptionKey([_]u8{0x42} ** 32);
+    try std.testing.expectError(error.DecryptionFailed, store.load(allocator, "legacy"));
+
+    // A file copied under another key does not decrypt
+    try store.save("github", token);
+    try tmp.dir.copyFile("github.json", tmp.dir, "gitlab.json", .{});
+    try std.testing.expectError(error.DecryptionFailed, store.load(allocator, "gitlab"));
+}
+
+test "FileStorage.rotateEncryptionKey: a rotation that fails midway leaves every token readable" {
+    const allocator = std.testing.allocator;
+
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
+
+    const old_key = [_]u8{0x01} ** 32;
+    const new_key = [_]u8{0x02} ** 32;
+    const names = [_][]const u8{ "github", "gitlab", "linear" };
+
+    var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+    defer file_storage.deinit();
+    file_storage.withEncryptionKey(old_key);
+    for (names) |name| {
+        var token = try Token.init(allocator, name, "Bearer");
+        defer token.deinit();
+        try file_storage.storage().save(name, token);
+    }
+
+    // Fail each allocation in turn, which interrupts the rotation before,
+    // between and during the rewrites, until one run completes
+    var fail_index: usize = 0;
+    const rewritten = while (true) : (fail_index += 1) {
+        var failing = std.testing.FailingAllocator.init(allocator, .{ .fail_index = fail_index });
+        file_storage.allocator = failing.allocator();
+        const result = file_storage.rotateEncryptionKey(new_key);
+        file_storage.allocator = allocator;
+        if (result) |count| break count else |_| {}
+
+        for (names) |name| {
+            var loaded = (try file_storage.storage().load(allocator, name)).?;
+            defer loaded.deinit();
+            try std.testing.expectEqualStrings(name, loaded.access_token);
+        }
+    };
+    try std.testing.expectEqual(names.len, rewritten);
+    try std.testing.expect(file_storage.rotation_key == null);
+
+    // Every file is sealed under the new key alone
+    var reopened = try FileStorage.initWithPath(allocator, dir_path);
+    defer reopened.deinit();
+    reopened.withEncryptionKey(new_key);
+    for (names) |name| {
+        var loaded = (try reopened.storage().load(allocator, name)).?;
+        defer loaded.deinit();
+        try std.testing.expectEqualStrings(name, loaded.access_token);
+    }
+}
+
+test "FileStorage.rotatePassphrase: re-encrypts every token under the new passphrase" {
+    const allocator = std.testing.allocator;
+
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
+
+    // Cheap parameters keep the test fast
+    const params: std.crypto.pwhash.argon2.Params = .{ .t = 1, .m = 64, .p = 1 };
+
+    var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+    defer file_storage.deinit();
+    const store = file_storage.storage();
+
+    // A plaintext token is migrated by the first rotation
+    var token = try Token.init(allocator, "secret-access", "Bearer");
+    defer token.deinit();
+    try store.save("legacy", token);
+
+    try std.testing.expectEqual(@as(usize, 1), try file_storage.rotatePassphrase("correct horse", params));
+    try std.testing.expectEqual(FileStorage.FileFormat.encrypted, (try file_storage.fileFormat("legacy")).?);
+    try store.save("github", token);
+
+    // The passphrase derives the same key in a new storage
+    var reopened = try FileStorage.initWithPath(allocator, dir_path);
+    defer reopened.deinit();
+    try reopened.withPassphrase("correct horse", params);
+    var loaded = (try reopened.storage().load(allocator, "github")).?;
+    defer loaded.deinit();
+    try std.testing.expectEqualStrings("secret-access", loaded.access_token);
+
+    try std.testing.expectEqual(@as(usize, 2), try reopened.rotatePassphrase("battery staple", params));
+
+    var stale = try FileStorage.initWithPath(allocator, dir_path);
+    defer stale.deinit();
+    try stale.withPassphrase("correct horse", params);
+    try std.testing.expectError(error.DecryptionFailed, stale.storage().load(allocator, "github"));
+
+    // Rotating with the wrong current key rewrites nothing
+    try std.testing.expectError(error.DecryptionFailed, stale.rotateEncryptionKey([_]u8{0x42} ** 32));
+    try stale.withPassphrase("battery staple", params);
+    var rotated = (try stale.storage().load(allocator, "legacy")).?;
+    defer rotated.deinit();
+    try std.testing.expectEqualStrings("secret-access", rotated.access_token);
+
+    // No pending salt is left behind by a finished rotation
+    try std.testing.expectError(error.FileNotFound, tmp.dir.access(".kdf-salt.next", .{}));
+
+    // An interrupted rotation: the pending salt is stored and "github" was
+    // already resealed under it, "legacy" was not
+    const pending_salt = [_]u8{0x07} ** 16;
+    try tmp.dir.writeFile(.{ .sub_path = ".kdf-salt.next", .data = &pending_salt });
+    var interrupted = try FileStorage.initWithPath(allocator, dir_path);
+    defer interrupted.deinit();
+    interrupted.withEncryptionKey(try FileStorage.deriveKey(allocator, "tr0ub4dor", &pending_salt, params));
+    try interrupted.storage().save("github", token);
+
+    // Retrying with the old passphrase and the same new one finishes it
+    var retry = try FileStorage.initWithPath(allocator, dir_path);
+    defer retry.deinit();
+    try retry.withPassphrase("battery staple", params);
+    try std.testing.expectEqual(@as(usize, 2), try retry.rotatePassphrase("tr0ub4dor", params));
+    try std.testing.expectError(error.FileNotFound, tmp.dir.access(".kdf-salt.next", .{}));
+
+    var finished = try FileStorage.initWithPath(allocator, dir_path);
+    defer finished.deinit();
+    try finished.withPassphrase("tr0ub4dor", params);
+    for ([_][]const u8{ "github", "legacy" }) |key| {
+        var reloaded = (try finished.storage().load(allocator, key)).?;
+        defer reloaded.deinit();
+        try std.testing.expectEqualStrings("secret-access", reloaded.access_token);
+    }
+}
+
+test "RefreshTokenOnlyStorage: persists the refresh token without the access token" {
+    const allocator = std.testing.allocator;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var disk = MemoryStorage.init(allocator);
+    defer disk.deinit();
+
+    var storage = RefreshTokenOnlyStorage.init(allocator, disk.storage());
+    defer storage.deinit();
+
+    var token = try Token.initFull(allocator, "access-secret", "Bearer", "refresh-1", 3600, "read", null);
+    defer token.deinit();
+    try storage.storage().save("user", token);
+
+    // The process sees the full token
+    var cached = (try storage.storage().load(allocator, "user")).?;
+    defer cached.deinit();
+    try std.testing.expectEqualStrings("access-secret", cached.access_token);
+
+    // The backing storage only holds an expired, refreshable record
+    var persisted = (try disk.storage().load(allocator, "user")).?;
+    defer persisted.deinit();
+    try std.testing.expectEqualStrings("", persisted.access_token);
+    try std.testing.expectEqualStrings("refresh-1", persisted.refresh_token.?);
+    try std.testing.expect(persisted.isExpired());
+
+    // After a restart the persisted record is what loads
+    var restarted = RefreshTokenOnlyStorage.init(allocator, disk.storage());
+    defer restarted.deinit();
+    var reloaded = (try restarted.storage().load(allocator, "user")).?;
+    defer reloaded.deinit();
+    try std.testing.expect(reloaded.isExpired());
+
+    // Tokens without a refresh token never reach the backing storage
+    var access_only = try Token.init(allocator, "short-lived", "Bearer");
+    defer access_only.deinit();
+    try restarted.storage().save("user", access_only);
+    try std.testing.expect(!disk.storage().exists("user"));
+    try std.testing.expect(restarted.storage().exists("user"));
+
+    const keys = try restarted.storage().listKeys(allocator);
+    defer SessionStorage.freeKeys(allocator, keys);
+    try std.testing.expectEqual(@as(usize, 1), keys.len);
+}
+
+test "ChainedStorage: falls back past failing backends and reports their errors" {
+    const allocator = std.testing.allocator;
+
+    const Unavailable = struct {
+        // Keeps the type addressable behind *anyopaque
+        unused: u8 = 0,
+
+        fn storage(self: *@This()) SessionStorage {
+            return .{ .ptr = self, .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+            }) };
+        }
+        fn save(_: *anyopaque, _: []const u8, _: Token) !void {
+            return error.StorageError;
+        }
+        fn load(_: *anyopaque, _: Allocator, _: []const u8) !?Token {
+            return error.StorageError;
+        }
+        fn delete(_: *anyopaque, _: []const u8) !void {
+            return error.StorageError;
+        }
+        fn exists(_: *anyopaque, _: []const u8) bool {
+            return false;
+        }
+    };
+
+    var keychain: Unavailable = .{};
+    var primary = MemoryStorage.init(allocator);
+    defer primary.deinit();
+    var fallback = MemoryStorage.init(allocator);
+    defer fallback.deinit();
 
-    try std.testing.expect(restored.expires_at != null);
-    const expires_at = restored.expires_at.?;
-    try std.testing.expect(expires_at >= before + 3600);
-    try std.testing.expect(expires_at <= after + 3600);
+    var chained = try ChainedStorage.init(allocator, &.{ keychain.storage(), primary.storage(), fallback.storage() });
+    defer chained.deinit();
+    const store = chained.storage();
+
+    var token = try Token.init(allocator, "chained", "Bearer");
+    defer token.deinit();
+    try store.save("user", token);
+    // The backend's own error is reported as StorageError.Backend
+    try std.testing.expectEqual(@as(?StorageError, error.Backend), chained.lastError(0));
+    try std.testing.expect(primary.storage().exists("user"));
+    try std.testing.expect(!fallback.storage().exists("user"));
+
+    var loaded = (try store.load(allocator, "user")).?;
+    defer loaded.deinit();
+    try std.testing.expectEqualStrings("chained", loaded.access_token);
+    try std.testing.expect(chained.lastError(1) == null);
+
+    // Deleting reaches every backend that holds a copy
+    try fallback.storage().save("user", token);
+    try store.delete("user");
+    try std.testing.expect(!store.exists("user"));
+    try std.testing.expect(!store.capabilities().list_keys);
+
+    var only_failing = try ChainedStorage.init(allocator, &.{keychain.storage()});
+    defer only_failing.deinit();
+    try std.testing.expectError(error.Backend, only_failing.storage().load(allocator, "user"));
 }
 
-test "MemoryStorage save and load" {
+test "ChainedStorage: a save that falls through evicts shadowing copies" {
     const allocator = std.testing.allocator;
 
-    var mem_storage = MemoryStorage.init(allocator);
-    defer mem_storage.deinit();
+    // Holds an older token but rejects writes, like a keychain that locked
+    const Locked = struct {
+        memory: MemoryStorage,
+        deletable: bool = true,
 
-    var token = try Token.init(allocator, "test_access", "Bearer");
-    defer token.deinit();
+        fn storage(self: *@This()) SessionStorage {
+            return .{ .ptr = self, .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+            }) };
+        }
+        fn save(_: *anyopaque, _: []const u8, _: Token) !void {
+            return error.Locked;
+        }
+        fn load(ptr: *anyopaque, allocator_: Allocator, key: []const u8) !?Token {
+            const self: *@This() = @ptrCast(@alignCast(ptr));
+            return self.memory.storage().load(allocator_, key);
+        }
+        fn delete(ptr: *anyopaque, key: []const u8) !void {
+            const self: *@This() = @ptrCast(@alignCast(ptr));
+            if (!self.deletable) return error.UnsupportedOperation;
+            return self.memory.storage().delete(key);
+        }
+        fn exists(ptr: *anyopaque, key: []const u8) bool {
+            const self: *@This() = @ptrCast(@alignCast(ptr));
+            return self.memory.storage().exists(key);
+        }
+    };
 
-    const storage_iface = mem_storage.storage();
-    try storage_iface.save("test_key", token);
+    var locked = Locked{ .memory = MemoryStorage.init(allocator) };
+    defer locked.memory.deinit();
+    var fallback = MemoryStorage.init(allocator);
+    defer fallback.deinit();
 
-    var loaded = (try storage_iface.load(allocator, "test_key")).?;
+    var stale = try Token.init(allocator, "stale", "Bearer");
+    defer stale.deinit();
+    try locked.memory.storage().save("user", stale);
+
+    var chained = try ChainedStorage.init(allocator, &.{ locked.storage(), fallback.storage() });
+    defer chained.deinit();
+    const store = chained.storage();
+
+    var fresh = try Token.init(allocator, "fresh", "Bearer");
+    defer fresh.deinit();
+    try store.save("user", fresh);
+    var loaded = (try store.load(allocator, "user")).?;
     defer loaded.deinit();
+    try std.testing.expectEqualStrings("fresh", loaded.access_token);
 
-    try std.testing.expectEqualStrings("test_access", loaded.access_token);
+    // A copy that cannot be evicted is reported instead of silently winning
+    try locked.memory.storage().save("user", stale);
+    locked.deletable = false;
+    try std.testing.expectError(error.UnsupportedOperation, store.save("user", fresh));
 }
 
-test "Token expiration checking" {
+test "NamespacedStorage: keeps tenants apart in one backing storage" {
     const allocator = std.testing.allocator;
 
-    var token = try Token.init(allocator, "expired_token", "Bearer");
-    defer token.deinit();
+    var shared = MemoryStorage.init(allocator);
+    defer shared.deinit();
 
-    token.expires_in = 3600;
-    token.expires_at = 1; // Long expired
+    var work = try NamespacedStorage.init(allocator, shared.storage(), "work");
+    defer work.deinit();
+    var personal = try NamespacedStorage.init(allocator, shared.storage(), "personal");
+    defer personal.deinit();
 
-    try std.testing.expect(token.isExpired());
-    try std.testing.expect(token.expiresWithin(0));
+    var work_token = try Token.init(allocator, "work-token", "Bearer");
+    defer work_token.deinit();
+    var personal_token = try Token.init(allocator, "personal-token", "Bearer");
+    defer personal_token.deinit();
+    try work.storage().save("github", work_token);
+    try personal.storage().save("github", personal_token);
+
+    try std.testing.expect(shared.storage().exists("work.github"));
+    var loaded = (try personal.storage().load(allocator, "github")).?;
+    defer loaded.deinit();
+    try std.testing.expectEqualStrings("personal-token", loaded.access_token);
+
+    const keys = try work.storage().listKeys(allocator);
+    defer SessionStorage.freeKeys(allocator, keys);
+    try std.testing.expectEqual(@as(usize, 1), keys.len);
+    try std.testing.expectEqualStrings("github", keys[0]);
+
+    try work.storage().delete("github");
+    try std.testing.expect(personal.storage().exists("github"));
+    try std.testing.expectError(error.InvalidParameter, NamespacedStorage.init(allocator, shared.storage(), "a.b"));
 }
 
-test "Token remaining lifetime fraction" {
+test "NamespacedStorage: qualified keys are plain FileStorage file names" {
     const allocator = std.testing.allocator;
 
-    const now = @as(u64, @intCast(std.time.timestamp()));
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
 
-    var token = try Token.init(allocator, "test_token", "Bearer");
-    defer token.deinit();
+    var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+    defer file_storage.deinit();
+    var work = try NamespacedStorage.init(allocator, file_storage.storage(), "work");
+    defer work.deinit();
 
-    token.expires_in = 3600;
-    token.expires_at = now + 1800; // Half expired
+    var token = try Token.init(allocator, "work-token", "Bearer");
+    defer token.deinit();
+    try work.storage().save("github", token);
 
-    if (token.remainingLifetimeFraction()) |fraction| {
-        try std.testing.expect(fraction >= 0.49 and fraction <= 0.51);
-    }
+    const keys = try file_storage.storage().listKeys(allocator);
+    defer SessionStorage.freeKeys(allocator, keys);
+    try std.testing.expectEqual(@as(usize, 1), keys.len);
+    try std.testing.expectEqualStrings("work.github", keys[0]);
 }
 
-// ============================================================================
-// Additional Memory Leak Tests
-// ============================================================================
-
-test "Token.initFull: all fields allocated without leaks" {
+test "CachedStorage: serves repeated loads from memory and evicts the least recently used" {
     const allocator = std.testing.allocator;
 
-    var token = try Token.initFull(
-        allocator,
-        "access_token_value",
-        "Bearer",
-        "refresh_token_value",
-        3600,
-        "read write delete",
-        "id_token_value",
-    );
-    defer token.deinit();
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
 
-    try std.testing.expectEqualStrings("access_token_value", token.access_token);
-    try std.testing.expectEqualStrings("Bearer", token.token_type);
-    try std.testing.expectEqualStrings("refresh_token_value", token.refresh_token.?);
-    try std.testing.expectEqual(@as(u64, 3600), token.expires_in.?);
-    try std.testing.expectEqualStrings("read write delete", token.scope.?);
-    try std.testing.expectEqualStrings("id_token_value", token.id_token.?);
+    var slow = MemoryStorage.init(allocator);
+    defer slow.deinit();
+    var cached = try CachedStorage.init(allocator, slow.storage(), 2);
+    defer cached.deinit();
+    cached.withMaxAge(60);
+    const store = cached.storage();
+
+    var token = try Token.init(allocator, "cached", "Bearer");
+    defer token.deinit();
+    try store.save("a", token);
+    try store.save("b", token);
+
+    // Served from the cache even though the backend lost it
+    try slow.storage().delete("a");
+    var hit = (try store.load(allocator, "a")).?;
+    hit.deinit();
+
+    // "b" is the least recently used entry when "c" arrives
+    try store.save("c", token);
+    try std.testing.expectEqual(@as(usize, 2), cached.count());
+    try std.testing.expect(store.exists("a"));
+
+    cached.invalidate("a");
+    try std.testing.expect((try store.load(allocator, "a")) == null);
+
+    // Entries expire after max_age
+    var other = try Token.init(allocator, "other process", "Bearer");
+    defer other.deinit();
+    try slow.storage().save("c", other);
+    clock.setMockTime(1_700_000_061);
+    var fresh = (try store.load(allocator, "c")).?;
+    defer fresh.deinit();
+    try std.testing.expectEqualStrings("other process", fresh.access_token);
+
+    try store.delete("c");
+    try std.testing.expect(!store.exists("c"));
 }
 
-test "Token.clone: clones all fields without leaks" {
+test "CachedStorage: a slow load cannot overwrite a newer save" {
     const allocator = std.testing.allocator;
 
-    var original = try Token.initFull(
-        allocator,
-        "original_access",
-        "Bearer",
-        "original_refresh",
-        7200,
-        "scope1 scope2",
-        "original_id",
-    );
-    defer original.deinit();
+    // Backend whose load stalls after reading, until released
+    const Stalling = struct {
+        memory: MemoryStorage,
+        loading: std.Thread.ResetEvent = .{},
+        release: std.Thread.ResetEvent = .{},
 
-    var cloned = try original.clone(allocator);
-    defer cloned.deinit();
+        fn storage(self: *@This()) SessionStorage {
+            return .{ .ptr = self, .vtable = SessionStorage.VTable.from(.{ .save = save, .load = load, .delete = delete, .exists = exists }) };
+        }
 
-    // Verify all fields cloned correctly
-    try std.testing.expectEqualStrings("original_access", cloned.access_token);
-    try std.testing.expectEqualStrings("Bearer", cloned.token_type);
-    try std.testing.expectEqualStrings("original_refresh", cloned.refresh_token.?);
-    try std.testing.expectEqual(@as(u64, 7200), cloned.expires_in.?);
-    try std.testing.expectEqualStrings("scope1 scope2", cloned.scope.?);
-    try std.testing.expectEqualStrings("original_id", cloned.id_token.?);
+        fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+            const self: *@This() = @ptrCast(@alignCast(ptr));
+            try self.memory.storage().save(key, token);
+        }
 
-    // Verify independent memory (different pointers)
-    try std.testing.expect(cloned.access_token.ptr != original.access_token.ptr);
-    try std.testing.expect(cloned.refresh_token.?.ptr != original.refresh_token.?.ptr);
-}
+        fn load(ptr: *anyopaque, alloc: Allocator, key: []const u8) !?Token {
+            const self: *@This() = @ptrCast(@alignCast(ptr));
+            const token = try self.memory.storage().load(alloc, key);
+            self.loading.set();
+            self.release.wait();
+            return token;
+        }
 
-test "Token.toJson and fromJson: roundtrip with all fields" {
-    const allocator = std.testing.allocator;
+        fn delete(ptr: *anyopaque, key: []const u8) !void {
+            const self: *@This() = @ptrCast(@alignCast(ptr));
+            try self.memory.storage().delete(key);
+        }
 
-    var original = try Token.initFull(
-        allocator,
-        "test_access",
-        "Bearer",
-        "test_refresh",
-        3600,
-        "read write",
-        "test_id_token",
-    );
-    defer original.deinit();
+        fn exists(ptr: *anyopaque, key: []const u8) bool {
+            const self: *@This() = @ptrCast(@alignCast(ptr));
+            return self.memory.storage().exists(key);
+        }
+    };
 
-    // Set expires_at manually for roundtrip test
-    original.expires_at = 1234567890;
+    var backend = Stalling{ .memory = MemoryStorage.init(allocator) };
+    defer backend.memory.deinit();
+    var cached = try CachedStorage.init(allocator, backend.storage(), 4);
+    defer cached.deinit();
+    const store = cached.storage();
 
-    const json_data = try original.toJson(allocator);
-    defer allocator.free(json_data);
+    var old = try Token.init(allocator, "old", "Bearer");
+    defer old.deinit();
+    try backend.memory.storage().save("user", old);
 
-    var restored = try Token.fromJson(allocator, json_data);
-    defer restored.deinit();
+    const Loader = struct {
+        fn run(s: SessionStorage) void {
+            var token = (s.load(std.testing.allocator, "user") catch return) orelse return;
+            token.deinit();
+        }
+    };
+    const loader = try std.Thread.spawn(.{}, Loader.run, .{store});
 
-    try std.testing.expectEqualStrings(original.access_token, restored.access_token);
-    try std.testing.expectEqualStrings(original.token_type, restored.token_type);
-    try std.testing.expectEqualStrings(original.refresh_token.?, restored.refresh_token.?);
-    try std.testing.expectEqual(original.expires_in, restored.expires_in);
-    try std.testing.expectEqual(original.expires_at, restored.expires_at);
-    try std.testing.expectEqualStrings(original.scope.?, restored.scope.?);
-    try std.testing.expectEqualStrings(original.id_token.?, restored.id_token.?);
+    // The load has read "old" when the rotated token is saved
+    backend.loading.wait();
+    const Saver = struct {
+        fn run(s: SessionStorage, token: *const Token) void {
+            s.save("user", token.*) catch {};
+        }
+    };
+    var rotated = try Token.init(allocator, "rotated", "Bearer");
+    defer rotated.deinit();
+    const saver = try std.Thread.spawn(.{}, Saver.run, .{ store, &rotated });
+    std.Thread.sleep(10 * std.time.ns_per_ms);
+    backend.release.set();
+    loader.join();
+    saver.join();
+
+    var current = (try store.load(allocator, "user")).?;
+    defer current.deinit();
+    try std.testing.expectEqualStrings("rotated", current.access_token);
 }
 
-test "Token.toJson: escapes special characters" {
+test "MemoryStorage.watch: reports saves and deletes of the watched key" {
     const allocator = std.testing.allocator;
 
-    var token = try Token.init(allocator, "token\"with\\special\nchars", "Bearer");
-    defer token.deinit();
+    const Recorder = struct {
+        changes: [4]TokenChange = undefined,
+        len: usize = 0,
 
-    const json_data = try token.toJson(allocator);
-    defer allocator.free(json_data);
+        fn record(context: *anyopaque, _: []const u8, change: TokenChange) void {
+            const self: *@This() = @ptrCast(@alignCast(context));
+            self.changes[self.len] = change;
+            self.len += 1;
+        }
+    };
 
-    // Verify the JSON can be parsed back
-    var restored = try Token.fromJson(allocator, json_data);
-    defer restored.deinit();
+    var memory = MemoryStorage.init(allocator);
+    defer memory.deinit();
+    var recorder: Recorder = .{};
+    const id = try memory.watch("github", .{ .context = &recorder, .callback = Recorder.record });
 
-    try std.testing.expectEqualStrings("token\"with\\special\nchars", restored.access_token);
+    var token = try Token.init(allocator, "watched", "Bearer");
+    defer token.deinit();
+    try memory.storage().save("github", token);
+    try memory.storage().save("github", token);
+    try memory.storage().save("gitlab", token);
+    try memory.storage().delete("github");
+    try std.testing.expectEqualSlices(TokenChange, &.{ .created, .updated, .deleted }, recorder.changes[0..recorder.len]);
+
+    memory.unwatch(id);
+    try memory.storage().save("github", token);
+    try std.testing.expectEqual(@as(usize, 3), recorder.len);
 }
 
-test "Token.fromJson: handles missing optional fields" {
+test "FileStorage.watch: notices a token saved by another storage instance" {
     const allocator = std.testing.allocator;
 
-    const minimal_json =
-        \\{"access_token":"minimal","token_type":"Bearer"}
-    ;
+    const Signal = struct {
+        event: std.Thread.ResetEvent = .{},
+        change: TokenChange = undefined,
 
-    var token = try Token.fromJson(allocator, minimal_json);
-    defer token.deinit();
+        fn record(context: *anyopaque, _: []const u8, change: TokenChange) void {
+            const self: *@This() = @ptrCast(@alignCast(context));
+            self.change = change;
+            self.event.set();
+        }
+    };
 
-    try std.testing.expectEqualStrings("minimal", token.access_token);
-    try std.testing.expectEqualStrings("Bearer", token.token_type);
-    try std.testing.expect(token.refresh_token == null);
-    try std.testing.expect(token.expires_in == null);
-    try std.testing.expect(token.expires_at == null);
-    try std.testing.expect(token.scope == null);
-    try std.testing.expect(token.id_token == null);
-}
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
 
-test "Token.fromJson: rejects invalid JSON" {
-    const allocator = std.testing.allocator;
+    var watched = try FileStorage.initWithPath(allocator, dir_path);
+    defer watched.deinit();
+    var other_process = try FileStorage.initWithPath(allocator, dir_path);
+    defer other_process.deinit();
 
-    // Missing required fields
-    const result1 = Token.fromJson(allocator, "{}");
-    try std.testing.expectError(error.InvalidParameter, result1);
+    var signal: Signal = .{};
+    const file_watch = try watched.watch("github", .{ .context = &signal, .callback = Signal.record }, 5);
+    defer file_watch.stop();
 
-    // Not an object
-    const result2 = Token.fromJson(allocator, "[]");
-    try std.testing.expectError(error.InvalidParameter, result2);
+    var token = try Token.init(allocator, "refreshed elsewhere", "Bearer");
+    defer token.deinit();
+    try other_process.storage().save("github", token);
 
-    // Invalid syntax
-    const result3 = Token.fromJson(allocator, "not json");
-    try std.testing.expectError(error.SyntaxError, result3);
+    try signal.event.timedWait(5 * std.time.ns_per_s);
+    try std.testing.expectEqual(TokenChange.created, signal.change);
 }
 
-test "MemoryStorage: multiple save and load cycles" {
+test "EnvStorage: resolves per-key variables before the JSON blob and refuses writes" {
     const allocator = std.testing.allocator;
 
-    var storage = MemoryStorage.init(allocator);
-    defer storage.deinit();
+    const Env = struct {
+        fn get(alloc: Allocator, name: []const u8) ?[]u8 {
+            const value: []const u8 = if (mem.eql(u8, name, "SCHLUSSEL_TOKEN_GITHUB_DEVICE"))
+                " ghp_bare\n"
+            else if (mem.eql(u8, name, "SCHLUSSEL_TOKEN_GITLAB"))
+                "{\"access_token\":\"glpat\",\"token_type\":\"Bearer\",\"scope\":\"api\"}"
+            else if (mem.eql(u8, name, "SCHLUSSEL_TOKENS"))
+                "{\"github:device\":\"shadowed\",\"linear\":{\"access_token\":\"lin\",\"token_type\":\"Bearer\"},\"jira\":\"jira-token\"}"
+            else
+                return null;
+            return alloc.dupe(u8, value) catch null;
+        }
+    };
 
-    const iface = storage.storage();
+    var env_storage = EnvStorage.init(allocator, .{});
+    env_storage.env = Env.get;
+    const store = env_storage.storage();
 
-    // Save multiple tokens
-    var token1 = try Token.init(allocator, "token1", "Bearer");
-    defer token1.deinit();
-    try iface.save("key1", token1);
+    var github = (try store.load(allocator, "github:device")).?;
+    defer github.deinit();
+    try std.testing.expectEqualStrings("ghp_bare", github.access_token);
+    try std.testing.expectEqualStrings("Bearer", github.token_type);
 
-    var token2 = try Token.init(allocator, "token2", "Bearer");
-    defer token2.deinit();
-    try iface.save("key2", token2);
+    var gitlab = (try store.load(allocator, "gitlab")).?;
+    defer gitlab.deinit();
+    try std.testing.expectEqualStrings("glpat", gitlab.access_token);
+    try std.testing.expectEqualStrings("api", gitlab.scope.?);
 
-    // Load and verify
-    var loaded1 = (try iface.load(allocator, "key1")).?;
-    defer loaded1.deinit();
-    try std.testing.expectEqualStrings("token1", loaded1.access_token);
+    var linear = (try store.load(allocator, "linear")).?;
+    defer linear.deinit();
+    try std.testing.expectEqualStrings("lin", linear.access_token);
 
-    var loaded2 = (try iface.load(allocator, "key2")).?;
-    defer loaded2.deinit();
-    try std.testing.expectEqualStrings("token2", loaded2.access_token);
+    var jira = (try store.load(allocator, "jira")).?;
+    defer jira.deinit();
+    try std.testing.expectEqualStrings("jira-token", jira.access_token);
 
-    // Overwrite
-    var token1_new = try Token.init(allocator, "token1_updated", "Bearer");
-    defer token1_new.deinit();
-    try iface.save("key1", token1_new);
+    try std.testing.expect((try store.load(allocator, "missing")) == null);
+    try std.testing.expect(store.exists("jira"));
+    try std.testing.expect(!store.exists("missing"));
 
-    var loaded1_new = (try iface.load(allocator, "key1")).?;
-    defer loaded1_new.deinit();
-    try std.testing.expectEqualStrings("token1_updated", loaded1_new.access_token);
+    try std.testing.expectError(error.UnsupportedOperation, store.save("jira", jira));
+    try std.testing.expectError(error.UnsupportedOperation, store.delete("jira"));
+
+    // Without the blob only per-key variables count
+    env_storage.options.blob_variable = null;
+    try std.testing.expect((try store.load(allocator, "jira")) == null);
 }
 
-test "MemoryStorage: delete removes token" {
+test "Token.offlineUsability: estimates from the stored expiry" {
     const allocator = std.testing.allocator;
 
-    var storage = MemoryStorage.init(allocator);
-    defer storage.deinit();
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    // Comfortably valid
+    var fresh = try Token.initFull(allocator, "access", "Bearer", null, 3600, null, null);
+    defer fresh.deinit();
+    clock.setMockTime(1_700_000_600);
+    try std.testing.expectEqual(Token.OfflineUsability{ .usable = .{ .remaining = 3000 } }, fresh.offlineUsability());
+
+    // Just expired
+    clock.setMockTime(1_700_003_605);
+    try std.testing.expectEqual(Token.OfflineUsability{ .stale = .{ .since = 5 } }, fresh.offlineUsability());
+
+    // Clock set back to before the token was issued
+    clock.setMockTime(1_699_999_000);
+    try std.testing.expectEqual(Token.OfflineUsability.unknown, fresh.offlineUsability());
+
+    // No expiry information
+    var no_expiry = try Token.init(allocator, "access", "Bearer");
+    defer no_expiry.deinit();
+    try std.testing.expectEqual(Token.OfflineUsability.unknown, no_expiry.offlineUsability());
+
+    // A JWT's nbf and iat take precedence over the derived issue time
+    clock.setMockTime(1_700_000_000);
+    var jwt_times = try Token.initFull(allocator, "access", "Bearer", null, 3600, null, null);
+    defer jwt_times.deinit();
+    jwt_times.not_before = 1_700_000_060;
+    try std.testing.expectEqual(Token.OfflineUsability{ .not_yet_valid = .{ .in = 60 } }, jwt_times.offlineUsability());
+    jwt_times.issued_at = 1_700_000_030;
+    try std.testing.expectEqual(Token.OfflineUsability.unknown, jwt_times.offlineUsability());
+    clock.setMockTime(1_700_000_100);
+    try std.testing.expectEqual(Token.OfflineUsability{ .usable = .{ .remaining = 3500 } }, jwt_times.offlineUsability());
+}
 
-    const iface = storage.storage();
+/// In-memory Keystore for tests
+const TestKeystore = struct {
+    secrets: std.StringHashMapUnmanaged([]const u8) = .{},
+    available: bool = true,
+    mutex: std.Thread.Mutex = .{},
 
-    var token = try Token.init(allocator, "to_delete", "Bearer");
-    defer token.deinit();
-    try iface.save("delete_key", token);
+    fn deinit(self: *TestKeystore) void {
+        var iter = self.secrets.iterator();
+        while (iter.next()) |entry| {
+            std.testing.allocator.free(entry.key_ptr.*);
+            std.testing.allocator.free(entry.value_ptr.*);
+        }
+        self.secrets.deinit(std.testing.allocator);
+    }
 
-    try std.testing.expect(iface.exists("delete_key"));
+    fn keystore(self: *TestKeystore) Keystore {
+        return .{ .ptr = self, .vtable = &.{ .load = load, .store = store } };
+    }
 
-    try iface.delete("delete_key");
+    fn load(ptr: *anyopaque, allocator: Allocator, name: []const u8) anyerror!?[]const u8 {
+        const self: *TestKeystore = @ptrCast(@alignCast(ptr));
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        if (!self.available) return error.StorageError;
+        const secret = self.secrets.get(name) orelse return null;
+        return try allocator.dupe(u8, secret);
+    }
 
-    try std.testing.expect(!iface.exists("delete_key"));
-    try std.testing.expect((try iface.load(allocator, "delete_key")) == null);
-}
+    fn store(ptr: *anyopaque, name: []const u8, secret: []const u8) anyerror!void {
+        const self: *TestKeystore = @ptrCast(@alignCast(ptr));
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        if (!self.available) return error.StorageError;
+        const value = try std.testing.allocator.dupe(u8, secret);
+        errdefer std.testing.allocator.free(value);
+        const entry = try self.secrets.getOrPut(std.testing.allocator, name);
+        if (entry.found_existing) {
+            std.testing.allocator.free(entry.value_ptr.*);
+        } else {
+            entry.key_ptr.* = std.testing.allocator.dupe(u8, name) catch |err| {
+                self.secrets.removeByPtr(entry.key_ptr);
+                return err;
+            };
+        }
+        entry.value_ptr.* = value;
+    }
+};
 
-test "MemoryStorage: load nonexistent returns null" {
+test "FileStorage.withKeystoreKey: generates, stores and unwraps the data key" {
     const allocator = std.testing.allocator;
 
-    var storage = MemoryStorage.init(allocator);
-    defer storage.deinit();
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
+
+    var keystore = TestKeystore{};
+    defer keystore.deinit();
+
+    // First start: a key is generated and kept in the keystore
+    {
+        var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+        defer file_storage.deinit();
+        try file_storage.withKeystoreKey(keystore.keystore(), "file-key");
+        try std.testing.expectEqual(@as(u32, 1), keystore.secrets.count());
+
+        var token = try Token.init(allocator, "secret-access", "Bearer");
+        defer token.deinit();
+        try file_storage.storage().save("github", token);
+        try std.testing.expectEqual(FileStorage.FileFormat.encrypted, (try file_storage.fileFormat("github")).?);
+    }
 
-    const result = try storage.storage().load(allocator, "nonexistent");
-    try std.testing.expect(result == null);
-}
+    // Next start: the same key is unwrapped and decrypts the record
+    {
+        var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+        defer file_storage.deinit();
+        try file_storage.withKeystoreKey(keystore.keystore(), "file-key");
+        try std.testing.expectEqual(@as(u32, 1), keystore.secrets.count());
 
-test "Session: creation and token management" {
-    const allocator = std.testing.allocator;
+        var loaded = (try file_storage.storage().load(allocator, "github")).?;
+        defer loaded.deinit();
+        try std.testing.expectEqualStrings("secret-access", loaded.access_token);
+    }
 
-    var session_instance = try Session.init(allocator, "github.com");
-    defer session_instance.deinit();
+    // An unavailable keystore is an error, not a silent plaintext fallback
+    {
+        keystore.available = false;
+        var file_storage = try FileStorage.initWithPath(allocator, dir_path);
+        defer file_storage.deinit();
+        try std.testing.expectError(error.StorageError, file_storage.withKeystoreKey(keystore.keystore(), "file-key"));
+        try std.testing.expect(file_storage.encryption_key == null);
+        // ...and the stored key is never replaced by a fresh one
+        keystore.available = true;
+        try std.testing.expectEqual(@as(u32, 1), keystore.secrets.count());
+        try file_storage.withKeystoreKey(keystore.keystore(), "file-key");
+        var loaded = (try file_storage.storage().load(allocator, "github")).?;
+        defer loaded.deinit();
+        try std.testing.expectEqualStrings("secret-access", loaded.access_token);
+    }
+}
 
-    try std.testing.expectEqualStrings("github.com", session_instance.domain);
-    try std.testing.expect(session_instance.token == null);
+test "FileStorage.withKeystoreKey: processes starting together share one key" {
+    if (@import("builtin").single_threaded) return error.SkipZigTest;
 
-    // Set token
-    const token = try Token.init(allocator, "session_token", "Bearer");
-    session_instance.setToken(token);
+    const allocator = std.testing.allocator;
 
-    try std.testing.expect(session_instance.token != null);
-    try std.testing.expectEqualStrings("session_token", session_instance.token.?.access_token);
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
 
-    // Replace token
-    const new_token = try Token.init(allocator, "new_session_token", "Bearer");
-    session_instance.setToken(new_token);
+    var keystore = TestKeystore{};
+    defer keystore.deinit();
 
-    try std.testing.expectEqualStrings("new_session_token", session_instance.token.?.access_token);
+    const Starter = struct {
+        fn run(path: []const u8, store: Keystore, out: *?[Aead.key_length]u8) void {
+            var file_storage = FileStorage.initWithPath(std.testing.allocator, path) catch return;
+            defer file_storage.deinit();
+            file_storage.withKeystoreKey(store, "file-key") catch return;
+            out.* = file_storage.encryption_key;
+        }
+    };
 
-    // Clear token
-    session_instance.clearToken();
-    try std.testing.expect(session_instance.token == null);
+    const starter_count = 4;
+    var keys = [_]?[Aead.key_length]u8{null} ** starter_count;
+    var threads: [starter_count]std.Thread = undefined;
+    for (&threads, &keys) |*thread, *key| {
+        thread.* = try std.Thread.spawn(.{}, Starter.run, .{ dir_path, keystore.keystore(), key });
+    }
+    for (threads) |thread| thread.join();
+
+    try std.testing.expectEqual(@as(u32, 1), keystore.secrets.count());
+    for (keys) |key| try std.testing.expectEqualSlices(u8, &keys[0].?, &key.?);
 }
@@ -0,0 +1,559 @@
+//! SQLite token and session storage
+//!
+//! `SqliteStorage` keeps every token as a row of a `tokens` table, keyed
+//! by storage key, so thousands of tokens live in a single file instead
+//! of one file each. Pending authorization sessions go in a `sessions`
+//! table keyed by their `state` parameter. The database runs in WAL mode with a busy
+//! timeout, so several processes can read and write it at once. It needs
+//! the system SQLite and is only built with `-Dsqlite=true`.
+//!
+//! The schema version is kept in `PRAGMA user_version`:
+//!
+//! ```sql
+//! CREATE TABLE tokens (
+//!     key TEXT PRIMARY KEY NOT NULL,
+//!     data BLOB NOT NULL,          -- codec-encoded Token
+//!     expires_at INTEGER,          -- Unix time, NULL if the token does not expire
+//!     refreshable INTEGER NOT NULL,
+//!     updated_at INTEGER NOT NULL
+//! ) WITHOUT ROWID;
+//! CREATE INDEX tokens_expires_at ON tokens (expires_at);
+//!
+//! -- Added in version 2
+//! CREATE TABLE sessions (
+//!     state TEXT PRIMARY KEY NOT NULL,
+//!     data BLOB NOT NULL,          -- Session.toJson()
+//!     created_at INTEGER NOT NULL,
+//!     updated_at INTEGER NOT NULL
+//! ) WITHOUT ROWID;
+//! CREATE INDEX sessions_created_at ON sessions (created_at);
+//! ```
+//!
+//! Opening a version 1 database adds the `sessions` table in place.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var db = try SqliteStorage.init(allocator, "/var/lib/sync/tokens.db");
+//! defer db.deinit();
+//!
+//! var client = OAuthClient.init(allocator, config, db.storage());
+//! // Periodically drop tokens that can no longer be used or refreshed
+//! _ = try db.pruneExpired(clock.now());
+//!
+//! // Keep the session until the callback for `state` comes back
+//! try db.saveSession(state, &pending);
+//! ```
+
+const std = @import("std");
+const build_options = @import("build_options");
+const Allocator = std.mem.Allocator;
+
+const clock = @import("clock.zig");
+const codec = @import("codec.zig");
+const session = @import("session.zig");
+
+const Session = session.Session;
+const Token = session.Token;
+const SessionStorage = session.SessionStorage;
+const StorageCapabilities = session.StorageCapabilities;
+
+/// Whether SQLite is linked into this build
+pub const supported = build_options.sqlite;
+
+/// `PRAGMA user_version` of the current schema
+pub const schema_version = 2;
+
+/// Migrations, indexed by the version they start from
+const migrations = [schema_version][:0]const u8{
+    \\BEGIN IMMEDIATE;
+    \\CREATE TABLE IF NOT EXISTS tokens (
+    \\    key TEXT PRIMARY KEY NOT NULL,
+    \\    data BLOB NOT NULL,
+    \\    expires_at INTEGER,
+    \\    refreshable INTEGER NOT NULL,
+    \\    updated_at INTEGER NOT NULL
+    \\) WITHOUT ROWID;
+    \\CREATE INDEX IF NOT EXISTS tokens_expires_at ON tokens (expires_at);
+    \\PRAGMA user_version = 1;
+    \\COMMIT;
+    ,
+    \\BEGIN IMMEDIATE;
+    \\CREATE TABLE IF NOT EXISTS sessions (
+    \\    state TEXT PRIMARY KEY NOT NULL,
+    \\    data BLOB NOT NULL,
+    \\    created_at INTEGER NOT NULL,
+    \\    updated_at INTEGER NOT NULL
+    \\) WITHOUT ROWID;
+    \\CREATE INDEX IF NOT EXISTS sessions_created_at ON sessions (created_at);
+    \\PRAGMA user_version = 2;
+    \\COMMIT;
+};
+
+/// Token storage in a SQLite database
+pub const SqliteStorage = struct {
+    allocator: Allocator,
+    db: *c.Database,
+
+    /// Open (or create) the database at `path`
+    ///
+    /// Returns `error.UnsupportedOperation` in builds without SQLite and
+    /// `error.StorageError` for a database with a newer schema.
+    pub fn init(allocator: Allocator, path: []const u8) !SqliteStorage {
+        if (!supported) return error.UnsupportedOperation;
+
+        const path_z = try allocator.dupeZ(u8, path);
+        defer allocator.free(path_z);
+
+        var db: ?*c.Database = null;
+        // Serialized mode: the handle is shared with background refreshes
+        const flags = c.open_readwrite | c.open_create | c.open_fullmutex;
+        if (c.sqlite3_open_v2(path_z, &db, flags, null) != c.ok) {
+            if (db) |handle| _ = c.sqlite3_close_v2(handle);
+            return error.StorageError;
+        }
+        var self = SqliteStorage{ .allocator = allocator, .db = db.? };
+        errdefer _ = c.sqlite3_close_v2(self.db);
+
+        _ = c.sqlite3_busy_timeout(self.db, 5000);
+        try self.exec("PRAGMA journal_mode = WAL;");
+
+        const version = try self.userVersion();
+        if (version > schema_version) return error.StorageError;
+
+        // Another process may migrate between the check and BEGIN IMMEDIATE;
+        // every step is idempotent, so running it twice is harmless
+        for (migrations[@intCast(version)..]) |migration| {
+            self.exec(migration) catch |err| {
+                self.exec("ROLLBACK;") catch {};
+                return err;
+            };
+        }
+        return self;
+    }
+
+    pub fn deinit(self: *SqliteStorage) void {
+        if (!supported) return;
+        _ = c.sqlite3_close_v2(self.db);
+    }
+
+    pub fn storage(self: *SqliteStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+                .capabilities = capabilities,
+                .list_keys = listKeys,
+                .save_session = saveSessionEntry,
+                .load_session = loadSessionEntry,
+                .delete_session = deleteSessionEntry,
+                .list_session_states = listSessionStates,
+            }),
+        };
+    }
+
+    /// Delete tokens that expired before `cutoff` and cannot be refreshed
+    ///
+    /// Returns the number of tokens removed.
+    pub fn pruneExpired(self: *SqliteStorage, cutoff: u64) !usize {
+        if (!supported) return error.UnsupportedOperation;
+
+        var stmt = try Statement.prepare(self.db, "DELETE FROM tokens WHERE expires_at < ?1 AND refreshable = 0;");
+        defer stmt.finalize();
+        try stmt.bindInt(1, cutoff);
+        _ = try stmt.step();
+        return @intCast(c.sqlite3_changes(self.db));
+    }
+
+    /// Store the authorization session started with `state`
+    ///
+    /// Only what Session.toJson() keeps is stored; the token goes through
+    /// storage() like any other.
+    pub fn saveSession(self: *SqliteStorage, state: []const u8, pending: *const Session) !void {
+        if (!supported) return error.UnsupportedOperation;
+
+        const data = try pending.toJson(self.allocator);
+        defer {
+            std.crypto.secureZero(u8, data);
+            self.allocator.free(data);
+        }
+
+        var stmt = try Statement.prepare(self.db,
+            \\INSERT INTO sessions (state, data, created_at, updated_at) VALUES (?1, ?2, ?3, ?4)
+            \\ON CONFLICT (state) DO UPDATE SET data = excluded.data, updated_at = excluded.updated_at;
+        );
+        defer stmt.finalize();
+        try stmt.bindText(1, state);
+        try stmt.bindBlob(2, data);
+        try stmt.bindInt(3, pending.created_at);
+        try stmt.bindInt(4, clock.now());
+        _ = try stmt.step();
+    }
+
+    /// Load the session saved for `state`, or null if there is none
+    pub fn loadSession(self: *SqliteStorage, allocator: Allocator, state: []const u8) !?Session {
+        if (!supported) return error.UnsupportedOperation;
+
+        var stmt = try Statement.prepare(self.db, "SELECT data FROM sessions WHERE state = ?1;");
+        defer stmt.finalize();
+        try stmt.bindText(1, state);
+        if (!try stmt.step()) return null;
+        return try Session.fromJson(allocator, stmt.columnBytes(0));
+    }
+
+    pub fn deleteSession(self: *SqliteStorage, state: []const u8) !void {
+        if (!supported) return error.UnsupportedOperation;
+
+        var stmt = try Statement.prepare(self.db, "DELETE FROM sessions WHERE state = ?1;");
+        defer stmt.finalize();
+        try stmt.bindText(1, state);
+        _ = try stmt.step();
+    }
+
+    /// Delete sessions started before `cutoff`, i.e. abandoned authorizations
+    ///
+    /// Returns the number of sessions removed.
+    pub fn pruneSessions(self: *SqliteStorage, cutoff: u64) !usize {
+        if (!supported) return error.UnsupportedOperation;
+
+        var stmt = try Statement.prepare(self.db, "DELETE FROM sessions WHERE created_at < ?1;");
+        defer stmt.finalize();
+        try stmt.bindInt(1, cutoff);
+        _ = try stmt.step();
+        return @intCast(c.sqlite3_changes(self.db));
+    }
+
+    fn capabilities(_: *anyopaque) StorageCapabilities {
+        // Each save is a single upsert
+        return .{ .list_keys = true, .atomic_swap = true, .iteration = true, .sessions = true };
+    }
+
+    fn saveSessionEntry(ptr: *anyopaque, state: []const u8, pending: *const Session) !void {
+        const self: *SqliteStorage = @ptrCast(@alignCast(ptr));
+        return self.saveSession(state, pending);
+    }
+
+    fn loadSessionEntry(ptr: *anyopaque, allocator: Allocator, state: []const u8) !?Session {
+        const self: *SqliteStorage = @ptrCast(@alignCast(ptr));
+        return self.loadSession(allocator, state);
+    }
+
+    fn deleteSessionEntry(ptr: *anyopaque, state: []const u8) !void {
+        const self: *SqliteStorage = @ptrCast(@alignCast(ptr));
+        return self.deleteSession(state);
+    }
+
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *SqliteStorage = @ptrCast(@alignCast(ptr));
+
+        const data = try codec.default.encode(self.allocator, &token);
+        defer {
+            std.crypto.secureZero(u8, data);
+            self.allocator.free(data);
+        }
+
+        var stmt = try Statement.prepare(self.db,
+            \\INSERT INTO tokens (key, data, expires_at, refreshable, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)
+            \\ON CONFLICT (key) DO UPDATE SET data = excluded.data, expires_at = excluded.expires_at,
+            \\    refreshable = excluded.refreshable, updated_at = excluded.updated_at;
+        );
+        defer stmt.finalize();
+        try stmt.bindText(1, key);
+        try stmt.bindBlob(2, data);
+        if (token.expires_at) |expires_at| try stmt.bindInt(3, expires_at) else try stmt.bindNull(3);
+        try stmt.bindInt(4, @intFromBool(token.refresh_token != null));
+        try stmt.bindInt(5, clock.now());
+        _ = try stmt.step();
+    }
+
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *SqliteStorage = @ptrCast(@alignCast(ptr));
+
+        var stmt = try Statement.prepare(self.db, "SELECT data FROM tokens WHERE key = ?1;");
+        defer stmt.finalize();
+        try stmt.bindText(1, key);
+        if (!try stmt.step()) return null;
+        return try codec.default.decode(allocator, stmt.columnBytes(0));
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *SqliteStorage = @ptrCast(@alignCast(ptr));
+
+        var stmt = try Statement.prepare(self.db, "DELETE FROM tokens WHERE key = ?1;");
+        defer stmt.finalize();
+        try stmt.bindText(1, key);
+        _ = try stmt.step();
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        if (!supported) return false;
+        const self: *SqliteStorage = @ptrCast(@alignCast(ptr));
+
+        var stmt = Statement.prepare(self.db, "SELECT 1 FROM tokens WHERE key = ?1;") catch return false;
+        defer stmt.finalize();
+        stmt.bindText(1, key) catch return false;
+        return stmt.step() catch false;
+    }
+
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) ![][]const u8 {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *SqliteStorage = @ptrCast(@alignCast(ptr));
+        return self.listColumn(allocator, "SELECT key FROM tokens;");
+    }
+
+    fn listSessionStates(ptr: *anyopaque, allocator: Allocator) ![][]const u8 {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *SqliteStorage = @ptrCast(@alignCast(ptr));
+        return self.listColumn(allocator, "SELECT state FROM sessions;");
+    }
+
+    /// Copy the first column of every row returned by `sql`
+    fn listColumn(self: *SqliteStorage, allocator: Allocator, sql: []const u8) ![][]const u8 {
+        var stmt = try Statement.prepare(self.db, sql);
+        defer stmt.finalize();
+
+        var keys: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
+        while (try stmt.step()) {
+            const key = try allocator.dupe(u8, stmt.columnBytes(0));
+            errdefer allocator.free(key);
+            try keys.append(allocator, key);
+        }
+        return keys.toOwnedSlice(allocator);
+    }
+
+    fn userVersion(self: *SqliteStorage) !i64 {
+        var stmt = try Statement.prepare(self.db, "PRAGMA user_version;");
+        defer stmt.finalize();
+        if (!try stmt.step()) return error.StorageError;
+        return stmt.columnInt(0);
+    }
+
+    fn exec(self: *SqliteStorage, sql: [:0]const u8) !void {
+        if (c.sqlite3_exec(self.db, sql, null, null, null) != c.ok) {
+            std.log.warn("SQLite error: {s}", .{c.sqlite3_errmsg(self.db)});
+            return error.StorageError;
+        }
+    }
+};
+
+/// Prepared statement; bound slices must outlive step()
+const Statement = struct {
+    db: *c.Database,
+    stmt: *c.Stmt,
+
+    fn prepare(db: *c.Database, sql: []const u8) !Statement {
+        var stmt: ?*c.Stmt = null;
+        if (c.sqlite3_prepare_v2(db, sql.ptr, @intCast(sql.len), &stmt, null) != c.ok) {
+            std.log.warn("SQLite error: {s}", .{c.sqlite3_errmsg(db)});
+            return error.StorageError;
+        }
+        return .{ .db = db, .stmt = stmt orelse return error.StorageError };
+    }
+
+    fn finalize(self: *Statement) void {
+        _ = c.sqlite3_finalize(self.stmt);
+    }
+
+    fn bindText(self: *Statement, index: c_int, value: []const u8) !void {
+        try self.check(c.sqlite3_bind_text(self.stmt, index, value.ptr, @intCast(value.len), null));
+    }
+
+    fn bindBlob(self: *Statement, index: c_int, value: []const u8) !void {
+        try self.check(c.sqlite3_bind_blob(self.stmt, index, value.ptr, @intCast(value.len), null));
+    }
+
+    fn bindInt(self: *Statement, index: c_int, value: u64) !void {
+        try self.check(c.sqlite3_bind_int64(self.stmt, index, @intCast(value)));
+    }
+
+    fn bindNull(self: *Statement, index: c_int) !void {
+        try self.check(c.sqlite3_bind_null(self.stmt, index));
+    }
+
+    /// Advance to the next row; false once the statement is done
+    fn step(self: *Statement) !bool {
+        return switch (c.sqlite3_step(self.stmt)) {
+            c.row => true,
+            c.done => false,
+            // Another connection held the database past the busy timeout
+            c.busy, c.locked => error.Locked,
+            else => {
+                std.log.warn("SQLite error: {s}", .{c.sqlite3_errmsg(self.db)});
+                return error.StorageError;
+            },
+        };
+    }
+
+    /// Text or blob of `column` in the current row, valid until the next step()
+    fn columnBytes(self: *Statement, column: c_int) []const u8 {
+        const len: usize = @intCast(c.sqlite3_column_bytes(self.stmt, column));
+        if (len == 0) return "";
+        const bytes: [*]const u8 = @ptrCast(c.sqlite3_column_blob(self.stmt, column).?);
+        return bytes[0..len];
+    }
+
+    fn columnInt(self: *Statement, column: c_int) i64 {
+        return c.sqlite3_column_int64(self.stmt, column);
+    }
+
+    fn check(self: *Statement, rc: c_int) !void {
+        if (rc == c.ok) return;
+        std.log.warn("SQLite error: {s}", .{c.sqlite3_errmsg(self.db)});
+        return error.StorageError;
+    }
+};
+
+/// The parts of the SQLite C API used here
+const c = struct {
+    const Database = opaque {};
+    const Stmt = opaque {};
+
+    const ok: c_int = 0;
+    const busy: c_int = 5;
+    const locked: c_int = 6;
+    const row: c_int = 100;
+    const done: c_int = 101;
+
+    const open_readwrite: c_int = 0x00000002;
+    const open_create: c_int = 0x00000004;
+    const open_fullmutex: c_int = 0x00010000;
+
+    extern fn sqlite3_open_v2(filename: [*:0]const u8, db: *?*Database, flags: c_int, vfs: ?[*:0]const u8) c_int;
+    extern fn sqlite3_close_v2(db: *Database) c_int;
+    extern fn sqlite3_busy_timeout(db: *Database, ms: c_int) c_int;
+    extern fn sqlite3_exec(db: *Database, sql: [*:0]const u8, callback: ?*const anyopaque, arg: ?*anyopaque, errmsg: ?*?[*:0]u8) c_int;
+    extern fn sqlite3_errmsg(db: *Database) [*:0]const u8;
+    extern fn sqlite3_changes(db: *Database) c_int;
+    extern fn sqlite3_prepare_v2(db: *Database, sql: [*]const u8, len: c_int, stmt: *?*Stmt, tail: ?*?[*]const u8) c_int;
+    extern fn sqlite3_finalize(stmt: *Stmt) c_int;
+    extern fn sqlite3_step(stmt: *Stmt) c_int;
+    extern fn sqlite3_bind_text(stmt: *Stmt, index: c_int, value: [*]const u8, len: c_int, destructor: ?*const anyopaque) c_int;
+    extern fn sqlite3_bind_blob(stmt: *Stmt, index: c_int, value: [*]const u8, len: c_int, destructor: ?*const anyopaque) c_int;
+    extern fn sqlite3_bind_int64(stmt: *Stmt, index: c_int, value: i64) c_int;
+    extern fn sqlite3_bind_null(stmt: *Stmt, index: c_int) c_int;
+    extern fn sqlite3_column_blob(stmt: *Stmt, column: c_int) ?*const anyopaque;
+    extern fn sqlite3_column_bytes(stmt: *Stmt, column: c_int) c_int;
+    extern fn sqlite3_column_int64(stmt: *Stmt, column: c_int) i64;
+};
+
+test "SqliteStorage saves, lists and prunes tokens" {
+    const allocator = std.testing.allocator;
+
+    if (!supported) {
+        try std.testing.expectError(error.UnsupportedOperation, SqliteStorage.init(allocator, ":memory:"));
+        return;
+    }
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var db = try SqliteStorage.init(allocator, ":memory:");
+    defer db.deinit();
+    const store = db.storage();
+
+    var expiring = try Token.initFull(allocator, "short-lived", "Bearer", null, 60, null, null);
+    defer expiring.deinit();
+    var refreshable = try Token.initFull(allocator, "access", "Bearer", "refresh", 60, null, null);
+    defer refreshable.deinit();
+    try store.save("ci", expiring);
+    try store.save("user", refreshable);
+    try store.save("user", refreshable);
+
+    var loaded = (try store.load(allocator, "user")).?;
+    defer loaded.deinit();
+    try std.testing.expectEqualStrings("refresh", loaded.refresh_token.?);
+    try std.testing.expect(store.exists("ci"));
+
+    const keys = try store.listKeys(allocator);
+    defer SessionStorage.freeKeys(allocator, keys);
+    try std.testing.expectEqual(@as(usize, 2), keys.len);
+
+    // Only the token that cannot be refreshed goes
+    try std.testing.expectEqual(@as(usize, 1), try db.pruneExpired(1_700_000_120));
+    try std.testing.expect(!store.exists("ci"));
+    try store.delete("user");
+    try std.testing.expect((try store.load(allocator, "user")) == null);
+}
+
+test "SqliteStorage keeps sessions by state" {
+    const allocator = std.testing.allocator;
+    if (!supported) return;
+
+    clock.setMockTime(1_700_000_000);
+    defer clock.clearMockTime();
+
+    var db = try SqliteStorage.init(allocator, ":memory:");
+    defer db.deinit();
+
+    var pending = try Session.init(allocator, "github.com");
+    defer pending.deinit();
+    pending.created_at = 1_700_000_000;
+    try pending.setNonce("n-0S6_WzA2Mj");
+    try db.saveSession("state-1", &pending);
+
+    var restored = (try db.loadSession(allocator, "state-1")).?;
+    defer restored.deinit();
+    try std.testing.expectEqualStrings("github.com", restored.domain);
+    try std.testing.expectEqualStrings("n-0S6_WzA2Mj", restored.nonce.?);
+    try std.testing.expect((try db.loadSession(allocator, "state-2")) == null);
+
+    try std.testing.expectEqual(@as(usize, 0), try db.pruneSessions(1_700_000_000));
+    try std.testing.expectEqual(@as(usize, 1), try db.pruneSessions(1_700_000_001));
+    try std.testing.expect((try db.loadSession(allocator, "state-1")) == null);
+
+    try db.storage().saveSession("state-1", &pending);
+    const states = try db.storage().listSessionStates(allocator);
+    defer SessionStorage.freeKeys(allocator, states);
+    try std.testing.expectEqual(@as(usize, 1), states.len);
+    try std.testing.expectEqualStrings("state-1", states[0]);
+
+    try db.deleteSession("state-1");
+    try std.testing.expect((try db.loadSession(allocator, "state-1")) == null);
+}
+
+test "SqliteStorage migrates a version 1 database" {
+    const allocator = std.testing.allocator;
+    if (!supported) return;
+
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const dir_path = try tmp.dir.realpathAlloc(allocator, ".");
+    defer allocator.free(dir_path);
+    const path = try std.fs.path.join(allocator, &.{ dir_path, "tokens.db" });
+    defer allocator.free(path);
+
+    {
+        var db = try SqliteStorage.init(allocator, path);
+        defer db.deinit();
+        // Back to the version 1 layout, with a token in it
+        try db.exec("DROP TABLE sessions; PRAGMA user_version = 1;");
+        var token = try Token.init(allocator, "kept", "Bearer");
+        defer token.deinit();
+        try db.storage().save("user", token);
+    }
+
+    var db = try SqliteStorage.init(allocator, path);
+    defer db.deinit();
+    try std.testing.expectEqual(@as(i64, schema_version), try db.userVersion());
+
+    var token = (try db.storage().load(allocator, "user")).?;
+    defer token.deinit();
+    try std.testing.expectEqualStrings("kept", token.access_token);
+
+    var pending = try Session.init(allocator, "github.com");
+    defer pending.deinit();
+    try db.saveSession("state", &pending);
+    var restored = (try db.loadSession(allocator, "state")).?;
+    defer restored.deinit();
+    try std.testing.expectEqualStrings("github.com", restored.domain);
+}
@@ -0,0 +1,506 @@
+//! HTTP transport used by the OAuth client
+//!
+//! `OAuthClient` sends every request to the authorization server through an
+//! `HttpTransport`. The default transport is backed by `std.http.Client`;
+//! applications can plug in their own implementation (for example to route
+//! through a proxy), and tests use `MockTransport` to return canned responses
+//! without touching the network.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var mock = MockTransport.init(allocator);
+//! defer mock.deinit();
+//!
+//! try mock.enqueue(.{ .body = "{\"access_token\":\"abc\",\"token_type\":\"Bearer\"}" });
+//!
+//! var client = OAuthClient.init(allocator, config, storage.storage());
+//! client.http_transport = mock.transport();
+//! ```
+//!
+//! ## Mutual TLS (RFC 8705)
+//!
+//! Schlussel covers the OAuth side of mutual TLS: `tls_client_auth` token
+//! requests (the certificate replaces `client_secret`), `x5t#S256`
+//! thumbprints and the `cnf` check on certificate-bound tokens. It does not
+//! perform the TLS handshake with a client certificate. std.crypto.tls has
+//! no client authentication, so StdTransport fails any request carrying
+//! `client_certificate` with `error.UnsupportedOperation`; a transport
+//! built on a TLS stack that can present the certificate (libcurl,
+//! OpenSSL) has to be supplied by the application.
+
+const std = @import("std");
+const http = std.http;
+const Allocator = std.mem.Allocator;
+
+/// A single HTTP header
+pub const Header = http.Header;
+
+/// An outgoing HTTP request
+pub const Request = struct {
+    method: http.Method = .POST,
+    url: []const u8,
+    /// Additional request headers (Content-Type, Accept, Authorization, ...)
+    headers: []const Header = &.{},
+    /// Request body, if any
+    body: ?[]const u8 = null,
+    /// Certificate to present in the TLS handshake (mutual TLS, RFC 8705)
+    ///
+    /// StdTransport cannot present one and returns
+    /// `error.UnsupportedOperation`; see "Mutual TLS" above.
+    client_certificate: ?ClientCertificate = null,
+};
+
+/// A PEM-encoded client certificate and its private key
+pub const ClientCertificate = struct {
+    cert_pem: []const u8,
+    key_pem: []const u8,
+
+    /// Read the certificate and key from PEM files; release with free()
+    pub fn loadFiles(allocator: Allocator, cert_path: []const u8, key_path: []const u8) !ClientCertificate {
+        const max_size = 64 * 1024;
+        const cert_pem = try std.fs.cwd().readFileAlloc(allocator, cert_path, max_size);
+        errdefer allocator.free(cert_pem);
+        const key_pem = try std.fs.cwd().readFileAlloc(allocator, key_path, max_size);
+        return .{ .cert_pem = cert_pem, .key_pem = key_pem };
+    }
+
+    /// Free a certificate returned by loadFiles()
+    pub fn free(self: ClientCertificate, allocator: Allocator) void {
+        allocator.free(self.cert_pem);
+        std.crypto.secureZero(u8, @constCast(self.key_pem));
+        allocator.free(self.key_pem);
+    }
+
+    /// SHA-256 thumbprint of the certificate (base64url), as carried in the
+    /// `x5t#S256` confirmation claim of certificate-bound tokens
+    pub fn thumbprint(self: ClientCertificate, allocator: Allocator) ![43]u8 {
+        const begin_marker = "-----BEGIN CERTIFICATE-----";
+        const end_marker = "-----END CERTIFICATE-----";
+        const begin = std.mem.indexOf(u8, self.cert_pem, begin_marker) orelse return error.InvalidParameter;
+        const body_start = begin + begin_marker.len;
+        const end = std.mem.indexOfPos(u8, self.cert_pem, body_start, end_marker) orelse return error.InvalidParameter;
+        const encoded = self.cert_pem[body_start..end];
+
+        const decoder = std.base64.standard.decoderWithIgnore(" \t\r\n");
+        const der = try allocator.alloc(u8, decoder.calcSizeUpperBound(encoded.len) catch return error.InvalidParameter);
+        defer allocator.free(der);
+        const der_len = decoder.decode(der, encoded) catch return error.InvalidParameter;
+        if (der_len == 0) return error.InvalidParameter;
+
+        var digest: [32]u8 = undefined;
+        std.crypto.hash.sha2.Sha256.hash(der[0..der_len], &digest, .{});
+        var out: [43]u8 = undefined;
+        _ = std.base64.url_safe_no_pad.Encoder.encode(&out, &digest);
+        return out;
+    }
+};
+
+/// A received HTTP response
+///
+/// The response owns its headers and body and must be released with deinit().
+pub const Response = struct {
+    allocator: Allocator,
+    status: u16,
+    headers: []Header,
+    body: []const u8,
+
+    pub fn deinit(self: *Response) void {
+        for (self.headers) |h| {
+            self.allocator.free(h.name);
+            self.allocator.free(h.value);
+        }
+        self.allocator.free(self.headers);
+        self.allocator.free(self.body);
+    }
+
+    /// Look up a response header by name (case-insensitive)
+    pub fn header(self: *const Response, name: []const u8) ?[]const u8 {
+        for (self.headers) |h| {
+            if (std.ascii.eqlIgnoreCase(h.name, name)) return h.value;
+        }
+        return null;
+    }
+};
+
+/// Transport interface for sending HTTP requests
+pub const HttpTransport = struct {
+    ptr: *anyopaque,
+    vtable: *const VTable,
+
+    pub const VTable = struct {
+        send: *const fn (ptr: *anyopaque, allocator: Allocator, request: Request) anyerror!Response,
+    };
+
+    /// Send a request and return the response (allocated with `allocator`)
+    pub fn send(self: HttpTransport, allocator: Allocator, request: Request) !Response {
+        return self.vtable.send(self.ptr, allocator, request);
+    }
+};
+
+/// Transport backed by `std.http.Client`
+pub const StdTransport = struct {
+    /// Maximum response size (1 MB) to prevent unbounded memory allocation
+    max_response_size: usize = 1024 * 1024,
+
+    /// Redirects followed for GET requests
+    ///
+    /// std.http only names `not_allowed` and `unhandled`; other limits are
+    /// plain counts of the non-exhaustive enum.
+    const get_redirect_limit: http.Client.Request.RedirectBehavior = @enumFromInt(3);
+
+    pub fn transport(self: *StdTransport) HttpTransport {
+        return .{
+            .ptr = self,
+            .vtable = &.{
+                .send = send,
+            },
+        };
+    }
+
+    fn send(ptr: *anyopaque, allocator: Allocator, request: Request) !Response {
+        const self: *StdTransport = @ptrCast(@alignCast(ptr));
+
+        // std.crypto.tls cannot present client certificates; mutual TLS
+        // needs a custom HttpTransport
+        if (request.client_certificate != null) return error.UnsupportedOperation;
+
+        var client = http.Client{ .allocator = allocator };
+        defer client.deinit();
+
+        const uri = try std.Uri.parse(request.url);
+
+        var req = try client.request(request.method, uri, .{
+            // Token endpoints must not be followed across redirects with a body
+            .redirect_behavior = if (request.method == .GET) get_redirect_limit else .unhandled,
+            .keep_alive = false,
+            // Ask for an identity-encoded body so it can be read directly
+            .headers = .{ .accept_encoding = .omit },
+            .extra_headers = request.headers,
+        });
+        defer req.deinit();
+
+        if (request.body != null or request.method.requestHasBody()) {
+            const payload = request.body orelse "";
+            req.transfer_encoding = .{ .content_length = payload.len };
+            var body_writer = try req.sendBodyUnflushed(&.{});
+            try body_writer.writer.writeAll(payload);
+            try body_writer.end();
+            try req.connection.?.flush();
+        } else {
+            try req.sendBodiless();
+        }
+
+        var redirect_buffer: [8 * 1024]u8 = undefined;
+        var response = try req.receiveHead(&redirect_buffer);
+
+        // Copy headers before reading the body (reading invalidates the head)
+        var headers: std.ArrayListUnmanaged(Header) = .{};
+        errdefer {
+            for (headers.items) |h| {
+                allocator.free(h.name);
+                allocator.free(h.value);
+            }
+            headers.deinit(allocator);
+        }
+
+        var header_iter = response.head.iterateHeaders();
+        while (header_iter.next()) |h| {
+            const name = try allocator.dupe(u8, h.name);
+            errdefer allocator.free(name);
+            const value = try allocator.dupe(u8, h.value);
+            errdefer allocator.free(value);
+            try headers.append(allocator, .{ .name = name, .value = value });
+        }
+
+        const status: u16 = @intFromEnum(response.head.status);
+
+        var transfer_buffer: [64]u8 = undefined;
+        const body_reader = response.reader(&transfer_buffer);
+        const body = body_reader.allocRemaining(allocator, .limited(self.max_response_size)) catch |err| switch (err) {
+            error.ReadFailed => return response.bodyErr().?,
+            error.StreamTooLong => return error.ResponseTooLarge,
+            else => |e| return e,
+        };
+        errdefer allocator.free(body);
+
+        return .{
+            .allocator = allocator,
+            .status = status,
+            .headers = try headers.toOwnedSlice(allocator),
+            .body = body,
+        };
+    }
+};
+
+var default_std_transport: StdTransport = .{};
+
+/// The process-wide default transport (std.http)
+pub fn defaultTransport() HttpTransport {
+    return default_std_transport.transport();
+}
+
+/// In-memory transport returning queued canned responses
+///
+/// Every request is recorded so tests can assert on what was sent. When the
+/// queue is empty, requests fail with `error.ConnectionFailed`, which is
+/// convenient for simulating an unreachable server.
+pub const MockTransport = struct {
+    allocator: Allocator,
+    mutex: std.Thread.Mutex = .{},
+    responses: std.ArrayListUnmanaged(OwnedResponse) = .{},
+    requests: std.ArrayListUnmanaged(RecordedRequest) = .{},
+    /// While set, requests stall (up to `max_block_ms`) before answering
+    blocked: std.atomic.Value(bool) = std.atomic.Value(bool).init(false),
+    max_block_ms: u64 = 5000,
+
+    /// A canned response to return
+    pub const MockResponse = struct {
+        status: u16 = 200,
+        headers: []const Header = &.{},
+        body: []const u8 = "",
+    };
+
+    /// A request captured by the mock
+    pub const RecordedRequest = struct {
+        method: http.Method,
+        url: []const u8,
+        headers: []Header,
+        body: ?[]const u8,
+        /// Whether a client certificate was attached
+        client_certificate: bool = false,
+
+        /// Look up a request header by name (case-insensitive)
+        pub fn header(self: *const RecordedRequest, name: []const u8) ?[]const u8 {
+            for (self.headers) |h| {
+                if (std.ascii.eqlIgnoreCase(h.name, name)) return h.value;
+            }
+            return null;
+        }
+    };
+
+    const OwnedResponse = struct {
+        status: u16,
+        headers: []Header,
+        body: []const u8,
+    };
+
+    pub fn init(allocator: Allocator) MockTransport {
+        return .{ .allocator = allocator };
+    }
+
+    pub fn deinit(self: *MockTransport) void {
+        for (self.responses.items) |r| {
+            freeHeaders(self.allocator, r.headers);
+            self.allocator.free(r.body);
+        }
+        self.responses.deinit(self.allocator);
+
+        for (self.requests.items) |r| {
+            self.allocator.free(r.url);
+            freeHeaders(self.allocator, r.headers);
+            if (r.body) |b| self.allocator.free(b);
+        }
+        self.requests.deinit(self.allocator);
+    }
+
+    pub fn transport(self: *MockTransport) HttpTransport {
+        return .{
+            .ptr = self,
+            .vtable = &.{
+                .send = send,
+            },
+        };
+    }
+
+    /// Queue a response for the next request
+    pub fn enqueue(self: *MockTransport, response: MockResponse) !void {
+        const headers = try dupeHeaders(self.allocator, response.headers);
+        errdefer freeHeaders(self.allocator, headers);
+        const body = try self.allocator.dupe(u8, response.body);
+        errdefer self.allocator.free(body);
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        try self.responses.append(self.allocator, .{
+            .status = response.status,
+            .headers = headers,
+            .body = body,
+        });
+    }
+
+    /// Stall requests until unblock() is called
+    pub fn block(self: *MockTransport) void {
+        self.blocked.store(true, .release);
+    }
+
+    /// Let stalled requests proceed
+    pub fn unblock(self: *MockTransport) void {
+        self.blocked.store(false, .release);
+    }
+
+    /// Number of requests received so far
+    pub fn requestCount(self: *MockTransport) usize {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        return self.requests.items.len;
+    }
+
+    /// Get a recorded request by index (borrowed from the mock)
+    pub fn request(self: *MockTransport, index: usize) ?*const RecordedRequest {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        if (index >= self.requests.items.len) return null;
+        return &self.requests.items[index];
+    }
+
+    /// Get the most recently recorded request (borrowed from the mock)
+    pub fn lastRequest(self: *MockTransport) ?*const RecordedRequest {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        if (self.requests.items.len == 0) return null;
+        return &self.requests.items[self.requests.items.len - 1];
+    }
+
+    fn send(ptr: *anyopaque, allocator: Allocator, req: Request) !Response {
+        const self: *MockTransport = @ptrCast(@alignCast(ptr));
+
+        try self.record(req);
+
+        var waited_ms: u64 = 0;
+        while (self.blocked.load(.acquire) and waited_ms < self.max_block_ms) : (waited_ms += 1) {
+            std.Thread.sleep(std.time.ns_per_ms);
+        }
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        if (self.responses.items.len == 0) return error.ConnectionFailed;
+        const canned = self.responses.orderedRemove(0);
+        defer {
+            freeHeaders(self.allocator, canned.headers);
+            self.allocator.free(canned.body);
+        }
+
+        const headers = try dupeHeaders(allocator, canned.headers);
+        errdefer freeHeaders(allocator, headers);
+
+        return .{
+            .allocator = allocator,
+            .status = canned.status,
+            .headers = headers,
+            .body = try allocator.dupe(u8, canned.body),
+        };
+    }
+
+    fn record(self: *MockTransport, req: Request) !void {
+        const url = try self.allocator.dupe(u8, req.url);
+        errdefer self.allocator.free(url);
+        const headers = try dupeHeaders(self.allocator, req.headers);
+        errdefer freeHeaders(self.allocator, headers);
+        const body = if (req.body) |b| try self.allocator.dupe(u8, b) else null;
+        errdefer if (body) |b| self.allocator.free(b);
+
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        try self.requests.append(self.allocator, .{
+            .method = req.method,
+            .url = url,
+            .headers = headers,
+            .body = body,
+            .client_certificate = req.client_certificate != null,
+        });
+    }
+};
+
+fn dupeHeaders(allocator: Allocator, headers: []const Header) ![]Header {
+    const out = try allocator.alloc(Header, headers.len);
+    var copied: usize = 0;
+    errdefer {
+        for (out[0..copied]) |h| {
+            allocator.free(h.name);
+            allocator.free(h.value);
+        }
+        allocator.free(out);
+    }
+
+    for (headers, 0..) |h, i| {
+        const name = try allocator.dupe(u8, h.name);
+        errdefer allocator.free(name);
+        out[i] = .{ .name = name, .value = try allocator.dupe(u8, h.value) };
+        copied += 1;
+    }
+    return out;
+}
+
+fn freeHeaders(allocator: Allocator, headers: []Header) void {
+    for (headers) |h| {
+        allocator.free(h.name);
+        allocator.free(h.value);
+    }
+    allocator.free(headers);
+}
+
+test "MockTransport returns queued responses and records requests" {
+    const allocator = std.testing.allocator;
+
+    var mock = MockTransport.init(allocator);
+    defer mock.deinit();
+
+    try mock.enqueue(.{
+        .status = 201,
+        .headers = &.{.{ .name = "Date", .value = "Tue, 14 Oct 2025 10:00:00 GMT" }},
+        .body = "{\"ok\":true}",
+    });
+
+    const t = mock.transport();
+    var response = try t.send(allocator, .{
+        .url = "https://example.com/token",
+        .headers = &.{.{ .name = "Content-Type", .value = "application/x-www-form-urlencoded" }},
+        .body = "grant_type=client_credentials",
+    });
+    defer response.deinit();
+
+    try std.testing.expectEqual(@as(u16, 201), response.status);
+    try std.testing.expectEqualStrings("{\"ok\":true}", response.body);
+    try std.testing.expectEqualStrings("Tue, 14 Oct 2025 10:00:00 GMT", response.header("date").?);
+
+    try std.testing.expectEqual(@as(usize, 1), mock.requestCount());
+    const recorded = mock.lastRequest().?;
+    try std.testing.expectEqualStrings("https://example.com/token", recorded.url);
+    try std.testing.expectEqualStrings("grant_type=client_credentials", recorded.body.?);
+    try std.testing.expectEqualStrings("application/x-www-form-urlencoded", recorded.header("content-type").?);
+}
+
+test "MockTransport fails when no response is queued" {
+    const allocator = std.testing.allocator;
+
+    var mock = MockTransport.init(allocator);
+    defer mock.deinit();
+
+    const t = mock.transport();
+    try std.testing.expectError(error.ConnectionFailed, t.send(allocator, .{ .url = "https://example.com" }));
+    try std.testing.expectEqual(@as(usize, 1), mock.requestCount());
+}
+
+test "ClientCertificate.thumbprint hashes the DER certificate" {
+    const allocator = std.testing.allocator;
+
+    const cert = ClientCertificate{
+        .cert_pem = "-----BEGIN CERTIFICATE-----\nc2NobHVz\nc2Vs\n-----END CERTIFICATE-----\n",
+        .key_pem = "",
+    };
+    // SHA-256("schlussel"), base64url
+    try std.testing.expectEqualStrings("TkAiFpuWAxsfpA8DGBG09oH5grKHuENs6bVWR9CwK3Q", &(try cert.thumbprint(allocator)));
+
+    const not_pem = ClientCertificate{ .cert_pem = "garbage", .key_pem = "" };
+    try std.testing.expectError(error.InvalidParameter, not_pem.thumbprint(allocator));
+}
+
+test "StdTransport refuses requests with a client certificate" {
+    var std_transport = StdTransport{};
+    try std.testing.expectError(error.UnsupportedOperation, std_transport.transport().send(std.testing.allocator, .{
+        .url = "https://example.com/token",
+        .client_certificate = .{ .cert_pem = "cert", .key_pem = "key" },
+    }));
+}
@@ -0,0 +1,421 @@
+//! HashiCorp Vault token storage
+//!
+//! `VaultStorage` keeps every token as a KV version 2 secret. The secret
+//! path comes from `Options.path_template`, where `{key}` is replaced by
+//! the percent-encoded storage key; the token is stored in the secret's
+//! `token` field. Deleting a key removes all of its versions.
+//!
+//! Requests authenticate with a Vault token, or log in with AppRole and
+//! renew the client token by logging in again when its lease runs out or
+//! Vault rejects it. Requests go through an `HttpTransport`, like the
+//! OAuth client's.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var vault = try VaultStorage.init(allocator, .{
+//!     .address = "https://vault.internal:8200",
+//!     .path_template = "teams/cli/{key}",
+//!     .auth = .{ .app_role = .{ .role_id = role_id, .secret_id = secret_id } },
+//! });
+//! defer vault.deinit();
+//!
+//! var client = OAuthClient.init(allocator, config, vault.storage());
+//! ```
+
+const std = @import("std");
+const json = std.json;
+const Allocator = std.mem.Allocator;
+
+const callback = @import("callback.zig");
+const clock = @import("clock.zig");
+const codec = @import("codec.zig");
+const session = @import("session.zig");
+const transport = @import("transport.zig");
+
+const Token = session.Token;
+const SessionStorage = session.SessionStorage;
+const StorageCapabilities = session.StorageCapabilities;
+const HttpTransport = transport.HttpTransport;
+const Header = transport.Header;
+
+/// Token storage in a Vault KV v2 secrets engine
+pub const VaultStorage = struct {
+    allocator: Allocator,
+    options: Options,
+    /// HTTP transport override (defaults to std.http when null)
+    http_transport: ?HttpTransport = null,
+    /// Guards the AppRole client token
+    mutex: std.Thread.Mutex = .{},
+    client_token: ?[]u8 = null,
+    client_token_expires_at: ?u64 = null,
+
+    /// Seconds before lease expiry at which AppRole logs in again
+    const renew_margin = 30;
+
+    /// How requests authenticate
+    pub const Auth = union(enum) {
+        /// A Vault token (e.g. from `VAULT_TOKEN`)
+        token: []const u8,
+        app_role: AppRole,
+    };
+
+    pub const AppRole = struct {
+        role_id: []const u8,
+        secret_id: []const u8,
+        /// Where the AppRole auth method is mounted
+        mount: []const u8 = "approle",
+    };
+
+    /// Storage settings; the strings are borrowed and must outlive the storage
+    pub const Options = struct {
+        /// Vault server, e.g. `https://vault.internal:8200`
+        address: []const u8,
+        /// Where the KV v2 engine is mounted
+        mount: []const u8 = "secret",
+        /// Secret path inside the mount; must contain `{key}` once
+        path_template: []const u8 = "schlussel/{key}",
+        /// Enterprise namespace sent as `X-Vault-Namespace`
+        namespace: ?[]const u8 = null,
+        auth: Auth,
+    };
+
+    /// Returns `error.ConfigurationError` for an empty address or mount, or a
+    /// path template without exactly one `{key}`
+    pub fn init(allocator: Allocator, options: Options) !VaultStorage {
+        if (options.address.len == 0 or options.mount.len == 0) return error.ConfigurationError;
+        if (std.mem.count(u8, options.path_template, "{key}") != 1) return error.ConfigurationError;
+
+        var trimmed = options;
+        trimmed.address = std.mem.trimRight(u8, options.address, "/");
+        trimmed.mount = std.mem.trim(u8, options.mount, "/");
+        return .{ .allocator = allocator, .options = trimmed };
+    }
+
+    pub fn deinit(self: *VaultStorage) void {
+        self.dropClientToken();
+    }
+
+    pub fn storage(self: *VaultStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+                .capabilities = capabilities,
+                .list_keys = listKeys,
+            }),
+        };
+    }
+
+    /// Get the transport used for requests to Vault
+    pub fn httpTransport(self: *const VaultStorage) HttpTransport {
+        return self.http_transport orelse transport.defaultTransport();
+    }
+
+    fn capabilities(ptr: *anyopaque) StorageCapabilities {
+        const self: *VaultStorage = @ptrCast(@alignCast(ptr));
+        // Keys can only be listed when they are the last path segment
+        return .{
+            .atomic_swap = true,
+            .list_keys = std.mem.endsWith(u8, self.options.path_template, "{key}") and
+                (self.options.path_template.len == "{key}".len or
+                    std.mem.endsWith(u8, self.options.path_template, "/{key}")),
+        };
+    }
+
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        const self: *VaultStorage = @ptrCast(@alignCast(ptr));
+
+        const url = try self.secretUrl("data", key);
+        defer self.allocator.free(url);
+
+        const data = try codec.default.encode(self.allocator, &token);
+        defer {
+            std.crypto.secureZero(u8, data);
+            self.allocator.free(data);
+        }
+        const body = try json.Stringify.valueAlloc(self.allocator, .{ .data = .{ .token = data } }, .{});
+        defer {
+            std.crypto.secureZero(u8, body);
+            self.allocator.free(body);
+        }
+
+        var response = try self.send(.POST, url, body);
+        defer response.deinit();
+        if (response.status != 200 and response.status != 204) return statusError(response.status);
+    }
+
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        const self: *VaultStorage = @ptrCast(@alignCast(ptr));
+
+        const url = try self.secretUrl("data", key);
+        defer self.allocator.free(url);
+
+        var response = try self.send(.GET, url, null);
+        defer {
+            std.crypto.secureZero(u8, @constCast(response.body));
+            response.deinit();
+        }
+        if (response.status == 404) return null;
+        if (response.status != 200) return statusError(response.status);
+
+        const Secret = struct {
+            data: struct {
+                data: ?struct { token: ?[]const u8 = null } = null,
+            },
+        };
+        const parsed = json.parseFromSlice(Secret, self.allocator, response.body, .{ .ignore_unknown_fields = true }) catch return error.ServerError;
+        defer parsed.deinit();
+
+        // A soft-deleted latest version reads back with null data
+        const fields = parsed.value.data.data orelse return null;
+        const data = fields.token orelse return error.ServerError;
+        return try codec.default.decode(allocator, data);
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        const self: *VaultStorage = @ptrCast(@alignCast(ptr));
+
+        const url = try self.secretUrl("metadata", key);
+        defer self.allocator.free(url);
+
+        var response = try self.send(.DELETE, url, null);
+        defer response.deinit();
+        if (response.status != 200 and response.status != 204 and response.status != 404) return statusError(response.status);
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        const self: *VaultStorage = @ptrCast(@alignCast(ptr));
+        var token = (load(self, self.allocator, key) catch return false) orelse return false;
+        token.deinit();
+        return true;
+    }
+
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) ![][]const u8 {
+        const self: *VaultStorage = @ptrCast(@alignCast(ptr));
+        if (!capabilities(ptr).list_keys) return error.UnsupportedOperation;
+
+        const template = self.options.path_template;
+        const directory = template[0 .. template.len - "{key}".len];
+        const url = try std.fmt.allocPrint(self.allocator, "{s}/v1/{s}/metadata/{s}?list=true", .{ self.options.address, self.options.mount, directory });
+        defer self.allocator.free(url);
+
+        var response = try self.send(.GET, url, null);
+        defer response.deinit();
+        // Vault answers an empty listing with 404
+        if (response.status == 404) return allocator.alloc([]const u8, 0);
+        if (response.status != 200) return statusError(response.status);
+
+        const Listing = struct {
+            data: struct { keys: []const []const u8 = &.{} },
+        };
+        const parsed = json.parseFromSlice(Listing, self.allocator, response.body, .{ .ignore_unknown_fields = true }) catch return error.ServerError;
+        defer parsed.deinit();
+
+        var keys: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
+        for (parsed.value.data.keys) |name| {
+            // Entries ending in `/` are sub-directories, not secrets
+            if (std.mem.endsWith(u8, name, "/")) continue;
+            const copy = try allocator.dupe(u8, name);
+            errdefer allocator.free(copy);
+            try keys.append(allocator, copy);
+        }
+        return keys.toOwnedSlice(allocator);
+    }
+
+    /// `<address>/v1/<mount>/<kind>/<path>` for `key`
+    fn secretUrl(self: *VaultStorage, kind: []const u8, key: []const u8) ![]u8 {
+        if (key.len == 0) return error.InvalidParameter;
+
+        var url: std.ArrayListUnmanaged(u8) = .{};
+        errdefer url.deinit(self.allocator);
+
+        const template = self.options.path_template;
+        const placeholder = std.mem.indexOf(u8, template, "{key}").?;
+        try url.writer(self.allocator).print("{s}/v1/{s}/{s}/{s}", .{ self.options.address, self.options.mount, kind, template[0..placeholder] });
+        try callback.appendUrlEncoded(self.allocator, &url, key);
+        try url.appendSlice(self.allocator, template[placeholder + "{key}".len ..]);
+        return url.toOwnedSlice(self.allocator);
+    }
+
+    /// Send an authenticated request, logging in again once if an AppRole
+    /// token is rejected
+    fn send(self: *VaultStorage, method: std.http.Method, url: []const u8, body: ?[]const u8) !transport.Response {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+
+        var response = try self.sendWithToken(method, url, body, try self.currentToken());
+        if (response.status == 403 and self.options.auth == .app_role) {
+            response.deinit();
+            self.dropClientToken();
+            response = try self.sendWithToken(method, url, body, try self.currentToken());
+        }
+        return response;
+    }
+
+    fn sendWithToken(self: *VaultStorage, method: std.http.Method, url: []const u8, body: ?[]const u8, vault_token: []const u8) !transport.Response {
+        var headers: [3]Header = undefined;
+        var count: usize = 0;
+        headers[count] = .{ .name = "X-Vault-Token", .value = vault_token };
+        count += 1;
+        if (self.options.namespace) |namespace| {
+            headers[count] = .{ .name = "X-Vault-Namespace", .value = namespace };
+            count += 1;
+        }
+        if (body != null) {
+            headers[count] = .{ .name = "Content-Type", .value = "application/json" };
+            count += 1;
+        }
+
+        return self.httpTransport().send(self.allocator, .{
+            .method = method,
+            .url = url,
+            .headers = headers[0..count],
+            .body = body,
+        });
+    }
+
+    /// The token to authenticate with, logging in with AppRole if needed
+    fn currentToken(self: *VaultStorage) ![]const u8 {
+        const app_role = switch (self.options.auth) {
+            .token => |vault_token| return vault_token,
+            .app_role => |app_role| app_role,
+        };
+
+        if (self.client_token) |client_token| {
+            const expires_at = self.client_token_expires_at orelse return client_token;
+            if (clock.now() + renew_margin < expires_at) return client_token;
+            self.dropClientToken();
+        }
+
+        const url = try std.fmt.allocPrint(self.allocator, "{s}/v1/auth/{s}/login", .{ self.options.address, std.mem.trim(u8, app_role.mount, "/") });
+        defer self.allocator.free(url);
+        const body = try json.Stringify.valueAlloc(self.allocator, .{ .role_id = app_role.role_id, .secret_id = app_role.secret_id }, .{});
+        defer {
+            std.crypto.secureZero(u8, body);
+            self.allocator.free(body);
+        }
+
+        var headers = [_]Header{
+            .{ .name = "Content-Type", .value = "application/json" },
+            .{ .name = "X-Vault-Namespace", .value = self.options.namespace orelse "" },
+        };
+        var response = try self.httpTransport().send(self.allocator, .{
+            .url = url,
+            .headers = headers[0..if (self.options.namespace != null) 2 else 1],
+            .body = body,
+        });
+        defer {
+            std.crypto.secureZero(u8, @constCast(response.body));
+            response.deinit();
+        }
+        if (response.status != 200) {
+            std.log.warn("Vault AppRole login failed with HTTP {d}", .{response.status});
+            return error.StorageError;
+        }
+
+        const Login = struct {
+            auth: struct {
+                client_token: []const u8,
+                lease_duration: u64 = 0,
+            },
+        };
+        const parsed = json.parseFromSlice(Login, self.allocator, response.body, .{ .ignore_unknown_fields = true }) catch return error.ServerError;
+        defer parsed.deinit();
+
+        const client_token = try self.allocator.dupe(u8, parsed.value.auth.client_token);
+        self.client_token = client_token;
+        // A zero lease never expires
+        const lease = parsed.value.auth.lease_duration;
+        self.client_token_expires_at = if (lease > 0) clock.now() + lease else null;
+        return client_token;
+    }
+
+    fn dropClientToken(self: *VaultStorage) void {
+        if (self.client_token) |client_token| {
+            std.crypto.secureZero(u8, client_token);
+            self.allocator.free(client_token);
+        }
+        self.client_token = null;
+        self.client_token_expires_at = null;
+    }
+};
+
+fn statusError(status: u16) error{StorageError} {
+    std.log.warn("Vault request failed with HTTP {d}", .{status});
+    return error.StorageError;
+}
+
+test "VaultStorage stores tokens as KV v2 secrets through AppRole" {
+    const allocator = std.testing.allocator;
+
+    var mock = transport.MockTransport.init(allocator);
+    defer mock.deinit();
+
+    var vault = try VaultStorage.init(allocator, .{
+        .address = "https://vault.test/",
+        .mount = "kv",
+        .path_template = "apps/cli/{key}",
+        .namespace = "platform",
+        .auth = .{ .app_role = .{ .role_id = "role", .secret_id = "secret" } },
+    });
+    defer vault.deinit();
+    vault.http_transport = mock.transport();
+    const store = vault.storage();
+
+    var token = try Token.initFull(allocator, "vault-access", "Bearer", "vault-refresh", 3600, null, null);
+    defer token.deinit();
+    const data = try codec.default.encode(allocator, &token);
+    defer allocator.free(data);
+    const secret = try json.Stringify.valueAlloc(allocator, .{ .data = .{ .data = .{ .token = data } } }, .{});
+    defer allocator.free(secret);
+
+    try mock.enqueue(.{ .body = "{\"auth\":{\"client_token\":\"hvs.first\",\"lease_duration\":3600}}" });
+    try mock.enqueue(.{ .status = 204 });
+    try store.save("github user", token);
+
+    const login = mock.request(0).?;
+    try std.testing.expectEqualStrings("https://vault.test/v1/auth/approle/login", login.url);
+    try std.testing.expect(std.mem.indexOf(u8, login.body.?, "\"role_id\":\"role\"") != null);
+    const write = mock.request(1).?;
+    try std.testing.expectEqualStrings("https://vault.test/v1/kv/data/apps/cli/github%20user", write.url);
+    try std.testing.expectEqualStrings("hvs.first", write.header("X-Vault-Token").?);
+    try std.testing.expectEqualStrings("platform", write.header("X-Vault-Namespace").?);
+
+    // A revoked client token triggers one new login
+    try mock.enqueue(.{ .status = 403, .body = "{\"errors\":[\"permission denied\"]}" });
+    try mock.enqueue(.{ .body = "{\"auth\":{\"client_token\":\"hvs.second\",\"lease_duration\":3600}}" });
+    try mock.enqueue(.{ .body = secret });
+    var loaded = (try store.load(allocator, "github user")).?;
+    defer loaded.deinit();
+    try std.testing.expectEqualStrings("vault-access", loaded.access_token);
+    try std.testing.expectEqualStrings("hvs.second", mock.lastRequest().?.header("X-Vault-Token").?);
+
+    try mock.enqueue(.{ .status = 404, .body = "{\"errors\":[]}" });
+    try std.testing.expect((try store.load(allocator, "missing")) == null);
+
+    try mock.enqueue(.{ .status = 204 });
+    try store.delete("github user");
+    try std.testing.expectEqualStrings("https://vault.test/v1/kv/metadata/apps/cli/github%20user", mock.lastRequest().?.url);
+    try std.testing.expectEqual(std.http.Method.DELETE, mock.lastRequest().?.method);
+
+    try mock.enqueue(.{ .body = "{\"data\":{\"keys\":[\"github user\",\"nested/\"]}}" });
+    const keys = try store.listKeys(allocator);
+    defer SessionStorage.freeKeys(allocator, keys);
+    try std.testing.expectEqual(@as(usize, 1), keys.len);
+    try std.testing.expectEqualStrings("github user", keys[0]);
+    try std.testing.expectEqualStrings("https://vault.test/v1/kv/metadata/apps/cli/?list=true", mock.lastRequest().?.url);
+
+    try std.testing.expectError(error.ConfigurationError, VaultStorage.init(allocator, .{
+        .address = "https://vault.test",
+        .path_template = "apps/cli",
+        .auth = .{ .token = "hvs.static" },
+    }));
+}
@@ -0,0 +1,351 @@
+//! Windows Credential Manager storage
+//!
+//! `CredentialManagerStorage` keeps every token as a generic credential
+//! named `<service>/<key>` through CredWriteW/CredReadW, so Windows
+//! encrypts it with the user's logon credentials (DPAPI). Credentials are
+//! persisted per machine and do not roam with the user profile.
+//!
+//! A credential blob holds at most 2560 bytes, less than a token with an
+//! ID token often needs. Longer tokens are split over continuation
+//! credentials named `<service>#<n>/<key>`; the first blob starts with the
+//! number of parts.
+//!
+//! ## Example
+//!
+//! ```zig
+//! var credentials = try CredentialManagerStorage.init(allocator, "dev.tuist.cli");
+//! defer credentials.deinit();
+//!
+//! var client = OAuthClient.init(allocator, config, credentials.storage());
+//! ```
+
+const std = @import("std");
+const builtin = @import("builtin");
+const Allocator = std.mem.Allocator;
+
+const codec = @import("codec.zig");
+const session = @import("session.zig");
+
+const Token = session.Token;
+const SessionStorage = session.SessionStorage;
+const StorageCapabilities = session.StorageCapabilities;
+
+/// Whether the Credential Manager is available on the target
+pub const supported = builtin.os.tag == .windows;
+
+/// Largest credential blob Windows accepts (CRED_MAX_CREDENTIAL_BLOB_SIZE)
+const max_blob_size = 5 * 512;
+/// The first blob starts with the part count as a little-endian u16
+const header_size = 2;
+
+/// Number of credentials needed for `len` bytes of token data
+fn partCount(len: usize) usize {
+    const first = max_blob_size - header_size;
+    if (len <= first) return 1;
+    return 1 + std.math.divCeil(usize, len - first, max_blob_size) catch unreachable;
+}
+
+/// Token storage in the Windows Credential Manager
+pub const CredentialManagerStorage = struct {
+    allocator: Allocator,
+    service_name: []const u8,
+
+    /// Returns `error.UnsupportedOperation` on platforms other than Windows
+    ///
+    /// The service name must not contain `/` or `#`, which separate it
+    /// from the key and the part number.
+    pub fn init(allocator: Allocator, service_name: []const u8) !CredentialManagerStorage {
+        if (!supported) return error.UnsupportedOperation;
+        if (service_name.len == 0) return error.InvalidParameter;
+        if (std.mem.indexOfAny(u8, service_name, "/#") != null) return error.InvalidParameter;
+        return .{
+            .allocator = allocator,
+            .service_name = try allocator.dupe(u8, service_name),
+        };
+    }
+
+    pub fn deinit(self: *CredentialManagerStorage) void {
+        self.allocator.free(self.service_name);
+    }
+
+    pub fn storage(self: *CredentialManagerStorage) SessionStorage {
+        return .{
+            .ptr = self,
+            .vtable = SessionStorage.VTable.from(.{
+                .save = save,
+                .load = load,
+                .delete = delete,
+                .exists = exists,
+                .capabilities = capabilities,
+                .list_keys = listKeys,
+            }),
+        };
+    }
+
+    fn capabilities(_: *anyopaque) StorageCapabilities {
+        // Tokens spread over several credentials are not replaced atomically
+        return .{ .list_keys = true, .os_protected = true };
+    }
+
+    fn save(ptr: *anyopaque, key: []const u8, token: Token) !void {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *CredentialManagerStorage = @ptrCast(@alignCast(ptr));
+
+        const data = try codec.default.encode(self.allocator, &token);
+        defer {
+            @memset(data, 0);
+            self.allocator.free(data);
+        }
+
+        const parts = partCount(data.len);
+        if (parts > std.math.maxInt(u16)) return error.InvalidParameter;
+        const previous_parts = try self.storedParts(key);
+
+        // Continuation parts first, so the header never names missing parts
+        var offset: usize = max_blob_size - header_size;
+        for (1..parts) |part| {
+            const end = @min(offset + max_blob_size, data.len);
+            try self.writePart(key, part, data[offset..end]);
+            offset = end;
+        }
+
+        var first: [max_blob_size]u8 = undefined;
+        defer @memset(&first, 0);
+        std.mem.writeInt(u16, first[0..header_size], @intCast(parts), .little);
+        const first_len = @min(data.len, max_blob_size - header_size);
+        @memcpy(first[header_size..][0..first_len], data[0..first_len]);
+        try self.writePart(key, 0, first[0 .. header_size + first_len]);
+
+        for (parts..@max(parts, previous_parts)) |part| try self.deletePart(key, part);
+    }
+
+    fn load(ptr: *anyopaque, allocator: Allocator, key: []const u8) !?Token {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *CredentialManagerStorage = @ptrCast(@alignCast(ptr));
+
+        const first = (try self.readPart(key, 0)) orelse return null;
+        defer {
+            @memset(first, 0);
+            self.allocator.free(first);
+        }
+        if (first.len < header_size) return error.StorageError;
+        const parts = std.mem.readInt(u16, first[0..header_size], .little);
+        // save() always writes at least the first part
+        if (parts == 0) return error.StorageError;
+
+        var data: std.ArrayListUnmanaged(u8) = .{};
+        defer {
+            @memset(data.items, 0);
+            data.deinit(self.allocator);
+        }
+        try data.appendSlice(self.allocator, first[header_size..]);
+        for (1..parts) |part| {
+            const blob = (try self.readPart(key, part)) orelse return error.StorageError;
+            defer {
+                @memset(blob, 0);
+                self.allocator.free(blob);
+            }
+            try data.appendSlice(self.allocator, blob);
+        }
+
+        return try codec.default.decode(allocator, data.items);
+    }
+
+    fn delete(ptr: *anyopaque, key: []const u8) !void {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *CredentialManagerStorage = @ptrCast(@alignCast(ptr));
+
+        const parts = try self.storedParts(key);
+        for (0..parts) |part| try self.deletePart(key, part);
+    }
+
+    fn exists(ptr: *anyopaque, key: []const u8) bool {
+        if (!supported) return false;
+        const self: *CredentialManagerStorage = @ptrCast(@alignCast(ptr));
+        return (self.storedParts(key) catch return false) > 0;
+    }
+
+    fn listKeys(ptr: *anyopaque, allocator: Allocator) ![][]const u8 {
+        if (!supported) return error.UnsupportedOperation;
+        const self: *CredentialManagerStorage = @ptrCast(@alignCast(ptr));
+
+        const filter = try std.fmt.allocPrint(self.allocator, "{s}/*", .{self.service_name});
+        defer self.allocator.free(filter);
+        const names = try credentials.enumerate(allocator, filter);
+        defer SessionStorage.freeKeys(allocator, names);
+
+        var keys: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
+        for (names) |name| {
+            const key = try allocator.dupe(u8, name[self.service_name.len + 1 ..]);
+            errdefer allocator.free(key);
+            try keys.append(allocator, key);
+        }
+        return keys.toOwnedSlice(allocator);
+    }
+
+    /// Number of parts of the token under `key`, 0 if there is none
+    fn storedParts(self: *CredentialManagerStorage, key: []const u8) !usize {
+        const first = (try self.readPart(key, 0)) orelse return 0;
+        defer {
+            @memset(first, 0);
+            self.allocator.free(first);
+        }
+        if (first.len < header_size) return 1;
+        return @max(1, std.mem.readInt(u16, first[0..header_size], .little));
+    }
+
+    fn targetName(self: *CredentialManagerStorage, key: []const u8, part: usize) ![]u8 {
+        if (part == 0) return std.fmt.allocPrint(self.allocator, "{s}/{s}", .{ self.service_name, key });
+        return std.fmt.allocPrint(self.allocator, "{s}#{d}/{s}", .{ self.service_name, part, key });
+    }
+
+    fn writePart(self: *CredentialManagerStorage, key: []const u8, part: usize, blob: []const u8) !void {
+        const target = try self.targetName(key, part);
+        defer self.allocator.free(target);
+        try credentials.write(self.allocator, target, key, blob);
+    }
+
+    fn readPart(self: *CredentialManagerStorage, key: []const u8, part: usize) !?[]u8 {
+        const target = try self.targetName(key, part);
+        defer self.allocator.free(target);
+        return credentials.read(self.allocator, target);
+    }
+
+    fn deletePart(self: *CredentialManagerStorage, key: []const u8, part: usize) !void {
+        const target = try self.targetName(key, part);
+        defer self.allocator.free(target);
+        try credentials.remove(self.allocator, target);
+    }
+};
+
+/// Generic credentials through the advapi32 C API
+const credentials = struct {
+    const windows = std.os.windows;
+    const DWORD = windows.DWORD;
+    const BOOL = windows.BOOL;
+
+    const cred_type_generic: DWORD = 1;
+    const cred_persist_local_machine: DWORD = 2;
+
+    const CREDENTIALW = extern struct {
+        Flags: DWORD,
+        Type: DWORD,
+        TargetName: ?[*:0]u16,
+        Comment: ?[*:0]u16,
+        LastWritten: windows.FILETIME,
+        CredentialBlobSize: DWORD,
+        CredentialBlob: ?[*]u8,
+        Persist: DWORD,
+        AttributeCount: DWORD,
+        Attributes: ?*anyopaque,
+        TargetAlias: ?[*:0]u16,
+        UserName: ?[*:0]u16,
+    };
+
+    extern "advapi32" fn CredWriteW(credential: *const CREDENTIALW, flags: DWORD) callconv(.winapi) BOOL;
+    extern "advapi32" fn CredReadW(target_name: [*:0]const u16, cred_type: DWORD, flags: DWORD, credential: *?*CREDENTIALW) callconv(.winapi) BOOL;
+    extern "advapi32" fn CredDeleteW(target_name: [*:0]const u16, cred_type: DWORD, flags: DWORD) callconv(.winapi) BOOL;
+    extern "advapi32" fn CredEnumerateW(filter: ?[*:0]const u16, flags: DWORD, count: *DWORD, found: *?[*]*CREDENTIALW) callconv(.winapi) BOOL;
+    extern "advapi32" fn CredFree(buffer: *anyopaque) callconv(.winapi) void;
+
+    fn notFound() bool {
+        return windows.GetLastError() == .NOT_FOUND;
+    }
+
+    fn write(allocator: Allocator, target: []const u8, user_name: []const u8, blob: []const u8) !void {
+        const target_w = try std.unicode.utf8ToUtf16LeAllocZ(allocator, target);
+        defer allocator.free(target_w);
+        const user_name_w = try std.unicode.utf8ToUtf16LeAllocZ(allocator, user_name);
+        defer allocator.free(user_name_w);
+
+        const credential = CREDENTIALW{
+            .Flags = 0,
+            .Type = cred_type_generic,
+            .TargetName = target_w.ptr,
+            .Comment = null,
+            .LastWritten = .{ .dwLowDateTime = 0, .dwHighDateTime = 0 },
+            .CredentialBlobSize = @intCast(blob.len),
+            // CredWriteW copies the blob and does not modify it
+            .CredentialBlob = @constCast(blob.ptr),
+            .Persist = cred_persist_local_machine,
+            .AttributeCount = 0,
+            .Attributes = null,
+            .TargetAlias = null,
+            .UserName = user_name_w.ptr,
+        };
+        if (CredWriteW(&credential, 0) == windows.FALSE) return error.StorageError;
+    }
+
+    /// Blob of the credential named `target`, or null if there is none; caller owns it
+    fn read(allocator: Allocator, target: []const u8) !?[]u8 {
+        const target_w = try std.unicode.utf8ToUtf16LeAllocZ(allocator, target);
+        defer allocator.free(target_w);
+
+        var found: ?*CREDENTIALW = null;
+        if (CredReadW(target_w.ptr, cred_type_generic, 0, &found) == windows.FALSE) {
+            if (notFound()) return null;
+            return error.StorageError;
+        }
+        const credential = found orelse return error.StorageError;
+        defer CredFree(credential);
+
+        const blob = credential.CredentialBlob orelse return try allocator.alloc(u8, 0);
+        return try allocator.dupe(u8, blob[0..credential.CredentialBlobSize]);
+    }
+
+    /// Delete the credential named `target`; a missing one is not an error
+    fn remove(allocator: Allocator, target: []const u8) !void {
+        const target_w = try std.unicode.utf8ToUtf16LeAllocZ(allocator, target);
+        defer allocator.free(target_w);
+
+        if (CredDeleteW(target_w.ptr, cred_type_generic, 0) == windows.FALSE and !notFound()) {
+            return error.StorageError;
+        }
+    }
+
+    /// Names of the credentials matching `filter` (`*` is a wildcard)
+    fn enumerate(allocator: Allocator, filter: []const u8) ![][]const u8 {
+        const filter_w = try std.unicode.utf8ToUtf16LeAllocZ(allocator, filter);
+        defer allocator.free(filter_w);
+
+        var count: DWORD = 0;
+        var found: ?[*]*CREDENTIALW = null;
+        if (CredEnumerateW(filter_w.ptr, 0, &count, &found) == windows.FALSE) {
+            if (notFound()) return allocator.alloc([]const u8, 0);
+            return error.StorageError;
+        }
+        const list = found orelse return allocator.alloc([]const u8, 0);
+        defer CredFree(@ptrCast(list));
+
+        var names: std.ArrayListUnmanaged([]const u8) = .{};
+        errdefer {
+            for (names.items) |name| allocator.free(name);
+            names.deinit(allocator);
+        }
+        for (list[0..count]) |credential| {
+            const name_w = credential.TargetName orelse continue;
+            const name = try std.unicode.utf16LeToUtf8Alloc(allocator, std.mem.sliceTo(name_w, 0));
+            errdefer allocator.free(name);
+            try names.append(allocator, name);
+        }
+        return names.toOwnedSlice(allocator);
+    }
+};
+
+test "CredentialManagerStorage splits long tokens over several credentials" {
+    try std.testing.expectEqual(@as(usize, 1), partCount(0));
+    try std.testing.expectEqual(@as(usize, 1), partCount(max_blob_size - header_size));
+    try std.testing.expectEqual(@as(usize, 2), partCount(max_blob_size - header_size + 1));
+    try std.testing.expectEqual(@as(usize, 3), partCount(max_blob_size - header_size + 2 * max_blob_size));
+
+    const allocator = std.testing.allocator;
+    if (!supported) {
+        try std.testing.expectError(error.UnsupportedOperation, CredentialManagerStorage.init(allocator, "dev.schlussel.test"));
+        return;
+    }
+    try std.testing.expectError(error.InvalidParameter, CredentialManagerStorage.init(allocator, "dev/schlussel"));
+}